@@ -3,30 +3,105 @@
 //! Parse an image file
 //! Usage: cargo run --example parser --input FILENAME
 //!
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use config::Config;
-use env_logger;
 use log::{error, info};
 
-use image_rider::disk_format::image::{DiskImage, DiskImageParser, DiskImageSaver};
+use image_rider::disk_format::apple::bootable::{add_file, make_bootable_with_patterns};
+use image_rider::disk_format::apple::catalog::FileType;
+use image_rider::disk_format::apple::text::{records, records_to_csv};
+use image_rider::disk_format::apple::woz::disk_image_to_woz;
+use image_rider::disk_format::blank::{blank_image, FormatPatterns};
+use image_rider::disk_format::cache::{ContentCache, ContentHash};
+use image_rider::disk_format::cancel::CancellationToken;
+use image_rider::disk_format::carve::{self, ContentKind};
+use image_rider::disk_format::collection::{
+    group_by_label, scan_collection, CollectionEntry, DEFAULT_VARIANT_THRESHOLD,
+};
+use image_rider::disk_format::converter::convert_external;
+use image_rider::disk_format::cpm::dpb::CpmFormat;
+use image_rider::disk_format::cpm::volume::CpmVolume;
+use image_rider::disk_format::device::{read_image, DeviceOptions};
+use image_rider::disk_format::file_select::{Collisions, FileSelection, NamingPolicy, Sanitize};
+use image_rider::disk_format::fingerprint::{fingerprint_disk, FingerprintDatabase};
+use image_rider::disk_format::flux::capture;
+use image_rider::disk_format::flux::scp::scp_image_parser;
+use image_rider::disk_format::format_info::{capability_matrix, formats};
+use image_rider::disk_format::geometry::Geometry;
+use image_rider::disk_format::identify::identify;
+use image_rider::disk_format::image::{
+    disk_image_nibble_dos_data, parse_nibble_dos_image, DiskImage, DiskImageParser, DiskImageSaver,
+};
+use image_rider::disk_format::image_set::{group_image_sets, SetMember};
+#[cfg(feature = "parity")]
+use image_rider::disk_format::logical::{flatten_tracks, split_tracks};
+use image_rider::disk_format::logical::{merge_sides, RawOrder};
+use image_rider::disk_format::manifest::Manifest;
+use image_rider::disk_format::overlay::{overlay_parser, Overlay};
+#[cfg(feature = "parity")]
+use image_rider::disk_format::parity::{parity_parser, ParityOptions};
+use image_rider::disk_format::report::Report;
+use image_rider::disk_format::search::Pattern;
+use image_rider::disk_format::stx::verify::verify_stx;
+use image_rider::disk_format::stx::writer::write_stx;
+use image_rider::disk_format::track_files::TrackFormat;
+use image_rider::disk_format::tree::{ImageTree, NodeKind, TreeNode};
+use image_rider::error::{
+    Error, ErrorKind, EXIT_CORRUPT_IMAGE, EXIT_NO_MATCH, EXIT_OK, EXIT_UNKNOWN_FORMAT,
+};
+use image_rider::options::Options;
+use image_rider::rom_format::image::{is_rom_image, RomImageParser};
+use image_rider::serialize::Serializer;
+
+/// The exit codes, listed after the help
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  nothing found, e.g. a search with no hits
+  2  unknown or unsupported image format
+  3  corrupt image, or an image that failed verification
+  4  the file to extract isn't on the image
+  5  the image is read-only
+  6  cancelled
+  7  any other error, e.g. a file that can't be read";
 
 /// Command line arguments to parse an image file
 #[derive(Parser, Debug)]
-#[clap(about, version, author)]
+#[clap(about, version, author, after_help = EXIT_CODES)]
 struct Args {
     /// Filename to parse
     #[clap(short, long)]
     input: String,
     /// Filename to select for writing.
     /// Specifying a filename select that file to saving if output is
-    /// also specified.
+    /// also specified.  Glob patterns like "GAME*" or a comma-separated
+    /// list select several files, which are written to the output
+    /// directory.
     #[clap(short, long)]
     filename: Option<String>,
+    /// Write every file on the disk to the output directory
+    #[clap(long)]
+    all_files: bool,
+    /// Template for the names of files written with --all-files or a
+    /// filename pattern, e.g. "{name}.{type}.bin", "{name}" by default
+    #[clap(long)]
+    name_template: Option<String>,
+    /// How names of files written with --all-files or a filename
+    /// pattern are made safe: "portable", "strict" or "minimal"
+    #[clap(long)]
+    sanitize: Option<Sanitize>,
+    /// What happens when two files written with --all-files or a
+    /// filename pattern get the same name: "suffix", "overwrite" or
+    /// "error"
+    #[clap(long)]
+    collisions: Option<Collisions>,
+    /// Write a metadata file with the original name bytes next to each
+    /// file written with --all-files or a filename pattern
+    #[clap(long)]
+    sidecar: bool,
     /// Filename to write track image data to,
     /// This writes the entire disk to a single file.
     #[clap(short, long)]
@@ -34,31 +109,322 @@ struct Args {
     /// Ignore any failed checksums on the disk data.
     #[clap(long)]
     ignore_checksums: bool,
+    /// Refuse to read an input block device, e.g. /dev/sdb, unless the
+    /// system reports it as write protected
+    #[clap(long)]
+    write_protected: bool,
+    /// Number of tracks of a headerless image or block device, used
+    /// with --sectors instead of guessing the geometry from the size
+    #[clap(long)]
+    tracks: Option<u8>,
+    /// Number of sectors on each track of a headerless image
+    #[clap(long)]
+    sectors: Option<u8>,
+    /// Size of each sector of a headerless image, 512 by default
+    #[clap(long)]
+    sector_size: Option<u16>,
+    /// Number of sides of a headerless image, 1 by default
+    #[clap(long)]
+    sides: Option<u8>,
+    /// Only decode sectors from this volume of an Apple nibble image
+    #[clap(long)]
+    volume: Option<u8>,
+    /// Unwrap a Binary II, BinSCII, AppleSingle or MacBinary file saved
+    /// with --filename, writing the files inside to the output directory
+    #[clap(long)]
+    unwrap: bool,
+    /// Overlay file to apply to the input image before parsing.
+    #[clap(long)]
+    overlay: Option<String>,
+    /// Parity sidecar to repair damaged sectors of the input image, or
+    /// of a capture with --capture, before parsing.
+    #[cfg(feature = "parity")]
+    #[clap(long)]
+    parity: Option<String>,
+    /// Modified image to compare with the input image.
+    /// The changed sectors are written as an overlay to the output file.
+    #[clap(long)]
+    diff: Option<String>,
+    /// Directory to write one file per track and side to.
+    #[clap(long)]
+    export_tracks: Option<String>,
+    /// Format of exported track files: raw, mfm or flux.
+    #[clap(long, default_value = "raw")]
+    track_format: String,
+    /// Directory to write the raw track images of an STX image to,
+    /// with an index.json of their sync offsets and sizes.
+    #[clap(long)]
+    export_track_images: Option<String>,
+    /// Treat the input as a Greaseweazle or FluxEngine capture directory,
+    /// or a SuperCard Pro .scp flux image.
+    /// The decoded sector image is written to the output file.
+    #[clap(long)]
+    capture: bool,
+    /// Command to run on the parsed image
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands that work on a parsed image
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Search the disk for a string or a sequence of bytes
+    Grep {
+        /// The string to search for, or hex bytes with --hex
+        pattern: String,
+        /// Treat the pattern as hex bytes, e.g. "A9 00 8D"
+        #[clap(long)]
+        hex: bool,
+    },
+    /// Print a JSON report on the image, including the entropy and
+    /// content of every track and sector
+    Report {
+        /// Directory to cache reports in, so unchanged images aren't
+        /// parsed again
+        #[clap(long)]
+        cache: Option<String>,
+    },
+    /// Print a single line of sector and file counts, for triaging
+    /// large collections
+    Stats {
+        /// Print the counts as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// List where each header, directory sector and file is in the
+    /// image, by byte offset
+    Map {
+        /// Print the source map as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print the volumes, directories and files on the image as a tree
+    Tree {
+        /// Print the sectors of each file too
+        #[clap(long)]
+        sectors: bool,
+    },
+    /// List data in allocated but unreferenced sectors and in the slack
+    /// space after the end of files
+    Slack {
+        /// Directory to write each region to
+        #[clap(long)]
+        dir: Option<String>,
+    },
+    /// Recover files from a disk with a damaged catalog or directory
+    /// The image doesn't need to parse, only its size is used to find
+    /// the geometry.
+    Carve {
+        /// Directory to write the carved files to
+        #[clap(long)]
+        dir: Option<String>,
+    },
+    /// List the CP/M directory on the disk's sectors, or on a flat
+    /// sector dump if the image doesn't parse, and extract the files
+    Cpm {
+        /// The disk format: ibm-3740, kaypro2, kaypro4, amstrad-data or
+        /// amstrad-system
+        /// cpm- settings in the configuration file override its layout.
+        #[clap(long, default_value = "kaypro2")]
+        format: String,
+        /// Directory to write the files to
+        #[clap(long)]
+        dir: Option<String>,
+    },
+    /// Build a bootable DOS 3.3 disk with the DOS from the input image,
+    /// a 35 track DOS-order .dsk, and add files to it
+    Bootable {
+        /// The disk image to write
+        output: String,
+        /// Host files to add, named on the disk by their uppercased
+        /// file names
+        files: Vec<String>,
+        /// The DOS file type of the added files: T, I, A, B, S or R
+        #[clap(long, default_value = "B")]
+        file_type: String,
+        /// Load address of binary files, in hex; the address and length
+        /// are added before the data.  Without it files are written as
+        /// they are, e.g. files saved from another DOS disk.
+        #[clap(long)]
+        address: Option<String>,
+    },
+    /// Write a blank formatted flat image, with every sector filled
+    /// with a fill pattern
+    /// The input file isn't read.
+    Blank {
+        /// The image to write
+        output: String,
+        /// The size of the image in bytes, which picks its geometry,
+        /// e.g. 143360 for an Apple DOS 3.3 disk or 737280 for an Atari
+        /// ST disk
+        #[clap(long, default_value = "143360")]
+        size: usize,
+        /// The fill pattern: zero, cpm, dos33 or hex bytes, e.g. E5
+        #[clap(long)]
+        fill: Option<String>,
+        /// Patterns for single tracks, e.g. 0=00,17=DB6D
+        #[clap(long)]
+        track_fill: Option<String>,
+    },
+    /// List the supported formats with their extensions, sizes,
+    /// capabilities and references
+    /// The input file isn't read.
+    Formats {
+        /// Print a table of the capabilities of each format
+        #[clap(long, conflicts_with = "json")]
+        matrix: bool,
+        /// Print each format as a JSON object
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check the sector CRCs and boot sector checksum of an STX image
+    /// without parsing it, exiting with status 3 if any sector has a
+    /// CRC error
+    Verify,
+    /// Print the format of the image and the key fields of its header
+    /// on one line, like file(1), without parsing the rest of it
+    /// Exits with status 2 if the format isn't known.
+    Identify {
+        /// Print the format as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Identify known DOS and boot sector versions from their checksums
+    Fingerprint {
+        /// Extra fingerprint database to check, in TOML
+        #[clap(long)]
+        database: Option<String>,
+        /// Print database entries for the image's system areas with this
+        /// name instead, for adding a known good disk to a database
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// List the contents of archives stored in files on the disk, like
+    /// Lynx, ShrinkIt, LHA and ZIP archives
+    Archives,
+    /// Write an Apple DOS or nibble disk as a WOZ 2.0 image
+    Woz {
+        /// The WOZ file to write
+        output: String,
+        /// Mark the image as write protected, by default it's write
+        /// protected if the input image is
+        #[clap(long, conflicts_with = "writable")]
+        write_protected: bool,
+        /// Mark the image as writable even if the input image is write
+        /// protected
+        #[clap(long)]
+        writable: bool,
+        /// Volume number to write instead of the disk's own, 1 to 254
+        #[clap(long)]
+        volume_number: Option<u8>,
+    },
+    /// Write the disk as a flat sector dump
+    Raw {
+        /// The file to write
+        output: String,
+        /// The order of the sides, "alternating" or "sequential"
+        #[clap(long, default_value = "alternating")]
+        sides: String,
+        /// The sector written at each position on a track, counting
+        /// from the first sector, e.g. "0,7,14,6,13,5,12,4,11,3,10,2,9,1,8,15"
+        #[clap(long)]
+        interleave: Option<String>,
+    },
+    /// Split the Apple DOS random-access text file selected with
+    /// --filename into records
+    Records {
+        /// The record length the file was opened with
+        length: usize,
+        /// The directory to write one file per record to, or the CSV
+        /// file to write with --csv
+        output: String,
+        /// Write the records as CSV, one row per record
+        #[clap(long)]
+        csv: bool,
+    },
+    /// Write a manifest of the CRC-32 of every sector, or check the
+    /// image against one made earlier
+    Manifest {
+        /// The manifest file to write, standard output if not given
+        output: Option<String>,
+        /// Check the image against this manifest instead
+        #[clap(long)]
+        verify: Option<String>,
+    },
+    /// Write Reed-Solomon parity data over the sectors of the image, for
+    /// repairing it later with --parity
+    #[cfg(feature = "parity")]
+    Parity {
+        /// The parity sidecar to write
+        output: String,
+    },
+    /// Merge the input, the first side of a disk, with dumps of its
+    /// other sides into one double-sided flat image
+    /// The image doesn't need to parse, the geometry of a side is
+    /// found from the size of the input.
+    Merge {
+        /// The dumps of the other sides, in side order
+        sides: Vec<String>,
+        /// The merged image to write
+        #[clap(long)]
+        output: String,
+        /// The order of the sides in the merged image, "alternating" or
+        /// "sequential"
+        #[clap(long, default_value = "alternating")]
+        order: String,
+    },
+    /// Find duplicates, variant dumps and label collisions between the
+    /// input and other images
+    Collection {
+        /// The other images to compare
+        images: Vec<String>,
+        /// The fraction of sectors variants must share
+        #[clap(long, default_value_t = DEFAULT_VARIANT_THRESHOLD)]
+        threshold: f64,
+    },
+    /// Group the input and other images into multi-disk sets by the
+    /// disk numbers in their file and volume names
+    Sets {
+        /// The other images to group
+        images: Vec<String>,
+        /// Print each set as a JSON object
+        #[clap(long, conflicts_with = "m3u")]
+        json: bool,
+        /// Print each set as an M3U playlist for emulators
+        #[clap(long)]
+        m3u: bool,
+    },
 }
 
 /// Open up a file and read in the data
 /// Returns all the data as a u8 vector
 pub fn open_file(filename: &str) -> Vec<u8> {
-    let path = Path::new(&filename);
-
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("Couldn't open {}: {}", path.display(), why),
-        Ok(file) => file,
-    };
-
-    let mut data = Vec::<u8>::new();
+    open_device(filename, &DeviceOptions::default())
+}
 
-    let result = file.read_to_end(&mut data);
+/// Read in the data of a file or a block device, e.g. a USB floppy
+/// drive
+/// Returns all the data as a u8 vector
+pub fn open_device(filename: &str, options: &DeviceOptions) -> Vec<u8> {
+    let path = Path::new(&filename);
 
-    match result {
-        Err(why) => {
-            error!("Error reading file: {}", why);
-            panic!("Error reading file: {}", why);
+    match read_image(path, options) {
+        Err(why) => fail(&Error::new(ErrorKind::Message(format!(
+            "Couldn't read {}: {}",
+            path.display(),
+            why
+        )))),
+        Ok(data) => {
+            info!("Read {}: {} bytes", path.display(), data.len());
+            data
         }
-        Ok(result) => info!("Read {}: {} bytes", path.display(), result),
-    };
+    }
+}
 
-    data
+/// Log an error and exit with the code for its kind
+fn fail(e: &Error) -> ! {
+    error!("{}", e);
+    exit(e.exit_code())
 }
 
 /// Parse an image file
@@ -75,7 +441,7 @@ fn main() {
     }
 
     let settings_result = load_settings("config/image-rider.toml");
-    let mut settings = match settings_result {
+    let settings = match settings_result {
         Ok(settings) => {
             info!("merged in config");
             if let Ok(b) = settings.get_bool("debug") {
@@ -89,57 +455,606 @@ fn main() {
         }
     };
 
-    // See the comment in the load_settings function about a better solution to this
-    if args.ignore_checksums == true {
-        #[allow(deprecated)]
-        settings
-            .set("ignore-checksums", args.ignore_checksums)
-            .unwrap();
+    // Command line arguments override the settings from the file
+    let mut settings = Options::new(settings);
+    let mut overrides: Vec<(&str, config::Value)> = Vec::new();
+    if args.ignore_checksums {
+        overrides.push(("ignore-checksums", args.ignore_checksums.into()));
+    }
+    if args.unwrap {
+        overrides.push(("unwrap", args.unwrap.into()));
+    }
+    if let Some(volume) = args.volume {
+        overrides.push(("volume", i64::from(volume).into()));
+    }
+    for (key, value) in [
+        ("tracks", args.tracks.map(u16::from)),
+        ("sectors", args.sectors.map(u16::from)),
+        ("sector-size", args.sector_size),
+        ("sides", args.sides.map(u16::from)),
+    ] {
+        if let Some(value) = value {
+            overrides.push((key, i64::from(value).into()));
+        }
+    }
+    if let Some(Command::Blank {
+        fill, track_fill, ..
+    }) = &args.command
+    {
+        if let Some(fill) = fill {
+            overrides.push(("fill-pattern", fill.as_str().into()));
+        }
+        if let Some(track_fill) = track_fill {
+            overrides.push(("track-fill-patterns", track_fill.as_str().into()));
+        }
+    }
+    for (key, value) in overrides {
+        settings = settings
+            .with_override(key, value)
+            .unwrap_or_else(|e| fail(&e));
+    }
+
+    if args.capture {
+        if let Err(e) = ingest_capture(&args, &settings) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Formats { matrix, json }) = &args.command {
+        if let Err(e) = list_formats(*matrix, *json) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Blank { output, size, .. }) = &args.command {
+        if let Err(e) = blank(&settings, output, *size) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    // The settings are shared from here on, commands override them
+    // for a single parse
+    // Formats that aren't read natively can be converted by external
+    // tools when they're allowed in the settings
+    let (data, filename) = match convert_external(&settings, Path::new(&args.input)) {
+        Ok(Some(converted)) => (converted.data, converted.filename),
+        Ok(None) => {
+            let options = DeviceOptions {
+                require_read_only: args.write_protected,
+                limits: settings.limits(),
+            };
+            (open_device(&args.input, &options), args.input.clone())
+        }
+        Err(e) => {
+            fail(&e);
+        }
+    };
+
+    if let Some(modified_filename) = &args.diff {
+        if let Err(e) = write_overlay(&args, &data, modified_filename) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    let data = match &args.overlay {
+        Some(overlay_filename) => match apply_overlay(&data, overlay_filename) {
+            Ok(data) => data,
+            Err(e) => {
+                fail(&e);
+            }
+        },
+        None => data,
+    };
+
+    #[cfg(feature = "parity")]
+    let data = match &args.parity {
+        Some(parity_filename) => match repair(&data, parity_filename) {
+            Ok(data) => data,
+            Err(e) => {
+                fail(&e);
+            }
+        },
+        None => data,
+    };
+
+    if let Some(Command::Identify { json }) = &args.command {
+        let id = identify(&data);
+        if *json {
+            match id.to_json() {
+                Ok(json) => println!("{}", json),
+                Err(e) => fail(&e),
+            }
+        } else {
+            println!("{}: {}", args.input, id);
+        }
+        exit(if id.is_known() {
+            EXIT_OK
+        } else {
+            EXIT_UNKNOWN_FORMAT
+        });
+    }
+
+    if let Some(Command::Carve { dir }) = &args.command {
+        match carve(&data, dir.as_deref()) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Cpm { format, dir }) = &args.command {
+        let settings = settings
+            .with_override("cpm-format", format.as_str())
+            .unwrap_or_else(|e| fail(&e));
+        if let Err(e) = cpm(&settings, &filename, &data, dir.as_deref()) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Bootable {
+        output,
+        files,
+        file_type,
+        address,
+    }) = &args.command
+    {
+        if let Err(e) = bootable(
+            &settings,
+            &data,
+            output,
+            files,
+            file_type,
+            address.as_deref(),
+        ) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Verify) = &args.command {
+        match verify_stx(&data) {
+            Ok(verification) => {
+                println!("{}: {}", args.input, verification);
+                exit(if verification.passed() {
+                    EXIT_OK
+                } else {
+                    EXIT_CORRUPT_IMAGE
+                });
+            }
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Merge {
+        sides,
+        output,
+        order,
+    }) = &args.command
+    {
+        if let Err(e) = merge(&data, sides, output, order) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Collection { images, threshold }) = &args.command {
+        match collection(&settings, &args.input, images, *threshold) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Sets { images, json, m3u }) = &args.command {
+        match image_sets(&settings, &args.input, images, *json, *m3u) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    // Reports for unchanged images come straight from the cache
+    // The cache is keyed by contents alone, so it isn't used when a
+    // volume is selected
+    let cache_dir = match &args.command {
+        Some(Command::Report { cache }) if args.volume.is_none() => cache.as_deref(),
+        _ => None,
+    };
+    if let Some(dir) = cache_dir {
+        let cached = ContentCache::<Report>::with_directory(1, PathBuf::from(dir))
+            .map(|mut cache| cache.get(&ContentHash::new(&data)));
+        if let Ok(Some(report)) = cached {
+            match report.to_json() {
+                Ok(json) => {
+                    println!("{}", json);
+                    exit(EXIT_OK);
+                }
+                Err(e) => {
+                    fail(&e);
+                }
+            }
+        }
+    }
+
+    // ROM images have their own parser, they're listed and saved but
+    // the disk commands don't apply to them
+    if is_rom_image(&filename, &data) {
+        match data.parse_rom_image(&settings, &filename) {
+            Ok(rom_image) => {
+                println!("ROM: {}", rom_image);
+                for warning in &rom_image.warnings {
+                    println!("Warning: {}", warning);
+                }
+                if let Err(e) = write_file(&settings, &args, &*rom_image) {
+                    fail(&e);
+                }
+                exit(EXIT_OK);
+            }
+            Err(e) => {
+                fail(&e);
+            }
+        }
     }
 
-    let data = open_file(&args.input);
+    let result = data.parse_disk_image(&settings, &filename);
 
-    let result = data.parse_disk_image(&settings, &args.input);
+    // Nibble disks holding a DOS 3.3 filesystem are read again from
+    // their decoded sectors, so the catalog can be listed and files
+    // extracted
+    let nibble_dos_data = result
+        .as_ref()
+        .ok()
+        .and_then(|image| disk_image_nibble_dos_data(image));
+    let result = match (&nibble_dos_data, result) {
+        (Some(dos_data), Ok(image)) => match parse_nibble_dos_image(dos_data) {
+            Ok(mut dos_image) => {
+                let mut warnings = image.warnings;
+                warnings.append(&mut dos_image.warnings);
+                dos_image.warnings = warnings;
+                Ok(dos_image)
+            }
+            Err(e) => {
+                info!("{}", e);
+                Ok(image)
+            }
+        },
+        (_, result) => result,
+    };
 
     let image = match result {
         Err(e) => {
-            error!("{}", e);
-            exit(1);
+            fail(&e);
         }
         Ok(res) => {
-            println!("Disk: {}", res);
+            // Keep standard output valid JSON or TOML for reports and
+            // fingerprint entries
+            if !matches!(
+                args.command,
+                Some(Command::Report { .. })
+                    | Some(Command::Stats { .. })
+                    | Some(Command::Map { json: true })
+                    | Some(Command::Fingerprint { name: Some(_), .. })
+                    | Some(Command::Manifest {
+                        output: None,
+                        verify: None
+                    })
+            ) {
+                println!("Disk: {}", res);
+                for warning in &res.warnings {
+                    println!("Warning: {}", warning);
+                }
+            }
             res
         }
     };
 
-    let result = write_file(&settings, &args, &image);
-    match result {
-        Err(e) => {
-            error!("{}", e);
-            exit(1);
+    if let Some(Command::Grep { pattern, hex }) = &args.command {
+        match grep(&image, pattern, *hex) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Report { .. }) = &args.command {
+        match report(&image, &data, cache_dir).and_then(|report| report.to_json()) {
+            Ok(json) => {
+                println!("{}", json);
+                exit(EXIT_OK);
+            }
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Stats { json }) = &args.command {
+        let stats = image.stats();
+        if *json {
+            match stats.to_json() {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    fail(&e);
+                }
+            }
+        } else {
+            println!("{}: {}", args.input, stats);
         }
-        Ok(_) => {}
+        exit(EXIT_OK);
     }
 
-    exit(0);
+    if let Some(Command::Map { json }) = &args.command {
+        if *json {
+            match image.source_map.to_json() {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    fail(&e);
+                }
+            }
+        } else {
+            print!("{}", image.source_map);
+        }
+        exit(if image.source_map.regions.is_empty() {
+            EXIT_NO_MATCH
+        } else {
+            EXIT_OK
+        });
+    }
+
+    if let Some(Command::Tree { sectors }) = &args.command {
+        let tree = image.tree();
+        print_tree(&tree, &tree.root(), 0, *sectors);
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Slack { dir }) = &args.command {
+        match slack(&image, dir.as_deref()) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Fingerprint { database, name }) = &args.command {
+        match fingerprint(&image, database.as_deref(), name.as_deref()) {
+            Ok(0) => exit(EXIT_NO_MATCH),
+            Ok(_) => exit(EXIT_OK),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    if let Some(Command::Manifest { output, verify }) = &args.command {
+        match manifest(&image, output.as_deref(), verify.as_deref()) {
+            Ok(true) => exit(EXIT_OK),
+            Ok(false) => exit(EXIT_CORRUPT_IMAGE),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    #[cfg(feature = "parity")]
+    if let Some(Command::Parity { output }) = &args.command {
+        let result = image
+            .parity(
+                &ParityOptions::from_config(&settings),
+                &CancellationToken::new(),
+            )
+            .and_then(|parity| parity.as_vec())
+            .and_then(|data| Ok(std::fs::write(output, data)?));
+        if let Err(e) = result {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Archives) = &args.command {
+        let archives = image.archives();
+        for (name, archive) in &archives {
+            print!("{}: {}", name, archive);
+        }
+        exit(if archives.is_empty() {
+            EXIT_NO_MATCH
+        } else {
+            EXIT_OK
+        });
+    }
+
+    if let Some(Command::Woz {
+        output,
+        write_protected,
+        writable,
+        volume_number,
+    }) = &args.command
+    {
+        let settings = if *write_protected || *writable {
+            settings
+                .with_override("write-protected", *write_protected)
+                .unwrap_or_else(|e| fail(&e))
+        } else {
+            settings.clone()
+        };
+        let result = disk_image_to_woz(
+            &image,
+            *volume_number,
+            image.converted_write_protected(&settings),
+            &CancellationToken::new(),
+        )
+        .and_then(|woz| Ok(std::fs::write(output, woz)?));
+        if let Err(e) = result {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Raw {
+        output,
+        sides,
+        interleave,
+    }) = &args.command
+    {
+        let result = raw_order(sides, interleave.as_deref())
+            .and_then(|order| image.export_raw(&order, None))
+            .and_then(|data| Ok(std::fs::write(output, data)?));
+        if let Err(e) = result {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    if let Some(Command::Records {
+        length,
+        output,
+        csv,
+    }) = &args.command
+    {
+        if let Err(e) = write_records(&args, &image, *length, output, *csv) {
+            fail(&e);
+        }
+        exit(EXIT_OK);
+    }
+
+    let result = write_file(&settings, &args, &*image);
+    if let Err(e) = result {
+        fail(&e);
+    }
+
+    if let Some(dir) = &args.export_tracks {
+        if let Err(e) = export_tracks(&args, &image, dir) {
+            fail(&e);
+        }
+    }
+
+    if let Some(dir) = &args.export_track_images {
+        match image.export_track_images(Path::new(dir), &CancellationToken::new()) {
+            Ok(index) => println!("Wrote {} track images", index.tracks.len()),
+            Err(e) => {
+                fail(&e);
+            }
+        }
+    }
+
+    exit(EXIT_OK);
+}
+
+/// Build the sector order for a raw export from the command line
+/// options
+fn raw_order(
+    sides: &str,
+    interleave: Option<&str>,
+) -> std::result::Result<RawOrder, image_rider::error::Error> {
+    let interleave = match interleave {
+        Some(interleave) => interleave
+            .split(',')
+            .map(|sector| {
+                sector.trim().parse::<u8>().map_err(|e| {
+                    Error::new(ErrorKind::Message(format!(
+                        "Invalid interleave sector {}: {}",
+                        sector, e
+                    )))
+                })
+            })
+            .collect::<std::result::Result<Vec<u8>, _>>()?,
+        None => Vec::new(),
+    };
+
+    Ok(RawOrder {
+        sides: sides.parse()?,
+        interleave,
+    })
+}
+
+/// Split the selected text file into records, writing them to a
+/// directory or a CSV file
+fn write_records(
+    args: &Args,
+    image: &DiskImage,
+    length: usize,
+    output: &str,
+    csv: bool,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let filename = args.filename.as_deref().ok_or_else(|| {
+        Error::new(ErrorKind::Message(String::from(
+            "A file must be selected with --filename",
+        )))
+    })?;
+    let file = image
+        .disk_files()
+        .into_iter()
+        .find(|file| file.name == filename)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound(String::from(filename))))?;
+
+    if csv {
+        std::fs::write(
+            output,
+            records_to_csv(&file.data, length, &image.charset())?,
+        )?;
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(output)?;
+    for (n, fields) in records(&file.data, length, &image.charset())? {
+        let mut text = fields.join("\n");
+        text.push('\n');
+        std::fs::write(Path::new(output).join(format!("{}.txt", n)), text)?;
+    }
+    Ok(())
 }
 
 /// Save a file from the image to disk if the user specifies it.
 fn write_file(
     settings: &Config,
     args: &Args,
-    image: &DiskImage,
+    image: &impl DiskImageSaver,
 ) -> std::result::Result<(), image_rider::error::Error> {
     // Find the type of disk image and write the track or sector data if its available
     if let Some(output_filename) = &args.output {
         info!("Got output filename, testing for image data");
 
+        let selection = match &args.filename {
+            _ if args.all_files => Some(FileSelection::All),
+            Some(s) => Some(s.parse::<FileSelection>()?).filter(|s| s.is_multiple()),
+            None => None,
+        };
+        if let Some(selection) = selection {
+            let default = NamingPolicy::from_config(settings);
+            let naming = NamingPolicy {
+                template: args.name_template.clone().unwrap_or(default.template),
+                sanitize: args.sanitize.unwrap_or(default.sanitize),
+                collisions: args.collisions.unwrap_or(default.collisions),
+                sidecar: args.sidecar || default.sidecar,
+            };
+            let paths =
+                image.save_files(settings, &selection, Path::new(output_filename), &naming)?;
+            println!("Wrote {} files", paths.len());
+            return Ok(());
+        }
+
         match &args.filename {
             Some(s) => {
-                image.save_disk_image(&settings, Some(s.as_str()), &output_filename)?;
+                image.save_disk_image(settings, Some(s.as_str()), output_filename)?;
             }
             None => {
-                image.save_disk_image(&settings, None, &output_filename)?;
+                image.save_disk_image(settings, None, output_filename)?;
             }
         };
         println!("Wrote file");
@@ -148,16 +1063,514 @@ fn write_file(
     Ok(())
 }
 
+/// Search the image for a pattern and print the hits
+/// Returns the number of hits
+fn grep(
+    image: &DiskImage,
+    pattern: &str,
+    hex: bool,
+) -> std::result::Result<usize, image_rider::error::Error> {
+    let pattern = if hex {
+        Pattern::from_hex(pattern)?
+    } else {
+        Pattern::Text(String::from(pattern))
+    };
+
+    let hits = image.search(&pattern);
+    for hit in &hits {
+        println!("{}", hit);
+    }
+
+    Ok(hits.len())
+}
+
+/// Print the usage map summary and the regions that may hold hidden
+/// data, optionally writing each region to a directory
+/// Returns the number of regions found
+/// Print a node of an image tree and its children, expanding each
+/// node as it's reached
+fn print_tree(tree: &ImageTree, node: &TreeNode, depth: usize, sectors: bool) {
+    if node.kind == NodeKind::Sector && !sectors {
+        return;
+    }
+    print!("{:width$}{}", "", node.name, width = depth * 2);
+    if node.kind == NodeKind::Directory {
+        print!("/");
+    }
+    if let (Some(file_type), Some(size)) = (&node.file_type, node.size) {
+        print!(" ({}, {} bytes)", file_type, size);
+    }
+    println!();
+    if node.has_children && (node.kind != NodeKind::File || sectors) {
+        for child in tree.children(&node.id) {
+            print_tree(tree, &child, depth + 1, sectors);
+        }
+    }
+}
+
+fn slack(
+    image: &DiskImage,
+    dir: Option<&str>,
+) -> std::result::Result<usize, image_rider::error::Error> {
+    let usage = image.usage().ok_or_else(|| {
+        Error::new(ErrorKind::Unimplemented(format!(
+            "No sector usage map for {}",
+            image
+        )))
+    })?;
+    println!("{}", usage);
+
+    let regions = image.hidden_regions();
+    for region in &regions {
+        println!("{}", region);
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(
+                Path::new(dir).join(format!(
+                    "track{:02}.{}.sector{:02}-{:02X}.bin",
+                    region.id.track, region.id.head, region.id.sector, region.offset
+                )),
+                &region.data,
+            )?;
+        }
+    }
+
+    Ok(regions.len())
+}
+
+/// Compare a collection of images, printing the images that share a
+/// label and the duplicates and variants found
+/// Images that don't parse are skipped.  Returns the number of
+/// related pairs.
+fn collection(
+    settings: &Options,
+    input: &str,
+    images: &[String],
+    threshold: f64,
+) -> std::result::Result<usize, image_rider::error::Error> {
+    let mut entries = Vec::new();
+    for path in std::iter::once(input).chain(images.iter().map(String::as_str)) {
+        let data = open_file(path);
+        match data.parse_disk_image(settings, path) {
+            Ok(image) => entries.push(CollectionEntry::new(path, &image, &data)),
+            Err(e) => error!("Skipping {}: {}", path, e),
+        }
+    }
+
+    for (label, paths) in group_by_label(&entries) {
+        if paths.len() > 1 {
+            println!("{}: {}", label, paths.join(", "));
+        }
+    }
+    let matches = scan_collection(&entries, threshold, &CancellationToken::new())?;
+    for m in &matches {
+        println!("{}", m);
+    }
+
+    Ok(matches.len())
+}
+
+/// Group images into multi-disk sets and print the sets with their
+/// disks in order
+/// Images that don't parse are skipped.  Returns the number of sets.
+fn image_sets(
+    settings: &Options,
+    input: &str,
+    images: &[String],
+    json: bool,
+    m3u: bool,
+) -> std::result::Result<usize, image_rider::error::Error> {
+    let mut members = Vec::new();
+    for path in std::iter::once(input).chain(images.iter().map(String::as_str)) {
+        let data = open_file(path);
+        match data.parse_disk_image(settings, path) {
+            Ok(image) => members.extend(SetMember::new(path, &image)),
+            Err(e) => error!("Skipping {}: {}", path, e),
+        }
+    }
+
+    let sets = group_image_sets(members);
+    for set in &sets {
+        if json {
+            println!("{}", set.to_json()?);
+        } else if m3u {
+            print!("{}", set.to_m3u());
+        } else {
+            print!("{}", set);
+        }
+    }
+
+    Ok(sets.len())
+}
+
+/// Merge the sides of a disk stored in separate files and write the
+/// merged image
+fn merge(
+    data: &[u8],
+    sides: &[String],
+    output: &str,
+    order: &str,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let side_geometry = Geometry::from_size(data.len())
+        .filter(|geometry| geometry.heads == 1)
+        .ok_or_else(|| {
+            Error::new(ErrorKind::Message(format!(
+                "No single-sided geometry is {} bytes",
+                data.len()
+            )))
+        })?;
+    let other_sides: Vec<Vec<u8>> = sides.iter().map(|side| open_file(side)).collect();
+    let all_sides: Vec<&[u8]> = std::iter::once(data)
+        .chain(other_sides.iter().map(Vec::as_slice))
+        .collect();
+    let (merged, geometry) = merge_sides(&all_sides, &side_geometry, order.parse()?)?;
+    println!(
+        "Merged {} sides of {} tracks, {} bytes",
+        geometry.heads,
+        geometry.tracks(),
+        merged.len()
+    );
+    std::fs::write(output, merged)?;
+    Ok(())
+}
+
+/// Build a report on the image, storing it in the cache directory if
+/// there is one
+fn report(
+    image: &DiskImage,
+    data: &[u8],
+    cache: Option<&str>,
+) -> std::result::Result<Report, image_rider::error::Error> {
+    match cache {
+        Some(dir) => ContentCache::with_directory(1, PathBuf::from(dir))?
+            .get_or_insert_with(data, || Ok(image.report())),
+        None => Ok(image.report()),
+    }
+}
+
+/// Print the known system areas on the image, or database entries for
+/// them if a name is given
+/// Returns the number of fingerprints matched or printed
+fn fingerprint(
+    image: &DiskImage,
+    database: Option<&str>,
+    name: Option<&str>,
+) -> std::result::Result<usize, image_rider::error::Error> {
+    if let Some(name) = name {
+        let tracks = image.tracks().ok_or_else(|| {
+            Error::new(ErrorKind::Unimplemented(format!(
+                "No track access for {}",
+                image
+            )))
+        })?;
+        let entries = fingerprint_disk(&tracks, name);
+        print!("{}", entries.to_toml()?);
+        return Ok(entries.fingerprints.len());
+    }
+
+    let mut fingerprints = FingerprintDatabase::builtin();
+    if let Some(database) = database {
+        fingerprints.extend(FingerprintDatabase::load(Path::new(database))?);
+    }
+
+    let matches = image.fingerprints(&fingerprints);
+    for fingerprint in &matches {
+        println!("{}", fingerprint);
+    }
+
+    Ok(matches.len())
+}
+
+/// Write a sector manifest for an image, or verify the image against
+/// one
+/// Returns false if verification found changed sectors.
+fn manifest(
+    image: &DiskImage,
+    output: Option<&str>,
+    verify: Option<&str>,
+) -> std::result::Result<bool, image_rider::error::Error> {
+    if let Some(filename) = verify {
+        let expected: Manifest = std::fs::read_to_string(filename)?.parse()?;
+        let diff = image.verify_manifest(&expected)?;
+        println!("{}", diff);
+        return Ok(diff.is_clean());
+    }
+
+    let manifest = image.manifest()?.to_string();
+    match output {
+        Some(filename) => std::fs::write(filename, manifest)?,
+        None => print!("{}", manifest),
+    }
+
+    Ok(true)
+}
+
+/// Carve files out of the image data, print them and optionally
+/// write them to a directory
+/// Returns the number of files found
+fn carve(data: &[u8], dir: Option<&str>) -> std::result::Result<usize, image_rider::error::Error> {
+    let geometry = geometry_for(data)?;
+    let files = carve::carve_image(data, &geometry, &CancellationToken::new())?;
+
+    for (i, file) in files.iter().enumerate() {
+        println!("{}: {}", i, file);
+        if let Some(dir) = dir {
+            let extension = match file.kind {
+                ContentKind::AppleSoftBasic | ContentKind::CommodoreBasic => "bas",
+                ContentKind::MachineCode => "bin",
+                ContentKind::Text => "txt",
+            };
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(
+                Path::new(dir).join(format!("carved{:03}.{}", i, extension)),
+                &file.data,
+            )?;
+        }
+    }
+
+    Ok(files.len())
+}
+
+/// List the CP/M directory of an image and write its files to a
+/// directory
+/// Images that don't parse are read as flat sector dumps.
+fn cpm(
+    settings: &Options,
+    filename: &str,
+    data: &Vec<u8>,
+    dir: Option<&str>,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let format = CpmFormat::from_config(settings)?;
+    let volume = match data.parse_disk_image(settings, filename) {
+        Ok(image) => image.cpm_volume(&format)?,
+        Err(_) => CpmVolume::parse(data.clone(), format)?,
+    };
+    println!("{}", volume);
+
+    if let Some(dir) = dir {
+        for file in volume.disk_files() {
+            let path = Path::new(dir).join(&file.name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, &file.data)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a bootable DOS 3.3 disk from the DOS on an image and add
+/// host files to it
+fn bootable(
+    settings: &Config,
+    data: &[u8],
+    output: &str,
+    files: &[String],
+    file_type: &str,
+    address: Option<&str>,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let file_type: FileType = file_type.parse()?;
+    let address = address
+        .map(|address| {
+            u16::from_str_radix(address.trim_start_matches('$'), 16).map_err(|_| {
+                Error::new(ErrorKind::Message(format!(
+                    "Invalid load address: {}",
+                    address
+                )))
+            })
+        })
+        .transpose()?;
+
+    let mut disk = make_bootable_with_patterns(data, &FormatPatterns::from_config(settings))?;
+    for file in files {
+        let path = Path::new(file);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_uppercase())
+            .unwrap_or_default();
+        let mut contents = std::fs::read(path)?;
+        if let (FileType::Binary, Some(address)) = (file_type, address) {
+            let mut binary = address.to_le_bytes().to_vec();
+            binary.extend_from_slice(&(contents.len() as u16).to_le_bytes());
+            binary.append(&mut contents);
+            contents = binary;
+        }
+        add_file(&mut disk, &name, file_type, &contents)?;
+        info!("Added {} to {}", name, output);
+    }
+    std::fs::write(output, disk)?;
+
+    Ok(())
+}
+
+/// Print the supported formats, as a capability table or JSON
+fn list_formats(matrix: bool, json: bool) -> std::result::Result<(), image_rider::error::Error> {
+    if matrix {
+        println!("{}", capability_matrix());
+        return Ok(());
+    }
+    for info in formats() {
+        if json {
+            println!("{}", info.to_json()?);
+        } else {
+            print!("{}", info);
+        }
+    }
+    Ok(())
+}
+
+/// Write a blank formatted image of a size, filled with the patterns
+/// from the settings
+fn blank(
+    settings: &Config,
+    output: &str,
+    size: usize,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let geometry = Geometry::from_size(size).ok_or_else(|| {
+        Error::new(ErrorKind::Message(format!(
+            "No known geometry for an image of {} bytes",
+            size
+        )))
+    })?;
+    let patterns = FormatPatterns::from_config(settings);
+    std::fs::write(output, blank_image(&geometry, &patterns))?;
+    info!(
+        "Wrote a blank image filled with {} to {}",
+        patterns.fill, output
+    );
+
+    Ok(())
+}
+
+/// Write the tracks of the image to individual files
+fn export_tracks(
+    args: &Args,
+    image: &DiskImage,
+    dir: &str,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let format: TrackFormat = args.track_format.parse()?;
+    let paths = image.export_tracks(Path::new(dir), format, &CancellationToken::new())?;
+    println!("Wrote {} track files", paths.len());
+
+    Ok(())
+}
+
+/// Decode a flux capture directory or SCP image, print a summary of
+/// the tracks and write the sector image to the output file
+fn ingest_capture(
+    args: &Args,
+    settings: &Options,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let path = Path::new(&args.input);
+    #[allow(unused_mut)]
+    let mut capture = if path.is_dir() {
+        capture::ingest_capture(path)?
+    } else {
+        let data = open_file(&args.input);
+        let (_, image) = scp_image_parser(settings)(&data)?;
+        println!("{}", image);
+        image.capture()
+    };
+    println!("{}", capture);
+
+    #[cfg(feature = "parity")]
+    if let Some(parity_filename) = &args.parity {
+        let (_, parity) = parity_parser(&open_file(parity_filename))?;
+        println!("{}", capture.repair(&parity)?);
+    }
+
+    for id in capture.bad_sectors() {
+        println!("Bad sector: {}", id);
+    }
+
+    if let Some(output_filename) = args.output.as_ref().filter(|f| f.ends_with(".stx")) {
+        std::fs::write(
+            output_filename,
+            write_stx(&capture.tracks, &BTreeMap::new()),
+        )?;
+        println!("Wrote STX file");
+    } else if let Some(output_filename) = &args.output {
+        let image = capture.image().ok_or_else(|| {
+            Error::new(ErrorKind::NotFound(String::from(
+                "No sectors found in capture",
+            )))
+        })?;
+        std::fs::write(output_filename, image)?;
+        println!("Wrote file");
+    }
+
+    Ok(())
+}
+
+/// Repair the damaged sectors of a flat image from a parity sidecar,
+/// returning the repaired image
+#[cfg(feature = "parity")]
+fn repair(
+    data: &[u8],
+    parity_filename: &str,
+) -> std::result::Result<Vec<u8>, image_rider::error::Error> {
+    let (_, parity) = parity_parser(&open_file(parity_filename))?;
+    let geometry = geometry_for(data)?;
+    let mut tracks = split_tracks(data, &geometry);
+    let report = parity.repair(&mut tracks)?;
+    println!("{}", report);
+
+    Ok(flatten_tracks(&tracks, &geometry))
+}
+
+/// Apply an overlay file to the image data, returning the modified image
+fn apply_overlay(
+    data: &[u8],
+    overlay_filename: &str,
+) -> std::result::Result<Vec<u8>, image_rider::error::Error> {
+    let overlay_data = open_file(overlay_filename);
+    let (_, overlay) = overlay_parser(&overlay_data)?;
+    let geometry = geometry_for(data)?;
+
+    overlay.apply(data, &geometry)
+}
+
+/// Write the sectors that differ between the input image and a
+/// modified image to the output file as an overlay
+fn write_overlay(
+    args: &Args,
+    data: &[u8],
+    modified_filename: &str,
+) -> std::result::Result<(), image_rider::error::Error> {
+    let output_filename = args.output.as_ref().ok_or_else(|| {
+        Error::new(ErrorKind::Message(String::from(
+            "An output filename is required to write an overlay",
+        )))
+    })?;
+    let modified = open_file(modified_filename);
+    let geometry = geometry_for(data)?;
+
+    let overlay = Overlay::diff(data, &modified, &geometry)?;
+    std::fs::write(output_filename, overlay.as_vec()?)?;
+    println!("Wrote overlay with {} sectors", overlay.sectors.len());
+
+    Ok(())
+}
+
+/// Guess the sector geometry of a flat image
+fn geometry_for(data: &[u8]) -> std::result::Result<Geometry, image_rider::error::Error> {
+    Geometry::from_size(data.len()).ok_or_else(|| {
+        Error::new(ErrorKind::Unimplemented(format!(
+            "Unknown geometry for image size: {}",
+            data.len()
+        )))
+    })
+}
+
 /// load settings from a config file
 /// returns the config settings as a Config on success, or a ConfigError on failure
-fn load_settings<'a>(config_name: &str) -> Result<Config, config::ConfigError> {
-    let builder = Config::builder()
+fn load_settings(config_name: &str) -> Result<Config, config::ConfigError> {
+    Config::builder()
         // Add in config file
         .add_source(config::File::with_name(config_name))
         // Add in settings from the environment (with a prefix of APP)
         // Eg.. `APP_DEBUG=1 ./target/command_bar_widget would set the `debug` key
         .add_source(config::Environment::with_prefix("APP"))
-        .build();
-
-    builder
+        .build()
 }
@@ -0,0 +1,582 @@
+//! AmigaDOS blocks
+//!
+//! An AmigaDOS volume is a list of 512 byte blocks.  The first two
+//! blocks are the boot block, which starts with "DOS" and a flags
+//! byte that selects the original (OFS) or fast (FFS) filesystem.
+//! The root block in the middle of the disk holds the volume name, a
+//! hash table of the entries in the root directory and the blocks of
+//! the free block bitmap.
+//!
+//! Files and directories have a header block with their name, their
+//! size and protection bits and a link to the next entry with the
+//! same hash.  A directory header holds a hash table of its own, a
+//! file header holds a table of its data blocks, last block first,
+//! continued in extension blocks for files with more than 72 blocks.
+//! OFS data blocks have a 24 byte header of their own, FFS data blocks
+//! are all data.
+//!
+//! Every header block has a checksum that makes the big-endian longs
+//! of the block sum to zero.  All numbers are big-endian.
+use std::fmt::{Display, Formatter, Result};
+
+use log::debug;
+use nom::bytes::complete::{tag, take};
+use nom::multi::count;
+use nom::number::complete::{be_i32, be_u32, be_u8};
+use nom::IResult;
+
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// The size of a block
+pub const BLOCK_SIZE: usize = 512;
+
+/// The size of the boot block, which covers the first two blocks
+pub const BOOT_BLOCK_SIZE: usize = 2 * BLOCK_SIZE;
+
+/// The number of entries in a hash table or data block table
+pub const TABLE_SIZE: usize = 72;
+
+/// The offset of the checksum in a header block
+pub const HEADER_CHECKSUM_OFFSET: usize = 20;
+
+/// The size of the header on an OFS data block
+pub const OFS_DATA_HEADER_SIZE: usize = 24;
+
+/// The number of file bytes in an OFS data block
+pub const OFS_DATA_SIZE: usize = BLOCK_SIZE - OFS_DATA_HEADER_SIZE;
+
+/// The longest file or volume name
+pub const MAX_NAME_LENGTH: usize = 30;
+
+/// The number of bitmap block pointers in the root block
+pub const BITMAP_PAGES: usize = 25;
+
+/// The block type of root, file and directory headers
+pub const T_HEADER: u32 = 2;
+/// The block type of OFS data blocks
+pub const T_DATA: u32 = 8;
+/// The block type of file extension blocks
+pub const T_LIST: u32 = 16;
+
+/// The secondary type of the root block
+pub const ST_ROOT: i32 = 1;
+/// The secondary type of a directory header
+pub const ST_USERDIR: i32 = 2;
+/// The secondary type of a file header and its extension blocks
+pub const ST_FILE: i32 = -3;
+
+/// The boot block flag for the fast filesystem
+pub const FLAG_FFS: u8 = 0x01;
+/// The boot block flag for international name hashing
+pub const FLAG_INTERNATIONAL: u8 = 0x02;
+/// The boot block flag for directory cache blocks
+pub const FLAG_DIRCACHE: u8 = 0x04;
+
+/// The value the root block bitmap flag has when the bitmap is valid
+const BITMAP_VALID: u32 = 0xFFFF_FFFF;
+
+/// Return a block of a volume
+/// Returns an error if the block is past the end of the image.
+pub fn block(volume: &[u8], number: u32) -> std::result::Result<&[u8], Error> {
+    let start = number as usize * BLOCK_SIZE;
+    volume.get(start..start + BLOCK_SIZE).ok_or_else(|| {
+        Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+            "Block {} is past the end of the image",
+            number
+        ))))
+    })
+}
+
+/// The big-endian longs of a block
+fn longs(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(4)
+        .map(|long| u32::from_be_bytes(long.try_into().unwrap()))
+}
+
+/// Calculate the checksum of a header or bitmap block, the value that
+/// makes the longs of the block sum to zero
+/// The long at the checksum offset is left out of the sum.
+pub fn block_checksum(data: &[u8], offset: usize) -> u32 {
+    let sum = longs(data)
+        .enumerate()
+        .filter(|(n, _)| n * 4 != offset)
+        .fold(0_u32, |sum, (_, long)| sum.wrapping_add(long));
+    0_u32.wrapping_sub(sum)
+}
+
+/// Calculate the checksum of a boot block
+/// The longs are added with the carry wrapped around, and the stored
+/// checksum is the complement of the sum.  Kickstart only boots disks
+/// whose checksum matches.
+pub fn boot_checksum(data: &[u8]) -> u32 {
+    let sum = longs(&data[..data.len().min(BOOT_BLOCK_SIZE)])
+        .enumerate()
+        .filter(|(n, _)| *n != 1)
+        .fold(0_u32, |sum, (_, long)| {
+            let (sum, carry) = sum.overflowing_add(long);
+            sum + u32::from(carry)
+        });
+    !sum
+}
+
+/// Hash a name into a hash table
+/// Names are compared without case, international volumes also fold
+/// the accented ISO 8859-1 letters.
+pub fn name_hash(name: &[u8], international: bool) -> usize {
+    let upper = |c: u8| match c {
+        b'a'..=b'z' => c - 0x20,
+        0xE0..=0xFE if international && c != 0xF7 => c - 0x20,
+        _ => c,
+    };
+    let hash = name.iter().fold(name.len() as u32, |hash, c| {
+        (hash.wrapping_mul(13) + u32::from(upper(*c))) & 0x7FF
+    });
+    hash as usize % TABLE_SIZE
+}
+
+/// Convert a name to a string, names are stored in ISO 8859-1
+fn name_string(name: &[u8]) -> String {
+    name.iter().map(|c| char::from(*c)).collect()
+}
+
+/// A date and time, as days, minutes and fiftieths of a second since
+/// January 1 1978
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DateStamp {
+    /// The number of days since January 1 1978
+    pub days: u32,
+    /// The number of minutes since midnight
+    pub minutes: u32,
+    /// The number of fiftieths of a second since the minute
+    pub ticks: u32,
+}
+
+/// Format a date as "1989-04-12 13:05:20"
+impl Display for DateStamp {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        // Days since 0000-03-01, counting years from March so leap
+        // days are at the end of the year
+        let z = i64::from(self.days) + 2922 + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            self.minutes / 60,
+            self.minutes % 60,
+            self.ticks / 50
+        )
+    }
+}
+
+/// Parse a date stamp
+pub fn date_stamp_parser(i: &[u8]) -> IResult<&[u8], DateStamp> {
+    let (i, days) = be_u32(i)?;
+    let (i, minutes) = be_u32(i)?;
+    let (i, ticks) = be_u32(i)?;
+    Ok((
+        i,
+        DateStamp {
+            days,
+            minutes,
+            ticks,
+        },
+    ))
+}
+
+/// Parse a name stored as a length byte and 30 characters
+fn name_parser(i: &[u8]) -> IResult<&[u8], String> {
+    let (i, length) = be_u8(i)?;
+    let (i, name) = take(MAX_NAME_LENGTH)(i)?;
+    let length = usize::from(length).min(MAX_NAME_LENGTH);
+    Ok((i, name_string(&name[..length])))
+}
+
+/// The boot block at the start of an AmigaDOS disk
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BootBlock {
+    /// The filesystem flags after "DOS"
+    pub flags: u8,
+    /// The boot block checksum
+    pub checksum: u32,
+    /// The root block, not always set on disks that don't boot
+    pub root_block: u32,
+}
+
+impl BootBlock {
+    /// Return true if the disk uses the fast filesystem
+    pub fn is_ffs(&self) -> bool {
+        self.flags & FLAG_FFS != 0
+    }
+
+    /// Return true if names are hashed with international case folding
+    /// Directory cache disks always use international hashing.
+    pub fn is_international(&self) -> bool {
+        self.flags & (FLAG_INTERNATIONAL | FLAG_DIRCACHE) != 0
+    }
+
+    /// Return the name of the filesystem, e.g. "FFS-INTL"
+    pub fn filesystem_name(&self) -> String {
+        let filesystem = if self.is_ffs() { "FFS" } else { "OFS" };
+        if self.flags & FLAG_DIRCACHE != 0 {
+            format!("{}-DC", filesystem)
+        } else if self.flags & FLAG_INTERNATIONAL != 0 {
+            format!("{}-INTL", filesystem)
+        } else {
+            String::from(filesystem)
+        }
+    }
+}
+
+impl SanityCheck for BootBlock {
+    fn check(&self) -> bool {
+        if self.flags > 0x07 {
+            debug!(target: PARSE, "Invalid boot block flags: {:02X}", self.flags);
+            return false;
+        }
+        true
+    }
+}
+
+/// Parse the boot block
+pub fn boot_block_parser(i: &[u8]) -> IResult<&[u8], BootBlock> {
+    let (i, _) = tag(b"DOS")(i)?;
+    let (i, flags) = be_u8(i)?;
+    let (i, checksum) = be_u32(i)?;
+    let (i, root_block) = be_u32(i)?;
+    Ok((
+        i,
+        BootBlock {
+            flags,
+            checksum,
+            root_block,
+        },
+    ))
+}
+
+/// The root block, the header of the volume and its root directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RootBlock {
+    /// The block type, T_HEADER
+    pub block_type: u32,
+    /// The size of the hash table
+    pub hash_table_size: u32,
+    /// The block checksum
+    pub checksum: u32,
+    /// The first header block of each hash chain, zero if empty
+    pub hash_table: Vec<u32>,
+    /// True if the free block bitmap is valid
+    pub bitmap_valid: bool,
+    /// The blocks of the free block bitmap, zero if unused
+    pub bitmap_pages: Vec<u32>,
+    /// The first bitmap extension block, for hard disks
+    pub bitmap_extension: u32,
+    /// When the root directory was last changed
+    pub modified: DateStamp,
+    /// The volume name
+    pub name: String,
+    /// When the volume was last changed
+    pub volume_modified: DateStamp,
+    /// When the volume was formatted
+    pub created: DateStamp,
+    /// The secondary type, ST_ROOT
+    pub secondary_type: i32,
+}
+
+impl SanityCheck for RootBlock {
+    fn check(&self) -> bool {
+        if self.block_type != T_HEADER || self.secondary_type != ST_ROOT {
+            debug!(
+                target: PARSE,
+                "Invalid root block type: {}, {}", self.block_type, self.secondary_type
+            );
+            return false;
+        }
+        if self.hash_table_size as usize != TABLE_SIZE {
+            debug!(target: PARSE, "Invalid hash table size: {}", self.hash_table_size);
+            return false;
+        }
+        true
+    }
+}
+
+/// Parse the root block
+pub fn root_block_parser(i: &[u8]) -> IResult<&[u8], RootBlock> {
+    let (i, block_type) = be_u32(i)?;
+    let (i, _header_key) = be_u32(i)?;
+    let (i, _high_seq) = be_u32(i)?;
+    let (i, hash_table_size) = be_u32(i)?;
+    let (i, _first_data) = be_u32(i)?;
+    let (i, checksum) = be_u32(i)?;
+    let (i, hash_table) = count(be_u32, TABLE_SIZE)(i)?;
+    let (i, bitmap_flag) = be_u32(i)?;
+    let (i, bitmap_pages) = count(be_u32, BITMAP_PAGES)(i)?;
+    let (i, bitmap_extension) = be_u32(i)?;
+    let (i, modified) = date_stamp_parser(i)?;
+    let (i, name) = name_parser(i)?;
+    let (i, _reserved) = take(9_usize)(i)?;
+    let (i, volume_modified) = date_stamp_parser(i)?;
+    let (i, created) = date_stamp_parser(i)?;
+    let (i, _links) = take(12_usize)(i)?;
+    let (i, secondary_type) = be_i32(i)?;
+    Ok((
+        i,
+        RootBlock {
+            block_type,
+            hash_table_size,
+            checksum,
+            hash_table,
+            bitmap_valid: bitmap_flag == BITMAP_VALID,
+            bitmap_pages,
+            bitmap_extension,
+            modified,
+            name,
+            volume_modified,
+            created,
+            secondary_type,
+        },
+    ))
+}
+
+/// A file header, directory header or file extension block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EntryBlock {
+    /// The block type, T_HEADER or T_LIST for extension blocks
+    pub block_type: u32,
+    /// The number of this block
+    pub header_key: u32,
+    /// The number of data blocks in the table
+    pub high_seq: u32,
+    /// The first data block of a file
+    pub first_data: u32,
+    /// The block checksum
+    pub checksum: u32,
+    /// The hash table of a directory, or the data blocks of a file,
+    /// last entry first
+    pub table: Vec<u32>,
+    /// The protection bits
+    pub protect: u32,
+    /// The size of a file in bytes
+    pub byte_size: u32,
+    /// The file comment
+    pub comment: String,
+    /// When the entry was last changed
+    pub modified: DateStamp,
+    /// The name of the file or directory
+    pub name: String,
+    /// The next entry with the same hash
+    pub hash_chain: u32,
+    /// The header block of the parent directory
+    pub parent: u32,
+    /// The next extension block of a file, zero if there is none
+    pub extension: u32,
+    /// The secondary type, ST_FILE or ST_USERDIR
+    pub secondary_type: i32,
+}
+
+impl EntryBlock {
+    /// Return true if the entry is a directory
+    pub fn is_directory(&self) -> bool {
+        self.secondary_type == ST_USERDIR
+    }
+
+    /// Return true if the entry is a file
+    pub fn is_file(&self) -> bool {
+        self.secondary_type == ST_FILE
+    }
+
+    /// Return the data blocks in the table, in file order
+    pub fn data_blocks(&self) -> Vec<u32> {
+        let used = (self.high_seq as usize).min(TABLE_SIZE);
+        self.table.iter().rev().take(used).copied().collect()
+    }
+
+    /// The protection bits as AmigaDOS lists them, e.g. "----rwed"
+    /// The hold, script, pure and archive flags are shown when set,
+    /// the read, write, execute and delete flags when they're allowed,
+    /// which is when the bit is clear.
+    pub fn protection_string(&self) -> String {
+        "hsparwed"
+            .chars()
+            .enumerate()
+            .map(|(n, flag)| {
+                let set = self.protect & (0x80 >> n) != 0;
+                if set == (n < 4) {
+                    flag
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+}
+
+/// Format an entry as a line of a directory listing
+impl Display for EntryBlock {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let size = if self.is_directory() {
+            String::from("<DIR>")
+        } else {
+            self.byte_size.to_string()
+        };
+        write!(
+            f,
+            "{:<30} {:>8} {} {}",
+            self.name,
+            size,
+            self.protection_string(),
+            self.modified
+        )
+    }
+}
+
+/// Parse a file header, directory header or file extension block
+pub fn entry_block_parser(i: &[u8]) -> IResult<&[u8], EntryBlock> {
+    let (i, block_type) = be_u32(i)?;
+    let (i, header_key) = be_u32(i)?;
+    let (i, high_seq) = be_u32(i)?;
+    let (i, _data_size) = be_u32(i)?;
+    let (i, first_data) = be_u32(i)?;
+    let (i, checksum) = be_u32(i)?;
+    let (i, table) = count(be_u32, TABLE_SIZE)(i)?;
+    let (i, _reserved) = take(8_usize)(i)?;
+    let (i, protect) = be_u32(i)?;
+    let (i, byte_size) = be_u32(i)?;
+    let (i, comment_length) = be_u8(i)?;
+    let (i, comment) = take(79_usize)(i)?;
+    let (i, _reserved) = take(12_usize)(i)?;
+    let (i, modified) = date_stamp_parser(i)?;
+    let (i, name) = name_parser(i)?;
+    let (i, _reserved) = take(33_usize)(i)?;
+    let (i, hash_chain) = be_u32(i)?;
+    let (i, parent) = be_u32(i)?;
+    let (i, extension) = be_u32(i)?;
+    let (i, secondary_type) = be_i32(i)?;
+    let comment_length = usize::from(comment_length).min(comment.len());
+    Ok((
+        i,
+        EntryBlock {
+            block_type,
+            header_key,
+            high_seq,
+            first_data,
+            checksum,
+            table,
+            protect,
+            byte_size,
+            comment: name_string(&comment[..comment_length]),
+            modified,
+            name,
+            hash_chain,
+            parent,
+            extension,
+            secondary_type,
+        },
+    ))
+}
+
+/// An OFS data block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DataBlock<'a> {
+    /// The block type, T_DATA
+    pub block_type: u32,
+    /// The header block of the file
+    pub header_key: u32,
+    /// The position of the block in the file, starting at one
+    pub seq_num: u32,
+    /// The number of file bytes in the block
+    pub data_size: u32,
+    /// The next data block, zero for the last block
+    pub next_data: u32,
+    /// The block checksum
+    pub checksum: u32,
+    /// The file bytes, cut at the data size
+    pub data: &'a [u8],
+}
+
+/// Parse an OFS data block
+pub fn data_block_parser(i: &[u8]) -> IResult<&[u8], DataBlock<'_>> {
+    let (i, block_type) = be_u32(i)?;
+    let (i, header_key) = be_u32(i)?;
+    let (i, seq_num) = be_u32(i)?;
+    let (i, data_size) = be_u32(i)?;
+    let (i, next_data) = be_u32(i)?;
+    let (i, checksum) = be_u32(i)?;
+    let (i, data) = take(OFS_DATA_SIZE)(i)?;
+    Ok((
+        i,
+        DataBlock {
+            block_type,
+            header_key,
+            seq_num,
+            data_size,
+            next_data,
+            checksum,
+            data: &data[..(data_size as usize).min(OFS_DATA_SIZE)],
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        block_checksum, boot_checksum, entry_block_parser, name_hash, DateStamp, BLOCK_SIZE,
+        HEADER_CHECKSUM_OFFSET,
+    };
+
+    /// Test the block checksums and the name hash
+    #[test]
+    fn checksums_work() {
+        let mut data = vec![0_u8; BLOCK_SIZE];
+        data[0..4].copy_from_slice(&2_u32.to_be_bytes());
+        data[100] = 0x80;
+        let checksum = block_checksum(&data, HEADER_CHECKSUM_OFFSET);
+        data[20..24].copy_from_slice(&checksum.to_be_bytes());
+        let sum = data
+            .chunks_exact(4)
+            .map(|long| u32::from_be_bytes(long.try_into().unwrap()))
+            .fold(0_u32, u32::wrapping_add);
+        assert_eq!(sum, 0);
+
+        // The carry is added back in, so "DOS\0" followed by zeros
+        // doesn't sum to its own complement
+        let mut boot = vec![0_u8; 2 * BLOCK_SIZE];
+        boot[..4].copy_from_slice(b"DOS\0");
+        boot[8..12].copy_from_slice(&880_u32.to_be_bytes());
+        assert_eq!(boot_checksum(&boot), !(0x444F_5300 + 880));
+        boot[1020..].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(boot_checksum(&boot), !(0x444F_5300 + 880));
+
+        assert_eq!(name_hash(b"s", false), name_hash(b"S", false));
+        assert_eq!(name_hash(b"Startup-Sequence", false), 49);
+        assert_ne!(name_hash(b"\xE9", true), name_hash(b"\xE9", false));
+
+        let (_, entry) = entry_block_parser(&data).unwrap();
+        assert_eq!(entry.protection_string(), "----rwed");
+        assert_eq!(
+            DateStamp {
+                days: 4119,
+                minutes: 13 * 60 + 5,
+                ticks: 20 * 50
+            }
+            .to_string(),
+            "1989-04-12 13:05:20"
+        );
+    }
+}
@@ -0,0 +1,585 @@
+//! ADF disk images
+//!
+//! An ADF image is a flat dump of the blocks of an Amiga floppy, 880K
+//! for a double density disk and 1760K for a high density disk, with
+//! the blocks in track order and the two sides of each track next to
+//! each other.  The disk is parsed from the boot block and the root
+//! block in the middle of the disk, and the directory tree is walked
+//! on demand by following the hash tables and hash chains of each
+//! directory.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{error, warn};
+use nom::IResult;
+
+use crate::disk_format::amiga::block::{
+    block, block_checksum, boot_block_parser, boot_checksum, data_block_parser, entry_block_parser,
+    root_block_parser, BootBlock, EntryBlock, RootBlock, BLOCK_SIZE, HEADER_CHECKSUM_OFFSET,
+    OFS_DATA_HEADER_SIZE, OFS_DATA_SIZE, T_DATA, T_HEADER, T_LIST,
+};
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, SizeRange};
+use crate::disk_format::geometry::{Block, Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::check_chain_length;
+use crate::disk_format::logical::{split_tracks, LogicalTrack, RawExporter};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+use crate::log_target::{IO, PARSE};
+
+/// An Amiga ADF image
+pub const ADF_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "ADF",
+    description: "Amiga 880K or 1760K disk image with an OFS or FFS filesystem",
+    extensions: &["adf"],
+    sizes: &[SizeRange::exact(ADF_DD_SIZE), SizeRange::exact(ADF_HD_SIZE)],
+    references: &[],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The size of a double density ADF image
+pub const ADF_DD_SIZE: usize = 901120;
+
+/// The size of a high density ADF image
+pub const ADF_HD_SIZE: usize = 1802240;
+
+/// The deepest subdirectory that's followed
+const MAX_DIRECTORY_DEPTH: usize = 16;
+
+/// A file or directory on an Amiga disk, with its path from the root
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AmigaFile {
+    /// The path of the file, directory names joined with '/'
+    pub path: String,
+    /// The header block of the file
+    pub header_block: u32,
+    /// The header of the file
+    pub entry: EntryBlock,
+}
+
+/// An Amiga ADF disk
+pub struct AmigaDisk<'a> {
+    /// The blocks of the image
+    pub data: &'a [u8],
+    /// The track layout of the image
+    pub geometry: Geometry,
+    /// The boot block
+    pub boot_block: BootBlock,
+    /// The number of the root block
+    pub root_block_number: u32,
+    /// The root block
+    pub root_block: RootBlock,
+}
+
+/// Build an error for a damaged disk
+fn disk_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+impl AmigaDisk<'_> {
+    /// The number of blocks on the disk
+    pub fn total_blocks(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE) as u32
+    }
+
+    /// Return true if the disk uses the fast filesystem
+    pub fn is_ffs(&self) -> bool {
+        self.boot_block.is_ffs()
+    }
+
+    /// Return true if the boot block checksum is valid, so Kickstart
+    /// will boot from the disk
+    pub fn is_bootable(&self) -> bool {
+        boot_checksum(self.data) == self.boot_block.checksum
+    }
+
+    /// Return the volume name
+    pub fn label(&self) -> String {
+        self.root_block.name.clone()
+    }
+
+    /// Read a file or directory header block
+    /// Returns an error if the block isn't a header; a bad checksum is
+    /// only logged.
+    pub fn entry_block(&self, number: u32) -> std::result::Result<EntryBlock, Error> {
+        let data = block(self.data, number)?;
+        let (_, entry) = entry_block_parser(data)?;
+        if entry.block_type != T_HEADER || !(entry.is_file() || entry.is_directory()) {
+            return Err(disk_error(format!(
+                "Block {} isn't a file or directory header",
+                number
+            )));
+        }
+        if block_checksum(data, HEADER_CHECKSUM_OFFSET) != entry.checksum {
+            warn!(target: PARSE, "Bad checksum on header block {}", number);
+        }
+        Ok(entry)
+    }
+
+    /// Return every file and directory on the disk, in hash table
+    /// order with the contents of a directory after it
+    /// Entries that can't be read end their hash chain with a warning.
+    pub fn catalog(&self) -> Vec<AmigaFile> {
+        let mut files = Vec::new();
+        let mut visited = BTreeSet::from([self.root_block_number]);
+        self.walk(&self.root_block.hash_table, "", 0, &mut visited, &mut files);
+        files
+    }
+
+    /// Add the entries of a directory and its subdirectories to a list
+    /// Blocks already visited aren't followed again, which stops hash
+    /// chains and directories that loop.
+    fn walk(
+        &self,
+        hash_table: &[u32],
+        prefix: &str,
+        depth: usize,
+        visited: &mut BTreeSet<u32>,
+        files: &mut Vec<AmigaFile>,
+    ) {
+        for first in hash_table.iter().filter(|number| **number != 0) {
+            let mut next = *first;
+            while next != 0 {
+                if !visited.insert(next) {
+                    warn!(target: PARSE, "Block {} is linked twice, ending the chain", next);
+                    break;
+                }
+                let entry = match self.entry_block(next) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        warn!(target: PARSE, "Error reading {}block {}: {}", prefix, next, e);
+                        break;
+                    }
+                };
+                let path = format!("{}{}", prefix, entry.name);
+                files.push(AmigaFile {
+                    path: path.clone(),
+                    header_block: next,
+                    entry: entry.clone(),
+                });
+                next = entry.hash_chain;
+                if !entry.is_directory() {
+                    continue;
+                }
+                if depth >= MAX_DIRECTORY_DEPTH {
+                    warn!(target: PARSE, "Directory {} is nested too deeply, skipping it", path);
+                    continue;
+                }
+                self.walk(
+                    &entry.table,
+                    &format!("{}/", path),
+                    depth + 1,
+                    visited,
+                    files,
+                );
+            }
+        }
+    }
+
+    /// Return the data blocks and the extension blocks of a file, in
+    /// file order
+    pub fn file_blocks(
+        &self,
+        entry: &EntryBlock,
+    ) -> std::result::Result<(Vec<u32>, Vec<u32>), Error> {
+        let mut data_blocks = entry.data_blocks();
+        let mut extension_blocks = Vec::new();
+        let mut next = entry.extension;
+        while next != 0 {
+            let (_, extension) = entry_block_parser(block(self.data, next)?)?;
+            if extension.block_type != T_LIST {
+                return Err(disk_error(format!(
+                    "Block {} of {} isn't an extension block",
+                    next, entry.name
+                )));
+            }
+            extension_blocks.push(next);
+            data_blocks.extend(extension.data_blocks());
+            check_chain_length(data_blocks.len())?;
+            next = extension.extension;
+        }
+        Ok((data_blocks, extension_blocks))
+    }
+
+    /// Read the data of a file
+    /// OFS data blocks are checked against the file header and their
+    /// headers removed.  Returns an error if the blocks are damaged or
+    /// hold less than the file size.
+    pub fn read_file(&self, entry: &EntryBlock) -> std::result::Result<Vec<u8>, Error> {
+        let (blocks, _) = self.file_blocks(entry)?;
+        let mut data = Vec::new();
+        for (n, number) in blocks.iter().enumerate() {
+            let block_data = block(self.data, *number)?;
+            if self.is_ffs() {
+                data.extend_from_slice(block_data);
+                continue;
+            }
+            let (_, data_block) = data_block_parser(block_data)?;
+            if data_block.block_type != T_DATA
+                || data_block.header_key != entry.header_key
+                || data_block.seq_num as usize != n + 1
+            {
+                return Err(disk_error(format!(
+                    "Block {} isn't data block {} of {}",
+                    number,
+                    n + 1,
+                    entry.name
+                )));
+            }
+            data.extend_from_slice(data_block.data);
+        }
+        let size = entry.byte_size as usize;
+        if data.len() < size {
+            return Err(disk_error(format!(
+                "{} is {} bytes but its blocks only hold {}",
+                entry.name,
+                size,
+                data.len()
+            )));
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Return the image sector holding a block
+    pub fn block_sector(&self, number: Block) -> SectorId {
+        let number = number.get();
+        let sectors = u32::from(self.geometry.sectors_per_track[0]);
+        SectorId::new(
+            (number / (2 * sectors)) as u8,
+            ((number / sectors) % 2) as u8,
+            (number % sectors) as u8,
+        )
+    }
+
+    /// Return the image sectors holding a set of blocks, in block
+    /// order
+    fn sectors(&self, blocks: impl IntoIterator<Item = u32>) -> Vec<SectorId> {
+        blocks
+            .into_iter()
+            .filter(|number| *number < self.total_blocks())
+            .map(|number| self.block_sector(Block(number)))
+            .collect()
+    }
+
+    /// Return the blocks of the free block bitmap
+    pub fn bitmap_blocks(&self) -> Vec<u32> {
+        self.root_block
+            .bitmap_pages
+            .iter()
+            .copied()
+            .filter(|number| *number != 0)
+            .collect()
+    }
+
+    /// Return the blocks marked free in the bitmap
+    /// The bitmap starts at block two, the boot block isn't in it.
+    /// Each long after the checksum covers 32 blocks, a set bit is
+    /// free, starting from the lowest bit.
+    pub fn free_blocks(&self) -> BTreeSet<u32> {
+        let total = self.total_blocks();
+        let mut free = BTreeSet::new();
+        let mut number = 2;
+        for page in self.bitmap_blocks() {
+            let Ok(bitmap) = block(self.data, page) else {
+                break;
+            };
+            for long in bitmap[4..].chunks_exact(4) {
+                let long = u32::from_be_bytes(long.try_into().unwrap());
+                for bit in 0..32 {
+                    if number >= total {
+                        return free;
+                    }
+                    if long & (1 << bit) != 0 {
+                        free.insert(number);
+                    }
+                    number += 1;
+                }
+            }
+        }
+        free
+    }
+
+    /// Return the free sectors of the image
+    pub fn free_sectors(&self) -> BTreeSet<SectorId> {
+        self.sectors(self.free_blocks()).into_iter().collect()
+    }
+
+    /// Return the blocks used by AmigaDOS: the boot block, the root
+    /// block, the bitmap and the header and extension blocks of every
+    /// file and directory
+    pub fn system_blocks(&self) -> Vec<u32> {
+        let mut blocks = vec![0, 1, self.root_block_number];
+        blocks.extend(self.bitmap_blocks());
+        for file in self.catalog() {
+            blocks.push(file.header_block);
+            if let Ok((_, extension_blocks)) = self.file_blocks(&file.entry) {
+                blocks.extend(extension_blocks);
+            }
+        }
+        blocks
+    }
+
+    /// Return the sectors used by AmigaDOS
+    pub fn system_sectors(&self) -> BTreeSet<SectorId> {
+        self.sectors(self.system_blocks()).into_iter().collect()
+    }
+
+    /// Return the data sectors of each file
+    /// OFS data blocks start with a 24 byte header, which is included
+    /// in the end offset.
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        let (per_block, header) = if self.is_ffs() {
+            (BLOCK_SIZE, 0)
+        } else {
+            (OFS_DATA_SIZE, OFS_DATA_HEADER_SIZE)
+        };
+        let mut extents: Vec<FileExtent> = self
+            .catalog()
+            .into_iter()
+            .filter(|file| file.entry.is_file())
+            .filter_map(|file| {
+                let (data_blocks, _) = self.file_blocks(&file.entry).ok()?;
+                let size = file.entry.byte_size as usize;
+                Some(FileExtent {
+                    name: file.path,
+                    sectors: self.sectors(data_blocks),
+                    end: Some((size / per_block, header + size % per_block)),
+                })
+            })
+            .collect();
+        extents.sort_by(|a, b| a.name.cmp(&b.name));
+        extents
+    }
+}
+
+/// Format the disk as a directory listing, with the volume name and
+/// the free space
+/// The files in a directory are indented under it.
+impl Display for AmigaDisk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(
+            f,
+            "Volume: {} ({})",
+            self.root_block.name,
+            self.boot_block.filesystem_name()
+        )?;
+        for file in self.catalog() {
+            let depth = file.path.matches('/').count();
+            writeln!(f, "{}{}", "  ".repeat(depth), file.entry)?;
+        }
+        write!(
+            f,
+            "blocks free: {} of {}",
+            self.free_blocks().len(),
+            self.total_blocks()
+        )
+    }
+}
+
+impl SanityCheck for AmigaDisk<'_> {
+    fn check(&self) -> bool {
+        self.boot_block.check() && self.root_block.check()
+    }
+}
+
+impl DiskImageSaver for AmigaDisk<'_> {
+    /// Return the files on the disk with their paths, without
+    /// directories
+    /// Files that can't be read are skipped with a warning.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.catalog()
+            .into_iter()
+            .filter(|file| file.entry.is_file())
+            .filter_map(|file| match self.read_file(&file.entry) {
+                Ok(data) => Some(DiskFile {
+                    name: file.path.clone(),
+                    file_type: file.entry.protection_string(),
+                    raw_name: file.entry.name.chars().map(|c| c as u8).collect(),
+                    data,
+                }),
+                Err(e) => {
+                    warn!(target: PARSE, "Error reading {}: {}", file.path, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Write a file from the disk, selected by its path
+    /// AmigaDOS names don't depend on case.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        let selected_filename = selected_filename.ok_or_else(|| {
+            error!(target: IO, "Filename must be specified for saving ADF images");
+            Error::new(ErrorKind::Message(String::from(
+                "Filename must be specified for saving ADF images",
+            )))
+        })?;
+        let file = self
+            .catalog()
+            .into_iter()
+            .find(|file| file.entry.is_file() && file.path.eq_ignore_ascii_case(selected_filename))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound(String::from(selected_filename))))?;
+        writer.write_all(&self.read_file(&file.entry)?)?;
+        Ok(())
+    }
+}
+
+impl RawExporter for AmigaDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        split_tracks(self.data, &self.geometry)
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        Some(self.geometry.clone())
+    }
+}
+
+/// The boot, root and bitmap blocks, the header blocks of each
+/// directory and the header, extension and data blocks of each file
+impl SourceMapper for AmigaDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(base) = slice_offset(data, self.data) else {
+            return map;
+        };
+        let mut add = |blocks: Vec<u32>, kind: RegionKind, name: &str| {
+            map.add_sectors(&self.geometry, base, &self.sectors(blocks), kind, name)
+        };
+
+        add(vec![0, 1], RegionKind::Header, "boot block");
+        add(
+            vec![self.root_block_number],
+            RegionKind::Directory,
+            "root block",
+        );
+        add(self.bitmap_blocks(), RegionKind::Directory, "bitmap");
+        for file in self.catalog() {
+            if file.entry.is_directory() {
+                add(
+                    vec![file.header_block],
+                    RegionKind::Directory,
+                    &format!("directory: {}", file.path),
+                );
+                continue;
+            }
+            add(
+                vec![file.header_block],
+                RegionKind::Directory,
+                &format!("header: {}", file.path),
+            );
+            let Ok((data_blocks, extension_blocks)) = self.file_blocks(&file.entry) else {
+                continue;
+            };
+            add(
+                extension_blocks,
+                RegionKind::Directory,
+                &format!("extension: {}", file.path),
+            );
+            add(data_blocks, RegionKind::File, &file.path);
+        }
+
+        map
+    }
+}
+
+/// Build a nom error for an ADF image
+fn adf_error(i: &[u8], kind: nom::error::ErrorKind) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Error(nom::error::Error::new(i, kind))
+}
+
+/// Parse an ADF image
+/// The image must be the size of a double or high density disk and
+/// start with an AmigaDOS boot block.  The root block is in the middle
+/// of the disk, a bad root block checksum is a warning.
+pub fn adf_disk_parser(i: &[u8]) -> IResult<&[u8], AmigaDisk<'_>> {
+    let geometry = match i.len() {
+        ADF_DD_SIZE => Geometry::amiga(11),
+        ADF_HD_SIZE => Geometry::amiga(22),
+        _ => return Err(adf_error(i, nom::error::ErrorKind::Eof)),
+    };
+    let (_, boot_block) = boot_block_parser(i)?;
+    if !boot_block.check() {
+        return Err(adf_error(&i[3..], nom::error::ErrorKind::Verify));
+    }
+
+    let root_block_number = (i.len() / BLOCK_SIZE / 2) as u32;
+    let root_offset = root_block_number as usize * BLOCK_SIZE;
+    let root_data = &i[root_offset..root_offset + BLOCK_SIZE];
+    let (_, root_block) = root_block_parser(root_data)?;
+    if !root_block.check() {
+        return Err(adf_error(root_data, nom::error::ErrorKind::Verify));
+    }
+    if block_checksum(root_data, HEADER_CHECKSUM_OFFSET) != root_block.checksum {
+        warning(Warning::new("Bad root block checksum").with_location(
+            Location::offset(root_offset + HEADER_CHECKSUM_OFFSET).with_format("ADF"),
+        ));
+    }
+
+    Ok((
+        &i[i.len()..],
+        AmigaDisk {
+            data: i,
+            geometry,
+            boot_block,
+            root_block_number,
+            root_block,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::adf_disk_parser;
+    use crate::disk_format::amiga::block::BLOCK_SIZE;
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::testgen::adf;
+
+    /// Test that damaged chains and loops don't stop the rest of the
+    /// disk being read
+    #[test]
+    fn amiga_disk_damage_works() {
+        let long: Vec<u8> = (0..40_000_usize).map(|i| (i % 251) as u8).collect();
+        let mut data = adf("DAMAGED", false, &[("A", b"FIRST"), ("B", &long)]).unwrap();
+        let (_, disk) = adf_disk_parser(&data).unwrap();
+        let catalog = disk.catalog();
+        assert_eq!(catalog.len(), 2);
+        let b = catalog.iter().find(|file| file.path == "B").unwrap();
+        let (data_blocks, extension_blocks) = disk.file_blocks(&b.entry).unwrap();
+        assert_eq!(data_blocks.len(), 82);
+        assert_eq!(extension_blocks.len(), 1);
+
+        // Link the first file's hash chain back to itself and overwrite
+        // a data block of the second
+        let a = catalog.iter().find(|file| file.path == "A").unwrap();
+        let start = a.header_block as usize * BLOCK_SIZE;
+        data[start + 496..start + 500].copy_from_slice(&a.header_block.to_be_bytes());
+        let start = data_blocks[3] as usize * BLOCK_SIZE;
+        data[start..start + 4].fill(0);
+        let (_, disk) = adf_disk_parser(&data).unwrap();
+        assert_eq!(disk.catalog().len(), 2);
+        let files = disk.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].data, b"FIRST");
+
+        assert!(adf_disk_parser(&data[..data.len() - 1]).is_err());
+        data[..4].copy_from_slice(b"KICK");
+        assert!(adf_disk_parser(&data).is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! Parse Amiga disk images
+//!
+//! This parses ADF images of Amiga floppy disks with the AmigaDOS
+//! original (OFS) and fast (FFS) filesystems, including the
+//! international and directory cache variants.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// Boot, root, header and data blocks
+pub mod block;
+
+/// ADF disks and their directory trees
+pub mod disk;
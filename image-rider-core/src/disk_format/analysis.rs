@@ -0,0 +1,150 @@
+//! Entropy analysis and content classification
+//!
+//! Measuring the entropy of each sector and guessing what kind of data
+//! it holds gives a quick map of a disk: unused regions are empty,
+//! compressed or encrypted data stands out with high entropy, and
+//! copy protection or hidden data often shows up where the filesystem
+//! says nothing should be.
+use std::fmt::{Display, Formatter, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::carve::{machine_code_score, text_score};
+
+/// The lowest bias-corrected entropy, in bits per byte, that is
+/// classified as compressed or encrypted
+const COMPRESSED_THRESHOLD: f64 = 7.5;
+
+/// The lowest text score that is classified as text
+const TEXT_THRESHOLD: f32 = 0.9;
+
+/// The lowest machine code score that is classified as code
+const CODE_THRESHOLD: f32 = 0.85;
+
+/// A guess at the kind of data in a sector or track
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentClass {
+    /// Every byte is the same, usually a fill pattern from formatting
+    Empty,
+    /// Printable text
+    Text,
+    /// 6502 machine code
+    Code,
+    /// Compressed or encrypted data, close to random
+    Compressed,
+    /// Anything else
+    Data,
+}
+
+impl Display for ContentClass {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ContentClass::Empty => write!(f, "empty"),
+            ContentClass::Text => write!(f, "text"),
+            ContentClass::Code => write!(f, "code"),
+            ContentClass::Compressed => write!(f, "compressed"),
+            ContentClass::Data => write!(f, "data"),
+        }
+    }
+}
+
+/// Calculate the Shannon entropy of the data in bits per byte, between
+/// zero and eight
+pub fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0_usize; 256];
+    for byte in data {
+        counts[usize::from(*byte)] += 1;
+    }
+
+    let length = data.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / length;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Calculate the entropy with the Miller-Madow bias correction
+/// Small samples like a 256 byte sector can't show all 256 byte values,
+/// so even random data measures well under eight bits per byte.  The
+/// correction brings random sectors close to eight.
+pub fn corrected_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut seen = [false; 256];
+    for byte in data {
+        seen[usize::from(*byte)] = true;
+    }
+    let symbols = seen.iter().filter(|s| **s).count() as f64;
+
+    (entropy(data) + (symbols - 1.0) / (2.0 * data.len() as f64 * std::f64::consts::LN_2)).min(8.0)
+}
+
+/// Guess the kind of data in a sector or track
+/// Zero padding at the end of the data is ignored when looking for
+/// text, but zeros anywhere else mean it isn't text.
+pub fn classify(data: &[u8]) -> ContentClass {
+    let end = data.iter().rposition(|b| *b != 0x00).map_or(0, |e| e + 1);
+    let trimmed = &data[..end];
+
+    if data.iter().all(|b| Some(b) == data.first()) {
+        ContentClass::Empty
+    } else if corrected_entropy(data) >= COMPRESSED_THRESHOLD {
+        ContentClass::Compressed
+    } else if !trimmed.contains(&0x00) && text_score(trimmed) >= TEXT_THRESHOLD {
+        ContentClass::Text
+    } else if machine_code_score(data) >= CODE_THRESHOLD {
+        ContentClass::Code
+    } else {
+        ContentClass::Data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, corrected_entropy, entropy, ContentClass};
+
+    /// Test entropy and classification of typical sectors
+    #[test]
+    fn classify_works() {
+        assert_eq!(entropy(&[0xE5; 256]), 0.0);
+        let all_bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(entropy(&all_bytes), 8.0);
+
+        // A simple linear congruential generator stands in for
+        // compressed data
+        let mut seed: u32 = 12345;
+        let random: Vec<u8> = (0..256)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        assert!(entropy(&random) < 7.5);
+        assert!(corrected_entropy(&random) > 7.5);
+
+        assert_eq!(classify(&[0xE5; 256]), ContentClass::Empty);
+        assert_eq!(classify(&random), ContentClass::Compressed);
+        assert_eq!(
+            classify(&b"THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG\r".repeat(5)),
+            ContentClass::Text
+        );
+        // LDA #$00, STA $D020, INX, BNE, RTS
+        assert_eq!(
+            classify(&[0xA9, 0x00, 0x8D, 0x20, 0xD0, 0xE8, 0xD0, 0xF8, 0x60].repeat(20)),
+            ContentClass::Code
+        );
+        assert_eq!(
+            classify(&[0x02, 0x03, 0x02, 0x04].repeat(64)),
+            ContentClass::Data
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Binary II archives
+//!
+//! Binary II wraps one or more ProDOS files, with their types, access
+//! bits and dates, so they survive transfer through systems that only
+//! store plain bytes.  Files on bulletin boards usually had a .BNY or
+//! .BQY (compressed) extension.
+//!
+//! Each file has a 128-byte header followed by the file data, padded
+//! to a multiple of 128 bytes.  Headers start with the bytes 0A 47 4C
+//! and have the byte 02 at offset 18.  All numbers are little-endian,
+//! with the high bytes of GS/OS sized fields stored later in the header.
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{le_u16, le_u24, le_u8};
+use nom::IResult;
+
+use crate::disk_format::apple::prodos::prodos_timestamp;
+use crate::disk_format::apple::wrappers::{FileDates, HostFile, ProDOSFileInfo};
+
+/// The size of a Binary II header and the block size files are padded
+/// to
+const BINARY_II_BLOCK: usize = 128;
+
+/// A file in a Binary II archive
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BinaryIIFile<'a> {
+    /// The file name, may be a partial ProDOS path
+    pub name: String,
+    /// The ProDOS access bits
+    pub access: u8,
+    /// The ProDOS file type, with the GS/OS high byte
+    pub file_type: u16,
+    /// The auxiliary type, with the GS/OS high word
+    pub aux_type: u32,
+    /// The ProDOS storage type
+    pub storage_type: u8,
+    /// The modification date and time
+    pub modified: (u16, u16),
+    /// The creation date and time
+    pub created: (u16, u16),
+    /// The data is compressed (SQueezed), the usual .BQY archive
+    pub compressed: bool,
+    /// The data is encrypted
+    pub encrypted: bool,
+    /// The file data
+    pub data: &'a [u8],
+}
+
+impl BinaryIIFile<'_> {
+    /// Convert to a host file, keeping the ProDOS type information and
+    /// dates
+    pub fn to_host_file(&self) -> HostFile {
+        HostFile {
+            name: Some(self.name.clone()),
+            data: self.data.to_vec(),
+            prodos_info: Some(ProDOSFileInfo {
+                access: u16::from(self.access),
+                file_type: self.file_type,
+                aux_type: self.aux_type,
+            }),
+            dates: Some(FileDates {
+                created: prodos_timestamp(self.created.0, self.created.1).unwrap_or_default(),
+                modified: prodos_timestamp(self.modified.0, self.modified.1).unwrap_or_default(),
+                ..FileDates::default()
+            }),
+            ..HostFile::default()
+        }
+    }
+}
+
+/// Parse a single Binary II entry
+/// Returns None for phantom entries, which carry no file.
+fn binary2_entry_parser(input: &[u8]) -> IResult<&[u8], (Option<BinaryIIFile<'_>>, u8)> {
+    let (rest, header) = take(BINARY_II_BLOCK)(input)?;
+
+    let (h, _id) = tag([0x0A, 0x47, 0x4C])(header)?;
+    let (h, access) = le_u8(h)?;
+    let (h, file_type) = le_u8(h)?;
+    let (h, aux_type) = le_u16(h)?;
+    let (h, storage_type) = le_u8(h)?;
+    let (h, _size_in_blocks) = le_u16(h)?;
+    let (h, modified_date) = le_u16(h)?;
+    let (h, modified_time) = le_u16(h)?;
+    let (h, created_date) = le_u16(h)?;
+    let (h, created_time) = le_u16(h)?;
+    let (h, _id) = tag([0x02])(h)?;
+    let (h, _reserved) = le_u8(h)?;
+    let (h, eof) = le_u24(h)?;
+    let (h, name_length) = le_u8(h)?;
+    let (h, name) = take(64_usize)(h)?;
+    let (h, _reserved) = take(21_usize)(h)?;
+    let (h, aux_type_high) = le_u16(h)?;
+    let (h, _access_high) = le_u8(h)?;
+    let (h, file_type_high) = le_u8(h)?;
+    let (h, _storage_type_high) = le_u8(h)?;
+    let (h, _size_in_blocks_high) = le_u16(h)?;
+    let (h, eof_high) = le_u8(h)?;
+    let (h, _disk_space) = take(4_usize)(h)?;
+    let (h, _os_type) = le_u8(h)?;
+    let (h, _native_file_type) = le_u16(h)?;
+    let (h, phantom) = le_u8(h)?;
+    let (h, data_flags) = le_u8(h)?;
+    let (h, _version) = le_u8(h)?;
+    let (_h, files_to_follow) = le_u8(h)?;
+
+    if phantom != 0 {
+        return Ok((rest, (None, files_to_follow)));
+    }
+
+    let eof = (eof | (u32::from(eof_high) << 24)) as usize;
+    let (_, data) = take(eof)(rest)?;
+    // The padding after the last file is sometimes left out
+    let (rest, _) = take(eof.next_multiple_of(BINARY_II_BLOCK).min(rest.len()))(rest)?;
+    let name = &name[..usize::from(name_length).min(64)];
+
+    Ok((
+        rest,
+        (
+            Some(BinaryIIFile {
+                name: String::from_utf8_lossy(name).to_string(),
+                access,
+                file_type: u16::from(file_type) | (u16::from(file_type_high) << 8),
+                aux_type: u32::from(aux_type) | (u32::from(aux_type_high) << 16),
+                storage_type,
+                modified: (modified_date, modified_time),
+                created: (created_date, created_time),
+                compressed: (data_flags & 0x80) != 0,
+                encrypted: (data_flags & 0x40) != 0,
+                data,
+            }),
+            files_to_follow,
+        ),
+    ))
+}
+
+/// Parse a Binary II archive, returning the files in it
+pub fn binary2_parser(i: &[u8]) -> IResult<&[u8], Vec<BinaryIIFile<'_>>> {
+    let mut files = Vec::new();
+    let mut i = i;
+
+    loop {
+        let (rest, (file, files_to_follow)) = binary2_entry_parser(i)?;
+        files.extend(file);
+        i = rest;
+        if files_to_follow == 0 {
+            break;
+        }
+    }
+
+    Ok((i, files))
+}
+
+/// Return true if the data starts with a Binary II header
+pub fn is_binary2(data: &[u8]) -> bool {
+    data.len() >= BINARY_II_BLOCK && data.starts_with(&[0x0A, 0x47, 0x4C]) && data[18] == 0x02
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binary2_parser, is_binary2};
+
+    /// Build a Binary II header
+    fn header(name: &str, file_type: u8, data: &[u8], files_to_follow: u8) -> Vec<u8> {
+        let mut header = vec![0_u8; 128];
+        header[0..3].copy_from_slice(&[0x0A, 0x47, 0x4C]);
+        header[3] = 0xC3;
+        header[4] = file_type;
+        header[5..7].copy_from_slice(&0x2000_u16.to_le_bytes());
+        header[7] = 0x01;
+        // March 1 2000, 01:02
+        header[10..12].copy_from_slice(&((3 << 5) | 1_u16).to_le_bytes());
+        header[12..14].copy_from_slice(&0x0102_u16.to_le_bytes());
+        header[18] = 0x02;
+        header[20..23].copy_from_slice(&(data.len() as u32).to_le_bytes()[0..3]);
+        header[23] = name.len() as u8;
+        header[24..24 + name.len()].copy_from_slice(name.as_bytes());
+        header[127] = files_to_follow;
+        header
+    }
+
+    /// Test unwrapping a Binary II archive with two files
+    #[test]
+    fn binary2_parser_works() {
+        let mut archive = header("HELLO", 0x06, &[0xA9; 130], 1);
+        archive.extend_from_slice(&[0xA9; 130]);
+        archive.resize(128 + 256, 0);
+        archive.extend(header("README", 0x04, b"TEXT FILE", 0));
+        archive.extend_from_slice(b"TEXT FILE");
+
+        assert!(is_binary2(&archive));
+        let (_rest, files) = binary2_parser(&archive).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "HELLO");
+        assert_eq!(files[0].data.len(), 130);
+        assert_eq!(files[0].aux_type, 0x2000);
+        assert_eq!(files[1].name, "README");
+        assert_eq!(files[1].data, b"TEXT FILE");
+
+        let host_file = files[0].to_host_file();
+        assert_eq!(host_file.prodos_info.unwrap().file_type, 0x06);
+        assert_eq!(host_file.dates.unwrap().modified, 60 * 86400 + 3600 + 120);
+
+        assert!(!is_binary2(b"HELLO"));
+        assert!(binary2_parser(&archive[..200]).is_err());
+    }
+}
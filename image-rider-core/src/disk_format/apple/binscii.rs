@@ -0,0 +1,312 @@
+//! BinSCII text encoding
+//!
+//! BinSCII encodes ProDOS files as lines of printable text so they can
+//! be posted to text-only services, splitting large files into
+//! segments.  Each segment is:
+//!
+//!   The line FiLeStArTfIlEsTaRt
+//!   A line with the 64 character alphabet used for the encoding
+//!   A header line: the file name length as a letter ('A' is 1), the
+//!     name padded to 15 characters, then 36 characters encoding the
+//!     file attributes, the segment position and a CRC
+//!   Data lines of 64 characters, each 48 bytes, optionally starting
+//!     with an 'M'
+//!   A line of four characters with the CRC of the segment data
+//!
+//! Every four characters encode three bytes, with the six bit values
+//! stored in reverse order.  CRCs are the XMODEM variant of CRC-CCITT.
+use std::collections::BTreeMap;
+
+use nom::number::complete::{le_u16, le_u24, le_u8};
+use nom::IResult;
+
+use crate::disk_format::apple::prodos::prodos_timestamp;
+use crate::disk_format::apple::wrappers::{FileDates, HostFile, ProDOSFileInfo};
+use crate::disk_format::checksum::Crc16;
+use crate::disk_format::limits::limits;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The line that starts every segment
+const SEGMENT_START: &str = "FiLeStArTfIlEsTaRt";
+
+/// Build an invalid data error
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "BinSCII: {}",
+        message
+    ))))
+}
+
+/// Calculate the XMODEM CRC-16 of some data
+fn crc16(data: &[u8]) -> u16 {
+    Crc16::with_initial(Crc16::XMODEM_INITIAL)
+        .update(data)
+        .finish()
+}
+
+/// Decode a line of characters with an alphabet
+fn decode(alphabet: &[u8], line: &[u8]) -> std::result::Result<Vec<u8>, Error> {
+    if line.len() % 4 != 0 {
+        return Err(invalid("line length isn't a multiple of four"));
+    }
+
+    let mut data = Vec::with_capacity(line.len() / 4 * 3);
+    for chunk in line.chunks(4) {
+        let mut v = [0_u8; 4];
+        for (value, c) in v.iter_mut().zip(chunk) {
+            *value = alphabet
+                .iter()
+                .position(|a| a == c)
+                .ok_or_else(|| invalid("character not in the alphabet"))?
+                as u8;
+        }
+        data.push((v[3] << 2) | (v[2] >> 4));
+        data.push((v[2] << 4) | (v[1] >> 2));
+        data.push((v[1] << 6) | v[0]);
+    }
+
+    Ok(data)
+}
+
+/// A segment header
+struct SegmentHeader {
+    /// The length of the whole file
+    file_size: usize,
+    /// Where this segment starts in the file
+    offset: usize,
+    /// The ProDOS file information
+    info: ProDOSFileInfo,
+    /// The creation and modification dates and times
+    dates: (u16, u16, u16, u16),
+    /// The length of this segment
+    length: usize,
+    /// The CRC of the header fields
+    crc: u16,
+}
+
+/// Parse the decoded attributes on a header line
+fn segment_header_parser(i: &[u8]) -> IResult<&[u8], SegmentHeader> {
+    let (i, file_size) = le_u24(i)?;
+    let (i, offset) = le_u24(i)?;
+    let (i, access) = le_u8(i)?;
+    let (i, file_type) = le_u8(i)?;
+    let (i, aux_type) = le_u16(i)?;
+    let (i, _storage_type) = le_u8(i)?;
+    let (i, _size_in_blocks) = le_u16(i)?;
+    let (i, created_date) = le_u16(i)?;
+    let (i, created_time) = le_u16(i)?;
+    let (i, modified_date) = le_u16(i)?;
+    let (i, modified_time) = le_u16(i)?;
+    let (i, length) = le_u24(i)?;
+    let (i, crc) = le_u16(i)?;
+
+    Ok((
+        i,
+        SegmentHeader {
+            file_size: file_size as usize,
+            offset: offset as usize,
+            info: ProDOSFileInfo {
+                access: u16::from(access),
+                file_type: u16::from(file_type),
+                aux_type: u32::from(aux_type),
+            },
+            dates: (created_date, created_time, modified_date, modified_time),
+            length: length as usize,
+            crc,
+        },
+    ))
+}
+
+/// Split text into lines, clearing the high bit so text from Apple DOS
+/// and ProDOS text files decodes too
+fn lines(text: &[u8]) -> Vec<Vec<u8>> {
+    text.iter()
+        .map(|b| b & 0x7F)
+        .collect::<Vec<u8>>()
+        .split(|b| *b == b'\r' || *b == b'\n')
+        .map(|line| {
+            let end = line
+                .iter()
+                .rposition(|b| !b.is_ascii_whitespace())
+                .map_or(0, |end| end + 1);
+            line[..end].to_vec()
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Return true if the text holds a BinSCII segment
+pub fn is_binscii(text: &[u8]) -> bool {
+    lines(text)
+        .iter()
+        .any(|line| line.as_slice() == SEGMENT_START.as_bytes())
+}
+
+/// Decode every file in BinSCII text, joining the segments of each
+/// file
+pub fn binscii_decode(text: &[u8]) -> std::result::Result<Vec<HostFile>, Error> {
+    let lines = lines(text);
+    let mut files: BTreeMap<String, HostFile> = BTreeMap::new();
+    let mut n = 0;
+
+    while n < lines.len() {
+        if lines[n].as_slice() != SEGMENT_START.as_bytes() {
+            n += 1;
+            continue;
+        }
+        let alphabet = lines
+            .get(n + 1)
+            .ok_or_else(|| invalid("missing alphabet"))?;
+        let header = lines.get(n + 2).ok_or_else(|| invalid("missing header"))?;
+        if alphabet.len() != 64 || header.len() != 52 {
+            return Err(invalid("malformed segment start"));
+        }
+        n += 3;
+
+        let name_length = usize::from(header[0].wrapping_sub(b'A')) + 1;
+        if name_length > 15 {
+            return Err(invalid("file name too long"));
+        }
+        let name = String::from_utf8_lossy(&header[1..1 + name_length]).to_string();
+        let attributes = decode(alphabet, &header[16..52])?;
+        let (_i, segment) =
+            segment_header_parser(&attributes).map_err(|_| invalid("short header"))?;
+        if crc16(&attributes[0..24]) != segment.crc {
+            return Err(invalid("header CRC mismatch"));
+        }
+        let max = limits().max_file_size;
+        if segment.file_size > max || segment.offset + segment.length > segment.file_size {
+            return Err(invalid("segment doesn't fit in the file"));
+        }
+
+        let mut data = Vec::with_capacity(segment.length);
+        while data.len() < segment.length {
+            let line = lines
+                .get(n)
+                .ok_or_else(|| invalid("segment data is short"))?;
+            let line = match line.len() {
+                65 if line[0] == b'M' => &line[1..],
+                _ => line.as_slice(),
+            };
+            data.extend(decode(alphabet, line)?);
+            n += 1;
+        }
+        data.truncate(segment.length);
+
+        // The segment CRC line is optional in some encoders
+        if let Some(line) = lines.get(n).filter(|line| line.len() == 4) {
+            let crc = decode(alphabet, line)?;
+            if u16::from_le_bytes([crc[0], crc[1]]) != crc16(&data) {
+                return Err(invalid("segment CRC mismatch"));
+            }
+            n += 1;
+        }
+
+        let (created_date, created_time, modified_date, modified_time) = segment.dates;
+        let file = files.entry(name.clone()).or_insert_with(|| HostFile {
+            name: Some(name),
+            data: vec![0; segment.file_size],
+            prodos_info: Some(segment.info),
+            dates: Some(FileDates {
+                created: prodos_timestamp(created_date, created_time).unwrap_or_default(),
+                modified: prodos_timestamp(modified_date, modified_time).unwrap_or_default(),
+                ..FileDates::default()
+            }),
+            ..HostFile::default()
+        });
+        if file.data.len() != segment.file_size {
+            return Err(invalid("segments disagree on the file size"));
+        }
+        file.data[segment.offset..segment.offset + segment.length].copy_from_slice(&data);
+    }
+
+    Ok(files.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binscii_decode, crc16, is_binscii, SEGMENT_START};
+
+    /// The standard BinSCII alphabet
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789()";
+
+    /// Encode bytes, padded to a multiple of three, the inverse of decode
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut text = Vec::new();
+        for chunk in data.chunks(3) {
+            let mut b = [0_u8; 3];
+            b[..chunk.len()].copy_from_slice(chunk);
+            let v = [
+                b[2] & 0x3F,
+                ((b[1] & 0x0F) << 2) | (b[2] >> 6),
+                ((b[0] & 0x03) << 4) | (b[1] >> 4),
+                b[0] >> 2,
+            ];
+            text.extend(v.iter().map(|v| ALPHABET[usize::from(*v)]));
+        }
+        text
+    }
+
+    /// Build a segment of a file
+    fn segment(name: &str, file: &[u8], offset: usize, length: usize) -> Vec<u8> {
+        let data = &file[offset..offset + length];
+        let mut attributes = Vec::new();
+        attributes.extend_from_slice(&(file.len() as u32).to_le_bytes()[0..3]);
+        attributes.extend_from_slice(&(offset as u32).to_le_bytes()[0..3]);
+        attributes.extend_from_slice(&[0xC3, 0x06, 0x00, 0x20, 0x01, 0x01, 0x00]);
+        attributes.extend_from_slice(&[0; 8]);
+        attributes.extend_from_slice(&(length as u32).to_le_bytes()[0..3]);
+        let crc = crc16(&attributes);
+        attributes.extend_from_slice(&crc.to_le_bytes());
+        attributes.push(0);
+
+        let mut text = Vec::new();
+        text.extend_from_slice(SEGMENT_START.as_bytes());
+        text.push(b'\n');
+        text.extend_from_slice(ALPHABET);
+        text.push(b'\n');
+        text.push(b'A' + name.len() as u8 - 1);
+        text.extend_from_slice(format!("{:15}", name).as_bytes());
+        text.extend(encode(&attributes));
+        text.push(b'\n');
+        for line in data.chunks(48) {
+            text.push(b'M');
+            let mut line = line.to_vec();
+            line.resize(48, 0);
+            text.extend(encode(&line));
+            text.push(b'\n');
+        }
+        let crc = crc16(data);
+        text.extend(encode(&[crc.to_le_bytes()[0], crc.to_le_bytes()[1], 0]));
+        text.push(b'\n');
+        text
+    }
+
+    /// Test decoding a file split into two segments
+    #[test]
+    fn binscii_decode_works() {
+        let file: Vec<u8> = (0..100_u8).collect();
+        let mut text = b"Posted to the Apple II forum\n".to_vec();
+        text.extend(segment("TEST.PROGRAM", &file, 0, 60));
+        text.extend(segment("TEST.PROGRAM", &file, 60, 40));
+
+        assert!(is_binscii(&text));
+        let files = binscii_decode(&text).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name.as_deref(), Some("TEST.PROGRAM"));
+        assert_eq!(files[0].data, file);
+        assert_eq!(files[0].prodos_info.unwrap().aux_type, 0x2000);
+
+        // High bit text with carriage returns, as stored on a DOS disk
+        let apple_text: Vec<u8> = text
+            .iter()
+            .map(|b| if *b == b'\n' { 0x8D } else { b | 0x80 })
+            .collect();
+        assert_eq!(binscii_decode(&apple_text).unwrap()[0].data, file);
+
+        // A damaged data line fails the segment CRC
+        let position = text.len() - 20;
+        text[position] = if text[position] == b'A' { b'B' } else { b'A' };
+        assert!(binscii_decode(&text).is_err());
+    }
+}
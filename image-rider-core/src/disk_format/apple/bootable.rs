@@ -0,0 +1,310 @@
+//! Bootable DOS 3.3 disks
+//!
+//! A DOS 3.3 disk boots from the copy of DOS on tracks zero to two, so
+//! a runnable disk can be built from any disk that holds one.  The boot
+//! tracks are checked for a DOS, and looked up in the fingerprint
+//! database to report which version it is, then copied to a new disk
+//! with an empty fifteen sector catalog and a VTOC that marks the boot
+//! tracks and the catalog track in use.  Files are added the way DOS
+//! saves them: sectors are allocated outward from the catalog track,
+//! the track/sector lists come first and the entry goes in the first
+//! free catalog slot.
+//!
+//! The free tracks are filled with a format pattern, zeros unless
+//! other patterns are given, since some software checks for the
+//! pattern a freshly formatted disk has.
+use log::{info, warn};
+
+use crate::disk_format::apple::catalog::{
+    parse_file_entry, track_sector_list_sectors, FileEntry, FileType, TrackSectorPair,
+    PAIRS_PER_TRACK_SECTOR_LIST,
+};
+use crate::disk_format::apple::disk::{parse_volume_table_of_contents, DosImage};
+use crate::disk_format::blank::{blank_image, FormatPatterns};
+use crate::disk_format::fingerprint::FingerprintDatabase;
+use crate::disk_format::geometry::{Geometry, Sector, SectorId, Track};
+use crate::disk_format::logical::split_tracks;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::CONVERT;
+use crate::serialize::Serializer;
+
+/// The size of a 35 track DOS-order image
+pub const DOS_33_DISK_SIZE: usize = 143360;
+
+/// The number of tracks holding DOS
+const BOOT_TRACKS: u8 = 3;
+
+/// The track holding the VTOC and catalog
+const CATALOG_TRACK: u8 = 17;
+
+/// The size of a sector
+const SECTOR_SIZE: usize = 256;
+
+/// The offset of the first file entry in a catalog sector
+const FIRST_ENTRY_OFFSET: usize = 0x0B;
+
+/// The size of a file entry
+const ENTRY_SIZE: usize = 35;
+
+/// The number of file entries in a catalog sector
+const ENTRIES_PER_SECTOR: usize = 7;
+
+/// The first byte of a catalog entry that has never been used
+const UNUSED_ENTRY: u8 = 0x00;
+
+/// The first byte of a deleted catalog entry
+const DELETED_ENTRY: u8 = 0xFF;
+
+/// Build the error for a disk that can't be written
+fn bootable_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+/// Return the offset of a sector in a 35 track DOS-order image
+fn sector_offset(track: impl Into<Track>, sector: impl Into<Sector>) -> usize {
+    (usize::from(track.into().get()) * 16 + usize::from(sector.into().get())) * SECTOR_SIZE
+}
+
+/// Check an image is a 35 track DOS-order image
+fn check_size(data: &[u8]) -> std::result::Result<(), Error> {
+    if data.len() != DOS_33_DISK_SIZE {
+        return Err(bootable_error(format!(
+            "A DOS 3.3 disk is {} bytes, not {}",
+            DOS_33_DISK_SIZE,
+            data.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Build a new bootable DOS 3.3 disk with the DOS from another disk
+/// The source is a 35 track DOS-order image whose boot tracks hold a
+/// DOS, e.g. a system master or any disk initialized with INIT.  The
+/// new disk keeps its volume number and has an empty catalog.
+/// Returns an error if the image isn't a DOS-order image or its boot
+/// tracks are free.
+pub fn make_bootable(dos_image: &[u8]) -> std::result::Result<Vec<u8>, Error> {
+    make_bootable_with_patterns(dos_image, &FormatPatterns::default())
+}
+
+/// Build a new bootable DOS 3.3 disk with the DOS from another disk,
+/// filling the free tracks with format patterns
+/// The catalog track is always zeros, which marks its entries unused.
+pub fn make_bootable_with_patterns(
+    dos_image: &[u8],
+    patterns: &FormatPatterns,
+) -> std::result::Result<Vec<u8>, Error> {
+    check_size(dos_image)?;
+    let vtoc_offset = sector_offset(CATALOG_TRACK, 0);
+    let vtoc_data = dos_image[vtoc_offset..vtoc_offset + SECTOR_SIZE].to_vec();
+    let (_, mut vtoc) = parse_volume_table_of_contents(&vtoc_data)?;
+
+    match DosImage::identify(&dos_image[..SECTOR_SIZE], &vtoc) {
+        DosImage::NoDos => {
+            return Err(Error::new(ErrorKind::NotFound(String::from(
+                "The image has no DOS on its boot tracks",
+            ))))
+        }
+        DosImage::Unknown(page) => warn!(
+            target: CONVERT,
+            "The boot tracks load to ${:02X}00, not a standard DOS 3.3 address", page
+        ),
+        dos => info!(target: CONVERT, "Copying a {}", dos),
+    }
+    let tracks = split_tracks(dos_image, &Geometry::apple_dos_33(35));
+    let database = FingerprintDatabase::builtin();
+    let fingerprints = database.identify(&tracks);
+    if fingerprints.is_empty() {
+        info!(target: CONVERT, "The DOS isn't in the fingerprint database");
+    }
+    for fingerprint in fingerprints {
+        info!(target: CONVERT, "The DOS is {}", fingerprint);
+    }
+
+    let mut disk = blank_image(&Geometry::apple_dos_33(35), patterns);
+    let boot_end = sector_offset(BOOT_TRACKS, 0);
+    disk[..boot_end].copy_from_slice(&dos_image[..boot_end]);
+    disk[vtoc_offset..sector_offset(CATALOG_TRACK + 1, 0)].fill(0);
+
+    // Fifteen catalog sectors linked from sector 15 down to sector 1
+    for sector in 1..16_u8 {
+        let offset = sector_offset(CATALOG_TRACK, sector);
+        if sector > 1 {
+            disk[offset + 1] = CATALOG_TRACK;
+            disk[offset + 2] = sector - 1;
+        }
+    }
+
+    vtoc.track_number_of_first_catalog_sector = CATALOG_TRACK;
+    vtoc.sector_number_of_first_catalog_sector = 15;
+    vtoc.last_track_where_sectors_were_allocated = CATALOG_TRACK;
+    vtoc.direction_of_track_allocation = 1;
+    vtoc.number_of_tracks_per_diskette = 35;
+    vtoc.number_of_sectors_per_track = 16;
+    vtoc.number_of_bytes_per_sector = SECTOR_SIZE as u16;
+    vtoc.maximum_number_of_track_sector_pairs = PAIRS_PER_TRACK_SECTOR_LIST as u8;
+    vtoc.bit_map_of_free_sectors = (0..35_u8)
+        .map(|track| {
+            if track < BOOT_TRACKS || track == CATALOG_TRACK {
+                [0; 4]
+            } else {
+                [0xFF, 0xFF, 0, 0]
+            }
+        })
+        .collect();
+    disk[vtoc_offset..vtoc_offset + SECTOR_SIZE].copy_from_slice(&vtoc.as_vec()?);
+
+    Ok(disk)
+}
+
+/// Add a file to a DOS 3.3 disk
+/// The data is written as it's stored in the file's sectors, so binary
+/// files start with their load address and length, and BASIC programs
+/// with their length.
+/// Returns an error if the disk isn't a DOS-order image, a file with
+/// the same name exists, or the disk or catalog is full.
+pub fn add_file(
+    disk: &mut [u8],
+    name: &str,
+    file_type: FileType,
+    data: &[u8],
+) -> std::result::Result<(), Error> {
+    check_size(disk)?;
+    let vtoc_offset = sector_offset(CATALOG_TRACK, 0);
+    let vtoc_data = disk[vtoc_offset..vtoc_offset + SECTOR_SIZE].to_vec();
+    let (_, mut vtoc) = parse_volume_table_of_contents(&vtoc_data)?;
+
+    // Find the first free catalog slot, checking the name isn't taken
+    let mut slot = None;
+    let mut catalog_sector = (
+        vtoc.track_number_of_first_catalog_sector,
+        vtoc.sector_number_of_first_catalog_sector,
+    );
+    let mut visited = Vec::new();
+    while catalog_sector.0 != 0 && !visited.contains(&catalog_sector) {
+        visited.push(catalog_sector);
+        let offset = sector_offset(catalog_sector.0, catalog_sector.1);
+        let sector = disk.get(offset..offset + SECTOR_SIZE).ok_or_else(|| {
+            bootable_error(format!(
+                "Catalog sector {}/{} is past the end of the disk",
+                catalog_sector.0, catalog_sector.1
+            ))
+        })?;
+        for n in 0..ENTRIES_PER_SECTOR {
+            let entry_offset = FIRST_ENTRY_OFFSET + n * ENTRY_SIZE;
+            let entry = &sector[entry_offset..entry_offset + ENTRY_SIZE];
+            match entry[0] {
+                UNUSED_ENTRY | DELETED_ENTRY => {
+                    slot = slot.or(Some(offset + entry_offset));
+                }
+                _ => {
+                    let (_, existing) = parse_file_entry(entry)?;
+                    if existing.filename().is_ok_and(|existing| existing == name) {
+                        return Err(bootable_error(format!("{} is already on the disk", name)));
+                    }
+                }
+            }
+        }
+        catalog_sector = (sector[1], sector[2]);
+    }
+    let slot = slot.ok_or_else(|| bootable_error(String::from("The catalog is full")))?;
+
+    let data_sectors = data.len().div_ceil(SECTOR_SIZE);
+    let list_count = data_sectors.div_ceil(PAIRS_PER_TRACK_SECTOR_LIST).max(1);
+    let mut allocate = |count: usize| -> std::result::Result<Vec<SectorId>, Error> {
+        (0..count)
+            .map(|_| {
+                vtoc.allocate()?
+                    .ok_or_else(|| bootable_error(format!("The disk is full adding {}", name)))
+            })
+            .collect()
+    };
+    let lists = allocate(list_count)?;
+    let sectors = allocate(data_sectors)?;
+
+    let pair = |id: &SectorId| TrackSectorPair {
+        track_number: id.track.get(),
+        sector_number: id.sector.get(),
+    };
+    let pairs: Vec<TrackSectorPair> = sectors.iter().map(pair).collect();
+    let list_pairs: Vec<TrackSectorPair> = lists.iter().map(pair).collect();
+    for (id, list) in lists
+        .iter()
+        .zip(track_sector_list_sectors(&pairs, &list_pairs)?)
+    {
+        let offset = sector_offset(id.track, id.sector);
+        disk[offset..offset + SECTOR_SIZE].copy_from_slice(&list);
+    }
+    for (id, chunk) in sectors.iter().zip(data.chunks(SECTOR_SIZE)) {
+        let offset = sector_offset(id.track, id.sector);
+        disk[offset..offset + SECTOR_SIZE].fill(0);
+        disk[offset..offset + chunk.len()].copy_from_slice(chunk);
+    }
+
+    let entry = FileEntry::new(
+        lists[0].track.get(),
+        lists[0].sector.get(),
+        file_type,
+        false,
+        name,
+        (lists.len() + sectors.len()) as u16,
+    );
+    disk[slot..slot + ENTRY_SIZE].copy_from_slice(&entry.as_vec()?);
+    disk[vtoc_offset..vtoc_offset + SECTOR_SIZE].copy_from_slice(&vtoc.as_vec()?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::Options;
+
+    use super::{add_file, make_bootable, make_bootable_with_patterns};
+    use crate::disk_format::apple::catalog::FileType;
+    use crate::disk_format::apple::disk::DosImage;
+    use crate::disk_format::blank::{FillPattern, FormatPatterns};
+    use crate::disk_format::image::{DiskImageParser, DiskImageSaver};
+    use crate::disk_format::testgen;
+
+    /// Test building a bootable disk from the DOS on another disk and
+    /// adding files to it that DOS can read back
+    #[test]
+    fn make_bootable_works() {
+        let source =
+            testgen::apple_dos_33(&[("OLD", &testgen::apple_binary(0x300, &[0x60]))]).unwrap();
+        let mut disk = make_bootable(&source).unwrap();
+        assert_eq!(&disk[..0x3000], &source[..0x3000]);
+
+        let program = testgen::apple_binary(0x2000, &[0xEA; 700]);
+        add_file(&mut disk, "HELLO", FileType::Binary, &program).unwrap();
+        add_file(&mut disk, "EMPTY", FileType::Text, &[]).unwrap();
+        assert!(add_file(&mut disk, "HELLO", FileType::Binary, &program).is_err());
+
+        let image = disk
+            .parse_disk_image(&Options::default(), "bootable.dsk")
+            .unwrap();
+        let dos_disk = image.dos_disk().unwrap();
+        assert_eq!(dos_disk.dos_image(), DosImage::Slave(48));
+        let files = image.disk_files();
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["HELLO", "EMPTY"]);
+        // Binary files are read back without their address and length
+        assert_eq!(files[0].data, [0xEA; 700]);
+        // The boot tracks, VTOC and catalog, one list and three data
+        // sectors for HELLO and one list for EMPTY
+        assert_eq!(dos_disk.free_sectors().len(), 560 - 48 - 16 - 5);
+
+        let patterns = FormatPatterns::new(FillPattern::byte(0xE5));
+        let mut disk = make_bootable_with_patterns(&source, &patterns).unwrap();
+        assert_eq!(disk[0x3000], 0xE5);
+        assert_eq!(disk[0x11100..0x11200], [0; 256]);
+        add_file(&mut disk, "HELLO", FileType::Binary, &program).unwrap();
+        let image = disk
+            .parse_disk_image(&Options::default(), "bootable.dsk")
+            .unwrap();
+        assert_eq!(image.disk_files()[0].data, [0xEA; 700]);
+
+        assert!(make_bootable(&[0; 143360]).is_err());
+        assert!(make_bootable(&source[..1000]).is_err());
+    }
+}
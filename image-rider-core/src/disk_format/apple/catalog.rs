@@ -11,9 +11,17 @@ use nom::number::complete::{le_u16, le_u8};
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter, Result},
+    str::FromStr,
     string::FromUtf8Error,
 };
 
+use crate::disk_format::apple::disk::SectorSource;
+use crate::disk_format::apple::text::read_record;
+use crate::disk_format::charset::Charset;
+use crate::disk_format::geometry::{Sector, SectorId, Track};
+use crate::disk_format::limits::check_chain_length;
+use crate::error::{Error, ErrorKind as ImageErrorKind, InvalidErrorKind, Location};
+use crate::log_target::PARSE;
 use crate::serialize::{little_endian_word_to_bytes, Serializer};
 
 /// Different file types
@@ -56,6 +64,27 @@ impl Display for FileType {
     }
 }
 
+/// Parse a FileType from the code shown in a catalog listing
+impl FromStr for FileType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<FileType, Error> {
+        match s.to_uppercase().as_str() {
+            "T" => Ok(FileType::Text),
+            "I" => Ok(FileType::IntegerBasic),
+            "A" => Ok(FileType::AppleSoftBasic),
+            "B" => Ok(FileType::Binary),
+            "S" => Ok(FileType::SType),
+            "R" => Ok(FileType::RelocatableObjectModule),
+            "AT" => Ok(FileType::AType),
+            "BT" => Ok(FileType::BType),
+            _ => Err(Error::new(ImageErrorKind::Invalid(
+                InvalidErrorKind::Invalid(format!("Unknown file type: {}", s)),
+            ))),
+        }
+    }
+}
+
 /// A file entry
 #[derive(Clone, Copy, Debug)]
 pub struct FileEntry<'a> {
@@ -87,6 +116,21 @@ pub struct File<'a> {
     pub data: Vec<u8>,
 }
 
+impl File<'_> {
+    /// Return the data sectors of the file, in file order
+    pub fn sectors(&self) -> Vec<SectorId> {
+        file_sectors(&self.track_sector_lists)
+            .map(|(_, tsp)| SectorId::new(tsp.track_number, 0, tsp.sector_number))
+            .collect()
+    }
+
+    /// Return record n of a random-access text file opened with a
+    /// record length, or None if the record is past the end of the file
+    pub fn read_record(&self, n: usize, length: usize) -> Option<&[u8]> {
+        read_record(&self.data, n, length)
+    }
+}
+
 impl Display for File<'_> {
     fn fmt(&self, f: &mut Formatter) -> Result {
         for tsl in &self.track_sector_lists {
@@ -126,6 +170,17 @@ pub struct TrackSectorList<'a> {
     pub track_sector_pairs: TrackSectorPairs, // Vec<TrackSectorPair>,
 }
 
+impl TrackSectorList<'_> {
+    /// Return the sector offset in the file of the first sector
+    /// described by this list
+    pub fn sector_offset(&self) -> usize {
+        match self.sector_offset_in_file {
+            [low, high] => usize::from(u16::from_le_bytes([*low, *high])),
+            _ => 0,
+        }
+    }
+}
+
 /// Display a FileType as a single character
 impl<'a> Display for TrackSectorList<'a> {
     fn fmt(&self, f: &mut Formatter) -> Result {
@@ -185,8 +240,85 @@ impl<'a> Serializer<'a> for TrackSectorList<'a> {
 // #[derive(Debug)]
 pub type TrackSectorLists<'a> = Vec<TrackSectorList<'a>>;
 
+/// Iterate over the data sectors of a file with their sector positions
+/// in the file, borrowing the track/sector pairs from the lists
+/// Each list records where its sectors go in the file, which leaves
+/// room for the holes in sparse files.  Lists with an offset before
+/// the end of the previous list follow on from it.  Holes are skipped.
+pub fn file_sectors<'l>(
+    track_sector_lists: &'l [TrackSectorList],
+) -> impl Iterator<Item = (usize, &'l TrackSectorPair)> {
+    let mut next_offset = 0;
+    track_sector_lists
+        .iter()
+        .flat_map(move |tsl| {
+            let offset = tsl.sector_offset().max(next_offset);
+            next_offset = offset + tsl.track_sector_pairs.len();
+            tsl.track_sector_pairs
+                .iter()
+                .enumerate()
+                .map(move |(n, tsp)| (offset + n, tsp))
+        })
+        .filter(|(_, tsp)| !tsp.is_hole())
+}
+
+/// The number of track/sector pairs in a track/sector list sector
+pub const PAIRS_PER_TRACK_SECTOR_LIST: usize = 122;
+
+/// Build the track/sector list sectors for a file, which may be sparse
+/// `pairs` holds the location of each sector of the file in file
+/// order, with zero pairs for holes that were never written.
+/// `list_sectors` holds the locations of the lists themselves, one for
+/// every 122 sectors of the file.  Each list links to the next and
+/// records the sector offset in the file of its first pair.
+pub fn track_sector_list_sectors(
+    pairs: &[TrackSectorPair],
+    list_sectors: &[TrackSectorPair],
+) -> std::result::Result<Vec<Vec<u8>>, Error> {
+    let needed = pairs.len().div_ceil(PAIRS_PER_TRACK_SECTOR_LIST).max(1);
+    if list_sectors.len() != needed {
+        return Err(Error::new(ImageErrorKind::Message(format!(
+            "A file of {} sectors needs {} track/sector lists, {} were given",
+            pairs.len(),
+            needed,
+            list_sectors.len()
+        ))));
+    }
+
+    let mut sectors = Vec::with_capacity(needed);
+    for n in 0..list_sectors.len() {
+        let offset = n * PAIRS_PER_TRACK_SECTOR_LIST;
+        let offset_bytes = u16::try_from(offset)
+            .map_err(|_| {
+                Error::new(ImageErrorKind::Message(String::from(
+                    "The file is too large for a track/sector list",
+                )))
+            })?
+            .to_le_bytes();
+        let next = list_sectors.get(n + 1).copied().unwrap_or(TrackSectorPair {
+            track_number: 0,
+            sector_number: 0,
+        });
+        let end = (offset + PAIRS_PER_TRACK_SECTOR_LIST).min(pairs.len());
+        let list = TrackSectorList {
+            reserved: 0,
+            track_number_of_next_sector: Some(next.track_number).filter(|t| *t != 0),
+            sector_number_of_next_sector: Some(next.sector_number).filter(|s| *s != 0),
+            reserved_2: &[0, 0],
+            sector_offset_in_file: &offset_bytes,
+            reserved_3: &[0, 0, 0, 0, 0],
+            track_sector_pairs: pairs.get(offset..end).unwrap_or_default().to_vec(),
+        };
+        let mut bytes = list.as_vec()?;
+        bytes.resize(256, 0);
+        sectors.push(bytes);
+    }
+
+    Ok(sectors)
+}
+
 /// Parse a track / sector list.
-pub fn parse_track_sector_list(i: &[u8]) -> IResult<&[u8], TrackSectorList> {
+pub fn parse_track_sector_list(i: &[u8]) -> IResult<&[u8], TrackSectorList<'_>> {
     let mut track_sector_pairs: Vec<TrackSectorPair> = Vec::new();
 
     let (i, reserved) = le_u8(i)?;
@@ -209,16 +341,16 @@ pub fn parse_track_sector_list(i: &[u8]) -> IResult<&[u8], TrackSectorList> {
     let (i, sector_offset_in_file) = take(2_usize)(i)?;
     let (i, reserved_3) = take(5_usize)(i)?;
 
-    let (mut i, mut track_sector_pair) = parse_track_sector_pair(i)?;
-
-    let max_tsps = 121;
-    let mut cnt = 1;
-    while (track_sector_pair.track_number != 0) && (cnt <= max_tsps) {
-        track_sector_pairs.push(track_sector_pair);
-        let (i2, tsp) = parse_track_sector_pair(i)?;
-        track_sector_pair = tsp;
-        i = i2;
-        cnt += 1;
+    let mut i = i;
+    while track_sector_pairs.len() < PAIRS_PER_TRACK_SECTOR_LIST && i.len() >= 2 {
+        let (rest, tsp) = parse_track_sector_pair(i)?;
+        track_sector_pairs.push(tsp);
+        i = rest;
+    }
+    // Zero pairs in the middle of a list are holes in a sparse file,
+    // the zero pairs at the end are unused
+    while track_sector_pairs.last().is_some_and(|tsp| tsp.is_hole()) {
+        track_sector_pairs.pop();
     }
 
     Ok((
@@ -244,6 +376,14 @@ pub struct TrackSectorPair {
     pub sector_number: u8,
 }
 
+impl TrackSectorPair {
+    /// Return true if this pair is a hole in a sparse file, a sector
+    /// that was never written and has no space on the disk
+    pub fn is_hole(&self) -> bool {
+        self.track_number == 0
+    }
+}
+
 impl Display for TrackSectorPair {
     fn fmt(&self, f: &mut Formatter) -> Result {
         write!(
@@ -303,7 +443,7 @@ impl<'a> FileEntry<'a> {
     /// # Examples
     ///
     /// ```
-    /// use image_rider::disk_format::apple::catalog::{FileEntry, FileType};
+    /// use image_rider_core::disk_format::apple::catalog::{FileEntry, FileType};
     ///
     /// let fe = FileEntry::new(0x12, 0x0F, FileType::AppleSoftBasic, false, "HELLO", 0x0002);
     /// assert_eq!(fe.filename().unwrap(), "HELLO");
@@ -315,7 +455,7 @@ impl<'a> FileEntry<'a> {
         locked: bool,
         filename: &str,
         file_length_in_sectors: u16,
-    ) -> FileEntry {
+    ) -> FileEntry<'_> {
         FileEntry {
             track_of_first_track_sector_list_sector,
             sector_of_first_track_sector_list_sector,
@@ -326,50 +466,54 @@ impl<'a> FileEntry<'a> {
         }
     }
 
-    /// Return the filename as a String
+    /// Return the filename as a String, in the default character set
+    /// Files are stored by this name.
     pub fn filename(&self) -> std::result::Result<String, FromUtf8Error> {
-        let filename_vector: Vec<u8> = self
-            .file_name
-            .iter()
-            .map(|c| if *c > 0x80 { *c - 0x80 } else { *c })
-            .collect();
-        let file_name = String::from_utf8(filename_vector)?;
+        Ok(self.name(&Charset::DEFAULT))
+    }
+
+    /// Return the filename as a String, in a character set
+    pub fn name(&self, charset: &Charset) -> String {
+        let file_name = charset.apple_string(self.file_name);
 
         // Apple DOS disks use spaces as padding at the end
         // Remove the spaces from the end
-        let file_name = String::from(file_name.trim_end_matches(' '));
-        Ok(file_name)
+        String::from(file_name.trim_end_matches(' '))
     }
 
     /// Get the data for a file
     pub fn get_data(
         &self,
-        tracks: &[Vec<&[u8]>],
+        tracks: &(impl SectorSource<'a> + ?Sized),
         track_sector_lists: &TrackSectorLists,
     ) -> std::result::Result<Vec<u8>, crate::error::Error> {
-        // We could build a custom iterator for TrackSectorList, since the
-        // normal meaning of iterating through a TrackSectorList is iterating
-        // through TrackSectorPairs
-        // let data: Vec<u8> = track_sector_lists
-        //     .into_iter()
-        //     .flat_map(|tsl| tsl.track_sector_pairs.clone())
-        //     .flat_map(|tsp| tracks[tsp.track_number as usize][tsp.sector_number as usize])
-        //     .map(|b| *b)
-        //     .collect::<Vec<u8>>();
-        let data: Vec<u8> = track_sector_lists
-            .iter()
-            .flat_map(|tsl| tsl.track_sector_pairs.clone())
-            .flat_map(|tsp| tracks[tsp.track_number as usize][tsp.sector_number as usize])
-            .copied()
-            .collect();
+        let mut data: Vec<u8> = Vec::new();
+        for (position, tsp) in file_sectors(track_sector_lists) {
+            let sector = tracks
+                .sector(Track(tsp.track_number), Sector(tsp.sector_number))
+                .ok_or_else(|| {
+                    Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                        String::from("File sector is off the disk"),
+                    )))
+                    .with_location(
+                        Location::sector(tsp.track_number, tsp.sector_number)
+                            .with_format("Apple DOS"),
+                    )
+                })?;
+            let start = position * sector.len();
+            if data.len() < start + sector.len() {
+                data.resize(start + sector.len(), 0);
+            }
+            data[start..start + sector.len()].copy_from_slice(sector);
+        }
 
         match self.file_type {
             FileType::Binary => {
                 if data.len() >= 4 {
                     let (i, address) = le_u16(data.as_slice())?;
-                    debug!("Binary file address: {}", address);
+                    debug!(target: PARSE, "Binary file address: {}", address);
                     let (_i, len) = le_u16(i)?;
-                    debug!("Binary file length: {}", len);
+                    debug!(target: PARSE, "Binary file length: {}", len);
                     // Some additional checking
                     if (data.len() - 4) >= len.into() {
                         Ok(data[4..(len + 4) as usize].to_vec())
@@ -380,6 +524,9 @@ impl<'a> FileEntry<'a> {
                     Ok(data)
                 }
             }
+            // Text files are stored as they are, random-access text
+            // files can have holes
+            FileType::Text => Ok(data),
             _ => {
                 let error = crate::error::Error::new(crate::error::ErrorKind::Invalid(
                     crate::error::InvalidErrorKind::Invalid(format!(
@@ -387,7 +534,7 @@ impl<'a> FileEntry<'a> {
                         self.file_type
                     )),
                 ));
-                debug!("{}", error);
+                debug!(target: PARSE, "{}", error);
                 Err(error)
             }
         }
@@ -398,31 +545,40 @@ impl<'a> FileEntry<'a> {
     /// E.g. tracks are a vector of sectors
     pub fn build_file(
         &self,
-        tracks: &[Vec<&'a [u8]>],
+        tracks: &(impl SectorSource<'a> + ?Sized),
     ) -> std::result::Result<TrackSectorLists<'a>, crate::error::Error> {
         let mut track_sector_lists: TrackSectorLists = Vec::new();
 
-        let track = self.track_of_first_track_sector_list_sector;
-        let sector = self.sector_of_first_track_sector_list_sector;
-
         // There is always at least one track and sector list for a file
-        let (_i, track_sector_list) =
-            parse_track_sector_list(tracks[track as usize][sector as usize]).unwrap();
-        track_sector_lists.push(track_sector_list.clone());
-
-        let mut track = track_sector_list.clone().track_number_of_next_sector;
-        let mut sector = track_sector_list.clone().sector_number_of_next_sector;
-        debug!("track sector list: {}", track_sector_lists.first().unwrap());
-
-        while track.is_some() {
+        let mut track = Some(self.track_of_first_track_sector_list_sector);
+        let mut sector = Some(self.sector_of_first_track_sector_list_sector);
+
+        while let Some(track_number) = track {
+            let sector_number = sector.unwrap_or(0);
+            let location = Location::sector(track_number, sector_number).with_format("Apple DOS");
+            // A damaged disk can have a loop in the chain
+            check_chain_length(track_sector_lists.len() + 1)
+                .map_err(|e| e.with_location(location.clone()))?;
             debug!(
-                "TSList track {}, sector {}",
-                track.unwrap(),
-                sector.unwrap()
+                target: PARSE,
+                "TSList track {}, sector {}", track_number, sector_number
             );
-            let (_i, track_sector_list) =
-                parse_track_sector_list(tracks[track.unwrap() as usize][sector.unwrap() as usize])
-                    .unwrap();
+            let data = tracks
+                .sector(Track(track_number), Sector(sector_number))
+                .ok_or_else(|| {
+                    Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                        String::from("track/sector list out of range"),
+                    )))
+                    .with_location(location.clone())
+                })?;
+            let (_i, track_sector_list) = parse_track_sector_list(data).map_err(|e| {
+                Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    String::from("damaged track/sector list"),
+                )))
+                .with_location(location)
+                .with_source(Error::from(e))
+            })?;
+            debug!(target: PARSE, "track sector list: {}", track_sector_list);
             track = track_sector_list.track_number_of_next_sector;
             sector = track_sector_list.sector_number_of_next_sector;
             track_sector_lists.push(track_sector_list);
@@ -530,7 +686,7 @@ impl Display for FileEntry<'_> {
 }
 
 /// Parse a file entry
-pub fn parse_file_entry(i: &[u8]) -> IResult<&[u8], FileEntry> {
+pub fn parse_file_entry(i: &[u8]) -> IResult<&[u8], FileEntry<'_>> {
     let (i, track_of_first_track_sector_list_sector) = le_u8(i)?;
     let (i, sector_of_first_track_sector_list_sector) = le_u8(i)?;
 
@@ -656,7 +812,7 @@ pub fn valid_file(track_of_first_track_sector_list_sector: u8) -> bool {
 }
 
 /// Parse an Apple ][ DOS disk catalog
-pub fn parse_catalog(i: &[u8]) -> IResult<&[u8], Catalog> {
+pub fn parse_catalog(i: &[u8]) -> IResult<&[u8], Catalog<'_>> {
     let (i, reserved) = le_u8(i)?;
     let (i, track_number_of_next_sector) = le_u8(i)?;
     let (i, sector_number_of_next_sector) = le_u8(i)?;
@@ -719,18 +875,22 @@ impl Display for FullCatalog<'_> {
 /// Parse a series of catalog sectors
 /// This parses all of the catalog sectors and builds a directory of files
 pub fn parse_catalogs<'a>(
-    tracks: &[Vec<&'a [u8]>],
+    tracks: &(impl SectorSource<'a> + ?Sized),
     catalog_track: u8,
     catalog_sector: u8,
 ) -> std::result::Result<FullCatalog<'a>, crate::error::Error> {
     let mut file_entries: Vec<FileEntry> = Vec::new();
     let mut catalog_by_filename: HashMap<String, FileEntry> = HashMap::new();
 
-    let (_i, mut catalog) = parse_catalog(tracks[catalog_track as usize][catalog_sector as usize])?;
-
-    // Show info about the tracks data structure
-    debug!("tracks length: {}", tracks.len());
-    debug!("track one length: {}", tracks[0].len());
+    let first_sector = tracks
+        .sector(Track(catalog_track), Sector(catalog_sector))
+        .ok_or_else(|| {
+            Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                String::from("catalog sector out of range"),
+            )))
+            .with_location(Location::sector(catalog_track, catalog_sector).with_format("Apple DOS"))
+        })?;
+    let (_i, mut catalog) = parse_catalog(first_sector)?;
 
     // debug!("Number of files: {}", &catalog.file_entries.len());
     for file in &catalog.file_entries {
@@ -743,14 +903,37 @@ pub fn parse_catalogs<'a>(
     // The first track and first sector usually contain the DOS boot
     // code (or a boot stub), so they cannot be used as a catalog
     // sector.
+    let mut catalog_sectors = 1;
     while (catalog.track_number_of_next_sector != 0) && (catalog.sector_number_of_next_sector != 0)
     {
-        let (_i, c) = parse_catalog(
-            tracks[catalog.track_number_of_next_sector as usize]
-                [catalog.sector_number_of_next_sector as usize],
-        )?;
-
-        debug!("parsed another catalog: {}", c);
+        // A damaged disk can have a loop in the chain
+        catalog_sectors += 1;
+        let location = Location::sector(
+            catalog.track_number_of_next_sector,
+            catalog.sector_number_of_next_sector,
+        )
+        .with_format("Apple DOS");
+        check_chain_length(catalog_sectors).map_err(|e| e.with_location(location.clone()))?;
+        let sector = tracks
+            .sector(
+                Track(catalog.track_number_of_next_sector),
+                Sector(catalog.sector_number_of_next_sector),
+            )
+            .ok_or_else(|| {
+                Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    String::from("catalog sector out of range"),
+                )))
+                .with_location(location.clone())
+            })?;
+        let (_i, c) = parse_catalog(sector).map_err(|e| {
+            Error::new(ImageErrorKind::Invalid(InvalidErrorKind::Invalid(
+                String::from("damaged catalog sector"),
+            )))
+            .with_location(location)
+            .with_source(Error::from(e))
+        })?;
+
+        debug!(target: PARSE, "parsed another catalog: {}", c);
 
         catalog = c;
         for file in &catalog.file_entries {
@@ -777,14 +960,14 @@ impl<'a> Catalog<'a> {
 
 /// Build the files in the catalog
 pub fn build_files<'a>(
-    catalog: FullCatalog<'a>,
-    tracks: &[Vec<&'a [u8]>],
+    catalog: &FullCatalog<'a>,
+    tracks: &(impl SectorSource<'a> + ?Sized),
 ) -> std::result::Result<Files<'a>, crate::error::Error> {
     let mut files: Files = HashMap::new();
 
     for file_entry in &catalog.file_entries {
         let track_sector_lists = file_entry.build_file(tracks)?;
-        debug!("Building file: {}", file_entry.filename().unwrap());
+        debug!(target: PARSE, "Building file: {}", file_entry.filename().unwrap());
         let res = file_entry.get_data(tracks, &track_sector_lists);
         let data = res.unwrap_or_default();
 
@@ -802,9 +985,12 @@ pub fn build_files<'a>(
 #[cfg(test)]
 mod tests {
     use super::{
-        build_files, parse_catalog, parse_catalogs, parse_file_entry, Catalog, FileEntry, FileType,
-        TrackSectorList, TrackSectorPair, TrackSectorPairs,
+        build_files, file_sectors, parse_catalog, parse_catalogs, parse_file_entry,
+        track_sector_list_sectors, Catalog, FileEntry, FileType, TrackSectorList, TrackSectorPair,
+        TrackSectorPairs,
     };
+    use crate::disk_format::apple::text::{read_record, record_fields, record_sectors};
+    use crate::disk_format::charset::Charset;
     use crate::serialize::{little_endian_word_to_bytes, Serializer};
     use nom::AsBytes;
     use pretty_assertions::assert_eq;
@@ -1509,8 +1695,7 @@ mod tests {
             track_number: 0x11,
             sector_number: 0x0B,
         };
-        let mut tsps: TrackSectorPairs = Vec::new();
-        tsps.push(tsp);
+        let tsps: TrackSectorPairs = vec![tsp];
 
         let tsl = TrackSectorList {
             reserved: 0,
@@ -1547,7 +1732,7 @@ mod tests {
             "BLAH"
         );
 
-        let files = build_files(catalog.clone(), &tracks).unwrap();
+        let files = build_files(&catalog, &tracks).unwrap();
         assert!(files.contains_key("BLAH"));
         assert!(!files.contains_key("BLARGH"));
 
@@ -1617,9 +1802,7 @@ mod tests {
             track_number: 0x11,
             sector_number: 0x0C,
         };
-        let mut tsps: TrackSectorPairs = Vec::new();
-        tsps.push(tsp1);
-        tsps.push(tsp2);
+        let tsps: TrackSectorPairs = vec![tsp1, tsp2];
 
         let tsl = TrackSectorList {
             reserved: 0,
@@ -1656,7 +1839,7 @@ mod tests {
             "BLAH"
         );
 
-        let files = build_files(catalog.clone(), &tracks).unwrap();
+        let files = build_files(&catalog, &tracks).unwrap();
         assert!(files.contains_key("BLAH"));
         assert!(!files.contains_key("BLARGH"));
 
@@ -1673,4 +1856,75 @@ mod tests {
         assert_eq!(file.data[5..397], expected_data);
         assert_eq!(&file.data[397..400], "END".as_bytes());
     }
+
+    /// Test writing the track/sector lists of a sparse random-access
+    /// text file and reading the file back
+    #[test]
+    fn sparse_text_file_works() {
+        let record_length = 100;
+        let sectors = record_sectors(record_length, &[0, 500]);
+        let last = *sectors.last().unwrap();
+        let mut pairs = vec![
+            TrackSectorPair {
+                track_number: 0,
+                sector_number: 0,
+            };
+            last + 1
+        ];
+        let mut disk_data: [[[u8; 256]; 16]; 35] = [[[0; 256]; 16]; 35];
+        for (n, file_sector) in sectors.iter().enumerate() {
+            pairs[*file_sector] = TrackSectorPair {
+                track_number: 0x13,
+                sector_number: n as u8,
+            };
+        }
+        let text: Vec<u8> = "HELLO\r".bytes().map(|b| b | 0x80).collect();
+        disk_data[0x13][0][..text.len()].copy_from_slice(&text);
+        let text: Vec<u8> = "WORLD\r".bytes().map(|b| b | 0x80).collect();
+        let offset = 500 * record_length - last * 256;
+        disk_data[0x13][1][offset..offset + text.len()].copy_from_slice(&text);
+
+        let list_sectors = [
+            TrackSectorPair {
+                track_number: 0x12,
+                sector_number: 0x0F,
+            },
+            TrackSectorPair {
+                track_number: 0x12,
+                sector_number: 0x0E,
+            },
+        ];
+        assert!(track_sector_list_sectors(&pairs, &list_sectors[..1]).is_err());
+        let lists = track_sector_list_sectors(&pairs, &list_sectors).unwrap();
+        assert_eq!(lists[0][1..3], [0x12, 0x0E]);
+        assert_eq!(lists[1][5..7], [122, 0]);
+        disk_data[0x12][0x0F].copy_from_slice(&lists[0]);
+        disk_data[0x12][0x0E].copy_from_slice(&lists[1]);
+
+        let tracks: Vec<Vec<&[u8]>> = disk_data
+            .iter()
+            .map(|track| track.iter().map(|sector| &sector[..]).collect())
+            .collect();
+        let file_entry = FileEntry::new(0x12, 0x0F, FileType::Text, false, "DATA", 4);
+        let track_sector_lists = file_entry.build_file(&tracks).unwrap();
+        assert_eq!(track_sector_lists.len(), 2);
+        assert_eq!(track_sector_lists[0].track_sector_pairs.len(), 1);
+        assert_eq!(track_sector_lists[1].sector_offset(), 122);
+        let positions: Vec<usize> = file_sectors(&track_sector_lists)
+            .map(|(position, _)| position)
+            .collect();
+        assert_eq!(positions, [0, last]);
+
+        let data = file_entry.get_data(&tracks, &track_sector_lists).unwrap();
+        assert_eq!(data.len(), (last + 1) * 256);
+        let record = read_record(&data, 500, record_length).unwrap();
+        assert_eq!(record_fields(record, &Charset::DEFAULT), ["WORLD"]);
+        let record = read_record(&data, 0, record_length).unwrap();
+        assert_eq!(record_fields(record, &Charset::DEFAULT), ["HELLO"]);
+        assert!(record_fields(
+            read_record(&data, 250, record_length).unwrap(),
+            &Charset::DEFAULT
+        )
+        .is_empty());
+    }
 }
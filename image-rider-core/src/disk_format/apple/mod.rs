@@ -9,8 +9,8 @@
 //!
 //! If the file has a nib extension, it's likely a Nibble format disk
 //!
-#[warn(missing_docs)]
-#[warn(unsafe_code)]
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
 
 /// Disk-level functions and data structures for Apple disks.
 pub mod disk;
@@ -20,3 +20,27 @@ pub mod catalog;
 
 /// Nibble decoding and encoding routines
 pub mod nibble;
+
+/// WOZ 1.0 and 2.0 image reader and WOZ 2.0 writer
+pub mod woz;
+
+/// DOS 3.3 text files and random-access records
+pub mod text;
+
+/// ProDOS block storage and extended files with resource forks
+pub mod prodos;
+
+/// 2MG universal disk images
+pub mod twoimg;
+
+/// AppleSingle, AppleDouble and MacBinary host files
+pub mod wrappers;
+
+/// Binary II archives
+pub mod binary2;
+
+/// BinSCII text encoded files
+pub mod binscii;
+
+/// Bootable DOS 3.3 disks built from the DOS on another disk
+pub mod bootable;
@@ -0,0 +1,1849 @@
+//! Encoding and Decoding Nibble-based disk formats
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::Write;
+use std::str::FromStr;
+
+use config::Config;
+use log::{debug, error, warn};
+
+use nom::{
+    bytes::streaming::{take, take_until},
+    character::complete::one_of,
+    number::complete::le_u8,
+    IResult,
+};
+
+use crate::disk_format::apple::woz::DOS_33_PHYSICAL_ORDER;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::with_limits;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack, RawExporter, RawOrder};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+use crate::log_target::PARSE;
+use crate::options::Options;
+
+/// An Apple ][ nibble image, 35 tracks of 6656 nibbles
+pub const NIB_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "NIB",
+    description: "Apple ][ nibble encoded disk image",
+    extensions: &["nib"],
+    sizes: &[SizeRange::exact(232960)],
+    references: &[],
+    capabilities: Capabilities {
+        tracks: true,
+        checksums: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The different nibble encoding formats used for Apple disk images.
+/// These are required because of hardware requirements with Apple
+/// disk drives.  Not all 256 possible byte values could be written to
+/// disk.
+///
+/// The first requirement for hardware required that the high bit
+/// always be set.
+/// Second, at least two adjacent bits need to be set.
+/// Third, at most one pair of consecutive zero bits.
+///
+/// This leaves 34 valid disk bytes, in the range from AA to FF.
+/// Two of these bytes are reserved: 0xAA and 0xD5
+///
+/// This led to the "4 and 4" encoding format.  This
+/// splits the byte into two bytes, one containing the odd bytes and
+/// one containing the even bytes.
+/// Other encoding formats satisify these properties while allowing
+/// more efficient data usage.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// Four and four splits each data byte into two disk bytes,
+    /// containing the odd and even bits.
+
+    /// b_7 b_6 b_5 b_4 b_3 b_2 b_1 b_0 -> 1 . . . b_7 b_5 b_3 b_1,
+    ///                                    1 . . . b_6 b_4 b_2 b_0
+    /// Used in earlier versions of DOS before DOS 3
+    FourAndFour,
+
+    /// 5 and 3 uses 5 bits of the data in the disk byte
+    /// Used in DOS versions from DOS 3 to DOS 3.2.1
+    FiveAndThree,
+
+    /// 6 and 2 uses 6 bits of the data in the disk byte
+    /// This was enabled by changes in the disk ROM that allowed two
+    /// consecutive zero bits.
+    /// Used in DOS 3.3
+    #[default]
+    SixAndTwo,
+}
+
+impl Format {
+    /// The number of sectors on each track of a disk with data fields
+    /// in this encoding
+    pub fn sectors_per_track(&self) -> u8 {
+        match self {
+            Format::FiveAndThree => 13,
+            _ => 16,
+        }
+    }
+
+    /// The number of data nibbles in a data field, not counting the
+    /// prologue, checksum and epilogue
+    pub fn data_field_size(&self) -> usize {
+        match self {
+            Format::FiveAndThree => 410,
+            _ => 342,
+        }
+    }
+
+    /// The valid disk bytes in a data field
+    fn write_table(&self) -> &'static [u8] {
+        match self {
+            Format::FiveAndThree => &NIBBLE_WRITE_TABLE_5_AND_3,
+            _ => &NIBBLE_WRITE_TABLE_6_AND_2,
+        }
+    }
+}
+
+/// The converstion table for writing nibble data
+#[allow(dead_code)]
+const NIBBLE_WRITE_TABLE_6_AND_2: [u8; 64] = [
+    0x96, 0x97, 0x9A, 0x9B, 0x9D, 0x9E, 0x9F, 0xA6, 0xA7, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF, 0xB2, 0xB3,
+    0xB4, 0xB5, 0xB6, 0xB7, 0xB9, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xCB, 0xCD, 0xCE, 0xCF, 0xD3,
+    0xD6, 0xD7, 0xD9, 0xDA, 0xDB, 0xDC, 0xDD, 0xDE, 0xDF, 0xE5, 0xE6, 0xE7, 0xE9, 0xEA, 0xEB, 0xEC,
+    0xED, 0xEE, 0xEF, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF9, 0xFA, 0xFB, 0xFC, 0xFD, 0xFE, 0xFF,
+];
+
+/// The converstion table for reading nibble data
+/// It's the write table inverted
+const NIBBLE_READ_TABLE_6_AND_2: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x02, 0x03, 0x00, 0x04, 0x05, 0x06,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x08, 0x00, 0x00, 0x00, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+    0x00, 0x00, 0x0E, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x00, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1B, 0x00, 0x1C, 0x1D, 0x1E,
+    0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x20, 0x21, 0x00, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x29, 0x2A, 0x2B, 0x00, 0x2C, 0x2D, 0x2E, 0x2F, 0x30, 0x31, 0x32,
+    0x00, 0x00, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x00, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+];
+
+/// The conversion table for writing 5 and 3 nibble data
+/// The 13 sector ROMs can't read two consecutive zero bits, so only
+/// 32 disk bytes are left after the reserved 0xAA and 0xD5.
+const NIBBLE_WRITE_TABLE_5_AND_3: [u8; 32] = [
+    0xAB, 0xAD, 0xAE, 0xAF, 0xB5, 0xB6, 0xB7, 0xBA, 0xBB, 0xBD, 0xBE, 0xBF, 0xD6, 0xD7, 0xDA, 0xDB,
+    0xDD, 0xDE, 0xDF, 0xEA, 0xEB, 0xED, 0xEE, 0xEF, 0xF5, 0xF6, 0xF7, 0xFA, 0xFB, 0xFD, 0xFE, 0xFF,
+];
+
+/// The conversion table for reading 5 and 3 nibble data
+/// It's the write table inverted
+const NIBBLE_READ_TABLE_5_AND_3: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x05, 0x06, 0x00, 0x00, 0x07, 0x08, 0x00, 0x09, 0x0A, 0x0B,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0D, 0x00, 0x00, 0x0E, 0x0F, 0x00, 0x10, 0x11, 0x12,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0x14, 0x00, 0x15, 0x16, 0x17,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x18, 0x19, 0x1A, 0x00, 0x00, 0x1B, 0x1C, 0x00, 0x1D, 0x1E, 0x1F,
+];
+
+/// The number of five bit values holding the low three bits of each
+/// byte in a 5 and 3 data field
+const THREES_SIZE: usize = 0x9A;
+
+/// The number of bytes in each of the five groups a 5 and 3 sector is
+/// split into, the last byte of the sector is stored on its own
+const CHUNK_SIZE_5_AND_3: usize = 0x33;
+
+/// Parse a single byte encoded in 4 and 4 nibble format
+/// This is used to encode the volume, track, sector and checksum fields
+/// in the address field
+pub fn parse_nibble_byte_4_and_4(i: &[u8]) -> IResult<&[u8], u8> {
+    let (i, bytes) = take(2_usize)(i)?;
+
+    let byte = ((bytes[0] << 1) | 0x01) & bytes[1];
+
+    Ok((i, byte))
+}
+
+/// An address field identifies the data field that follows it
+pub struct AddressField {
+    /// The volume of the track
+    pub volume: u8,
+    /// The track of the track
+    pub track: u8,
+    /// The sector of the track
+    pub sector: u8,
+    /// The checksum of the address field
+    pub checksum: u8,
+    /// The encoding of the data field that follows, from the address
+    /// prologue
+    pub format: Format,
+}
+
+/// Finds the first sector address prologue in the data
+/// Returns the prologue as a slice of three bytes
+pub fn parse_prologue(i: &[u8]) -> IResult<&[u8], &[u8]> {
+    // Find 0xD5 0xAA, the start of the address prologue, then either 0x96 or 0xB5
+    // If another ending byte is found, keep searching from that point
+    let mut new_i = i;
+
+    loop {
+        let (i, _result) = take_until(&[0xD5, 0xAA][..])(new_i)?;
+
+        // Start the search at the character after DFAA
+        new_i = &i[2..];
+
+        let result = one_of::<&[u8], [u8; 2], crate::error::Error>([0x96_u8, 0xB5_u8])(new_i);
+        match result {
+            Ok(r) => {
+                debug!(target: PARSE, "Found an address field prologue");
+                return Ok((r.0, i));
+            }
+            // Should check for EOF error
+            Err(_e) => {}
+        }
+
+        // Next search includes the character after DFAA
+    }
+}
+
+/// Searches for an address prologue in the image
+/// If it's found, it returns the byte indicating the type of the prologue
+pub fn recognize_prologue(i: &[u8]) -> Option<u8> {
+    let result: IResult<&[u8], &[u8]> = parse_prologue(i);
+
+    match result {
+        Ok(r) => Some(r.1[2]),
+        Err(_) => None,
+    }
+}
+
+/// Find and parse an address field in the nibblized file
+pub fn find_and_parse_address_field(
+    config: &Config,
+) -> impl Fn(&[u8]) -> IResult<&[u8], AddressField> + '_ {
+    // Find the first field
+    // Read in the address field
+    // 3 byte prologue (D5 AA 96, or D5 AA B5 for 5 and 3 data)
+    // 2 byte odd-even encoded volume:
+    //   odd (D_7 D_5 D_3 D_1) followed by even (D_6 D_4 D_2 D_0)
+    // 2 byte odd-even encoded track
+    // 2 byte odd-even encoded sector
+    // 2 byte odd-even encoded checksum
+    // Epilogue DE AA EB
+    // debug!("Searching 1");
+    move |i| {
+        let (i, prologue) = parse_prologue(i)?;
+        let format = match prologue[2] {
+            0xB5 => Format::FiveAndThree,
+            _ => Format::SixAndTwo,
+        };
+        let (i, volume) = parse_nibble_byte_4_and_4(i)?;
+        let (i, track) = parse_nibble_byte_4_and_4(i)?;
+        let (i, sector) = parse_nibble_byte_4_and_4(i)?;
+        let (i, checksum) = parse_nibble_byte_4_and_4(i)?;
+        let (i, _epilogue) = take(3_usize)(i)?;
+
+        debug!(
+            target: PARSE,
+            "Found address field: volume: {}, track: {}, sector: {}, checksum: {}",
+            volume, track, sector, checksum
+        );
+
+        let computed_checksum = volume ^ track ^ sector;
+
+        let address_field = AddressField {
+            volume,
+            track,
+            sector,
+            checksum,
+            format,
+        };
+
+        if computed_checksum != checksum {
+            error!(
+                target: PARSE,
+                "Address field computed checksum not equal to disk checksum: {} {}",
+                computed_checksum, checksum
+            );
+            if !config.get_bool("ignore-checksums").unwrap_or(false) {
+                panic!(
+                    "Address field computed checksum not equal to disk checksum: {} {}",
+                    computed_checksum, checksum
+                );
+            }
+            warning(
+                Warning::new("ignored address field checksum mismatch")
+                    .with_location(Location::sector(track, sector).with_format("Apple nibble")),
+            );
+        }
+
+        Ok((i, address_field))
+    }
+}
+
+/// A 6 and 2 or 5 and 3 encoded data field that follows an address
+/// field in a nibblized image
+pub struct DataField {
+    /// The DataField prologue, three bytes
+    _prologue: [u8; 3],
+    /// 342 bytes of data encoded as 6 and 2, or 410 bytes encoded as
+    /// 5 and 3
+    pub data: Vec<u8>,
+    /// The checksum of the data
+    pub checksum: u8,
+    /// The DataField epilogue, three bytes
+    _epilogue: [u8; 3],
+}
+
+/// Parse the data component of a data field
+pub fn parse_6_and_2_nibblized_data(i: &[u8]) -> Vec<u8> {
+    let data: Vec<u8> = i
+        .iter()
+        .map(|b| NIBBLE_READ_TABLE_6_AND_2[(*b & 0x7F) as usize])
+        .collect();
+
+    data
+}
+
+/// Find and parse a 6 and 2 data field in the nibblized file
+pub fn find_and_parse_data_field(i: &[u8]) -> IResult<&[u8], DataField> {
+    find_and_parse_data_field_sized(i, Format::SixAndTwo.data_field_size())
+}
+
+/// Find and parse a 5 and 3 data field in the nibblized file
+pub fn find_and_parse_data_field_5_and_3(i: &[u8]) -> IResult<&[u8], DataField> {
+    find_and_parse_data_field_sized(i, Format::FiveAndThree.data_field_size())
+}
+
+/// Find and parse a data field with size bytes of data
+fn find_and_parse_data_field_sized(i: &[u8], size: usize) -> IResult<&[u8], DataField> {
+    // Find the next sequence of 0xD5 0xAA 0xAD that identifies a field
+    // let (i, find_tag) = tag([0xD5, 0xAA, 0xAD])(i)?;
+    // Find the first field
+    let (i, _data) = take_until(&[0xD5, 0xAA, 0xAD][..])(i)?;
+
+    // Read in the data field
+    // 3 byte prologue (D5 AA AD)
+    // 342 bytes data 6 and 2 encoded, or 410 bytes 5 and 3 encoded
+    // 1 byte checksum
+    // Epilogue DE AA EB
+    let (i, prologue) = take(3_usize)(i)?;
+    let (i, data) = take(size)(i)?;
+    let (i, checksum) = le_u8(i)?;
+    // let (i, _epilogue) = tag(&[0xDE, 0xAA, 0xEB][..])(i)?;
+    let (i, epilogue) = take(3_usize)(i)?;
+
+    Ok((
+        i,
+        DataField {
+            _prologue: prologue.try_into().unwrap(),
+            data: data.to_vec(),
+            checksum,
+            _epilogue: epilogue.try_into().unwrap(),
+        },
+    ))
+}
+
+/// A 256-byte 8-bit data structure computed from 6 and 2 data
+#[derive(Clone)]
+pub struct Sector {
+    /// The data
+    pub data: Vec<u8>,
+}
+
+/// Compute the checksum and transformed buffer for the data field
+pub fn data_field_build_buffer(data_field: &DataField) -> ([u8; 342], u8) {
+    // The data is split up into several different sections
+    // The first 0x56 bytes are the "auxiliary data buffer"
+    // Starting at offset 0x56 the 6 bit bytes are stored
+    let mut computed_checksum: u8 = 0;
+    let data_field_data_size = data_field.data.len();
+    let mut buffer = [0; 342];
+
+    for (index, byte) in data_field.data.iter().enumerate() {
+        computed_checksum ^= NIBBLE_READ_TABLE_6_AND_2[(*byte) as usize];
+        if index < 0x56 {
+            buffer[data_field_data_size - index - 1] = computed_checksum;
+        } else {
+            buffer[index - 0x56] = computed_checksum;
+        }
+    }
+    computed_checksum ^= NIBBLE_READ_TABLE_6_AND_2[data_field.checksum as usize];
+
+    (buffer, computed_checksum)
+}
+
+/// Transform a 6 and 2 data field to a 256-byte sector
+pub fn transform_data_field(config: &Config, data_field: &DataField) -> Sector {
+    // The data is split up into several different sections
+    // The first 0x56 bytes are the "auxiliary data buffer"
+    // Starting at offset 0x56 the 6 bit bytes are stored
+    // Two references that explain the encoding and decoding:
+    // Beneath Apple DOS and Beneath Apple ProDOS
+    // The source code for AppleCommander and apple2emu was invaluable
+    // in writing this code
+    let mut data = [0; 256];
+
+    let (buffer, computed_checksum) = data_field_build_buffer(data_field);
+
+    if computed_checksum != 0 {
+        error!(
+            target: PARSE,
+            "Invalid checksum on data: calculated: {}, disk: {}",
+            computed_checksum, data_field.checksum
+        );
+        if !config.get_bool("ignore-checksums").unwrap_or(false) {
+            panic!(
+                "Invalid checksum on data: calculated: {}, disk: {}",
+                computed_checksum, data_field.checksum
+            );
+        }
+    }
+
+    let reverse_values = [0x00, 0x02, 0x01, 0x03];
+    for i in 0..=255 {
+        let byte_1 = buffer[i];
+        let nibble_low = buffer.len() - (i % 0x56) - 1;
+        let byte_2 = buffer[nibble_low];
+        let shift_pairs = (i / 0x56) * 2;
+        let byte: u8 = (byte_1 << 2) | reverse_values[((byte_2 >> shift_pairs) & 0x03) as usize];
+        data[i] = byte;
+    }
+
+    Sector {
+        data: data.to_vec(),
+    }
+}
+
+/// Compute the checksum and transformed buffer for a 5 and 3 data
+/// field
+/// The first 0x9A values are the low three bits of each byte, stored
+/// backwards on the disk, followed by the high five bits of each byte.
+pub fn data_field_build_buffer_5_and_3(data_field: &DataField) -> ([u8; 410], u8) {
+    let mut computed_checksum: u8 = 0;
+    let mut buffer = [0; 410];
+
+    for (index, byte) in data_field.data.iter().enumerate().take(buffer.len()) {
+        computed_checksum ^= NIBBLE_READ_TABLE_5_AND_3[usize::from(*byte)];
+        if index < THREES_SIZE {
+            buffer[THREES_SIZE - index - 1] = computed_checksum;
+        } else {
+            buffer[index] = computed_checksum;
+        }
+    }
+    computed_checksum ^= NIBBLE_READ_TABLE_5_AND_3[usize::from(data_field.checksum)];
+
+    (buffer, computed_checksum)
+}
+
+/// Transform a 5 and 3 data field to a 256-byte sector
+/// Each group of five bytes is spread over five of the high five bit
+/// values and three of the low three bit values, the low bits of the
+/// fourth and fifth bytes share the spare bits of the other three.
+/// A field with a bad checksum is an error unless checksums are
+/// ignored.
+pub fn transform_data_field_5_and_3(
+    config: &Config,
+    data_field: &DataField,
+) -> std::result::Result<Sector, Error> {
+    let mut data = Vec::with_capacity(256);
+
+    let (buffer, computed_checksum) = data_field_build_buffer_5_and_3(data_field);
+
+    if computed_checksum != 0 {
+        error!(
+            target: PARSE,
+            "Invalid checksum on data: calculated: {}, disk: {}",
+            computed_checksum, data_field.checksum
+        );
+        if !config.get_bool("ignore-checksums").unwrap_or(false) {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Checksum)));
+        }
+    }
+
+    let (threes, fives) = buffer.split_at(THREES_SIZE);
+    for i in (0..CHUNK_SIZE_5_AND_3).rev() {
+        let three_1 = threes[i];
+        let three_2 = threes[CHUNK_SIZE_5_AND_3 + i];
+        let three_3 = threes[CHUNK_SIZE_5_AND_3 * 2 + i];
+        let three_4 = ((three_1 & 0x02) << 1) | (three_2 & 0x02) | ((three_3 & 0x02) >> 1);
+        let three_5 = ((three_1 & 0x01) << 2) | ((three_2 & 0x01) << 1) | (three_3 & 0x01);
+
+        data.push((fives[i] << 3) | ((three_1 >> 2) & 0x07));
+        data.push((fives[CHUNK_SIZE_5_AND_3 + i] << 3) | ((three_2 >> 2) & 0x07));
+        data.push((fives[CHUNK_SIZE_5_AND_3 * 2 + i] << 3) | ((three_3 >> 2) & 0x07));
+        data.push((fives[CHUNK_SIZE_5_AND_3 * 3 + i] << 3) | three_4);
+        data.push((fives[CHUNK_SIZE_5_AND_3 * 4 + i] << 3) | three_5);
+    }
+    data.push((fives[255] << 3) | (threes[THREES_SIZE - 1] & 0x07));
+
+    Ok(Sector { data })
+}
+
+/// Nibblize a sector using the 5 and 3 algorithm
+/// This is the inverse of transform_data_field_5_and_3, the values
+/// are chained with exclusive or and the checksum is the last value.
+pub fn build_nibble_sector_5_and_3(data: &[u8]) -> DataField {
+    let mut sector = [0_u8; 256];
+    let length = data.len().min(256);
+    sector[..length].copy_from_slice(&data[..length]);
+
+    let mut threes = [0_u8; THREES_SIZE];
+    let mut fives = [0_u8; 256];
+    for (chunk, bytes) in sector.chunks_exact(5).enumerate() {
+        let i = CHUNK_SIZE_5_AND_3 - chunk - 1;
+        for (group, byte) in bytes.iter().enumerate() {
+            fives[CHUNK_SIZE_5_AND_3 * group + i] = byte >> 3;
+        }
+        for group in 0..3 {
+            threes[CHUNK_SIZE_5_AND_3 * group + i] = ((bytes[group] & 0x07) << 2)
+                | (((bytes[3] >> (2 - group)) & 0x01) << 1)
+                | ((bytes[4] >> (2 - group)) & 0x01);
+        }
+    }
+    fives[255] = sector[255] >> 3;
+    threes[THREES_SIZE - 1] = sector[255] & 0x07;
+
+    let mut previous: u8 = 0;
+    let data: Vec<u8> = threes
+        .iter()
+        .rev()
+        .chain(fives.iter())
+        .map(|value| {
+            let nibble = NIBBLE_WRITE_TABLE_5_AND_3[usize::from(value ^ previous)];
+            previous = *value;
+            nibble
+        })
+        .collect();
+
+    DataField {
+        _prologue: [0xD5, 0xAA, 0xAD],
+        data,
+        checksum: NIBBLE_WRITE_TABLE_5_AND_3[usize::from(previous)],
+        _epilogue: [0xDE, 0xAA, 0xEB],
+    }
+}
+
+/// Nibblize a sector
+/// This nibblizes a sector using the 6 and 2 algorithm
+/// This encodes data in the lower six bits.
+/// There are two reserved bytes, 0xAA and 0xD5
+///
+/// There are several ways this can be done.  The clearest is to split
+/// up the data into blocks that are multiples of six, since the
+/// encoding format uses the lower six bits.
+/// For u8 blocks of size six or 256 (the standard sector size) work.
+pub fn build_nibble_sector(data: &[u8]) -> DataField {
+    // The nibble data plus two bytes for the checksum
+    let mut nibble_data: [u8; 344] = [0; 344];
+
+    // The following was recommended from cargo-clippy, it's the equivalent of a
+    // loop over the entire data array:
+    // for i in 0..=255 {
+    //     nibble_data[i + 86] = data[i];
+    // }
+    // Copy a slice instead
+    nibble_data[86..(0xFF + 0x56 + 1)].copy_from_slice(&data[..(0xFF + 1)]);
+
+    let mut val: u8;
+
+    for (i, nibble_item) in nibble_data.iter_mut().enumerate().take(0x56) {
+        let ac_index: usize = (i + 0xAC) % 0x100;
+        let index_56: usize = (i + 0x56) % 0x100;
+        let index: usize = i % 0x100;
+        val = (((data[ac_index] & 0x1) << 1) | ((data[ac_index] & 0x2) >> 1)) << 6;
+        val |= (((data[index_56] & 0x1) << 1) | ((data[index_56] & 0x2) >> 1)) << 4;
+        val |= (((data[index] & 0x1) << 1) | ((data[index] & 0x2) >> 1)) << 2;
+        *nibble_item = val;
+    }
+
+    nibble_data[84] &= 0x3F;
+    nibble_data[85] &= 0x3F;
+
+    let mut checksum: u8 = 0;
+    let mut saved_data: u8;
+    for item in &mut nibble_data {
+        saved_data = *item;
+        *item ^= checksum;
+        checksum = saved_data;
+    }
+
+    let final_data: [u8; 342] = nibble_data[0..=341]
+        .iter()
+        .map(|d| NIBBLE_WRITE_TABLE_6_AND_2[(d >> 2) as usize])
+        .collect::<Vec<u8>>()
+        .try_into()
+        .unwrap();
+
+    DataField {
+        _prologue: [0xD5, 0xAA, 0xAD],
+        data: final_data.to_vec(),
+        checksum,
+        _epilogue: [0xDE, 0xAA, 0xEB],
+    }
+}
+
+/// Encode a byte in 4 and 4 format, as used in address fields
+pub fn encode_nibble_byte_4_and_4(byte: u8) -> [u8; 2] {
+    [(byte >> 1) | 0xAA, byte | 0xAA]
+}
+
+/// Encode a complete address field, with the prologue, checksum and
+/// epilogue
+pub fn encode_address_field(volume: u8, track: u8, sector: u8) -> Vec<u8> {
+    encode_address_field_with_prologue(0x96, volume, track, sector)
+}
+
+/// Encode a complete address field for a sector with a 5 and 3 data
+/// field, as DOS 3.2 writes them
+pub fn encode_address_field_5_and_3(volume: u8, track: u8, sector: u8) -> Vec<u8> {
+    encode_address_field_with_prologue(0xB5, volume, track, sector)
+}
+
+/// Encode an address field with the last byte of its prologue
+fn encode_address_field_with_prologue(prologue: u8, volume: u8, track: u8, sector: u8) -> Vec<u8> {
+    let mut nibbles = vec![0xD5, 0xAA, prologue];
+    for byte in [volume, track, sector, volume ^ track ^ sector] {
+        nibbles.extend_from_slice(&encode_nibble_byte_4_and_4(byte));
+    }
+    nibbles.extend_from_slice(&[0xDE, 0xAA, 0xEB]);
+
+    nibbles
+}
+
+/// Encode a 256 byte sector as a complete 6 and 2 data field, with
+/// the prologue, checksum and epilogue
+pub fn encode_data_field(data: &[u8]) -> Vec<u8> {
+    let data_field = build_nibble_sector(data);
+    // The checksum cancels the running checksum of the data
+    let checksum = data_field.data.iter().fold(0, |checksum, byte| {
+        checksum ^ NIBBLE_READ_TABLE_6_AND_2[usize::from(*byte)]
+    });
+
+    let mut nibbles = data_field._prologue.to_vec();
+    nibbles.extend_from_slice(&data_field.data);
+    nibbles.push(NIBBLE_WRITE_TABLE_6_AND_2[usize::from(checksum)]);
+    nibbles.extend_from_slice(&data_field._epilogue);
+
+    nibbles
+}
+
+/// Encode a 256 byte sector as a complete 5 and 3 data field, with
+/// the prologue, checksum and epilogue
+pub fn encode_data_field_5_and_3(data: &[u8]) -> Vec<u8> {
+    let data_field = build_nibble_sector_5_and_3(data);
+
+    let mut nibbles = data_field._prologue.to_vec();
+    nibbles.extend_from_slice(&data_field.data);
+    nibbles.push(data_field.checksum);
+    nibbles.extend_from_slice(&data_field._epilogue);
+
+    nibbles
+}
+
+/// Nibblize a slice of u8 data
+pub fn nibblize_data(data: &[u8]) -> Vec<u8> {
+    let mut output_data: Vec<u8> = Vec::new();
+
+    let mut i = 0;
+    debug!(target: PARSE, "Data length: {}", data.len());
+    while (i + 256) < data.len() {
+        let block = &data[i..=(i + 255)];
+        output_data.append(&mut build_nibble_sector(block).data);
+        i += 256;
+    }
+
+    if i > 0 {
+        i -= 256;
+    }
+    let block = &data[i..data.len()];
+    output_data.append(&mut build_nibble_sector(block).data);
+
+    output_data
+}
+
+/// The ProDOS-order sector stored in each physical sector of a track
+/// Each ProDOS block is two consecutive sectors in this order.
+pub const PRODOS_PHYSICAL_ORDER: [u8; 16] = [
+    0x00, 0x08, 0x01, 0x09, 0x02, 0x0A, 0x03, 0x0B, 0x04, 0x0C, 0x05, 0x0D, 0x06, 0x0E, 0x07, 0x0F,
+];
+
+/// The order decoded sectors are numbered in
+/// The address field of a sector holds its physical sector number.
+/// DOS 3.3 and ProDOS skew their logical sectors across the physical
+/// sectors of a track, so sector dumps for DOS tools are stored in
+/// DOS 3.3 order (.dsk and .do files) or ProDOS order (.po files).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SectorOrder {
+    /// The physical sector numbers from the address fields
+    Physical,
+    /// DOS 3.3 logical sector order
+    #[default]
+    Dos33,
+    /// ProDOS block order
+    ProDOS,
+}
+
+impl SectorOrder {
+    /// Return the table from physical to logical sectors, None for
+    /// physical order
+    fn table(&self) -> Option<&'static [u8; 16]> {
+        match self {
+            SectorOrder::Physical => None,
+            SectorOrder::Dos33 => Some(&DOS_33_PHYSICAL_ORDER),
+            SectorOrder::ProDOS => Some(&PRODOS_PHYSICAL_ORDER),
+        }
+    }
+
+    /// Return the logical sector stored in a physical sector
+    /// Sector numbers past the sixteen sectors of a track are
+    /// unchanged.
+    pub fn logical(&self, physical: u8) -> u8 {
+        self.table()
+            .and_then(|table| table.get(usize::from(physical)))
+            .copied()
+            .unwrap_or(physical)
+    }
+
+    /// Return the physical sector a logical sector is stored in
+    pub fn physical(&self, logical: u8) -> u8 {
+        self.table()
+            .and_then(|table| table.iter().position(|sector| *sector == logical))
+            .map(|physical| physical as u8)
+            .unwrap_or(logical)
+    }
+}
+
+impl FromStr for SectorOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<SectorOrder, Error> {
+        match s.to_lowercase().as_str() {
+            "physical" => Ok(SectorOrder::Physical),
+            "dos" | "dos33" => Ok(SectorOrder::Dos33),
+            "prodos" => Ok(SectorOrder::ProDOS),
+            _ => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Unknown sector order: {}", s),
+            )))),
+        }
+    }
+}
+
+/// A single track on the disk
+#[derive(Default)]
+pub struct Track {
+    /// The sectors on the disk, by sector number in the disk's sector
+    /// order
+    pub sectors: BTreeMap<u8, Sector>,
+}
+
+/// A single volume on the disk
+#[derive(Default)]
+pub struct Volume {
+    /// The tracks on the disk
+    pub tracks: BTreeMap<u8, Track>,
+}
+
+/// A Nibble encoded disk
+/// (although this is generic enough a module-wide data structure
+/// could be used)
+#[derive(Default)]
+pub struct NibbleDisk {
+    /// The sectors on the disk
+    pub volumes: BTreeMap<u8, Volume>,
+    /// Sectors with an address field that couldn't be decoded
+    pub failed_sectors: Vec<FailedSector>,
+    /// The order the sectors of each track are numbered in
+    pub order: SectorOrder,
+    /// The encoding of the data fields, 5 and 3 disks have 13 sectors
+    /// per track
+    pub format: Format,
+}
+
+/// A sector whose address field was found but whose data couldn't be
+/// decoded
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FailedSector {
+    /// The volume from the address field
+    pub volume: u8,
+    /// The track from the address field
+    pub track: u8,
+    /// The physical sector from the address field
+    pub sector: u8,
+    /// Why the data couldn't be decoded
+    pub reason: String,
+}
+
+/// Options for decoding marginal nibble dumps
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NibbleOptions {
+    /// The most bytes of sync and gap searched after an address field
+    /// for its data field
+    pub resync_tolerance: usize,
+    /// Decode damaged data fields again from each bit offset, for
+    /// sectors that slipped bits when the disk was dumped
+    pub bit_shift_retry: bool,
+    /// The order decoded sectors are numbered and saved in
+    pub sector_order: SectorOrder,
+}
+
+impl NibbleOptions {
+    /// The default options
+    /// DOS 3.3 writes five to ten sync bytes between the address and
+    /// data fields, the tolerance allows for much longer gaps from
+    /// drives running slow.
+    pub const DEFAULT: NibbleOptions = NibbleOptions {
+        resync_tolerance: 64,
+        bit_shift_retry: true,
+        sector_order: SectorOrder::Dos33,
+    };
+
+    /// Build options from a configuration, using the defaults for any
+    /// setting that's missing
+    pub fn from_config(config: &Config) -> NibbleOptions {
+        NibbleOptions {
+            resync_tolerance: config
+                .get_int("nibble-resync-tolerance")
+                .ok()
+                .and_then(|value| usize::try_from(value).ok())
+                .unwrap_or(NibbleOptions::DEFAULT.resync_tolerance),
+            bit_shift_retry: config
+                .get_bool("nibble-bit-shift-retry")
+                .unwrap_or(NibbleOptions::DEFAULT.bit_shift_retry),
+            sector_order: match config.get_string("nibble-sector-order") {
+                Ok(value) => value.parse().unwrap_or_else(|e| {
+                    warn!(target: PARSE, "{}, using the default", e);
+                    NibbleOptions::DEFAULT.sector_order
+                }),
+                Err(_) => NibbleOptions::DEFAULT.sector_order,
+            },
+        }
+    }
+}
+
+impl Default for NibbleOptions {
+    fn default() -> NibbleOptions {
+        NibbleOptions::DEFAULT
+    }
+}
+
+impl NibbleDisk {
+    /// Return the volume numbers found in the address fields
+    pub fn volume_numbers(&self) -> Vec<u8> {
+        self.volumes.keys().copied().collect()
+    }
+
+    /// Return the decoded sectors as a DOS 3.3 order image, for reading
+    /// the DOS filesystem on the disk
+    /// DOS 3.2 disks have no sector skew, their image has 13 sectors per
+    /// track in physical order.
+    /// Returns None if no sectors were decoded.
+    pub fn dos_order_image(&self) -> Option<Vec<u8>> {
+        self.sector_image(SectorOrder::Dos33)
+    }
+
+    /// Return the decoded sectors as a flat image in a sector order
+    /// Each track holds its sectors in the chosen order and missing
+    /// sectors are filled with zeros, so a standard disk is always a
+    /// 143,360 byte image.
+    /// If more than one volume has a sector, the lowest volume is used.
+    /// The image has at least 35 tracks.
+    /// DOS 3.2 disks are always in physical order.
+    /// Returns None if no sectors were decoded.
+    pub fn sector_image(&self, order: SectorOrder) -> Option<Vec<u8>> {
+        let geometry = self.raw_geometry()?;
+        Some(self.export_raw(&self.raw_order(order), &geometry))
+    }
+
+    /// Return the order the decoded sectors are written in a flat image
+    /// The sectors are numbered in the order the disk was decoded in,
+    /// the interleave maps them to the chosen order.
+    fn raw_order(&self, order: SectorOrder) -> RawOrder {
+        let interleave = match self.format {
+            Format::FiveAndThree => Vec::new(),
+            _ => (0..16)
+                .map(|sector| self.order.logical(order.physical(sector)))
+                .collect(),
+        };
+        RawOrder {
+            interleave,
+            ..RawOrder::default()
+        }
+    }
+}
+
+// impl DiskImageParser for NibbleDisk {
+//     fn parse_disk_image<'a>(
+//         &self,
+//         config: &Config,
+//         filename: &str,
+//         // data: &'a [u8],
+//     ) -> IResult<&'a [u8], DiskImage<'a>> {
+//         let guess_option = format_from_filename_and_data(filename, data);
+
+//         match guess_option {
+//             Some(guess) => {
+//                 let (i, disk) = parse_nib_disk(config)(data)?;
+//                 Ok((
+//                     i,
+//                     DiskImage::Apple(AppleDisk {
+//                         encoding: guess.encoding,
+//                         format: guess.format,
+//                         data: AppleDiskData::Nibble(disk),
+//                     }),
+//                 ))
+//             }
+//             None => {
+//                 panic!("Invalid format");
+//             }
+//         }
+//     }
+// }
+
+impl DiskImageSaver for NibbleDisk {
+    /// Save the decoded sectors as a flat dump, in the disk's sector
+    /// order
+    /// With the default DOS 3.3 order the dump is a .dsk or .do image,
+    /// with ProDOS order a .po image.  Missing sectors and tracks are
+    /// filled with zeros so the image can be mounted.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        _selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error> {
+        if let Some(geometry) = self.raw_geometry() {
+            writer.write_all(&self.export_raw(&self.raw_order(self.order), &geometry))?;
+        }
+        Ok(())
+    }
+}
+
+impl RawExporter for NibbleDisk {
+    /// Higher volumes come first, so a flat image keeps the sector from
+    /// the lowest volume when more than one volume has it
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.volumes
+            .values()
+            .rev()
+            .flat_map(|volume| volume.tracks.iter())
+            .map(|(track_number, track)| LogicalTrack {
+                track: *track_number,
+                head: 0,
+                sectors: track
+                    .sectors
+                    .iter()
+                    .map(|(sector_number, sector)| {
+                        LogicalSector::new(
+                            SectorId::new(*track_number, 0, *sector_number),
+                            sector.data.clone(),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Nibble disks have 16 sectors of 256 bytes per track, or 13 on
+    /// DOS 3.2 disks, and at least 35 tracks
+    fn raw_geometry(&self) -> Option<Geometry> {
+        let tracks = self.logical_tracks();
+        let track_count = (tracks.iter().map(|t| t.track).max()? + 1).max(35);
+        match self.format {
+            Format::FiveAndThree => Some(Geometry::apple_dos_32(track_count)),
+            _ => Some(Geometry::apple_dos_33(track_count)),
+        }
+    }
+}
+
+/// A field, containing both the data field and address field
+pub struct Field {
+    /// The address field, which contains volume, track and sector info indicating
+    /// where this sector is located on the disk
+    /// It also contains a checksum
+    pub address_field: AddressField,
+    /// The data field, which contains the data and checksum
+    pub data_field: DataField,
+}
+
+/// Parse an address field, data field and build a Sector
+pub fn parse_nib_sector(config: &Config) -> impl Fn(&[u8]) -> IResult<&[u8], Field> + '_ {
+    move |i| {
+        let (i, header) = find_and_parse_address_field(config)(i)?;
+        let (i, data_field) = find_and_parse_data_field(i)?;
+
+        Ok((
+            i,
+            Field {
+                address_field: header,
+                data_field,
+            },
+        ))
+    }
+}
+
+/// Read disk bytes from a bit stream the way the Disk II controller
+/// does, starting at a bit offset
+/// Bits are shifted into a latch until its high bit is set, so extra
+/// zero bits between bytes are dropped and the bytes after a bit slip
+/// line up again.
+pub fn latch_nibbles(data: &[u8], bit_offset: usize) -> Vec<u8> {
+    let mut nibbles = Vec::with_capacity(data.len());
+    let mut latch: u8 = 0;
+
+    for bit in bit_offset..data.len() * 8 {
+        latch = (latch << 1) | ((data[bit / 8] >> (7 - bit % 8)) & 0x01);
+        if latch & 0x80 != 0 {
+            nibbles.push(latch);
+            latch = 0;
+        }
+    }
+
+    nibbles
+}
+
+/// Find and parse the data field that follows an address field,
+/// searching at most tolerance bytes for its prologue
+/// Returns the offset of the end of the field and the field
+fn find_data_field_within(
+    i: &[u8],
+    tolerance: usize,
+    format: Format,
+) -> Option<(usize, DataField)> {
+    let window = &i[..i.len().min(tolerance + 3)];
+    let start = window.windows(3).position(|w| w == [0xD5, 0xAA, 0xAD])?;
+    let (rest, data_field) =
+        find_and_parse_data_field_sized(&i[start..], format.data_field_size()).ok()?;
+
+    Some((i.len() - rest.len(), data_field))
+}
+
+/// Return true if the data field decodes with a good checksum and
+/// only holds valid disk bytes
+fn data_field_is_good(data_field: &DataField, format: Format) -> bool {
+    let computed_checksum = match format {
+        Format::FiveAndThree => data_field_build_buffer_5_and_3(data_field).1,
+        _ => data_field_build_buffer(data_field).1,
+    };
+    computed_checksum == 0
+        && data_field
+            .data
+            .iter()
+            .chain(std::iter::once(&data_field.checksum))
+            .all(|b| format.write_table().contains(b))
+}
+
+/// Transform a data field in either encoding to a 256-byte sector
+fn transform_data_field_format(
+    config: &Config,
+    data_field: &DataField,
+    format: Format,
+) -> std::result::Result<Sector, String> {
+    match format {
+        Format::FiveAndThree => {
+            transform_data_field_5_and_3(config, data_field).map_err(|e| e.to_string())
+        }
+        _ => Ok(transform_data_field(config, data_field)),
+    }
+}
+
+/// Find and decode the data field following an address field
+/// Damaged fields are retried at each bit offset if the options allow.
+/// A field with a bad checksum is only returned if checksums are
+/// ignored.
+/// Returns the number of bytes used and the sector, or why the sector
+/// couldn't be decoded
+fn decode_data_field(
+    config: &Config,
+    options: &NibbleOptions,
+    format: Format,
+    i: &[u8],
+) -> std::result::Result<(usize, Sector, bool), String> {
+    let found = find_data_field_within(i, options.resync_tolerance, format);
+    if let Some((used, data_field)) = &found {
+        if data_field_is_good(data_field, format) {
+            return Ok((
+                *used,
+                transform_data_field_format(config, data_field, format)?,
+                true,
+            ));
+        }
+    }
+
+    if options.bit_shift_retry {
+        // Enough bytes for the sync gap and a data field, plus a byte
+        // for the shifted bits
+        let window = &i[..i
+            .len()
+            .min(options.resync_tolerance + format.data_field_size() + 8)];
+        for bit_offset in 0..8 {
+            let nibbles = latch_nibbles(window, bit_offset);
+            if let Some((used, data_field)) =
+                find_data_field_within(&nibbles, options.resync_tolerance, format)
+            {
+                if data_field_is_good(&data_field, format) {
+                    debug!(
+                        target: PARSE,
+                        "Recovered data field at bit offset {}", bit_offset
+                    );
+                    // Every latched byte used at least eight bits, so
+                    // this doesn't skip past the next address field
+                    return Ok((
+                        used.min(i.len()),
+                        transform_data_field_format(config, &data_field, format)?,
+                        true,
+                    ));
+                }
+            }
+        }
+    }
+
+    match found {
+        Some((used, data_field)) if config.get_bool("ignore-checksums").unwrap_or(false) => Ok((
+            used,
+            transform_data_field_format(config, &data_field, format)?,
+            false,
+        )),
+        Some(_) => Err(String::from("data field checksum mismatch")),
+        None => Err(format!(
+            "no data field within {} bytes of the address field",
+            options.resync_tolerance
+        )),
+    }
+}
+
+/// Parse an entire nibble encoded disk
+/// Sectors that can't be decoded are listed in failed_sectors and
+/// recorded as warnings, decoding continues with the next address
+/// field.
+pub fn parse_nib_disk(config: &Options) -> impl Fn(&[u8]) -> IResult<&[u8], NibbleDisk> + '_ {
+    move |i| {
+        with_limits(config.limits(), || {
+            let options = NibbleOptions::from_config(config);
+            let selected = selected_volume(config);
+            let mut disk = NibbleDisk {
+                order: options.sector_order,
+                ..NibbleDisk::default()
+            };
+            let mut found = BTreeMap::new();
+            let mut fields = 0;
+
+            // The address field search uses streaming combinators, so the
+            // end of the image shows up as Incomplete rather than an Error
+            let mut i = i;
+            loop {
+                let (rest, address_field) = match find_and_parse_address_field(config)(i) {
+                    Ok(result) => result,
+                    Err(nom::Err::Failure(e)) => return Err(nom::Err::Failure(e)),
+                    Err(_) => break,
+                };
+                i = rest;
+                fields += 1;
+                // Disks with both encodings are rare, the first address
+                // field decides
+                if fields == 1 {
+                    disk.format = address_field.format;
+                }
+
+                *found.entry(address_field.volume).or_insert(0) += 1;
+                let decoded = decode_data_field(config, &options, address_field.format, i);
+                if let Ok((used, _, _)) = &decoded {
+                    i = &i[*used..];
+                }
+                if selected.is_some_and(|volume| volume != address_field.volume) {
+                    continue;
+                }
+
+                debug!(target: PARSE, "Parsing another field");
+                match decoded {
+                    Ok((_, sector, checksum_good)) => {
+                        let volume = disk.volumes.entry(address_field.volume);
+                        let track = volume.or_default().tracks.entry(address_field.track);
+                        let entry = track.or_default().sectors.entry(sector_number(
+                            disk.format,
+                            disk.order,
+                            address_field.sector,
+                        ));
+                        if !checksum_good {
+                            warning(
+                                Warning::new("ignored data field checksum mismatch").with_location(
+                                    Location::sector(address_field.track, address_field.sector)
+                                        .with_format("Apple nibble"),
+                                ),
+                            );
+                        }
+                        entry.or_insert(sector);
+                    }
+                    Err(reason) => disk.failed_sectors.push(FailedSector {
+                        volume: address_field.volume,
+                        track: address_field.track,
+                        sector: address_field.sector,
+                        reason,
+                    }),
+                }
+            }
+
+            debug!(target: PARSE, "Found {} fields", fields);
+
+            // Tracks are often dumped with more than one revolution, a
+            // sector that decoded on another pass hasn't failed
+            let volumes = &disk.volumes;
+            let (format, order) = (disk.format, disk.order);
+            disk.failed_sectors.retain(|failed| {
+                !volumes
+                    .get(&failed.volume)
+                    .and_then(|volume| volume.tracks.get(&failed.track))
+                    .is_some_and(|track| {
+                        track
+                            .sectors
+                            .contains_key(&sector_number(format, order, failed.sector))
+                    })
+            });
+            disk.failed_sectors.dedup();
+            for failed in &disk.failed_sectors {
+                warning(
+                    Warning::new(&format!("couldn't decode sector: {}", failed.reason))
+                        .with_location(
+                            Location::sector(failed.track, failed.sector)
+                                .with_format("Apple nibble"),
+                        ),
+                );
+            }
+
+            let volumes: Vec<String> = found.keys().map(|volume| volume.to_string()).collect();
+            match selected {
+                Some(volume) if !found.contains_key(&volume) => warning(Warning::new(&format!(
+                    "volume {} not found, the disk has volumes {}",
+                    volume,
+                    volumes.join(", ")
+                ))),
+                None if found.len() > 1 => warning(Warning::new(&format!(
+                    "the disk has {} volumes ({}), set volume to decode one of them",
+                    found.len(),
+                    volumes.join(", ")
+                ))),
+                _ => (),
+            }
+
+            Ok((i, disk))
+        })
+    }
+}
+
+/// Return the number a physical sector is stored under
+/// DOS 3.2 disks are kept in physical order, the sector order only
+/// applies to 16 sector disks.
+fn sector_number(format: Format, order: SectorOrder, physical: u8) -> u8 {
+    match format {
+        Format::FiveAndThree => physical,
+        _ => order.logical(physical),
+    }
+}
+
+/// Return the volume selected with the volume setting, if any
+/// Address fields from other volumes are skipped when decoding.
+pub fn selected_volume(config: &Config) -> Option<u8> {
+    config
+        .get_int("volume")
+        .ok()
+        .and_then(|volume| u8::try_from(volume).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_nibble_sector, build_nibble_sector_5_and_3, data_field_build_buffer,
+        data_field_build_buffer_5_and_3, encode_address_field, encode_address_field_5_and_3,
+        encode_data_field, encode_data_field_5_and_3, find_and_parse_address_field,
+        find_and_parse_data_field_5_and_3, latch_nibbles, parse_nib_disk,
+        parse_nibble_byte_4_and_4, parse_prologue, transform_data_field,
+        transform_data_field_5_and_3, DataField, Format, SectorOrder, NIBBLE_WRITE_TABLE_5_AND_3,
+        NIBBLE_WRITE_TABLE_6_AND_2,
+    };
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::logical::RawExporter;
+    use crate::disk_format::parsed::collect_warnings;
+    use crate::disk_format::testgen;
+    use crate::error::{Error, ErrorKind, InvalidErrorKind};
+    use crate::options::Options;
+    use pretty_assertions::assert_eq;
+
+    /// Test nibble byte 4 and 4 parsing works
+    #[test]
+    fn parse_nibble_byte_4_and_4_works() {
+        let volume_data: [u8; 2] = [0xFF, 0xFE];
+        let track_data: [u8; 2] = [0xAB, 0xBF];
+        let sector_data: [u8; 2] = [0xAA, 0xAF];
+        let checksum_data: [u8; 2] = [0xFE, 0xEE];
+
+        let zero_data: [u8; 2] = [0x00, 0x00];
+        let one_data: [u8; 2] = [0x00, 0x01];
+
+        let volume_result = parse_nibble_byte_4_and_4(&volume_data);
+        match volume_result {
+            Ok(volume) => {
+                assert_eq!(volume.1, 0xFE);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+
+        let track_result = parse_nibble_byte_4_and_4(&track_data);
+        match track_result {
+            Ok(track) => {
+                assert_eq!(track.1, 0x17);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+
+        let sector_result = parse_nibble_byte_4_and_4(&sector_data);
+        match sector_result {
+            Ok(sector) => {
+                assert_eq!(sector.1, 0x05);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+
+        let checksum_result = parse_nibble_byte_4_and_4(&checksum_data);
+        match checksum_result {
+            Ok(checksum) => {
+                assert_eq!(checksum.1, 0xEC);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+
+        let zero_result = parse_nibble_byte_4_and_4(&zero_data);
+        match zero_result {
+            Ok(zero) => {
+                assert_eq!(zero.1, 0x00);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+
+        let one_result = parse_nibble_byte_4_and_4(&one_data);
+        match one_result {
+            Ok(one) => {
+                assert_eq!(one.1, 0x01);
+            }
+            Err(e) => {
+                panic!("Parser failed: {}", e);
+            }
+        }
+    }
+
+    /// Test find_and_parse_address_field with valid data
+    #[test]
+    fn find_and_parse_address_field_works() {
+        // volume: 254, track: 23, sector: 5
+        let address_field_data: [u8; 14] = [
+            0xD5, 0xAA, 0x96, 0xFF, 0xFE, 0xAB, 0xBF, 0xAA, 0xAF, 0xFE, 0xEE, 0xDE, 0xAA, 0xEB,
+        ];
+
+        let config = Options::default();
+        let address_field_result = find_and_parse_address_field(&config)(&address_field_data);
+
+        match address_field_result {
+            Ok(address_field) => {
+                assert_eq!(address_field.1.volume, 0xFE);
+                assert_eq!(address_field.1.track, 0x17);
+                assert_eq!(address_field.1.sector, 0x05);
+                assert_eq!(address_field.1.checksum, 0xEC);
+            }
+            Err(e) => {
+                panic!("Parsing error: {}", e);
+            }
+        }
+    }
+
+    /// Test that transform_data_field works
+    /// TODO: Build some known checksum values
+    #[test]
+    pub fn transform_data_field_works() {
+        let mut data: [u8; 342] = [0; 342];
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = NIBBLE_WRITE_TABLE_6_AND_2[i % 0x40];
+        }
+
+        let data_field = DataField {
+            _prologue: [0xD5, 0xAA, 0xAD],
+            data: data.to_vec(),
+            checksum: 0x96,
+            _epilogue: [0xDE, 0xAA, 0xEB],
+        };
+
+        let (_buffer, checksum) = data_field_build_buffer(&data_field);
+
+        assert_eq!(checksum, 1);
+    }
+
+    /// Do a round-trip test of nibblizing a sector and denibblizing
+    /// it.  More work needs to be done on this.
+    ///
+    /// A full disk round-trip test may not be byte-for-byte equal.
+    /// The nibblized data may be out of order, which is why data
+    /// address headers are included.
+    #[test]
+    pub fn data_field_round_trip() {
+        let mut original_data: [u8; 256] = [0; 256];
+
+        for i in 0_u8..=255_u8 {
+            original_data[i as usize] = i;
+        }
+        original_data[255] = 1;
+
+        let data_field = build_nibble_sector(&original_data);
+
+        let config = Options::default();
+        let sector = transform_data_field(&config, &data_field);
+
+        assert_eq!(sector.data, original_data);
+    }
+
+    /// Test that 5 and 3 data fields decode to the sector they were
+    /// built from
+    #[test]
+    pub fn data_field_5_and_3_round_trip() {
+        let config = Options::default();
+        for original_data in [
+            (0_u8..=255).collect::<Vec<u8>>(),
+            (0_u8..=255).map(|i| i.wrapping_mul(37) ^ 0x5A).collect(),
+            vec![0xFF; 256],
+        ] {
+            let data_field = build_nibble_sector_5_and_3(&original_data);
+            assert_eq!(data_field.data.len(), 410);
+            assert!(data_field
+                .data
+                .iter()
+                .chain(std::iter::once(&data_field.checksum))
+                .all(|b| NIBBLE_WRITE_TABLE_5_AND_3.contains(b)));
+            assert_eq!(data_field_build_buffer_5_and_3(&data_field).1, 0);
+
+            let sector = transform_data_field_5_and_3(&config, &data_field).unwrap();
+            assert_eq!(sector.data, original_data);
+        }
+
+        // A damaged nibble breaks the checksum
+        let mut nibbles = encode_data_field_5_and_3(&[0x12; 256]);
+        nibbles[100] = if nibbles[100] == 0xAB { 0xAD } else { 0xAB };
+        let (_, data_field) = find_and_parse_data_field_5_and_3(&nibbles).unwrap();
+        assert_ne!(data_field_build_buffer_5_and_3(&data_field).1, 0);
+    }
+
+    /// Test that a corrupted 5 and 3 data field is an error, or decodes
+    /// when checksums are ignored
+    #[test]
+    fn data_field_5_and_3_checksum_error() {
+        let mut nibbles = encode_data_field_5_and_3(&[0x12; 256]);
+        nibbles[100] = if nibbles[100] == 0xAB { 0xAD } else { 0xAB };
+        let (_, data_field) = find_and_parse_data_field_5_and_3(&nibbles).unwrap();
+
+        assert_eq!(
+            transform_data_field_5_and_3(&Options::default(), &data_field).err(),
+            Some(Error::new(ErrorKind::Invalid(InvalidErrorKind::Checksum)))
+        );
+
+        let config = Options::default()
+            .with_override("ignore-checksums", true)
+            .unwrap();
+        let sector = transform_data_field_5_and_3(&config, &data_field).unwrap();
+        assert_eq!(sector.data.len(), 256);
+
+        // The damaged sector is left out of the disk
+        let mut track = vec![0xFF, 0xFF];
+        track.extend(encode_address_field_5_and_3(254, 0, 0));
+        track.extend_from_slice(&[0xFF, 0xFF]);
+        track.extend(nibbles);
+        track.extend_from_slice(&[0xFF, 0xFF]);
+        track.extend(encode_address_field_5_and_3(254, 0, 1));
+        track.extend_from_slice(&[0xFF, 0xFF]);
+        track.extend(encode_data_field_5_and_3(&[0x34; 256]));
+        track.extend_from_slice(&[0xFF; 16]);
+        let parsed = collect_warnings::<_, ()>(|| {
+            Ok(parse_nib_disk(&Options::default())(&track).unwrap().1)
+        })
+        .unwrap();
+        let sectors = &parsed.value.volumes[&254].tracks[&0].sectors;
+        assert_eq!(sectors.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(parsed.warnings.len(), 1);
+    }
+
+    /// Test decoding a 13 sector track with DOS 3.2 address fields
+    #[test]
+    fn parse_nib_disk_5_and_3_works() {
+        let mut nibbles = vec![];
+        for sector in 0..13_u8 {
+            nibbles.extend_from_slice(&[0xFF, 0xFF]);
+            nibbles.extend(encode_address_field_5_and_3(254, 1, sector));
+            nibbles.extend_from_slice(&[0xFF, 0xFF]);
+            nibbles.extend(encode_data_field_5_and_3(&[sector; 256]));
+        }
+        // 6 and 2 data fields aren't decoded as 5 and 3
+        nibbles.extend(nibble_sector(254, 2, 0, &[0xAA; 256]));
+
+        let config = Options::default();
+        let (_, disk) = parse_nib_disk(&config)(&nibbles).unwrap();
+        assert_eq!(disk.format, Format::FiveAndThree);
+        let track = &disk.volumes[&254].tracks[&1];
+        assert_eq!(track.sectors.len(), 13);
+        for (sector, data) in &track.sectors {
+            assert_eq!(data.data, [*sector; 256]);
+        }
+
+        let image = disk.dos_order_image().unwrap();
+        assert_eq!(image.len(), 35 * 13 * 256);
+        assert_eq!(image[13 * 256 + 12 * 256], 12);
+        assert_eq!(disk.raw_geometry().unwrap().sectors_per_track[0], 13);
+    }
+
+    /// Build a nibblized sector with an address field and data field
+    fn nibble_sector(volume: u8, track: u8, sector: u8, data: &[u8]) -> Vec<u8> {
+        let mut nibbles = vec![0xFF, 0xFF];
+        nibbles.extend(encode_address_field(volume, track, sector));
+        nibbles.extend_from_slice(&[0xFF, 0xFF]);
+        nibbles.extend(encode_data_field(data));
+
+        nibbles
+    }
+
+    /// Test decoding a disk with two volumes, with and without
+    /// selecting a volume
+    #[test]
+    fn parse_nib_disk_volumes_work() {
+        let mut data = nibble_sector(1, 0, 0, &[1; 256]);
+        data.extend(nibble_sector(2, 0, 0, &[2; 256]));
+        data.extend(nibble_sector(2, 0, 1, &[3; 256]));
+        data.extend_from_slice(&[0xFF; 16]);
+
+        let config = Options::default();
+        let disk = collect_warnings(|| parse_nib_disk(&config)(&data)).unwrap();
+        assert_eq!(disk.value.1.volume_numbers(), vec![1, 2]);
+        assert_eq!(disk.warnings.len(), 1);
+        assert!(disk.warnings[0].message.contains("2 volumes (1, 2)"));
+        // The flat image keeps the sector from the lowest volume
+        let image = disk.value.1.dos_order_image().unwrap();
+        assert_eq!(image[..256], [1; 256]);
+        assert_eq!(image[7 * 256..8 * 256], [3; 256]);
+
+        let config = Options::default().with_override("volume", 2).unwrap();
+        let disk = collect_warnings(|| parse_nib_disk(&config)(&data)).unwrap();
+        assert!(disk.is_clean());
+        let volume = &disk.value.1.volumes[&2];
+        assert_eq!(volume.tracks[&0].sectors.len(), 2);
+        // Physical sector one holds DOS 3.3 sector seven
+        assert_eq!(volume.tracks[&0].sectors[&7].data, vec![3; 256]);
+        assert_eq!(disk.value.1.volume_numbers(), vec![2]);
+
+        let config = Options::default().with_override("volume", 3).unwrap();
+        let disk = collect_warnings(|| parse_nib_disk(&config)(&data)).unwrap();
+        assert!(disk.value.1.volumes.is_empty());
+        assert_eq!(
+            disk.warnings[0].message,
+            "volume 3 not found, the disk has volumes 1, 2"
+        );
+    }
+
+    /// Test recovering a bit-slipped sector and reporting sectors that
+    /// can't be decoded
+    #[test]
+    fn parse_nib_disk_recovery_works() {
+        let good = nibble_sector(254, 0, 0, &[1; 256]);
+        // Insert three zero bits after the address field, so every
+        // byte of the data field is misaligned
+        let split = good.len() - 349;
+        let mut slipped = good[..split].to_vec();
+        let mut bits: Vec<u8> = vec![0; 3];
+        for byte in &good[split..] {
+            bits.extend((0..8).rev().map(|n| (byte >> n) & 0x01));
+        }
+        bits.resize(bits.len().next_multiple_of(8), 1);
+        slipped.extend(
+            bits.chunks(8)
+                .map(|chunk| chunk.iter().fold(0, |byte, bit| (byte << 1) | bit)),
+        );
+        assert_eq!(latch_nibbles(&slipped[split..], 0)[..349], good[split..]);
+
+        let mut data = slipped.clone();
+        let mut damaged = nibble_sector(254, 0, 1, &[2; 256]);
+        let n = damaged.len() - 100;
+        damaged[n] = 0xAA;
+        data.extend(damaged);
+        data.extend(nibble_sector(254, 0, 2, &[3; 256])[..18].iter());
+        data.extend_from_slice(&[0xFF; 400]);
+        data.extend(nibble_sector(254, 0, 3, &[4; 256]));
+        data.extend_from_slice(&[0xFF; 16]);
+
+        let config = Options::default();
+        let disk = collect_warnings(|| parse_nib_disk(&config)(&data)).unwrap();
+        let track = &disk.value.1.volumes[&254].tracks[&0];
+        assert_eq!(track.sectors[&0].data, vec![1; 256]);
+        assert_eq!(track.sectors[&6].data, vec![4; 256]);
+        let failed = &disk.value.1.failed_sectors;
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].sector, 1);
+        assert_eq!(failed[0].reason, "data field checksum mismatch");
+        assert_eq!(
+            failed[1].reason,
+            "no data field within 64 bytes of the address field"
+        );
+        assert_eq!(
+            disk.warnings[0].to_string(),
+            "couldn't decode sector: data field checksum mismatch \
+             at track 0 sector 1 of the Apple nibble image"
+        );
+
+        let config = Options::default()
+            .with_override("nibble-bit-shift-retry", false)
+            .unwrap();
+        let disk = collect_warnings(|| parse_nib_disk(&config)(&slipped)).unwrap();
+        assert!(disk.value.1.volumes.is_empty());
+        assert_eq!(disk.value.1.failed_sectors.len(), 1);
+    }
+
+    /// Test saving a nibble disk in DOS 3.3 and ProDOS order
+    #[test]
+    fn sector_order_works() {
+        for order in [
+            SectorOrder::Physical,
+            SectorOrder::Dos33,
+            SectorOrder::ProDOS,
+        ] {
+            for sector in 0..16 {
+                assert_eq!(order.physical(order.logical(sector)), sector);
+            }
+        }
+        assert_eq!(SectorOrder::Dos33.logical(1), 7);
+        assert_eq!(SectorOrder::ProDOS.logical(1), 8);
+        assert_eq!(SectorOrder::ProDOS.physical(1), 2);
+        assert_eq!(SectorOrder::Dos33.logical(16), 16);
+
+        let dos =
+            testgen::apple_dos_33(&[("HELLO", &testgen::apple_binary(0x0803, b"HI"))]).unwrap();
+        let nib = testgen::nib_from_dos_order(&dos);
+
+        let config = Options::default();
+        let (_, disk) = parse_nib_disk(&config)(&nib).unwrap();
+        let mut saved = Vec::new();
+        disk.save_to_writer(&config, None, &mut saved).unwrap();
+        assert_eq!(saved, dos);
+
+        let config = Options::default()
+            .with_override("nibble-sector-order", "ProDOS")
+            .unwrap();
+        let (_, disk) = parse_nib_disk(&config)(&nib).unwrap();
+        let mut saved = Vec::new();
+        disk.save_to_writer(&config, None, &mut saved).unwrap();
+        // ProDOS sector one is physical sector two, DOS 3.3 sector 14
+        assert_eq!(saved[0x100..0x200], dos[0xE00..0xF00]);
+        assert_eq!(saved[..0x100], dos[..0x100]);
+        assert_eq!(disk.dos_order_image().unwrap(), dos);
+        assert_eq!(disk.sector_image(SectorOrder::ProDOS).unwrap(), saved);
+    }
+
+    /// Test that missing sectors and tracks are filled in when saving
+    #[test]
+    fn save_missing_sectors_works() {
+        let dos: Vec<u8> = (0..143360).map(|i| (i / 256) as u8).collect();
+        // The first three tracks, without physical sector one of
+        // track one
+        let nib = testgen::nib_from_dos_order(&dos);
+        let mut nib = nib[..3 * testgen::NIB_TRACK_SIZE].to_vec();
+        let track_one = &mut nib[testgen::NIB_TRACK_SIZE..2 * testgen::NIB_TRACK_SIZE];
+        let sector_one = track_one
+            .windows(3)
+            .enumerate()
+            .filter(|(_, w)| *w == [0xD5, 0xAA, 0x96])
+            .nth(1)
+            .unwrap()
+            .0;
+        track_one[sector_one] = 0xFF;
+
+        let config = Options::default();
+        let (_, disk) = parse_nib_disk(&config)(&nib).unwrap();
+        let mut saved = Vec::new();
+        disk.save_to_writer(&config, None, &mut saved).unwrap();
+        assert_eq!(saved.len(), 143360);
+        // Physical sector one is DOS 3.3 sector seven
+        let missing = (16 + 7) * 256;
+        assert_eq!(saved[..missing], dos[..missing]);
+        assert!(saved[missing..missing + 256].iter().all(|b| *b == 0));
+        assert_eq!(saved[missing + 256..3 * 4096], dos[missing + 256..3 * 4096]);
+        assert!(saved[3 * 4096..].iter().all(|b| *b == 0));
+        assert_eq!(disk.raw_geometry().unwrap().total_size(), 143360);
+    }
+
+    /// Test find_and_parse_address_field with invalid checksum
+    #[test]
+    #[should_panic(expected = "Address field computed checksum not equal to disk checksum: 236 0")]
+    fn find_and_parse_address_field_panics_with_invalid_checksum() {
+        // volume: 254, track: 23, sector: 5
+        let address_field_data: [u8; 14] = [
+            0xD5, 0xAA, 0x96, 0xFF, 0xFE, 0xAB, 0xBF, 0xAA, 0xAF, 0x00, 0x00, 0xDE, 0xAA, 0xEB,
+        ];
+
+        let config = Options::default();
+        let address_field_result = find_and_parse_address_field(&config)(&address_field_data);
+
+        match address_field_result {
+            Ok(_address_field) => {
+                panic!("Should fail with checksum error");
+            }
+            Err(_e) => {
+                panic!("Should fail with checksum error");
+            }
+        }
+    }
+
+    // Test address field prologue parsing and identification
+
+    /// Test parsing a simple address field for a DOS 3.3 disk
+    #[test]
+    fn parse_prologue_dos_33_works() {
+        let data = [0xD5, 0xAA, 0x96];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(r) => {
+                assert_eq!(r.1, &[0xD5, 0xAA, 0x96]);
+            }
+            Err(_) => {
+                panic!("Shouldn't fail parsing data");
+            }
+        }
+    }
+
+    /// Test parsing a simple address field for a DOS 3.3 disk
+    /// Where the first header is several bytes in the data
+    #[test]
+    fn parse_prologue_dos_33_skip_works() {
+        let data = [0x00, 0x00, 0xD5, 0xAA, 0x96];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(r) => {
+                assert_eq!(r.1, &[0xD5, 0xAA, 0x96]);
+            }
+            Err(_) => {
+                panic!("Shouldn't fail parsing data");
+            }
+        }
+    }
+
+    /// Test parsing a simple address field for a DOS 3.2 disk
+    #[test]
+    fn parse_prologue_dos_32_works() {
+        let data = [0xD5, 0xAA, 0xB5];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(r) => {
+                assert_eq!(r.1, &[0xD5, 0xAA, 0xB5]);
+            }
+            Err(_) => {
+                panic!("Shouldn't fail parsing data");
+            }
+        }
+    }
+
+    /// Test parsing a prologe on data without one
+    #[test]
+    fn parse_no_prologue_fails() {
+        let data = [0x00, 0x00, 0x00];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(_) => {
+                panic!("Should fail parsing data");
+            }
+            Err(e) => match e {
+                nom::Err::Incomplete(nom::Needed::Unknown) => {
+                    assert_eq!("Parsing requires more data", e.to_string());
+                }
+                _ => {
+                    panic!("Wrong parsing error");
+                }
+            },
+        }
+    }
+
+    /// Test parsing a prologue on data that matches the first two
+    /// prologue bytes but not the last.
+    #[test]
+    fn parse_incomplete_prologue_fails() {
+        let data = [0xD5, 0xAA, 0x00];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(_) => {
+                panic!("Should fail parsing data");
+            }
+            Err(e) => match e {
+                nom::Err::Incomplete(nom::Needed::Unknown) => {
+                    assert_eq!("Parsing requires more data", e.to_string());
+                }
+                _ => {
+                    panic!("Wrong parsing error");
+                }
+            },
+        }
+    }
+
+    /// Test parsing a prologue on data that matches the first two
+    /// prologue bytes with no data left fails.
+    #[test]
+    fn parse_incomplete_prologue_two_bytes_fails() {
+        let data = [0xD5, 0xAA];
+
+        let result = parse_prologue(&data);
+
+        match result {
+            Ok(_) => {
+                panic!("Should fail parsing data");
+            }
+            Err(e) => match e {
+                nom::Err::Incomplete(nom::Needed::Unknown) => {
+                    assert_eq!("Parsing requires more data", e.to_string());
+                }
+                _ => {
+                    panic!("Wrong parsing error");
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,1319 @@
+//! ProDOS file storage
+//!
+//! ProDOS volumes are divided into 512-byte blocks.  A file's data is
+//! found from its key block, and how the key block is interpreted
+//! depends on the storage type:
+//!
+//!   Seedling (1): the key block is the only data block
+//!   Sapling (2): the key block is an index of up to 256 data blocks
+//!   Tree (3): the key block is a master index of up to 128 index blocks
+//!   Extended (5): the key block holds a mini-entry for each of the
+//!     data fork and resource fork, plus the Finder information, used
+//!     by GS/OS for files created on the Apple IIGS
+//!
+//! Index blocks store the low bytes of the block numbers in the first
+//! half of the block and the high bytes in the second half.  A block
+//! number of zero is a sparse block that reads as zeros.
+//!
+//! The volume directory starts at block two, its first entry is the
+//! volume header with the name, the size of the volume and where the
+//! bitmap of free blocks is.  Directories are chains of blocks of 39
+//! byte entries, subdirectories are files with their own header entry.
+//! 5.25" disks are stored as .po images in block order, or as .dsk
+//! images in DOS 3.3 sector order with the two halves of each block in
+//! different sectors.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, warn};
+use nom::bytes::complete::take;
+use nom::number::complete::{le_u16, le_u24, le_u8};
+use nom::IResult;
+
+use crate::disk_format::apple::disk::SectorView;
+use crate::disk_format::apple::nibble::SectorOrder;
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::geometry::{Block, Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::{check_chain_length, limits};
+use crate::disk_format::logical::{LogicalSector, LogicalTrack, RawExporter};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::{IO, PARSE};
+use crate::serialize::Serializer;
+
+/// The size of a ProDOS block
+pub const BLOCK_SIZE: usize = 512;
+
+/// Finder information, FInfo or FXInfo
+pub type FinderInfo = [u8; 16];
+
+/// How a file's blocks are organized
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StorageType {
+    /// A deleted entry
+    Deleted,
+    /// A file of one block
+    Seedling,
+    /// A file of up to 256 blocks, with one index block
+    Sapling,
+    /// A file of up to 32768 blocks, with a master index block
+    Tree,
+    /// A Pascal area on a ProDOS volume
+    PascalArea,
+    /// A file with a data fork and a resource fork
+    Extended,
+    /// A subdirectory file
+    Subdirectory,
+    /// The header of a subdirectory
+    SubdirectoryHeader,
+    /// The header of the volume directory
+    VolumeDirectoryHeader,
+    /// An unknown storage type
+    Unknown(u8),
+}
+
+impl From<u8> for StorageType {
+    fn from(storage_type: u8) -> StorageType {
+        match storage_type {
+            0x0 => StorageType::Deleted,
+            0x1 => StorageType::Seedling,
+            0x2 => StorageType::Sapling,
+            0x3 => StorageType::Tree,
+            0x4 => StorageType::PascalArea,
+            0x5 => StorageType::Extended,
+            0xD => StorageType::Subdirectory,
+            0xE => StorageType::SubdirectoryHeader,
+            0xF => StorageType::VolumeDirectoryHeader,
+            other => StorageType::Unknown(other),
+        }
+    }
+}
+
+impl Display for StorageType {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            StorageType::Deleted => write!(f, "Deleted"),
+            StorageType::Seedling => write!(f, "Seedling"),
+            StorageType::Sapling => write!(f, "Sapling"),
+            StorageType::Tree => write!(f, "Tree"),
+            StorageType::PascalArea => write!(f, "Pascal Area"),
+            StorageType::Extended => write!(f, "Extended"),
+            StorageType::Subdirectory => write!(f, "Subdirectory"),
+            StorageType::SubdirectoryHeader => write!(f, "Subdirectory Header"),
+            StorageType::VolumeDirectoryHeader => write!(f, "Volume Directory Header"),
+            StorageType::Unknown(t) => write!(f, "Unknown ({:#X})", t),
+        }
+    }
+}
+
+/// The storage of one fork of a file
+/// Used for the mini-entries in an extended file's key block, and
+/// built from directory entries for ordinary files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ForkEntry {
+    /// How the fork's blocks are organized
+    pub storage_type: StorageType,
+    /// The key block of the fork
+    pub key_block: u16,
+    /// The number of blocks used by the fork, including index blocks
+    pub blocks_used: u16,
+    /// The length of the fork in bytes
+    pub eof: u32,
+}
+
+impl Display for ForkEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "storage type: {}, key block: {}, blocks used: {}, eof: {}",
+            self.storage_type, self.key_block, self.blocks_used, self.eof
+        )
+    }
+}
+
+/// Parse a fork mini-entry from an extended file's key block
+pub fn fork_entry_parser(i: &[u8]) -> IResult<&[u8], ForkEntry> {
+    let (i, storage_type) = le_u8(i)?;
+    let (i, key_block) = le_u16(i)?;
+    let (i, blocks_used) = le_u16(i)?;
+    let (i, eof) = le_u24(i)?;
+
+    Ok((
+        i,
+        ForkEntry {
+            storage_type: StorageType::from(storage_type & 0x0F),
+            key_block,
+            blocks_used,
+            eof,
+        },
+    ))
+}
+
+/// The key block of an extended file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExtendedKeyBlock {
+    /// The data fork
+    pub data_fork: ForkEntry,
+    /// The resource fork
+    pub resource_fork: ForkEntry,
+    /// The Finder information (FInfo), if present
+    pub finder_info: Option<FinderInfo>,
+    /// The extended Finder information (FXInfo), if present
+    pub extended_finder_info: Option<FinderInfo>,
+}
+
+/// Parse the Finder information entries after the data fork mini-entry
+/// Each entry is a size byte of 18, a type byte of 1 (FInfo) or 2
+/// (FXInfo) and 16 bytes of information.
+fn finder_info_parser(i: &[u8]) -> IResult<&[u8], (Option<FinderInfo>, Option<FinderInfo>)> {
+    let mut finder_info = None;
+    let mut extended_finder_info = None;
+    let mut i = i;
+
+    for _ in 0..2 {
+        let (rest, size) = le_u8(i)?;
+        let (rest, entry_type) = le_u8(rest)?;
+        let (rest, info) = take(16_usize)(rest)?;
+        if size != 18 {
+            break;
+        }
+        let mut data: FinderInfo = [0; 16];
+        data.copy_from_slice(info);
+        match entry_type {
+            1 => finder_info = Some(data),
+            2 => extended_finder_info = Some(data),
+            _ => (),
+        }
+        i = rest;
+    }
+
+    Ok((i, (finder_info, extended_finder_info)))
+}
+
+/// Parse the key block of an extended file
+pub fn extended_key_block_parser(i: &[u8]) -> IResult<&[u8], ExtendedKeyBlock> {
+    let block = i;
+    let (i, data_fork) = fork_entry_parser(i)?;
+    let (_i, (finder_info, extended_finder_info)) = finder_info_parser(i)?;
+    let (i, _) = take(0x100_usize)(block)?;
+    let (i, resource_fork) = fork_entry_parser(i)?;
+    let (i, _) = take(BLOCK_SIZE - 0x100 - 8)(i)?;
+
+    Ok((
+        i,
+        ExtendedKeyBlock {
+            data_fork,
+            resource_fork,
+            finder_info,
+            extended_finder_info,
+        },
+    ))
+}
+
+/// A file with both forks read from a volume
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExtendedFile {
+    /// The data fork
+    pub data: Vec<u8>,
+    /// The resource fork
+    pub resource: Vec<u8>,
+    /// The Finder information (FInfo), if present
+    pub finder_info: Option<FinderInfo>,
+    /// The extended Finder information (FXInfo), if present
+    pub extended_finder_info: Option<FinderInfo>,
+}
+
+/// Return a block from a volume
+pub fn block(volume: &[u8], block: u16) -> std::result::Result<&[u8], Error> {
+    let start = usize::from(block) * BLOCK_SIZE;
+    volume.get(start..start + BLOCK_SIZE).ok_or_else(|| {
+        Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+            "Block {} is past the end of the volume",
+            block
+        ))))
+    })
+}
+
+/// Return the block numbers in an index block
+fn index_entries(index: &[u8]) -> Vec<u16> {
+    (0..256)
+        .map(|n| u16::from_le_bytes([index[n], index[n + 256]]))
+        .collect()
+}
+
+/// Append a data block to a fork, reading sparse blocks as zeros
+fn append_block(volume: &[u8], data: &mut Vec<u8>, number: u16) -> std::result::Result<(), Error> {
+    if number == 0 {
+        data.extend_from_slice(&[0; BLOCK_SIZE]);
+    } else {
+        data.extend_from_slice(block(volume, number)?);
+    }
+    Ok(())
+}
+
+/// Read a seedling, sapling or tree fork from a volume
+/// The data is truncated to the fork's eof.
+pub fn read_fork(volume: &[u8], fork: &ForkEntry) -> std::result::Result<Vec<u8>, Error> {
+    let eof = fork.eof as usize;
+    let max = limits().max_file_size;
+    if eof > max {
+        return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!("Fork length {} exceeds the limit of {}", eof, max),
+        ))));
+    }
+    let blocks_needed = eof.div_ceil(BLOCK_SIZE);
+    let mut data: Vec<u8> = Vec::with_capacity(blocks_needed * BLOCK_SIZE);
+
+    match fork.storage_type {
+        StorageType::Deleted => (),
+        StorageType::Seedling => append_block(volume, &mut data, fork.key_block)?,
+        StorageType::Sapling => {
+            let index = block(volume, fork.key_block)?;
+            for number in index_entries(index).into_iter().take(blocks_needed) {
+                append_block(volume, &mut data, number)?;
+            }
+        }
+        StorageType::Tree => {
+            let master = block(volume, fork.key_block)?;
+            'master: for index_number in index_entries(master).into_iter().take(128) {
+                // A sparse index block is 256 sparse data blocks
+                let numbers = if index_number == 0 {
+                    vec![0; 256]
+                } else {
+                    index_entries(block(volume, index_number)?)
+                };
+                for number in numbers {
+                    if data.len() >= blocks_needed * BLOCK_SIZE {
+                        break 'master;
+                    }
+                    append_block(volume, &mut data, number)?;
+                }
+            }
+        }
+        other => {
+            return Err(Error::new(ErrorKind::Unimplemented(format!(
+                "Reading {} storage",
+                other
+            ))))
+        }
+    }
+    data.resize(eof, 0);
+
+    Ok(data)
+}
+
+/// Read both forks and the Finder information of an extended file,
+/// given its key block
+pub fn read_extended_file(
+    volume: &[u8],
+    key_block: u16,
+) -> std::result::Result<ExtendedFile, Error> {
+    let (_i, key) = extended_key_block_parser(block(volume, key_block)?)?;
+
+    Ok(ExtendedFile {
+        data: read_fork(volume, &key.data_fork)?,
+        resource: read_fork(volume, &key.resource_fork)?,
+        finder_info: key.finder_info,
+        extended_finder_info: key.extended_finder_info,
+    })
+}
+
+/// Return the data blocks and index blocks of a seedling, sapling or
+/// tree fork
+/// Sparse blocks aren't included in the data blocks.
+pub fn fork_blocks(
+    volume: &[u8],
+    fork: &ForkEntry,
+) -> std::result::Result<(Vec<u16>, Vec<u16>), Error> {
+    let blocks_needed = (fork.eof as usize).div_ceil(BLOCK_SIZE);
+    let mut data_blocks = Vec::new();
+    let mut index_blocks = Vec::new();
+
+    match fork.storage_type {
+        StorageType::Deleted => (),
+        StorageType::Seedling => data_blocks.push(fork.key_block),
+        StorageType::Sapling => {
+            index_blocks.push(fork.key_block);
+            let index = block(volume, fork.key_block)?;
+            data_blocks.extend(index_entries(index).into_iter().take(blocks_needed));
+        }
+        StorageType::Tree => {
+            index_blocks.push(fork.key_block);
+            let master = block(volume, fork.key_block)?;
+            let index_count = blocks_needed.div_ceil(256).min(128);
+            for index_number in index_entries(master).into_iter().take(index_count) {
+                if index_number != 0 {
+                    index_blocks.push(index_number);
+                    data_blocks.extend(index_entries(block(volume, index_number)?));
+                } else {
+                    data_blocks.extend([0; 256]);
+                }
+            }
+            data_blocks.truncate(blocks_needed);
+        }
+        other => {
+            return Err(Error::new(ErrorKind::Unimplemented(format!(
+                "Reading {} storage",
+                other
+            ))))
+        }
+    }
+    data_blocks.retain(|number| *number != 0);
+
+    Ok((data_blocks, index_blocks))
+}
+
+/// The block holding the key block of the volume directory
+pub const VOLUME_DIRECTORY_BLOCK: u16 = 2;
+
+/// The length of each directory entry on ProDOS 1.x and 2.x volumes
+pub const ENTRY_LENGTH: u8 = 0x27;
+
+/// The number of entries in each directory block
+pub const ENTRIES_PER_BLOCK: u8 = 0x0D;
+
+/// The offset of the first entry in a directory block, after the
+/// pointers to the previous and next blocks
+pub const DIRECTORY_ENTRIES_OFFSET: usize = 4;
+
+/// The deepest subdirectory that's followed, which also stops
+/// directories that contain themselves
+const MAX_DIRECTORY_DEPTH: usize = 16;
+
+/// The size of a sector in a 5.25" disk image
+const SECTOR_SIZE: usize = 256;
+
+/// The number of sectors on each track of a 5.25" disk image
+const SECTORS_PER_TRACK: usize = 16;
+
+/// Parse a name of up to fifteen characters, with its length in the
+/// low nibble of the storage type byte
+/// Returns the storage type and the name.
+fn storage_and_name_parser(i: &[u8]) -> IResult<&[u8], (StorageType, String)> {
+    let (i, storage_and_length) = le_u8(i)?;
+    let (i, name) = take(15_usize)(i)?;
+    let length = usize::from(storage_and_length & 0x0F);
+    Ok((
+        i,
+        (
+            StorageType::from(storage_and_length >> 4),
+            String::from_utf8_lossy(&name[..length]).into_owned(),
+        ),
+    ))
+}
+
+/// Parse a date and time pair
+fn date_time_parser(i: &[u8]) -> IResult<&[u8], (u16, u16)> {
+    let (i, date) = le_u16(i)?;
+    let (i, time) = le_u16(i)?;
+    Ok((i, (date, time)))
+}
+
+/// The header of the volume directory, the first entry in its key
+/// block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VolumeDirectoryHeader {
+    /// The volume name
+    pub name: String,
+    /// The date and time the volume was created
+    pub created: (u16, u16),
+    /// The version of ProDOS that created the volume
+    pub version: u8,
+    /// The minimum version of ProDOS that can access the volume
+    pub min_version: u8,
+    /// The access bits
+    pub access: u8,
+    /// The length of each directory entry
+    pub entry_length: u8,
+    /// The number of entries in each directory block
+    pub entries_per_block: u8,
+    /// The number of active entries in the volume directory
+    pub file_count: u16,
+    /// The first block of the volume bitmap
+    pub bit_map_pointer: u16,
+    /// The number of blocks on the volume
+    pub total_blocks: u16,
+}
+
+impl SanityCheck for VolumeDirectoryHeader {
+    fn check(&self) -> bool {
+        if self.name.is_empty()
+            || !self.name.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.')
+        {
+            debug!(target: PARSE, "Invalid ProDOS volume name: {}", self.name);
+            return false;
+        }
+        if self.entry_length != ENTRY_LENGTH || self.entries_per_block != ENTRIES_PER_BLOCK {
+            debug!(
+                target: PARSE,
+                "Unsupported directory entry layout: {} entries of {} bytes",
+                self.entries_per_block,
+                self.entry_length
+            );
+            return false;
+        }
+        if self.bit_map_pointer <= VOLUME_DIRECTORY_BLOCK
+            || self.bit_map_pointer >= self.total_blocks
+        {
+            debug!(
+                target: PARSE,
+                "Volume bitmap at block {} isn't on a {} block volume",
+                self.bit_map_pointer,
+                self.total_blocks
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Serialize the storage type and name byte and the padded name
+fn storage_and_name_bytes(storage_type: u8, name: &str) -> Vec<u8> {
+    let mut bytes = vec![(storage_type << 4) | (name.len().min(15) as u8)];
+    let mut padded = [0_u8; 15];
+    for (byte, c) in padded.iter_mut().zip(name.bytes()) {
+        *byte = c;
+    }
+    bytes.extend_from_slice(&padded);
+    bytes
+}
+
+impl Serializer<'_> for VolumeDirectoryHeader {
+    fn as_vec(&self) -> std::result::Result<Vec<u8>, Error> {
+        let mut bytes = storage_and_name_bytes(0xF, &self.name);
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&self.created.0.to_le_bytes());
+        bytes.extend_from_slice(&self.created.1.to_le_bytes());
+        bytes.extend_from_slice(&[
+            self.version,
+            self.min_version,
+            self.access,
+            self.entry_length,
+            self.entries_per_block,
+        ]);
+        bytes.extend_from_slice(&self.file_count.to_le_bytes());
+        bytes.extend_from_slice(&self.bit_map_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.total_blocks.to_le_bytes());
+        Ok(bytes)
+    }
+}
+
+/// Parse the volume directory header
+pub fn volume_directory_header_parser(i: &[u8]) -> IResult<&[u8], VolumeDirectoryHeader> {
+    let (i, (storage_type, name)) = storage_and_name_parser(i)?;
+    if storage_type != StorageType::VolumeDirectoryHeader {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    let (i, _reserved) = take(8_usize)(i)?;
+    let (i, created) = date_time_parser(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, min_version) = le_u8(i)?;
+    let (i, access) = le_u8(i)?;
+    let (i, entry_length) = le_u8(i)?;
+    let (i, entries_per_block) = le_u8(i)?;
+    let (i, file_count) = le_u16(i)?;
+    let (i, bit_map_pointer) = le_u16(i)?;
+    let (i, total_blocks) = le_u16(i)?;
+
+    Ok((
+        i,
+        VolumeDirectoryHeader {
+            name,
+            created,
+            version,
+            min_version,
+            access,
+            entry_length,
+            entries_per_block,
+            file_count,
+            bit_map_pointer,
+            total_blocks,
+        },
+    ))
+}
+
+/// A file entry in a directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileEntry {
+    /// How the file's blocks are organized
+    pub storage_type: StorageType,
+    /// The file name
+    pub name: String,
+    /// The ProDOS file type
+    pub file_type: u8,
+    /// The key block of the file
+    pub key_pointer: u16,
+    /// The number of blocks used by the file, including index blocks
+    pub blocks_used: u16,
+    /// The length of the file in bytes
+    pub eof: u32,
+    /// The date and time the file was created
+    pub created: (u16, u16),
+    /// The version of ProDOS that created the file
+    pub version: u8,
+    /// The minimum version of ProDOS that can access the file
+    pub min_version: u8,
+    /// The access bits
+    pub access: u8,
+    /// The auxiliary type, e.g. the load address of a binary file
+    pub aux_type: u16,
+    /// The date and time the file was last modified
+    pub modified: (u16, u16),
+    /// The key block of the directory holding the entry
+    pub header_pointer: u16,
+}
+
+impl FileEntry {
+    /// Return the storage of the file's data as a fork entry
+    pub fn fork(&self) -> ForkEntry {
+        ForkEntry {
+            storage_type: self.storage_type,
+            key_block: self.key_pointer,
+            blocks_used: self.blocks_used,
+            eof: self.eof,
+        }
+    }
+
+    /// Return true if the entry is a subdirectory
+    pub fn is_directory(&self) -> bool {
+        self.storage_type == StorageType::Subdirectory
+    }
+
+    /// The file type as the three letter name ProDOS lists it with,
+    /// or a hex number for types without one
+    pub fn file_type_name(&self) -> String {
+        let name = match self.file_type {
+            0x00 => "NON",
+            0x01 => "BAD",
+            0x04 => "TXT",
+            0x06 => "BIN",
+            0x0F => "DIR",
+            0x19 => "ADB",
+            0x1A => "AWP",
+            0x1B => "ASP",
+            0xB3 => "S16",
+            0xEF => "PAS",
+            0xF0 => "CMD",
+            0xFA => "INT",
+            0xFB => "IVR",
+            0xFC => "BAS",
+            0xFD => "VAR",
+            0xFE => "REL",
+            0xFF => "SYS",
+            other => return format!("${:02X}", other),
+        };
+        String::from(name)
+    }
+}
+
+/// Format an entry as a line of a catalog listing: the name, type,
+/// blocks used, modification date and length
+impl Display for FileEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let modified = match self.modified.0 {
+            0 => String::from("<NO DATE>"),
+            date => format!(
+                "{:02}-{:02}-{:02}",
+                date >> 9,
+                (date >> 5) & 0x0F,
+                date & 0x1F
+            ),
+        };
+        write!(
+            f,
+            "{:<15} {:<4} {:>6} {:<9} {:>8}",
+            self.name,
+            self.file_type_name(),
+            self.blocks_used,
+            modified,
+            self.eof
+        )
+    }
+}
+
+impl Serializer<'_> for FileEntry {
+    fn as_vec(&self) -> std::result::Result<Vec<u8>, Error> {
+        let storage_type = match self.storage_type {
+            StorageType::Deleted => 0x0,
+            StorageType::Seedling => 0x1,
+            StorageType::Sapling => 0x2,
+            StorageType::Tree => 0x3,
+            StorageType::PascalArea => 0x4,
+            StorageType::Extended => 0x5,
+            StorageType::Subdirectory => 0xD,
+            StorageType::SubdirectoryHeader => 0xE,
+            StorageType::VolumeDirectoryHeader => 0xF,
+            StorageType::Unknown(other) => other & 0x0F,
+        };
+        let mut bytes = storage_and_name_bytes(storage_type, &self.name);
+        bytes.push(self.file_type);
+        bytes.extend_from_slice(&self.key_pointer.to_le_bytes());
+        bytes.extend_from_slice(&self.blocks_used.to_le_bytes());
+        bytes.extend_from_slice(&self.eof.to_le_bytes()[..3]);
+        bytes.extend_from_slice(&self.created.0.to_le_bytes());
+        bytes.extend_from_slice(&self.created.1.to_le_bytes());
+        bytes.extend_from_slice(&[self.version, self.min_version, self.access]);
+        bytes.extend_from_slice(&self.aux_type.to_le_bytes());
+        bytes.extend_from_slice(&self.modified.0.to_le_bytes());
+        bytes.extend_from_slice(&self.modified.1.to_le_bytes());
+        bytes.extend_from_slice(&self.header_pointer.to_le_bytes());
+        Ok(bytes)
+    }
+}
+
+/// Parse a file entry
+pub fn file_entry_parser(i: &[u8]) -> IResult<&[u8], FileEntry> {
+    let (i, (storage_type, name)) = storage_and_name_parser(i)?;
+    let (i, file_type) = le_u8(i)?;
+    let (i, key_pointer) = le_u16(i)?;
+    let (i, blocks_used) = le_u16(i)?;
+    let (i, eof) = le_u24(i)?;
+    let (i, created) = date_time_parser(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, min_version) = le_u8(i)?;
+    let (i, access) = le_u8(i)?;
+    let (i, aux_type) = le_u16(i)?;
+    let (i, modified) = date_time_parser(i)?;
+    let (i, header_pointer) = le_u16(i)?;
+
+    Ok((
+        i,
+        FileEntry {
+            storage_type,
+            name,
+            file_type,
+            key_pointer,
+            blocks_used,
+            eof,
+            created,
+            version,
+            min_version,
+            access,
+            aux_type,
+            modified,
+            header_pointer,
+        },
+    ))
+}
+
+/// Read the entries of a directory, following its blocks from the key
+/// block
+/// The header entry and deleted entries are left out.  Returns the
+/// entries and the blocks of the directory.
+pub fn read_directory(
+    volume: &[u8],
+    key_block: u16,
+) -> std::result::Result<(Vec<FileEntry>, Vec<u16>), Error> {
+    let mut entries = Vec::new();
+    let mut blocks: Vec<u16> = Vec::new();
+    let mut number = key_block;
+
+    while number != 0 {
+        if blocks.contains(&number) {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "The directory at block {} loops back to block {}",
+                    key_block, number
+                ),
+            ))));
+        }
+        blocks.push(number);
+        check_chain_length(blocks.len())?;
+
+        let data = block(volume, number)?;
+        let entry_data = &data[DIRECTORY_ENTRIES_OFFSET..];
+        for (index, entry) in entry_data
+            .chunks_exact(usize::from(ENTRY_LENGTH))
+            .take(usize::from(ENTRIES_PER_BLOCK))
+            .enumerate()
+        {
+            if number == key_block && index == 0 {
+                continue;
+            }
+            let (_, entry) = file_entry_parser(entry)?;
+            if entry.storage_type != StorageType::Deleted {
+                entries.push(entry);
+            }
+        }
+        number = u16::from_le_bytes([data[2], data[3]]);
+    }
+
+    Ok((entries, blocks))
+}
+
+/// Reorder a 5.25" sector image into ProDOS block order
+/// Images in DOS 3.3 order hold the two halves of each block in
+/// different sectors of the track.
+pub fn block_order_image(data: &[u8], order: SectorOrder) -> Vec<u8> {
+    reorder_sectors(
+        data,
+        |prodos_sector| image_sector(order, prodos_sector),
+        true,
+    )
+}
+
+/// Reorder a ProDOS block order volume into a 5.25" sector image in
+/// another order
+pub fn sector_order_image(volume: &[u8], order: SectorOrder) -> Vec<u8> {
+    reorder_sectors(
+        volume,
+        |prodos_sector| image_sector(order, prodos_sector),
+        false,
+    )
+}
+
+/// Return the sector of an image in an order that holds a ProDOS
+/// order sector
+fn image_sector(order: SectorOrder, prodos_sector: u8) -> u8 {
+    order.logical(SectorOrder::ProDOS.physical(prodos_sector))
+}
+
+/// Move the sectors of each track with a sector mapping, from the
+/// image to ProDOS order or back
+/// A partial track at the end is copied as it is.
+fn reorder_sectors(data: &[u8], map: impl Fn(u8) -> u8, to_blocks: bool) -> Vec<u8> {
+    let track_size = SECTORS_PER_TRACK * SECTOR_SIZE;
+    let mut reordered = data.to_vec();
+    for (track, chunk) in data.chunks_exact(track_size).enumerate() {
+        for prodos_sector in 0..SECTORS_PER_TRACK {
+            let sector = usize::from(map(prodos_sector as u8));
+            let (from, to) = if to_blocks {
+                (sector, prodos_sector)
+            } else {
+                (prodos_sector, sector)
+            };
+            let start = track * track_size + to * SECTOR_SIZE;
+            reordered[start..start + SECTOR_SIZE]
+                .copy_from_slice(&chunk[from * SECTOR_SIZE..(from + 1) * SECTOR_SIZE]);
+        }
+    }
+    reordered
+}
+
+/// Find the volume directory in a 5.25" sector image
+/// Both ProDOS and DOS 3.3 sector orders are tried.  Returns the
+/// order with a valid volume directory header, None if there isn't
+/// one.
+pub fn find_volume_directory(data: &[u8]) -> Option<(SectorOrder, VolumeDirectoryHeader)> {
+    [SectorOrder::ProDOS, SectorOrder::Dos33]
+        .into_iter()
+        .find_map(|order| {
+            let track_size = SECTORS_PER_TRACK * SECTOR_SIZE;
+            let first_track = block_order_image(data.get(..track_size)?, order);
+            let key_block = block(&first_track, VOLUME_DIRECTORY_BLOCK).ok()?;
+            // The key block of the volume directory has no previous
+            // block
+            if key_block[0..2] != [0, 0] {
+                return None;
+            }
+            let (_, header) =
+                volume_directory_header_parser(&key_block[DIRECTORY_ENTRIES_OFFSET..]).ok()?;
+            Some((order, header)).filter(|(_, header)| header.check())
+        })
+}
+
+/// A file or directory on a ProDOS volume, with its path from the
+/// volume directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProDOSFile {
+    /// The path of the file, directory names joined with '/'
+    pub path: String,
+    /// The directory entry of the file
+    pub entry: FileEntry,
+}
+
+/// An Apple ][ ProDOS disk
+pub struct ProDOSDisk<'a> {
+    /// The sectors of the image, in the image's sector order
+    pub tracks: SectorView<'a>,
+    /// The sector order of the image
+    pub order: SectorOrder,
+    /// The volume, in block order
+    pub volume: Vec<u8>,
+    /// The volume directory header
+    pub header: VolumeDirectoryHeader,
+    /// The blocks of every directory
+    pub directory_blocks: Vec<u16>,
+    /// Every file and directory, in directory order
+    pub files: Vec<ProDOSFile>,
+}
+
+impl<'a> ProDOSDisk<'a> {
+    /// Parse a ProDOS volume from a 5.25" sector image
+    /// Subdirectories that can't be read are skipped with a warning.
+    pub fn parse(data: &'a [u8]) -> std::result::Result<ProDOSDisk<'a>, Error> {
+        let (order, header) = find_volume_directory(data).ok_or_else(|| {
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(String::from(
+                "No ProDOS volume directory found",
+            ))))
+        })?;
+        let track_size = SECTORS_PER_TRACK * SECTOR_SIZE;
+        let tracks = SectorView::new(
+            data,
+            data.len() / track_size,
+            SECTORS_PER_TRACK,
+            SECTOR_SIZE,
+        );
+        let volume = block_order_image(tracks.data(), order);
+
+        let (entries, directory_blocks) = read_directory(&volume, VOLUME_DIRECTORY_BLOCK)?;
+        let mut disk = ProDOSDisk {
+            tracks,
+            order,
+            volume,
+            header,
+            directory_blocks,
+            files: Vec::new(),
+        };
+        let mut files = Vec::new();
+        let mut directory_blocks = Vec::new();
+        disk.walk(&entries, "", 0, &mut files, &mut directory_blocks);
+        disk.files = files;
+        disk.directory_blocks.extend(directory_blocks);
+
+        Ok(disk)
+    }
+
+    /// Add the entries of a directory and its subdirectories to a list
+    fn walk(
+        &self,
+        entries: &[FileEntry],
+        prefix: &str,
+        depth: usize,
+        files: &mut Vec<ProDOSFile>,
+        directory_blocks: &mut Vec<u16>,
+    ) {
+        for entry in entries {
+            let path = format!("{}{}", prefix, entry.name);
+            files.push(ProDOSFile {
+                path: path.clone(),
+                entry: entry.clone(),
+            });
+            if !entry.is_directory() {
+                continue;
+            }
+            if depth >= MAX_DIRECTORY_DEPTH {
+                warn!(target: PARSE, "Directory {} is nested too deeply, skipping it", path);
+                continue;
+            }
+            match read_directory(&self.volume, entry.key_pointer) {
+                Ok((children, blocks)) => {
+                    directory_blocks.extend(blocks);
+                    self.walk(
+                        &children,
+                        &format!("{}/", path),
+                        depth + 1,
+                        files,
+                        directory_blocks,
+                    );
+                }
+                Err(e) => warn!(target: PARSE, "Error reading directory {}: {}", path, e),
+            }
+        }
+    }
+
+    /// Read the data of a file
+    /// Extended files are read as their data fork.
+    pub fn read_file(&self, entry: &FileEntry) -> std::result::Result<Vec<u8>, Error> {
+        match entry.storage_type {
+            StorageType::Extended => Ok(read_extended_file(&self.volume, entry.key_pointer)?.data),
+            _ => read_fork(&self.volume, &entry.fork()),
+        }
+    }
+
+    /// Return the data blocks and index blocks of a file
+    /// Extended files have their key block counted as an index block.
+    pub fn file_blocks(
+        &self,
+        entry: &FileEntry,
+    ) -> std::result::Result<(Vec<u16>, Vec<u16>), Error> {
+        match entry.storage_type {
+            StorageType::Extended => {
+                let (_, key) = extended_key_block_parser(block(&self.volume, entry.key_pointer)?)?;
+                let (mut data_blocks, mut index_blocks) =
+                    fork_blocks(&self.volume, &key.data_fork)?;
+                let (resource_blocks, resource_index_blocks) =
+                    fork_blocks(&self.volume, &key.resource_fork)?;
+                data_blocks.extend(resource_blocks);
+                index_blocks.insert(0, entry.key_pointer);
+                index_blocks.extend(resource_index_blocks);
+                Ok((data_blocks, index_blocks))
+            }
+            _ => fork_blocks(&self.volume, &entry.fork()),
+        }
+    }
+
+    /// Return the image sectors holding a block
+    pub fn block_sectors(&self, number: Block) -> [SectorId; 2] {
+        let blocks_per_track = (SECTORS_PER_TRACK / 2) as u32;
+        let track = (number.get() / blocks_per_track) as u8;
+        let first = ((number.get() % blocks_per_track) * 2) as u8;
+        [first, first + 1]
+            .map(|prodos_sector| SectorId::new(track, 0, image_sector(self.order, prodos_sector)))
+    }
+
+    /// Return the image sectors holding a set of blocks, in block
+    /// order
+    fn sectors(&self, blocks: impl IntoIterator<Item = u16>) -> Vec<SectorId> {
+        blocks
+            .into_iter()
+            .flat_map(|number| self.block_sectors(Block::from(number)))
+            .collect()
+    }
+
+    /// Return the blocks marked free in the volume bitmap
+    /// Bit seven of the first byte is block zero, a set bit is free.
+    pub fn free_blocks(&self) -> BTreeSet<u16> {
+        let total = usize::from(self.header.total_blocks);
+        let bitmap_blocks = total.div_ceil(BLOCK_SIZE * 8);
+        let start = usize::from(self.header.bit_map_pointer) * BLOCK_SIZE;
+        let bitmap = self
+            .volume
+            .get(start..start + bitmap_blocks * BLOCK_SIZE)
+            .unwrap_or_default();
+        (0..total.min(bitmap.len() * 8))
+            .filter(|number| bitmap[number / 8] & (0x80 >> (number % 8)) != 0)
+            .map(|number| number as u16)
+            .collect()
+    }
+
+    /// Return the free sectors of the image
+    pub fn free_sectors(&self) -> BTreeSet<SectorId> {
+        self.sectors(self.free_blocks()).into_iter().collect()
+    }
+
+    /// Return the sectors used by ProDOS: the boot blocks, the
+    /// directory blocks, the volume bitmap and the index blocks of
+    /// every file
+    pub fn system_sectors(&self) -> BTreeSet<SectorId> {
+        let bitmap_blocks = usize::from(self.header.total_blocks).div_ceil(BLOCK_SIZE * 8) as u16;
+        let mut blocks: Vec<u16> = vec![0, 1];
+        blocks.extend(&self.directory_blocks);
+        blocks.extend(self.header.bit_map_pointer..self.header.bit_map_pointer + bitmap_blocks);
+        for file in self.files.iter().filter(|file| !file.entry.is_directory()) {
+            if let Ok((_, index_blocks)) = self.file_blocks(&file.entry) {
+                blocks.extend(index_blocks);
+            }
+        }
+        self.sectors(blocks).into_iter().collect()
+    }
+
+    /// Return the data sectors of each file
+    /// Files with sparse blocks have no known end, their data sectors
+    /// don't line up with their length.
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        let mut extents: Vec<FileExtent> = self
+            .files
+            .iter()
+            .filter(|file| !file.entry.is_directory())
+            .filter_map(|file| {
+                let (data_blocks, _) = self.file_blocks(&file.entry).ok()?;
+                let eof = file.entry.eof as usize;
+                let sparse = data_blocks.len() < eof.div_ceil(BLOCK_SIZE);
+                let extended = file.entry.storage_type == StorageType::Extended;
+                Some(FileExtent {
+                    name: file.path.clone(),
+                    sectors: self.sectors(data_blocks),
+                    end: Some((eof / SECTOR_SIZE, eof % SECTOR_SIZE))
+                        .filter(|_| !sparse && !extended),
+                })
+            })
+            .collect();
+        extents.sort_by(|a, b| a.name.cmp(&b.name));
+        extents
+    }
+}
+
+/// Format the disk as a catalog listing
+/// The files in a subdirectory are indented under it.
+impl Display for ProDOSDisk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "/{}", self.header.name)?;
+        for file in &self.files {
+            let depth = file.path.matches('/').count();
+            writeln!(f, "{}{}", "  ".repeat(depth), file.entry)?;
+        }
+        write!(
+            f,
+            "blocks free: {} of {}",
+            self.free_blocks().len(),
+            self.header.total_blocks
+        )
+    }
+}
+
+impl DiskImageSaver for ProDOSDisk<'_> {
+    /// Return the files on the volume with their paths, without
+    /// directories
+    /// Files that can't be read are skipped with a warning.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.files
+            .iter()
+            .filter(|file| !file.entry.is_directory())
+            .filter_map(|file| match self.read_file(&file.entry) {
+                Ok(data) => Some(DiskFile {
+                    name: file.path.clone(),
+                    file_type: file.entry.file_type_name(),
+                    raw_name: file.entry.name.as_bytes().to_vec(),
+                    data,
+                }),
+                Err(e) => {
+                    warn!(target: PARSE, "Error reading {}: {}", file.path, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Write a file from the volume, selected by its path
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        let selected_filename = selected_filename.ok_or_else(|| {
+            error!(target: IO, "Filename must be specified for saving ProDOS images");
+            Error::new(ErrorKind::Message(String::from(
+                "Filename must be specified for saving ProDOS images",
+            )))
+        })?;
+        let file = self
+            .files
+            .iter()
+            .find(|file| {
+                !file.entry.is_directory() && file.path.eq_ignore_ascii_case(selected_filename)
+            })
+            .ok_or_else(|| Error::new(ErrorKind::NotFound(String::from(selected_filename))))?;
+        writer.write_all(&self.read_file(&file.entry)?)?;
+        Ok(())
+    }
+}
+
+impl RawExporter for ProDOSDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        (0..self.tracks.track_count())
+            .map(|track_number| LogicalTrack {
+                track: track_number as u8,
+                head: 0,
+                sectors: self
+                    .tracks
+                    .track_sectors(track_number)
+                    .enumerate()
+                    .map(|(sector_number, data)| {
+                        LogicalSector::new(
+                            SectorId::new(track_number as u8, 0, sector_number as u8),
+                            data.to_vec(),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// The boot blocks, directory blocks and volume bitmap, and the index
+/// and data blocks of each file
+impl SourceMapper for ProDOSDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(base) = slice_offset(data, self.tracks.data()) else {
+            return map;
+        };
+        let geometry = Geometry::apple_dos_33(self.tracks.track_count() as u8);
+
+        map.add_sectors(
+            &geometry,
+            base,
+            &self.sectors(self.directory_blocks.iter().copied()),
+            RegionKind::Directory,
+            "directory",
+        );
+        let bitmap_blocks = usize::from(self.header.total_blocks).div_ceil(BLOCK_SIZE * 8) as u16;
+        map.add_sectors(
+            &geometry,
+            base,
+            &self.sectors(self.header.bit_map_pointer..self.header.bit_map_pointer + bitmap_blocks),
+            RegionKind::Directory,
+            "volume bitmap",
+        );
+        for file in self.files.iter().filter(|file| !file.entry.is_directory()) {
+            let Ok((data_blocks, index_blocks)) = self.file_blocks(&file.entry) else {
+                continue;
+            };
+            map.add_sectors(
+                &geometry,
+                base,
+                &self.sectors(index_blocks),
+                RegionKind::Directory,
+                &format!("index: {}", file.path),
+            );
+            map.add_sectors(
+                &geometry,
+                base,
+                &self.sectors(data_blocks),
+                RegionKind::File,
+                &file.path,
+            );
+        }
+
+        map
+    }
+}
+
+/// Convert a ProDOS date and time to seconds since January 1 2000,
+/// the epoch used by AppleSingle
+/// Two digit years below 40 are taken as 2000 to 2039, as ProDOS 2.x
+/// does.  Returns None for a zero or invalid date.
+pub fn prodos_timestamp(date: u16, time: u16) -> Option<i32> {
+    let year = i64::from(date >> 9);
+    let month = i64::from((date >> 5) & 0x0F);
+    let day = i64::from(date & 0x1F);
+    let hour = i64::from((time >> 8) & 0x1F);
+    let minute = i64::from(time & 0x3F);
+    if date == 0 || !(1..=12).contains(&month) || day == 0 || hour > 23 || minute > 59 {
+        return None;
+    }
+    let year = if year < 40 { 2000 + year } else { 1900 + year };
+
+    // Days since 2000-03-01, counting years from March so leap days
+    // fall at the end of the year
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let y = y - 2000;
+    let days = 365 * y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)
+        + (153 * m + 2) / 5
+        + day
+        - 1
+        // 2000-01-01 is 60 days before 2000-03-01
+        + 60;
+
+    i32::try_from(days * 86400 + hour * 3600 + minute * 60).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        prodos_timestamp, read_extended_file, sector_order_image, ProDOSDisk, StorageType,
+        BLOCK_SIZE,
+    };
+    use crate::disk_format::apple::nibble::SectorOrder;
+    use crate::disk_format::testgen::prodos;
+
+    /// Test reading an extended file with a seedling data fork and a
+    /// sapling resource fork with a sparse block
+    #[test]
+    fn read_extended_file_works() {
+        let mut volume = vec![0_u8; 16 * BLOCK_SIZE];
+
+        // Key block 2: data fork in block 3, 10 bytes long
+        let key = 2 * BLOCK_SIZE;
+        volume[key..key + 8].copy_from_slice(&[0x01, 3, 0, 1, 0, 10, 0, 0]);
+        volume[key + 8] = 18;
+        volume[key + 9] = 1;
+        volume[key + 10..key + 14].copy_from_slice(b"TEXT");
+        // Resource fork: sapling with index block 4, 600 bytes long,
+        // first block sparse, second in block 5
+        volume[key + 0x100..key + 0x108].copy_from_slice(&[0x02, 4, 0, 2, 0, 0x58, 0x02, 0]);
+        volume[4 * BLOCK_SIZE + 1] = 5;
+
+        volume[3 * BLOCK_SIZE..3 * BLOCK_SIZE + 10].copy_from_slice(b"HELLO DATA");
+        volume[5 * BLOCK_SIZE..5 * BLOCK_SIZE + 4].copy_from_slice(b"RSRC");
+
+        let file = read_extended_file(&volume, 2).unwrap();
+        assert_eq!(file.data, b"HELLO DATA");
+        assert_eq!(file.resource.len(), 600);
+        assert!(file.resource[..BLOCK_SIZE].iter().all(|b| *b == 0));
+        assert_eq!(&file.resource[BLOCK_SIZE..BLOCK_SIZE + 4], b"RSRC");
+        assert_eq!(&file.finder_info.unwrap()[0..4], b"TEXT");
+        assert_eq!(file.extended_finder_info, None);
+
+        assert!(read_extended_file(&volume, 100).is_err());
+        assert_eq!(StorageType::from(0x0F), StorageType::VolumeDirectoryHeader);
+    }
+
+    /// Test reading the catalog and files of a volume in both sector
+    /// orders
+    #[test]
+    fn prodos_disk_works() {
+        let long: Vec<u8> = (0..3000_usize).map(|i| (i % 251) as u8).collect();
+        let volume = prodos(
+            "GAMES",
+            &[
+                ("README", b"HELLO PRODOS"),
+                ("EMPTY", &[]),
+                ("UTIL/COPY", &long),
+            ],
+        )
+        .unwrap();
+        let dos_order = sector_order_image(&volume, SectorOrder::Dos33);
+        assert_ne!(dos_order, volume);
+
+        for (data, order) in [
+            (&volume, SectorOrder::ProDOS),
+            (&dos_order, SectorOrder::Dos33),
+        ] {
+            let disk = ProDOSDisk::parse(data).unwrap();
+            assert_eq!(disk.order, order);
+            assert_eq!(disk.header.name, "GAMES");
+            let paths: Vec<&str> = disk.files.iter().map(|file| file.path.as_str()).collect();
+            assert_eq!(paths, ["README", "EMPTY", "UTIL", "UTIL/COPY"]);
+            assert_eq!(disk.files[0].entry.storage_type, StorageType::Seedling);
+            assert_eq!(disk.files[3].entry.storage_type, StorageType::Sapling);
+            assert_eq!(
+                disk.read_file(&disk.files[0].entry).unwrap(),
+                b"HELLO PRODOS"
+            );
+            assert!(disk.read_file(&disk.files[1].entry).unwrap().is_empty());
+            assert_eq!(disk.read_file(&disk.files[3].entry).unwrap(), long);
+
+            // The boot blocks, four volume directory blocks, the bitmap,
+            // one UTIL directory block and the index block of COPY
+            assert_eq!(disk.system_sectors().len(), 9 * 2);
+            // Two seedling blocks, six data blocks of COPY and its index
+            // block, and the UTIL directory block
+            assert_eq!(disk.free_blocks().len(), 280 - 7 - 2 - 7 - 1);
+
+            let listing = disk.to_string();
+            assert!(listing.starts_with("/GAMES\n"));
+            assert!(listing.ends_with("blocks free: 263 of 280"));
+        }
+
+        let mut damaged = volume;
+        damaged[2 * BLOCK_SIZE + 4] = 0x00;
+        assert!(ProDOSDisk::parse(&damaged).is_err());
+    }
+
+    /// Test converting ProDOS dates
+    #[test]
+    fn prodos_timestamp_works() {
+        // January 1 2000, 00:00
+        assert_eq!(prodos_timestamp((1 << 5) | 1, 0), Some(0));
+        // March 1 2000, 01:02
+        assert_eq!(
+            prodos_timestamp((3 << 5) | 1, (1 << 8) | 2),
+            Some(60 * 86400 + 3600 + 120)
+        );
+        // December 31 1999
+        assert_eq!(
+            prodos_timestamp((99 << 9) | (12 << 5) | 31, 0),
+            Some(-86400)
+        );
+        assert_eq!(prodos_timestamp(0, 0), None);
+    }
+}
@@ -0,0 +1,174 @@
+//! Apple DOS 3.3 text files
+//!
+//! Text is stored with the high bit set and each line, or field, ends
+//! with a carriage return (0x8D).  Sequential text files end at the
+//! first zero byte.
+//!
+//! Random-access text files are divided into fixed-length records.
+//! The record length is chosen by the program when it opens the file
+//! and isn't stored on the disk.  Record n starts at byte n * length
+//! and holds one or more fields, followed by zeros.  Records that were
+//! never written are all zeros, and sectors without any written
+//! records are left out of the file as holes.
+use std::collections::BTreeSet;
+
+use crate::disk_format::charset::Charset;
+use crate::error::{Error, ErrorKind};
+
+/// The byte that ends each field, a carriage return with the high bit set
+pub const FIELD_TERMINATOR: u8 = 0x8D;
+
+/// The size of a DOS 3.3 sector
+const SECTOR_SIZE: usize = 256;
+
+/// Return the data of record n of a random-access text file, or None
+/// if the record is past the end of the file
+/// The last record may be short if the file doesn't fill it.
+pub fn read_record(data: &[u8], n: usize, length: usize) -> Option<&[u8]> {
+    if length == 0 {
+        return None;
+    }
+    let start = n.checked_mul(length)?;
+    let end = start.saturating_add(length).min(data.len());
+    data.get(start..end).filter(|record| !record.is_empty())
+}
+
+/// Return the number of records in a random-access text file
+pub fn record_count(data: &[u8], length: usize) -> usize {
+    match length {
+        0 => 0,
+        _ => data.len().div_ceil(length),
+    }
+}
+
+/// Split a record into its fields
+/// The record ends at the first zero byte, and the high bit of each
+/// character is cleared.  A record that was never written has no
+/// fields.
+pub fn record_fields(record: &[u8], charset: &Charset) -> Vec<String> {
+    let end = record.iter().position(|b| *b == 0).unwrap_or(record.len());
+    let mut fields: Vec<String> = record[..end]
+        .split(|b| *b == FIELD_TERMINATOR)
+        .map(|field| charset.apple_string(field))
+        .collect();
+    // The last field is followed by a terminator, which leaves an
+    // empty field at the end
+    if fields.last().is_some_and(|field| field.is_empty()) {
+        fields.pop();
+    }
+    fields
+}
+
+/// Return the fields of every record that has been written, with the
+/// record number
+pub fn records(
+    data: &[u8],
+    length: usize,
+    charset: &Charset,
+) -> Result<Vec<(usize, Vec<String>)>, Error> {
+    if length == 0 {
+        return Err(Error::new(ErrorKind::Message(String::from(
+            "The record length must be greater than zero",
+        ))));
+    }
+
+    Ok((0..record_count(data, length))
+        .filter_map(|n| {
+            let fields = record_fields(read_record(data, n, length)?, charset);
+            Some((n, fields)).filter(|(_, fields)| !fields.is_empty())
+        })
+        .collect())
+}
+
+/// Return the sectors of a random-access text file that hold a set of
+/// records, counting from the start of the file
+/// Only these sectors need space on the disk, the others are holes.
+pub fn record_sectors(length: usize, records: &[usize]) -> BTreeSet<usize> {
+    records
+        .iter()
+        .filter(|_| length != 0)
+        .flat_map(|n| {
+            let start = n * length;
+            (start / SECTOR_SIZE)..=((start + length - 1) / SECTOR_SIZE)
+        })
+        .collect()
+}
+
+/// Quote a CSV field if it contains a comma, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+/// Convert a random-access text file to CSV
+/// Each written record is a row, starting with the record number
+/// followed by the record's fields.
+pub fn records_to_csv(data: &[u8], length: usize, charset: &Charset) -> Result<String, Error> {
+    let mut csv = String::new();
+    for (n, fields) in records(data, length, charset)? {
+        csv.push_str(&n.to_string());
+        for field in fields {
+            csv.push(',');
+            csv.push_str(&csv_field(&field));
+        }
+        csv.push('\n');
+    }
+    Ok(csv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_record, record_count, record_sectors, records, records_to_csv};
+    use crate::disk_format::charset::Charset;
+
+    /// Build a random-access text file with 32 byte records
+    fn text_file() -> Vec<u8> {
+        let mut data = vec![0_u8; 32 * 4];
+        for (n, text) in [(0, "SMITH\rJOHN\r"), (2, "DOE, JANE\r\"JD\"\r")] {
+            let start = n * 32;
+            let bytes: Vec<u8> = text.bytes().map(|b| b | 0x80).collect();
+            data[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+        data
+    }
+
+    /// Test reading records from a random-access text file
+    #[test]
+    fn read_record_works() {
+        let data = text_file();
+        assert_eq!(record_count(&data, 32), 4);
+        assert_eq!(read_record(&data, 1, 32), Some(&[0_u8; 32][..]));
+        assert_eq!(read_record(&data, 3, 40).map(|r| r.len()), Some(8));
+        assert_eq!(read_record(&data, 4, 32), None);
+        assert_eq!(read_record(&data, 0, 0), None);
+
+        let written = records(&data, 32, &Charset::DEFAULT).unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(
+            written[0],
+            (0, vec![String::from("SMITH"), String::from("JOHN")])
+        );
+        assert!(records(&data, 0, &Charset::DEFAULT).is_err());
+    }
+
+    /// Test finding the sectors that hold records
+    #[test]
+    fn record_sectors_works() {
+        let sectors: Vec<usize> = record_sectors(100, &[0, 5, 500]).into_iter().collect();
+        // Record 5 spans the second and third sectors
+        assert_eq!(sectors, [0, 1, 2, 195]);
+        assert!(record_sectors(0, &[1]).is_empty());
+    }
+
+    /// Test converting a random-access text file to CSV
+    #[test]
+    fn records_to_csv_works() {
+        assert_eq!(
+            records_to_csv(&text_file(), 32, &Charset::DEFAULT).unwrap(),
+            "0,SMITH,JOHN\n2,\"DOE, JANE\",\"\"\"JD\"\"\"\n"
+        );
+    }
+}
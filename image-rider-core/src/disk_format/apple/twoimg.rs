@@ -0,0 +1,343 @@
+//! 2MG universal disk images
+//!
+//! A .2mg file wraps a DOS order, ProDOS order or nibble image in a 64
+//! byte header of little-endian fields, with an optional comment and
+//! data for the program that created it after the image:
+//!
+//! ```ignore
+//! 00: "2IMG"
+//! 04: the creator, e.g. "!nfc" or "CTKG"
+//! 08: the header size
+//! 0A: the version, 1
+//! 0C: the image format, 0 for DOS order, 1 for ProDOS order, 2 for nibbles
+//! 10: the flags: bit 31 locked, bit 8 volume number valid, and the
+//!     volume number in the low byte
+//! 14: the number of ProDOS blocks
+//! 18: the offset and length of the image data
+//! 20: the offset and length of the comment
+//! 28: the offset and length of the creator data
+//! ```
+//!
+//! The image data is parsed by the parser for its format, the same as
+//! a .dsk, .po or .nib file.
+//!
+//! Information from:\
+//! [2IMG](https://apple2.org.za/gswv/a2zine/Docs/DiskImage_2MG_Info.txt)
+use std::fmt::{Display, Formatter, Result};
+
+use log::debug;
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{le_u16, le_u32};
+use nom::IResult;
+
+use crate::disk_format::apple::disk::{AppleDiskGuess, Encoding, Format};
+use crate::disk_format::apple::nibble::recognize_prologue;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::log_target::PARSE;
+
+/// A disk image in a 2IMG wrapper
+pub const TWOIMG_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "2MG",
+    description: "Apple ][ DOS order, ProDOS order or nibble disk image in a 2IMG wrapper",
+    extensions: &["2mg", "2img"],
+    sizes: &[SizeRange::at_least(TWOIMG_HEADER_SIZE)],
+    references: &[Reference {
+        title: "2IMG",
+        url: Some("https://apple2.org.za/gswv/a2zine/Docs/DiskImage_2MG_Info.txt"),
+    }],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        write_protect: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature at the start of a 2MG image
+pub const TWOIMG_MAGIC: &[u8] = b"2IMG";
+
+/// The size of the 2MG header
+pub const TWOIMG_HEADER_SIZE: usize = 64;
+
+/// The flag set on locked images
+const FLAG_LOCKED: u32 = 0x8000_0000;
+
+/// The flag set when the low byte of the flags is the DOS volume
+const FLAG_VOLUME_VALID: u32 = 0x0000_0100;
+
+/// True if the data starts with the 2MG signature
+pub fn is_2mg(data: &[u8]) -> bool {
+    data.starts_with(TWOIMG_MAGIC)
+}
+
+/// The format of the image inside a 2MG file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TwoImgFormat {
+    /// A DOS 3.3 order sector image
+    DOSOrder,
+    /// A ProDOS order sector image
+    ProDOSOrder,
+    /// A nibble image
+    Nibble,
+}
+
+impl Display for TwoImgFormat {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            TwoImgFormat::DOSOrder => write!(f, "DOS order"),
+            TwoImgFormat::ProDOSOrder => write!(f, "ProDOS order"),
+            TwoImgFormat::Nibble => write!(f, "nibble"),
+        }
+    }
+}
+
+/// The 2MG header and comment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TwoImgHeader {
+    /// The program that created the image
+    pub creator: String,
+    /// The size of the header
+    pub header_size: u16,
+    /// The header version
+    pub version: u16,
+    /// The format of the image data
+    pub format: TwoImgFormat,
+    /// The flags
+    pub flags: u32,
+    /// The number of ProDOS blocks, zero for DOS order images
+    pub blocks: u32,
+    /// The offset of the image data in the file
+    pub data_offset: u32,
+    /// The length of the image data
+    pub data_length: u32,
+    /// The offset of the comment, zero if there isn't one
+    pub comment_offset: u32,
+    /// The length of the comment
+    pub comment_length: u32,
+    /// The offset of the creator data, zero if there isn't any
+    pub creator_data_offset: u32,
+    /// The length of the creator data
+    pub creator_data_length: u32,
+    /// The comment, if there is one
+    pub comment: Option<String>,
+}
+
+impl TwoImgHeader {
+    /// Return true if the image is locked, emulators mount it read-only
+    pub fn locked(&self) -> bool {
+        self.flags & FLAG_LOCKED != 0
+    }
+
+    /// Return the DOS volume number, if the flags hold one
+    pub fn volume(&self) -> Option<u8> {
+        (self.flags & FLAG_VOLUME_VALID != 0).then_some(self.flags as u8)
+    }
+}
+
+impl Display for TwoImgHeader {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "2MG {} from {}", self.format, self.creator)?;
+        if let Some(volume) = self.volume() {
+            write!(f, ", volume: {}", volume)?;
+        }
+        if self.locked() {
+            write!(f, ", locked")?;
+        }
+        if let Some(comment) = &self.comment {
+            write!(f, ", comment: {}", comment)?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for TwoImgHeader {
+    fn check(&self) -> bool {
+        if usize::from(self.header_size) < TWOIMG_HEADER_SIZE
+            || (self.data_offset as usize) < usize::from(self.header_size)
+        {
+            debug!(
+                target: PARSE,
+                "2MG data at {} overlaps the {} byte header", self.data_offset, self.header_size
+            );
+            return false;
+        }
+        if self.format == TwoImgFormat::ProDOSOrder && self.data_length != self.blocks * 512 {
+            debug!(
+                target: PARSE,
+                "2MG data length {} doesn't match {} blocks", self.data_length, self.blocks
+            );
+        }
+        true
+    }
+}
+
+/// A 2MG image: the header and the image data it wraps
+pub struct TwoImgImage<'a> {
+    /// The header
+    pub header: TwoImgHeader,
+    /// The wrapped image data
+    pub data: &'a [u8],
+    /// The creator data, empty if there isn't any
+    pub creator_data: &'a [u8],
+}
+
+impl<'a> TwoImgImage<'a> {
+    /// Return the guess for the wrapped image, for the Apple parsers
+    pub fn guess(&self) -> AppleDiskGuess<'a> {
+        let size = self.data.len() as u64;
+        match self.header.format {
+            TwoImgFormat::DOSOrder => {
+                AppleDiskGuess::new(Encoding::Plain, Format::DOS33(size), self.data)
+            }
+            TwoImgFormat::ProDOSOrder => {
+                AppleDiskGuess::new(Encoding::Plain, Format::ProDOS(size), self.data)
+            }
+            TwoImgFormat::Nibble => {
+                let format = match recognize_prologue(self.data) {
+                    Some(0xB5) => Format::DOS32(size),
+                    Some(0x96) => Format::DOS33(size),
+                    _ => Format::Unknown(size),
+                };
+                AppleDiskGuess::new(Encoding::Nibble, format, self.data)
+            }
+        }
+    }
+}
+
+/// Return the part of the file an offset and length in the header
+/// point to, empty for a zero offset
+fn chunk(data: &[u8], offset: u32, length: u32) -> Option<&[u8]> {
+    if offset == 0 {
+        return Some(&[]);
+    }
+    data.get(offset as usize..(offset as usize).checked_add(length as usize)?)
+}
+
+/// Parse the 2MG header
+pub fn twoimg_header_parser(i: &[u8]) -> IResult<&[u8], TwoImgHeader> {
+    let (i, _magic) = tag(TWOIMG_MAGIC)(i)?;
+    let (i, creator) = take(4_usize)(i)?;
+    let (i, header_size) = le_u16(i)?;
+    let (i, version) = le_u16(i)?;
+    let format_input = i;
+    let (i, format) = le_u32(i)?;
+    let format = match format {
+        0 => TwoImgFormat::DOSOrder,
+        1 => TwoImgFormat::ProDOSOrder,
+        2 => TwoImgFormat::Nibble,
+        _ => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                format_input,
+                nom::error::ErrorKind::Verify,
+            )))
+        }
+    };
+    let (i, flags) = le_u32(i)?;
+    let (i, blocks) = le_u32(i)?;
+    let (i, data_offset) = le_u32(i)?;
+    let (i, data_length) = le_u32(i)?;
+    let (i, comment_offset) = le_u32(i)?;
+    let (i, comment_length) = le_u32(i)?;
+    let (i, creator_data_offset) = le_u32(i)?;
+    let (i, creator_data_length) = le_u32(i)?;
+    let (i, _reserved) = take(TWOIMG_HEADER_SIZE - 48)(i)?;
+
+    Ok((
+        i,
+        TwoImgHeader {
+            creator: creator.iter().map(|c| char::from(*c)).collect(),
+            header_size,
+            version,
+            format,
+            flags,
+            blocks,
+            data_offset,
+            data_length,
+            comment_offset,
+            comment_length,
+            creator_data_offset,
+            creator_data_length,
+            comment: None,
+        },
+    ))
+}
+
+/// Parse a 2MG image, finding the image data, the comment and the
+/// creator data
+pub fn twoimg_parser(i: &[u8]) -> IResult<&[u8], TwoImgImage<'_>> {
+    let data = i;
+    let (_, mut header) = twoimg_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[8..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let (rest, _) = take(header.data_offset as usize)(data)?;
+    let (rest, image_data) = take(header.data_length as usize)(rest)?;
+
+    // A damaged comment or creator data chunk doesn't lose the image
+    match chunk(data, header.comment_offset, header.comment_length) {
+        Some(comment) if !comment.is_empty() => {
+            let comment: String = comment.iter().map(|c| char::from(*c)).collect();
+            header.comment = Some(String::from(comment.trim_end_matches(['\0', '\r', '\n'])));
+        }
+        Some(_) => (),
+        None => debug!(target: PARSE, "2MG comment is past the end of the file"),
+    }
+    let creator_data = chunk(data, header.creator_data_offset, header.creator_data_length)
+        .unwrap_or_else(|| {
+            debug!(target: PARSE, "2MG creator data is past the end of the file");
+            &[]
+        });
+
+    Ok((
+        rest,
+        TwoImgImage {
+            header,
+            data: image_data,
+            creator_data,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_2mg, twoimg_parser, TwoImgFormat};
+    use crate::disk_format::apple::disk::{Encoding, Format};
+    use crate::disk_format::testgen;
+
+    /// Test parsing the header, comment and image data of a 2MG image
+    #[test]
+    fn twoimg_parser_works() {
+        let payload = testgen::apple_dos_33(&[("HELLO", b"data")]).unwrap();
+        let data = testgen::two_img(&payload, 0, Some(254), "A COMMENT");
+        assert!(is_2mg(&data));
+        let (_, image) = twoimg_parser(&data).unwrap();
+        assert_eq!(image.header.format, TwoImgFormat::DOSOrder);
+        assert_eq!(image.header.volume(), Some(254));
+        assert!(!image.header.locked());
+        assert_eq!(image.header.comment.as_deref(), Some("A COMMENT"));
+        assert_eq!(image.data, &payload[..]);
+        assert_eq!(
+            image.header.to_string(),
+            "2MG DOS order from RIDR, volume: 254, comment: A COMMENT"
+        );
+        let guess = image.guess();
+        assert_eq!(guess.encoding, Encoding::Plain);
+        assert_eq!(guess.format, Format::DOS33(143360));
+
+        // A truncated image
+        assert!(twoimg_parser(&data[..1000]).is_err());
+        // An unknown image format
+        let mut bad = data.clone();
+        bad[12] = 7;
+        assert!(twoimg_parser(&bad).is_err());
+        // A comment past the end of the file
+        let mut bad = data.clone();
+        bad[32..36].copy_from_slice(&0x100000_u32.to_le_bytes());
+        assert_eq!(twoimg_parser(&bad).unwrap().1.header.comment, None);
+    }
+}
@@ -0,0 +1,1003 @@
+//! WOZ disk images
+//!
+//! WOZ images store the bit stream of every track, so they can hold
+//! copy protection and odd formats that sector images can't.  WOZ 1.0
+//! and 2.0 images are read, with the bit stream of each track available
+//! and the sectors of 5.25 inch disks decoded like a nibble dump.  This
+//! also writes 5.25 inch WOZ 2.0 images from DOS-order sector images
+//! and decoded nibble disks, with each track written as standard DOS
+//! 3.3 16 sector fields.
+//!
+//! The file layout is:
+//!
+//! ```ignore
+//! Header: "WOZ1" or "WOZ2", FF 0A 0D 0A, CRC-32 of the rest of the
+//!   file, or zero if it wasn't computed
+//! INFO chunk: 60 bytes of disk information
+//! TMAP chunk: 160 quarter tracks, each the index of a TRKS entry or FF
+//! TRKS chunk, WOZ 1.0: 6656 byte entries, each 6646 bytes of track
+//!   bits followed by the bytes used and the bit count
+//! TRKS chunk, WOZ 2.0: 160 8 byte entries (starting block, block
+//!   count, bit count), then the track bits from byte 1536, each track
+//!   padded to a 512 byte block
+//! META chunk: optional tab separated keys and values, one per line
+//! ```
+//!
+//! Other chunks, like WRIT and FLUX, are skipped.
+//!
+//! Information from:\
+//! [WOZ 1.0 specification](https://applesaucefdc.com/woz/reference/)\
+//! [WOZ 2.0 specification](https://applesaucefdc.com/woz/reference2/)\
+//! Beneath Apple DOS, chapter 3
+use std::collections::BTreeMap;
+
+use log::{debug, error};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take};
+use nom::multi::many0;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::apple::disk::AppleDiskData;
+use crate::disk_format::apple::nibble::{
+    encode_address_field, encode_data_field, latch_nibbles, parse_nib_disk, NibbleDisk,
+};
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::checksum::crc32;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::image::DiskImage;
+use crate::disk_format::limits::{limit_tracks, with_limits};
+use crate::disk_format::mfm::{pack_bits, unpack_bits};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+use crate::log_target::PARSE;
+use crate::options::Options;
+
+/// A WOZ bit stream image
+pub const WOZ_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "WOZ",
+    description: "Apple ][ WOZ 1.0 and 2.0 bit stream disk image",
+    extensions: &["woz"],
+    sizes: &[SizeRange::at_least(12)],
+    references: &[
+        Reference {
+            title: "WOZ 1.0 specification",
+            url: Some("https://applesaucefdc.com/woz/reference/"),
+        },
+        Reference {
+            title: "WOZ 2.0 specification",
+            url: Some("https://applesaucefdc.com/woz/reference2/"),
+        },
+    ],
+    capabilities: Capabilities {
+        tracks: true,
+        checksums: true,
+        write_protect: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The DOS-order sector stored in each physical sector of a DOS 3.3
+/// track
+pub const DOS_33_PHYSICAL_ORDER: [u8; 16] = [
+    0x00, 0x07, 0x0E, 0x06, 0x0D, 0x05, 0x0C, 0x04, 0x0B, 0x03, 0x0A, 0x02, 0x09, 0x01, 0x08, 0x0F,
+];
+
+/// The size of a DOS-order 35 track image
+const DOS_ORDER_IMAGE_SIZE: usize = 35 * 16 * 256;
+
+/// The offset of the volume number in the VTOC of a DOS-order image,
+/// track 17 sector 0
+const VTOC_VOLUME_OFFSET: usize = 17 * 16 * 256 + 6;
+
+/// The signature of a WOZ 1.0 image
+const WOZ1_MAGIC: &[u8; 8] = b"WOZ1\xFF\x0A\x0D\x0A";
+
+/// The signature of a WOZ 2.0 image
+const WOZ2_MAGIC: &[u8; 8] = b"WOZ2\xFF\x0A\x0D\x0A";
+
+/// The size of the signature and CRC-32
+const HEADER_SIZE: usize = 12;
+
+/// The size of the header of each chunk, the ID and the length
+const CHUNK_HEADER_SIZE: usize = 8;
+
+/// The size of a WOZ 1.0 TRKS entry
+const WOZ1_TRACK_SIZE: usize = 6656;
+
+/// The bytes of track bits in a WOZ 1.0 TRKS entry
+const WOZ1_BITS_SIZE: usize = 6646;
+
+/// WOZ files are divided into 512 byte blocks
+const BLOCK_SIZE: usize = 512;
+
+/// The number of quarter track and track entries
+const TRACK_ENTRIES: usize = 160;
+
+/// The block where track data starts
+const FIRST_TRACK_BLOCK: usize = 3;
+
+/// Sync bytes before the first sector
+const GAP_1: usize = 48;
+
+/// Sync bytes between the address and data fields
+const GAP_2: usize = 6;
+
+/// Sync bytes after each data field
+const GAP_3: usize = 20;
+
+/// A sector to write to a nibble track
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NibbleSector<'a> {
+    /// The volume written in the address field
+    pub volume: u8,
+    /// The physical sector number written in the address field
+    pub sector: u8,
+    /// The 256 bytes of sector data
+    pub data: &'a [u8],
+}
+
+/// Encodes disk bytes into the bit stream of a track
+#[derive(Debug, Default)]
+pub struct NibbleEncoder {
+    /// The encoded bits
+    bits: Vec<bool>,
+}
+
+impl NibbleEncoder {
+    /// Create a new empty encoder
+    pub fn new() -> NibbleEncoder {
+        NibbleEncoder::default()
+    }
+
+    /// Write disk bytes, most significant bit first
+    pub fn write_nibbles(&mut self, nibbles: &[u8]) {
+        for nibble in nibbles {
+            self.bits
+                .extend((0..8).rev().map(|i| (nibble >> i) & 0x01 == 0x01));
+        }
+    }
+
+    /// Write self-sync bytes, 0xFF followed by two zero bits
+    pub fn write_sync(&mut self, count: usize) {
+        for _ in 0..count {
+            self.write_nibbles(&[0xFF]);
+            self.bits.extend([false, false]);
+        }
+    }
+
+    /// The number of bits written so far
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// True if nothing has been written
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Return the encoded bits
+    pub fn into_bits(self) -> Vec<bool> {
+        self.bits
+    }
+}
+
+/// Encode a track of 16 sector DOS 3.3 fields
+pub fn encode_nibble_track(track: u8, sectors: &[NibbleSector]) -> Vec<bool> {
+    let mut encoder = NibbleEncoder::new();
+
+    encoder.write_sync(GAP_1);
+    for sector in sectors {
+        encoder.write_nibbles(&encode_address_field(sector.volume, track, sector.sector));
+        encoder.write_sync(GAP_2);
+        encoder.write_nibbles(&encode_data_field(sector.data));
+        encoder.write_sync(GAP_3);
+    }
+
+    encoder.into_bits()
+}
+
+/// Build a WOZ 2.0 image from the bits of each whole track
+/// Each track is mapped to its own quarter track and the quarter
+/// tracks on either side, the way drives read them.  Tracks past the
+/// 40 tracks a TMAP can hold are left out.
+pub fn write_woz(tracks: &BTreeMap<u8, Vec<bool>>, write_protected: bool) -> Vec<u8> {
+    let mut tmap = [0xFF_u8; TRACK_ENTRIES];
+    let mut trk_entries = Vec::with_capacity(TRACK_ENTRIES * 8);
+    let mut track_data = Vec::new();
+    let mut largest_track = 0;
+
+    let tracks = tracks
+        .iter()
+        .filter(|(track, _)| usize::from(**track) * 4 < TRACK_ENTRIES);
+    for (index, (track, bits)) in tracks.enumerate() {
+        let quarter_track = usize::from(*track) * 4;
+        for entry in quarter_track.saturating_sub(1)..=quarter_track + 1 {
+            if let Some(entry) = tmap.get_mut(entry) {
+                *entry = index as u8;
+            }
+        }
+
+        let mut data = pack_bits(bits);
+        let blocks = data.len().div_ceil(BLOCK_SIZE);
+        data.resize(blocks * BLOCK_SIZE, 0);
+        largest_track = largest_track.max(blocks);
+
+        let start_block = FIRST_TRACK_BLOCK + track_data.len() / BLOCK_SIZE;
+        trk_entries.extend_from_slice(&(start_block as u16).to_le_bytes());
+        trk_entries.extend_from_slice(&(blocks as u16).to_le_bytes());
+        trk_entries.extend_from_slice(&(bits.len() as u32).to_le_bytes());
+        track_data.extend(data);
+    }
+    trk_entries.resize(TRACK_ENTRIES * 8, 0);
+
+    let mut info = vec![
+        2,                         // INFO version
+        1,                         // 5.25 inch disk
+        u8::from(write_protected), // write protected
+        0,                         // tracks aren't synchronized
+        1,                         // no MC3470 fake bits
+    ];
+    let mut creator = format!("image-rider {}", env!("CARGO_PKG_VERSION")).into_bytes();
+    creator.resize(32, b' ');
+    info.extend(creator);
+    info.extend_from_slice(&[
+        1,  // disk sides
+        1,  // 16 sector boot sector
+        32, // optimal bit timing, 4 microseconds
+    ]);
+    info.extend_from_slice(&0_u16.to_le_bytes()); // compatible hardware
+    info.extend_from_slice(&0_u16.to_le_bytes()); // required RAM
+    info.extend_from_slice(&(largest_track as u16).to_le_bytes());
+    info.resize(60, 0);
+
+    let mut chunks = Vec::new();
+    for (id, data) in [
+        (b"INFO", info),
+        (b"TMAP", tmap.to_vec()),
+        (b"TRKS", [trk_entries, track_data].concat()),
+    ] {
+        chunks.extend_from_slice(id);
+        chunks.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunks.extend(data);
+    }
+
+    let mut woz = WOZ2_MAGIC.to_vec();
+    woz.extend_from_slice(&crc32(&chunks).to_le_bytes());
+    woz.extend(chunks);
+
+    woz
+}
+
+/// Build a WOZ 2.0 image from a 35 track DOS-order sector image
+/// The token is checked before each track is encoded.
+pub fn woz_from_dos_order(
+    data: &[u8],
+    volume: u8,
+    write_protected: bool,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<u8>, Error> {
+    if data.len() != DOS_ORDER_IMAGE_SIZE {
+        return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!(
+                "DOS-order image must be {} bytes, not {}",
+                DOS_ORDER_IMAGE_SIZE,
+                data.len()
+            ),
+        ))));
+    }
+
+    cancel.start(35);
+    let tracks = data
+        .chunks(16 * 256)
+        .enumerate()
+        .map(|(track, track_data)| {
+            cancel.check()?;
+            cancel.advance();
+            let sectors: Vec<NibbleSector> = DOS_33_PHYSICAL_ORDER
+                .iter()
+                .enumerate()
+                .map(|(physical, logical)| {
+                    let start = usize::from(*logical) * 256;
+                    NibbleSector {
+                        volume,
+                        sector: physical as u8,
+                        data: &track_data[start..start + 256],
+                    }
+                })
+                .collect();
+            Ok((track as u8, encode_nibble_track(track as u8, &sectors)))
+        })
+        .collect::<std::result::Result<_, Error>>()?;
+
+    Ok(write_woz(&tracks, write_protected))
+}
+
+/// Build a WOZ 2.0 image from a decoded nibble disk
+/// Sectors keep their volume and are written to their physical
+/// sectors.  If more
+/// than one volume has a sector, the lowest volume is written.  The
+/// token is checked before each track is encoded.
+pub fn woz_from_nibble_disk(
+    disk: &NibbleDisk,
+    write_protected: bool,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<u8>, Error> {
+    nibble_disk_to_woz(disk, None, write_protected, cancel)
+}
+
+/// Build a WOZ 2.0 image from a decoded nibble disk, writing every
+/// sector with one volume number if one is given
+fn nibble_disk_to_woz(
+    disk: &NibbleDisk,
+    volume: Option<u8>,
+    write_protected: bool,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<u8>, Error> {
+    let mut track_sectors: BTreeMap<u8, BTreeMap<u8, NibbleSector>> = BTreeMap::new();
+    for (sector_volume, volume_data) in &disk.volumes {
+        for (track, track_data) in &volume_data.tracks {
+            let sectors = track_sectors.entry(*track).or_default();
+            for (sector, sector_data) in &track_data.sectors {
+                let physical = disk.order.physical(*sector);
+                sectors.entry(physical).or_insert(NibbleSector {
+                    volume: volume.unwrap_or(*sector_volume),
+                    sector: physical,
+                    data: &sector_data.data,
+                });
+            }
+        }
+    }
+
+    cancel.start(track_sectors.len());
+    let tracks = track_sectors
+        .iter()
+        .map(|(track, sectors)| {
+            cancel.check()?;
+            cancel.advance();
+            let sectors: Vec<NibbleSector> = sectors.values().copied().collect();
+            Ok((*track, encode_nibble_track(*track, &sectors)))
+        })
+        .collect::<std::result::Result<_, Error>>()?;
+
+    Ok(write_woz(&tracks, write_protected))
+}
+
+/// Check a volume number is one DOS 3.3 can use, 1 to 254
+pub fn check_volume_number(volume: u8) -> std::result::Result<u8, Error> {
+    if volume == 0 || volume == 255 {
+        return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!("Volume numbers are 1 to 254, not {}", volume),
+        ))));
+    }
+    Ok(volume)
+}
+
+/// Build a WOZ 2.0 image from a parsed Apple DOS or nibble disk
+/// If a volume number is given it's written in every address field,
+/// and in the VTOC of DOS disks, instead of the disk's own volume
+/// numbers.  Some software checks the volume number.
+pub fn disk_image_to_woz(
+    disk_image: &DiskImage,
+    volume: Option<u8>,
+    write_protected: bool,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<u8>, Error> {
+    let volume = volume.map(check_volume_number).transpose()?;
+    match disk_image.as_apple().map(|apple_disk| &apple_disk.data) {
+        Some(AppleDiskData::DOS(dos_disk)) => match volume {
+            Some(volume) => {
+                let mut data = dos_disk.tracks.data().to_vec();
+                if let Some(byte) = data.get_mut(VTOC_VOLUME_OFFSET) {
+                    *byte = volume;
+                }
+                woz_from_dos_order(&data, volume, write_protected, cancel)
+            }
+            None => woz_from_dos_order(
+                dos_disk.tracks.data(),
+                dos_disk.volume_table_of_contents.diskette_volume_number,
+                write_protected,
+                cancel,
+            ),
+        },
+        Some(AppleDiskData::Nibble(nibble_disk)) => {
+            nibble_disk_to_woz(nibble_disk, volume, write_protected, cancel)
+        }
+        _ => Err(Error::new(ErrorKind::Unimplemented(String::from(
+            "WOZ images can only be written from Apple DOS and nibble disks",
+        )))),
+    }
+}
+
+/// The disk information in the INFO chunk
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WozInfo {
+    /// The version of the INFO chunk, 1 in WOZ 1.0 images
+    pub version: u8,
+    /// 1 for a 5.25 inch disk, 2 for a 3.5 inch disk
+    pub disk_type: u8,
+    /// True if the disk was write protected
+    pub write_protected: bool,
+    /// True if the tracks were imaged with cross track sync
+    pub synchronized: bool,
+    /// True if the MC3470 fake bits were removed
+    pub cleaned: bool,
+    /// The program that made the image, without the padding
+    pub creator: String,
+    /// The number of sides, 1 for WOZ 1.0 images
+    pub sides: u8,
+    /// The boot sector format: 0 unknown, 1 16 sector, 2 13 sector or
+    /// 3 both
+    pub boot_sector_format: u8,
+    /// The time between bits in 125 nanosecond units
+    pub optimal_bit_timing: u8,
+    /// The Apple II models the disk runs on, one bit per model, zero
+    /// if unknown
+    pub compatible_hardware: u16,
+    /// The RAM the disk needs in KB, zero if unknown
+    pub required_ram: u16,
+    /// The blocks used by the largest track
+    pub largest_track: u16,
+}
+
+impl SanityCheck for WozInfo {
+    fn check(&self) -> bool {
+        if !(1..=3).contains(&self.version) {
+            debug!(target: PARSE, "Unknown WOZ INFO version: {}", self.version);
+            return false;
+        }
+        if !(1..=2).contains(&self.disk_type) || !(1..=2).contains(&self.sides) {
+            debug!(
+                target: PARSE,
+                "Invalid WOZ disk type or sides: {}, {}",
+                self.disk_type,
+                self.sides
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// The bit stream of a track
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WozTrack<'a> {
+    /// The bytes holding the bits, most significant bit first
+    pub data: &'a [u8],
+    /// The number of bits in the stream
+    pub bit_count: usize,
+}
+
+impl WozTrack<'_> {
+    /// Return the bits of one revolution of the track
+    pub fn bits(&self) -> Vec<bool> {
+        let mut bits = unpack_bits(self.data);
+        bits.truncate(self.bit_count);
+        bits
+    }
+
+    /// Return the disk bytes a Disk II controller reads from the track
+    /// Two revolutions are read, so a field that crosses the end of
+    /// the stream is read whole.
+    pub fn nibbles(&self) -> Vec<u8> {
+        let mut bits = self.bits();
+        bits.extend_from_within(..);
+        latch_nibbles(&pack_bits(&bits), 0)
+    }
+}
+
+/// A parsed WOZ image
+pub struct WozDisk<'a> {
+    /// The signature and CRC-32 at the start of the file
+    pub header: &'a [u8],
+    /// The WOZ version from the signature, 1 or 2
+    pub version: u8,
+    /// The CRC-32 of the chunks, zero if it wasn't computed
+    pub crc: u32,
+    /// The disk information
+    pub info: WozInfo,
+    /// The TRKS entry of each quarter track, 0xFF for no track
+    /// 3.5 inch disks use one entry per track and side.
+    pub tmap: Vec<u8>,
+    /// The tracks in the TRKS chunk
+    pub tracks: Vec<WozTrack<'a>>,
+    /// The metadata in the META chunk, by key
+    pub meta: BTreeMap<String, String>,
+    /// The sectors decoded from the tracks of a 5.25 inch disk
+    pub nibble_disk: NibbleDisk,
+}
+
+impl<'a> WozDisk<'a> {
+    /// Return the track mapped to a TMAP entry, None if there's no
+    /// track there
+    pub fn quarter_track(&self, entry: usize) -> Option<&WozTrack<'a>> {
+        let index = *self.tmap.get(entry)?;
+        if index == 0xFF {
+            return None;
+        }
+        self.tracks.get(usize::from(index))
+    }
+
+    /// Return a whole track on one side
+    /// Returns None if the track isn't in the image.
+    pub fn track(&self, track: u8, head: u8) -> Option<&WozTrack<'a>> {
+        match self.info.disk_type {
+            2 if head < self.info.sides => self.quarter_track(
+                usize::from(track) * usize::from(self.info.sides) + usize::from(head),
+            ),
+            1 if head == 0 => self.quarter_track(usize::from(track) * 4),
+            _ => None,
+        }
+    }
+
+    /// Return the bits of one revolution of a whole track
+    pub fn track_bits(&self, track: u8, head: u8) -> Option<Vec<bool>> {
+        self.track(track, head).map(WozTrack::bits)
+    }
+
+    /// Return the name of the track stored in a TRKS entry, from the
+    /// TMAP entries that use it
+    /// A whole track is named before the quarter tracks next to it.
+    fn track_name(&self, index: usize) -> String {
+        let uses: Vec<usize> = (0..self.tmap.len())
+            .filter(|entry| usize::from(self.tmap[*entry]) == index)
+            .collect();
+        let entry = uses
+            .iter()
+            .find(|entry| *entry % 4 == 0 || self.info.disk_type == 2)
+            .or(uses.first())
+            .copied();
+        match (entry, self.info.disk_type) {
+            (Some(entry), 2) => {
+                let sides = usize::from(self.info.sides.max(1));
+                format!("track {} side {}", entry / sides, entry % sides)
+            }
+            (Some(entry), _) if entry % 4 == 0 => format!("track {}", entry / 4),
+            (Some(entry), _) => format!("track {}.{:02}", entry / 4, entry % 4 * 25),
+            (None, _) => format!("unmapped track {}", index),
+        }
+    }
+}
+
+impl SourceMapper for WozDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.header) else {
+            return map;
+        };
+        map.add(RegionKind::Header, "file header", start, HEADER_SIZE);
+
+        let mut offset = start + HEADER_SIZE;
+        if let Ok((_, chunks)) = many0(woz_chunk_parser)(&data[offset..]) {
+            for (id, chunk) in chunks {
+                let name = format!("{} chunk", String::from_utf8_lossy(id));
+                map.add(RegionKind::Header, &name, offset, CHUNK_HEADER_SIZE);
+                offset += CHUNK_HEADER_SIZE + chunk.len();
+            }
+        }
+        for (index, track) in self.tracks.iter().enumerate() {
+            if track.data.is_empty() {
+                continue;
+            }
+            map.add_slice(data, track.data, RegionKind::Data, &self.track_name(index));
+        }
+
+        map
+    }
+}
+
+/// Return true if the data starts with a WOZ 1.0 or 2.0 signature
+pub fn is_woz(data: &[u8]) -> bool {
+    data.starts_with(WOZ1_MAGIC) || data.starts_with(WOZ2_MAGIC)
+}
+
+/// Build a nom error for a damaged WOZ structure
+fn woz_error(i: &[u8], kind: nom::error::ErrorKind) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Error(nom::error::Error::new(i, kind))
+}
+
+/// Parse a chunk, returning the ID and the data
+fn woz_chunk_parser(i: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (i, id) = take(4_usize)(i)?;
+    let (i, size) = le_u32(i)?;
+    let (i, data) = take(size)(i)?;
+    Ok((i, (id, data)))
+}
+
+/// Parse the INFO chunk
+/// Version 1 chunks don't have the fields after the creator, they get
+/// the values of a single sided 5.25 inch disk.
+pub fn woz_info_parser(i: &[u8]) -> IResult<&[u8], WozInfo> {
+    let (i, version) = le_u8(i)?;
+    let (i, disk_type) = le_u8(i)?;
+    let (i, write_protected) = le_u8(i)?;
+    let (i, synchronized) = le_u8(i)?;
+    let (i, cleaned) = le_u8(i)?;
+    let (i, creator) = take(32_usize)(i)?;
+    let mut info = WozInfo {
+        version,
+        disk_type,
+        write_protected: write_protected == 1,
+        synchronized: synchronized == 1,
+        cleaned: cleaned == 1,
+        creator: String::from_utf8_lossy(creator).trim_end().to_string(),
+        sides: 1,
+        boot_sector_format: 0,
+        optimal_bit_timing: 32,
+        compatible_hardware: 0,
+        required_ram: 0,
+        largest_track: 0,
+    };
+    if version < 2 {
+        return Ok((i, info));
+    }
+
+    let (i, sides) = le_u8(i)?;
+    let (i, boot_sector_format) = le_u8(i)?;
+    let (i, optimal_bit_timing) = le_u8(i)?;
+    let (i, compatible_hardware) = le_u16(i)?;
+    let (i, required_ram) = le_u16(i)?;
+    let (i, largest_track) = le_u16(i)?;
+    info.sides = sides;
+    info.boot_sector_format = boot_sector_format;
+    info.optimal_bit_timing = optimal_bit_timing;
+    info.compatible_hardware = compatible_hardware;
+    info.required_ram = required_ram;
+    info.largest_track = largest_track;
+
+    Ok((i, info))
+}
+
+/// Parse the tracks of a WOZ 1.0 TRKS chunk
+fn woz1_tracks_parser(i: &[u8]) -> IResult<&[u8], Vec<WozTrack<'_>>> {
+    limit_tracks(i, i.len() / WOZ1_TRACK_SIZE)?;
+    let mut tracks = Vec::new();
+    let mut i = i;
+    while i.len() >= WOZ1_TRACK_SIZE {
+        let (rest, bits) = take(WOZ1_BITS_SIZE)(i)?;
+        let (rest, bytes_used) = le_u16(rest)?;
+        let (rest, bit_count) = le_u16(rest)?;
+        let (rest, _splice) = take(6_usize)(rest)?;
+        let data = &bits[..usize::from(bytes_used).min(WOZ1_BITS_SIZE)];
+        if usize::from(bit_count) > data.len() * 8 {
+            error!(
+                target: PARSE,
+                "WOZ track has {} bits in {} bytes",
+                bit_count,
+                data.len()
+            );
+            return Err(woz_error(i, nom::error::ErrorKind::Verify));
+        }
+        tracks.push(WozTrack {
+            data,
+            bit_count: usize::from(bit_count),
+        });
+        i = rest;
+    }
+    Ok((i, tracks))
+}
+
+/// Parse the tracks of a WOZ 2.0 TRKS chunk
+/// The track data is found by block number from the start of the file.
+fn woz2_tracks_parser<'a>(file: &'a [u8], i: &'a [u8]) -> IResult<&'a [u8], Vec<WozTrack<'a>>> {
+    let mut tracks = Vec::with_capacity(TRACK_ENTRIES);
+    let mut rest = i;
+    for _ in 0..TRACK_ENTRIES {
+        let (i, start_block) = le_u16(rest)?;
+        let (i, block_count) = le_u16(i)?;
+        let (i, bit_count) = le_u32(i)?;
+        let start = usize::from(start_block) * BLOCK_SIZE;
+        let data = file
+            .get(start..start + usize::from(block_count) * BLOCK_SIZE)
+            .ok_or_else(|| woz_error(rest, nom::error::ErrorKind::Eof))?;
+        let bit_count = bit_count as usize;
+        if bit_count > data.len() * 8 {
+            error!(
+                target: PARSE,
+                "WOZ track has {} bits in {} blocks",
+                bit_count,
+                block_count
+            );
+            return Err(woz_error(rest, nom::error::ErrorKind::Verify));
+        }
+        tracks.push(WozTrack { data, bit_count });
+        rest = i;
+    }
+    Ok((rest, tracks))
+}
+
+/// Parse the META chunk, tab separated keys and values one per line
+fn woz_meta_parser(data: &[u8]) -> BTreeMap<String, String> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+/// Decode the sectors on the whole tracks of a 5.25 inch disk
+/// Sectors that can't be decoded are recorded as warnings by the
+/// nibble decoder.
+fn decode_woz_tracks(config: &Options, disk: &WozDisk) -> NibbleDisk {
+    let mut nibbles: Vec<u8> = (0..TRACK_ENTRIES / 4)
+        .filter_map(|track| disk.quarter_track(track * 4))
+        .flat_map(|track| track.nibbles())
+        .collect();
+    // The address field search needs bytes past the last field
+    nibbles.extend_from_slice(&[0xFF; 16]);
+
+    match parse_nib_disk(config)(&nibbles) {
+        Ok((_, nibble_disk)) => nibble_disk,
+        Err(e) => {
+            warning(Warning::new(&format!(
+                "couldn't decode the WOZ tracks: {}",
+                e
+            )));
+            NibbleDisk::default()
+        }
+    }
+}
+
+/// Parse a WOZ 1.0 or 2.0 image
+/// The CRC-32 is checked unless ignore-checksums is set, in which case
+/// a mismatch is recorded as a warning.  The sectors of 5.25 inch disks
+/// are decoded from the whole tracks.
+pub fn woz_disk_parser(config: &Options) -> impl Fn(&[u8]) -> IResult<&[u8], WozDisk<'_>> + '_ {
+    move |file| {
+        with_limits(config.limits(), || {
+            let (i, magic) = alt((tag(WOZ1_MAGIC), tag(WOZ2_MAGIC)))(file)?;
+            let (chunks, crc) = le_u32(i)?;
+            let header = &file[..HEADER_SIZE];
+            if crc != 0 && crc32(chunks) != crc {
+                if config.get_bool("ignore-checksums").unwrap_or(false) {
+                    warning(
+                        Warning::new("ignored WOZ CRC-32 mismatch")
+                            .with_location(Location::offset(8).with_format("WOZ")),
+                    );
+                } else {
+                    error!(target: PARSE, "WOZ CRC-32 doesn't match the chunks");
+                    return Err(woz_error(i, nom::error::ErrorKind::Verify));
+                }
+            }
+            let version = magic[3] - b'0';
+
+            let (rest, chunks) = many0(woz_chunk_parser)(chunks)?;
+            let chunk = |id: &[u8]| {
+                chunks
+                    .iter()
+                    .find(|(chunk_id, _)| *chunk_id == id)
+                    .map(|(_, data)| *data)
+            };
+            let missing = |id: &str| {
+                error!(target: PARSE, "WOZ image has no {} chunk", id);
+                woz_error(rest, nom::error::ErrorKind::Tag)
+            };
+
+            let (_, info) = woz_info_parser(chunk(b"INFO").ok_or_else(|| missing("INFO"))?)?;
+            let tmap = chunk(b"TMAP").ok_or_else(|| missing("TMAP"))?;
+            let (_, tmap) = take(TRACK_ENTRIES)(tmap)?;
+            let trks = chunk(b"TRKS").ok_or_else(|| missing("TRKS"))?;
+            let (_, tracks) = match version {
+                1 => woz1_tracks_parser(trks)?,
+                _ => woz2_tracks_parser(file, trks)?,
+            };
+            let meta = chunk(b"META").map(woz_meta_parser).unwrap_or_default();
+
+            let mut disk = WozDisk {
+                header,
+                version,
+                crc,
+                info,
+                tmap: tmap.to_vec(),
+                tracks,
+                meta,
+                nibble_disk: NibbleDisk::default(),
+            };
+            if disk.info.disk_type == 1 {
+                disk.nibble_disk = decode_woz_tracks(config, &disk);
+            }
+
+            Ok((rest, disk))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        disk_image_to_woz, woz_disk_parser, woz_from_dos_order, FIRST_TRACK_BLOCK, TRACK_ENTRIES,
+        WOZ1_BITS_SIZE, WOZ1_MAGIC,
+    };
+    use crate::disk_format::apple::nibble::{latch_nibbles, parse_nib_disk};
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::checksum::crc32;
+    use crate::disk_format::image::{DiskImage, DiskImageParser};
+    use crate::disk_format::mfm::pack_bits;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Build a WOZ 1.0 image from the bits of whole tracks, with a META
+    /// chunk
+    fn woz1(tracks: &[Vec<bool>]) -> Vec<u8> {
+        let mut info = vec![1, 1, 0, 0, 0];
+        let mut creator = b"test".to_vec();
+        creator.resize(32, b' ');
+        info.extend(creator);
+        info.resize(60, 0);
+
+        let mut tmap = [0xFF; TRACK_ENTRIES];
+        let mut trks = Vec::new();
+        for (index, bits) in tracks.iter().enumerate() {
+            tmap[index * 4] = index as u8;
+            let mut data = pack_bits(bits);
+            let bytes_used = data.len() as u16;
+            data.resize(WOZ1_BITS_SIZE, 0);
+            trks.extend(data);
+            trks.extend_from_slice(&bytes_used.to_le_bytes());
+            trks.extend_from_slice(&(bits.len() as u16).to_le_bytes());
+            trks.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0, 0, 0]);
+        }
+
+        let mut chunks = Vec::new();
+        for (id, data) in [
+            (b"INFO", info),
+            (b"TMAP", tmap.to_vec()),
+            (b"TRKS", trks),
+            (b"META", b"title\tTest Disk\npublisher\tNobody\n".to_vec()),
+        ] {
+            chunks.extend_from_slice(id);
+            chunks.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            chunks.extend(data);
+        }
+
+        let mut woz = WOZ1_MAGIC.to_vec();
+        woz.extend_from_slice(&crc32(&chunks).to_le_bytes());
+        woz.extend(chunks);
+        woz
+    }
+
+    /// Test writing a DOS-order image as WOZ and decoding the tracks
+    /// again
+    #[test]
+    fn woz_from_dos_order_works() {
+        let data: Vec<u8> = (0..143360_usize)
+            .map(|i| ((i / 256) as u8) ^ (i as u8))
+            .collect();
+        let woz = woz_from_dos_order(&data, 254, true, &CancellationToken::new()).unwrap();
+
+        assert_eq!(&woz[0..8], b"WOZ2\xFF\x0A\x0D\x0A");
+        assert_eq!(woz[8..12], crc32(&woz[12..]).to_le_bytes());
+        assert_eq!(&woz[12..16], b"INFO");
+        assert_eq!(woz[20], 2);
+        assert_eq!(woz[22], 1);
+        assert_eq!(&woz[80..84], b"TMAP");
+        assert_eq!(&woz[88..96], &[0, 0, 0xFF, 1, 1, 1, 0xFF, 2]);
+        assert_eq!(&woz[248..252], b"TRKS");
+
+        // The first track starts at block 3 and fits in 13 blocks
+        let entry = &woz[256..264];
+        assert_eq!(u16::from_le_bytes([entry[0], entry[1]]), 3);
+        assert_eq!(u16::from_le_bytes([entry[2], entry[3]]), 13);
+        let bit_count = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize;
+        assert_eq!(bit_count, (48 + 16 * 26) * 10 + 16 * (14 + 349) * 8);
+        assert_eq!(woz.len(), (FIRST_TRACK_BLOCK + 35 * 13) * 512);
+
+        // Decode track 1 and check the sectors are in DOS 3.3 order
+        let start = (FIRST_TRACK_BLOCK + 13) * 512;
+        let mut nibbles = latch_nibbles(&woz[start..start + bit_count / 8], 0);
+        nibbles.extend_from_slice(&[0xFF; 16]);
+        let disk = parse_nib_disk(&Options::default())(&nibbles).unwrap().1;
+        let track = &disk.volumes[&254].tracks[&1];
+        assert_eq!(track.sectors.len(), 16);
+        for (sector, sector_data) in &track.sectors {
+            let offset = (16 + usize::from(*sector)) * 256;
+            assert_eq!(sector_data.data, data[offset..offset + 256]);
+        }
+
+        assert!(woz_from_dos_order(&data[..256], 254, false, &CancellationToken::new()).is_err());
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        assert!(woz_from_dos_order(&data, 254, false, &cancel).is_err());
+    }
+
+    /// Test writing a DOS disk as WOZ with a different volume number
+    #[test]
+    fn disk_image_to_woz_volume_works() {
+        let data = testgen::apple_dos_33(&[("HELLO", b"HELLO")]).unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "test.dsk")
+            .unwrap();
+        let woz = disk_image_to_woz(&image, Some(17), false, &CancellationToken::new()).unwrap();
+
+        // Decode the catalog track, both the address fields and the
+        // VTOC have the new volume
+        let entry = &woz[256 + 17 * 8..256 + 18 * 8];
+        let start = usize::from(u16::from_le_bytes([entry[0], entry[1]])) * 512;
+        let bit_count = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize;
+        let mut nibbles = latch_nibbles(&woz[start..start + bit_count / 8], 0);
+        nibbles.extend_from_slice(&[0xFF; 16]);
+        let disk = parse_nib_disk(&Options::default())(&nibbles).unwrap().1;
+        assert_eq!(disk.volume_numbers(), vec![17]);
+        assert_eq!(disk.volumes[&17].tracks[&17].sectors[&0].data[6], 17);
+
+        assert!(disk_image_to_woz(&image, Some(0), false, &CancellationToken::new()).is_err());
+    }
+
+    /// Test reading a WOZ 2.0 image, its track bits and the decoded
+    /// sectors
+    #[test]
+    fn woz2_disk_parser_works() {
+        let data = testgen::apple_dos_33(&[("HELLO", b"HELLO")]).unwrap();
+        let woz = woz_from_dos_order(&data, 254, true, &CancellationToken::new()).unwrap();
+        let config = Options::default();
+
+        let (_, disk) = woz_disk_parser(&config)(&woz).unwrap();
+        assert_eq!(disk.version, 2);
+        assert!(disk.info.check());
+        assert!(disk.info.write_protected);
+        assert!(disk.info.creator.starts_with("image-rider"));
+        assert_eq!(disk.tracks.len(), TRACK_ENTRIES);
+        assert!(disk.quarter_track(1).is_some());
+        assert!(disk.quarter_track(2).is_none());
+        assert!(disk.track(35, 0).is_none());
+        assert!(disk.track(0, 1).is_none());
+        let bits = disk.track_bits(17, 0).unwrap();
+        assert_eq!(bits.len(), (48 + 16 * 26) * 10 + 16 * (14 + 349) * 8);
+        assert!(disk.meta.is_empty());
+        assert_eq!(disk.nibble_disk.volume_numbers(), vec![254]);
+        assert_eq!(disk.nibble_disk.dos_order_image().unwrap(), data);
+
+        let image = woz.parse_disk_image(&config, "test.woz").unwrap();
+        assert!(matches!(*image, DiskImage::Woz(_)));
+        assert_eq!(image.format_name(), "WOZ 2.0 Disk");
+        assert_eq!(image.tracks().unwrap().len(), 35);
+        assert_eq!(image.source_map.regions[0].name, "file header");
+        assert!(image
+            .source_map
+            .regions
+            .iter()
+            .any(|region| region.name == "track 17"));
+
+        // A damaged image fails the CRC-32 unless checksums are ignored
+        let mut damaged = woz.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xFF;
+        assert!(damaged.parse_disk_image(&config, "test.woz").is_err());
+        let ignore = config.with_override("ignore-checksums", true).unwrap();
+        let image = damaged.parse_disk_image(&ignore, "test.woz").unwrap();
+        assert!(!image.is_clean());
+    }
+
+    /// Test reading a WOZ 1.0 image with metadata
+    #[test]
+    fn woz1_disk_parser_works() {
+        let data = testgen::apple_dos_33(&[]).unwrap();
+        let woz2 = woz_from_dos_order(&data, 254, false, &CancellationToken::new()).unwrap();
+        let config = Options::default();
+        let (_, disk2) = woz_disk_parser(&config)(&woz2).unwrap();
+        let tracks: Vec<Vec<bool>> = (0..3)
+            .map(|track| disk2.track_bits(track, 0).unwrap())
+            .collect();
+
+        let woz = woz1(&tracks);
+        let (_, disk) = woz_disk_parser(&config)(&woz).unwrap();
+        assert_eq!(disk.version, 1);
+        assert_eq!(disk.info.creator, "test");
+        assert_eq!(disk.info.sides, 1);
+        assert_eq!(disk.tracks.len(), 3);
+        assert_eq!(disk.track_bits(2, 0).unwrap(), tracks[2]);
+        assert_eq!(disk.meta["title"], "Test Disk");
+        assert_eq!(disk.meta["publisher"], "Nobody");
+        assert_eq!(
+            disk.nibble_disk.dos_order_image().unwrap()[..3 * 16 * 256],
+            data[..3 * 16 * 256]
+        );
+
+        assert!(woz_disk_parser(&config)(&woz[..200]).is_err());
+    }
+}
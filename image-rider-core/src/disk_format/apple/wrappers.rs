@@ -0,0 +1,617 @@
+//! AppleSingle, AppleDouble and MacBinary host files
+//!
+//! Files from HFS and ProDOS volumes have a resource fork and Finder
+//! or ProDOS type information that a host filesystem can't store.
+//! These wrappers keep everything together so extracted files can be
+//! put back into an image without losing anything:
+//!
+//!   AppleSingle: one file with a header and every fork and attribute
+//!   AppleDouble: the data fork as a plain file, with the rest in a
+//!     header file named ._NAME
+//!   MacBinary: a 128-byte header followed by the data and resource
+//!     forks, common for files downloaded from bulletin boards
+//!
+//! All numbers in these formats are big-endian.
+//!
+//! unwrap_host_files also recognizes the Binary II and BinSCII
+//! transfer formats, for unwrapping files during extraction.
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{be_i32, be_u16, be_u32, be_u8};
+use nom::IResult;
+
+use crate::disk_format::apple::binary2::{binary2_parser, is_binary2};
+use crate::disk_format::apple::binscii::{binscii_decode, is_binscii};
+use crate::disk_format::apple::prodos::{ExtendedFile, FinderInfo};
+use crate::disk_format::checksum::Crc16;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The magic number at the start of an AppleSingle file
+pub const APPLE_SINGLE_MAGIC: u32 = 0x0005_1600;
+
+/// The magic number at the start of an AppleDouble header file
+pub const APPLE_DOUBLE_MAGIC: u32 = 0x0005_1607;
+
+/// The AppleSingle and AppleDouble version written, version 2
+const APPLE_SINGLE_VERSION: u32 = 0x0002_0000;
+
+/// The data fork entry
+const ENTRY_DATA_FORK: u32 = 1;
+/// The resource fork entry
+const ENTRY_RESOURCE_FORK: u32 = 2;
+/// The real name entry
+const ENTRY_REAL_NAME: u32 = 3;
+/// The file dates entry
+const ENTRY_FILE_DATES: u32 = 8;
+/// The Finder information entry
+const ENTRY_FINDER_INFO: u32 = 9;
+/// The ProDOS file information entry
+const ENTRY_PRODOS_FILE_INFO: u32 = 11;
+
+/// Seconds between the Macintosh epoch (1904) and the AppleSingle
+/// epoch (2000)
+const MAC_EPOCH_OFFSET: i64 = 3_029_529_600;
+
+/// The size of a MacBinary header and the block size forks are padded
+/// to
+const MACBINARY_BLOCK: usize = 128;
+
+/// ProDOS file information
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ProDOSFileInfo {
+    /// The access bits
+    pub access: u16,
+    /// The ProDOS file type
+    pub file_type: u16,
+    /// The auxiliary type, e.g. the load address of a binary file
+    pub aux_type: u32,
+}
+
+/// File dates, in seconds since January 1 2000 GMT
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FileDates {
+    /// When the file was created
+    pub created: i32,
+    /// When the file was last modified
+    pub modified: i32,
+    /// When the file was last backed up
+    pub backup: i32,
+    /// When the file was last accessed
+    pub accessed: i32,
+}
+
+/// A file with its forks and metadata, ready to be wrapped for the host
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HostFile {
+    /// The name of the file on the original volume
+    pub name: Option<String>,
+    /// The data fork
+    pub data: Vec<u8>,
+    /// The resource fork
+    pub resource: Vec<u8>,
+    /// The Finder information, FInfo followed by FXInfo
+    pub finder_info: Option<[u8; 32]>,
+    /// The file dates
+    pub dates: Option<FileDates>,
+    /// The ProDOS file information
+    pub prodos_info: Option<ProDOSFileInfo>,
+    /// Any other AppleSingle entries, as entry ID and data, kept so
+    /// files round trip unchanged
+    pub other_entries: Vec<(u32, Vec<u8>)>,
+}
+
+/// Build an invalid data error
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(String::from(
+        message,
+    ))))
+}
+
+impl HostFile {
+    /// The Macintosh file type from the Finder information
+    pub fn file_type(&self) -> Option<[u8; 4]> {
+        self.finder_info.map(|f| [f[0], f[1], f[2], f[3]])
+    }
+
+    /// The Macintosh creator from the Finder information
+    pub fn creator(&self) -> Option<[u8; 4]> {
+        self.finder_info.map(|f| [f[4], f[5], f[6], f[7]])
+    }
+
+    /// Build a host file from a ProDOS extended file
+    pub fn from_extended_file(
+        file: &ExtendedFile,
+        name: &str,
+        prodos_info: Option<ProDOSFileInfo>,
+    ) -> HostFile {
+        let finder_info = match (file.finder_info, file.extended_finder_info) {
+            (None, None) => None,
+            (finfo, fxinfo) => {
+                let mut info = [0_u8; 32];
+                info[0..16].copy_from_slice(&finfo.unwrap_or_default());
+                info[16..32].copy_from_slice(&fxinfo.unwrap_or_default());
+                Some(info)
+            }
+        };
+
+        HostFile {
+            name: Some(String::from(name)),
+            data: file.data.clone(),
+            resource: file.resource.clone(),
+            finder_info,
+            prodos_info,
+            ..HostFile::default()
+        }
+    }
+
+    /// Convert back to a ProDOS extended file, for re-inserting into a
+    /// volume
+    pub fn to_extended_file(&self) -> ExtendedFile {
+        let split = |range: std::ops::Range<usize>| {
+            self.finder_info.map(|info| {
+                let mut part: FinderInfo = [0; 16];
+                part.copy_from_slice(&info[range]);
+                part
+            })
+        };
+
+        ExtendedFile {
+            data: self.data.clone(),
+            resource: self.resource.clone(),
+            finder_info: split(0..16),
+            extended_finder_info: split(16..32),
+        }
+    }
+
+    /// The AppleSingle entries for the file, excluding the data fork
+    fn header_entries(&self) -> Vec<(u32, Vec<u8>)> {
+        let mut entries = Vec::new();
+
+        if let Some(name) = &self.name {
+            entries.push((ENTRY_REAL_NAME, name.as_bytes().to_vec()));
+        }
+        if let Some(dates) = &self.dates {
+            let mut bytes = Vec::new();
+            for date in [dates.created, dates.modified, dates.backup, dates.accessed] {
+                bytes.extend_from_slice(&date.to_be_bytes());
+            }
+            entries.push((ENTRY_FILE_DATES, bytes));
+        }
+        if let Some(finder_info) = &self.finder_info {
+            entries.push((ENTRY_FINDER_INFO, finder_info.to_vec()));
+        }
+        if let Some(info) = &self.prodos_info {
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(&info.access.to_be_bytes());
+            bytes.extend_from_slice(&info.file_type.to_be_bytes());
+            bytes.extend_from_slice(&info.aux_type.to_be_bytes());
+            entries.push((ENTRY_PRODOS_FILE_INFO, bytes));
+        }
+        entries.extend(self.other_entries.iter().cloned());
+        // Resource forks go last so they can grow in place
+        if !self.resource.is_empty() {
+            entries.push((ENTRY_RESOURCE_FORK, self.resource.clone()));
+        }
+
+        entries
+    }
+
+    /// Encode the file as AppleSingle
+    pub fn to_apple_single(&self) -> Vec<u8> {
+        let mut entries = self.header_entries();
+        if !self.data.is_empty() {
+            entries.push((ENTRY_DATA_FORK, self.data.clone()));
+        }
+        apple_single_encode(APPLE_SINGLE_MAGIC, &entries)
+    }
+
+    /// Encode the AppleDouble header file, which holds everything but
+    /// the data fork
+    pub fn to_apple_double(&self) -> Vec<u8> {
+        apple_single_encode(APPLE_DOUBLE_MAGIC, &self.header_entries())
+    }
+
+    /// Decode an AppleSingle file
+    pub fn from_apple_single(data: &[u8]) -> std::result::Result<HostFile, Error> {
+        let (_i, entries) = apple_single_parser(APPLE_SINGLE_MAGIC)(data)?;
+        HostFile::from_entries(entries)
+    }
+
+    /// Decode an AppleDouble header file and the matching data file
+    pub fn from_apple_double(header: &[u8], data: &[u8]) -> std::result::Result<HostFile, Error> {
+        let (_i, entries) = apple_single_parser(APPLE_DOUBLE_MAGIC)(header)?;
+        let mut file = HostFile::from_entries(entries)?;
+        file.data = data.to_vec();
+        Ok(file)
+    }
+
+    /// Build a host file from AppleSingle entries
+    fn from_entries(entries: Vec<Entry>) -> std::result::Result<HostFile, Error> {
+        let mut file = HostFile::default();
+
+        for (id, data) in entries {
+            match id {
+                ENTRY_DATA_FORK => file.data = data.to_vec(),
+                ENTRY_RESOURCE_FORK => file.resource = data.to_vec(),
+                ENTRY_REAL_NAME => file.name = Some(String::from_utf8_lossy(data).to_string()),
+                ENTRY_FILE_DATES => {
+                    let (_i, (created, modified, backup, accessed)) =
+                        file_dates_parser(data).map_err(|_| invalid("Short file dates entry"))?;
+                    file.dates = Some(FileDates {
+                        created,
+                        modified,
+                        backup,
+                        accessed,
+                    });
+                }
+                ENTRY_FINDER_INFO => {
+                    let mut info = [0_u8; 32];
+                    let length = data.len().min(32);
+                    info[..length].copy_from_slice(&data[..length]);
+                    file.finder_info = Some(info);
+                }
+                ENTRY_PRODOS_FILE_INFO => {
+                    let (_i, info) = prodos_file_info_parser(data)
+                        .map_err(|_| invalid("Short ProDOS file info entry"))?;
+                    file.prodos_info = Some(info);
+                }
+                other => file.other_entries.push((other, data.to_vec())),
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Encode the file as MacBinary III
+    /// Names longer than 63 bytes are truncated.
+    pub fn to_macbinary(&self) -> Vec<u8> {
+        let mut header = [0_u8; MACBINARY_BLOCK];
+        let name = self.name.as_deref().unwrap_or("untitled").as_bytes();
+        let name = &name[..name.len().min(63)];
+        header[1] = name.len() as u8;
+        header[2..2 + name.len()].copy_from_slice(name);
+
+        if let Some(info) = &self.finder_info {
+            // type, creator, flags high byte
+            header[65..74].copy_from_slice(&info[0..9]);
+            // location and folder
+            header[75..81].copy_from_slice(&info[10..16]);
+            // flags low byte
+            header[101] = info[9];
+            // script and extended flags
+            header[106] = info[16 + 8];
+            header[107] = info[16 + 9];
+        }
+        header[83..87].copy_from_slice(&(self.data.len() as u32).to_be_bytes());
+        header[87..91].copy_from_slice(&(self.resource.len() as u32).to_be_bytes());
+        if let Some(dates) = &self.dates {
+            let to_mac = |date: i32| (i64::from(date) + MAC_EPOCH_OFFSET) as u32;
+            header[91..95].copy_from_slice(&to_mac(dates.created).to_be_bytes());
+            header[95..99].copy_from_slice(&to_mac(dates.modified).to_be_bytes());
+        }
+        header[102..106].copy_from_slice(b"mBIN");
+        header[122] = 130;
+        header[123] = 129;
+        let crc = macbinary_crc(&header[..124]);
+        header[124..126].copy_from_slice(&crc.to_be_bytes());
+
+        let mut data = header.to_vec();
+        for fork in [&self.data, &self.resource] {
+            data.extend_from_slice(fork);
+            data.resize(data.len().next_multiple_of(MACBINARY_BLOCK), 0);
+        }
+
+        data
+    }
+
+    /// Decode a MacBinary file
+    pub fn from_macbinary(data: &[u8]) -> std::result::Result<HostFile, Error> {
+        let (_i, file) = macbinary_parser(data)?;
+        Ok(file)
+    }
+}
+
+/// Unwrap a Binary II archive, BinSCII text, AppleSingle file or
+/// MacBinary II or later file, returning the files inside
+/// Returns None if the data isn't recognized as any of them.
+pub fn unwrap_host_files(data: &[u8]) -> Option<Vec<HostFile>> {
+    if is_binary2(data) {
+        if let Ok((_i, files)) = binary2_parser(data) {
+            return Some(files.iter().map(|f| f.to_host_file()).collect());
+        }
+    }
+    if data.starts_with(&APPLE_SINGLE_MAGIC.to_be_bytes()) {
+        return HostFile::from_apple_single(data).ok().map(|f| vec![f]);
+    }
+    if is_binscii(data) {
+        return binscii_decode(data).ok().filter(|files| !files.is_empty());
+    }
+    // MacBinary I has no signature or CRC, so only accept headers that
+    // can be checked
+    if data.len() >= MACBINARY_BLOCK && data[122] >= 129 {
+        return HostFile::from_macbinary(data).ok().map(|f| vec![f]);
+    }
+
+    None
+}
+
+/// Make a file name safe to create on the host, replacing path
+/// separators and other special characters
+pub fn host_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || " ._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.trim_matches('.') {
+        "" => String::from("untitled"),
+        _ => name,
+    }
+}
+
+/// Return the name of the AppleDouble header file for a file
+pub fn apple_double_name(name: &str) -> String {
+    format!("._{}", name)
+}
+
+/// Encode AppleSingle or AppleDouble entries
+fn apple_single_encode(magic: u32, entries: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&magic.to_be_bytes());
+    data.extend_from_slice(&APPLE_SINGLE_VERSION.to_be_bytes());
+    data.extend_from_slice(&[0; 16]);
+    data.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+
+    let mut offset = 26 + 12 * entries.len();
+    for (id, entry) in entries {
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&(offset as u32).to_be_bytes());
+        data.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        offset += entry.len();
+    }
+    for (_id, entry) in entries {
+        data.extend_from_slice(entry);
+    }
+
+    data
+}
+
+/// An AppleSingle entry ID and its data
+pub type Entry<'a> = (u32, &'a [u8]);
+
+/// Parse an AppleSingle or AppleDouble file with a given magic number
+/// Returns the entry IDs and data.  Version 1 files are accepted too,
+/// they share the same layout.
+pub fn apple_single_parser(magic: u32) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<Entry>> {
+    move |input| {
+        let (i, _magic) = tag(magic.to_be_bytes())(input)?;
+        let (i, _version) = be_u32(i)?;
+        let (i, _filler) = take(16_usize)(i)?;
+        let (mut i, count) = be_u16(i)?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (rest, id) = be_u32(i)?;
+            let (rest, offset) = be_u32(rest)?;
+            let (rest, length) = be_u32(rest)?;
+            let (data, _) = take(offset as usize)(input)?;
+            let (_data, entry) = take(length as usize)(data)?;
+            entries.push((id, entry));
+            i = rest;
+        }
+
+        Ok((i, entries))
+    }
+}
+
+/// Parse a file dates entry
+fn file_dates_parser(i: &[u8]) -> IResult<&[u8], (i32, i32, i32, i32)> {
+    let (i, created) = be_i32(i)?;
+    let (i, modified) = be_i32(i)?;
+    let (i, backup) = be_i32(i)?;
+    let (i, accessed) = be_i32(i)?;
+    Ok((i, (created, modified, backup, accessed)))
+}
+
+/// Parse a ProDOS file info entry
+fn prodos_file_info_parser(i: &[u8]) -> IResult<&[u8], ProDOSFileInfo> {
+    let (i, access) = be_u16(i)?;
+    let (i, file_type) = be_u16(i)?;
+    let (i, aux_type) = be_u32(i)?;
+    Ok((
+        i,
+        ProDOSFileInfo {
+            access,
+            file_type,
+            aux_type,
+        },
+    ))
+}
+
+/// Calculate the CRC-16 of a MacBinary header, the XMODEM variant of
+/// CRC-CCITT
+fn macbinary_crc(header: &[u8]) -> u16 {
+    Crc16::with_initial(Crc16::XMODEM_INITIAL)
+        .update(header)
+        .finish()
+}
+
+/// Parse a MacBinary I, II or III file
+/// MacBinary II and later headers are checked against their CRC, the
+/// original format is recognized by its zero bytes and sane lengths.
+pub fn macbinary_parser(input: &[u8]) -> IResult<&[u8], HostFile> {
+    let error = |i| nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify));
+
+    let (i, header) = take(MACBINARY_BLOCK)(input)?;
+    let crc = u16::from_be_bytes([header[124], header[125]]);
+    let valid = if header[122] >= 129 {
+        crc == macbinary_crc(&header[..124])
+    } else {
+        header[82] == 0
+    };
+    if header[0] != 0 || header[74] != 0 || !(1..=63).contains(&header[1]) || !valid {
+        return Err(error(input));
+    }
+
+    let (h, _) = take(2_usize)(header)?;
+    let (h, name) = take(63_usize)(h)?;
+    let (h, type_creator_flags) = take(9_usize)(h)?;
+    let (h, _zero) = be_u8(h)?;
+    let (h, location_folder) = take(6_usize)(h)?;
+    let (h, _protected) = take(2_usize)(h)?;
+    let (h, data_length) = be_u32(h)?;
+    let (h, resource_length) = be_u32(h)?;
+    let (h, created) = be_u32(h)?;
+    let (h, modified) = be_u32(h)?;
+    let (h, _comment_length) = be_u16(h)?;
+    let (h, flags_low) = be_u8(h)?;
+    let (h, _signature) = take(4_usize)(h)?;
+    let (h, script) = be_u8(h)?;
+    let (_h, extended_flags) = be_u8(h)?;
+
+    // Forks are padded to a multiple of 128 bytes, the padding after
+    // the last fork is sometimes left out
+    let (_, data) = take(data_length as usize)(i)?;
+    let resource_start = (data_length as usize).next_multiple_of(MACBINARY_BLOCK);
+    let (rest, _) = take(resource_start.min(i.len()))(i)?;
+    let (rest, resource) = take(resource_length as usize)(rest)?;
+
+    let mut finder_info = [0_u8; 32];
+    finder_info[0..9].copy_from_slice(type_creator_flags);
+    finder_info[9] = flags_low;
+    finder_info[10..16].copy_from_slice(location_folder);
+    finder_info[16 + 8] = script;
+    finder_info[16 + 9] = extended_flags;
+
+    let from_mac = |date: u32| (i64::from(date) - MAC_EPOCH_OFFSET) as i32;
+
+    Ok((
+        rest,
+        HostFile {
+            name: Some(String::from_utf8_lossy(&name[..usize::from(header[1])]).to_string()),
+            data: data.to_vec(),
+            resource: resource.to_vec(),
+            finder_info: Some(finder_info),
+            dates: Some(FileDates {
+                created: from_mac(created),
+                modified: from_mac(modified),
+                ..FileDates::default()
+            }),
+            ..HostFile::default()
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apple_double_name, host_file_name, unwrap_host_files, FileDates, HostFile, ProDOSFileInfo,
+    };
+    use crate::disk_format::apple::prodos::ExtendedFile;
+
+    /// Build a test file with a resource fork and Finder information
+    fn test_file() -> HostFile {
+        let mut finder_info = [0_u8; 32];
+        finder_info[0..8].copy_from_slice(b"TEXTttxt");
+        finder_info[8] = 0x01;
+        finder_info[9] = 0x00;
+
+        HostFile {
+            name: Some(String::from("Read Me")),
+            data: b"Hello from the data fork".to_vec(),
+            resource: vec![0xAB; 300],
+            finder_info: Some(finder_info),
+            dates: Some(FileDates {
+                created: -100_000_000,
+                modified: -99_000_000,
+                ..FileDates::default()
+            }),
+            ..HostFile::default()
+        }
+    }
+
+    /// Test round trips through AppleSingle and AppleDouble
+    #[test]
+    fn apple_single_works() {
+        let mut file = test_file();
+        file.prodos_info = Some(ProDOSFileInfo {
+            access: 0xC3,
+            file_type: 0x04,
+            aux_type: 0,
+        });
+        file.other_entries.push((4, b"A comment".to_vec()));
+
+        let encoded = file.to_apple_single();
+        assert_eq!(&encoded[0..4], &[0x00, 0x05, 0x16, 0x00]);
+        assert_eq!(HostFile::from_apple_single(&encoded).unwrap(), file);
+
+        let header = file.to_apple_double();
+        assert_eq!(&header[0..4], &[0x00, 0x05, 0x16, 0x07]);
+        assert_eq!(
+            HostFile::from_apple_double(&header, &file.data).unwrap(),
+            file
+        );
+        assert_eq!(apple_double_name("Read Me"), "._Read Me");
+
+        assert!(HostFile::from_apple_single(&header).is_err());
+        assert!(HostFile::from_apple_single(&encoded[..60]).is_err());
+    }
+
+    /// Test a round trip through MacBinary
+    #[test]
+    fn macbinary_works() {
+        let file = test_file();
+        let encoded = file.to_macbinary();
+        assert_eq!(encoded.len(), 128 + 128 + 384);
+        assert_eq!(&encoded[102..106], b"mBIN");
+
+        let decoded = HostFile::from_macbinary(&encoded).unwrap();
+        assert_eq!(decoded, file);
+        assert_eq!(decoded.file_type(), Some(*b"TEXT"));
+        assert_eq!(decoded.creator(), Some(*b"ttxt"));
+
+        // A damaged header fails the CRC check
+        let mut damaged = encoded.clone();
+        damaged[10] ^= 0xFF;
+        assert!(HostFile::from_macbinary(&damaged).is_err());
+    }
+
+    /// Test recognizing wrapped files
+    #[test]
+    fn unwrap_host_files_works() {
+        let file = test_file();
+        assert_eq!(
+            unwrap_host_files(&file.to_apple_single()),
+            Some(vec![file.clone()])
+        );
+        assert_eq!(unwrap_host_files(&file.to_macbinary()), Some(vec![file]));
+        assert_eq!(unwrap_host_files(&[0_u8; 256]), None);
+
+        assert_eq!(host_file_name("DIR/SUB/FILE.S"), "DIR_SUB_FILE.S");
+        assert_eq!(host_file_name(".."), "untitled");
+    }
+
+    /// Test converting to and from a ProDOS extended file
+    #[test]
+    fn extended_file_works() {
+        let mut finfo = [0_u8; 16];
+        finfo[0..4].copy_from_slice(b"APPL");
+        let extended = ExtendedFile {
+            data: vec![1, 2, 3],
+            resource: vec![4, 5],
+            finder_info: Some(finfo),
+            extended_finder_info: None,
+        };
+
+        let file = HostFile::from_extended_file(&extended, "SHRINKIT", None);
+        assert_eq!(file.file_type(), Some(*b"APPL"));
+        let round_trip = file.to_extended_file();
+        assert_eq!(round_trip.data, extended.data);
+        assert_eq!(round_trip.resource, extended.resource);
+        assert_eq!(round_trip.finder_info, extended.finder_info);
+    }
+}
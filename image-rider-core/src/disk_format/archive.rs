@@ -0,0 +1,744 @@
+//! Recognition of period archive formats
+//!
+//! Disks often hold archives rather than the programs themselves: Lynx
+//! and ARK archives on the Commodore 64, ShrinkIt (NuFX) archives on the
+//! Apple II and LHA and PKZIP archives everywhere else.  Archives are
+//! recognized by their signatures and their directories are listed so
+//! reports can show what's really on a disk.
+//!
+//! Entries that are stored without compression are unpacked and
+//! searched for nested archives.  There are no decompressors, so
+//! compressed entries are listed but not expanded, and an archive
+//! inside a compressed entry isn't found.  Entries are marked when
+//! they weren't expanded.  How deep nested archives are searched and
+//! how much data is unpacked are bounded by the max-nesting-depth and
+//! max-expanded-size limits.
+use std::fmt::{Display, Formatter, Result};
+
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::charset::Charset;
+use crate::disk_format::limits::{check_expanded_size, check_nesting_depth};
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The size of a Commodore data block, without the link bytes
+const CBM_BLOCK: usize = 254;
+
+/// The kinds of archive recognized
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveKind {
+    /// Commodore 64 Lynx archive
+    Lynx,
+    /// Commodore 64 ARK archive
+    Ark,
+    /// Apple II ShrinkIt (NuFX) archive
+    ShrinkIt,
+    /// LHA or LHarc archive
+    Lha,
+    /// PKZIP archive
+    Zip,
+}
+
+impl Display for ArchiveKind {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ArchiveKind::Lynx => write!(f, "Lynx"),
+            ArchiveKind::Ark => write!(f, "ARK"),
+            ArchiveKind::ShrinkIt => write!(f, "ShrinkIt"),
+            ArchiveKind::Lha => write!(f, "LHA"),
+            ArchiveKind::Zip => write!(f, "ZIP"),
+        }
+    }
+}
+
+/// A file in an archive
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ArchiveEntry {
+    /// The name of the file
+    pub name: String,
+    /// The compression method, e.g. "stored", "-lh5-" or "LZW/2"
+    pub method: String,
+    /// The length of the file, if the archive records it
+    pub size: Option<u64>,
+    /// The length of the file in the archive, if known
+    pub compressed_size: Option<u64>,
+    /// An archive stored inside this entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested: Option<Box<Archive>>,
+    /// True if the entry was unpacked and searched for a nested
+    /// archive, false for compressed entries and entries past the
+    /// nesting or expansion limits
+    #[serde(default)]
+    pub expanded: bool,
+    /// Where the stored data is in the archive, for unpacking
+    #[serde(skip)]
+    stored: Option<(usize, usize)>,
+}
+
+impl Display for ArchiveEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{} ({}", self.name, self.method)?;
+        if let Some(size) = self.size {
+            write!(f, ", {} bytes", size)?;
+        }
+        if !self.expanded {
+            write!(f, ", not expanded")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// An archive's directory
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Archive {
+    /// The kind of archive
+    pub kind: ArchiveKind,
+    /// The files in the archive
+    pub entries: Vec<ArchiveEntry>,
+}
+
+impl Display for Archive {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "{} archive, {} files", self.kind, self.entries.len())?;
+        for entry in &self.entries {
+            writeln!(f, "  {}", entry)?;
+            if let Some(nested) = &entry.nested {
+                for line in nested.to_string().lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Archive {
+    /// Return the data of an entry stored without compression
+    pub fn stored_data<'a>(&self, data: &'a [u8], entry: &ArchiveEntry) -> Option<&'a [u8]> {
+        entry
+            .stored
+            .and_then(|(start, length)| data.get(start..start + length))
+    }
+}
+
+/// Build an entry
+fn entry(
+    name: String,
+    method: &str,
+    size: Option<u64>,
+    compressed_size: Option<u64>,
+    stored: Option<(usize, usize)>,
+) -> ArchiveEntry {
+    ArchiveEntry {
+        name,
+        method: String::from(method),
+        size,
+        compressed_size,
+        nested: None,
+        expanded: false,
+        stored,
+    }
+}
+
+/// Convert a PETSCII file name padded with shifted spaces
+fn petscii_name(name: &[u8], charset: &Charset) -> String {
+    let end = name.iter().position(|b| *b == 0xA0).unwrap_or(name.len());
+    charset.petscii_string(&name[..end])
+}
+
+/// Return true if the data is a Lynx archive
+fn is_lynx(data: &[u8]) -> bool {
+    let start = &data[..data.len().min(256)];
+    start.windows(4).any(|w| w == b"LYNX") && data.starts_with(&[0x01, 0x08])
+}
+
+/// Read a number from a line of a Lynx directory
+fn lynx_number(line: &[u8]) -> Option<usize> {
+    std::str::from_utf8(line)
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// List a Lynx archive
+/// After a BASIC loader come carriage return separated lines: the
+/// number of directory blocks and the signature, the number of files,
+/// then for each file its name, blocks, type and the number of bytes
+/// used in its last block plus one.  Files start on block boundaries
+/// after the directory.
+fn lynx_entries(data: &[u8], charset: &Charset) -> Option<Vec<ArchiveEntry>> {
+    // The directory starts after the end of the BASIC loader
+    let basic_end = data.windows(3).position(|w| w == [0, 0, 0])? + 3;
+    let mut lines = data[basic_end..].split(|b| *b == 0x0D);
+    let mut line = lines.next()?;
+    if line.is_empty() {
+        line = lines.next()?;
+    }
+    let directory_blocks = lynx_number(line)?;
+    let count = lynx_number(lines.next()?)?;
+
+    let mut offset = directory_blocks * CBM_BLOCK;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let name = petscii_name(lines.next()?, charset);
+        let blocks = lynx_number(lines.next()?)?;
+        let file_type = lines.next()?.first().copied().unwrap_or(b'?');
+        if file_type == b'R' {
+            // Relative files also have a record length
+            lines.next()?;
+        }
+        let last = lynx_number(lines.next()?)?;
+        let size = match blocks {
+            0 => 0,
+            _ => (blocks - 1) * CBM_BLOCK + last.saturating_sub(1),
+        };
+        entries.push(entry(
+            name,
+            "stored",
+            Some(size as u64),
+            Some((blocks * CBM_BLOCK) as u64),
+            Some((offset, size)),
+        ));
+        offset += blocks * CBM_BLOCK;
+    }
+
+    Some(entries)
+}
+
+/// List an ARK archive
+/// The first byte is the number of files, followed by a 29 byte entry
+/// for each: the file type, the name padded with shifted spaces and
+/// relative file and size information.
+fn ark_entries(data: &[u8], charset: &Charset) -> Option<Vec<ArchiveEntry>> {
+    let count = usize::from(*data.first()?);
+    (0..count)
+        .map(|n| {
+            let record = data.get(1 + n * 29..1 + (n + 1) * 29)?;
+            Some(entry(
+                petscii_name(&record[1..17], charset),
+                "stored",
+                None,
+                None,
+                None,
+            ))
+        })
+        .collect()
+}
+
+/// Return true if the data looks like an ARK archive
+/// ARK has no signature, so check every entry has a valid closed file
+/// type and a padded name.
+fn is_ark(data: &[u8]) -> bool {
+    let count = usize::from(data.first().copied().unwrap_or(0));
+    count > 0
+        && data.len() >= CBM_BLOCK.max(1 + count * 29)
+        && (0..count).all(|n| {
+            let record = &data[1 + n * 29..1 + (n + 1) * 29];
+            (record[0] & 0x80) != 0
+                && (1..=4).contains(&(record[0] & 0x07))
+                && record[1] != 0xA0
+                && record[1..17]
+                    .iter()
+                    .skip_while(|b| **b != 0xA0)
+                    .all(|b| *b == 0xA0)
+        })
+}
+
+/// The NuFX master header signature, "NuFile" with alternating high bits
+const NUFX_MASTER: [u8; 6] = [0x4E, 0xF5, 0x46, 0xE9, 0x6C, 0xE5];
+
+/// The NuFX record signature, "NuFX" with alternating high bits
+const NUFX_RECORD: [u8; 4] = [0x4E, 0xF5, 0x46, 0xD8];
+
+/// The name of a NuFX thread format
+fn nufx_format(format: u16) -> &'static str {
+    match format {
+        0 => "stored",
+        1 => "squeeze",
+        2 => "LZW/1",
+        3 => "LZW/2",
+        4 => "compress 12 bit",
+        5 => "compress 16 bit",
+        _ => "unknown",
+    }
+}
+
+/// Parse a NuFX record, returning the entry and the remaining input
+fn nufx_record_parser(input: &[u8]) -> IResult<&[u8], ArchiveEntry> {
+    let (i, _) = tag(NUFX_RECORD)(input)?;
+    let (i, _crc) = le_u16(i)?;
+    let (i, attribute_count) = le_u16(i)?;
+    let (i, _version) = le_u16(i)?;
+    let (_i, total_threads) = le_u32(i)?;
+
+    // The old style file name is at the end of the attributes
+    let attribute_count = usize::from(attribute_count);
+    let (i, _) = take(attribute_count.saturating_sub(2))(input)?;
+    let (i, name_length) = le_u16(i)?;
+    let (mut i, old_name) = take(name_length)(i)?;
+
+    let mut threads = Vec::new();
+    for _ in 0..total_threads.min(16) {
+        let (rest, class) = le_u16(i)?;
+        let (rest, format) = le_u16(rest)?;
+        let (rest, kind) = le_u16(rest)?;
+        let (rest, _crc) = le_u16(rest)?;
+        let (rest, eof) = le_u32(rest)?;
+        let (rest, compressed_eof) = le_u32(rest)?;
+        threads.push((class, format, kind, eof, compressed_eof));
+        i = rest;
+    }
+
+    let mut name = String::from_utf8_lossy(old_name).to_string();
+    let mut data_fork = None;
+    let mut offset = input.len() - i.len();
+    for (class, format, kind, eof, compressed_eof) in &threads {
+        let (_, thread) = take(*compressed_eof)(&input[offset..])?;
+        match (class, kind) {
+            // Filename thread
+            (3, 0) => {
+                name = String::from_utf8_lossy(&thread[..(*eof as usize).min(thread.len())])
+                    .to_string()
+            }
+            // Data fork or disk image
+            (2, 0) | (2, 1) => data_fork = Some((offset, *format, *eof, *compressed_eof)),
+            _ => (),
+        }
+        offset += *compressed_eof as usize;
+    }
+
+    let (rest, _) = take(offset)(input)?;
+    let entry = match data_fork {
+        Some((start, format, eof, compressed_eof)) => self::entry(
+            name,
+            nufx_format(format),
+            Some(u64::from(eof)),
+            Some(u64::from(compressed_eof)),
+            (format == 0).then_some((start, eof as usize)),
+        ),
+        None => self::entry(name, "none", None, None, None),
+    };
+
+    Ok((rest, entry))
+}
+
+/// List a ShrinkIt archive
+fn nufx_entries(data: &[u8]) -> Option<Vec<ArchiveEntry>> {
+    let (_, total_records) = le_u32::<_, nom::error::Error<&[u8]>>(data.get(8..)?).ok()?;
+    let mut i = data.get(48..)?;
+    let mut entries = Vec::new();
+
+    for _ in 0..total_records {
+        let start = data.len() - i.len();
+        let (rest, mut entry) = nufx_record_parser(i).ok()?;
+        entry.stored = entry
+            .stored
+            .map(|(offset, length)| (start + offset, length));
+        entries.push(entry);
+        i = rest;
+    }
+
+    Some(entries)
+}
+
+/// Return true if the data starts with an LHA header
+fn is_lha(data: &[u8]) -> bool {
+    data.len() > 21
+        && data[2] == b'-'
+        && data[6] == b'-'
+        && (&data[3..5] == b"lh" || &data[3..5] == b"lz")
+        && data[20] <= 2
+}
+
+/// Follow a chain of LHA extended headers
+/// Each is a type byte, the header data and the size of the next
+/// header.  Returns the offset after the chain and the file name, if
+/// one of the headers holds it.
+fn lha_extended_headers(
+    input: &[u8],
+    mut start: usize,
+    mut next: u16,
+) -> IResult<&[u8], (usize, Option<String>)> {
+    let mut name = None;
+
+    while next != 0 {
+        if next < 3 || start > input.len() {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (_, extended) = take(next)(&input[start..])?;
+        let (data, header_type) = le_u8(extended)?;
+        let (data, next_bytes) = data.split_at(data.len() - 2);
+        if header_type == 1 {
+            name = Some(String::from_utf8_lossy(data).to_string());
+        }
+        start += usize::from(next);
+        next = u16::from_le_bytes([next_bytes[0], next_bytes[1]]);
+    }
+
+    Ok((input, (start, name)))
+}
+
+/// Parse an LHA header, returning the entry and the remaining input
+fn lha_header_parser(input: &[u8]) -> IResult<&[u8], ArchiveEntry> {
+    let (i, header_size) = le_u8(input)?;
+    let (i, _checksum) = le_u8(i)?;
+    let (i, method) = take(5_usize)(i)?;
+    let (i, compressed_size) = le_u32(i)?;
+    let (i, size) = le_u32(i)?;
+    let (i, _time) = le_u32(i)?;
+    let (i, _attribute) = le_u8(i)?;
+    let (i, level) = le_u8(i)?;
+    let method = String::from_utf8_lossy(method).to_string();
+    let base_size = usize::from(header_size) + 2;
+
+    let (data_start, name) = match level {
+        0 => {
+            let (i, name_length) = le_u8(i)?;
+            let (_i, name) = take(name_length)(i)?;
+            (base_size, String::from_utf8_lossy(name).to_string())
+        }
+        1 => {
+            // Extended headers follow the base header and are counted
+            // in the compressed size
+            let (i, name_length) = le_u8(i)?;
+            let (_i, name) = take(name_length)(i)?;
+            let (_, base) = take(base_size)(input)?;
+            let next = u16::from_le_bytes([base[base_size - 2], base[base_size - 1]]);
+            let (_, (start, _)) = lha_extended_headers(input, base_size, next)?;
+            (start, String::from_utf8_lossy(name).to_string())
+        }
+        _ => {
+            // Level 2 headers have a 16-bit size and keep the name in
+            // an extended header
+            let (_, (base, _)) = nom::sequence::pair(le_u16, take(22_usize))(input)?;
+            let (_, next) = le_u16(&input[24..])?;
+            let (_, (_, name)) = lha_extended_headers(input, 26, next)?;
+            (usize::from(base), name.unwrap_or_default())
+        }
+    };
+
+    let data_length = match level {
+        1 => (compressed_size as usize).saturating_sub(data_start - base_size),
+        _ => compressed_size as usize,
+    };
+    let (rest, _) = take(data_start + data_length)(input)?;
+    let stored = (method == "-lh0-" || method == "-lz4-").then_some((data_start, data_length));
+
+    Ok((
+        rest,
+        entry(
+            name,
+            &method,
+            Some(u64::from(size)),
+            Some(data_length as u64),
+            stored,
+        ),
+    ))
+}
+
+/// List an LHA archive
+fn lha_entries(data: &[u8]) -> Option<Vec<ArchiveEntry>> {
+    let mut i = data;
+    let mut entries = Vec::new();
+
+    while i.first().is_some_and(|size| *size != 0) {
+        let start = data.len() - i.len();
+        let (rest, mut entry) = lha_header_parser(i).ok()?;
+        entry.stored = entry
+            .stored
+            .map(|(offset, length)| (start + offset, length));
+        entries.push(entry);
+        i = rest;
+    }
+
+    Some(entries)
+}
+
+/// The name of a ZIP compression method
+fn zip_method(method: u16) -> &'static str {
+    match method {
+        0 => "stored",
+        1 => "shrunk",
+        2..=5 => "reduced",
+        6 => "imploded",
+        8 => "deflated",
+        _ => "unknown",
+    }
+}
+
+/// Parse a ZIP local file header, returning the entry and the remaining
+/// input
+fn zip_header_parser(input: &[u8]) -> IResult<&[u8], ArchiveEntry> {
+    let (i, _) = tag(b"PK\x03\x04")(input)?;
+    let (i, _version) = le_u16(i)?;
+    let (i, flags) = le_u16(i)?;
+    let (i, method) = le_u16(i)?;
+    let (i, _time) = le_u16(i)?;
+    let (i, _date) = le_u16(i)?;
+    let (i, _crc) = le_u32(i)?;
+    let (i, compressed_size) = le_u32(i)?;
+    let (i, size) = le_u32(i)?;
+    let (i, name_length) = le_u16(i)?;
+    let (i, extra_length) = le_u16(i)?;
+    let (i, name) = take(name_length)(i)?;
+    let (i, _extra) = take(extra_length)(i)?;
+
+    // Sizes after the data can't be followed from the local headers
+    if (flags & 0x08) != 0 {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let start = input.len() - i.len();
+    let (rest, _) = take(compressed_size)(i)?;
+
+    Ok((
+        rest,
+        entry(
+            String::from_utf8_lossy(name).to_string(),
+            zip_method(method),
+            Some(u64::from(size)),
+            Some(u64::from(compressed_size)),
+            (method == 0).then_some((start, size as usize)),
+        ),
+    ))
+}
+
+/// List a ZIP archive from its local file headers
+fn zip_entries(data: &[u8]) -> Option<Vec<ArchiveEntry>> {
+    let mut i = data;
+    let mut entries = Vec::new();
+
+    while i.starts_with(b"PK\x03\x04") {
+        let start = data.len() - i.len();
+        let (rest, mut entry) = zip_header_parser(i).ok()?;
+        entry.stored = entry
+            .stored
+            .map(|(offset, length)| (start + offset, length));
+        entries.push(entry);
+        i = rest;
+    }
+
+    Some(entries)
+}
+
+/// Recognize an archive by its signature
+pub fn identify_archive(data: &[u8]) -> Option<ArchiveKind> {
+    if data.starts_with(b"PK\x03\x04") {
+        Some(ArchiveKind::Zip)
+    } else if data.starts_with(&NUFX_MASTER) {
+        Some(ArchiveKind::ShrinkIt)
+    } else if is_lha(data) {
+        Some(ArchiveKind::Lha)
+    } else if is_lynx(data) {
+        Some(ArchiveKind::Lynx)
+    } else if is_ark(data) {
+        Some(ArchiveKind::Ark)
+    } else {
+        None
+    }
+}
+
+/// List the files in an archive, looking inside stored entries for
+/// nested archives, decoding PETSCII file names with charset
+pub fn list_archive(data: &[u8], charset: &Charset) -> std::result::Result<Archive, Error> {
+    list_nested(data, 0, charset)
+}
+
+/// List an archive and its nested archives, up to a depth
+/// Nested archives past the nesting or expansion limits and entries
+/// that are compressed aren't expanded.
+fn list_nested(
+    data: &[u8],
+    depth: usize,
+    charset: &Charset,
+) -> std::result::Result<Archive, Error> {
+    let kind = identify_archive(data)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound(String::from("No archive signature"))))?;
+    let entries = match kind {
+        ArchiveKind::Lynx => lynx_entries(data, charset),
+        ArchiveKind::Ark => ark_entries(data, charset),
+        ArchiveKind::ShrinkIt => nufx_entries(data),
+        ArchiveKind::Lha => lha_entries(data),
+        ArchiveKind::Zip => zip_entries(data),
+    }
+    .ok_or_else(|| {
+        Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+            "Damaged {} archive directory",
+            kind
+        ))))
+    })?;
+
+    let mut archive = Archive { kind, entries };
+    for n in 0..archive.entries.len() {
+        let Some(stored) = archive.stored_data(data, &archive.entries[n]) else {
+            continue;
+        };
+        if identify_archive(stored).is_none() {
+            archive.entries[n].expanded = true;
+            continue;
+        }
+        if check_nesting_depth(depth + 1).is_err() || check_expanded_size(stored.len()).is_err() {
+            break;
+        }
+        let nested = list_nested(stored, depth + 1, charset).ok();
+        archive.entries[n].nested = nested.map(Box::new);
+        archive.entries[n].expanded = true;
+    }
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{identify_archive, list_archive, ArchiveKind, NUFX_MASTER, NUFX_RECORD};
+    use crate::disk_format::charset::Charset;
+    use crate::disk_format::limits::{check_file_size, Limits};
+
+    /// Build a stored ZIP entry
+    fn zip_entry(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut entry = b"PK\x03\x04".to_vec();
+        entry.extend_from_slice(&[10, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        entry.extend_from_slice(&[0, 0]);
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    /// Build an LHA level 0 entry
+    fn lha_entry(name: &str, method: &[u8; 5], data: &[u8], size: u32) -> Vec<u8> {
+        let mut entry = vec![(22 + name.len()) as u8, 0];
+        entry.extend_from_slice(method);
+        entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&size.to_le_bytes());
+        entry.extend_from_slice(&[0; 4]);
+        entry.extend_from_slice(&[0x20, 0]);
+        entry.push(name.len() as u8);
+        entry.extend_from_slice(name.as_bytes());
+        entry.extend_from_slice(&[0, 0]);
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    /// Test listing ZIP and LHA archives, with a ZIP stored inside an
+    /// LHA archive
+    #[test]
+    fn zip_and_lha_work() {
+        let mut zip = zip_entry("README.TXT", b"HELLO");
+        zip.extend(zip_entry("GAME.EXE", b"MZ"));
+        let archive = list_archive(&zip, &Charset::DEFAULT).unwrap();
+        assert_eq!(archive.kind, ArchiveKind::Zip);
+        assert_eq!(archive.entries.len(), 2);
+        assert_eq!(archive.entries[1].name, "GAME.EXE");
+        assert_eq!(
+            archive.stored_data(&zip, &archive.entries[0]),
+            Some(&b"HELLO"[..])
+        );
+
+        let mut lha = lha_entry("DISK.ZIP", b"-lh0-", &zip, zip.len() as u32);
+        lha.extend(lha_entry("DOCS.TXT", b"-lh5-", &[1, 2, 3], 100));
+        lha.push(0);
+        assert_eq!(identify_archive(&lha), Some(ArchiveKind::Lha));
+        let archive = list_archive(&lha, &Charset::DEFAULT).unwrap();
+        assert_eq!(archive.entries.len(), 2);
+        assert_eq!(archive.entries[1].method, "-lh5-");
+        assert_eq!(archive.entries[1].size, Some(100));
+        assert!(archive.entries[0].expanded);
+        assert!(!archive.entries[1].expanded);
+        let nested = archive.entries[0].nested.as_ref().unwrap();
+        assert_eq!(nested.kind, ArchiveKind::Zip);
+        assert_eq!(nested.entries[0].name, "README.TXT");
+        assert!(archive
+            .to_string()
+            .contains("    README.TXT (stored, 5 bytes)"));
+        assert!(archive
+            .to_string()
+            .contains("  DOCS.TXT (-lh5-, 100 bytes, not expanded)"));
+
+        assert_eq!(identify_archive(b"HELLO WORLD"), None);
+        assert!(list_archive(b"HELLO WORLD", &Charset::DEFAULT).is_err());
+    }
+
+    /// Test that nested archives are only listed to the nesting limit
+    #[test]
+    fn nesting_limit_works() {
+        let mut zip = zip_entry("README.TXT", b"HELLO");
+        for level in 0..8 {
+            zip = zip_entry(&format!("LEVEL{}.ZIP", level), &zip);
+        }
+
+        check_file_size(zip.len()).unwrap();
+        let mut archive = list_archive(&zip, &Charset::DEFAULT).unwrap();
+        let mut depth = 0;
+        while let Some(nested) = archive.entries[0].nested.take() {
+            archive = *nested;
+            depth += 1;
+        }
+        assert_eq!(depth, Limits::DEFAULT.max_nesting_depth);
+        assert_eq!(archive.entries[0].name, "LEVEL3.ZIP");
+        assert!(!archive.entries[0].expanded);
+    }
+
+    /// Test listing Commodore and Apple archives
+    #[test]
+    fn lynx_and_shrinkit_work() {
+        // A Lynx archive with a one block directory and two files
+        let mut lynx = vec![0x01, 0x08, 0x0B, 0x08, 0x0A, 0x00, 0x9E];
+        lynx.extend_from_slice(b"2061 LYNX\x00\x00\x00\x0D");
+        lynx.extend_from_slice(b" 1  *LYNX XII\x0D 2 \x0D");
+        lynx.extend_from_slice(
+            b"HELLO\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\x0D 1 \x0DP\x0D 11 \x0D",
+        );
+        lynx.extend_from_slice(
+            b"NOTES\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\xA0\x0D 2 \x0DS\x0D 3 \x0D",
+        );
+        lynx.resize(254, 0);
+        lynx.extend_from_slice(b"0123456789");
+        lynx.resize(254 * 4, 0);
+        assert_eq!(identify_archive(&lynx), Some(ArchiveKind::Lynx));
+        let archive = list_archive(&lynx, &Charset::DEFAULT).unwrap();
+        assert_eq!(archive.entries.len(), 2);
+        assert_eq!(archive.entries[0].name, "HELLO");
+        assert_eq!(
+            archive.stored_data(&lynx, &archive.entries[0]),
+            Some(&b"0123456789"[..])
+        );
+        assert_eq!(archive.entries[1].size, Some(256));
+
+        // A ShrinkIt archive with one record: a filename thread and an
+        // LZW/2 data fork
+        let mut shk = NUFX_MASTER.to_vec();
+        shk.extend_from_slice(&[0, 0, 1, 0, 0, 0]);
+        shk.resize(48, 0);
+        let record = shk.len();
+        shk.extend_from_slice(&NUFX_RECORD);
+        shk.extend_from_slice(&[0, 0, 58, 0, 3, 0, 2, 0, 0, 0]);
+        shk.resize(record + 56, 0);
+        shk.extend_from_slice(&[0, 0]);
+        shk.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 16, 0, 0, 0]);
+        shk.extend_from_slice(&[2, 0, 3, 0, 0, 0, 0, 0, 0, 2, 0, 0, 4, 0, 0, 0]);
+        let mut name = b"SYSTEM".to_vec();
+        name.resize(16, 0);
+        shk.extend(name);
+        shk.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(identify_archive(&shk), Some(ArchiveKind::ShrinkIt));
+        let archive = list_archive(&shk, &Charset::DEFAULT).unwrap();
+        assert_eq!(archive.entries[0].name, "SYSTEM");
+        assert_eq!(archive.entries[0].method, "LZW/2");
+        assert_eq!(archive.entries[0].size, Some(512));
+    }
+}
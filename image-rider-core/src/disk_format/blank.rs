@@ -0,0 +1,240 @@
+//! Blank formatted images and their fill patterns
+//!
+//! Formatting a disk fills every sector with a pattern, and some
+//! software checks for that pattern to tell a freshly formatted disk
+//! or track from a used one.  CP/M formats with 0xE5, which also marks
+//! its directory entries unused, most other systems with zeros, and
+//! some formatters write a different pattern on particular tracks.
+//! The patterns can be loaded from the configuration, with a pattern
+//! for the whole disk and patterns for single tracks:
+//!
+//! ```toml
+//! fill-pattern = "cpm"
+//! track-fill-patterns = "0=00,17=DB6D"
+//! ```
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
+
+use config::Config;
+use log::warn;
+
+use crate::disk_format::geometry::Geometry;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::CONVERT;
+
+/// The byte CP/M fills formatted sectors with
+pub const CPM_FILL: u8 = 0xE5;
+
+/// The byte DOS 3.3 style formatters fill sectors with
+pub const DOS_33_FILL: u8 = 0x01;
+
+/// A pattern repeated through each sector when it's formatted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FillPattern {
+    /// The bytes of the pattern, never empty
+    bytes: Vec<u8>,
+}
+
+/// Build an error for a pattern that can't be read
+fn pattern_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+impl FillPattern {
+    /// A pattern of a single byte
+    pub fn byte(byte: u8) -> FillPattern {
+        FillPattern { bytes: vec![byte] }
+    }
+
+    /// A pattern of a sequence of bytes
+    /// Returns an error if the sequence is empty.
+    pub fn new(bytes: &[u8]) -> std::result::Result<FillPattern, Error> {
+        if bytes.is_empty() {
+            return Err(pattern_error(String::from("A fill pattern can't be empty")));
+        }
+        Ok(FillPattern {
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// The bytes of the pattern
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Return the pattern repeated to a length, starting from its first
+    /// byte
+    pub fn fill(&self, length: usize) -> Vec<u8> {
+        self.bytes.iter().copied().cycle().take(length).collect()
+    }
+}
+
+/// Zeros, as most systems format with
+impl Default for FillPattern {
+    fn default() -> FillPattern {
+        FillPattern::byte(0)
+    }
+}
+
+/// Parse a pattern from a name, "zero", "cpm" or "dos33", or from
+/// hex bytes, e.g. "E5" or "DB6D"
+impl FromStr for FillPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<FillPattern, Error> {
+        match s.to_lowercase().as_str() {
+            "zero" => return Ok(FillPattern::byte(0)),
+            "cpm" => return Ok(FillPattern::byte(CPM_FILL)),
+            "dos33" => return Ok(FillPattern::byte(DOS_33_FILL)),
+            _ => (),
+        }
+        let hex = s.trim_start_matches("0x");
+        if hex.len() % 2 != 0 {
+            return Err(pattern_error(format!("Unknown fill pattern: {}", s)));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| pattern_error(format!("Unknown fill pattern: {}", s)))
+            })
+            .collect::<std::result::Result<Vec<u8>, Error>>()?;
+        FillPattern::new(&bytes)
+    }
+}
+
+/// Display a pattern as hex bytes
+impl Display for FillPattern {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for byte in &self.bytes {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The fill patterns for formatting a disk
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FormatPatterns {
+    /// The pattern for tracks without their own pattern
+    pub fill: FillPattern,
+    /// Patterns for single tracks, by track number
+    pub tracks: BTreeMap<u8, FillPattern>,
+}
+
+impl FormatPatterns {
+    /// Patterns that fill every track the same way
+    pub fn new(fill: FillPattern) -> FormatPatterns {
+        FormatPatterns {
+            fill,
+            tracks: BTreeMap::new(),
+        }
+    }
+
+    /// Build the patterns from a configuration, with fill-pattern for
+    /// the disk and track-fill-patterns as a list of track=pattern
+    /// pairs
+    /// Settings that can't be read are skipped with a warning.
+    pub fn from_config(config: &Config) -> FormatPatterns {
+        let mut patterns = FormatPatterns::default();
+        if let Ok(fill) = config.get_string("fill-pattern") {
+            match fill.parse() {
+                Ok(fill) => patterns.fill = fill,
+                Err(e) => warn!(target: CONVERT, "{}, filling with zeros", e),
+            }
+        }
+        if let Ok(tracks) = config.get_string("track-fill-patterns") {
+            for pair in tracks.split(',').filter(|pair| !pair.trim().is_empty()) {
+                match parse_track_pattern(pair.trim()) {
+                    Ok((track, pattern)) => {
+                        patterns.tracks.insert(track, pattern);
+                    }
+                    Err(e) => warn!(target: CONVERT, "{}, skipping it", e),
+                }
+            }
+        }
+        patterns
+    }
+
+    /// Return the pattern for a track
+    pub fn for_track(&self, track: u8) -> &FillPattern {
+        self.tracks.get(&track).unwrap_or(&self.fill)
+    }
+}
+
+/// Parse a track=pattern pair
+fn parse_track_pattern(pair: &str) -> std::result::Result<(u8, FillPattern), Error> {
+    let (track, pattern) = pair.split_once('=').ok_or_else(|| {
+        pattern_error(format!("Track fill pattern isn't track=pattern: {}", pair))
+    })?;
+    let track = track
+        .trim()
+        .parse()
+        .map_err(|_| pattern_error(format!("Unknown track in fill pattern: {}", pair)))?;
+    Ok((track, pattern.trim().parse()?))
+}
+
+/// Build a blank flat image in a geometry, with every sector filled
+/// with its track's pattern
+/// The pattern starts again at the start of each sector, as it does
+/// when a sector is formatted.
+pub fn blank_image(geometry: &Geometry, patterns: &FormatPatterns) -> Vec<u8> {
+    let mut data = Vec::with_capacity(geometry.total_size());
+    for id in geometry.sector_ids() {
+        data.extend(
+            patterns
+                .for_track(id.track.get())
+                .fill(geometry.sector_size),
+        );
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{blank_image, FillPattern, FormatPatterns};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+
+    /// Test parsing patterns and building blank images with a pattern
+    /// for one track
+    #[test]
+    fn blank_image_works() {
+        assert_eq!("cpm".parse::<FillPattern>().unwrap().bytes(), [0xE5]);
+        assert_eq!(
+            "0xDB6D".parse::<FillPattern>().unwrap().bytes(),
+            [0xDB, 0x6D]
+        );
+        assert!("".parse::<FillPattern>().is_err());
+        assert!("E".parse::<FillPattern>().is_err());
+        assert!("XY".parse::<FillPattern>().is_err());
+
+        let config = Config::builder()
+            .set_override("fill-pattern", "dos33")
+            .unwrap()
+            .set_override("track-fill-patterns", "17=DB6D, 3=zero, bad")
+            .unwrap()
+            .build()
+            .unwrap();
+        let patterns = FormatPatterns::from_config(&config);
+        assert_eq!(patterns.tracks.len(), 2);
+        assert_eq!(patterns.for_track(17).to_string(), "DB6D");
+
+        let geometry = Geometry::apple_dos_33(35);
+        let data = blank_image(&geometry, &patterns);
+        assert_eq!(data.len(), 143360);
+        assert_eq!(
+            geometry.sector(&data, &SectorId::new(0, 0, 0)),
+            Some(&[0x01; 256][..])
+        );
+        let sector = geometry.sector(&data, &SectorId::new(17, 0, 5)).unwrap();
+        assert_eq!(&sector[..4], [0xDB, 0x6D, 0xDB, 0x6D]);
+        assert_eq!(
+            geometry.sector(&data, &SectorId::new(3, 0, 15)),
+            Some(&[0; 256][..])
+        );
+    }
+}
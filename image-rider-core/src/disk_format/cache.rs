@@ -0,0 +1,232 @@
+//! A cache of parsed results keyed by image content
+//!
+//! Indexing a large collection parses the same images over and over.
+//! The cache maps a hash of each image's contents to a result, like a
+//! report, so unchanged files are skipped on later runs.  Results are
+//! kept in memory with least recently used eviction, and optionally
+//! written to a directory as JSON so they survive between runs.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter, Result};
+use std::fs;
+use std::path::PathBuf;
+
+use log::debug;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::disk_format::checksum::{crc32, fnv1a_64};
+use crate::error::Error;
+use crate::log_target::IO;
+
+/// A hash of an image's contents
+/// Combines the length with two independent hashes, so files that
+/// differ are practically never confused.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ContentHash {
+    /// The length of the data
+    pub length: u64,
+    /// The CRC-32 of the data
+    pub crc32: u32,
+    /// The FNV-1a hash of the data
+    pub fnv1a: u64,
+}
+
+impl ContentHash {
+    /// Hash a block of data
+    pub fn new(data: &[u8]) -> ContentHash {
+        ContentHash {
+            length: data.len() as u64,
+            crc32: crc32(data),
+            fnv1a: fnv1a_64(data),
+        }
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{:x}-{:08x}-{:016x}",
+            self.length, self.crc32, self.fnv1a
+        )
+    }
+}
+
+/// A cache of results keyed by content hash
+pub struct ContentCache<T> {
+    /// The maximum number of results kept in memory
+    capacity: usize,
+    /// The results in memory
+    entries: HashMap<ContentHash, T>,
+    /// The hashes in memory, least recently used first
+    order: VecDeque<ContentHash>,
+    /// The directory results are stored in, if any
+    directory: Option<PathBuf>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> ContentCache<T> {
+    /// Create an in-memory cache holding up to capacity results
+    pub fn new(capacity: usize) -> ContentCache<T> {
+        ContentCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            directory: None,
+        }
+    }
+
+    /// Create a cache that also stores results in a directory
+    /// The directory is created if it doesn't exist.
+    pub fn with_directory(
+        capacity: usize,
+        directory: PathBuf,
+    ) -> std::result::Result<ContentCache<T>, Error> {
+        fs::create_dir_all(&directory)?;
+        let mut cache = ContentCache::new(capacity);
+        cache.directory = Some(directory);
+        Ok(cache)
+    }
+
+    /// The number of results in memory
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return true if there are no results in memory
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The file a result is stored in
+    fn path(&self, hash: &ContentHash) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{}.json", hash)))
+    }
+
+    /// Mark a hash as the most recently used
+    fn touch(&mut self, hash: &ContentHash) {
+        self.order.retain(|h| h != hash);
+        self.order.push_back(*hash);
+    }
+
+    /// Add a result to memory, evicting the least recently used results
+    /// if the cache is full
+    fn remember(&mut self, hash: ContentHash, value: T) {
+        self.entries.insert(hash, value);
+        self.touch(&hash);
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(old) => {
+                    self.entries.remove(&old);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Look up a result, in memory first and then in the directory
+    /// Unreadable or outdated files in the directory are treated as
+    /// missing.
+    pub fn get(&mut self, hash: &ContentHash) -> Option<T> {
+        if let Some(value) = self.entries.get(hash).cloned() {
+            self.touch(hash);
+            return Some(value);
+        }
+
+        let path = self.path(hash)?;
+        let json = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str::<T>(&json) {
+            Ok(value) => {
+                self.remember(*hash, value.clone());
+                Some(value)
+            }
+            Err(e) => {
+                debug!(target: IO, "Ignoring cache file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Store a result, in memory and in the directory
+    pub fn insert(&mut self, hash: ContentHash, value: T) -> std::result::Result<(), Error> {
+        if let Some(path) = self.path(&hash) {
+            fs::write(path, serde_json::to_string(&value)?)?;
+        }
+        self.remember(hash, value);
+
+        Ok(())
+    }
+
+    /// Return the cached result for some data, or build, store and
+    /// return it if there isn't one
+    pub fn get_or_insert_with<F>(&mut self, data: &[u8], build: F) -> std::result::Result<T, Error>
+    where
+        F: FnOnce() -> std::result::Result<T, Error>,
+    {
+        let hash = ContentHash::new(data);
+        if let Some(value) = self.get(&hash) {
+            return Ok(value);
+        }
+        let value = build()?;
+        self.insert(hash, value.clone())?;
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContentCache, ContentHash};
+
+    /// Test the in-memory cache and eviction
+    #[test]
+    fn content_cache_works() {
+        let mut cache: ContentCache<String> = ContentCache::new(2);
+        let (a, b, c) = (
+            ContentHash::new(b"a"),
+            ContentHash::new(b"b"),
+            ContentHash::new(b"c"),
+        );
+        assert_ne!(a, b);
+
+        cache.insert(a, String::from("first")).unwrap();
+        cache.insert(b, String::from("second")).unwrap();
+        // Using a makes b the least recently used
+        assert_eq!(cache.get(&a), Some(String::from("first")));
+        cache.insert(c, String::from("third")).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&a), Some(String::from("first")));
+
+        let mut built = 0;
+        for _ in 0..2 {
+            let value = cache
+                .get_or_insert_with(b"d", || {
+                    built += 1;
+                    Ok(String::from("fourth"))
+                })
+                .unwrap();
+            assert_eq!(value, "fourth");
+        }
+        assert_eq!(built, 1);
+    }
+
+    /// Test that results stored in a directory are found by a new cache
+    #[test]
+    fn content_cache_directory_works() {
+        let directory =
+            std::env::temp_dir().join(format!("image-rider-cache-test-{}", std::process::id()));
+        let hash = ContentHash::new(b"disk");
+
+        let mut cache: ContentCache<Vec<u8>> =
+            ContentCache::with_directory(1, directory.clone()).unwrap();
+        cache.insert(hash, vec![1, 2, 3]).unwrap();
+
+        let mut cache: ContentCache<Vec<u8>> =
+            ContentCache::with_directory(1, directory.clone()).unwrap();
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&hash), Some(vec![1, 2, 3]));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//! Cancelling long operations and following their progress
+//!
+//! Converting, carving and comparing a collection of images can take
+//! minutes.  These operations take a CancellationToken and check it
+//! between tracks, files or images, stopping with ErrorKind::Cancelled
+//! once it's cancelled.  A host like a GUI keeps a clone of the token,
+//! cancels it from another thread and reads the progress to show how
+//! far the operation has got.
+//!
+//! ```
+//! use image_rider_core::disk_format::cancel::CancellationToken;
+//!
+//! let token = CancellationToken::new();
+//! let host = token.clone();
+//! token.start(2);
+//! token.advance();
+//! assert_eq!(host.progress(), (1, 2));
+//! host.cancel();
+//! assert!(token.check().is_err());
+//! ```
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Error, ErrorKind};
+
+/// The shared state of a token and its clones
+#[derive(Debug, Default)]
+struct TokenState {
+    /// Set once the operation should stop
+    cancelled: AtomicBool,
+    /// The number of steps finished
+    done: AtomicUsize,
+    /// The number of steps in the operation, zero if it isn't known
+    total: AtomicUsize,
+}
+
+/// A token for cancelling an operation and following its progress
+/// Clones share the same state, so a token cancelled through one clone
+/// is cancelled for all of them.  The default token is never cancelled
+/// unless cancel is called.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    /// The shared state
+    state: Arc<TokenState>,
+}
+
+impl CancellationToken {
+    /// Create a token that isn't cancelled
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Ask the operation to stop at the next track, file or image
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Return true if the operation should stop
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Return an error if the operation should stop
+    /// Operations call this between steps and return the error.
+    pub fn check(&self) -> std::result::Result<(), Error> {
+        if self.is_cancelled() {
+            Err(Error::new(ErrorKind::Cancelled))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start counting the progress of an operation with a number of
+    /// steps
+    pub fn start(&self, total: usize) {
+        self.state.done.store(0, Ordering::Relaxed);
+        self.state.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Record a finished step
+    pub fn advance(&self) {
+        self.state.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the number of steps finished so far
+    pub fn advance_to(&self, done: usize) {
+        self.state.done.store(done, Ordering::Relaxed);
+    }
+
+    /// Return the number of steps finished and the number of steps in
+    /// the operation
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.state.done.load(Ordering::Relaxed),
+            self.state.total.load(Ordering::Relaxed),
+        )
+    }
+}
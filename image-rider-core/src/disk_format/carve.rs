@@ -0,0 +1,621 @@
+//! File carving for disks with damaged filesystems
+//!
+//! When a catalog or directory is destroyed the file data is often
+//! still on the disk.  Carving scans the sectors for recognizable
+//! content and rebuilds probable files from them:
+//!
+//! - Microsoft BASIC programs (Applesoft and Commodore BASIC), found by
+//!   following the chain of line pointers
+//! - Machine code with a load address header, scored by how much of it
+//!   decodes as legal 6502 instructions
+//! - Runs of text
+//!
+//! Commodore disks link every sector of a file to the next one in the
+//! first two bytes of the sector, so files are rebuilt by following
+//! the links.  Apple DOS disks have no links, files are rebuilt from
+//! the length in the file header, assuming the sectors were allocated
+//! in order.  Every carved file has a confidence between zero and one.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+
+use log::debug;
+
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::logical::{split_tracks, LogicalTrack};
+use crate::error::Error;
+use crate::log_target::PARSE;
+
+/// The kind of content found in a carved file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentKind {
+    /// An Applesoft BASIC program
+    AppleSoftBasic,
+    /// A Commodore BASIC program
+    CommodoreBasic,
+    /// 6502 machine code
+    MachineCode,
+    /// Text
+    Text,
+}
+
+impl Display for ContentKind {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ContentKind::AppleSoftBasic => write!(f, "Applesoft BASIC"),
+            ContentKind::CommodoreBasic => write!(f, "Commodore BASIC"),
+            ContentKind::MachineCode => write!(f, "machine code"),
+            ContentKind::Text => write!(f, "text"),
+        }
+    }
+}
+
+/// A file rebuilt from sectors
+#[derive(Clone, Debug, PartialEq)]
+pub struct CarvedFile {
+    /// The kind of content in the file
+    pub kind: ContentKind,
+    /// The sectors the file was built from, in file order
+    pub sectors: Vec<SectorId>,
+    /// The file data, without any disk-specific header
+    pub data: Vec<u8>,
+    /// The load address from the file header, if there is one
+    pub load_address: Option<u16>,
+    /// How likely this is a real file, between zero and one
+    pub confidence: f32,
+}
+
+impl Display for CarvedFile {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}, {} bytes in {} sectors starting at {}",
+            self.kind,
+            self.data.len(),
+            self.sectors.len(),
+            self.sectors
+                .first()
+                .map(|id| id.to_string())
+                .unwrap_or_default()
+        )?;
+        if let Some(address) = self.load_address {
+            write!(f, ", load address: 0x{:04X}", address)?;
+        }
+        write!(f, ", confidence: {:.2}", self.confidence)
+    }
+}
+
+/// Instruction lengths for the legal 6502 opcodes, zero for illegal
+/// opcodes
+const OPCODE_LENGTHS: [u8; 256] = build_opcode_lengths();
+
+/// Build the 6502 opcode length table
+const fn build_opcode_lengths() -> [u8; 256] {
+    let one: [u8; 29] = [
+        0x00, 0x08, 0x18, 0x28, 0x38, 0x40, 0x48, 0x58, 0x60, 0x68, 0x78, 0x88, 0x8A, 0x98, 0x9A,
+        0xA8, 0xAA, 0xB8, 0xBA, 0xC8, 0xCA, 0xD8, 0xE8, 0xEA, 0xF8, 0x0A, 0x2A, 0x4A, 0x6A,
+    ];
+    let two: [u8; 64] = [
+        0x09, 0x29, 0x49, 0x69, 0xA9, 0xC9, 0xE9, 0xA0, 0xA2, 0xC0, 0xE0, 0x05, 0x25, 0x45, 0x65,
+        0x85, 0xA5, 0xC5, 0xE5, 0x06, 0x26, 0x46, 0x66, 0x86, 0xA6, 0xC6, 0xE6, 0x24, 0x84, 0xA4,
+        0xC4, 0xE4, 0x15, 0x35, 0x55, 0x75, 0x95, 0xB5, 0xD5, 0xF5, 0x16, 0x36, 0x56, 0x76, 0x96,
+        0xB6, 0xD6, 0xF6, 0x94, 0xB4, 0x01, 0x21, 0x41, 0x61, 0x81, 0xA1, 0xC1, 0xE1, 0x11, 0x31,
+        0x51, 0x71, 0x91, 0xB1,
+    ];
+    let two_more: [u8; 10] = [0xD1, 0xF1, 0x10, 0x30, 0x50, 0x70, 0x90, 0xB0, 0xD0, 0xF0];
+    let three: [u8; 48] = [
+        0x0D, 0x2D, 0x4D, 0x6D, 0x8D, 0xAD, 0xCD, 0xED, 0x0E, 0x2E, 0x4E, 0x6E, 0x8E, 0xAE, 0xCE,
+        0xEE, 0x2C, 0x8C, 0xAC, 0xCC, 0xEC, 0x4C, 0x20, 0x6C, 0x1D, 0x3D, 0x5D, 0x7D, 0x9D, 0xBD,
+        0xDD, 0xFD, 0x1E, 0x3E, 0x5E, 0x7E, 0xDE, 0xFE, 0xBC, 0xBE, 0x19, 0x39, 0x59, 0x79, 0x99,
+        0xB9, 0xD9, 0xF9,
+    ];
+
+    let mut lengths = [0_u8; 256];
+    let mut i = 0;
+    while i < one.len() {
+        lengths[one[i] as usize] = 1;
+        i += 1;
+    }
+    i = 0;
+    while i < two.len() {
+        lengths[two[i] as usize] = 2;
+        i += 1;
+    }
+    i = 0;
+    while i < two_more.len() {
+        lengths[two_more[i] as usize] = 2;
+        i += 1;
+    }
+    i = 0;
+    while i < three.len() {
+        lengths[three[i] as usize] = 3;
+        i += 1;
+    }
+    lengths
+}
+
+/// Score how much of the data decodes as legal 6502 instructions,
+/// between zero and one
+/// Runs of zero bytes (BRK) count against the score, they're far more
+/// likely to be empty space than code.
+pub fn machine_code_score(data: &[u8]) -> f32 {
+    let mut legal = 0;
+    let mut illegal = 0;
+    let mut i = 0;
+
+    while i < data.len() {
+        let length = usize::from(OPCODE_LENGTHS[usize::from(data[i])]);
+        let zero_run = data[i] == 0x00 && data.get(i + 1) == Some(&0x00);
+        if length == 0 || zero_run || i + length > data.len() {
+            illegal += 1;
+            i += 1;
+        } else {
+            legal += length;
+            i += length;
+        }
+    }
+
+    if legal + illegal == 0 {
+        0.0
+    } else {
+        legal as f32 / (legal + illegal) as f32
+    }
+}
+
+/// Return true for printable ASCII, Apple high ASCII or line endings
+fn is_text_byte(byte: u8) -> bool {
+    matches!(byte & 0x7F, 0x20..=0x7E | 0x0D | 0x0A)
+}
+
+/// Score how much of the data is text, between zero and one
+/// Data after the first zero byte is ignored, zero ends a text file.
+pub fn text_score(data: &[u8]) -> f32 {
+    let end = data.iter().position(|b| *b == 0x00).unwrap_or(data.len());
+    if end == 0 {
+        return 0.0;
+    }
+    data[..end].iter().filter(|b| is_text_byte(**b)).count() as f32 / end as f32
+}
+
+/// Check the line pointer chain of a Microsoft BASIC program
+/// Each line starts with a pointer to the next line and a line
+/// number, followed by the tokenized line ending in a zero byte.  The
+/// last line pointer is zero.
+/// Returns the number of lines that were consistent, and true if the
+/// end of the program was found.
+pub fn basic_lines(program: &[u8], load_address: u16) -> (usize, bool) {
+    let mut offset = 0;
+    let mut lines = 0;
+    let mut previous_line_number: Option<u16> = None;
+
+    while offset + 2 <= program.len() {
+        let next = u16::from_le_bytes([program[offset], program[offset + 1]]);
+        if next == 0 {
+            return (lines, lines > 0);
+        }
+        if offset + 4 > program.len() {
+            break;
+        }
+        let line_number = u16::from_le_bytes([program[offset + 2], program[offset + 3]]);
+        if line_number > 63999 || previous_line_number.is_some_and(|p| line_number <= p) {
+            break;
+        }
+        let end = match program[offset + 4..].iter().position(|b| *b == 0x00) {
+            Some(e) => offset + 4 + e,
+            None => break,
+        };
+        let next_offset = usize::from(next.wrapping_sub(load_address));
+        if next < load_address || next_offset != end + 1 {
+            break;
+        }
+        lines += 1;
+        previous_line_number = Some(line_number);
+        offset = next_offset;
+    }
+
+    (lines, false)
+}
+
+/// Score a BASIC program by its line chain
+fn basic_confidence(program: &[u8], load_address: u16) -> Option<f32> {
+    match basic_lines(program, load_address) {
+        (0, _) => None,
+        (_, true) => Some(0.95),
+        (1, false) => Some(0.6),
+        (_, false) => Some(0.8),
+    }
+}
+
+/// The lowest machine code score that is accepted as code
+const MACHINE_CODE_THRESHOLD: f32 = 0.8;
+
+/// The lowest text score that is accepted as text
+const TEXT_THRESHOLD: f32 = 0.9;
+
+/// Classify the payload of a file with a two byte load address, as
+/// found in Commodore PRG files
+fn classify_program(payload: &[u8]) -> Option<(ContentKind, Option<u16>, f32)> {
+    if payload.len() >= 2 {
+        let address = u16::from_le_bytes([payload[0], payload[1]]);
+        if address == 0x0801 {
+            if let Some(confidence) = basic_confidence(&payload[2..], address) {
+                return Some((ContentKind::CommodoreBasic, Some(address), confidence));
+            }
+        }
+        let score = machine_code_score(&payload[2..]);
+        if score >= MACHINE_CODE_THRESHOLD && address >= 0x0200 {
+            return Some((ContentKind::MachineCode, Some(address), 0.3 + score * 0.5));
+        }
+    }
+
+    let score = text_score(payload);
+    if score >= TEXT_THRESHOLD {
+        return Some((ContentKind::Text, None, score * 0.7));
+    }
+
+    None
+}
+
+/// Rebuild files on a Commodore disk by following the sector links
+/// Chains start at sectors that no other sector links to.  Sectors
+/// on the directory track (18) are skipped.  The token is checked
+/// before each chain, progress counts the chains.
+pub fn carve_linked_sectors(
+    tracks: &[LogicalTrack],
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<CarvedFile>, Error> {
+    let mut sectors: BTreeMap<SectorId, &[u8]> = BTreeMap::new();
+    for track in tracks.iter().filter(|t| t.track != 18) {
+        for sector in track.sectors.iter().filter(|s| s.data.len() >= 2) {
+            sectors.insert(
+                SectorId::new(track.track, 0, sector.id.sector),
+                &sector.data,
+            );
+        }
+    }
+
+    let linked_to: BTreeSet<SectorId> = sectors
+        .values()
+        .filter(|data| data[0] != 0)
+        .map(|data| SectorId::new(data[0], 0, data[1]))
+        .collect();
+
+    let starts: Vec<&SectorId> = sectors
+        .keys()
+        .filter(|id| !linked_to.contains(id))
+        .collect();
+    cancel.start(starts.len());
+    let mut files = Vec::new();
+    for start in starts {
+        cancel.check()?;
+        cancel.advance();
+        let mut chain: Vec<SectorId> = Vec::new();
+        let mut payload: Vec<u8> = Vec::new();
+        let mut broken = false;
+        let mut id = *start;
+
+        loop {
+            let data = match sectors.get(&id) {
+                Some(d) if !chain.contains(&id) => d,
+                _ => {
+                    broken = true;
+                    break;
+                }
+            };
+            chain.push(id);
+            if data[0] == 0 {
+                // The last sector, the second byte is the index of the
+                // last byte used
+                let last = usize::from(data[1]).min(data.len() - 1);
+                if last >= 2 {
+                    payload.extend_from_slice(&data[2..=last]);
+                }
+                break;
+            }
+            payload.extend_from_slice(&data[2..]);
+            id = SectorId::new(data[0], 0, data[1]);
+        }
+
+        if payload.is_empty() {
+            continue;
+        }
+        if let Some((kind, load_address, confidence)) = classify_program(&payload) {
+            let confidence = if broken { confidence * 0.5 } else { confidence };
+            debug!(target: PARSE, "Carved {} file at {}", kind, start);
+            files.push(CarvedFile {
+                kind,
+                sectors: chain,
+                data: if load_address.is_some() {
+                    payload[2..].to_vec()
+                } else {
+                    payload
+                },
+                load_address,
+                confidence,
+            });
+        }
+    }
+
+    Ok(files)
+}
+
+/// Classify the first sector of an Apple DOS file
+/// Returns the kind of file, the load address, the number of bytes in
+/// the file including the header and a confidence
+fn classify_apple_start(data: &[u8]) -> Option<(ContentKind, Option<u16>, usize, f32)> {
+    if data.len() < 4 {
+        return None;
+    }
+    let first = u16::from_le_bytes([data[0], data[1]]);
+    let second = u16::from_le_bytes([data[2], data[3]]);
+
+    // Applesoft: length, then the program loaded at 0x0801
+    if (3..=0x9000).contains(&first) {
+        if let Some(confidence) = basic_confidence(&data[2..], 0x0801) {
+            return Some((
+                ContentKind::AppleSoftBasic,
+                Some(0x0801),
+                usize::from(first) + 2,
+                confidence,
+            ));
+        }
+    }
+
+    // Binary: load address, length, then the data
+    if first >= 0x0200 && second > 0 && u32::from(first) + u32::from(second) <= 0xC000 {
+        let end = (usize::from(second) + 4).min(data.len());
+        let score = machine_code_score(&data[4..end]);
+        if score >= MACHINE_CODE_THRESHOLD {
+            return Some((
+                ContentKind::MachineCode,
+                Some(first),
+                usize::from(second) + 4,
+                0.3 + score * 0.5,
+            ));
+        }
+    }
+
+    let score = text_score(data);
+    if score >= TEXT_THRESHOLD {
+        return Some((ContentKind::Text, None, 0, score * 0.7));
+    }
+
+    None
+}
+
+/// Rebuild files on a disk without sector links
+/// Sectors are scanned in order, within each track in descending
+/// sector order if descending is set (Apple DOS allocates sectors from
+/// the top of the track down).  Files with a length in their header
+/// take that many bytes of sectors, text files run until a sector
+/// containing a zero byte.  The token is checked before each file,
+/// progress counts the sectors scanned.
+pub fn carve_sequential_sectors(
+    tracks: &[LogicalTrack],
+    descending: bool,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<CarvedFile>, Error> {
+    let mut order: Vec<(SectorId, &[u8])> = Vec::new();
+    for track in tracks {
+        let mut sectors: Vec<(SectorId, &[u8])> = track
+            .sectors
+            .iter()
+            .map(|s| {
+                (
+                    SectorId::new(track.track, track.head, s.id.sector),
+                    s.data.as_slice(),
+                )
+            })
+            .collect();
+        sectors.sort_by_key(|(id, _)| id.sector);
+        if descending {
+            sectors.reverse();
+        }
+        order.extend(sectors);
+    }
+
+    cancel.start(order.len());
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < order.len() {
+        cancel.check()?;
+        cancel.advance_to(i);
+        let (kind, load_address, length, confidence) = match classify_apple_start(order[i].1) {
+            Some(c) => c,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut sectors = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        let mut confidence = confidence;
+        if kind == ContentKind::Text {
+            while i < order.len() {
+                let (id, sector) = order[i];
+                if text_score(sector) < TEXT_THRESHOLD {
+                    break;
+                }
+                sectors.push(id);
+                i += 1;
+                match sector.iter().position(|b| *b == 0x00) {
+                    Some(end) => {
+                        data.extend_from_slice(&sector[..end]);
+                        break;
+                    }
+                    None => data.extend_from_slice(sector),
+                }
+            }
+        } else {
+            while data.len() < length && i < order.len() {
+                let (id, sector) = order[i];
+                sectors.push(id);
+                data.extend_from_slice(sector);
+                i += 1;
+            }
+            if data.len() < length {
+                // Ran off the end of the disk
+                confidence *= 0.5;
+            }
+            let header = if kind == ContentKind::AppleSoftBasic {
+                2
+            } else {
+                4
+            };
+            data.truncate(length);
+            data.drain(..header.min(data.len()));
+        }
+
+        debug!(target: PARSE, "Carved {} file at {}", kind, sectors[0]);
+        files.push(CarvedFile {
+            kind,
+            sectors,
+            data,
+            load_address,
+            confidence,
+        });
+    }
+    cancel.advance_to(order.len());
+
+    Ok(files)
+}
+
+/// Carve files from a flat sector image
+/// This doesn't need the filesystem to parse, so it works on images
+/// too damaged to open.  Commodore geometries (tracks numbered from
+/// one) follow sector links, other 256 byte sector geometries are
+/// scanned as Apple DOS disks.
+pub fn carve_image(
+    data: &[u8],
+    geometry: &Geometry,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<CarvedFile>, Error> {
+    let tracks = split_tracks(data, geometry);
+    let mut files = if geometry.first_track == 1 {
+        carve_linked_sectors(&tracks, cancel)?
+    } else {
+        carve_sequential_sectors(&tracks, geometry.sector_size == 256, cancel)?
+    };
+    files.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        basic_lines, carve_linked_sectors, carve_sequential_sectors, machine_code_score,
+        text_score, ContentKind,
+    };
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::split_tracks;
+
+    /// A two line BASIC program loaded at 0x0801:
+    /// 10 PRINT "HI"
+    /// 20 GOTO 10
+    const PROGRAM: [u8; 23] = [
+        0x0C, 0x08, 0x0A, 0x00, 0x99, 0x20, 0x22, 0x48, 0x49, 0x22, 0x00, 0x15, 0x08, 0x14, 0x00,
+        0x89, 0x20, 0x31, 0x30, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// Test the scoring functions
+    #[test]
+    fn scores_work() {
+        assert_eq!(basic_lines(&PROGRAM, 0x0801), (2, true));
+        assert_eq!(basic_lines(&PROGRAM[0..12], 0x0801), (1, false));
+        assert_eq!(basic_lines(&[0xFF; 16], 0x0801), (0, false));
+
+        // LDA #$00, STA $D020, RTS
+        assert_eq!(
+            machine_code_score(&[0xA9, 0x00, 0x8D, 0x20, 0xD0, 0x60]),
+            1.0
+        );
+        assert!(machine_code_score(&[0x00; 32]) < 0.1);
+
+        assert_eq!(text_score(b"HELLO WORLD\r\0\0\0"), 1.0);
+        assert!(text_score(&[0x02; 16]) < 0.1);
+    }
+
+    /// Test carving a BASIC program from a Commodore disk with linked
+    /// sectors
+    #[test]
+    fn carve_linked_sectors_works() {
+        let geometry = Geometry::commodore_1541(35);
+        let mut data = vec![0_u8; geometry.total_size()];
+
+        // Spread the program over two sectors: track 17 sector 0 links
+        // to track 17 sector 10
+        let first = geometry.offset(&SectorId::new(17, 0, 0)).unwrap();
+        let second = geometry.offset(&SectorId::new(17, 0, 10)).unwrap();
+        data[first] = 17;
+        data[first + 1] = 10;
+        data[first + 2..first + 4].copy_from_slice(&[0x01, 0x08]);
+        data[first + 4..first + 4 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        // The last sector uses bytes 2 and 3
+        data[second] = 0;
+        data[second + 1] = 3;
+        data[second + 2..second + 4].copy_from_slice(&[0xAA, 0xBB]);
+
+        let files =
+            carve_linked_sectors(&split_tracks(&data, &geometry), &CancellationToken::new())
+                .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].kind, ContentKind::CommodoreBasic);
+        assert_eq!(files[0].load_address, Some(0x0801));
+        assert_eq!(
+            files[0].sectors,
+            vec![SectorId::new(17, 0, 0), SectorId::new(17, 0, 10)]
+        );
+        assert_eq!(&files[0].data[0..PROGRAM.len()], &PROGRAM);
+        assert_eq!(files[0].data.len(), 252 + 2);
+        assert!(files[0].confidence > 0.9);
+    }
+
+    /// Test carving Applesoft, binary and text files from an Apple disk
+    #[test]
+    fn carve_sequential_sectors_works() {
+        let geometry = Geometry::apple_dos_33(35);
+        let mut data = vec![0_u8; geometry.total_size()];
+
+        // Applesoft program at track 20, sector 15
+        let offset = geometry.offset(&SectorId::new(20, 0, 15)).unwrap();
+        data[offset..offset + 2].copy_from_slice(&(PROGRAM.len() as u16).to_le_bytes());
+        data[offset + 2..offset + 2 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+
+        // A 300 byte binary file at track 21, sectors 15 and 14
+        let offset = geometry.offset(&SectorId::new(21, 0, 15)).unwrap();
+        data[offset..offset + 4].copy_from_slice(&[0x00, 0x03, 0x2C, 0x01]);
+        let second = geometry.offset(&SectorId::new(21, 0, 14)).unwrap();
+        data[offset + 4..offset + 256].fill(0xEA);
+        data[second..second + 48].fill(0xEA);
+
+        // A text file at track 22, sector 15
+        let offset = geometry.offset(&SectorId::new(22, 0, 15)).unwrap();
+        let text = b"\xC8\xC5\xCC\xCC\xCF\x8D";
+        data[offset..offset + text.len()].copy_from_slice(text);
+
+        let files = carve_sequential_sectors(
+            &split_tracks(&data, &geometry),
+            true,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(files.len(), 3);
+
+        assert_eq!(files[0].kind, ContentKind::AppleSoftBasic);
+        assert_eq!(files[0].data, PROGRAM);
+
+        assert_eq!(files[1].kind, ContentKind::MachineCode);
+        assert_eq!(files[1].load_address, Some(0x0300));
+        assert_eq!(
+            files[1].sectors,
+            vec![SectorId::new(21, 0, 15), SectorId::new(21, 0, 14)]
+        );
+        assert_eq!(files[1].data, vec![0xEA; 300]);
+
+        assert_eq!(files[2].kind, ContentKind::Text);
+        assert_eq!(files[2].data, text.to_vec());
+    }
+}
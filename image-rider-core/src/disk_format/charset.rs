@@ -0,0 +1,256 @@
+//! Character sets for names and text on disks
+//!
+//! Commodore disks store names in PETSCII and Apple disks in high-bit
+//! ASCII, and both machines had more than one character set.  The
+//! charset settings pick which one names and text are read with, and
+//! the encoding they're converted to when they're displayed or used to
+//! name extracted files.
+//!
+//! The charset is read from the options an image is parsed with, with
+//! Options::charset, and kept with the parsed disk so names are shown
+//! and extracted files named the same way.  It's set in the
+//! configuration:
+//!
+//! ```toml
+//! petscii-charset = "lowercase"
+//! apple-charset = "apple2"
+//! host-encoding = "ascii"
+//! ```
+use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
+
+use config::Config;
+use log::warn;
+
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// The PETSCII character set names are shown in
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PetsciiVariant {
+    /// The uppercase and graphics set the machines start up with
+    /// Shifted letters are graphics characters, they're shown as
+    /// uppercase letters.
+    #[default]
+    Uppercase,
+    /// The lowercase and uppercase set, selected with Commodore-Shift
+    /// Unshifted letters are lowercase and shifted letters uppercase.
+    Lowercase,
+}
+
+/// The Apple ][ character set names and text are shown in
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AppleCharset {
+    /// The Apple ][ and ][+, without lowercase letters
+    /// Lowercase codes are shown as uppercase letters.
+    Apple2,
+    /// The Apple //e and later, with lowercase letters
+    #[default]
+    Apple2e,
+}
+
+/// The encoding names and text are converted to
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HostEncoding {
+    /// Unicode, with characters that aren't in ASCII like the
+    /// Commodore pound sign and arrows
+    #[default]
+    Utf8,
+    /// Printable ASCII, other characters are replaced with a question
+    /// mark
+    Ascii,
+}
+
+/// Build an error for an unknown charset setting
+fn unknown_setting(what: &str, value: &str) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "Unknown {}: {}",
+        what, value
+    ))))
+}
+
+impl FromStr for PetsciiVariant {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<PetsciiVariant, Error> {
+        match s.to_lowercase().as_str() {
+            "uppercase" => Ok(PetsciiVariant::Uppercase),
+            "lowercase" => Ok(PetsciiVariant::Lowercase),
+            _ => Err(unknown_setting("PETSCII character set", s)),
+        }
+    }
+}
+
+impl FromStr for AppleCharset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<AppleCharset, Error> {
+        match s.to_lowercase().as_str() {
+            "apple2" => Ok(AppleCharset::Apple2),
+            "apple2e" => Ok(AppleCharset::Apple2e),
+            _ => Err(unknown_setting("Apple character set", s)),
+        }
+    }
+}
+
+impl FromStr for HostEncoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<HostEncoding, Error> {
+        match s.to_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(HostEncoding::Utf8),
+            "ascii" => Ok(HostEncoding::Ascii),
+            _ => Err(unknown_setting("host encoding", s)),
+        }
+    }
+}
+
+/// The character sets names and text are read with
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Charset {
+    /// The PETSCII character set for Commodore disks
+    pub petscii: PetsciiVariant,
+    /// The character set for Apple disks
+    pub apple: AppleCharset,
+    /// The encoding names and text are converted to
+    pub host: HostEncoding,
+}
+
+impl Charset {
+    /// The default charset, the uppercase PETSCII set, the Apple //e
+    /// set and UTF-8
+    pub const DEFAULT: Charset = Charset {
+        petscii: PetsciiVariant::Uppercase,
+        apple: AppleCharset::Apple2e,
+        host: HostEncoding::Utf8,
+    };
+
+    /// Build a charset from a configuration, using the defaults for any
+    /// setting that's missing or unknown
+    pub fn from_config(config: &Config) -> Charset {
+        fn get<T: FromStr<Err = Error> + Copy>(config: &Config, key: &str, default: T) -> T {
+            match config.get_string(key) {
+                Ok(value) => value.parse().unwrap_or_else(|e| {
+                    warn!(target: PARSE, "{}, using the default", e);
+                    default
+                }),
+                Err(_) => default,
+            }
+        }
+
+        Charset {
+            petscii: get(config, "petscii-charset", Charset::DEFAULT.petscii),
+            apple: get(config, "apple-charset", Charset::DEFAULT.apple),
+            host: get(config, "host-encoding", Charset::DEFAULT.host),
+        }
+    }
+
+    /// Convert a character to the host encoding
+    fn host_char(&self, c: char) -> char {
+        match self.host {
+            HostEncoding::Utf8 => c,
+            HostEncoding::Ascii if c == ' ' || c.is_ascii_graphic() => c,
+            HostEncoding::Ascii => '?',
+        }
+    }
+
+    /// Convert a PETSCII character
+    pub fn petscii_char(&self, byte: u8) -> char {
+        let c = match (byte, self.petscii) {
+            (0x41..=0x5A, PetsciiVariant::Lowercase) => char::from(byte + 0x20),
+            // The shifted letters are at 0x61-0x7A and again at 0xC1-0xDA
+            (0x61..=0x7A, _) => char::from(byte - 0x20),
+            (0xC1..=0xDA, _) => char::from(byte - 0x80),
+            (0x5C, _) => '£',
+            (0x5E, _) => '↑',
+            (0x5F, _) => '←',
+            (0xA0, _) => ' ',
+            (0xFF, _) | (0x7E, PetsciiVariant::Uppercase) => 'π',
+            _ => char::from(byte & 0x7F),
+        };
+        match (c, self.host) {
+            ('£', HostEncoding::Ascii) => '#',
+            ('↑', HostEncoding::Ascii) => '^',
+            ('←', HostEncoding::Ascii) => '_',
+            _ => self.host_char(c),
+        }
+    }
+
+    /// Convert an Apple ][ high-bit ASCII character
+    pub fn apple_char(&self, byte: u8) -> char {
+        let c = char::from(byte & 0x7F);
+        let c = match self.apple {
+            AppleCharset::Apple2 => c.to_ascii_uppercase(),
+            AppleCharset::Apple2e => c,
+        };
+        self.host_char(c)
+    }
+
+    /// Convert a PETSCII string
+    pub fn petscii_string(&self, bytes: &[u8]) -> String {
+        bytes.iter().map(|b| self.petscii_char(*b)).collect()
+    }
+
+    /// Convert an Apple ][ high-bit ASCII string
+    pub fn apple_string(&self, bytes: &[u8]) -> String {
+        bytes.iter().map(|b| self.apple_char(*b)).collect()
+    }
+}
+
+impl Display for Charset {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "PETSCII: {:?}, Apple: {:?}, host encoding: {:?}",
+            self.petscii, self.apple, self.host
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{AppleCharset, Charset, HostEncoding, PetsciiVariant};
+
+    /// Test converting PETSCII and Apple names with each character set
+    #[test]
+    fn charset_works() {
+        let name = [0x48, 0x49, 0x20, 0xD4, 0xC8, 0xC5, 0xD2, 0xC5, 0x5C];
+        let uppercase = Charset::DEFAULT;
+        assert_eq!(uppercase.petscii_string(&name), "HI THERE£");
+
+        let lowercase = Charset {
+            petscii: PetsciiVariant::Lowercase,
+            host: HostEncoding::Ascii,
+            ..Charset::DEFAULT
+        };
+        assert_eq!(lowercase.petscii_string(&name), "hi THERE#");
+
+        let apple_name: Vec<u8> = b"Hello\x01".iter().map(|b| b | 0x80).collect();
+        assert_eq!(uppercase.apple_string(&apple_name), "Hello\x01");
+        let apple2 = Charset {
+            apple: AppleCharset::Apple2,
+            host: HostEncoding::Ascii,
+            ..Charset::DEFAULT
+        };
+        assert_eq!(apple2.apple_string(&apple_name), "HELLO?");
+    }
+
+    /// Test loading a charset from the configuration
+    #[test]
+    fn charset_from_config_works() {
+        let config = Config::builder()
+            .set_override("petscii-charset", "Lowercase")
+            .unwrap()
+            .set_override("host-encoding", "klingon")
+            .unwrap()
+            .build()
+            .unwrap();
+        let charset = Charset::from_config(&config);
+        assert_eq!(charset.petscii, PetsciiVariant::Lowercase);
+        assert_eq!(charset.apple, AppleCharset::Apple2e);
+        assert_eq!(charset.host, HostEncoding::Utf8);
+        assert!("apple3".parse::<AppleCharset>().is_err());
+    }
+}
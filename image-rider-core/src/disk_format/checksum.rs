@@ -0,0 +1,159 @@
+//! Checksums shared between formats and tools
+//!
+//! CRC-32 is the IEEE 802.3 CRC used by zip, PNG and most checksum
+//! databases, so fingerprints and manifests can be compared with
+//! checksums from other tools.  FNV-1a is a fast 64-bit hash used
+//! alongside it for content addressing.  CRC-16/CCITT is the CRC the
+//! floppy disk controllers write after every MFM address and data
+//! field, and the XMODEM variant of it protects BinSCII and MacBinary
+//! headers.
+use crate::disk_format::stx::crc16_add_byte;
+
+/// The reflected CRC-32 polynomial
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320;
+
+/// CRC-32 lookup table, one entry per byte value
+const CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+/// Build the CRC-32 lookup table
+const fn build_crc32_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Add a block of data to a running CRC-32
+/// Start with 0xFFFFFFFF and invert the result when done, or use crc32
+/// for a single block.
+pub fn crc32_add(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        CRC32_TABLE[usize::from((crc as u8) ^ byte)] ^ (crc >> 8)
+    })
+}
+
+/// Calculate the CRC-32 of a block of data
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_add(0xFFFF_FFFF, data)
+}
+
+/// A running CRC-16/CCITT, polynomial 0x1021 without reflection
+/// Floppy controllers start the CRC at 0xFFFF and include the three
+/// 0xA1 sync bytes and the address mark, the XMODEM variant starts at
+/// zero.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Crc16 {
+    /// The CRC so far
+    crc: u16,
+}
+
+impl Crc16 {
+    /// The starting value used by floppy disk controllers
+    pub const CCITT_INITIAL: u16 = 0xFFFF;
+
+    /// The starting value used by XMODEM
+    pub const XMODEM_INITIAL: u16 = 0;
+
+    /// Start a CRC the way a floppy disk controller does, at 0xFFFF
+    pub fn new() -> Crc16 {
+        Crc16::with_initial(Crc16::CCITT_INITIAL)
+    }
+
+    /// Start a CRC at a value
+    pub fn with_initial(crc: u16) -> Crc16 {
+        Crc16 { crc }
+    }
+
+    /// Add a block of data to the CRC
+    pub fn update(&mut self, data: &[u8]) -> &mut Crc16 {
+        self.crc = data
+            .iter()
+            .fold(self.crc, |crc, byte| crc16_add_byte(crc, *byte));
+        self
+    }
+
+    /// Return the CRC of the data added so far
+    /// More data can still be added afterwards.
+    pub fn finish(&self) -> u16 {
+        self.crc
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Crc16 {
+        Crc16::new()
+    }
+}
+
+/// The 64-bit FNV offset basis
+const FNV1A_64_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// The 64-bit FNV prime
+const FNV1A_64_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// Calculate the 64-bit FNV-1a hash of a block of data
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(FNV1A_64_OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV1A_64_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, crc32_add, fnv1a_64, Crc16};
+
+    /// Test CRC-32 against the standard check value
+    #[test]
+    fn crc32_works() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+
+        let crc = crc32_add(0xFFFF_FFFF, b"12345");
+        assert_eq!(!crc32_add(crc, b"6789"), 0xCBF4_3926);
+    }
+
+    /// Test CRC-16 against the standard check values for the CCITT
+    /// and XMODEM variants, and against an address field CRC
+    #[test]
+    fn crc16_works() {
+        assert_eq!(Crc16::new().update(b"123456789").finish(), 0x29B1);
+        assert_eq!(
+            Crc16::with_initial(Crc16::XMODEM_INITIAL)
+                .update(b"123456789")
+                .finish(),
+            0x31C3
+        );
+
+        let mut crc = Crc16::default();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.finish(), 0x29B1);
+
+        // Track 0, head 0, sector 1, 512 bytes, after the sync bytes
+        // and ID address mark
+        let mut crc = Crc16::new();
+        crc.update(&[0xA1, 0xA1, 0xA1, 0xFE, 0, 0, 1, 2]);
+        assert_eq!(crc.finish(), 0xCA6F);
+    }
+
+    /// Test FNV-1a against the reference test vectors
+    #[test]
+    fn fnv1a_64_works() {
+        assert_eq!(fnv1a_64(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a_64(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_eq!(fnv1a_64(b"foobar"), 0x8594_4171_F739_67E8);
+    }
+}
@@ -0,0 +1,249 @@
+//! Duplicate and variant detection across a collection of images
+//!
+//! Large Commodore and Apple collections hold many copies of the same
+//! disk: exact duplicates under different file names and variant dumps
+//! of one original that differ in a few sectors, often from a bad read
+//! or a high score table.  Each image is summarized by its label (the
+//! Commodore disk name and ID or the Apple DOS volume number), a hash
+//! of its contents and a checksum of every sector.  Images are then
+//! grouped by label and compared sector by sector.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+
+use serde::Serialize;
+
+use crate::disk_format::cache::ContentHash;
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::checksum::crc32;
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::image::DiskImage;
+use crate::error::Error;
+
+/// The default fraction of sectors two images must share to be called
+/// variants of each other
+pub const DEFAULT_VARIANT_THRESHOLD: f64 = 0.9;
+
+/// A summary of an image in a collection
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionEntry {
+    /// Where the image came from, usually a path
+    pub path: String,
+    /// The volume name or disk ID, if the image has one
+    pub label: Option<String>,
+    /// A hash of the whole image
+    pub hash: ContentHash,
+    /// The CRC-32 of every sector that isn't a single repeated byte
+    pub sectors: BTreeMap<SectorId, u32>,
+}
+
+impl CollectionEntry {
+    /// Summarize a parsed image and its raw data
+    pub fn new(path: &str, image: &DiskImage, data: &[u8]) -> CollectionEntry {
+        let sectors = image
+            .tracks()
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|track| {
+                track.sectors.iter().filter_map(|sector| {
+                    let fill = sector.data.first()?;
+                    if sector.data.iter().all(|b| b == fill) {
+                        return None;
+                    }
+                    Some((
+                        SectorId::new(track.track, track.head, sector.id.sector),
+                        crc32(&sector.data),
+                    ))
+                })
+            })
+            .collect();
+
+        CollectionEntry {
+            path: String::from(path),
+            label: image.label(),
+            hash: ContentHash::new(data),
+            sectors,
+        }
+    }
+
+    /// The fraction of sectors with data that are the same in both
+    /// images, from 0.0 to 1.0
+    /// Sectors filled with a single byte are ignored, so freshly
+    /// formatted space doesn't make unrelated disks look alike.
+    pub fn similarity(&self, other: &CollectionEntry) -> f64 {
+        let mut total = 0;
+        let mut same = 0;
+        for (id, crc) in &self.sectors {
+            total += 1;
+            if other.sectors.get(id) == Some(crc) {
+                same += 1;
+            }
+        }
+        total += other
+            .sectors
+            .keys()
+            .filter(|id| !self.sectors.contains_key(id))
+            .count();
+
+        match total {
+            0 => 1.0,
+            _ => f64::from(same) / total as f64,
+        }
+    }
+}
+
+/// How two images in a collection are related
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relation {
+    /// The images are byte for byte identical
+    Duplicate,
+    /// Most sectors are the same, probably different dumps of one disk
+    Variant(f64),
+    /// The images share a label but not their contents
+    LabelCollision,
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Relation::Duplicate => write!(f, "duplicate"),
+            Relation::Variant(similarity) => {
+                write!(f, "variant ({:.1}% similar)", similarity * 100.0)
+            }
+            Relation::LabelCollision => write!(f, "label collision"),
+        }
+    }
+}
+
+/// A pair of related images
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CollectionMatch {
+    /// The first image
+    pub first: String,
+    /// The second image
+    pub second: String,
+    /// How they are related
+    pub relation: Relation,
+}
+
+impl Display for CollectionMatch {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}: {} and {}", self.relation, self.first, self.second)
+    }
+}
+
+/// Group the images by label
+/// Images without a label aren't included.
+pub fn group_by_label(entries: &[CollectionEntry]) -> BTreeMap<String, Vec<&str>> {
+    let mut groups: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for entry in entries {
+        if let Some(label) = &entry.label {
+            groups.entry(label.clone()).or_default().push(&entry.path);
+        }
+    }
+
+    groups
+}
+
+/// Compare every pair of images, returning the duplicates, the variants
+/// sharing at least threshold of their sectors and the images that
+/// share a label but are otherwise different
+/// The token is checked before comparing each image with the rest,
+/// progress counts the images.
+pub fn scan_collection(
+    entries: &[CollectionEntry],
+    threshold: f64,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<CollectionMatch>, Error> {
+    let mut matches = Vec::new();
+
+    cancel.start(entries.len());
+    for (n, first) in entries.iter().enumerate() {
+        cancel.check()?;
+        cancel.advance();
+        for second in &entries[n + 1..] {
+            let relation = if first.hash == second.hash {
+                Relation::Duplicate
+            } else {
+                let similarity = first.similarity(second);
+                if similarity >= threshold {
+                    Relation::Variant(similarity)
+                } else if first.label.is_some() && first.label == second.label {
+                    Relation::LabelCollision
+                } else {
+                    continue;
+                }
+            };
+            matches.push(CollectionMatch {
+                first: first.path.clone(),
+                second: second.path.clone(),
+                relation,
+            });
+        }
+    }
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{group_by_label, scan_collection, CollectionEntry, Relation};
+    use crate::disk_format::cache::ContentHash;
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::SectorId;
+
+    /// Build an entry with sector checksums
+    fn entry(path: &str, label: &str, sectors: &[u32]) -> CollectionEntry {
+        let sectors: BTreeMap<SectorId, u32> = sectors
+            .iter()
+            .enumerate()
+            .map(|(n, crc)| (SectorId::new(1, 0, n as u8), *crc))
+            .collect();
+        let data: Vec<u8> = sectors.values().flat_map(|crc| crc.to_le_bytes()).collect();
+        CollectionEntry {
+            path: String::from(path),
+            label: Some(String::from(label)),
+            hash: ContentHash::new(&data),
+            sectors,
+        }
+    }
+
+    /// Test finding duplicates, variants and label collisions
+    #[test]
+    fn scan_collection_works() {
+        let sectors: Vec<u32> = (0..20).collect();
+        let mut variant = sectors.clone();
+        variant[19] = 99;
+        let entries = vec![
+            entry("game.d64", "GAME,01", &sectors),
+            entry("game (copy).d64", "GAME,01", &sectors),
+            entry("game [a].d64", "GAME,01", &variant),
+            entry("other.d64", "GAME,01", &[5, 6, 7]),
+            entry("tools.d64", "TOOLS,02", &[8, 9]),
+        ];
+
+        let groups = group_by_label(&entries);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["GAME,01"].len(), 4);
+
+        let cancel = CancellationToken::new();
+        let matches = scan_collection(&entries, 0.9, &cancel).unwrap();
+        assert_eq!(cancel.progress(), (5, 5));
+        assert_eq!(matches[0].relation, Relation::Duplicate);
+        assert_eq!(matches[0].second, "game (copy).d64");
+        assert_eq!(matches[1].relation, Relation::Variant(0.95));
+        assert_eq!(matches[2].relation, Relation::LabelCollision);
+        assert_eq!(matches.len(), 6);
+        assert!(!matches.iter().any(|m| m.second == "tools.d64"));
+        assert_eq!(
+            matches[1].to_string(),
+            "variant (95.0% similar): game.d64 and game [a].d64"
+        );
+
+        cancel.cancel();
+        let e = scan_collection(&entries, 0.9, &cancel).unwrap_err();
+        assert_eq!(e.to_string(), "The operation was cancelled");
+    }
+}
@@ -0,0 +1,1293 @@
+use config::Config;
+use log::{debug, error, info, warn};
+use nom::bytes::complete::{tag, take};
+use nom::combinator::{map, verify};
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u8};
+use nom::sequence::preceded;
+use nom::IResult;
+/// Parse a Commodore D64 disk image
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use crate::disk_format::charset::Charset;
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, SizeRange};
+use crate::disk_format::geometry::{Geometry, Sector, SectorId, Track};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::logical::{split_tracks, LogicalTrack, RawExporter};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::{IO, PARSE};
+use crate::serialize::Serializer;
+
+/// A Commodore 1541 disk image
+pub const D64_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "D64",
+    description: "Commodore 64 1541 disk image",
+    extensions: &["d64"],
+    sizes: &[
+        SizeRange::exact(174848),
+        SizeRange::exact(175531),
+        SizeRange::exact(196608),
+        SizeRange::exact(197376),
+    ],
+    references: &[],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// A Commodore 1571 double-sided disk image
+pub const D71_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "D71",
+    description: "Commodore 1571 double-sided disk image",
+    extensions: &["d71"],
+    sizes: &[SizeRange::exact(349696), SizeRange::exact(351062)],
+    references: &[],
+    capabilities: D64_FORMAT_INFO.capabilities,
+};
+
+/// A Commodore D64 disk
+pub struct D64Disk<'a> {
+    /// The raw image data
+    pub data: &'a [u8],
+    /// The D64 Block Availability Map
+    pub bam: D64BlockAvailabilityMap<'a>,
+    /// The directory entries, from every directory sector
+    pub directory: Vec<D64FileEntry<'a>>,
+    /// The character set the disk name and file names are read with
+    pub charset: Charset,
+}
+
+/// A file in the directory with its data
+#[derive(Clone, Debug)]
+pub struct D64File<'a> {
+    /// The directory entry of the file
+    pub entry: D64FileEntry<'a>,
+    /// The file data, without the sector links
+    pub data: Vec<u8>,
+}
+
+impl<'a> D64Disk<'a> {
+    /// Return the sectors marked free in the BAM
+    /// Each track has three bitmap bytes, bit n of byte m is sector
+    /// 8 * m + n, a set bit is a free sector.  Tracks past 35 use the
+    /// second side BAM on a D71 disk, or the extended BAM if there is
+    /// one.
+    pub fn free_sectors(&self) -> BTreeSet<SectorId> {
+        let mut bam_entries: Vec<&D64BAMEntry> = self.bam.bam_entries.iter().collect();
+        let extended_bam = self.extended_bam();
+        if let Some((_, entries)) = &extended_bam {
+            bam_entries.extend(entries.iter());
+        }
+        let second_side_bam = self.second_side_bam();
+        if let Some(entries) = &second_side_bam {
+            bam_entries.extend(entries.iter());
+        }
+
+        d64_geometry(self.data)
+            .sector_ids()
+            .into_iter()
+            .filter(|id| {
+                match id
+                    .track
+                    .index_from(Track(1))
+                    .and_then(|i| bam_entries.get(i))
+                {
+                    Some(entry) => entry.is_free(id.sector),
+                    None => false,
+                }
+            })
+            .collect()
+    }
+
+    /// Return the DOS extension and its BAM entries for tracks 36 to 40,
+    /// or None if the disk has 35 tracks or no extended BAM was found
+    pub fn extended_bam(&self) -> Option<(ExtendedDOS, Vec<D64BAMEntry>)> {
+        if self.extra_tracks().is_empty() {
+            return None;
+        }
+        let bam_sector = self.data.get(0x16500..0x16600)?;
+
+        [ExtendedDOS::DolphinDOS, ExtendedDOS::SpeedDOS]
+            .into_iter()
+            .find_map(|dos| {
+                let (_, entries) = d64_extended_bam_parser(bam_sector, dos).ok()?;
+                valid_extended_bam(&entries).then_some((dos, entries))
+            })
+    }
+
+    /// Return true if the image is a double-sided 1571 disk, a D71
+    /// image, with the second side as tracks 36 to 70
+    pub fn is_double_sided(&self) -> bool {
+        d64_geometry(self.data).tracks() == usize::from(2 * TRACKS_PER_SIDE)
+    }
+
+    /// Return the BAM entries for tracks 36 to 70 of a double-sided
+    /// disk, or None if the disk is single-sided
+    /// The free sector counts follow the disk name in the BAM sector on
+    /// track 18 and the bitmaps are at the start of track 53 sector 0.
+    pub fn second_side_bam(&self) -> Option<Vec<D64BAMEntry>> {
+        if !self.is_double_sided() {
+            return None;
+        }
+        let geometry = d64_geometry(self.data);
+        let bam_sector = geometry.sector(self.data, &SectorId::new(DIRECTORY_TRACK, 0, 0))?;
+        let bitmap_sector =
+            geometry.sector(self.data, &SectorId::new(SECOND_SIDE_BAM_TRACK, 0, 0))?;
+        let (_, entries) = d71_second_side_bam_parser(bam_sector, bitmap_sector).ok()?;
+        Some(entries)
+    }
+
+    /// Return the track numbers past the tracks the DOS manages, 35
+    /// tracks on a single-sided disk or 70 on a double-sided disk
+    pub fn extra_tracks(&self) -> Vec<Track> {
+        let managed = if self.is_double_sided() {
+            2 * TRACKS_PER_SIDE
+        } else {
+            TRACKS_PER_SIDE
+        };
+        (managed + 1..=d64_geometry(self.data).tracks() as u8)
+            .map(Track)
+            .collect()
+    }
+
+    /// Return the sectors on tracks standard DOS doesn't manage
+    /// Tracks 36 to 40 are ignored by CBM DOS unless the disk has an
+    /// extended BAM, so data on them is invisible to a normal directory
+    /// listing.
+    pub fn unmanaged_sectors(&self) -> BTreeSet<SectorId> {
+        if self.extended_bam().is_some() {
+            return BTreeSet::new();
+        }
+        let extra_tracks = self.extra_tracks();
+        d64_geometry(self.data)
+            .sector_ids()
+            .into_iter()
+            .filter(|id| extra_tracks.contains(&id.track))
+            .collect()
+    }
+
+    /// Return the sectors the directory was read from
+    pub fn directory_sectors(&self) -> Vec<SectorId> {
+        d64_directory_parser(self.data)
+            .map(|(_, (_, sectors))| sectors)
+            .unwrap_or_default()
+    }
+
+    /// Return the sectors used by DOS: the BAM and the directory
+    /// sectors, and on a double-sided disk all of track 53, which the
+    /// 1571 reserves for the second side BAM
+    pub fn system_sectors(&self) -> BTreeSet<SectorId> {
+        let mut system: BTreeSet<SectorId> = self.directory_sectors().into_iter().collect();
+        system.insert(SectorId::new(18, 0, 0));
+        if self.is_double_sided() {
+            system.extend(
+                d64_geometry(self.data)
+                    .sector_ids()
+                    .into_iter()
+                    .filter(|id| id.track == SECOND_SIDE_BAM_TRACK),
+            );
+        }
+
+        system
+    }
+
+    /// Return the sectors of every file in the directory and where the
+    /// file data ends
+    /// The second byte of the last sector in a file is the offset of the
+    /// last byte used.
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        let geometry = d64_geometry(self.data);
+
+        self.directory
+            .iter()
+            .filter(|entry| entry.first_track != 0)
+            .map(|entry| {
+                let sectors = d64_sector_chain(
+                    self.data,
+                    Track(entry.first_track),
+                    Sector(entry.first_sector),
+                );
+                let end = sectors
+                    .last()
+                    .and_then(|id| geometry.sector(self.data, id))
+                    .filter(|data| data[0] == 0)
+                    .map(|data| (sectors.len() - 1, usize::from(data[1]) + 1));
+                FileExtent {
+                    name: entry.name(&self.charset),
+                    sectors,
+                    end,
+                }
+            })
+            .collect()
+    }
+
+    /// Return the files in the directory with their data, in directory
+    /// order
+    /// Entries without a first sector are skipped, files that can't be
+    /// read are skipped with a warning.
+    pub fn files(&self) -> Vec<D64File<'a>> {
+        self.directory
+            .iter()
+            .filter(|entry| entry.first_track != 0)
+            .filter_map(|entry| match d64_file_data_parser(self.data, entry) {
+                Ok((_, data)) => Some(D64File {
+                    entry: *entry,
+                    data,
+                }),
+                Err(e) => {
+                    warn!(target: PARSE, "Error reading {}: {}", entry.filename(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Return the disk name and the two character ID, e.g. "GAMES,01"
+    pub fn label(&self) -> String {
+        let disk_name = self.bam.disk_name;
+        let end = disk_name
+            .iter()
+            .position(|b| *b == 0xA0)
+            .unwrap_or(disk_name.len());
+        let name = self.charset.petscii_string(&disk_name[..end]);
+        let id = self.charset.petscii_string(&self.bam.disk_id.to_le_bytes());
+        format!("{},{}", name, id)
+    }
+}
+
+/// Display a Commodore D64 disk
+impl Display for D64Disk<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.bam)
+    }
+}
+
+/// The different DOS types
+#[derive(Debug)]
+pub enum DOSType {
+    /// Original CBM DOS
+    CBM,
+}
+
+/// Heuristic guesses for what kind of disk this is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct D64DiskGuess<'a> {
+    /// The raw image data
+    pub data: &'a [u8],
+}
+
+/// The Block Availability Map (BAM) lives at track 18, which is at offset 0x16500
+pub struct D64BlockAvailabilityMap<'a> {
+    /// The first byte is the track of the first directory sector
+    pub first_directory_sector_track: u8,
+    /// The second byte is the sector of the first directory sector
+    pub first_directory_sector_sector: u8,
+    /// The third byte is the DOS version type
+    pub disk_dos_version: u8,
+
+    /// reserved byte
+    pub reserved: u8,
+
+    /// 140 bytes of BAM entries for each track (offset 0x04 to 0x8f)
+    /// four bytes per track, starting at track one
+    pub bam_entries: Vec<D64BAMEntry>,
+
+    /// The disk name, 16 bytes, padded with 0xA0
+    pub disk_name: &'a [u8],
+
+    /// two reserved bytes
+    pub second_reserved: &'a [u8],
+
+    /// disk id
+    pub disk_id: u16,
+
+    /// reserved byte, usually 0xA0
+    pub third_reserved: u8,
+
+    /// DOS type, usually "2A" aka CBM DOS
+    pub dos_type: DOSType,
+
+    /// The rest of the sector, 0xA7 to 0xFF, reserved bytes and the
+    /// data of DOS extensions like the extended BAMs
+    pub trailing_bytes: &'a [u8],
+
+    /// True if the disk is read-only, allocating and freeing sectors
+    /// fails with ErrorKind::ReadOnly
+    pub read_only: bool,
+}
+
+/// A single Block Availability Map entry
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct D64BAMEntry {
+    /// Number of free sectors on track
+    pub free_sectors_on_track: u8,
+
+    /// The sector use bitmap, 3 bytes or 24 bits
+    pub sector_use_bitmap: [u8; 3],
+}
+
+impl D64BAMEntry {
+    /// Return true if the sector is marked free
+    pub fn is_free(&self, sector: Sector) -> bool {
+        let sector = sector.get();
+        self.sector_use_bitmap
+            .get(usize::from(sector / 8))
+            .is_some_and(|byte| byte & (1 << (sector % 8)) != 0)
+    }
+}
+
+/// DOS extensions that keep a BAM for tracks 36 to 40 in spare space
+/// in the BAM sector
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtendedDOS {
+    /// DolphinDOS, BAM entries at offset 0xAC
+    DolphinDOS,
+    /// SpeedDOS, BAM entries at offset 0xC0
+    SpeedDOS,
+}
+
+impl ExtendedDOS {
+    /// The offset of the extended BAM entries in the BAM sector
+    pub fn bam_offset(&self) -> usize {
+        match self {
+            ExtendedDOS::DolphinDOS => 0xAC,
+            ExtendedDOS::SpeedDOS => 0xC0,
+        }
+    }
+}
+
+impl Display for ExtendedDOS {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Parse the five extended BAM entries for tracks 36 to 40 from the BAM
+/// sector
+pub fn d64_extended_bam_parser(i: &[u8], dos: ExtendedDOS) -> IResult<&[u8], Vec<D64BAMEntry>> {
+    let (i, _) = take(dos.bam_offset())(i)?;
+    count(bam_entry_parser, 5_usize)(i)
+}
+
+/// Parse the BAM entries for the second side of a 1571 disk from the
+/// BAM sector, which holds the free sector counts, and track 53 sector
+/// 0, which holds the bitmaps
+pub fn d71_second_side_bam_parser<'a>(
+    bam_sector: &'a [u8],
+    bitmap_sector: &'a [u8],
+) -> IResult<&'a [u8], Vec<D64BAMEntry>> {
+    let (_, free_counts) =
+        preceded(take(SECOND_SIDE_FREE_COUNTS), take(TRACKS_PER_SIDE))(bam_sector)?;
+    let (i, bitmaps) = count(take(3_usize), usize::from(TRACKS_PER_SIDE))(bitmap_sector)?;
+
+    Ok((
+        i,
+        free_counts
+            .iter()
+            .zip(bitmaps)
+            .map(|(free_sectors_on_track, bitmap)| D64BAMEntry {
+                free_sectors_on_track: *free_sectors_on_track,
+                sector_use_bitmap: [bitmap[0], bitmap[1], bitmap[2]],
+            })
+            .collect(),
+    ))
+}
+
+/// Check that extended BAM entries are consistent: every track has 17
+/// sectors, the free count matches the bitmap and the entries aren't
+/// all zero
+fn valid_extended_bam(entries: &[D64BAMEntry]) -> bool {
+    let consistent = entries.iter().all(|entry| {
+        let bitmap = entry.sector_use_bitmap;
+        let bits: u32 = bitmap.iter().map(|b| b.count_ones()).sum();
+        bitmap[2] & 0xFE == 0 && bits == u32::from(entry.free_sectors_on_track)
+    });
+    let used = entries.iter().any(|entry| {
+        entry.free_sectors_on_track != 0 || entry.sector_use_bitmap.iter().any(|b| *b != 0)
+    });
+
+    consistent && used
+}
+
+/// Parse an entry in the Block Availability Map table
+pub fn bam_entry_parser(i: &[u8]) -> IResult<&[u8], D64BAMEntry> {
+    let (i, free_sectors_on_track) = le_u8(i)?;
+
+    let (i, sector_use_bitmap) = take(3_usize)(i)?;
+    let sector_use_bitmap = [
+        sector_use_bitmap[0],
+        sector_use_bitmap[1],
+        sector_use_bitmap[2],
+    ];
+
+    Ok((
+        i,
+        D64BAMEntry {
+            free_sectors_on_track,
+            sector_use_bitmap,
+        },
+    ))
+}
+
+impl Display for D64BlockAvailabilityMap<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "D64 Block Availability Map: ")?;
+        write!(
+            f,
+            "first_directory_sector_track: 0x{:02X}, ",
+            self.first_directory_sector_track
+        )?;
+        write!(
+            f,
+            "first_directory_sector_sector: 0x{:02X}, ",
+            self.first_directory_sector_sector
+        )?;
+        write!(
+            f,
+            "first_directory_sector_track: 0x{:02X}, ",
+            self.disk_dos_version
+        )?;
+        write!(
+            f,
+            "disk_name: {}, ",
+            String::from_utf8_lossy(self.disk_name)
+        )?;
+        write!(f, "disk_id: {}, ", self.disk_id)?;
+        write!(f, "dos_type: {:?}", self.dos_type)
+    }
+}
+
+/// Perform sanity checks for DOS 2.x boot sectors
+impl SanityCheck for D64BlockAvailabilityMap<'_> {
+    fn check(&self) -> bool {
+        if self.disk_dos_version != 0x41 {
+            debug!(
+                target: PARSE,
+                "disk dos version should be 0x41: 0x{:02X}",
+                self.disk_dos_version
+            );
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// The size of the BAM sector
+const BAM_SIZE: usize = 256;
+
+/// The track holding the BAM and the directory
+const DIRECTORY_TRACK: u8 = 18;
+
+/// The number of tracks on each side of a 1541 or 1571 disk
+const TRACKS_PER_SIDE: u8 = 35;
+
+/// The track holding the BAM bitmaps for the second side of a 1571
+/// disk, the directory track of the second side
+const SECOND_SIDE_BAM_TRACK: Track = Track(53);
+
+/// The offset of the free sector counts for the second side of a 1571
+/// disk in the BAM sector
+const SECOND_SIDE_FREE_COUNTS: usize = 0xDD;
+
+impl D64BlockAvailabilityMap<'_> {
+    /// Return the number of sectors on a track, or None if the BAM
+    /// doesn't have an entry for the track
+    fn sectors_on_track(&self, track: Track) -> Option<u8> {
+        let index = track.index_from(Track(1))?;
+        if index >= self.bam_entries.len() {
+            return None;
+        }
+        Geometry::commodore_1541(40)
+            .sectors_per_track
+            .get(index)
+            .copied()
+    }
+
+    /// Return an error if the disk is read-only
+    fn check_writable(&self) -> std::result::Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnly(String::from(
+                "The BAM is read-only",
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Return the BAM entry for a sector, or an error if the sector
+    /// isn't on a track the BAM manages
+    fn entry_mut(
+        &mut self,
+        track: Track,
+        sector: Sector,
+    ) -> std::result::Result<&mut D64BAMEntry, Error> {
+        match self.sectors_on_track(track) {
+            Some(sectors) if sector.get() < sectors => {
+                Ok(&mut self.bam_entries[usize::from(track.get()) - 1])
+            }
+            _ => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Track {} sector {} isn't in the BAM", track, sector),
+            )))),
+        }
+    }
+
+    /// Return true if a sector is marked free in the BAM
+    /// Sectors that aren't on a track the BAM manages aren't free.
+    pub fn is_free(&self, track: Track, sector: Sector) -> bool {
+        self.sectors_on_track(track)
+            .is_some_and(|sectors| sector.get() < sectors)
+            && self.bam_entries[usize::from(track.get()) - 1].is_free(sector)
+    }
+
+    /// Allocate a free sector and mark it in use
+    /// Like CBM DOS, tracks are searched outward from the directory
+    /// track, alternating between the tracks below and above it, and
+    /// the lowest free sector on a track is taken.  The directory track
+    /// is only used for the directory.  Returns None if the disk is
+    /// full, and an error if it's read-only.
+    pub fn allocate(&mut self) -> std::result::Result<Option<SectorId>, Error> {
+        self.check_writable()?;
+        let tracks = self.bam_entries.len() as u8;
+        let free = (1..tracks)
+            .flat_map(|distance| {
+                [
+                    DIRECTORY_TRACK.checked_sub(distance),
+                    DIRECTORY_TRACK.checked_add(distance),
+                ]
+            })
+            .flatten()
+            .filter(|track| *track != 0 && *track <= tracks)
+            .map(Track)
+            .find_map(|track| {
+                let sectors = self.sectors_on_track(track)?;
+                (0..sectors)
+                    .map(Sector)
+                    .find(|sector| self.is_free(track, *sector))
+                    .map(|sector| (track, sector))
+            });
+        let Some((track, sector)) = free else {
+            return Ok(None);
+        };
+
+        let entry = self.entry_mut(track, sector)?;
+        let sector = sector.get();
+        entry.sector_use_bitmap[usize::from(sector / 8)] &= !(1 << (sector % 8));
+        entry.free_sectors_on_track = entry.free_sectors_on_track.saturating_sub(1);
+        Ok(Some(SectorId::new(track, 0, sector)))
+    }
+
+    /// Mark a sector free
+    pub fn free(&mut self, track: Track, sector: Sector) -> std::result::Result<(), Error> {
+        self.check_writable()?;
+        let entry = self.entry_mut(track, sector)?;
+        if !entry.is_free(sector) {
+            let sector = sector.get();
+            entry.sector_use_bitmap[usize::from(sector / 8)] |= 1 << (sector % 8);
+            entry.free_sectors_on_track = entry.free_sectors_on_track.saturating_add(1);
+        }
+        Ok(())
+    }
+
+    /// Recompute the free sector count of every track from its bitmap
+    /// Returns the tracks whose count didn't match the bitmap.
+    pub fn recompute_free_counts(&mut self) -> std::result::Result<Vec<Track>, Error> {
+        self.check_writable()?;
+        let mut fixed = Vec::new();
+        for track in (1..=self.bam_entries.len() as u8).map(Track) {
+            let Some(sectors) = self.sectors_on_track(track) else {
+                continue;
+            };
+            let free = (0..sectors)
+                .filter(|s| self.is_free(track, Sector(*s)))
+                .count() as u8;
+            let entry = &mut self.bam_entries[usize::from(track.get()) - 1];
+            if entry.free_sectors_on_track != free {
+                entry.free_sectors_on_track = free;
+                fixed.push(track);
+            }
+        }
+        Ok(fixed)
+    }
+}
+
+impl<'a> Serializer<'a> for D64BlockAvailabilityMap<'a> {
+    /// Serialize the BAM as a 256 byte sector
+    fn as_vec(&'a self) -> std::result::Result<Vec<u8>, Error> {
+        let mut bytes: Vec<u8> = vec![
+            self.first_directory_sector_track,
+            self.first_directory_sector_sector,
+            self.disk_dos_version,
+            self.reserved,
+        ];
+        for entry in &self.bam_entries {
+            bytes.push(entry.free_sectors_on_track);
+            bytes.extend_from_slice(&entry.sector_use_bitmap);
+        }
+        bytes.extend_from_slice(self.disk_name);
+        bytes.extend_from_slice(self.second_reserved);
+        bytes.extend_from_slice(&self.disk_id.to_le_bytes());
+        bytes.push(self.third_reserved);
+        match self.dos_type {
+            DOSType::CBM => bytes.extend_from_slice(b"2A"),
+        }
+        bytes.extend_from_slice(self.trailing_bytes);
+
+        if bytes.len() != BAM_SIZE {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("The BAM is {} bytes, not {}", bytes.len(), BAM_SIZE),
+            ))));
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// TODO: Get this parser working as it should
+/// e.g. it should fail if there is no NOP for a DOS 3.x
+pub fn d64_block_availability_map_parser(i: &[u8]) -> IResult<&[u8], D64BlockAvailabilityMap<'_>> {
+    // Jump to the BAM
+    let (i, _) = take(0x16500_usize)(i)?;
+
+    // Should be 0x12, fail on a parse error if it isn't
+    let (i, first_directory_sector_track) = verify(le_u8, |val: &u8| *val == 0x12)(i)?;
+    // Should be 0x01, fail on a parse error if it isn't
+    let (i, first_directory_sector_sector) = verify(le_u8, |val: &u8| *val == 0x01)(i)?;
+    // Should be 0x41, otherwise it uses "soft write protection"
+    // For now, parse fail because we don't support soft write protection
+    let (i, disk_dos_version) = verify(le_u8, |val: &u8| *val == 0x41)(i)?;
+    let (i, reserved) = le_u8(i)?;
+    let (i, bam_entries) = count(bam_entry_parser, 35_usize)(i)?;
+    let (i, disk_name) = take(16_usize)(i)?;
+    let (i, second_reserved) = take(2_usize)(i)?;
+    let (i, disk_id) = le_u16(i)?;
+    let (i, third_reserved) = le_u8(i)?;
+    let (i, dos_type) = map(tag("2A"), |_| DOSType::CBM)(i)?;
+    let (i, trailing_bytes) = take(BAM_SIZE - 0xA7)(i)?;
+
+    let d64_bam = D64BlockAvailabilityMap {
+        first_directory_sector_track,
+        first_directory_sector_sector,
+        disk_dos_version,
+        reserved,
+        bam_entries,
+        disk_name,
+        second_reserved,
+        disk_id,
+        third_reserved,
+        dos_type,
+        trailing_bytes,
+        read_only: false,
+    };
+    Ok((i, d64_bam))
+}
+
+/// Commodore file types
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum D64FileType {
+    /// Deleted file
+    DEL,
+    /// Sequential file
+    SEQ,
+    /// Program file
+    PRG,
+    /// User file
+    USR,
+    /// Relative file
+    REL,
+    /// Unknown file type
+    Unknown(u8),
+}
+
+impl Display for D64FileType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            D64FileType::DEL => write!(f, "DEL"),
+            D64FileType::SEQ => write!(f, "SEQ"),
+            D64FileType::PRG => write!(f, "PRG"),
+            D64FileType::USR => write!(f, "USR"),
+            D64FileType::REL => write!(f, "REL"),
+            D64FileType::Unknown(t) => write!(f, "0x{:02X}", t),
+        }
+    }
+}
+
+/// A directory entry
+/// Each directory sector holds eight 32 byte entries.  The first two
+/// bytes of each entry are only used in the first entry, as the link
+/// to the next directory sector, so they aren't part of this
+/// structure.
+#[derive(Clone, Copy, Debug)]
+pub struct D64FileEntry<'a> {
+    /// The file type
+    pub file_type: D64FileType,
+    /// Whether the file is locked
+    pub locked: bool,
+    /// Whether the file was closed properly
+    pub closed: bool,
+    /// Track of the first sector of the file
+    pub first_track: u8,
+    /// Sector of the first sector of the file
+    pub first_sector: u8,
+    /// The file name, 16 bytes, padded with 0xA0
+    pub file_name: &'a [u8],
+    /// Track of the first side sector block, REL files only
+    pub side_sector_track: u8,
+    /// Sector of the first side sector block, REL files only
+    pub side_sector_sector: u8,
+    /// Record length, REL files only
+    pub record_length: u8,
+    /// Six unused bytes, used by GEOS
+    pub unused: &'a [u8],
+    /// The file size in sectors
+    pub file_size_in_sectors: u16,
+}
+
+impl D64FileEntry<'_> {
+    /// Return the filename without the 0xA0 padding, in the default
+    /// character set
+    pub fn filename(&self) -> String {
+        self.name(&Charset::DEFAULT)
+    }
+
+    /// Return the filename without the 0xA0 padding, in a character set
+    pub fn name(&self, charset: &Charset) -> String {
+        let end = self
+            .file_name
+            .iter()
+            .position(|c| *c == 0xA0)
+            .unwrap_or(self.file_name.len());
+        charset.petscii_string(&self.file_name[..end])
+    }
+}
+
+impl Display for D64FileEntry<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{:>4} \"{}\" {}",
+            self.file_size_in_sectors,
+            self.filename(),
+            self.file_type
+        )
+    }
+}
+
+/// Parse a directory entry, not including the two link bytes
+pub fn d64_file_entry_parser(i: &[u8]) -> IResult<&[u8], D64FileEntry<'_>> {
+    let (i, file_type) = le_u8(i)?;
+    let (i, first_track) = le_u8(i)?;
+    let (i, first_sector) = le_u8(i)?;
+    let (i, file_name) = take(16_usize)(i)?;
+    let (i, side_sector_track) = le_u8(i)?;
+    let (i, side_sector_sector) = le_u8(i)?;
+    let (i, record_length) = le_u8(i)?;
+    let (i, unused) = take(6_usize)(i)?;
+    let (i, file_size_in_sectors) = le_u16(i)?;
+
+    Ok((
+        i,
+        D64FileEntry {
+            file_type: match file_type & 0x0F {
+                0 => D64FileType::DEL,
+                1 => D64FileType::SEQ,
+                2 => D64FileType::PRG,
+                3 => D64FileType::USR,
+                4 => D64FileType::REL,
+                t => D64FileType::Unknown(t),
+            },
+            locked: file_type & 0x40 != 0,
+            closed: file_type & 0x80 != 0,
+            first_track,
+            first_sector,
+            file_name,
+            side_sector_track,
+            side_sector_sector,
+            record_length,
+            unused,
+            file_size_in_sectors,
+        },
+    ))
+}
+
+/// A directory sector
+pub struct D64DirectorySector<'a> {
+    /// Track of the next directory sector, zero if this is the last
+    pub track_of_next_directory_block: u8,
+    /// Sector of the next directory sector
+    pub sector_of_next_directory_block: u8,
+    /// The entries in use, empty slots are skipped
+    pub file_entries: Vec<D64FileEntry<'a>>,
+}
+
+/// Parse a directory sector
+pub fn d64_directory_sector_parser(i: &[u8]) -> IResult<&[u8], D64DirectorySector<'_>> {
+    let (mut i, (track_of_next_directory_block, sector_of_next_directory_block)) =
+        nom::sequence::pair(le_u8, le_u8)(i)?;
+    let mut file_entries = Vec::new();
+
+    for slot in 0..8 {
+        if slot > 0 {
+            let (rest, _) = take(2_usize)(i)?;
+            i = rest;
+        }
+        let (rest, file_entry) = d64_file_entry_parser(i)?;
+        // A file type byte of zero is an empty or scratched slot
+        if file_entry.closed || file_entry.file_type != D64FileType::DEL {
+            file_entries.push(file_entry);
+        }
+        i = rest;
+    }
+
+    Ok((
+        i,
+        D64DirectorySector {
+            track_of_next_directory_block,
+            sector_of_next_directory_block,
+            file_entries,
+        },
+    ))
+}
+
+/// Return the geometry for a D64 image
+pub fn d64_geometry(data: &[u8]) -> Geometry {
+    Geometry::from_size(data.len())
+        .filter(|g| g.first_track == 1)
+        .unwrap_or(Geometry::commodore_1541(35))
+}
+
+/// Follow a chain of linked sectors starting at a track and sector
+/// The first two bytes of each sector are the track and sector of the
+/// next one, a track of zero ends the chain.  The chain also ends at a
+/// link outside the disk or a sector that was already visited.
+pub fn d64_sector_chain(data: &[u8], track: Track, sector: Sector) -> Vec<SectorId> {
+    let geometry = d64_geometry(data);
+    let mut chain: Vec<SectorId> = Vec::new();
+    let mut id = SectorId::new(track, 0, sector);
+
+    while let Some(sector_data) = geometry.sector(data, &id) {
+        if chain.contains(&id) {
+            debug!(target: PARSE, "Sector chain loops back to {}", id);
+            break;
+        }
+        chain.push(id);
+        if sector_data[0] == 0 {
+            break;
+        }
+        id = SectorId::new(sector_data[0], 0, sector_data[1]);
+    }
+
+    chain
+}
+
+/// Parse the directory, following the chain of directory sectors
+/// starting at track 18 sector 1
+/// Returns the directory entries and the directory sectors.
+pub fn d64_directory_parser(data: &[u8]) -> IResult<&[u8], (Vec<D64FileEntry<'_>>, Vec<SectorId>)> {
+    let geometry = d64_geometry(data);
+    let sectors = d64_sector_chain(data, Track(DIRECTORY_TRACK), Sector(1));
+    let mut file_entries = Vec::new();
+
+    for id in &sectors {
+        if let Some(sector_data) = geometry.sector(data, id) {
+            let (_, directory_sector) = d64_directory_sector_parser(sector_data)?;
+            file_entries.extend(directory_sector.file_entries);
+        }
+    }
+
+    Ok((data, (file_entries, sectors)))
+}
+
+/// Parse the data in a file sector
+/// Returns the link to the next sector and the data.  A link track of
+/// zero marks the last sector, its link sector is the offset of the
+/// last byte used.
+pub fn d64_sector_data_parser(i: &[u8]) -> IResult<&[u8], (SectorId, &[u8])> {
+    let (i, (track, sector)) = nom::sequence::pair(le_u8, le_u8)(i)?;
+    let length = match track {
+        0 => usize::from(sector).saturating_sub(1),
+        _ => i.len(),
+    };
+    let (i, data) = take(length)(i)?;
+
+    Ok((i, (SectorId::new(track, 0, sector), data)))
+}
+
+/// Read the data of a file by following its chain of sectors
+/// A chain that leaves the disk or loops back on itself is cut at the
+/// last good sector.
+pub fn d64_file_data_parser<'a>(
+    data: &'a [u8],
+    entry: &D64FileEntry,
+) -> IResult<&'a [u8], Vec<u8>> {
+    let geometry = d64_geometry(data);
+    let mut file_data = Vec::new();
+
+    for id in d64_sector_chain(data, Track(entry.first_track), Sector(entry.first_sector)) {
+        let Some(sector) = geometry.sector(data, &id) else {
+            break;
+        };
+        let (_, (_, sector_data)) = d64_sector_data_parser(sector)?;
+        file_data.extend_from_slice(sector_data);
+    }
+
+    Ok((data, file_data))
+}
+
+/// Parse a D64 disk image
+pub fn d64_disk_parser(i: &[u8]) -> IResult<&[u8], D64Disk<'_>> {
+    let data = i;
+    let (i, bam) = d64_block_availability_map_parser(i)?;
+    let (_, (directory, _)) = d64_directory_parser(data)?;
+
+    Ok((
+        i,
+        D64Disk {
+            data,
+            bam,
+            directory,
+            charset: Charset::DEFAULT,
+        },
+    ))
+}
+
+// impl DiskImageParser for D64Disk<'_> {
+//     fn parse_disk_image<'a>(
+//         &self,
+//         _config: &Config,
+//         _filename: &str,
+//         data: &'a [u8],
+//     ) -> IResult<&'a [u8], DiskImage<'a>> {
+//         let (i, parse_result) = d64_disk_parser(data)?;
+
+//         Ok((i, DiskImage::D64(parse_result)))
+//     }
+// }
+
+impl DiskImageSaver for D64Disk<'_> {
+    /// Save the file selected by name
+    /// Returns an error if no file is selected or there is no file with
+    /// that name in the directory.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error> {
+        let selected_filename = selected_filename.ok_or_else(|| {
+            error!(target: IO, "Filename must be specified for saving D64 images");
+            Error::new(ErrorKind::Message(String::from(
+                "Filename must be specified for saving D64 images",
+            )))
+        })?;
+        let file = self
+            .files()
+            .into_iter()
+            .find(|file| file.entry.name(&self.charset) == selected_filename)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound(String::from(selected_filename))))?;
+        info!(target: IO, "Found file {}, writing data", selected_filename);
+        writer.write_all(&file.data)?;
+        Ok(())
+    }
+
+    /// Return the files in the directory, in directory order
+    /// The two link bytes at the start of each sector are removed.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.files()
+            .into_iter()
+            .map(|file| DiskFile {
+                name: file.entry.name(&self.charset),
+                file_type: file.entry.file_type.to_string(),
+                raw_name: file.entry.file_name.to_vec(),
+                data: file.data,
+            })
+            .collect()
+    }
+}
+
+impl RawExporter for D64Disk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.raw_geometry()
+            .map(|geometry| split_tracks(self.data, &geometry))
+            .unwrap_or_default()
+    }
+
+    /// The geometry is found from the image size, 35 track disks are
+    /// assumed for unusual sizes
+    fn raw_geometry(&self) -> Option<Geometry> {
+        Some(Geometry::from_size(self.data.len()).unwrap_or(Geometry::commodore_1541(35)))
+    }
+}
+
+/// The BAM, the directory sectors and the sectors of each file
+impl SourceMapper for D64Disk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(base) = slice_offset(data, self.data) else {
+            return map;
+        };
+        let geometry = d64_geometry(self.data);
+
+        map.add_sectors(
+            &geometry,
+            base,
+            &[SectorId::new(18, 0, 0)],
+            RegionKind::Directory,
+            "BAM",
+        );
+        map.add_sectors(
+            &geometry,
+            base,
+            &self.directory_sectors(),
+            RegionKind::Directory,
+            "directory",
+        );
+        for extent in self.file_extents() {
+            map.add_sectors(
+                &geometry,
+                base,
+                &extent.sectors,
+                RegionKind::File,
+                &extent.name,
+            );
+        }
+
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        d64_block_availability_map_parser, d64_directory_parser, d64_disk_parser,
+        d64_file_data_parser, d64_sector_data_parser, ExtendedDOS,
+    };
+    use crate::disk_format::geometry::{Geometry, Sector, SectorId, Track};
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::source_map::{RegionKind, SourceMapper};
+    use crate::disk_format::testgen;
+    use crate::serialize::Serializer;
+
+    /// Build a minimal 40 track D64 image with a valid BAM
+    fn d64_40_track_image() -> Vec<u8> {
+        let mut data = vec![0_u8; Geometry::commodore_1541(40).total_size()];
+        data[0x16500..0x16504].copy_from_slice(&[0x12, 0x01, 0x41, 0x00]);
+        data[0x165A5..0x165A7].copy_from_slice(b"2A");
+        data
+    }
+
+    /// Write a directory entry at a slot in a directory sector
+    fn write_entry(data: &mut [u8], offset: usize, name: &[u8], track: u8, sector: u8) {
+        data[offset + 2] = 0x82;
+        data[offset + 3] = track;
+        data[offset + 4] = sector;
+        data[offset + 5..offset + 21].fill(0xA0);
+        data[offset + 5..offset + 5 + name.len()].copy_from_slice(name);
+        data[offset + 30] = 1;
+    }
+
+    /// Test allocating and freeing sectors in the BAM, fixing free
+    /// counts and writing the BAM back out
+    #[test]
+    fn d64_bam_allocation_works() {
+        let data = testgen::d64("BAM", &[("FILE", &[1; 600])]).unwrap();
+        let sector = &data[0x16500..0x16600];
+        let (_, mut bam) = d64_block_availability_map_parser(&data).unwrap();
+        assert_eq!(bam.as_vec().unwrap(), sector);
+
+        // The file is on track one, the directory on track 18 sector 1
+        assert!(!bam.is_free(Track(1), Sector(2)));
+        assert!(bam.is_free(Track(1), Sector(3)));
+        assert!(!bam.is_free(Track(18), Sector(1)));
+        assert!(!bam.is_free(Track(1), Sector(21)));
+        assert!(!bam.is_free(Track(36), Sector(0)));
+
+        assert_eq!(bam.allocate().unwrap(), Some(SectorId::new(17, 0, 0)));
+        assert_eq!(bam.allocate().unwrap(), Some(SectorId::new(17, 0, 1)));
+        assert_eq!(bam.bam_entries[16].free_sectors_on_track, 19);
+        bam.free(Track(17), Sector(0)).unwrap();
+        bam.free(Track(17), Sector(1)).unwrap();
+        bam.free(Track(17), Sector(1)).unwrap();
+        assert_eq!(bam.as_vec().unwrap(), sector);
+        assert!(bam.free(Track(35), Sector(17)).is_err());
+
+        bam.bam_entries[4].free_sectors_on_track = 0;
+        assert_eq!(bam.recompute_free_counts().unwrap(), [Track(5)]);
+        assert_eq!(bam.as_vec().unwrap(), sector);
+
+        // Read-only disks refuse changes
+        bam.read_only = true;
+        assert!(bam.allocate().is_err());
+        assert!(bam.free(Track(17), Sector(0)).is_err());
+        assert!(bam.recompute_free_counts().is_err());
+        assert_eq!(bam.as_vec().unwrap(), sector);
+    }
+
+    /// Test parsing a directory that spans two sectors and finding the
+    /// file sectors and free sectors
+    #[test]
+    fn d64_directory_parser_works() {
+        let mut data = vec![0_u8; 174848];
+        data[0x16500..0x16504].copy_from_slice(&[0x12, 0x01, 0x41, 0x00]);
+        data[0x165A5..0x165A7].copy_from_slice(b"2A");
+        // Track 1 is free except for sector 0, every other track is in use
+        data[0x16504..0x16508].copy_from_slice(&[20, 0xFE, 0xFF, 0x1F]);
+
+        // Directory sectors 18/1 and 18/4
+        let first = 0x16600;
+        let second = 0x16600 + 3 * 256;
+        data[first..first + 2].copy_from_slice(&[18, 4]);
+        write_entry(&mut data, first, b"FIRST", 1, 0);
+        data[second..second + 2].copy_from_slice(&[0, 0xFF]);
+        write_entry(&mut data, second + 0x20, b"SECOND", 2, 0);
+        // FIRST is one sector ending at byte 9, SECOND is two sectors
+        data[1] = 9;
+        data[0x1500..0x1502].copy_from_slice(&[2, 5]);
+        data[0x1500 + 5 * 256..0x1500 + 5 * 256 + 2].copy_from_slice(&[0, 0x40]);
+
+        let (_, disk) = d64_disk_parser(&data).unwrap();
+        assert_eq!(disk.directory.len(), 2);
+        assert_eq!(disk.directory[0].filename(), "FIRST");
+        assert_eq!(disk.directory[1].filename(), "SECOND");
+
+        let extents = disk.file_extents();
+        assert_eq!(extents[0].sectors, vec![SectorId::new(1, 0, 0)]);
+        assert_eq!(extents[0].end, Some((0, 10)));
+        assert_eq!(
+            extents[1].sectors,
+            vec![SectorId::new(2, 0, 0), SectorId::new(2, 0, 5)]
+        );
+        assert_eq!(extents[1].end, Some((1, 0x41)));
+
+        let files = disk.disk_files();
+        assert_eq!(files[0].name, "FIRST");
+        assert_eq!(files[0].file_type, "PRG");
+        assert_eq!(files[0].data.len(), 8);
+        assert_eq!(files[1].data.len(), 254 + 0x3F);
+
+        let free = disk.free_sectors();
+        assert!(!free.contains(&SectorId::new(1, 0, 0)));
+        assert!(free.contains(&SectorId::new(1, 0, 1)));
+        assert!(!free.contains(&SectorId::new(2, 0, 0)));
+        assert!(disk.system_sectors().contains(&SectorId::new(18, 0, 4)));
+
+        // SECOND's sectors aren't next to each other, so it has two
+        // regions
+        let source_map = disk.source_map(&data);
+        let names: Vec<&str> = source_map.regions.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["BAM", "directory", "directory", "FIRST", "SECOND", "SECOND"]
+        );
+        assert_eq!(source_map.regions[0].offset, 0x16500);
+        assert_eq!(source_map.regions[3].kind, RegionKind::File);
+        assert_eq!(source_map.regions[4].offset, 0x1500);
+    }
+
+    /// Test that a full directory, eighteen sectors of eight entries
+    /// chained from 18/1, is read to the end
+    #[test]
+    fn d64_full_directory_works() {
+        let names: Vec<String> = (0..144).map(|n| format!("FILE{}", n)).collect();
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|name| (name.as_str(), &b"X"[..]))
+            .collect();
+        let data = testgen::d64("FULL", &files).unwrap();
+
+        let (_, (entries, sectors)) = d64_directory_parser(&data).unwrap();
+        assert_eq!(sectors.len(), 18);
+        assert_eq!(sectors[17], SectorId::new(18, 0, 18));
+        assert_eq!(entries.len(), 144);
+        assert_eq!(entries[143].filename(), "FILE143");
+    }
+
+    /// Test reading file data by following sector links, and saving a
+    /// file selected by name
+    #[test]
+    fn d64_file_data_parser_works() {
+        let mut sector = vec![0_u8; 256];
+        sector[..2].copy_from_slice(&[0, 4]);
+        sector[2..5].copy_from_slice(b"ABC");
+        assert_eq!(
+            d64_sector_data_parser(&sector).unwrap().1,
+            (SectorId::new(0, 0, 4), &b"ABC"[..])
+        );
+        sector[..2].copy_from_slice(&[1, 3]);
+        assert_eq!(d64_sector_data_parser(&sector).unwrap().1 .1.len(), 254);
+
+        let long: Vec<u8> = (0..600_usize).map(|i| (i % 251) as u8).collect();
+        let mut data = testgen::d64("FILES", &[("SHORT", b"HELLO"), ("LONG", &long)]).unwrap();
+        let (_, disk) = d64_disk_parser(&data).unwrap();
+        let files = disk.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].entry.filename(), "SHORT");
+        assert_eq!(files[0].data, b"HELLO");
+        assert_eq!(files[1].data, long);
+
+        let mut saved = Vec::new();
+        disk.save_to_writer(&Config::default(), Some("LONG"), &mut saved)
+            .unwrap();
+        assert_eq!(saved, long);
+        assert!(disk
+            .save_to_writer(&Config::default(), Some("MISSING"), &mut Vec::new())
+            .is_err());
+        assert!(disk
+            .save_to_writer(&Config::default(), None, &mut Vec::new())
+            .is_err());
+
+        // A chain that loops back on itself stops at the loop
+        let first = (
+            disk.directory[1].first_track,
+            disk.directory[1].first_sector,
+        );
+        let second = disk.file_extents()[1].sectors[1];
+        let offset = Geometry::commodore_1541(35).offset(&second).unwrap();
+        data[offset..offset + 2].copy_from_slice(&[first.0, first.1]);
+        let (_, disk) = d64_disk_parser(&data).unwrap();
+        let (_, looped) = d64_file_data_parser(&data, &disk.directory[1]).unwrap();
+        assert_eq!(looped.len(), 2 * 254);
+    }
+
+    /// Test that tracks 36 to 40 are unmanaged without an extended BAM,
+    /// and use the extended BAM when there is one
+    #[test]
+    fn d64_extra_tracks_works() {
+        let mut data = d64_40_track_image();
+        let (_, disk) = d64_disk_parser(&data).unwrap();
+        assert_eq!(
+            disk.extra_tracks(),
+            [36, 37, 38, 39, 40].map(Track).to_vec()
+        );
+        assert!(disk.extended_bam().is_none());
+        assert_eq!(disk.unmanaged_sectors().len(), 5 * 17);
+        assert!(disk.unmanaged_sectors().contains(&SectorId::new(36, 0, 0)));
+
+        // SpeedDOS BAM entries, track 36 has sectors 1 to 16 free
+        for track in 0..5 {
+            let offset = 0x16500 + 0xC0 + track * 4;
+            data[offset..offset + 4].copy_from_slice(&[17, 0xFF, 0xFF, 0x01]);
+        }
+        data[0x16500 + 0xC0..0x16500 + 0xC4].copy_from_slice(&[16, 0xFE, 0xFF, 0x01]);
+        let (_, disk) = d64_disk_parser(&data).unwrap();
+        assert_eq!(
+            disk.extended_bam().map(|(dos, _)| dos),
+            Some(ExtendedDOS::SpeedDOS)
+        );
+        assert!(disk.unmanaged_sectors().is_empty());
+        let free = disk.free_sectors();
+        assert!(!free.contains(&SectorId::new(36, 0, 0)));
+        assert!(free.contains(&SectorId::new(36, 0, 1)));
+        assert!(free.contains(&SectorId::new(40, 0, 16)));
+    }
+}
@@ -0,0 +1,688 @@
+//! G64 disk images
+//!
+//! G64 images store the GCR bit stream of each track of a Commodore
+//! 1541 disk, so they can hold copy protection, half tracks and odd
+//! speed zones that a D64 can't.  The sectors of the whole tracks are
+//! decoded into logical 256 byte sectors, so a G64 disk can be
+//! cataloged like a D64 or converted to one.
+//!
+//! The file layout is:
+//!
+//! ```ignore
+//! Header: "GCR-1541", version (0), number of track entries (usually
+//!   84, a whole track and a half track for each of 42 tracks), the
+//!   maximum track size (16 bit little endian)
+//! Track offset table: a 32 bit offset for each entry, zero for a
+//!   track that isn't stored
+//! Speed zone table: a 32 bit speed zone for each entry, 0 to 3, or
+//!   the offset of a table with a zone for every byte of the track
+//! Tracks: a 16 bit length followed by the track bytes
+//! ```
+//!
+//! Each sector on a track is a header block and a data block, each
+//! after a sync mark of at least ten one bits.  Every four bytes of a
+//! block are written as five GCR bytes:
+//!
+//! ```ignore
+//! Header block: 08, checksum, sector, track, ID 2, ID 1, 0F, 0F
+//! Data block: 07, 256 data bytes, checksum, 00, 00
+//! ```
+//!
+//! Information from:\
+//! [G64 format](http://www.unusedino.de/ec64/technical/formats/g64.html)\
+//! Inside Commodore DOS, chapter 3
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error};
+
+use nom::bytes::complete::{tag, take};
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::charset::Charset;
+use crate::disk_format::commodore::d64::{d64_disk_parser, D64Disk};
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::limit_tracks;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack, RawExporter, RawOrder};
+use crate::disk_format::mfm::unpack_bits;
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// A Commodore 1541 GCR bit stream image
+pub const G64_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "G64",
+    description: "Commodore 1541 GCR bit stream disk image",
+    extensions: &["g64"],
+    sizes: &[SizeRange::at_least(HEADER_SIZE)],
+    references: &[Reference {
+        title: "G64 format",
+        url: Some("http://www.unusedino.de/ec64/technical/formats/g64.html"),
+    }],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature at the start of a G64 image
+pub const G64_MAGIC: &[u8] = b"GCR-1541";
+
+/// The size of the header before the track offset table
+const HEADER_SIZE: usize = 12;
+
+/// The GCR code for each nybble
+pub const GCR_ENCODE: [u8; 16] = [
+    0x0A, 0x0B, 0x12, 0x13, 0x0E, 0x0F, 0x16, 0x17, 0x09, 0x19, 0x1A, 0x1B, 0x0D, 0x1D, 0x1E, 0x15,
+];
+
+/// The number of one bits that make a sync mark
+const SYNC_BITS: usize = 10;
+
+/// The block ID of a sector header block
+const HEADER_BLOCK_ID: u8 = 0x08;
+
+/// The block ID of a sector data block
+const DATA_BLOCK_ID: u8 = 0x07;
+
+/// The decoded size of a header block
+const HEADER_BLOCK_SIZE: usize = 8;
+
+/// The decoded size of a data block, the ID, the sector data, the
+/// checksum and two padding bytes
+const DATA_BLOCK_SIZE: usize = 260;
+
+/// Return true if the data starts with a G64 signature
+pub fn is_g64(data: &[u8]) -> bool {
+    data.starts_with(G64_MAGIC)
+}
+
+/// Return the standard speed zone of a track, 3 for the outer tracks
+/// with 21 sectors down to 0 for the inner tracks with 17 sectors
+pub fn speed_zone_for_track(track: u8) -> u8 {
+    match track {
+        0..=17 => 3,
+        18..=24 => 2,
+        25..=30 => 1,
+        _ => 0,
+    }
+}
+
+/// Decode a GCR code to its nybble, None for an invalid code
+fn gcr_nybble(code: u8) -> Option<u8> {
+    GCR_ENCODE
+        .iter()
+        .position(|c| *c == code)
+        .map(|nybble| nybble as u8)
+}
+
+/// Encode bytes as GCR, five bytes for every four
+/// The data is padded with zeros to a multiple of four bytes.
+pub fn gcr_encode(data: &[u8]) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::with_capacity(data.len() * 10);
+    for byte in data {
+        for nybble in [byte >> 4, byte & 0x0F] {
+            let code = GCR_ENCODE[usize::from(nybble)];
+            bits.extend((0..5).rev().map(|bit| code & (1 << bit) != 0));
+        }
+    }
+    bits.resize(bits.len().div_ceil(40) * 40, false);
+    bits.chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | u8::from(*bit)))
+        .collect()
+}
+
+/// Decode a GCR bit stream to bytes, ten bits for every byte
+/// Returns None if the stream holds an invalid GCR code.
+pub fn gcr_decode(bits: &[bool]) -> Option<Vec<u8>> {
+    bits.chunks_exact(10)
+        .map(|byte| {
+            let code = |bits: &[bool]| bits.iter().fold(0, |acc, bit| (acc << 1) | u8::from(*bit));
+            Some((gcr_nybble(code(&byte[..5]))? << 4) | gcr_nybble(code(&byte[5..]))?)
+        })
+        .collect()
+}
+
+/// Find the next sync mark in a bit stream
+/// Returns the position of the first bit after the mark.
+fn next_sync(bits: &[bool], start: usize) -> Option<usize> {
+    let mut ones = 0;
+    for (position, bit) in bits.iter().enumerate().skip(start) {
+        if *bit {
+            ones += 1;
+        } else if ones >= SYNC_BITS {
+            return Some(position);
+        } else {
+            ones = 0;
+        }
+    }
+    None
+}
+
+/// A sector header block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct G64SectorHeader {
+    /// The checksum of the sector, track and ID bytes
+    pub checksum: u8,
+    /// The sector number
+    pub sector: u8,
+    /// The track number
+    pub track: u8,
+    /// The disk ID, the second ID byte first as it's written
+    pub disk_id: [u8; 2],
+}
+
+impl SanityCheck for G64SectorHeader {
+    fn check(&self) -> bool {
+        let checksum = self.sector ^ self.track ^ self.disk_id[0] ^ self.disk_id[1];
+        if checksum != self.checksum {
+            debug!(
+                target: PARSE,
+                "Sector header checksum mismatch on track {} sector {}",
+                self.track,
+                self.sector
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Decode the sectors on a track's bit stream
+/// The stream is read twice, so a sector that crosses the end of the
+/// stream is read whole.  Sectors whose header checksum fails are
+/// skipped, sectors whose data checksum fails are kept and marked as
+/// CRC errors.
+pub fn decode_gcr_track(bits: &[bool], track: u8) -> LogicalTrack {
+    let revolution = bits.len();
+    let mut bits = bits.to_vec();
+    bits.extend_from_within(..);
+
+    let mut logical_track = LogicalTrack::new(track, 0);
+    let mut seen = BTreeSet::new();
+    let mut header: Option<G64SectorHeader> = None;
+    let mut position = 0;
+    while let Some(start) = next_sync(&bits, position) {
+        // Only the data block of a sector started in the first
+        // revolution is read from the second
+        if start - SYNC_BITS > revolution && header.is_none() {
+            break;
+        }
+        position = start;
+        let Some(block) = bits
+            .get(start..start + HEADER_BLOCK_SIZE * 10)
+            .and_then(gcr_decode)
+        else {
+            header = None;
+            continue;
+        };
+        match block[0] {
+            HEADER_BLOCK_ID => {
+                let candidate = G64SectorHeader {
+                    checksum: block[1],
+                    sector: block[2],
+                    track: block[3],
+                    disk_id: [block[4], block[5]],
+                };
+                header = candidate.check().then_some(candidate);
+            }
+            DATA_BLOCK_ID => {
+                let Some(sector_header) = header.take() else {
+                    continue;
+                };
+                if !seen.insert(sector_header.sector) {
+                    continue;
+                }
+                let Some(block) = bits
+                    .get(start..start + DATA_BLOCK_SIZE * 10)
+                    .and_then(gcr_decode)
+                else {
+                    warning(Warning::new(&format!(
+                        "couldn't decode the GCR data of track {} sector {}",
+                        track, sector_header.sector
+                    )));
+                    continue;
+                };
+                let data = &block[1..257];
+                let mut sector = LogicalSector::new(
+                    SectorId::new(sector_header.track, 0, sector_header.sector),
+                    data.to_vec(),
+                );
+                sector.crc_error = data.iter().fold(0, |acc, byte| acc ^ byte) != block[257];
+                logical_track.sectors.push(sector);
+            }
+            _ => header = None,
+        }
+    }
+
+    logical_track.sectors.sort_by_key(|sector| sector.id.sector);
+    logical_track
+}
+
+/// The G64 header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct G64Header {
+    /// The version of the format, 0
+    pub version: u8,
+    /// The number of entries in the track tables, two for each track
+    pub track_entries: u8,
+    /// The largest track size in bytes
+    pub max_track_size: u16,
+}
+
+impl SanityCheck for G64Header {
+    fn check(&self) -> bool {
+        if self.version != 0 {
+            debug!(target: PARSE, "Unknown G64 version: {}", self.version);
+            return false;
+        }
+        if self.track_entries == 0 || self.max_track_size == 0 {
+            debug!(target: PARSE, "G64 header has no tracks");
+            return false;
+        }
+        true
+    }
+}
+
+/// A track or half track in a G64 image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct G64Track<'a> {
+    /// The track number, starting at one
+    pub track: u8,
+    /// True for the half track after the track
+    pub half: bool,
+    /// The speed zone of the track, 0 to 3, or the offset of a table
+    /// of zones for each byte of the track
+    pub speed_zone: u32,
+    /// The track bytes
+    pub data: &'a [u8],
+}
+
+impl G64Track<'_> {
+    /// Return the speed zone used for the whole track, None if the
+    /// track has a zone for each byte
+    pub fn uniform_speed_zone(&self) -> Option<u8> {
+        u8::try_from(self.speed_zone).ok().filter(|zone| *zone <= 3)
+    }
+
+    /// True if the track isn't written at its standard speed zone
+    pub fn is_nonstandard_speed(&self) -> bool {
+        self.uniform_speed_zone() != Some(speed_zone_for_track(self.track))
+    }
+}
+
+/// Format a track as a line of a track table
+impl Display for G64Track<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "track: {}{}, {} bytes, speed zone: ",
+            self.track,
+            if self.half { ".5" } else { "" },
+            self.data.len()
+        )?;
+        match self.uniform_speed_zone() {
+            Some(zone) => write!(f, "{}", zone)?,
+            None => write!(f, "variable")?,
+        }
+        if self.is_nonstandard_speed() {
+            write!(f, " (standard {})", speed_zone_for_track(self.track))?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed G64 image
+pub struct G64Disk<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The G64 header
+    pub header: G64Header,
+    /// The tracks and half tracks stored in the image
+    pub tracks: Vec<G64Track<'a>>,
+    /// The sectors decoded from the whole tracks
+    pub logical_tracks: Vec<LogicalTrack>,
+    /// The character set the D64 directory is read with
+    pub charset: Charset,
+}
+
+impl G64Disk<'_> {
+    /// The half tracks stored in the image
+    pub fn half_tracks(&self) -> Vec<&G64Track<'_>> {
+        self.tracks.iter().filter(|track| track.half).collect()
+    }
+
+    /// The tracks not written at their standard speed zone
+    pub fn nonstandard_speed_tracks(&self) -> Vec<&G64Track<'_>> {
+        self.tracks
+            .iter()
+            .filter(|track| track.is_nonstandard_speed())
+            .collect()
+    }
+
+    /// A table of the tracks, one per line
+    pub fn track_table(&self) -> String {
+        self.tracks
+            .iter()
+            .map(|track| {
+                let sectors = self
+                    .logical_tracks
+                    .iter()
+                    .find(|logical| !track.half && logical.track == track.track)
+                    .map_or(0, |logical| logical.sectors.len());
+                format!("{}, sectors: {}", track, sectors)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Return the decoded sectors as a D64 image, 35 tracks unless the
+    /// disk has sectors on the extended tracks
+    /// Sectors that couldn't be decoded are zero filled.
+    pub fn d64_image(&self) -> Vec<u8> {
+        let geometry = self.raw_geometry().unwrap_or(Geometry::commodore_1541(35));
+        self.export_raw(&RawOrder::default(), &geometry)
+    }
+
+    /// Parse the D64 directory of the decoded sectors, with the
+    /// character set of this disk
+    fn d64_disk<'b>(&self, data: &'b [u8]) -> IResult<&'b [u8], D64Disk<'b>> {
+        let (i, mut d64_disk) = d64_disk_parser(data)?;
+        d64_disk.charset = self.charset;
+        Ok((i, d64_disk))
+    }
+
+    /// Return the disk name and ID from the D64 directory, None if the
+    /// decoded sectors don't have one
+    pub fn label(&self) -> Option<String> {
+        let data = self.d64_image();
+        self.d64_disk(&data)
+            .ok()
+            .map(|(_, d64_disk)| d64_disk.label())
+    }
+
+    /// Return the sectors of each file in the D64 directory
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        let data = self.d64_image();
+        self.d64_disk(&data)
+            .map(|(_, d64_disk)| d64_disk.file_extents())
+            .unwrap_or_default()
+    }
+}
+
+/// Format the header and the track table
+impl Display for G64Disk<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "G64 version {}, track entries: {}, max track size: {}\n{}",
+            self.header.version,
+            self.header.track_entries,
+            self.header.max_track_size,
+            self.track_table()
+        )
+    }
+}
+
+impl SanityCheck for G64Disk<'_> {
+    fn check(&self) -> bool {
+        self.header.check()
+    }
+}
+
+impl DiskImageSaver for G64Disk<'_> {
+    /// Save a file from the D64 directory, or the whole disk as a D64
+    /// image if no file is selected
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        let data = self.d64_image();
+        if selected_filename.is_none() {
+            writer.write_all(&data)?;
+            return Ok(());
+        }
+        let (_, d64_disk) = self.d64_disk(&data).map_err(|e| {
+            error!(target: IO, "No D64 directory on the G64 disk: {}", e);
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                "No D64 directory on the G64 disk: {}",
+                e
+            ))))
+        })?;
+        d64_disk.save_to_writer(config, selected_filename, writer)
+    }
+
+    /// Return the files in the D64 directory of the decoded sectors
+    fn disk_files(&self) -> Vec<DiskFile> {
+        let data = self.d64_image();
+        self.d64_disk(&data)
+            .map(|(_, d64_disk)| d64_disk.disk_files())
+            .unwrap_or_default()
+    }
+}
+
+impl RawExporter for G64Disk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.logical_tracks.clone()
+    }
+
+    /// A 1541 disk with 35 tracks, or 40 if there are sectors past
+    /// track 35
+    fn raw_geometry(&self) -> Option<Geometry> {
+        let last_track = self
+            .logical_tracks
+            .iter()
+            .filter(|track| !track.sectors.is_empty())
+            .map(|track| track.track)
+            .max()?;
+        Some(Geometry::commodore_1541(if last_track > 35 {
+            40
+        } else {
+            35
+        }))
+    }
+}
+
+/// The header, the track tables and the data of each track
+impl SourceMapper for G64Disk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        let entries = usize::from(self.header.track_entries) * 4;
+        map.add(RegionKind::Header, "G64 header", start, HEADER_SIZE);
+        map.add(
+            RegionKind::Header,
+            "track offset table",
+            start + HEADER_SIZE,
+            entries,
+        );
+        map.add(
+            RegionKind::Header,
+            "speed zone table",
+            start + HEADER_SIZE + entries,
+            entries,
+        );
+        for track in &self.tracks {
+            let name = format!(
+                "track {}{}",
+                track.track,
+                if track.half { ".5" } else { "" }
+            );
+            map.add_slice(data, track.data, RegionKind::Data, &name);
+        }
+
+        map
+    }
+}
+
+/// Parse the G64 header
+pub fn g64_header_parser(i: &[u8]) -> IResult<&[u8], G64Header> {
+    let (i, _magic) = tag(G64_MAGIC)(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, track_entries) = le_u8(i)?;
+    let (i, max_track_size) = le_u16(i)?;
+
+    Ok((
+        i,
+        G64Header {
+            version,
+            track_entries,
+            max_track_size,
+        },
+    ))
+}
+
+/// Parse a G64 image and decode the sectors on its whole tracks
+pub fn g64_disk_parser(i: &[u8]) -> IResult<&[u8], G64Disk<'_>> {
+    let data = i;
+    let (i, header) = g64_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[8..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let entries = usize::from(header.track_entries);
+    limit_tracks(i, entries / 2)?;
+    let (i, offsets) = count(le_u32, entries)(i)?;
+    let (i, speed_zones) = count(le_u32, entries)(i)?;
+
+    let mut tracks = Vec::new();
+    for (entry, (offset, speed_zone)) in offsets.iter().zip(speed_zones).enumerate() {
+        if *offset == 0 {
+            continue;
+        }
+        let track_data = data.get(*offset as usize..).ok_or_else(|| {
+            nom::Err::Error(nom::error::Error::new(
+                &data[data.len()..],
+                nom::error::ErrorKind::Eof,
+            ))
+        })?;
+        let (track_data, length) = le_u16(track_data)?;
+        if length > header.max_track_size {
+            error!(
+                target: PARSE,
+                "G64 track entry {} is longer than the maximum track size", entry
+            );
+            return Err(nom::Err::Error(nom::error::Error::new(
+                track_data,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (_, track_data) = take(length)(track_data)?;
+        tracks.push(G64Track {
+            track: (entry / 2 + 1) as u8,
+            half: entry % 2 == 1,
+            speed_zone,
+            data: track_data,
+        });
+    }
+
+    let logical_tracks = tracks
+        .iter()
+        .filter(|track| !track.half)
+        .map(|track| decode_gcr_track(&unpack_bits(track.data), track.track))
+        .collect();
+    debug!(target: PARSE, "G64 header: {:?}", header);
+
+    Ok((
+        i,
+        G64Disk {
+            data,
+            header,
+            tracks,
+            logical_tracks,
+            charset: Charset::DEFAULT,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_gcr_track, g64_disk_parser, gcr_decode, gcr_encode, is_g64};
+    use crate::disk_format::commodore::d64::d64_disk_parser;
+    use crate::disk_format::image::{DiskImageParser, DiskImageSaver};
+    use crate::disk_format::mfm::unpack_bits;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test decoding the tracks of a G64 image to the D64 image it was
+    /// built from, with a damaged sector and a half track
+    #[test]
+    fn g64_disk_parser_works() {
+        let encoded = gcr_encode(&[0x08, 0x12, 0xFF, 0x00]);
+        assert_eq!(encoded.len(), 5);
+        assert_eq!(
+            gcr_decode(&unpack_bits(&encoded)),
+            Some(vec![0x08, 0x12, 0xFF, 0x00])
+        );
+        assert_eq!(gcr_decode(&[false; 10]), None);
+
+        let d64 = testgen::d64("TEST", &[("HELLO", &[0x42; 600])]).unwrap();
+        let g64 = testgen::g64(&d64, &[]);
+        assert!(is_g64(&g64));
+        let (_, disk) = g64_disk_parser(&g64).unwrap();
+        assert!(disk.check());
+        assert_eq!(disk.tracks.len(), 35);
+        assert!(disk.half_tracks().is_empty());
+        assert!(disk.nonstandard_speed_tracks().is_empty());
+        assert_eq!(disk.logical_tracks[17].sectors.len(), 19);
+        assert_eq!(disk.d64_image(), d64);
+
+        let files = disk.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "HELLO");
+        assert_eq!(files[0].data, [0x42; 600]);
+        let (_, d64_disk) = d64_disk_parser(&d64).unwrap();
+        assert_eq!(disk.file_extents(), d64_disk.file_extents());
+
+        let config = Options::default();
+        let image = g64.parse_disk_image(&config, "game.g64").unwrap().value;
+        assert_eq!(image.format_name(), "G64 Disk");
+        assert_eq!(image.label(), Some(String::from("TEST,00")));
+        assert_eq!(image.to_bytes(&config, None).unwrap(), d64);
+        assert_eq!(image.to_bytes(&config, Some("HELLO")).unwrap(), [0x42; 600]);
+
+        // A damaged data checksum, and track 1 stored again as a half
+        // track with a speed zone for every byte
+        let mut damaged = testgen::g64(&d64, &[(1, 3)]);
+        let (first, speed) = (12, 12 + 84 * 4);
+        damaged.copy_within(first..first + 4, first + 4);
+        damaged[speed + 4..speed + 8].copy_from_slice(&0x1000_u32.to_le_bytes());
+        let (_, disk) = g64_disk_parser(&damaged).unwrap();
+        assert!(disk.logical_tracks[0].sectors[3].crc_error);
+        assert!(!disk.logical_tracks[0].sectors[4].crc_error);
+        assert_eq!(disk.half_tracks().len(), 1);
+        assert_eq!(disk.nonstandard_speed_tracks().len(), 1);
+        assert!(disk
+            .track_table()
+            .contains("track: 1.5, 7692 bytes, speed zone: variable (standard 3), sectors: 0"));
+
+        let track = &disk.tracks[0];
+        let bits = unpack_bits(track.data);
+        // Start the stream halfway through a sector
+        let rotated = [&bits[3000..], &bits[..3000]].concat();
+        assert_eq!(decode_gcr_track(&rotated, 1).sectors.len(), 21);
+
+        let mut truncated = g64.clone();
+        truncated.truncate(g64.len() - 4000);
+        assert!(g64_disk_parser(&truncated).is_err());
+    }
+}
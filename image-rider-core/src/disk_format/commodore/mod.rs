@@ -0,0 +1,15 @@
+//! Parse a Commodore disk image
+//!
+//! This parses Commodore disk images.
+//!
+//! Currently this includes support for parsing D64 disk images and
+//! decoding the GCR tracks of G64 disk images.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// Disk-level functions and data structures for D64 disks.
+pub mod d64;
+
+/// G64 GCR bit stream disk images
+pub mod g64;
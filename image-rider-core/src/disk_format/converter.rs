@@ -0,0 +1,266 @@
+//! External converters for formats that aren't read natively
+//!
+//! Some formats aren't supported yet, but other tools can already
+//! convert them to one that is: samdisk writes most floppy formats as
+//! DSK or flat images and unar unpacks archives.  An external converter
+//! is a command line run on the input file, whose output is parsed in
+//! place of the input.  The command writes the converted image to the
+//! {output} path in its arguments, or to standard output if there's no
+//! {output}.
+//!
+//! Running other programs on an image is opt-in: converters are only
+//! used when allow-external-converters is set.  They're configured by
+//! name with the extensions they handle:
+//!
+//! ```toml
+//! allow-external-converters = true
+//!
+//! [converters.samdisk]
+//! command = "samdisk"
+//! args = ["copy", "{input}", "{output}"]
+//! extensions = ["td0", "scp"]
+//! output-extension = "dsk"
+//! ```
+//!
+//! The output extension names the temporary output file, some tools
+//! pick the format to write from it, and is the extension the
+//! converted image is parsed with.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use config::Config;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::error::{Error, ErrorKind};
+use crate::log_target::IO;
+
+/// The placeholder for the input path in a converter's arguments
+pub const INPUT_PLACEHOLDER: &str = "{input}";
+
+/// The placeholder for the output path in a converter's arguments
+pub const OUTPUT_PLACEHOLDER: &str = "{output}";
+
+/// A counter for naming temporary output files
+static OUTPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A command that converts images in an unsupported format to one that
+/// can be parsed
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ExternalConverter {
+    /// The program to run
+    pub command: String,
+    /// The arguments, with {input} and {output} replaced by the paths
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The file extensions the converter handles, without the dot
+    pub extensions: Vec<String>,
+    /// The extension of the converted image
+    #[serde(default = "default_output_extension")]
+    pub output_extension: String,
+}
+
+/// Converted images are parsed as flat images unless the converter
+/// says otherwise
+fn default_output_extension() -> String {
+    String::from("img")
+}
+
+/// Build an error for a converter that couldn't be run or failed
+fn converter_error(message: String) -> Error {
+    Error::new(ErrorKind::Message(message))
+}
+
+impl ExternalConverter {
+    /// True if the converter handles the extension of a path
+    pub fn handles(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|extension| {
+            self.extensions
+                .iter()
+                .any(|handled| extension.eq_ignore_ascii_case(handled.as_str()))
+        })
+    }
+
+    /// Run the converter on a file and return the converted image
+    /// The output is read from a temporary file if the arguments name
+    /// an {output}, otherwise from standard output.
+    pub fn convert(&self, input: &Path) -> std::result::Result<Vec<u8>, Error> {
+        let writes_file = self.args.iter().any(|arg| arg.contains(OUTPUT_PLACEHOLDER));
+        let output = temporary_output(&self.output_extension);
+        let args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| {
+                arg.replace(INPUT_PLACEHOLDER, &input.to_string_lossy())
+                    .replace(OUTPUT_PLACEHOLDER, &output.to_string_lossy())
+            })
+            .collect();
+
+        info!(target: IO, "Converting {} with {}", input.display(), self.command);
+        let result = Command::new(&self.command).args(&args).output();
+        let converted = match result {
+            Err(e) => Err(converter_error(format!(
+                "Couldn't run {}: {}",
+                self.command, e
+            ))),
+            Ok(result) if !result.status.success() => Err(converter_error(format!(
+                "{} failed with {}: {}",
+                self.command,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ))),
+            Ok(_) if writes_file => fs::read(&output).map_err(Error::from),
+            Ok(result) => Ok(result.stdout),
+        };
+        if writes_file && output.exists() {
+            if let Err(e) = fs::remove_file(&output) {
+                warn!(target: IO, "Couldn't remove {}: {}", output.display(), e);
+            }
+        }
+
+        converted
+    }
+}
+
+/// Return a path for a converter's output in the temporary directory
+fn temporary_output(extension: &str) -> PathBuf {
+    let count = OUTPUT_COUNT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "image-rider-{}-{}.{}",
+        std::process::id(),
+        count,
+        extension
+    ))
+}
+
+/// The external converters in a configuration, by name
+/// Returns no converters unless allow-external-converters is set.
+/// Converters that can't be read are skipped with a warning.
+pub fn converters_from_config(config: &Config) -> BTreeMap<String, ExternalConverter> {
+    if !config
+        .get_bool("allow-external-converters")
+        .unwrap_or(false)
+    {
+        return BTreeMap::new();
+    }
+    match config.get::<BTreeMap<String, ExternalConverter>>("converters") {
+        Ok(converters) => converters,
+        Err(config::ConfigError::NotFound(_)) => BTreeMap::new(),
+        Err(e) => {
+            warn!(target: IO, "Couldn't read the external converters: {}", e);
+            BTreeMap::new()
+        }
+    }
+}
+
+/// An image converted by an external converter
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConvertedImage {
+    /// The name of the converter that was run
+    pub converter: String,
+    /// The converted image
+    pub data: Vec<u8>,
+    /// The input file name with the converter's output extension, for
+    /// guessing the format of the converted image
+    pub filename: String,
+}
+
+/// Convert a file with the first configured converter that handles its
+/// extension
+/// Returns None if no converter handles the file, which is always the
+/// case unless external converters are allowed.
+pub fn convert_external(
+    config: &Config,
+    path: &Path,
+) -> std::result::Result<Option<ConvertedImage>, Error> {
+    let converters = converters_from_config(config);
+    let Some((name, converter)) = converters
+        .iter()
+        .find(|(_, converter)| converter.handles(path))
+    else {
+        return Ok(None);
+    };
+
+    let data = converter.convert(path)?;
+    info!(
+        target: IO,
+        "Converted {} with {}: {} bytes",
+        path.display(),
+        name,
+        data.len()
+    );
+    Ok(Some(ConvertedImage {
+        converter: name.clone(),
+        data,
+        filename: path
+            .with_extension(&converter.output_extension)
+            .to_string_lossy()
+            .to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use config::Config;
+
+    use super::{convert_external, converters_from_config};
+
+    /// Test that converters are opt-in and are run on the files they
+    /// handle, with the output read from a file or standard output
+    #[cfg(unix)]
+    #[test]
+    fn convert_external_works() {
+        let input = std::env::temp_dir().join(format!("image-rider-{}.td0", std::process::id()));
+        std::fs::write(&input, [1, 2, 3]).unwrap();
+        let build = |allow: bool| {
+            Config::builder()
+                .set_override("allow-external-converters", allow)
+                .unwrap()
+                .set_override("converters.copy.command", "cp")
+                .unwrap()
+                .set_override("converters.copy.args", vec!["{input}", "{output}"])
+                .unwrap()
+                .set_override("converters.copy.extensions", vec!["TD0"])
+                .unwrap()
+                .set_override("converters.copy.output-extension", "dsk")
+                .unwrap()
+                .set_override("converters.print.command", "cat")
+                .unwrap()
+                .set_override("converters.print.args", vec!["{input}"])
+                .unwrap()
+                .set_override("converters.print.extensions", vec!["scp"])
+                .unwrap()
+                .build()
+                .unwrap()
+        };
+
+        assert!(converters_from_config(&build(false)).is_empty());
+        assert_eq!(convert_external(&build(false), &input).unwrap(), None);
+
+        let config = build(true);
+        assert_eq!(converters_from_config(&config).len(), 2);
+        let converted = convert_external(&config, &input).unwrap().unwrap();
+        assert_eq!(converted.converter, "copy");
+        assert_eq!(converted.data, [1, 2, 3]);
+        assert!(converted.filename.ends_with(".dsk"));
+
+        let scp = input.with_extension("scp");
+        std::fs::rename(&input, &scp).unwrap();
+        let converted = convert_external(&config, &scp).unwrap().unwrap();
+        assert_eq!(converted.data, [1, 2, 3]);
+        assert!(converted.filename.ends_with(".img"));
+        std::fs::remove_file(&scp).unwrap();
+
+        assert!(convert_external(&config, &scp).is_err());
+        assert_eq!(
+            convert_external(&config, Path::new("disk.d64")).unwrap(),
+            None
+        );
+    }
+}
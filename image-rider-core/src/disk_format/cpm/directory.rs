@@ -0,0 +1,216 @@
+//! Directory entries
+//!
+//! A CP/M directory is a fixed number of 32 byte entries in the first
+//! blocks after the system tracks.  There are no subdirectories, files
+//! are kept apart by a user number from 0 to 15 instead.  Each entry is
+//! an extent, holding the block numbers of up to 16K of a file or more
+//! on disks with large blocks; longer files have an entry for each
+//! extent, numbered from zero.  The record count of the last extent
+//! gives the length of the file in 128 byte records.
+use std::fmt::{Display, Formatter, Result};
+
+use nom::bytes::complete::take;
+use nom::multi::many0;
+use nom::number::complete::le_u8;
+use nom::IResult;
+
+use crate::disk_format::cpm::dpb::{DiskParameterBlock, RECORDS_PER_EXTENT, RECORD_SIZE};
+
+/// The user number of an unused or deleted entry
+pub const DELETED_USER: u8 = 0xE5;
+
+/// The highest user number of a file entry
+/// Higher numbers are used by CP/M 3 for labels and timestamps.
+pub const MAX_USER: u8 = 15;
+
+/// A directory entry, one extent of a file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectoryEntry {
+    /// The user number, or 0xE5 for an unused entry
+    pub user: u8,
+    /// The name, padded with spaces
+    /// The high bits of the name bytes are attribute flags on some
+    /// systems.
+    pub name: [u8; 8],
+    /// The extension, padded with spaces
+    /// The high bits hold the read only, system and archive flags.
+    pub extension: [u8; 3],
+    /// EX, the low five bits of the extent number
+    pub extent_low: u8,
+    /// S1, the number of bytes used in the last record on CP/M 3, zero
+    /// if the whole record is used
+    pub last_record_bytes: u8,
+    /// S2, the high bits of the extent number
+    pub extent_high: u8,
+    /// RC, the number of records used in the last logical extent of
+    /// the entry
+    pub record_count: u8,
+    /// The block numbers, sixteen bytes or eight little-endian words
+    pub allocation: [u8; 16],
+}
+
+impl DirectoryEntry {
+    /// True if the entry is unused or deleted
+    pub fn is_deleted(&self) -> bool {
+        self.user == DELETED_USER
+    }
+
+    /// True if the entry is an extent of a file
+    pub fn is_file(&self) -> bool {
+        self.user <= MAX_USER
+    }
+
+    /// The extent number, counting 16K logical extents
+    pub fn extent(&self) -> usize {
+        (usize::from(self.extent_high & 0x3F) << 5) | usize::from(self.extent_low & 0x1F)
+    }
+
+    /// The index of the entry in the file, counting directory entries
+    /// rather than logical extents
+    pub fn entry_index(&self, dpb: &DiskParameterBlock) -> usize {
+        self.extent() / (usize::from(dpb.extent_mask) + 1)
+    }
+
+    /// The number of records in the file up to the end of this entry
+    pub fn records(&self) -> usize {
+        self.extent() * RECORDS_PER_EXTENT + usize::from(self.record_count)
+    }
+
+    /// The number of bytes in the file up to the end of this entry
+    pub fn size(&self) -> usize {
+        match (self.records(), self.last_record_bytes) {
+            (0, _) | (_, 0) => self.records() * RECORD_SIZE,
+            (records, bytes) => (records - 1) * RECORD_SIZE + usize::from(bytes.min(128)),
+        }
+    }
+
+    /// The block numbers in the entry, without the unused zeros
+    pub fn blocks(&self, dpb: &DiskParameterBlock) -> Vec<usize> {
+        let blocks: Vec<usize> = if dpb.is_large() {
+            self.allocation
+                .chunks(2)
+                .map(|word| usize::from(u16::from_le_bytes([word[0], word[1]])))
+                .collect()
+        } else {
+            self.allocation
+                .iter()
+                .map(|block| usize::from(*block))
+                .collect()
+        };
+        blocks.into_iter().filter(|block| *block != 0).collect()
+    }
+
+    /// The name and extension as they're stored in the directory
+    pub fn raw_name(&self) -> Vec<u8> {
+        let mut raw_name = self.name.to_vec();
+        raw_name.extend_from_slice(&self.extension);
+        raw_name
+    }
+
+    /// The name with the attribute bits and trailing spaces removed,
+    /// joined to the extension with a dot if there is one
+    pub fn filename(&self) -> String {
+        let name = trim_name(&self.name);
+        let extension = trim_name(&self.extension);
+        if extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, extension)
+        }
+    }
+
+    /// True if the file can't be written
+    pub fn is_read_only(&self) -> bool {
+        self.extension[0] & 0x80 != 0
+    }
+
+    /// True if the file is hidden from directory listings
+    pub fn is_system(&self) -> bool {
+        self.extension[1] & 0x80 != 0
+    }
+
+    /// True if the file has been backed up
+    pub fn is_archived(&self) -> bool {
+        self.extension[2] & 0x80 != 0
+    }
+
+    /// The attributes as a string of flags, e.g. "R-A"
+    pub fn attribute_string(&self) -> String {
+        [
+            (self.is_read_only(), 'R'),
+            (self.is_system(), 'S'),
+            (self.is_archived(), 'A'),
+        ]
+        .iter()
+        .map(|(set, flag)| if *set { *flag } else { '-' })
+        .collect()
+    }
+}
+
+/// Format an entry as a line of an extent listing
+impl Display for DirectoryEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{:>2}:{:<12} extent {:>3} records {:>3} {}",
+            self.user,
+            self.filename(),
+            self.extent(),
+            self.record_count,
+            self.attribute_string()
+        )
+    }
+}
+
+/// Convert a space padded name to a string, dropping the attribute
+/// bits
+/// Bytes outside printable ASCII are shown as '?'.
+fn trim_name(name: &[u8]) -> String {
+    let name: Vec<u8> = name.iter().map(|b| b & 0x7F).collect();
+    let end = name
+        .iter()
+        .rposition(|b| *b != b' ')
+        .map_or(0, |position| position + 1);
+    name[..end]
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                char::from(*b)
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+/// Parse a directory entry
+pub fn directory_entry_parser(i: &[u8]) -> IResult<&[u8], DirectoryEntry> {
+    let (i, user) = le_u8(i)?;
+    let (i, name) = take(8_usize)(i)?;
+    let (i, extension) = take(3_usize)(i)?;
+    let (i, extent_low) = le_u8(i)?;
+    let (i, last_record_bytes) = le_u8(i)?;
+    let (i, extent_high) = le_u8(i)?;
+    let (i, record_count) = le_u8(i)?;
+    let (i, allocation) = take(16_usize)(i)?;
+
+    Ok((
+        i,
+        DirectoryEntry {
+            user,
+            name: name.try_into().unwrap(),
+            extension: extension.try_into().unwrap(),
+            extent_low,
+            last_record_bytes,
+            extent_high,
+            record_count,
+            allocation: allocation.try_into().unwrap(),
+        },
+    ))
+}
+
+/// Parse the entries of a directory
+/// Unlike FAT there's no end marker, every entry is returned.
+pub fn directory_parser(i: &[u8]) -> IResult<&[u8], Vec<DirectoryEntry>> {
+    many0(directory_entry_parser)(i)
+}
@@ -0,0 +1,451 @@
+//! Disk parameter blocks and CP/M disk formats
+//!
+//! CP/M doesn't store the layout of a disk on the disk.  The BIOS of
+//! each machine holds a disk parameter block (DPB) for every format it
+//! reads: the number of 128 byte records on a track, the allocation
+//! block size, the number of blocks and directory entries and the
+//! number of reserved system tracks before the directory.  A CpmFormat
+//! adds the physical sector size and sector skew, and well known
+//! formats are available by name.
+use std::fmt::{Display, Formatter, Result};
+
+use config::Config;
+use log::debug;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// The size of a CP/M record, the unit the BDOS reads and writes
+pub const RECORD_SIZE: usize = 128;
+
+/// The size of a directory entry
+pub const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+/// The number of records in a logical extent, 16K
+pub const RECORDS_PER_EXTENT: usize = 128;
+
+/// The layout of a CP/M filesystem, as the BIOS describes it to the
+/// BDOS
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiskParameterBlock {
+    /// SPT, the number of 128 byte records on each track
+    pub records_per_track: u16,
+    /// BSH, the block shift, log2 of the block size in records
+    pub block_shift: u8,
+    /// BLM, the block mask, the block size in records minus one
+    pub block_mask: u8,
+    /// EXM, the extent mask, the number of logical extents in each
+    /// directory entry minus one
+    pub extent_mask: u8,
+    /// DSM, the number of the last block on the disk
+    pub max_block: u16,
+    /// DRM, the number of the last directory entry
+    pub max_directory_entry: u16,
+    /// AL0 and AL1, a bit for each block holding the directory,
+    /// starting with the most significant bit of AL0
+    pub directory_allocation: u16,
+    /// CKS, the size of the directory check vector, zero for fixed
+    /// disks
+    pub check_size: u16,
+    /// OFF, the number of reserved system tracks before the directory
+    pub reserved_tracks: u16,
+}
+
+impl DiskParameterBlock {
+    /// Build a parameter block from sizes in bytes
+    /// The shift, masks, directory allocation and check vector size
+    /// are worked out the way the CP/M 2.2 alteration guide describes.
+    /// Returns an error if the block size isn't a power of two from 1K
+    /// to 16K or the directory doesn't fit in sixteen blocks.
+    pub fn new(
+        records_per_track: u16,
+        block_size: usize,
+        blocks: usize,
+        directory_entries: usize,
+        reserved_tracks: u16,
+    ) -> std::result::Result<DiskParameterBlock, Error> {
+        if !(1024..=16384).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(dpb_error(format!(
+                "Invalid CP/M block size: {}",
+                block_size
+            )));
+        }
+        if !(1..=65536).contains(&blocks) || directory_entries == 0 {
+            return Err(dpb_error(format!(
+                "Invalid CP/M block or directory entry count: {}, {}",
+                blocks, directory_entries
+            )));
+        }
+        let directory_blocks = (directory_entries * DIRECTORY_ENTRY_SIZE).div_ceil(block_size);
+        if directory_blocks > 16 {
+            return Err(dpb_error(format!(
+                "{} directory entries don't fit in sixteen {} byte blocks",
+                directory_entries, block_size
+            )));
+        }
+
+        let block_records = block_size / RECORD_SIZE;
+        // Each directory entry holds sixteen one byte block numbers on
+        // small disks and eight two byte block numbers on large ones
+        let pointers = if blocks > 256 { 8 } else { 16 };
+        Ok(DiskParameterBlock {
+            records_per_track,
+            block_shift: block_records.trailing_zeros() as u8,
+            block_mask: (block_records - 1) as u8,
+            extent_mask: ((pointers * block_records / RECORDS_PER_EXTENT).max(1) - 1) as u8,
+            max_block: (blocks - 1) as u16,
+            max_directory_entry: (directory_entries - 1) as u16,
+            directory_allocation: !(0xFFFF_u16 >> directory_blocks),
+            check_size: (directory_entries / 4) as u16,
+            reserved_tracks,
+        })
+    }
+
+    /// The size of an allocation block in bytes
+    pub fn block_size(&self) -> usize {
+        RECORD_SIZE << self.block_shift
+    }
+
+    /// The number of allocation blocks
+    pub fn blocks(&self) -> usize {
+        usize::from(self.max_block) + 1
+    }
+
+    /// The number of directory entries
+    pub fn directory_entries(&self) -> usize {
+        usize::from(self.max_directory_entry) + 1
+    }
+
+    /// The number of blocks holding the directory
+    pub fn directory_blocks(&self) -> usize {
+        self.directory_allocation.leading_ones() as usize
+    }
+
+    /// The size of a track in bytes
+    pub fn track_size(&self) -> usize {
+        usize::from(self.records_per_track) * RECORD_SIZE
+    }
+
+    /// True if directory entries hold two byte block numbers, on disks
+    /// with more than 256 blocks
+    pub fn is_large(&self) -> bool {
+        self.max_block > 255
+    }
+
+    /// The number of block numbers in each directory entry
+    pub fn blocks_per_entry(&self) -> usize {
+        if self.is_large() {
+            8
+        } else {
+            16
+        }
+    }
+}
+
+impl SanityCheck for DiskParameterBlock {
+    fn check(&self) -> bool {
+        if !(3..=7).contains(&self.block_shift)
+            || usize::from(self.block_mask) != (1 << self.block_shift) - 1
+        {
+            debug!(
+                target: PARSE,
+                "Invalid CP/M block shift and mask: {}, {}", self.block_shift, self.block_mask
+            );
+            return false;
+        }
+        if self.records_per_track == 0 {
+            debug!(target: PARSE, "CP/M tracks have no records");
+            return false;
+        }
+        if self.directory_blocks() == 0
+            || self.directory_allocation.count_ones() != self.directory_allocation.leading_ones()
+            || self.directory_blocks() > self.blocks()
+        {
+            debug!(
+                target: PARSE,
+                "Invalid CP/M directory allocation: 0x{:04X}", self.directory_allocation
+            );
+            return false;
+        }
+        if self.directory_entries() * DIRECTORY_ENTRY_SIZE
+            > self.directory_blocks() * self.block_size()
+        {
+            debug!(target: PARSE, "The CP/M directory blocks are too small for the entries");
+            return false;
+        }
+        true
+    }
+}
+
+impl Display for DiskParameterBlock {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "SPT: {}, BSH: {}, BLM: {}, EXM: {}, DSM: {}, DRM: {}, AL0: 0x{:02X}, AL1: 0x{:02X}, CKS: {}, OFF: {}",
+            self.records_per_track,
+            self.block_shift,
+            self.block_mask,
+            self.extent_mask,
+            self.max_block,
+            self.max_directory_entry,
+            self.directory_allocation >> 8,
+            self.directory_allocation & 0xFF,
+            self.check_size,
+            self.reserved_tracks
+        )
+    }
+}
+
+/// Build the error for a bad parameter block or format
+fn dpb_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+/// Parse a disk parameter block as it's stored in a BIOS, fifteen
+/// bytes in little-endian order
+pub fn disk_parameter_block_parser(i: &[u8]) -> IResult<&[u8], DiskParameterBlock> {
+    let (i, records_per_track) = le_u16(i)?;
+    let (i, block_shift) = le_u8(i)?;
+    let (i, block_mask) = le_u8(i)?;
+    let (i, extent_mask) = le_u8(i)?;
+    let (i, max_block) = le_u16(i)?;
+    let (i, max_directory_entry) = le_u16(i)?;
+    let (i, al0) = le_u8(i)?;
+    let (i, al1) = le_u8(i)?;
+    let (i, check_size) = le_u16(i)?;
+    let (i, reserved_tracks) = le_u16(i)?;
+
+    Ok((
+        i,
+        DiskParameterBlock {
+            records_per_track,
+            block_shift,
+            block_mask,
+            extent_mask,
+            max_block,
+            max_directory_entry,
+            directory_allocation: u16::from_be_bytes([al0, al1]),
+            check_size,
+            reserved_tracks,
+        },
+    ))
+}
+
+/// A CP/M disk format, the parameter block and the physical layout of
+/// the sectors it's read from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpmFormat {
+    /// The name of the format
+    pub name: String,
+    /// The disk parameter block
+    pub dpb: DiskParameterBlock,
+    /// The size of a physical sector in bytes
+    pub sector_size: usize,
+    /// The sector skew applied by the BIOS on the data tracks, one for
+    /// no skew
+    /// A flat image holds the sectors of each track in physical order,
+    /// so logical sector n is read from the nth entry of the skew
+    /// table.
+    pub skew: usize,
+}
+
+/// The names of the built in formats
+pub const CPM_FORMAT_NAMES: [&str; 5] = [
+    "ibm-3740",
+    "kaypro2",
+    "kaypro4",
+    "amstrad-data",
+    "amstrad-system",
+];
+
+impl CpmFormat {
+    /// Return a built in format by name
+    /// Returns None if the name isn't known.
+    pub fn named(name: &str) -> Option<CpmFormat> {
+        // (records per track, block size, blocks, directory entries,
+        //  reserved tracks, sector size, skew)
+        let (spt, block_size, blocks, entries, off, sector_size, skew) =
+            match name.to_lowercase().as_str() {
+                // The original 8" single sided single density format
+                "ibm-3740" => (26, 1024, 243, 64, 2, 128, 6),
+                // Kaypro II, single sided 5.25" double density
+                "kaypro2" => (40, 1024, 195, 64, 1, 512, 1),
+                // Kaypro 4, double sided, the sides of a cylinder are
+                // separate logical tracks
+                "kaypro4" => (40, 2048, 197, 64, 1, 512, 1),
+                // Amstrad CPC and PCW data format, no system tracks
+                "amstrad-data" => (36, 1024, 180, 64, 0, 512, 1),
+                // Amstrad CPC system format, with the boot tracks
+                "amstrad-system" => (36, 1024, 171, 64, 2, 512, 1),
+                _ => return None,
+            };
+        Some(CpmFormat {
+            name: name.to_lowercase(),
+            dpb: DiskParameterBlock::new(spt, block_size, blocks, entries, off).ok()?,
+            sector_size,
+            skew,
+        })
+    }
+
+    /// Build a format from a configuration
+    /// cpm-format names a built in format to start from, kaypro2 if
+    /// it's missing.  Any of cpm-records-per-track, cpm-block-size,
+    /// cpm-blocks, cpm-directory-entries, cpm-reserved-tracks,
+    /// cpm-sector-size and cpm-skew override it.
+    /// Returns an error if the format isn't known or the result isn't
+    /// a valid layout.
+    pub fn from_config(config: &Config) -> std::result::Result<CpmFormat, Error> {
+        let name = config
+            .get_string("cpm-format")
+            .unwrap_or_else(|_| String::from("kaypro2"));
+        let base = CpmFormat::named(&name).ok_or_else(|| {
+            dpb_error(format!(
+                "Unknown CP/M format: {}, expected one of {}",
+                name,
+                CPM_FORMAT_NAMES.join(", ")
+            ))
+        })?;
+        let get = |key: &str, default: usize| {
+            config
+                .get_int(key)
+                .ok()
+                .and_then(|value| usize::try_from(value).ok())
+                .unwrap_or(default)
+        };
+
+        let records_per_track = get(
+            "cpm-records-per-track",
+            usize::from(base.dpb.records_per_track),
+        );
+        let reserved_tracks = get("cpm-reserved-tracks", usize::from(base.dpb.reserved_tracks));
+        let dpb = DiskParameterBlock::new(
+            u16::try_from(records_per_track).map_err(|_| {
+                dpb_error(format!("Invalid CP/M track size: {}", records_per_track))
+            })?,
+            get("cpm-block-size", base.dpb.block_size()),
+            get("cpm-blocks", base.dpb.blocks()),
+            get("cpm-directory-entries", base.dpb.directory_entries()),
+            u16::try_from(reserved_tracks).map_err(|_| {
+                dpb_error(format!("Invalid CP/M reserved tracks: {}", reserved_tracks))
+            })?,
+        )?;
+        let format = CpmFormat {
+            name: base.name,
+            dpb,
+            sector_size: get("cpm-sector-size", base.sector_size),
+            skew: get("cpm-skew", base.skew),
+        };
+        if !format.check() {
+            return Err(dpb_error(format!("Invalid CP/M format: {}", format)));
+        }
+        Ok(format)
+    }
+
+    /// The number of physical sectors on a track
+    pub fn sectors_per_track(&self) -> usize {
+        self.dpb.track_size() / self.sector_size.max(1)
+    }
+
+    /// The skew table, the physical sector holding each logical
+    /// sector of a track
+    /// Sectors already used are skipped, the way cpmtools builds the
+    /// table.
+    pub fn skew_table(&self) -> Vec<usize> {
+        let sectors = self.sectors_per_track();
+        let mut table: Vec<usize> = Vec::with_capacity(sectors);
+        let mut next = 0;
+        for _ in 0..sectors {
+            while table.contains(&next) {
+                next = (next + 1) % sectors;
+            }
+            table.push(next);
+            next = (next + self.skew.max(1)) % sectors;
+        }
+        table
+    }
+}
+
+impl SanityCheck for CpmFormat {
+    fn check(&self) -> bool {
+        if !(128..=4096).contains(&self.sector_size) || !self.sector_size.is_power_of_two() {
+            debug!(target: PARSE, "Invalid CP/M sector size: {}", self.sector_size);
+            return false;
+        }
+        if self.dpb.track_size() % self.sector_size != 0 {
+            debug!(
+                target: PARSE,
+                "CP/M tracks of {} bytes don't hold whole {} byte sectors",
+                self.dpb.track_size(),
+                self.sector_size
+            );
+            return false;
+        }
+        self.dpb.check()
+    }
+}
+
+impl Display for CpmFormat {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}: {} byte sectors, skew {}, {}",
+            self.name, self.sector_size, self.skew, self.dpb
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{disk_parameter_block_parser, CpmFormat, DiskParameterBlock, CPM_FORMAT_NAMES};
+    use crate::disk_format::sanity_check::SanityCheck;
+
+    /// Test that parameter blocks built from sizes match the ones in
+    /// real BIOSes, and that the built in formats are valid
+    #[test]
+    fn disk_parameter_block_works() {
+        // The 8" format from the CP/M 2.2 alteration guide
+        let bios = [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xC0, 0, 16, 0, 2, 0];
+        let (_, dpb) = disk_parameter_block_parser(&bios).unwrap();
+        assert_eq!(DiskParameterBlock::new(26, 1024, 243, 64, 2).unwrap(), dpb);
+        assert_eq!(dpb.directory_blocks(), 2);
+        assert!(dpb.check());
+
+        // Two byte block numbers on large disks
+        let large = DiskParameterBlock::new(64, 2048, 512, 256, 0).unwrap();
+        assert!(large.is_large());
+        assert_eq!((large.block_shift, large.block_mask), (4, 15));
+        assert_eq!(large.extent_mask, 0);
+        assert!(DiskParameterBlock::new(26, 1000, 243, 64, 2).is_err());
+
+        for name in CPM_FORMAT_NAMES {
+            assert!(CpmFormat::named(name).unwrap().check(), "{}", name);
+        }
+
+        let format = CpmFormat::named("ibm-3740").unwrap();
+        assert_eq!(format.skew_table()[..8], [0, 6, 12, 18, 24, 4, 10, 16]);
+
+        let config = Config::builder()
+            .set_override("cpm-format", "amstrad-data")
+            .unwrap()
+            .set_override("cpm-directory-entries", 128)
+            .unwrap()
+            .build()
+            .unwrap();
+        let format = CpmFormat::from_config(&config).unwrap();
+        assert_eq!(format.dpb.directory_entries(), 128);
+        assert_eq!(format.dpb.directory_blocks(), 4);
+        assert_eq!(format.dpb.track_size(), 9 * 512);
+
+        let config = Config::builder()
+            .set_override("cpm-format", "osborne")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(CpmFormat::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,19 @@
+//! CP/M filesystems
+//!
+//! CP/M ran on machines with all kinds of disk drives, and disks from
+//! the Kaypro, Osborne, Amstrad CPC and many others hold a CP/M
+//! filesystem on otherwise ordinary sectors.  Because the layout isn't
+//! stored on the disk, a volume is read with a disk parameter block
+//! describing it, and can be layered on the sectors of any image.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// Disk parameter blocks and disk formats
+pub mod dpb;
+
+/// Directory entries and extents
+pub mod directory;
+
+/// Parsing volumes and reading their files
+pub mod volume;
@@ -0,0 +1,306 @@
+//! CP/M volumes
+//!
+//! A volume is read from a flat image of a disk's sectors with a
+//! CpmFormat describing its layout, so it can be layered on the sectors
+//! of any image the crate reads, or on a plain sector dump.  The
+//! directory is read from the first blocks after the system tracks and
+//! its extents are gathered into files by user number and name.  Files
+//! are read by joining the blocks of their extents and cutting the data
+//! at the record count of the last extent.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+
+use log::{debug, warn};
+
+use crate::disk_format::cpm::directory::{directory_parser, DirectoryEntry};
+use crate::disk_format::cpm::dpb::{CpmFormat, DIRECTORY_ENTRY_SIZE};
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// A file on a volume, the directory extents with the same user
+/// number and name
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpmFile {
+    /// The user number of the file
+    pub user: u8,
+    /// The extents of the file, in extent order
+    pub extents: Vec<DirectoryEntry>,
+}
+
+impl CpmFile {
+    /// The name of the file
+    pub fn filename(&self) -> String {
+        self.extents[0].filename()
+    }
+
+    /// The path of the file, the name under a directory for the user
+    /// number for files outside user 0, e.g. "3/GAME.COM"
+    pub fn path(&self) -> String {
+        match self.user {
+            0 => self.filename(),
+            user => format!("{}/{}", user, self.filename()),
+        }
+    }
+
+    /// The size of the file in bytes, from the last extent
+    pub fn size(&self) -> usize {
+        self.extents.last().map_or(0, DirectoryEntry::size)
+    }
+}
+
+/// Format a file as a line of a directory listing
+impl Display for CpmFile {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{:>2}:{:<12} {:>8} {}",
+            self.user,
+            self.filename(),
+            self.size(),
+            self.extents[0].attribute_string()
+        )
+    }
+}
+
+/// A parsed CP/M volume
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CpmVolume {
+    /// The sectors of the disk, in physical order, including the
+    /// system tracks
+    pub data: Vec<u8>,
+    /// The layout of the volume
+    pub format: CpmFormat,
+    /// Every directory entry, including unused ones
+    pub directory: Vec<DirectoryEntry>,
+    /// The files, in the order of their first extent in the directory
+    pub files: Vec<CpmFile>,
+}
+
+/// Build an error for a damaged volume
+fn volume_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+impl CpmVolume {
+    /// Parse a volume from a flat image of a disk's sectors
+    /// Returns an error if the format isn't valid or the directory is
+    /// past the end of the image.
+    pub fn parse(data: Vec<u8>, format: CpmFormat) -> std::result::Result<CpmVolume, Error> {
+        if !format.check() {
+            return Err(volume_error(format!("Invalid CP/M format: {}", format)));
+        }
+
+        let mut volume = CpmVolume {
+            data,
+            format,
+            directory: Vec::new(),
+            files: Vec::new(),
+        };
+        let mut directory_data = Vec::new();
+        for block in 0..volume.format.dpb.directory_blocks() {
+            directory_data.extend(volume.read_block(block)?);
+        }
+        directory_data.truncate(volume.format.dpb.directory_entries() * DIRECTORY_ENTRY_SIZE);
+        let (_, directory) = directory_parser(&directory_data)?;
+
+        let mut files: Vec<CpmFile> = Vec::new();
+        for entry in directory.iter().filter(|entry| entry.is_file()) {
+            let name = entry.filename();
+            match files
+                .iter_mut()
+                .find(|file| file.user == entry.user && file.filename() == name)
+            {
+                Some(file) => file.extents.push(entry.clone()),
+                None => files.push(CpmFile {
+                    user: entry.user,
+                    extents: vec![entry.clone()],
+                }),
+            }
+        }
+        for file in files.iter_mut() {
+            file.extents.sort_by_key(DirectoryEntry::extent);
+        }
+        debug!(
+            target: PARSE,
+            "CP/M directory has {} files in {} entries",
+            files.len(),
+            directory.len()
+        );
+        volume.directory = directory;
+        volume.files = files;
+
+        Ok(volume)
+    }
+
+    /// Return the offset in the image of a byte in the data area, after
+    /// the system tracks
+    /// The sectors of each track are read through the skew table.
+    pub fn image_offset(&self, offset: usize) -> usize {
+        let track_size = self.format.dpb.track_size();
+        let sector_size = self.format.sector_size;
+        let track = offset / track_size + usize::from(self.format.dpb.reserved_tracks);
+        let sector = (offset % track_size) / sector_size;
+        let physical = self
+            .format
+            .skew_table()
+            .get(sector)
+            .copied()
+            .unwrap_or(sector);
+        track * track_size + physical * sector_size + offset % sector_size
+    }
+
+    /// Return the image sectors holding a block, as indexes of sectors
+    /// in the flat image
+    pub fn block_sectors(&self, block: usize) -> Vec<usize> {
+        let block_size = self.format.dpb.block_size();
+        let sector_size = self.format.sector_size;
+        (block * block_size..(block + 1) * block_size)
+            .step_by(sector_size)
+            .map(|offset| self.image_offset(offset) / sector_size)
+            .collect()
+    }
+
+    /// Read an allocation block
+    /// Returns an error if the block is out of range or past the end of
+    /// the image.
+    pub fn read_block(&self, block: usize) -> std::result::Result<Vec<u8>, Error> {
+        if block >= self.format.dpb.blocks() {
+            return Err(volume_error(format!("Block {} is out of range", block)));
+        }
+        let sector_size = self.format.sector_size;
+        let mut data = Vec::with_capacity(self.format.dpb.block_size());
+        for sector in self.block_sectors(block) {
+            let sector_data = self
+                .data
+                .get(sector * sector_size..(sector + 1) * sector_size)
+                .ok_or_else(|| {
+                    volume_error(format!("Block {} is past the end of the image", block))
+                })?;
+            data.extend_from_slice(sector_data);
+        }
+        Ok(data)
+    }
+
+    /// Return the blocks of a file, in file order
+    pub fn file_blocks(&self, file: &CpmFile) -> Vec<usize> {
+        file.extents
+            .iter()
+            .flat_map(|extent| extent.blocks(&self.format.dpb))
+            .collect()
+    }
+
+    /// Read the data of a file
+    /// Returns an error if a block is out of range or the blocks don't
+    /// hold the whole file.
+    pub fn read_file(&self, file: &CpmFile) -> std::result::Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        for block in self.file_blocks(file) {
+            data.extend(self.read_block(block)?);
+        }
+        let size = file.size();
+        if data.len() < size {
+            return Err(volume_error(format!(
+                "{} is {} bytes but its blocks only hold {}",
+                file.path(),
+                size,
+                data.len()
+            )));
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    /// Return the files on the volume
+    /// Files that can't be read are skipped with a warning.
+    pub fn disk_files(&self) -> Vec<DiskFile> {
+        self.files
+            .iter()
+            .filter_map(|file| match self.read_file(file) {
+                Ok(data) => Some(DiskFile {
+                    name: file.path(),
+                    file_type: file.extents[0].attribute_string(),
+                    raw_name: file.extents[0].raw_name(),
+                    data,
+                }),
+                Err(e) => {
+                    warn!(target: PARSE, "Error reading {}: {}", file.path(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Return the blocks not used by the directory or any file
+    pub fn free_blocks(&self) -> BTreeSet<usize> {
+        let used: BTreeSet<usize> = self
+            .files
+            .iter()
+            .flat_map(|file| self.file_blocks(file))
+            .collect();
+        (self.format.dpb.directory_blocks()..self.format.dpb.blocks())
+            .filter(|block| !used.contains(block))
+            .collect()
+    }
+}
+
+/// Format the volume as a directory listing with the free space
+impl Display for CpmVolume {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "CP/M format: {}", self.format.name)?;
+        for file in &self.files {
+            writeln!(f, "{}", file)?;
+        }
+        let free = self.free_blocks().len();
+        write!(
+            f,
+            "{} files, {}K free",
+            self.files.len(),
+            free * self.format.dpb.block_size() / 1024
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpmVolume;
+    use crate::disk_format::cpm::dpb::CpmFormat;
+    use crate::disk_format::testgen;
+
+    /// Test listing and reading files on generated disks, with and
+    /// without sector skew and with files spanning several extents
+    #[test]
+    fn cpm_volume_works() {
+        let big: Vec<u8> = (0..40000).map(|i| (i % 253) as u8).collect();
+        for name in ["kaypro2", "ibm-3740"] {
+            let format = CpmFormat::named(name).unwrap();
+            let files: [(u8, &str, &[u8]); 3] = [
+                (0, "HELLO.TXT", b"HELLO FROM CP/M"),
+                (0, "BIG.DAT", &big),
+                (3, "GAME.COM", &[0xC3; 300]),
+            ];
+            let data = testgen::cpm(&format, &files).unwrap();
+            let volume = CpmVolume::parse(data, format).unwrap();
+
+            assert_eq!(volume.files.len(), 3);
+            assert_eq!(volume.files[1].extents.len(), 3);
+            let disk_files = volume.disk_files();
+            assert_eq!(disk_files[0].name, "HELLO.TXT");
+            // Files are stored in whole records
+            assert_eq!(&disk_files[0].data[..15], b"HELLO FROM CP/M");
+            assert_eq!(disk_files[0].data.len(), 128);
+            assert_eq!(&disk_files[1].data[..big.len()], &big[..]);
+            assert_eq!(disk_files[2].name, "3/GAME.COM");
+            assert_eq!(disk_files[2].data.len(), 384);
+
+            let listing = volume.to_string();
+            assert!(listing.contains(" 0:BIG.DAT         40064 ---"));
+            let used = 1 + 40 + 1;
+            assert!(listing.ends_with(&format!(
+                "3 files, {}K free",
+                volume.format.dpb.blocks() - volume.format.dpb.directory_blocks() - used
+            )));
+        }
+    }
+}
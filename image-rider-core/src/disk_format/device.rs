@@ -0,0 +1,156 @@
+//! Reading images from block devices
+//!
+//! A USB floppy drive or a card reader shows up as a block device like
+//! /dev/sdb.  Block devices report a size of zero in their metadata, so
+//! the size is probed by seeking to the end of the device, and the
+//! whole device is read the same way as an image file.  Read errors
+//! are reported with the offset that failed, which on a floppy points
+//! to the bad sector.
+//!
+//! Devices are only ever opened for reading.  For archiving, reading
+//! can be refused unless the system reports the device as read-only,
+//! e.g. a floppy with its write protect tab set.
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::info;
+
+use crate::disk_format::limits::{check_file_size, with_limits, Limits};
+use crate::error::{Error, ErrorKind};
+use crate::log_target::IO;
+
+/// The size of the chunks devices are read in
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How images are read from block devices
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DeviceOptions {
+    /// Refuse to read a block device unless the system reports it as
+    /// read-only
+    pub require_read_only: bool,
+    /// The limits the device size is checked against
+    pub limits: Limits,
+}
+
+/// Return true if the path is a block device
+#[cfg(unix)]
+pub fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+/// Return true if the path is a block device
+#[cfg(not(unix))]
+pub fn is_block_device(_path: &Path) -> bool {
+    false
+}
+
+/// Return true if the system reports the block device as read-only
+/// The flag is read from sysfs, so this returns None on systems other
+/// than Linux and for paths that aren't block devices.
+pub fn device_is_read_only(path: &Path) -> Option<bool> {
+    if !is_block_device(path) {
+        return None;
+    }
+    // Resolve links like /dev/disk/by-id/usb-... to the device name
+    let path = fs::canonicalize(path).ok()?;
+    let name = path.file_name()?.to_str()?;
+    let flag = fs::read_to_string(Path::new("/sys/class/block").join(name).join("ro")).ok()?;
+    Some(flag.trim() != "0")
+}
+
+/// Return true if changes to the image at a path can't be written
+/// back: block devices are only opened for reading, and files without
+/// write permission are read-only.  Paths that don't exist aren't
+/// read-only.
+pub fn is_read_only_source(path: &Path) -> bool {
+    is_block_device(path) || fs::metadata(path).is_ok_and(|m| m.permissions().readonly())
+}
+
+/// Return the size of an open device or file
+/// The size comes from seeking to the end, which works for block
+/// devices as well as regular files.  The file is left at the start.
+pub fn device_size(file: &mut File) -> std::result::Result<u64, Error> {
+    let size = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+    Ok(size)
+}
+
+/// Read an image from a file or a block device
+/// Block devices are checked against the options, their size probed
+/// and checked against the file size limit before anything is read.
+/// Returns an error if the device is empty, e.g. a floppy drive with
+/// no disk in it.
+pub fn read_image(path: &Path, options: &DeviceOptions) -> std::result::Result<Vec<u8>, Error> {
+    if !is_block_device(path) {
+        return Ok(fs::read(path)?);
+    }
+
+    if options.require_read_only && device_is_read_only(path) != Some(true) {
+        return Err(Error::new(ErrorKind::Message(format!(
+            "{} isn't write protected",
+            path.display()
+        ))));
+    }
+
+    let mut file = File::open(path)?;
+    let size = usize::try_from(device_size(&mut file)?).map_err(|_| {
+        Error::new(ErrorKind::Message(format!(
+            "{} is too large to read",
+            path.display()
+        )))
+    })?;
+    if size == 0 {
+        return Err(Error::new(ErrorKind::NotFound(format!(
+            "No media in {}",
+            path.display()
+        ))));
+    }
+    with_limits(options.limits, || check_file_size(size))?;
+    info!(target: IO, "Reading {} bytes from {}", size, path.display());
+
+    let mut data = vec![0; size];
+    for (n, chunk) in data.chunks_mut(READ_CHUNK_SIZE).enumerate() {
+        file.read_exact(chunk).map_err(|e| {
+            Error::new(ErrorKind::Message(format!(
+                "Error reading {} at offset {}: {}",
+                path.display(),
+                n * READ_CHUNK_SIZE,
+                e
+            )))
+        })?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::Path;
+
+    use super::{device_is_read_only, device_size, is_block_device, read_image, DeviceOptions};
+
+    /// Test reading regular files, which aren't block devices
+    #[test]
+    fn read_image_works() {
+        let filename = "testdata/test-read_image_works.st";
+        let path = Path::new(filename);
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        fs::write(path, &data).unwrap();
+
+        assert!(!is_block_device(path));
+        assert_eq!(device_is_read_only(path), None);
+        assert_eq!(device_size(&mut File::open(path).unwrap()).unwrap(), 10000);
+        let options = DeviceOptions {
+            require_read_only: true,
+            ..DeviceOptions::default()
+        };
+        assert_eq!(read_image(path, &options).unwrap(), data);
+
+        fs::remove_file(path).unwrap();
+        assert!(read_image(path, &options).is_err());
+    }
+}
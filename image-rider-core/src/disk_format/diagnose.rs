@@ -0,0 +1,394 @@
+//! Explanations for parser failures
+//!
+//! A nom error only records the input left and the combinator that
+//! failed, which makes messages like "Parsing Error: Error { input:
+//! [...], code: Tag }".  This module works out which format the image
+//! was most likely in and where the parser stopped, and translates
+//! failures in well known structures into messages like "bad STX magic".
+//! The nom error is kept as the source.
+use nom::error::ErrorKind as NomErrorKind;
+
+use crate::disk_format::amiga::disk::adf_disk_parser;
+use crate::disk_format::apple::twoimg::{is_2mg, twoimg_parser};
+use crate::disk_format::apple::woz::is_woz;
+use crate::disk_format::commodore::d64::d64_disk_parser;
+use crate::disk_format::commodore::g64::{g64_disk_parser, is_g64};
+use crate::disk_format::dsk::disk::{dsk_disk_parser, is_dsk};
+use crate::disk_format::guess::best_guess;
+use crate::disk_format::hfe::{hfe_disk_parser, is_hfe};
+use crate::disk_format::image::nom_error_location;
+use crate::disk_format::imd::{imd_disk_parser, is_imd};
+use crate::disk_format::mac::diskcopy::is_dc42;
+use crate::disk_format::stx::st::{is_msa, is_st_filename, msa_disk_parser, st_disk_parser};
+use crate::disk_format::td0::disk::{is_td0, td0_disk_parser};
+use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+use crate::options::Options;
+
+/// A structure at a fixed position in an image format
+struct KnownRegion {
+    /// The format the region is part of
+    format: &'static str,
+    /// The first byte of the region
+    start: usize,
+    /// The byte after the end of the region
+    end: usize,
+    /// The parser failure this explains, or None for any failure
+    code: Option<NomErrorKind>,
+    /// What the failure means
+    message: &'static str,
+}
+
+/// Well known structures, the first match is used
+const KNOWN_REGIONS: &[KnownRegion] = &[
+    KnownRegion {
+        format: "2MG",
+        start: 8,
+        end: 10,
+        code: Some(NomErrorKind::Verify),
+        message: "2MG image data overlaps the header",
+    },
+    KnownRegion {
+        format: "2MG",
+        start: 12,
+        end: 16,
+        code: Some(NomErrorKind::Verify),
+        message: "unknown 2MG image format",
+    },
+    KnownRegion {
+        format: "DC42",
+        start: 0x48,
+        end: 0x4C,
+        code: Some(NomErrorKind::Verify),
+        message: "DiskCopy data checksum doesn't match, the disk may be damaged",
+    },
+    KnownRegion {
+        format: "STX",
+        start: 0,
+        end: 4,
+        code: Some(NomErrorKind::Tag),
+        message: "bad STX magic, expected RSY",
+    },
+    KnownRegion {
+        format: "STX",
+        start: 4,
+        end: 16,
+        code: None,
+        message: "truncated STX header",
+    },
+    KnownRegion {
+        format: "D64",
+        start: 0,
+        end: 0x16500,
+        code: Some(NomErrorKind::Eof),
+        message: "image is too short to hold a D64 BAM",
+    },
+    KnownRegion {
+        format: "D64",
+        start: 0x16500,
+        end: 0x16502,
+        code: Some(NomErrorKind::Verify),
+        message: "D64 BAM doesn't point to the directory on track 18 sector 1",
+    },
+    KnownRegion {
+        format: "D64",
+        start: 0x16502,
+        end: 0x16503,
+        code: Some(NomErrorKind::Verify),
+        message: "unsupported D64 DOS version, the disk may be soft write protected",
+    },
+    KnownRegion {
+        format: "D64",
+        start: 0x165A5,
+        end: 0x165A7,
+        code: Some(NomErrorKind::Tag),
+        message: "bad D64 DOS type, expected 2A",
+    },
+    KnownRegion {
+        format: "D64",
+        start: 0x16600,
+        end: 0x17800,
+        code: None,
+        message: "damaged D64 directory sector",
+    },
+    KnownRegion {
+        format: "WOZ",
+        start: 8,
+        end: 12,
+        code: Some(NomErrorKind::Verify),
+        message: "WOZ CRC-32 doesn't match the chunks, set ignore-checksums to read it anyway",
+    },
+    KnownRegion {
+        format: "ADF",
+        start: 0,
+        end: 3,
+        code: Some(NomErrorKind::Tag),
+        message: "bad ADF boot block, expected DOS",
+    },
+    KnownRegion {
+        format: "ADF",
+        start: 3,
+        end: 4,
+        code: Some(NomErrorKind::Verify),
+        message: "unknown AmigaDOS filesystem flags in the ADF boot block",
+    },
+    KnownRegion {
+        format: "ADF",
+        start: 0x6E000,
+        end: 0x6E200,
+        code: Some(NomErrorKind::Verify),
+        message: "ADF root block isn't a root directory header",
+    },
+    KnownRegion {
+        format: "DSK",
+        start: 0x30,
+        end: 0x32,
+        code: Some(NomErrorKind::Verify),
+        message: "DSK disk information block has an invalid number of sides",
+    },
+    KnownRegion {
+        format: "G64",
+        start: 8,
+        end: 12,
+        code: Some(NomErrorKind::Verify),
+        message: "unsupported G64 version or empty track table",
+    },
+    KnownRegion {
+        format: "HFE",
+        start: 8,
+        end: 12,
+        code: Some(NomErrorKind::Verify),
+        message: "unsupported HFE format revision, or no tracks or sides",
+    },
+    KnownRegion {
+        format: "TD0",
+        start: 2,
+        end: 10,
+        code: Some(NomErrorKind::Verify),
+        message: "unsupported Teledisk version, compression or number of sides, or a later volume of a set",
+    },
+    KnownRegion {
+        format: "TD0",
+        start: 10,
+        end: 12,
+        code: Some(NomErrorKind::Verify),
+        message: "TD0 header CRC doesn't match",
+    },
+    KnownRegion {
+        format: "MSA",
+        start: 2,
+        end: 10,
+        code: Some(NomErrorKind::Verify),
+        message: "MSA header has an invalid number of sectors, sides or tracks",
+    },
+    KnownRegion {
+        format: "ST",
+        start: 0,
+        end: 512,
+        code: Some(NomErrorKind::Verify),
+        message: "ST boot sector doesn't describe the image and its size isn't a known size",
+    },
+    KnownRegion {
+        format: "Apple DOS",
+        start: 0x11000,
+        end: 0x11100,
+        code: None,
+        message: "damaged Apple DOS VTOC",
+    },
+];
+
+/// Guess the format of an image that failed to parse
+fn likely_format(data: &[u8], filename: &str, guessed: bool) -> Option<&'static str> {
+    if is_woz(data) {
+        Some("WOZ")
+    } else if is_2mg(data) {
+        Some("2MG")
+    } else if is_dc42(data) {
+        Some("DC42")
+    } else if is_dsk(data) {
+        Some("DSK")
+    } else if is_g64(data) {
+        Some("G64")
+    } else if is_hfe(data) {
+        Some("HFE")
+    } else if is_td0(data) {
+        Some("TD0")
+    } else if is_imd(data) {
+        Some("IMD")
+    } else if is_msa(data) {
+        Some("MSA")
+    } else if is_st_filename(filename) {
+        Some("ST")
+    } else if guessed {
+        Some("Apple DOS")
+    } else {
+        best_guess(data).map(|guess| guess.format)
+    }
+}
+
+/// A message for a parser failure outside the known regions
+fn generic_message(code: NomErrorKind) -> &'static str {
+    match code {
+        NomErrorKind::Eof => "image is truncated",
+        NomErrorKind::TooLarge => "a header asks for more than the allocation limits allow",
+        NomErrorKind::Tag => "bad signature",
+        NomErrorKind::Verify => "unexpected value in a header",
+        _ => "couldn't parse the image",
+    }
+}
+
+/// Translate a parser failure into a domain error
+/// The format is guessed from the data, and the parser for that format
+/// is run again, with the same options, if the failure came from
+/// trying another format.
+pub fn explain_parse_failure(
+    config: &Options,
+    data: &[u8],
+    filename: &str,
+    guessed: bool,
+    e: nom::Err<nom::error::Error<&[u8]>>,
+) -> Error {
+    let format = likely_format(data, filename, guessed);
+    // Formats are tried in turn, so the error may be from the last
+    // format tried rather than the format the image is in
+    let e = match format {
+        Some("2MG") => twoimg_parser(data).err().unwrap_or(e),
+        Some("D64") => d64_disk_parser(data).err().unwrap_or(e),
+        Some("ADF") => adf_disk_parser(data).err().unwrap_or(e),
+        Some("DSK") => dsk_disk_parser(data).err().unwrap_or(e),
+        Some("G64") => g64_disk_parser(data).err().unwrap_or(e),
+        Some("HFE") => hfe_disk_parser(data).err().unwrap_or(e),
+        Some("TD0") => td0_disk_parser(config)(data).err().unwrap_or(e),
+        Some("IMD") => imd_disk_parser(data).err().unwrap_or(e),
+        Some("MSA") => msa_disk_parser(data).err().unwrap_or(e),
+        Some("ST") => st_disk_parser(data).err().unwrap_or(e),
+        _ => e,
+    };
+    let location = nom_error_location(data, &e, filename);
+    let code = match &e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.code,
+        nom::Err::Incomplete(_) => NomErrorKind::Eof,
+    };
+    let source = Error::new(ErrorKind::new(&e.to_string()));
+
+    let format = match format {
+        Some(format) => format,
+        None => {
+            return Error::new(ErrorKind::Unimplemented(format!(
+                "Unknown image format, {} bytes",
+                data.len()
+            )))
+            .with_source(source)
+        }
+    };
+
+    let offset = location.as_ref().and_then(|location| location.offset);
+    let message = KNOWN_REGIONS
+        .iter()
+        .find(|region| {
+            region.format == format
+                && offset.is_some_and(|offset| region.start <= offset && offset < region.end)
+                && region.code.map_or(true, |c| c == code)
+        })
+        .map(|region| region.message)
+        .unwrap_or_else(|| generic_message(code));
+
+    let error = Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(String::from(
+        message,
+    ))))
+    .with_source(source);
+    match location {
+        Some(location) => error.with_location(Location {
+            format: Some(String::from(format)),
+            ..location
+        }),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::error::ErrorKind as NomErrorKind;
+
+    use super::explain_parse_failure;
+    use crate::disk_format::image::disk_image_parser;
+    use crate::options::Options;
+
+    /// Run the parsers over an image and explain the failure
+    fn explain(data: &[u8]) -> String {
+        match disk_image_parser(data) {
+            Ok(_) => String::from("parsed"),
+            Err(e) => {
+                explain_parse_failure(&Options::default(), data, "image", false, e).to_string()
+            }
+        }
+    }
+
+    /// Test that failures in known structures are explained
+    #[test]
+    fn explain_parse_failure_works() {
+        let mut d64 = vec![0_u8; 174848];
+        assert_eq!(
+            explain(&d64),
+            "Image is invalid: D64 BAM doesn't point to the directory on track 18 sector 1 \
+             at track 18 sector 0 offset 0x16500 of the D64 image"
+        );
+        d64[0x16500..0x16502].copy_from_slice(&[0x12, 0x01]);
+        assert!(explain(&d64).contains("soft write protected"));
+        d64[0x16502] = 0x41;
+        assert!(explain(&d64).contains("expected 2A at track 18 sector 0 offset 0x165a5"));
+
+        assert_eq!(
+            explain(b"RSX\0"),
+            "Unimplemented feature: Unknown image format, 4 bytes"
+        );
+        assert_eq!(
+            explain(b"RSY\0\x03"),
+            "Image is invalid: truncated STX header at offset 0x4 of the STX image"
+        );
+
+        let mut adf = vec![0_u8; 901120];
+        adf[..4].copy_from_slice(b"DOS\0");
+        assert_eq!(
+            explain(&adf),
+            "Image is invalid: ADF root block isn't a root directory header \
+             at track 40 sector 0 offset 0x6e000 of the ADF image"
+        );
+
+        let mut dsk = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n".to_vec();
+        dsk.resize(256, 0);
+        dsk[0x30] = 40;
+        assert_eq!(
+            explain(&dsk),
+            "Image is invalid: DSK disk information block has an invalid number of sides \
+             at offset 0x30 of the DSK image"
+        );
+
+        assert_eq!(
+            explain(b"GCR-1541\x01\x54\xF8\x1E"),
+            "Image is invalid: unsupported G64 version or empty track table \
+             at offset 0x8 of the G64 image"
+        );
+
+        assert_eq!(
+            explain(b"\x0E\x0F\x00\x09\x00\x03\x00\x00\x00\x4F"),
+            "Image is invalid: MSA header has an invalid number of sectors, sides or tracks \
+             at offset 0x2 of the MSA image"
+        );
+        let st = vec![0_u8; 1000];
+        let e = disk_image_parser(&st).err().unwrap();
+        assert_eq!(
+            explain_parse_failure(&Options::default(), &st, "game.st", false, e).to_string(),
+            "Image is invalid: ST boot sector doesn't describe the image and its size isn't \
+             a known size at offset 0x0 of the ST image"
+        );
+
+        let mut stx = b"RSY\0".to_vec();
+        stx.resize(64, 0);
+        let e = nom::Err::Error(nom::error::Error::new(&stx[32..], NomErrorKind::TooLarge));
+        assert_eq!(
+            explain_parse_failure(&Options::default(), &stx, "", false, e).to_string(),
+            "Image is invalid: a header asks for more than the allocation limits allow \
+             at offset 0x20 of the STX image"
+        );
+    }
+}
@@ -0,0 +1,363 @@
+//!
+//! DSK disk image functions
+//!
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, info};
+
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take};
+use nom::combinator::{map, value};
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::dsk::track::{dsk_track_parser, DskTrack};
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::limit_tracks;
+use crate::disk_format::logical::{LogicalTrack, RawExporter, RawOrder};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// A CPCEMU DSK or extended DSK image
+pub const DSK_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "DSK",
+    description: "Amstrad CPC, Spectrum +3 or MSX CPCEMU or extended DSK disk image",
+    extensions: &["dsk"],
+    sizes: &[SizeRange::at_least(DISK_INFO_SIZE)],
+    references: &[Reference {
+        title: "CPCWiki",
+        url: Some("https://www.cpcwiki.eu/index.php/Format:DSK_disk_image_file_format"),
+    }],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The magic at the start of a standard DSK image
+/// The full signature is "MV - CPCEMU Disk-File\r\nDisk-Info\r\n", but
+/// only the start is fixed.
+pub const DSK_MAGIC: &[u8] = b"MV - CPC";
+
+/// The magic at the start of an extended DSK image
+pub const EDSK_MAGIC: &[u8] = b"EXTENDED CPC DSK File\r\n";
+
+/// The size of the disk information block, the first track starts
+/// after it
+pub const DISK_INFO_SIZE: usize = 256;
+
+/// The offset of the creator name in the disk information block
+const CREATOR_OFFSET: usize = 0x22;
+
+/// Return true if the data starts with a DSK or extended DSK signature
+pub fn is_dsk(data: &[u8]) -> bool {
+    data.starts_with(DSK_MAGIC) || data.starts_with(EDSK_MAGIC)
+}
+
+/// The disk information block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DskDiskHeader {
+    /// True for an extended DSK image
+    pub extended: bool,
+    /// The name of the tool that created the image
+    pub creator: [u8; 14],
+    /// The number of tracks on each side
+    pub tracks: u8,
+    /// The number of sides
+    pub sides: u8,
+    /// The size of each track in the image, including its information
+    /// block, in track order with the sides of each track next to each
+    /// other
+    /// Standard images have one size for every track.  A zero size in
+    /// an extended image is an unformatted track that isn't stored.
+    pub track_sizes: Vec<usize>,
+}
+
+impl DskDiskHeader {
+    /// The name of the tool that created the image, without padding
+    pub fn creator(&self) -> String {
+        String::from_utf8_lossy(&self.creator)
+            .trim_end_matches(['\0', ' '])
+            .to_string()
+    }
+}
+
+/// Format a DskDiskHeader for display
+impl Display for DskDiskHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{}, creator: {}, tracks: {}, sides: {}",
+            if self.extended { "Extended DSK" } else { "DSK" },
+            self.creator(),
+            self.tracks,
+            self.sides
+        )
+    }
+}
+
+impl SanityCheck for DskDiskHeader {
+    fn check(&self) -> bool {
+        if !(1..=2).contains(&self.sides) {
+            debug!(target: PARSE, "Invalid number of sides: {}", self.sides);
+            return false;
+        }
+        if self.track_sizes.len() != usize::from(self.tracks) * usize::from(self.sides) {
+            debug!(target: PARSE, "Track size table doesn't match the tracks");
+            return false;
+        }
+        true
+    }
+}
+
+/// A DSK or extended DSK disk image
+pub struct DskDisk<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The disk information block
+    pub header: DskDiskHeader,
+    /// The formatted tracks, in image order
+    pub tracks: Vec<DskTrack<'a>>,
+}
+
+impl DskDisk<'_> {
+    /// The sectors stored as several copies of their data
+    pub fn weak_sectors(&self) -> Vec<SectorId> {
+        self.tracks
+            .iter()
+            .flat_map(|track| {
+                track
+                    .sectors
+                    .iter()
+                    .filter(|sector| sector.is_weak())
+                    .map(|sector| {
+                        SectorId::new(track.header.track, track.header.side, sector.info.sector)
+                    })
+            })
+            .collect()
+    }
+
+    /// A table of the tracks, one per line
+    pub fn track_table(&self) -> String {
+        self.tracks
+            .iter()
+            .map(|track| track.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Format the disk header and the track table
+impl Display for DskDisk<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}\n{}", self.header, self.track_table())
+    }
+}
+
+impl SanityCheck for DskDisk<'_> {
+    fn check(&self) -> bool {
+        self.header.check() && self.tracks.iter().all(|track| track.header.check())
+    }
+}
+
+impl DiskImageSaver for DskDisk<'_> {
+    /// Save the sectors of the disk as a flat dump in the geometry used
+    /// by most of the tracks
+    /// There's no filesystem to select files from.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        if let Some(selected_filename) = selected_filename {
+            error!(target: IO, "No filesystem to read {} from", selected_filename);
+            return Err(Error::new(ErrorKind::NotFound(format!(
+                "File not found: {}",
+                selected_filename
+            ))));
+        }
+
+        let disk_image_data = self
+            .raw_geometry()
+            .map(|geometry| self.export_raw(&RawOrder::default(), &geometry))
+            .unwrap_or_default();
+        info!(target: IO, "Found image data, writing data");
+        writer.write_all(&disk_image_data)?;
+        Ok(())
+    }
+
+    /// DSK images are read as sectors, they have no files
+    fn disk_files(&self) -> Vec<DiskFile> {
+        Vec::new()
+    }
+}
+
+impl RawExporter for DskDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.tracks.iter().map(DskTrack::logical_track).collect()
+    }
+}
+
+/// The disk information block, and the information block and sector
+/// data of each track
+impl SourceMapper for DskDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        map.add(
+            RegionKind::Header,
+            "disk information block",
+            start,
+            DISK_INFO_SIZE,
+        );
+
+        for track in &self.tracks {
+            let name = format!("track {} side {}", track.header.track, track.header.side);
+            map.add_slice(data, track.data, RegionKind::Data, &name);
+            if let Some(offset) = slice_offset(data, track.data) {
+                map.add(
+                    RegionKind::Header,
+                    &format!("{} information block", name),
+                    offset,
+                    track.header.size(),
+                );
+            }
+            for sector in &track.sectors {
+                map.add_slice(
+                    data,
+                    sector.data,
+                    RegionKind::Data,
+                    &format!("{} sector {}", name, sector.info.sector),
+                );
+            }
+        }
+
+        map
+    }
+}
+
+/// Parse the disk information block
+pub fn dsk_disk_header_parser(i: &[u8]) -> IResult<&[u8], DskDiskHeader> {
+    let start = i;
+    let (i, extended) = alt((value(true, tag(EDSK_MAGIC)), value(false, tag(DSK_MAGIC))))(i)?;
+    let (i, _) = take(CREATOR_OFFSET - (start.len() - i.len()))(i)?;
+    let (i, creator) = take(14_usize)(i)?;
+    let (i, tracks) = le_u8(i)?;
+    let (i, sides) = le_u8(i)?;
+    limit_tracks(i, usize::from(tracks) * usize::from(sides))?;
+    let entries = usize::from(tracks) * usize::from(sides);
+    let (i, track_sizes) = if extended {
+        let (i, _unused) = take(2_usize)(i)?;
+        let (i, sizes) = take(entries)(i)?;
+        (
+            i,
+            sizes.iter().map(|size| usize::from(*size) * 256).collect(),
+        )
+    } else {
+        map(le_u16, |size| vec![usize::from(size); entries])(i)?
+    };
+    let (i, _) = take(DISK_INFO_SIZE - (start.len() - i.len()))(i)?;
+
+    Ok((
+        i,
+        DskDiskHeader {
+            extended,
+            creator: creator.try_into().unwrap(),
+            tracks,
+            sides,
+            track_sizes,
+        },
+    ))
+}
+
+/// Parse a DSK or extended DSK image
+pub fn dsk_disk_parser(i: &[u8]) -> IResult<&[u8], DskDisk<'_>> {
+    let data = i;
+    let (mut i, header) = dsk_disk_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[0x30..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    debug!(target: PARSE, "Disk header: {}", header);
+
+    let mut tracks = Vec::new();
+    for size in &header.track_sizes {
+        if *size == 0 {
+            continue;
+        }
+        let (rest, track_data) = take(*size)(i)?;
+        let (_, track) = dsk_track_parser(header.extended)(track_data)?;
+        tracks.push(track);
+        i = rest;
+    }
+
+    Ok((
+        i,
+        DskDisk {
+            data,
+            header,
+            tracks,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dsk_disk_parser, is_dsk};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::{split_tracks, RawExporter};
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+
+    /// Test reading standard and extended images, with a weak sector
+    /// and a sector with a CRC error in the extended image
+    #[test]
+    fn dsk_disk_parser_works() {
+        let geometry = Geometry::uniform(3, 2, 9, 512, 0, 0xC1);
+        let flat = testgen::atari_st_sectors(3, 2, 9);
+        let mut tracks = split_tracks(&flat, &geometry);
+
+        let standard = testgen::dsk(&tracks, false, &[]);
+        assert!(is_dsk(&standard));
+        let (_, disk) = dsk_disk_parser(&standard).unwrap();
+        assert!(!disk.header.extended);
+        assert!(disk.check());
+        assert_eq!(disk.tracks.len(), 6);
+        assert_eq!(disk.logical_tracks(), tracks);
+        assert_eq!(disk.raw_geometry(), Some(geometry.clone()));
+
+        tracks[1].sectors[2].crc_error = true;
+        let weak = SectorId::new(0, 1, 0xC3);
+        let extended = testgen::dsk(&tracks, true, &[weak]);
+        let (_, disk) = dsk_disk_parser(&extended).unwrap();
+        assert!(disk.header.extended);
+        assert_eq!(disk.header.creator(), "image-rider");
+        assert_eq!(disk.weak_sectors(), vec![weak]);
+        let sector = &disk.tracks[1].sectors[2];
+        assert_eq!(sector.copies(), 3);
+        assert_ne!(sector.copy_data()[0], sector.copy_data()[1]);
+        // The first copy is used, with its CRC error
+        assert_eq!(disk.logical_tracks(), tracks);
+        assert!(disk
+            .track_table()
+            .contains("weak sectors: 1, CRC errors: 1"));
+
+        let mut truncated = extended.clone();
+        truncated.truncate(extended.len() - 100);
+        assert!(dsk_disk_parser(&truncated).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Parse CPCEMU DSK and Extended DSK disk images
+//!
+//! These images are used by Amstrad CPC, Spectrum +3 and MSX
+//! emulators.  They hold the sectors of each track as the floppy
+//! controller read them, with the sector IDs and controller status
+//! bytes, so unformatted tracks, odd sector numbering and some copy
+//! protection survive.  The basic structure is:
+//!
+//! ```ignore
+//! Disk Information Block (256 bytes)
+//! Track Information Block (256 bytes)
+//!  Sector Information x sector count
+//!  Sector data x sector count
+//! ...
+//! Track Information Block
+//! etc.
+//! ```
+//!
+//! Standard images have one track size for every track and one sector
+//! size per track.  Extended images have a table of track sizes, where
+//! a zero size is an unformatted track, and store the length of each
+//! sector, so sectors can be any size and weak sectors can be stored
+//! as several copies of the data read on different revolutions.
+//!
+//! Information from:\
+//! [CPCWiki](https://www.cpcwiki.eu/index.php/Format:DSK_disk_image_file_format)\
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// DSK disk images and the disk information block
+pub mod disk;
+
+/// DSK tracks and sector information
+pub mod track;
@@ -0,0 +1,356 @@
+//!
+//! DSK track and sector information functions
+//!
+use std::fmt::{Display, Formatter, Result};
+
+use log::debug;
+
+use nom::bytes::complete::{tag, take};
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::limits::limit_sectors_per_track;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::log_target::PARSE;
+
+/// The magic at the start of every track information block
+pub const TRACK_INFO_MAGIC: &[u8] = b"Track-Info";
+
+/// The size of a track information block, sector data starts after it
+/// Tracks with more than 29 sectors need a longer block, rounded up to
+/// the next 256 bytes.
+pub const TRACK_INFO_SIZE: usize = 256;
+
+/// The offset of the first sector information entry in the block
+const SECTOR_INFO_OFFSET: usize = 0x18;
+
+/// The size of a sector information entry
+const SECTOR_INFO_SIZE: usize = 8;
+
+/// Status register 1 bit set when the address or data field has a
+/// CRC error
+pub const ST1_DATA_ERROR: u8 = 0x20;
+
+/// Status register 2 bit set when the data field has a CRC error
+pub const ST2_DATA_ERROR: u8 = 0x20;
+
+/// Status register 2 bit set when the sector has a deleted data mark
+pub const ST2_CONTROL_MARK: u8 = 0x40;
+
+/// Return the size of a sector with an IBM size code
+/// Codes above 8 aren't formattable and are treated as 8.
+pub fn sector_size_for_code(size_code: u8) -> usize {
+    128_usize << size_code.min(8)
+}
+
+/// The information about a sector, its ID field as the floppy
+/// controller read it and the controller status after reading it
+/// 8 bytes
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DskSectorInfo {
+    /// C, the track number from the ID field
+    pub track: u8,
+    /// H, the side number from the ID field
+    pub side: u8,
+    /// R, the sector number from the ID field
+    pub sector: u8,
+    /// N, the size code from the ID field
+    pub size_code: u8,
+    /// The µPD765 status register 1 after reading the sector
+    pub status_1: u8,
+    /// The µPD765 status register 2 after reading the sector
+    pub status_2: u8,
+    /// The number of bytes of data stored for the sector in extended
+    /// images, unused in standard images
+    /// Weak sectors store more than one copy of the data.
+    pub data_length: u16,
+}
+
+impl DskSectorInfo {
+    /// The size of the sector from its size code
+    pub fn size(&self) -> usize {
+        sector_size_for_code(self.size_code)
+    }
+
+    /// True if the controller reported a CRC error in the sector
+    pub fn is_data_error(&self) -> bool {
+        self.status_1 & ST1_DATA_ERROR != 0 || self.status_2 & ST2_DATA_ERROR != 0
+    }
+
+    /// True if the sector was written with a deleted data mark
+    pub fn is_deleted(&self) -> bool {
+        self.status_2 & ST2_CONTROL_MARK != 0
+    }
+}
+
+/// Format a DskSectorInfo for display
+impl Display for DskSectorInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "C: {}, H: {}, R: 0x{:02X}, N: {}, ST1: 0x{:02X}, ST2: 0x{:02X}, length: {}",
+            self.track,
+            self.side,
+            self.sector,
+            self.size_code,
+            self.status_1,
+            self.status_2,
+            self.data_length
+        )
+    }
+}
+
+/// A sector and the data stored for it
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DskSector<'a> {
+    /// The sector information
+    pub info: DskSectorInfo,
+    /// The data stored for the sector, every copy for a weak sector
+    pub data: &'a [u8],
+}
+
+impl DskSector<'_> {
+    /// The number of copies of the data stored for the sector
+    /// Extended images store a weak sector, whose data reads
+    /// differently each time, as several copies one after the other.
+    pub fn copies(&self) -> usize {
+        let size = self.info.size();
+        if self.data.len() > size && self.data.len() % size == 0 {
+            self.data.len() / size
+        } else {
+            1
+        }
+    }
+
+    /// True if the sector is a weak sector with more than one copy of
+    /// its data
+    pub fn is_weak(&self) -> bool {
+        self.copies() > 1
+    }
+
+    /// Return each copy of the data stored for the sector
+    pub fn copy_data(&self) -> Vec<&[u8]> {
+        match self.copies() {
+            1 => vec![self.data],
+            _ => self.data.chunks(self.info.size()).collect(),
+        }
+    }
+}
+
+/// The track information block
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DskTrackHeader {
+    /// The track number
+    pub track: u8,
+    /// The side number
+    pub side: u8,
+    /// The data rate in extended images: 0 unknown, 1 single or
+    /// double density, 2 high density, 3 extended density
+    pub data_rate: u8,
+    /// The recording mode in extended images: 0 unknown, 1 FM, 2 MFM
+    pub recording_mode: u8,
+    /// The size code used to format the track
+    /// In standard images every sector is stored with this size.
+    pub size_code: u8,
+    /// The number of sectors on the track
+    pub sector_count: u8,
+    /// The length of GAP#3 used to format the track
+    pub gap3_length: u8,
+    /// The byte the sectors were filled with when the track was
+    /// formatted
+    pub filler: u8,
+    /// The information for each sector, in the order they were read
+    pub sector_info: Vec<DskSectorInfo>,
+}
+
+impl DskTrackHeader {
+    /// The size of the track information block, where the sector
+    /// data starts
+    pub fn size(&self) -> usize {
+        (SECTOR_INFO_OFFSET + self.sector_info.len() * SECTOR_INFO_SIZE)
+            .div_ceil(TRACK_INFO_SIZE)
+            .max(1)
+            * TRACK_INFO_SIZE
+    }
+}
+
+/// Format a DskTrackHeader for display
+impl Display for DskTrackHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "track: {}, side: {}, sectors: {}, size code: {}, gap: {}, filler: 0x{:02X}",
+            self.track, self.side, self.sector_count, self.size_code, self.gap3_length, self.filler
+        )
+    }
+}
+
+impl SanityCheck for DskTrackHeader {
+    fn check(&self) -> bool {
+        if self.side > 1 {
+            debug!(target: PARSE, "Invalid side number: {}", self.side);
+            return false;
+        }
+        if usize::from(self.sector_count) != self.sector_info.len() {
+            debug!(target: PARSE, "Sector count doesn't match the sectors");
+            return false;
+        }
+        true
+    }
+}
+
+/// A formatted track
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DskTrack<'a> {
+    /// The track information block
+    pub header: DskTrackHeader,
+    /// The sectors on the track, in the order they were read
+    pub sectors: Vec<DskSector<'a>>,
+    /// The whole track, the information block and the sector data
+    pub data: &'a [u8],
+}
+
+impl DskTrack<'_> {
+    /// Return the track as a logical track
+    /// Weak sectors use the first copy of their data and sectors with
+    /// no data are left out.
+    pub fn logical_track(&self) -> LogicalTrack {
+        let mut track = LogicalTrack::new(self.header.track, self.header.side);
+        for sector in self.sectors.iter().filter(|sector| !sector.data.is_empty()) {
+            let data = sector.copy_data()[0];
+            let mut logical_sector = LogicalSector::new(
+                SectorId::new(sector.info.track, sector.info.side, sector.info.sector),
+                data.to_vec(),
+            );
+            logical_sector.crc_error = sector.info.is_data_error();
+            logical_sector.deleted = sector.info.is_deleted();
+            track.sectors.push(logical_sector);
+        }
+        track
+    }
+}
+
+/// Format a track as a line of a track table
+impl Display for DskTrack<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.header)?;
+        let weak = self
+            .sectors
+            .iter()
+            .filter(|sector| sector.is_weak())
+            .count();
+        if weak > 0 {
+            write!(f, ", weak sectors: {}", weak)?;
+        }
+        let errors = self
+            .sectors
+            .iter()
+            .filter(|sector| sector.info.is_data_error())
+            .count();
+        if errors > 0 {
+            write!(f, ", CRC errors: {}", errors)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a sector information entry
+pub fn dsk_sector_info_parser(i: &[u8]) -> IResult<&[u8], DskSectorInfo> {
+    let (i, track) = le_u8(i)?;
+    let (i, side) = le_u8(i)?;
+    let (i, sector) = le_u8(i)?;
+    let (i, size_code) = le_u8(i)?;
+    let (i, status_1) = le_u8(i)?;
+    let (i, status_2) = le_u8(i)?;
+    let (i, data_length) = le_u16(i)?;
+
+    Ok((
+        i,
+        DskSectorInfo {
+            track,
+            side,
+            sector,
+            size_code,
+            status_1,
+            status_2,
+            data_length,
+        },
+    ))
+}
+
+/// Parse a track information block
+pub fn dsk_track_header_parser(i: &[u8]) -> IResult<&[u8], DskTrackHeader> {
+    let (i, _magic) = tag(TRACK_INFO_MAGIC)(i)?;
+    // "\r\n" and four unused bytes, not every tool writes the line end
+    let (i, _unused) = take(6_usize)(i)?;
+    let (i, track) = le_u8(i)?;
+    let (i, side) = le_u8(i)?;
+    let (i, data_rate) = le_u8(i)?;
+    let (i, recording_mode) = le_u8(i)?;
+    let (i, size_code) = le_u8(i)?;
+    let (i, sector_count) = le_u8(i)?;
+    limit_sectors_per_track(i, usize::from(sector_count))?;
+    let (i, gap3_length) = le_u8(i)?;
+    let (i, filler) = le_u8(i)?;
+    let (i, sector_info) = count(dsk_sector_info_parser, usize::from(sector_count))(i)?;
+
+    Ok((
+        i,
+        DskTrackHeader {
+            track,
+            side,
+            data_rate,
+            recording_mode,
+            size_code,
+            sector_count,
+            gap3_length,
+            filler,
+            sector_info,
+        },
+    ))
+}
+
+/// Parse a track, the information block and the sector data
+/// The input is the whole track from the disk's track size.  Extended
+/// images store each sector with its own length, standard images store
+/// every sector with the track's size code.
+pub fn dsk_track_parser(extended: bool) -> impl Fn(&[u8]) -> IResult<&[u8], DskTrack<'_>> {
+    move |i: &[u8]| {
+        let data = i;
+        let (_, header) = dsk_track_header_parser(i)?;
+        debug!(target: PARSE, "Track header: {}", header);
+
+        let mut i = data.get(header.size()..).ok_or_else(|| {
+            nom::Err::Error(nom::error::Error::new(
+                &data[data.len()..],
+                nom::error::ErrorKind::Eof,
+            ))
+        })?;
+        let mut sectors = Vec::with_capacity(header.sector_info.len());
+        for info in &header.sector_info {
+            let length = if extended {
+                usize::from(info.data_length)
+            } else {
+                sector_size_for_code(header.size_code)
+            };
+            let (rest, sector_data) = take(length)(i)?;
+            i = rest;
+            sectors.push(DskSector {
+                info: info.clone(),
+                data: sector_data,
+            });
+        }
+
+        Ok((
+            i,
+            DskTrack {
+                header,
+                sectors,
+                data,
+            },
+        ))
+    }
+}
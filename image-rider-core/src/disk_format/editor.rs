@@ -0,0 +1,479 @@
+//! Sector editing sessions
+//!
+//! An EditSession holds a working copy of a flat sector image and
+//! records every change as a sector-level edit so it can be undone and
+//! redone.  Several sector writes can be grouped into a single
+//! transaction that is undone as a unit.
+//!
+//! Before the edited image is committed it is parsed again and the
+//! sanity checks are run, so a disk editor can refuse to save an image
+//! whose filesystem structures were damaged by an edit.
+//!
+//! Sessions are read-only when the parsed image is: when the
+//! "read-only" setting is true, when the image file can't be written or
+//! is a block device, or when the image is in a format that can't be
+//! saved.  Every change fails with ErrorKind::ReadOnly.
+use std::path::Path;
+
+use log::debug;
+
+use crate::disk_format::device::{is_read_only_source, read_image, DeviceOptions};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageParser;
+use crate::disk_format::overlay::{changed_sectors, Overlay};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::CONVERT;
+use crate::options::Options;
+
+/// A single sector change
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SectorEdit {
+    /// The sector that was changed
+    pub id: SectorId,
+    /// The sector data before the change
+    pub before: Vec<u8>,
+    /// The sector data after the change
+    pub after: Vec<u8>,
+}
+
+/// A group of sector changes that are undone and redone together
+pub type Transaction = Vec<SectorEdit>;
+
+/// An editing session over a flat sector image
+pub struct EditSession<'a> {
+    /// The options used when validating the image
+    config: &'a Options,
+    /// The filename, used to guess the image type when validating
+    filename: String,
+    /// The sector geometry of the image
+    geometry: Geometry,
+    /// The image as it was when the session started
+    original: Vec<u8>,
+    /// The working copy of the image
+    data: Vec<u8>,
+    /// Transactions that can be undone, most recent last
+    undo_stack: Vec<Transaction>,
+    /// Transactions that can be redone, most recent last
+    redo_stack: Vec<Transaction>,
+    /// True if changes are refused
+    read_only: bool,
+}
+
+impl<'a> EditSession<'a> {
+    /// Start a new editing session on a copy of the image data
+    pub fn new(
+        config: &'a Options,
+        filename: &str,
+        data: &[u8],
+        geometry: Geometry,
+    ) -> std::result::Result<EditSession<'a>, Error> {
+        if data.len() < geometry.total_size() {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "Image is smaller than its geometry: {} < {}",
+                    data.len(),
+                    geometry.total_size()
+                ),
+            ))));
+        }
+
+        // Images that don't parse can still be edited sector by sector
+        let original = data.to_vec();
+        let read_only = match original.parse_disk_image(config, filename) {
+            Ok(image) => image.read_only,
+            Err(_) => {
+                config.get_bool("read-only").unwrap_or(false)
+                    || is_read_only_source(Path::new(filename))
+            }
+        };
+
+        Ok(EditSession {
+            config,
+            filename: String::from(filename),
+            geometry,
+            data: original.clone(),
+            original,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            read_only,
+        })
+    }
+
+    /// Start a new editing session, with the geometry from the settings
+    /// or guessed from the image size
+    pub fn from_data(
+        config: &'a Options,
+        filename: &str,
+        data: &[u8],
+    ) -> std::result::Result<EditSession<'a>, Error> {
+        let geometry = Geometry::from_config(config)?
+            .or_else(|| Geometry::from_size(data.len()))
+            .ok_or_else(|| {
+                Error::new(ErrorKind::Unimplemented(format!(
+                    "Unknown geometry for image size: {}",
+                    data.len()
+                )))
+            })?;
+
+        EditSession::new(config, filename, data, geometry)
+    }
+
+    /// Start a new editing session on an image file or a block device
+    /// The session is read-only if the file can't be written, and
+    /// always for block devices.
+    pub fn from_file(
+        config: &'a Options,
+        path: &Path,
+    ) -> std::result::Result<EditSession<'a>, Error> {
+        let options = DeviceOptions {
+            limits: config.limits(),
+            ..DeviceOptions::default()
+        };
+        let data = read_image(path, &options)?;
+        EditSession::from_data(config, &path.to_string_lossy(), &data)
+    }
+
+    /// True if the session refuses changes
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Make the session read-only or writable
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// The geometry of the image being edited
+    pub fn geometry(&self) -> &Geometry {
+        &self.geometry
+    }
+
+    /// The current contents of the image
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Read a sector from the working copy
+    pub fn read_sector(&self, id: &SectorId) -> Option<&[u8]> {
+        self.geometry.sector(&self.data, id)
+    }
+
+    /// Write a single sector as its own transaction
+    pub fn write_sector(&mut self, id: SectorId, data: &[u8]) -> std::result::Result<(), Error> {
+        self.write_sectors(&[(id, data)])
+    }
+
+    /// Write bytes into a sector starting at an offset, as its own
+    /// transaction
+    pub fn write_bytes(
+        &mut self,
+        id: SectorId,
+        offset: usize,
+        bytes: &[u8],
+    ) -> std::result::Result<(), Error> {
+        let mut sector = self.sector_or_error(&id)?.to_vec();
+        let range = sector
+            .get_mut(offset..offset + bytes.len())
+            .ok_or_else(|| {
+                Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                    "Write past the end of sector {}: offset {}, length {}",
+                    id,
+                    offset,
+                    bytes.len()
+                ))))
+            })?;
+        range.copy_from_slice(bytes);
+
+        self.write_sectors(&[(id, &sector)])
+    }
+
+    /// Write several sectors as a single transaction
+    /// Every write is checked before any are applied, so either all of
+    /// the sectors are written or none are.
+    pub fn write_sectors(
+        &mut self,
+        writes: &[(SectorId, &[u8])],
+    ) -> std::result::Result<(), Error> {
+        self.check_writable()?;
+        let mut transaction: Transaction = Vec::new();
+
+        for (id, data) in writes {
+            let before = self.sector_or_error(id)?;
+            if data.len() != self.geometry.sector_size {
+                return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    format!(
+                        "Sector data for {} is {} bytes, sectors are {} bytes",
+                        id,
+                        data.len(),
+                        self.geometry.sector_size
+                    ),
+                ))));
+            }
+            transaction.push(SectorEdit {
+                id: *id,
+                before: before.to_vec(),
+                after: data.to_vec(),
+            });
+        }
+
+        for edit in &transaction {
+            self.store(&edit.id, &edit.after);
+        }
+        debug!(target: CONVERT, "Applied transaction with {} sectors", transaction.len());
+        self.undo_stack.push(transaction);
+        self.redo_stack.clear();
+
+        Ok(())
+    }
+
+    /// Undo the most recent transaction
+    /// Returns the sectors that changed, or None if there is nothing to
+    /// undo or the session is read-only
+    pub fn undo(&mut self) -> Option<Vec<SectorId>> {
+        if self.read_only {
+            return None;
+        }
+        let transaction = self.undo_stack.pop()?;
+        for edit in transaction.iter().rev() {
+            self.store(&edit.id, &edit.before);
+        }
+        let ids = transaction.iter().map(|e| e.id).collect();
+        self.redo_stack.push(transaction);
+
+        Some(ids)
+    }
+
+    /// Redo the most recently undone transaction
+    /// Returns the sectors that changed, or None if there is nothing to
+    /// redo or the session is read-only
+    pub fn redo(&mut self) -> Option<Vec<SectorId>> {
+        if self.read_only {
+            return None;
+        }
+        let transaction = self.redo_stack.pop()?;
+        for edit in &transaction {
+            self.store(&edit.id, &edit.after);
+        }
+        let ids = transaction.iter().map(|e| e.id).collect();
+        self.undo_stack.push(transaction);
+
+        Some(ids)
+    }
+
+    /// True if there is a transaction to undo
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+
+    /// True if there is a transaction to redo
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// The transactions that can be undone, oldest first
+    pub fn history(&self) -> &[Transaction] {
+        &self.undo_stack
+    }
+
+    /// Return the sectors that differ from the original image
+    pub fn changed_sectors(&self) -> Vec<SectorId> {
+        changed_sectors(&self.original, &self.data, &self.geometry).unwrap_or_default()
+    }
+
+    /// True if the working copy differs from the original image
+    pub fn is_modified(&self) -> bool {
+        self.original != self.data
+    }
+
+    /// Build an overlay of the changes made in this session
+    pub fn overlay(&self) -> std::result::Result<Overlay, Error> {
+        Overlay::diff(&self.original, &self.data, &self.geometry)
+    }
+
+    /// Parse the working copy and run the sanity checks on it
+    pub fn validate(&self) -> std::result::Result<(), Error> {
+        let image = self.data.parse_disk_image(self.config, &self.filename)?;
+        if !image.check() {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Edited image failed sanity checks: {}", image.format_name()),
+            ))));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the working copy and return it
+    /// The session is consumed, the caller is responsible for saving
+    /// the returned image.
+    pub fn commit(self) -> std::result::Result<Vec<u8>, Error> {
+        self.check_writable()?;
+        self.validate()?;
+
+        Ok(self.data)
+    }
+
+    /// Return a sector from the working copy or an error if it doesn't
+    /// exist
+    fn sector_or_error(&self, id: &SectorId) -> std::result::Result<&[u8], Error> {
+        self.read_sector(id)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound(format!("Sector not in image: {}", id))))
+    }
+
+    /// Return an error if the session is read-only
+    fn check_writable(&self) -> std::result::Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnly(self.filename.clone())));
+        }
+
+        Ok(())
+    }
+
+    /// Store sector data in the working copy
+    /// The sector has already been checked to exist
+    fn store(&mut self, id: &SectorId, data: &[u8]) {
+        if let Some(offset) = self.geometry.offset(id) {
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EditSession;
+    use crate::disk_format::geometry::SectorId;
+    use crate::disk_format::image::DiskImageParser;
+    use crate::disk_format::testgen;
+    use crate::error::{Error, ErrorKind};
+    use crate::options::Options;
+
+    /// Build a minimal D64 image with a valid Block Availability Map
+    fn d64_image() -> Vec<u8> {
+        let mut data = vec![0_u8; 174848];
+        data[0x16500..0x16504].copy_from_slice(&[0x12, 0x01, 0x41, 0x00]);
+        data[0x165A5..0x165A7].copy_from_slice(b"2A");
+        data
+    }
+
+    /// Test that writes can be undone and redone
+    #[test]
+    fn undo_redo_works() {
+        let config = Options::default();
+        let data = d64_image();
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        let id = SectorId::new(1, 0, 0);
+
+        session.write_bytes(id, 2, b"HELLO").unwrap();
+        assert_eq!(&session.read_sector(&id).unwrap()[0..7], b"\0\0HELLO");
+        assert!(session.is_modified());
+        assert_eq!(session.changed_sectors(), vec![id]);
+
+        assert_eq!(session.undo(), Some(vec![id]));
+        assert!(!session.is_modified());
+        assert!(session.can_redo());
+        assert_eq!(session.undo(), None);
+
+        assert_eq!(session.redo(), Some(vec![id]));
+        assert_eq!(&session.read_sector(&id).unwrap()[2..7], b"HELLO");
+        assert_eq!(session.redo(), None);
+    }
+
+    /// Test that a transaction is applied and undone as a unit, and a
+    /// bad write leaves the image unchanged
+    #[test]
+    fn transaction_works() {
+        let config = Options::default();
+        let data = d64_image();
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        let sector = vec![0xAA_u8; 256];
+
+        session
+            .write_sectors(&[
+                (SectorId::new(1, 0, 0), &sector),
+                (SectorId::new(2, 0, 0), &sector),
+            ])
+            .unwrap();
+        assert_eq!(session.changed_sectors().len(), 2);
+        assert_eq!(session.history().len(), 1);
+
+        let result = session.write_sectors(&[
+            (SectorId::new(3, 0, 0), &sector),
+            (SectorId::new(99, 0, 0), &sector),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(session.changed_sectors().len(), 2);
+
+        session.undo();
+        assert!(!session.is_modified());
+    }
+
+    /// Test that committing an image with a damaged BAM fails
+    #[test]
+    fn commit_validation_works() {
+        let config = Options::default();
+        let data = d64_image();
+
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        session
+            .write_bytes(SectorId::new(18, 0, 0), 0x90, b"DISK")
+            .unwrap();
+        assert!(session.validate().is_ok());
+        assert_eq!(session.commit().unwrap()[0x16590..0x16594], *b"DISK");
+
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        session
+            .write_bytes(SectorId::new(18, 0, 0), 0, &[0x00])
+            .unwrap();
+        assert!(session.commit().is_err());
+    }
+
+    /// Test that read-only sessions refuse changes
+    #[test]
+    fn read_only_works() {
+        let config = Options::default();
+        let data = d64_image();
+        let id = SectorId::new(1, 0, 0);
+
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        assert!(!session.is_read_only());
+        session.write_bytes(id, 0, b"A").unwrap();
+        session.set_read_only(true);
+        assert_eq!(
+            session.write_bytes(id, 0, b"B"),
+            Err(Error::new(ErrorKind::ReadOnly(String::from("test.d64"))))
+        );
+        assert_eq!(session.undo(), None);
+        assert!(session.commit().is_err());
+
+        let config = config.with_override("read-only", true).unwrap();
+        let mut session = EditSession::from_data(&config, "test.d64", &data).unwrap();
+        assert!(session.is_read_only());
+        assert!(session.write_sector(id, &[0; 256]).is_err());
+        assert_eq!(session.data(), &data[..]);
+    }
+
+    /// Test that sectors can be written to Atari ST and ProDOS images,
+    /// and nibble images are read-only
+    #[test]
+    fn sector_image_formats_are_writable() {
+        let config = Options::default();
+        let id = SectorId::new(1, 0, 1);
+
+        let st = testgen::atari_st_fat("DISK", &[]).unwrap();
+        let mut session = EditSession::from_data(&config, "a.st", &st).unwrap();
+        assert!(!session.is_read_only());
+        session.write_bytes(id, 0, b"ST").unwrap();
+        assert_eq!(session.changed_sectors(), vec![id]);
+
+        let po = testgen::prodos("DISK", &[]).unwrap();
+        let mut session = EditSession::from_data(&config, "a.po", &po).unwrap();
+        assert!(!session.is_read_only());
+        session.write_bytes(id, 0, b"PO").unwrap();
+        assert_eq!(session.changed_sectors(), vec![id]);
+
+        let dos = testgen::apple_dos_33(&[]).unwrap();
+        let nib = testgen::nib_from_dos_order(&dos);
+        let image = nib.parse_disk_image(&config, "a.nib").unwrap();
+        assert!(image.is_read_only());
+    }
+}
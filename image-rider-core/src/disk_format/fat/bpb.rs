@@ -0,0 +1,218 @@
+//! The BIOS parameter block
+//!
+//! The boot sector of a FAT volume starts with a branch instruction
+//! and an OEM name, or on the Atari ST a serial number, followed by
+//! the layout of the volume: the sector and cluster sizes, the number
+//! of copies of the file allocation table, the size of the root
+//! directory and the total number of sectors.  The offsets of the
+//! tables, the root directory and the data clusters are computed from
+//! it.
+use std::fmt::{Display, Formatter, Result};
+
+use log::debug;
+use nom::bytes::complete::take;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::fat::chain::{FatType, FIRST_CLUSTER};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::Error;
+use crate::log_target::PARSE;
+use crate::serialize::Serializer;
+
+/// The size of a directory entry
+pub const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+/// Volumes with fewer clusters than this use FAT12
+const FAT12_MAX_CLUSTERS: usize = 4085;
+
+/// The layout of a FAT volume, read from its boot sector
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BiosParameterBlock {
+    /// The number of bytes in each sector
+    pub bytes_per_sector: u16,
+    /// The number of sectors in each cluster
+    pub sectors_per_cluster: u8,
+    /// The number of sectors before the first file allocation table,
+    /// including the boot sector
+    pub reserved_sectors: u16,
+    /// The number of copies of the file allocation table
+    pub fat_count: u8,
+    /// The number of entries in the root directory
+    pub root_entries: u16,
+    /// The total number of sectors on the volume
+    pub total_sectors: u16,
+    /// The media descriptor byte
+    pub media_descriptor: u8,
+    /// The number of sectors in each file allocation table
+    pub sectors_per_fat: u16,
+    /// The number of sectors on each track
+    pub sectors_per_track: u16,
+    /// The number of sides
+    pub heads: u16,
+    /// The number of sectors before the volume
+    pub hidden_sectors: u16,
+}
+
+impl BiosParameterBlock {
+    /// The number of sectors used by the root directory
+    pub fn root_directory_sectors(&self) -> usize {
+        (usize::from(self.root_entries) * DIRECTORY_ENTRY_SIZE)
+            .div_ceil(usize::from(self.bytes_per_sector).max(1))
+    }
+
+    /// The first sector of the root directory
+    pub fn root_directory_sector(&self) -> usize {
+        usize::from(self.reserved_sectors)
+            + usize::from(self.fat_count) * usize::from(self.sectors_per_fat)
+    }
+
+    /// The first sector of the data clusters
+    pub fn data_sector(&self) -> usize {
+        self.root_directory_sector() + self.root_directory_sectors()
+    }
+
+    /// The number of data clusters
+    pub fn clusters(&self) -> usize {
+        usize::from(self.total_sectors).saturating_sub(self.data_sector())
+            / usize::from(self.sectors_per_cluster).max(1)
+    }
+
+    /// The width of the table entries, from the number of clusters
+    pub fn fat_type(&self) -> FatType {
+        if self.clusters() < FAT12_MAX_CLUSTERS {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        }
+    }
+
+    /// The number of bytes in each cluster
+    pub fn cluster_size(&self) -> usize {
+        usize::from(self.bytes_per_sector) * usize::from(self.sectors_per_cluster)
+    }
+
+    /// The sectors of a data cluster
+    /// Returns None if the cluster isn't a data cluster.
+    pub fn cluster_sectors(&self, cluster: u32) -> Option<std::ops::Range<usize>> {
+        let index = usize::try_from(cluster.checked_sub(FIRST_CLUSTER)?).ok()?;
+        if index >= self.clusters() {
+            return None;
+        }
+        let per_cluster = usize::from(self.sectors_per_cluster);
+        let start = self.data_sector() + index * per_cluster;
+        Some(start..start + per_cluster)
+    }
+}
+
+impl SanityCheck for BiosParameterBlock {
+    fn check(&self) -> bool {
+        if !(128..=4096).contains(&self.bytes_per_sector)
+            || !self.bytes_per_sector.is_power_of_two()
+        {
+            debug!(target: PARSE, "Invalid bytes per sector: {}", self.bytes_per_sector);
+            return false;
+        }
+        if !self.sectors_per_cluster.is_power_of_two() {
+            debug!(target: PARSE, "Invalid sectors per cluster: {}", self.sectors_per_cluster);
+            return false;
+        }
+        if self.reserved_sectors == 0 || !(1..=4).contains(&self.fat_count) {
+            debug!(
+                target: PARSE,
+                "Invalid reserved sectors or FAT count: {}, {}",
+                self.reserved_sectors,
+                self.fat_count
+            );
+            return false;
+        }
+        if self.sectors_per_fat == 0 || self.root_entries == 0 {
+            debug!(target: PARSE, "Empty file allocation table or root directory");
+            return false;
+        }
+        if self.clusters() == 0 {
+            debug!(target: PARSE, "No data clusters on {} sectors", self.total_sectors);
+            return false;
+        }
+        let table_size = self
+            .fat_type()
+            .table_size(self.clusters() + FIRST_CLUSTER as usize);
+        if table_size > usize::from(self.sectors_per_fat) * usize::from(self.bytes_per_sector) {
+            debug!(target: PARSE, "The file allocation table is too small for the clusters");
+            return false;
+        }
+        true
+    }
+}
+
+impl Display for BiosParameterBlock {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{} sectors of {} bytes, {} sectors per cluster, {} FATs of {} sectors, {} root entries",
+            self.total_sectors,
+            self.bytes_per_sector,
+            self.sectors_per_cluster,
+            self.fat_count,
+            self.sectors_per_fat,
+            self.root_entries
+        )
+    }
+}
+
+/// Serialize the block as it's stored after the first eleven bytes of
+/// the boot sector
+impl Serializer<'_> for BiosParameterBlock {
+    fn as_vec(&self) -> std::result::Result<Vec<u8>, Error> {
+        let mut data = self.bytes_per_sector.to_le_bytes().to_vec();
+        data.push(self.sectors_per_cluster);
+        data.extend_from_slice(&self.reserved_sectors.to_le_bytes());
+        data.push(self.fat_count);
+        data.extend_from_slice(&self.root_entries.to_le_bytes());
+        data.extend_from_slice(&self.total_sectors.to_le_bytes());
+        data.push(self.media_descriptor);
+        for word in [
+            self.sectors_per_fat,
+            self.sectors_per_track,
+            self.heads,
+            self.hidden_sectors,
+        ] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        Ok(data)
+    }
+}
+
+/// Parse the BIOS parameter block from a boot sector
+pub fn bios_parameter_block_parser(i: &[u8]) -> IResult<&[u8], BiosParameterBlock> {
+    // The branch instruction and the OEM name or serial number
+    let (i, _) = take(11_usize)(i)?;
+    let (i, bytes_per_sector) = le_u16(i)?;
+    let (i, sectors_per_cluster) = le_u8(i)?;
+    let (i, reserved_sectors) = le_u16(i)?;
+    let (i, fat_count) = le_u8(i)?;
+    let (i, root_entries) = le_u16(i)?;
+    let (i, total_sectors) = le_u16(i)?;
+    let (i, media_descriptor) = le_u8(i)?;
+    let (i, sectors_per_fat) = le_u16(i)?;
+    let (i, sectors_per_track) = le_u16(i)?;
+    let (i, heads) = le_u16(i)?;
+    let (i, hidden_sectors) = le_u16(i)?;
+
+    Ok((
+        i,
+        BiosParameterBlock {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            root_entries,
+            total_sectors,
+            media_descriptor,
+            sectors_per_fat,
+            sectors_per_track,
+            heads,
+            hidden_sectors,
+        },
+    ))
+}
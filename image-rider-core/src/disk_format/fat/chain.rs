@@ -0,0 +1,520 @@
+//! File allocation tables and cluster chains
+//!
+//! The table is decoded into one entry per cluster.  Reading files,
+//! checking disks and writing files all go through the chain
+//! functions here, so following, allocating and freeing chains is
+//! implemented once.
+//!
+//! Clusters are numbered from two, entries zero and one hold the media
+//! descriptor and an end of chain marker.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::limits::check_chain_length;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The first cluster that holds data
+pub const FIRST_CLUSTER: u32 = 2;
+
+/// The width of the entries in a file allocation table
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FatType {
+    /// 12 bit entries, two entries in three bytes
+    Fat12,
+    /// 16 bit little endian entries
+    Fat16,
+}
+
+impl FatType {
+    /// The largest entry value
+    fn mask(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+        }
+    }
+
+    /// The size in bytes of a table with a number of entries
+    pub fn table_size(&self, entries: usize) -> usize {
+        match self {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+        }
+    }
+}
+
+impl Display for FatType {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            FatType::Fat12 => write!(f, "FAT12"),
+            FatType::Fat16 => write!(f, "FAT16"),
+        }
+    }
+}
+
+/// The meaning of an entry in the table
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClusterEntry {
+    /// The cluster is free
+    Free,
+    /// The cluster is in use and the chain continues at a cluster
+    Next(u32),
+    /// The cluster is the last in its chain
+    EndOfChain,
+    /// The cluster is marked bad
+    Bad,
+    /// A reserved value
+    Reserved(u32),
+}
+
+impl Display for ClusterEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ClusterEntry::Free => write!(f, "free"),
+            ClusterEntry::Next(cluster) => write!(f, "next: {}", cluster),
+            ClusterEntry::EndOfChain => write!(f, "end of chain"),
+            ClusterEntry::Bad => write!(f, "bad"),
+            ClusterEntry::Reserved(value) => write!(f, "reserved: 0x{:X}", value),
+        }
+    }
+}
+
+/// How allocate looks for free clusters
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AllocationStrategy {
+    /// Take the lowest free clusters
+    #[default]
+    FirstFit,
+    /// Take free clusters after the last cluster allocated, wrapping
+    /// around to the start, the way DOS and TOS allocate
+    NextFit,
+}
+
+/// A decoded file allocation table
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileAllocationTable {
+    /// The width of the entries
+    pub fat_type: FatType,
+    /// The raw entry values, including the two reserved entries
+    pub entries: Vec<u32>,
+    /// Where next-fit allocation continues
+    next_free: u32,
+    /// True if the volume is read-only, changing the table fails with
+    /// ErrorKind::ReadOnly
+    pub read_only: bool,
+}
+
+/// Build an error for a damaged or invalid chain
+fn chain_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+impl FileAllocationTable {
+    /// Create an empty table for a number of data clusters
+    /// The reserved entries hold the media descriptor byte.
+    pub fn new(fat_type: FatType, clusters: usize, media_descriptor: u8) -> FileAllocationTable {
+        let mask = fat_type.mask();
+        let mut entries = vec![0; clusters + FIRST_CLUSTER as usize];
+        entries[0] = (mask & !0xFF) | u32::from(media_descriptor);
+        entries[1] = mask;
+        FileAllocationTable {
+            fat_type,
+            entries,
+            next_free: FIRST_CLUSTER,
+            read_only: false,
+        }
+    }
+
+    /// Decode a table holding a number of data clusters
+    /// Returns an error if the data is too short for the table.
+    pub fn parse(
+        data: &[u8],
+        fat_type: FatType,
+        clusters: usize,
+    ) -> std::result::Result<FileAllocationTable, Error> {
+        let count = clusters + FIRST_CLUSTER as usize;
+        let size = fat_type.table_size(count);
+        if data.len() < size {
+            return Err(chain_error(format!(
+                "A {} table of {} clusters needs {} bytes, only {} were given",
+                fat_type,
+                clusters,
+                size,
+                data.len()
+            )));
+        }
+
+        let entries = (0..count)
+            .map(|n| match fat_type {
+                FatType::Fat12 => {
+                    let offset = n * 3 / 2;
+                    let pair = u32::from(data[offset]) | (u32::from(data[offset + 1]) << 8);
+                    if n % 2 == 0 {
+                        pair & 0xFFF
+                    } else {
+                        pair >> 4
+                    }
+                }
+                FatType::Fat16 => u32::from(u16::from_le_bytes([data[n * 2], data[n * 2 + 1]])),
+            })
+            .collect();
+
+        Ok(FileAllocationTable {
+            fat_type,
+            entries,
+            next_free: FIRST_CLUSTER,
+            read_only: false,
+        })
+    }
+
+    /// Encode the table
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0_u8; self.fat_type.table_size(self.entries.len())];
+        for (n, value) in self.entries.iter().enumerate() {
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = n * 3 / 2;
+                    if n % 2 == 0 {
+                        bytes[offset] = *value as u8;
+                        bytes[offset + 1] =
+                            (bytes[offset + 1] & 0xF0) | ((*value >> 8) as u8 & 0x0F);
+                    } else {
+                        bytes[offset] = (bytes[offset] & 0x0F) | ((*value << 4) as u8);
+                        bytes[offset + 1] = (*value >> 4) as u8;
+                    }
+                }
+                FatType::Fat16 => {
+                    bytes[n * 2..n * 2 + 2].copy_from_slice(&(*value as u16).to_le_bytes())
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The number of data clusters
+    pub fn clusters(&self) -> usize {
+        self.entries.len() - FIRST_CLUSTER as usize
+    }
+
+    /// Return true if a cluster number is a data cluster in the table
+    pub fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && (cluster as usize) < self.entries.len()
+    }
+
+    /// Return an error if a cluster isn't a data cluster
+    fn check_cluster(&self, cluster: u32) -> std::result::Result<(), Error> {
+        if self.is_data_cluster(cluster) {
+            Ok(())
+        } else {
+            Err(chain_error(format!(
+                "Cluster {} isn't between {} and {}",
+                cluster,
+                FIRST_CLUSTER,
+                self.entries.len() - 1
+            )))
+        }
+    }
+
+    /// Return the entry for a cluster, or None if it isn't a data
+    /// cluster
+    pub fn entry(&self, cluster: u32) -> Option<ClusterEntry> {
+        if !self.is_data_cluster(cluster) {
+            return None;
+        }
+        let value = self.entries[cluster as usize];
+        let mask = self.fat_type.mask();
+        Some(match value {
+            0 => ClusterEntry::Free,
+            v if v >= mask - 7 => ClusterEntry::EndOfChain,
+            v if v == mask - 8 => ClusterEntry::Bad,
+            v if self.is_data_cluster(v) => ClusterEntry::Next(v),
+            v => ClusterEntry::Reserved(v),
+        })
+    }
+
+    /// Return an error if the volume is read-only
+    fn check_writable(&self) -> std::result::Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnly(String::from(
+                "The file allocation table is read-only",
+            ))));
+        }
+        Ok(())
+    }
+
+    /// Set the entry for a cluster
+    pub fn set_entry(
+        &mut self,
+        cluster: u32,
+        entry: ClusterEntry,
+    ) -> std::result::Result<(), Error> {
+        self.check_writable()?;
+        self.check_cluster(cluster)?;
+        let mask = self.fat_type.mask();
+        let value = match entry {
+            ClusterEntry::Free => 0,
+            ClusterEntry::Next(next) => {
+                self.check_cluster(next)?;
+                next
+            }
+            ClusterEntry::EndOfChain => mask,
+            ClusterEntry::Bad => mask - 8,
+            ClusterEntry::Reserved(value) => value & mask,
+        };
+        self.entries[cluster as usize] = value;
+        Ok(())
+    }
+
+    /// Return true if a cluster is free
+    pub fn is_free(&self, cluster: u32) -> bool {
+        self.entry(cluster) == Some(ClusterEntry::Free)
+    }
+
+    /// The number of free clusters
+    pub fn free_clusters(&self) -> usize {
+        (FIRST_CLUSTER..self.entries.len() as u32)
+            .filter(|cluster| self.is_free(*cluster))
+            .count()
+    }
+
+    /// Follow a chain from its first cluster
+    /// Returns an error if the chain loops, runs into a free, bad or
+    /// reserved cluster, or leaves the table.
+    pub fn follow(&self, start: u32) -> std::result::Result<Vec<u32>, Error> {
+        let mut chain = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut cluster = start;
+
+        loop {
+            self.check_cluster(cluster)?;
+            if !seen.insert(cluster) {
+                return Err(chain_error(format!(
+                    "The chain starting at cluster {} loops back to cluster {}",
+                    start, cluster
+                )));
+            }
+            chain.push(cluster);
+            check_chain_length(chain.len())?;
+
+            match self.entry(cluster) {
+                Some(ClusterEntry::Next(next)) => cluster = next,
+                Some(ClusterEntry::EndOfChain) => return Ok(chain),
+                Some(entry) => {
+                    return Err(chain_error(format!(
+                        "The chain starting at cluster {} reaches cluster {}, which is {}",
+                        start, cluster, entry
+                    )))
+                }
+                None => return Err(chain_error(format!("Cluster {} is out of range", cluster))),
+            }
+        }
+    }
+
+    /// Allocate a chain of clusters
+    /// If after is given, the new clusters are linked to the end of the
+    /// chain starting there.  The table isn't changed if there aren't
+    /// enough free clusters.  Returns the new clusters.
+    pub fn allocate(
+        &mut self,
+        count: usize,
+        strategy: AllocationStrategy,
+        after: Option<u32>,
+    ) -> std::result::Result<Vec<u32>, Error> {
+        self.check_writable()?;
+        let tail = match after {
+            Some(start) => self.follow(start)?.last().copied(),
+            None => None,
+        };
+
+        let end = self.entries.len() as u32;
+        let start = match strategy {
+            AllocationStrategy::FirstFit => FIRST_CLUSTER,
+            AllocationStrategy::NextFit => self.next_free.clamp(FIRST_CLUSTER, end),
+        };
+        let clusters: Vec<u32> = (start..end)
+            .chain(FIRST_CLUSTER..start)
+            .filter(|cluster| self.is_free(*cluster))
+            .take(count)
+            .collect();
+        if clusters.len() < count {
+            return Err(chain_error(format!(
+                "{} clusters are needed but only {} are free",
+                count,
+                clusters.len()
+            )));
+        }
+
+        for (n, cluster) in clusters.iter().enumerate() {
+            let entry = match clusters.get(n + 1) {
+                Some(next) => ClusterEntry::Next(*next),
+                None => ClusterEntry::EndOfChain,
+            };
+            self.set_entry(*cluster, entry)?;
+        }
+        if let (Some(tail), Some(first)) = (tail, clusters.first()) {
+            self.set_entry(tail, ClusterEntry::Next(*first))?;
+        }
+        if let Some(last) = clusters.last() {
+            self.next_free = last + 1;
+        }
+
+        Ok(clusters)
+    }
+
+    /// Free every cluster of a chain
+    /// Returns the number of clusters freed.
+    pub fn free(&mut self, start: u32) -> std::result::Result<usize, Error> {
+        self.check_writable()?;
+        let chain = self.follow(start)?;
+        for cluster in &chain {
+            self.set_entry(*cluster, ClusterEntry::Free)?;
+        }
+        Ok(chain.len())
+    }
+
+    /// Cut a chain down to a number of clusters, freeing the rest
+    /// A chain can't be truncated to zero clusters, free it instead.
+    /// Returns the number of clusters freed.
+    pub fn truncate(&mut self, start: u32, length: usize) -> std::result::Result<usize, Error> {
+        self.check_writable()?;
+        if length == 0 {
+            return Err(chain_error(String::from(
+                "A chain can't be truncated to zero clusters",
+            )));
+        }
+        let chain = self.follow(start)?;
+        if chain.len() <= length {
+            return Ok(0);
+        }
+        self.set_entry(chain[length - 1], ClusterEntry::EndOfChain)?;
+        for cluster in &chain[length..] {
+            self.set_entry(*cluster, ClusterEntry::Free)?;
+        }
+        Ok(chain.len() - length)
+    }
+
+    /// Find clusters used by more than one chain
+    /// Returns each cross-linked cluster with the first clusters of the
+    /// chains that reach it.  Chains that can't be followed are checked
+    /// up to where they fail.
+    pub fn cross_links(&self, starts: &[u32]) -> BTreeMap<u32, Vec<u32>> {
+        let mut owners: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for start in starts {
+            let mut seen = BTreeSet::new();
+            let mut cluster = *start;
+            while self.is_data_cluster(cluster) && seen.insert(cluster) {
+                owners.entry(cluster).or_default().push(*start);
+                match self.entry(cluster) {
+                    Some(ClusterEntry::Next(next)) => cluster = next,
+                    _ => break,
+                }
+            }
+        }
+
+        owners.retain(|_, chains| chains.len() > 1);
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocationStrategy, ClusterEntry, FatType, FileAllocationTable};
+
+    /// Test decoding and encoding FAT12 and FAT16 tables
+    #[test]
+    fn file_allocation_table_parse_works() {
+        // Clusters 2 -> 3 -> 4 end, 5 free, 6 bad, 7 end
+        let fat12 = [
+            0xF9, 0xFF, 0xFF, 0x03, 0x40, 0x00, 0xFF, 0x0F, 0x00, 0xF7, 0xFF, 0xFF,
+        ];
+        let table = FileAllocationTable::parse(&fat12, FatType::Fat12, 6).unwrap();
+        assert_eq!(table.follow(2).unwrap(), [2, 3, 4]);
+        assert_eq!(table.entry(5), Some(ClusterEntry::Free));
+        assert_eq!(table.entry(6), Some(ClusterEntry::Bad));
+        assert_eq!(table.entry(8), None);
+        assert_eq!(table.free_clusters(), 1);
+        assert_eq!(table.to_bytes(), fat12);
+
+        let mut fat16 = FileAllocationTable::new(FatType::Fat16, 4, 0xF8);
+        fat16
+            .allocate(2, AllocationStrategy::FirstFit, None)
+            .unwrap();
+        assert_eq!(
+            fat16.to_bytes(),
+            [0xF8, 0xFF, 0xFF, 0xFF, 0x03, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert!(FileAllocationTable::parse(&fat12, FatType::Fat16, 6).is_err());
+    }
+
+    /// Test allocating, extending, truncating and freeing chains
+    #[test]
+    fn cluster_chain_works() {
+        let mut table = FileAllocationTable::new(FatType::Fat12, 10, 0xF9);
+        let first = table
+            .allocate(3, AllocationStrategy::FirstFit, None)
+            .unwrap();
+        assert_eq!(first, [2, 3, 4]);
+        let second = table
+            .allocate(2, AllocationStrategy::NextFit, None)
+            .unwrap();
+        assert_eq!(second, [5, 6]);
+
+        // Freed clusters are reused by first-fit but not next-fit
+        table.free(2).unwrap();
+        assert_eq!(
+            table
+                .allocate(1, AllocationStrategy::NextFit, None)
+                .unwrap(),
+            [7]
+        );
+        assert_eq!(
+            table
+                .allocate(2, AllocationStrategy::FirstFit, Some(5))
+                .unwrap(),
+            [2, 3]
+        );
+        assert_eq!(table.follow(5).unwrap(), [5, 6, 2, 3]);
+
+        assert_eq!(table.truncate(5, 1).unwrap(), 3);
+        assert_eq!(table.follow(5).unwrap(), [5]);
+        assert!(table.is_free(2));
+        assert!(table.truncate(5, 0).is_err());
+        assert!(table
+            .allocate(20, AllocationStrategy::FirstFit, None)
+            .is_err());
+        assert_eq!(table.free_clusters(), 8);
+
+        // Read-only volumes refuse changes
+        table.read_only = true;
+        assert!(table
+            .allocate(1, AllocationStrategy::FirstFit, None)
+            .is_err());
+        assert!(table.truncate(5, 1).is_err());
+        assert!(table.free(5).is_err());
+        assert!(table.set_entry(2, ClusterEntry::Bad).is_err());
+        assert_eq!(table.free_clusters(), 8);
+    }
+
+    /// Test finding loops and cross-linked chains
+    #[test]
+    fn cluster_chain_damage_works() {
+        let mut table = FileAllocationTable::new(FatType::Fat12, 8, 0xF9);
+        table.set_entry(2, ClusterEntry::Next(3)).unwrap();
+        table.set_entry(3, ClusterEntry::Next(4)).unwrap();
+        table.set_entry(4, ClusterEntry::EndOfChain).unwrap();
+        table.set_entry(6, ClusterEntry::Next(3)).unwrap();
+        table.set_entry(7, ClusterEntry::Next(8)).unwrap();
+        table.set_entry(8, ClusterEntry::Next(7)).unwrap();
+
+        let cross_links = table.cross_links(&[2, 6, 7]);
+        assert_eq!(cross_links.len(), 2);
+        assert_eq!(cross_links[&3], [2, 6]);
+        assert_eq!(cross_links[&4], [2, 6]);
+
+        assert!(table.follow(7).is_err());
+        assert!(table.follow(5).is_err());
+        assert!(table.set_entry(1, ClusterEntry::Free).is_err());
+        assert!(table.set_entry(2, ClusterEntry::Next(10)).is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! Directory entries
+//!
+//! A directory is a list of 32 byte entries, each holding an 8.3 name,
+//! the attributes, the modification time, the first cluster and the
+//! size of a file.  The root directory has a fixed size after the file
+//! allocation tables, subdirectories are stored in cluster chains like
+//! files.  A zero byte at the start of a name ends the directory.
+use std::fmt::{Display, Formatter, Result};
+
+use nom::bytes::complete::take;
+use nom::multi::many0;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::error::Error;
+use crate::serialize::Serializer;
+
+/// The file can't be written
+pub const ATTRIBUTE_READ_ONLY: u8 = 0x01;
+/// The file is hidden from directory listings
+pub const ATTRIBUTE_HIDDEN: u8 = 0x02;
+/// The file belongs to the operating system
+pub const ATTRIBUTE_SYSTEM: u8 = 0x04;
+/// The entry is the volume label
+pub const ATTRIBUTE_VOLUME_LABEL: u8 = 0x08;
+/// The entry is a subdirectory
+pub const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+/// The file has changed since it was last backed up
+pub const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+
+/// The attributes of a long file name entry
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+/// The first name byte of a deleted entry
+const DELETED_MARKER: u8 = 0xE5;
+
+/// The first name byte stored for a name starting with 0xE5
+const ESCAPED_DELETED_MARKER: u8 = 0x05;
+
+/// An entry in a directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectoryEntry {
+    /// The name, padded with spaces
+    pub name: [u8; 8],
+    /// The extension, padded with spaces
+    pub extension: [u8; 3],
+    /// The attribute bits
+    pub attributes: u8,
+    /// The modification time, in DOS format
+    pub time: u16,
+    /// The modification date, in DOS format
+    pub date: u16,
+    /// The first cluster of the file, zero for an empty file
+    pub start_cluster: u16,
+    /// The size of the file in bytes, zero for a directory
+    pub size: u32,
+}
+
+impl DirectoryEntry {
+    /// Create an entry for a name, which is split into the name and
+    /// extension at the last dot and padded with spaces
+    /// The "." and ".." entries of a subdirectory keep their dots.
+    pub fn new(filename: &str, attributes: u8, start_cluster: u16, size: u32) -> DirectoryEntry {
+        let (name, extension) = match filename.rsplit_once('.') {
+            Some((name, extension)) if !name.is_empty() && !name.ends_with('.') => {
+                (name, extension)
+            }
+            _ => (filename, ""),
+        };
+        DirectoryEntry {
+            name: pad_name(name),
+            extension: pad_name(extension),
+            attributes,
+            time: 0,
+            date: 0,
+            start_cluster,
+            size,
+        }
+    }
+
+    /// Create a volume label entry, the label fills the name and
+    /// extension without a dot
+    pub fn volume_label(label: &str) -> DirectoryEntry {
+        let padded: [u8; 11] = pad_name(label);
+        DirectoryEntry {
+            name: padded[..8].try_into().unwrap(),
+            extension: padded[8..].try_into().unwrap(),
+            ..DirectoryEntry::new("", ATTRIBUTE_VOLUME_LABEL, 0, 0)
+        }
+    }
+
+    /// Return true if the entry ends the directory
+    pub fn is_end(&self) -> bool {
+        self.name[0] == 0x00
+    }
+
+    /// Return true if the file was deleted
+    pub fn is_deleted(&self) -> bool {
+        self.name[0] == DELETED_MARKER
+    }
+
+    /// Return true if the entry is the volume label
+    pub fn is_volume_label(&self) -> bool {
+        self.attributes & ATTRIBUTE_VOLUME_LABEL != 0 && !self.is_long_name()
+    }
+
+    /// Return true if the entry is part of a long file name
+    pub fn is_long_name(&self) -> bool {
+        self.attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME
+    }
+
+    /// Return true if the entry is a subdirectory
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0 && !self.is_long_name()
+    }
+
+    /// Return true if the entry is the "." or ".." entry of a
+    /// subdirectory
+    pub fn is_dot(&self) -> bool {
+        self.name[0] == b'.'
+    }
+
+    /// Return true if the entry is a file or directory that's in use
+    pub fn is_used(&self) -> bool {
+        !self.is_end()
+            && !self.is_deleted()
+            && !self.is_long_name()
+            && !self.is_volume_label()
+            && !self.is_dot()
+    }
+
+    /// The name and extension as they're stored in the directory
+    pub fn raw_name(&self) -> Vec<u8> {
+        let mut raw_name = self.name.to_vec();
+        raw_name.extend_from_slice(&self.extension);
+        raw_name
+    }
+
+    /// The name with trailing spaces removed, joined to the extension
+    /// with a dot if there is one
+    pub fn filename(&self) -> String {
+        let mut name = self.name;
+        if name[0] == ESCAPED_DELETED_MARKER {
+            name[0] = DELETED_MARKER;
+        }
+        let name = trim_name(&name);
+        let extension = trim_name(&self.extension);
+        if extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, extension)
+        }
+    }
+
+    /// The volume label, the name and extension without a dot
+    pub fn label(&self) -> String {
+        trim_name(&self.raw_name())
+    }
+
+    /// The attributes as a string of flags, e.g. "R--A"
+    pub fn attribute_string(&self) -> String {
+        [
+            (ATTRIBUTE_READ_ONLY, 'R'),
+            (ATTRIBUTE_HIDDEN, 'H'),
+            (ATTRIBUTE_SYSTEM, 'S'),
+            (ATTRIBUTE_ARCHIVE, 'A'),
+        ]
+        .iter()
+        .map(|(bit, flag)| {
+            if self.attributes & bit != 0 {
+                *flag
+            } else {
+                '-'
+            }
+        })
+        .collect()
+    }
+
+    /// The modification date and time, e.g. "1989-04-12 13:05:20"
+    pub fn modified(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            1980 + (self.date >> 9),
+            (self.date >> 5) & 0x0F,
+            self.date & 0x1F,
+            self.time >> 11,
+            (self.time >> 5) & 0x3F,
+            (self.time & 0x1F) * 2
+        )
+    }
+}
+
+/// Format an entry as a line of a directory listing
+impl Display for DirectoryEntry {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let size = if self.is_directory() {
+            String::from("<DIR>")
+        } else {
+            self.size.to_string()
+        };
+        write!(
+            f,
+            "{:<12} {:>8} {} {}",
+            self.filename(),
+            size,
+            self.modified(),
+            self.attribute_string()
+        )
+    }
+}
+
+impl Serializer<'_> for DirectoryEntry {
+    fn as_vec(&self) -> std::result::Result<Vec<u8>, Error> {
+        let mut data = self.raw_name();
+        data.push(self.attributes);
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(&self.time.to_le_bytes());
+        data.extend_from_slice(&self.date.to_le_bytes());
+        data.extend_from_slice(&self.start_cluster.to_le_bytes());
+        data.extend_from_slice(&self.size.to_le_bytes());
+        Ok(data)
+    }
+}
+
+/// Pad a name with spaces, cutting it if it's too long
+fn pad_name<const N: usize>(name: &str) -> [u8; N] {
+    let mut padded = [b' '; N];
+    for (byte, c) in padded.iter_mut().zip(name.bytes()) {
+        *byte = c;
+    }
+    padded
+}
+
+/// Convert a space padded name to a string
+/// Bytes outside printable ASCII are shown as '?'.
+fn trim_name(name: &[u8]) -> String {
+    let end = name
+        .iter()
+        .rposition(|b| *b != b' ')
+        .map_or(0, |position| position + 1);
+    name[..end]
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                char::from(*b)
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+/// Parse a directory entry
+pub fn directory_entry_parser(i: &[u8]) -> IResult<&[u8], DirectoryEntry> {
+    let (i, name) = take(8_usize)(i)?;
+    let (i, extension) = take(3_usize)(i)?;
+    let (i, attributes) = le_u8(i)?;
+    let (i, _reserved) = take(10_usize)(i)?;
+    let (i, time) = le_u16(i)?;
+    let (i, date) = le_u16(i)?;
+    let (i, start_cluster) = le_u16(i)?;
+    let (i, size) = le_u32(i)?;
+
+    Ok((
+        i,
+        DirectoryEntry {
+            name: name.try_into().unwrap(),
+            extension: extension.try_into().unwrap(),
+            attributes,
+            time,
+            date,
+            start_cluster,
+            size,
+        },
+    ))
+}
+
+/// Parse the entries of a directory, up to the entry that ends it
+pub fn directory_parser(i: &[u8]) -> IResult<&[u8], Vec<DirectoryEntry>> {
+    let (i, mut entries) = many0(directory_entry_parser)(i)?;
+    if let Some(end) = entries.iter().position(DirectoryEntry::is_end) {
+        entries.truncate(end);
+    }
+    Ok((i, entries))
+}
@@ -0,0 +1,21 @@
+//! FAT filesystems
+//!
+//! Atari ST disks, and many other disks with 512 byte sectors, use the
+//! MS-DOS FAT12 filesystem.  Files are stored in clusters of sectors
+//! and the file allocation table links each cluster of a file to the
+//! next one.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// File allocation tables and cluster chains
+pub mod chain;
+
+/// The BIOS parameter block in the boot sector
+pub mod bpb;
+
+/// Directory entries
+pub mod directory;
+
+/// Parsing volumes and reading their files
+pub mod volume;
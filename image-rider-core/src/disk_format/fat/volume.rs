@@ -0,0 +1,368 @@
+//! FAT volumes
+//!
+//! A volume is parsed from a flat image of its logical sectors: the
+//! BIOS parameter block in the boot sector gives the layout, the first
+//! file allocation table is decoded and the directory tree is walked
+//! from the root directory.  Files are read by following their cluster
+//! chains and cutting the data at the size in the directory entry.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+
+use log::warn;
+
+use crate::disk_format::fat::bpb::{
+    bios_parameter_block_parser, BiosParameterBlock, DIRECTORY_ENTRY_SIZE,
+};
+use crate::disk_format::fat::chain::{FileAllocationTable, FIRST_CLUSTER};
+use crate::disk_format::fat::directory::{directory_parser, DirectoryEntry};
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// The deepest subdirectory that's followed, which also stops
+/// directories that contain themselves
+const MAX_DIRECTORY_DEPTH: usize = 16;
+
+/// The separator between directory names in a path
+pub const PATH_SEPARATOR: char = '/';
+
+/// A file or directory on a volume, with its path from the root
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FatFile {
+    /// The path of the file, directory names joined with '/'
+    pub path: String,
+    /// The directory entry of the file
+    pub entry: DirectoryEntry,
+}
+
+/// A parsed FAT volume
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FatVolume {
+    /// The logical sectors of the volume
+    pub data: Vec<u8>,
+    /// The layout of the volume
+    pub bpb: BiosParameterBlock,
+    /// The first file allocation table
+    pub fat: FileAllocationTable,
+    /// The entries in the root directory, including the volume label
+    pub root_directory: Vec<DirectoryEntry>,
+    /// Every file and directory, in directory order
+    pub files: Vec<FatFile>,
+}
+
+/// Build an error for a damaged volume
+fn volume_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+impl FatVolume {
+    /// Parse a volume from a flat image of its sectors
+    /// Returns an error if the boot sector doesn't hold a valid BIOS
+    /// parameter block or the tables are outside the image.
+    /// Subdirectories that can't be read are skipped with a warning.
+    pub fn parse(data: Vec<u8>) -> std::result::Result<FatVolume, Error> {
+        let (_, bpb) = bios_parameter_block_parser(&data)?;
+        if !bpb.check() {
+            return Err(volume_error(String::from(
+                "The boot sector doesn't hold a valid BIOS parameter block",
+            )));
+        }
+
+        let sector_size = usize::from(bpb.bytes_per_sector);
+        let fat_start = usize::from(bpb.reserved_sectors) * sector_size;
+        let fat_end = fat_start + usize::from(bpb.sectors_per_fat) * sector_size;
+        let fat_data = data.get(fat_start..fat_end).ok_or_else(|| {
+            volume_error(String::from(
+                "The file allocation table is past the end of the image",
+            ))
+        })?;
+        let fat = FileAllocationTable::parse(fat_data, bpb.fat_type(), bpb.clusters())?;
+
+        let root_start = bpb.root_directory_sector() * sector_size;
+        let root_end = root_start + usize::from(bpb.root_entries) * DIRECTORY_ENTRY_SIZE;
+        let root_data = data.get(root_start..root_end).ok_or_else(|| {
+            volume_error(String::from(
+                "The root directory is past the end of the image",
+            ))
+        })?;
+        let (_, root_directory) = directory_parser(root_data)?;
+
+        let mut volume = FatVolume {
+            data,
+            bpb,
+            fat,
+            root_directory,
+            files: Vec::new(),
+        };
+        let mut files = Vec::new();
+        volume.walk(&volume.root_directory, "", 0, &mut files);
+        volume.files = files;
+
+        Ok(volume)
+    }
+
+    /// Add the entries of a directory and its subdirectories to a list
+    fn walk(
+        &self,
+        entries: &[DirectoryEntry],
+        prefix: &str,
+        depth: usize,
+        files: &mut Vec<FatFile>,
+    ) {
+        for entry in entries.iter().filter(|entry| entry.is_used()) {
+            let path = format!("{}{}", prefix, entry.filename());
+            files.push(FatFile {
+                path: path.clone(),
+                entry: entry.clone(),
+            });
+            if !entry.is_directory() {
+                continue;
+            }
+            if depth >= MAX_DIRECTORY_DEPTH {
+                warn!(target: PARSE, "Directory {} is nested too deeply, skipping it", path);
+                continue;
+            }
+            match self
+                .read_chain(u32::from(entry.start_cluster))
+                .and_then(|data| Ok(directory_parser(&data)?.1))
+            {
+                Ok(children) => self.walk(
+                    &children,
+                    &format!("{}{}", path, PATH_SEPARATOR),
+                    depth + 1,
+                    files,
+                ),
+                Err(e) => warn!(target: PARSE, "Error reading directory {}: {}", path, e),
+            }
+        }
+    }
+
+    /// Read the clusters of a chain
+    fn read_chain(&self, start: u32) -> std::result::Result<Vec<u8>, Error> {
+        let sector_size = usize::from(self.bpb.bytes_per_sector);
+        let mut data = Vec::new();
+        for cluster in self.fat.follow(start)? {
+            let sectors = self
+                .bpb
+                .cluster_sectors(cluster)
+                .ok_or_else(|| volume_error(format!("Cluster {} is out of range", cluster)))?;
+            let cluster_data = self
+                .data
+                .get(sectors.start * sector_size..sectors.end * sector_size)
+                .ok_or_else(|| {
+                    volume_error(format!("Cluster {} is past the end of the image", cluster))
+                })?;
+            data.extend_from_slice(cluster_data);
+        }
+        Ok(data)
+    }
+
+    /// Read the data of a file
+    /// Returns an error if the chain is damaged or shorter than the
+    /// file.
+    pub fn read_file(&self, entry: &DirectoryEntry) -> std::result::Result<Vec<u8>, Error> {
+        if entry.start_cluster == 0 {
+            return Ok(Vec::new());
+        }
+        let mut data = self.read_chain(u32::from(entry.start_cluster))?;
+        let size = entry.size as usize;
+        if !entry.is_directory() {
+            if data.len() < size {
+                return Err(volume_error(format!(
+                    "{} is {} bytes but its clusters only hold {}",
+                    entry.filename(),
+                    size,
+                    data.len()
+                )));
+            }
+            data.truncate(size);
+        }
+        Ok(data)
+    }
+
+    /// Return the volume label from the root directory
+    pub fn label(&self) -> Option<String> {
+        self.root_directory
+            .iter()
+            .find(|entry| entry.is_volume_label() && !entry.is_deleted())
+            .map(DirectoryEntry::label)
+    }
+
+    /// Return the files on the volume, without directories
+    /// Files that can't be read are skipped with a warning.
+    pub fn disk_files(&self) -> Vec<DiskFile> {
+        self.files
+            .iter()
+            .filter(|file| !file.entry.is_directory())
+            .filter_map(|file| match self.read_file(&file.entry) {
+                Ok(data) => Some(DiskFile {
+                    name: file.path.clone(),
+                    file_type: file.entry.attribute_string(),
+                    raw_name: file.entry.raw_name(),
+                    data,
+                }),
+                Err(e) => {
+                    warn!(target: PARSE, "Error reading {}: {}", file.path, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Return the sectors of a chain, in chain order
+    /// The chain is followed as far as it can be.
+    pub fn chain_sectors(&self, start: u32) -> Vec<usize> {
+        self.fat
+            .follow(start)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|cluster| self.bpb.cluster_sectors(*cluster))
+            .flatten()
+            .collect()
+    }
+
+    /// Return the sectors of the free clusters
+    pub fn free_sectors(&self) -> BTreeSet<usize> {
+        (0..self.fat.clusters() as u32)
+            .map(|index| index + FIRST_CLUSTER)
+            .filter(|cluster| self.fat.is_free(*cluster))
+            .filter_map(|cluster| self.bpb.cluster_sectors(cluster))
+            .flatten()
+            .collect()
+    }
+
+    /// Return the sectors used by the filesystem: the boot sector, the
+    /// file allocation tables, the root directory and the clusters of
+    /// every subdirectory
+    pub fn system_sectors(&self) -> BTreeSet<usize> {
+        let mut system: BTreeSet<usize> = (0..self.bpb.data_sector()).collect();
+        for file in self.files.iter().filter(|file| file.entry.is_directory()) {
+            system.extend(self.chain_sectors(u32::from(file.entry.start_cluster)));
+        }
+        system
+    }
+
+    /// Return the disk sectors holding a set of volume sectors, in the
+    /// geometry of the disk the volume was read from
+    pub fn sector_ids(
+        &self,
+        geometry: &Geometry,
+        sectors: impl IntoIterator<Item = usize>,
+    ) -> Vec<SectorId> {
+        let sector_size = usize::from(self.bpb.bytes_per_sector);
+        let mut ids: Vec<SectorId> = Vec::new();
+        for sector in sectors {
+            for offset in (sector * sector_size..(sector + 1) * sector_size)
+                .step_by(geometry.sector_size.max(1))
+            {
+                if let Some(id) = geometry.sector_at(offset) {
+                    if ids.last() != Some(&id) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    /// Return the disk sectors of each file, sorted by path
+    pub fn file_extents(&self, geometry: &Geometry) -> Vec<FileExtent> {
+        let sector_size = geometry.sector_size.max(1);
+        let mut extents: Vec<FileExtent> = self
+            .files
+            .iter()
+            .filter(|file| !file.entry.is_directory())
+            .map(|file| {
+                let size = file.entry.size as usize;
+                FileExtent {
+                    name: file.path.clone(),
+                    sectors: self.sector_ids(
+                        geometry,
+                        self.chain_sectors(u32::from(file.entry.start_cluster)),
+                    ),
+                    end: Some((size / sector_size, size % sector_size)),
+                }
+            })
+            .collect();
+        extents.sort_by(|a, b| a.name.cmp(&b.name));
+        extents
+    }
+}
+
+/// Format the volume as a directory listing, with the volume label
+/// and the free space
+/// The files in a subdirectory are indented under it.
+impl Display for FatVolume {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(
+            f,
+            "Volume: {}",
+            self.label().unwrap_or_else(|| String::from("(no label)"))
+        )?;
+        for file in &self.files {
+            let depth = file.path.matches(PATH_SEPARATOR).count();
+            writeln!(f, "{}{}", "  ".repeat(depth), file.entry)?;
+        }
+        write!(
+            f,
+            "{} bytes free",
+            self.fat.free_clusters() * self.bpb.cluster_size()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FatVolume;
+    use crate::disk_format::fat::chain::ClusterEntry;
+    use crate::disk_format::testgen::atari_st_fat;
+
+    /// Test reading the directory tree and files of a volume
+    #[test]
+    fn fat_volume_works() {
+        let long: Vec<u8> = (0..5000_usize).map(|i| (i % 251) as u8).collect();
+        let data = atari_st_fat(
+            "GAMEDISK",
+            &[
+                ("README.TXT", b"HELLO ATARI"),
+                ("EMPTY", &[]),
+                ("AUTO/LOADER.PRG", &long),
+            ],
+        )
+        .unwrap();
+
+        let volume = FatVolume::parse(data.clone()).unwrap();
+        assert_eq!(volume.label().as_deref(), Some("GAMEDISK"));
+        let paths: Vec<&str> = volume.files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["README.TXT", "EMPTY", "AUTO", "AUTO/LOADER.PRG"]);
+
+        let files = volume.disk_files();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].data, b"HELLO ATARI");
+        assert_eq!(files[0].file_type, "---A");
+        assert_eq!(files[0].raw_name, b"README  TXT");
+        assert!(files[1].data.is_empty());
+        assert_eq!(files[2].data, long);
+
+        let listing = volume.to_string();
+        assert!(listing.starts_with("Volume: GAMEDISK\n"));
+        assert!(listing.contains("\n  LOADER.PRG       5000 1989-04-12 00:00:00 ---A\n"));
+
+        // The boot sector, two FATs of five sectors, seven root
+        // directory sectors and the AUTO directory cluster
+        assert_eq!(volume.system_sectors().len(), 20);
+        assert_eq!(volume.free_sectors().len(), volume.fat.free_clusters() * 2);
+
+        // A broken chain makes the file unreadable but not the volume
+        let mut damaged = volume.clone();
+        damaged.fat.set_entry(4, ClusterEntry::Free).unwrap();
+        assert_eq!(damaged.disk_files().len(), 2);
+
+        let mut bad_boot_sector = data;
+        bad_boot_sector[12] = 0x00;
+        assert!(FatVolume::parse(bad_boot_sector).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Selecting files to export and naming the exported files
+//!
+//! Files are selected by name with glob patterns, where * matches any
+//! run of characters and ? matches a single character, or every file
+//! on the disk is selected.  Exported files are named with a template
+//! that can include the file's name and type, e.g. "{name}.{type}.bin".
+//!
+//! Different names on a disk can map to the same host name once
+//! they're made safe, or differ only in case on a case-insensitive host
+//! file system.  A naming policy sets how names are made safe, what
+//! happens when two files end up with the same name and whether a
+//! metadata file with the original name bytes is written next to each
+//! file.  It can be loaded from the configuration:
+//!
+//! ```toml
+//! name-sanitize = "strict"
+//! name-collisions = "suffix"
+//! name-sidecar = true
+//! ```
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use config::Config;
+use log::warn;
+use serde::Serialize;
+
+use crate::disk_format::apple::wrappers::host_file_name;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::IO;
+
+/// The default template for naming exported files, the file's name
+pub const DEFAULT_NAME_TEMPLATE: &str = "{name}";
+
+/// A file on a disk, ready to be exported
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiskFile {
+    /// The name of the file on the disk
+    pub name: String,
+    /// The type of the file, in the platform's catalog notation
+    pub file_type: String,
+    /// The name as it's stored in the directory, before any character
+    /// set conversion
+    pub raw_name: Vec<u8>,
+    /// The file data
+    pub data: Vec<u8>,
+}
+
+/// The files to select from a disk
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FileSelection {
+    /// Every file on the disk
+    All,
+    /// The files matching any of these glob patterns
+    Patterns(Vec<String>),
+}
+
+impl FileSelection {
+    /// Return true if a file name is selected
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            FileSelection::All => true,
+            FileSelection::Patterns(patterns) => patterns.iter().any(|p| glob_match(p, name)),
+        }
+    }
+
+    /// Return true if the selection can match more than one file
+    pub fn is_multiple(&self) -> bool {
+        match self {
+            FileSelection::All => true,
+            FileSelection::Patterns(patterns) => {
+                patterns.len() > 1 || patterns.iter().any(|p| p.contains(['*', '?']))
+            }
+        }
+    }
+}
+
+/// Parse a selection from a comma-separated list of patterns
+impl FromStr for FileSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<FileSelection, Error> {
+        Ok(FileSelection::Patterns(
+            s.split(',').map(String::from).collect(),
+        ))
+    }
+}
+
+/// Match a name against a glob pattern
+/// A star matches any run of characters, including none, and a
+/// question mark matches a single character.  Matching is
+/// case-sensitive.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last star and the name position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Build the output name for a file from a template
+/// {name} is replaced with the file's name and {type} with its type,
+/// both made safe for use as a host file name.
+pub fn output_name(template: &str, file: &DiskFile) -> String {
+    template
+        .replace("{name}", &host_file_name(&file.name))
+        .replace("{type}", &host_file_name(&file.file_type))
+}
+
+/// How names are made safe to create on the host
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Sanitize {
+    /// Keep letters, digits, spaces, dots, dashes and underscores and
+    /// replace everything else with an underscore
+    #[default]
+    Portable,
+    /// Portable names, lowercased and with spaces replaced, for case
+    /// insensitive file systems and shell scripts
+    Strict,
+    /// Only replace path separators and control characters
+    Minimal,
+}
+
+/// What happens when a file's name is already used by an earlier file
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Collisions {
+    /// Add a number to the name, before the extension: "GAME_2.bin"
+    #[default]
+    Suffix,
+    /// Write over the earlier file
+    Overwrite,
+    /// Stop with an error
+    Error,
+}
+
+/// Build an error for an unknown naming setting
+fn unknown_setting(what: &str, value: &str) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "Unknown {}: {}",
+        what, value
+    ))))
+}
+
+impl FromStr for Sanitize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Sanitize, Error> {
+        match s.to_lowercase().as_str() {
+            "portable" => Ok(Sanitize::Portable),
+            "strict" => Ok(Sanitize::Strict),
+            "minimal" => Ok(Sanitize::Minimal),
+            _ => Err(unknown_setting("name sanitization", s)),
+        }
+    }
+}
+
+impl FromStr for Collisions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Collisions, Error> {
+        match s.to_lowercase().as_str() {
+            "suffix" => Ok(Collisions::Suffix),
+            "overwrite" => Ok(Collisions::Overwrite),
+            "error" => Ok(Collisions::Error),
+            _ => Err(unknown_setting("name collision policy", s)),
+        }
+    }
+}
+
+impl Sanitize {
+    /// Make a name safe to create on the host
+    pub fn apply(&self, name: &str) -> String {
+        match self {
+            Sanitize::Portable => host_file_name(name),
+            Sanitize::Strict => host_file_name(name).to_lowercase().replace(' ', "_"),
+            Sanitize::Minimal => {
+                let name: String = name
+                    .chars()
+                    .map(|c| {
+                        if c.is_control() || c == '/' || c == '\\' {
+                            '_'
+                        } else {
+                            c
+                        }
+                    })
+                    .collect();
+                match name.trim_matches('.') {
+                    "" => String::from("untitled"),
+                    _ => name,
+                }
+            }
+        }
+    }
+}
+
+/// The metadata file written next to an exported file, holding what
+/// the host name can lose
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileMetadata {
+    /// The name of the file on the disk
+    pub name: String,
+    /// The name bytes from the directory, in hex
+    pub raw_name: String,
+    /// The type of the file, in the platform's catalog notation
+    pub file_type: String,
+    /// The size of the file in bytes
+    pub size: usize,
+}
+
+impl FileMetadata {
+    /// Build the metadata for a file
+    pub fn new(file: &DiskFile) -> FileMetadata {
+        FileMetadata {
+            name: file.name.clone(),
+            raw_name: file.raw_name.iter().map(|b| format!("{:02X}", b)).collect(),
+            file_type: file.file_type.clone(),
+            size: file.data.len(),
+        }
+    }
+
+    /// Serialize the metadata as pretty-printed JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// How exported files are named
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NamingPolicy {
+    /// The template for the names, see output_name
+    pub template: String,
+    /// How names are made safe for the host
+    pub sanitize: Sanitize,
+    /// What happens when two files get the same name
+    pub collisions: Collisions,
+    /// Write a metadata file with the original name next to each file,
+    /// named after the file with METADATA_EXTENSION added
+    pub sidecar: bool,
+}
+
+/// The extension added to the name of a file for its metadata file
+pub const METADATA_EXTENSION: &str = "meta.json";
+
+impl Default for NamingPolicy {
+    fn default() -> NamingPolicy {
+        NamingPolicy {
+            template: String::from(DEFAULT_NAME_TEMPLATE),
+            sanitize: Sanitize::default(),
+            collisions: Collisions::default(),
+            sidecar: false,
+        }
+    }
+}
+
+impl NamingPolicy {
+    /// Build a policy from a configuration, using the defaults for any
+    /// setting that's missing or unknown
+    pub fn from_config(config: &Config) -> NamingPolicy {
+        fn get<T: FromStr<Err = Error> + Copy>(config: &Config, key: &str, default: T) -> T {
+            match config.get_string(key) {
+                Ok(value) => value.parse().unwrap_or_else(|e| {
+                    warn!(target: IO, "{}, using the default", e);
+                    default
+                }),
+                Err(_) => default,
+            }
+        }
+
+        let default = NamingPolicy::default();
+        NamingPolicy {
+            template: config
+                .get_string("name-template")
+                .unwrap_or(default.template),
+            sanitize: get(config, "name-sanitize", default.sanitize),
+            collisions: get(config, "name-collisions", default.collisions),
+            sidecar: config.get_bool("name-sidecar").unwrap_or(default.sidecar),
+        }
+    }
+
+    /// Build the name for a file from the template
+    /// {name} is replaced with the file's name and {type} with its
+    /// type, both made safe with the sanitization rules.
+    pub fn name(&self, file: &DiskFile) -> String {
+        self.template
+            .replace("{name}", &self.sanitize.apply(&file.name))
+            .replace("{type}", &self.sanitize.apply(&file.file_type))
+    }
+
+    /// Return a Namer, which names files and resolves collisions
+    /// between them
+    pub fn namer(&self) -> Namer<'_> {
+        Namer {
+            policy: self,
+            used: BTreeSet::new(),
+        }
+    }
+}
+
+/// Names a set of exported files, keeping track of the names used
+/// Names are compared without case, so files don't write over each
+/// other on case-insensitive file systems.
+#[derive(Debug)]
+pub struct Namer<'a> {
+    /// The naming policy
+    policy: &'a NamingPolicy,
+    /// The lowercased names already used
+    used: BTreeSet<String>,
+}
+
+impl Namer<'_> {
+    /// Return the name for the next file
+    pub fn name(&mut self, file: &DiskFile) -> std::result::Result<String, Error> {
+        let name = self.policy.name(file);
+        if self.used.insert(name.to_lowercase()) {
+            return Ok(name);
+        }
+
+        match self.policy.collisions {
+            Collisions::Overwrite => Ok(name),
+            Collisions::Error => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("More than one file is named {}", name),
+            )))),
+            Collisions::Suffix => {
+                let (stem, extension) = match name.find('.') {
+                    Some(dot) if dot > 0 => name.split_at(dot),
+                    _ => (name.as_str(), ""),
+                };
+                let name = (2..)
+                    .map(|n| format!("{}_{}{}", stem, n, extension))
+                    .find(|candidate| !self.used.contains(&candidate.to_lowercase()))
+                    .unwrap_or_default();
+                self.used.insert(name.to_lowercase());
+                Ok(name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        glob_match, output_name, Collisions, DiskFile, FileMetadata, FileSelection, NamingPolicy,
+        Sanitize,
+    };
+
+    /// Test matching names against glob patterns
+    #[test]
+    fn glob_match_works() {
+        assert!(glob_match("GAME*", "GAME"));
+        assert!(glob_match("GAME*", "GAME OVER"));
+        assert!(glob_match("*.PRG", "LOADER.PRG"));
+        assert!(glob_match("A?C", "ABC"));
+        assert!(glob_match("*B*B", "ABBAB"));
+        assert!(!glob_match("GAME*", "MY GAME"));
+        assert!(!glob_match("A?C", "AC"));
+        assert!(!glob_match("game*", "GAME"));
+
+        let selection: FileSelection = "HELLO,GAME*".parse().unwrap();
+        assert!(selection.matches("HELLO"));
+        assert!(selection.matches("GAME 2"));
+        assert!(!selection.matches("HELLO WORLD"));
+        assert!(selection.is_multiple());
+        assert!(!"HELLO".parse::<FileSelection>().unwrap().is_multiple());
+    }
+
+    /// Test naming exported files with a template
+    #[test]
+    fn output_name_works() {
+        let file = DiskFile {
+            name: String::from("MY/GAME"),
+            file_type: String::from("B"),
+            raw_name: Vec::new(),
+            data: Vec::new(),
+        };
+        assert_eq!(output_name("{name}.{type}.bin", &file), "MY_GAME.B.bin");
+    }
+
+    /// Build a file with a name
+    fn file(name: &str) -> DiskFile {
+        DiskFile {
+            name: String::from(name),
+            file_type: String::from("PRG"),
+            raw_name: name.as_bytes().to_vec(),
+            data: vec![1, 2, 3],
+        }
+    }
+
+    /// Test naming files with each sanitization and collision setting
+    #[test]
+    fn naming_policy_works() {
+        let policy = NamingPolicy {
+            template: String::from("{name}.bin"),
+            ..NamingPolicy::default()
+        };
+        let mut namer = policy.namer();
+        assert_eq!(namer.name(&file("GAME/1")).unwrap(), "GAME_1.bin");
+        assert_eq!(namer.name(&file("GAME:1")).unwrap(), "GAME_1_2.bin");
+        assert_eq!(namer.name(&file("game_1")).unwrap(), "game_1_3.bin");
+
+        let policy = NamingPolicy {
+            sanitize: Sanitize::Strict,
+            collisions: Collisions::Error,
+            ..NamingPolicy::default()
+        };
+        let mut namer = policy.namer();
+        assert_eq!(namer.name(&file("MY GAME")).unwrap(), "my_game");
+        assert!(namer.name(&file("my game")).is_err());
+
+        let policy = NamingPolicy {
+            sanitize: Sanitize::Minimal,
+            collisions: Collisions::Overwrite,
+            ..NamingPolicy::default()
+        };
+        let mut namer = policy.namer();
+        assert_eq!(namer.name(&file("A*B/C")).unwrap(), "A*B_C");
+        assert_eq!(namer.name(&file("A*B\\C")).unwrap(), "A*B_C");
+        assert_eq!(namer.name(&file("..")).unwrap(), "untitled");
+
+        let metadata = FileMetadata::new(&file("HI"));
+        assert_eq!(metadata.raw_name, "4849");
+        assert!(metadata.to_json().unwrap().contains("\"size\": 3"));
+        assert!("lowercase".parse::<Sanitize>().is_err());
+    }
+}
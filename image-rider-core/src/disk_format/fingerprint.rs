@@ -0,0 +1,242 @@
+//! Fingerprints of known system areas
+//!
+//! Boot sectors and DOS images are the same on every disk written by a
+//! given version of the operating system.  Comparing the CRC-32 of those
+//! areas against a database of known versions tells a standard DOS 3.3
+//! system disk apart from one with a modified or custom loader.
+//!
+//! The database is TOML, one table per fingerprint:
+//!
+//! ```toml
+//! [[fingerprint]]
+//! name = "Apple DOS 3.3 boot sector"
+//! area = "apple-boot-sector"
+//! crc32 = 0x12345678
+//! ```
+//!
+//! The bundled database is only compiled in with the `fingerprints`
+//! feature.  Entries for it, or for a local database, can be generated
+//! from a known good image with the parser example's fingerprint
+//! command.
+use std::fmt::{Display, Formatter, Result};
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::checksum::crc32;
+use crate::disk_format::logical::LogicalTrack;
+use crate::error::Error;
+
+/// An area of a disk that holds operating system code
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemArea {
+    /// The Apple ][ boot sector, track zero sector zero
+    AppleBootSector,
+    /// The Apple DOS image on tracks zero to two
+    AppleDosImage,
+    /// The Commodore 128 boot sector, track one sector zero
+    CommodoreBootSector,
+    /// The boot code in an Atari ST boot sector, bytes 0x1E to 0x1FD
+    /// The BIOS parameter block, serial number and checksum word differ
+    /// between disks and are left out.
+    AtariStBootSector,
+}
+
+impl SystemArea {
+    /// All the system areas
+    pub const ALL: [SystemArea; 4] = [
+        SystemArea::AppleBootSector,
+        SystemArea::AppleDosImage,
+        SystemArea::CommodoreBootSector,
+        SystemArea::AtariStBootSector,
+    ];
+
+    /// Return the data in this area, or None if the area isn't on the
+    /// disk
+    pub fn data(&self, tracks: &[LogicalTrack]) -> Option<Vec<u8>> {
+        let sector = |track: u8, head: u8, sector: u8| {
+            tracks
+                .iter()
+                .find(|t| t.track == track && t.head == head)
+                .and_then(|t| t.sector(sector))
+                .map(|s| s.data.as_slice())
+        };
+
+        match self {
+            SystemArea::AppleBootSector => sector(0, 0, 0).map(|d| d.to_vec()),
+            SystemArea::AppleDosImage => {
+                let mut data = Vec::new();
+                for track in 0..3 {
+                    for s in 0..16 {
+                        data.extend_from_slice(sector(track, 0, s)?);
+                    }
+                }
+                Some(data)
+            }
+            SystemArea::CommodoreBootSector => sector(1, 0, 0)
+                .filter(|d| d.starts_with(b"CBM"))
+                .map(|d| d.to_vec()),
+            SystemArea::AtariStBootSector => sector(0, 0, 1)
+                .and_then(|d| d.get(0x1E..0x1FE))
+                .map(|d| d.to_vec()),
+        }
+    }
+}
+
+impl Display for SystemArea {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            SystemArea::AppleBootSector => write!(f, "apple-boot-sector"),
+            SystemArea::AppleDosImage => write!(f, "apple-dos-image"),
+            SystemArea::CommodoreBootSector => write!(f, "commodore-boot-sector"),
+            SystemArea::AtariStBootSector => write!(f, "atari-st-boot-sector"),
+        }
+    }
+}
+
+/// A fingerprint of a known system area
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Fingerprint {
+    /// A description of the system, e.g. the DOS version and release date
+    pub name: String,
+    /// The area that was fingerprinted
+    pub area: SystemArea,
+    /// The CRC-32 of the area
+    pub crc32: u32,
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{} ({}, crc32: 0x{:08X})",
+            self.name, self.area, self.crc32
+        )
+    }
+}
+
+/// A database of fingerprints
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FingerprintDatabase {
+    /// The fingerprints
+    #[serde(default, rename = "fingerprint")]
+    pub fingerprints: Vec<Fingerprint>,
+}
+
+impl FingerprintDatabase {
+    /// Parse a database from TOML
+    pub fn from_toml(toml: &str) -> std::result::Result<FingerprintDatabase, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Serialize the database as TOML
+    pub fn to_toml(&self) -> std::result::Result<String, Error> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Load a database from a TOML file
+    pub fn load(path: &Path) -> std::result::Result<FingerprintDatabase, Error> {
+        FingerprintDatabase::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// The bundled database
+    /// Empty unless the fingerprints feature is enabled.
+    pub fn builtin() -> FingerprintDatabase {
+        #[cfg(feature = "fingerprints")]
+        {
+            FingerprintDatabase::from_toml(include_str!("../../data/fingerprints.toml"))
+                .expect("bundled fingerprint database should parse")
+        }
+        #[cfg(not(feature = "fingerprints"))]
+        {
+            FingerprintDatabase::default()
+        }
+    }
+
+    /// Add the fingerprints from another database
+    pub fn extend(&mut self, other: FingerprintDatabase) {
+        self.fingerprints.extend(other.fingerprints);
+    }
+
+    /// Return the fingerprints that exactly match areas on the disk
+    pub fn identify(&self, tracks: &[LogicalTrack]) -> Vec<&Fingerprint> {
+        let checksums: Vec<(SystemArea, u32)> = fingerprint_areas(tracks)
+            .into_iter()
+            .map(|(area, data)| (area, crc32(&data)))
+            .collect();
+
+        self.fingerprints
+            .iter()
+            .filter(|fingerprint| checksums.contains(&(fingerprint.area, fingerprint.crc32)))
+            .collect()
+    }
+}
+
+/// Return the system areas present on a disk and their data
+pub fn fingerprint_areas(tracks: &[LogicalTrack]) -> Vec<(SystemArea, Vec<u8>)> {
+    SystemArea::ALL
+        .iter()
+        .filter_map(|area| area.data(tracks).map(|data| (*area, data)))
+        .collect()
+}
+
+/// Build fingerprints for every system area on a known good disk, for
+/// adding to a database
+pub fn fingerprint_disk(tracks: &[LogicalTrack], name: &str) -> FingerprintDatabase {
+    FingerprintDatabase {
+        fingerprints: fingerprint_areas(tracks)
+            .into_iter()
+            .map(|(area, data)| Fingerprint {
+                name: format!("{} {}", name, area),
+                area,
+                crc32: crc32(&data),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint_disk, FingerprintDatabase, SystemArea};
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test that a fingerprinted disk is identified and a modified boot
+    /// sector isn't
+    #[test]
+    fn identify_works() {
+        let geometry = Geometry::apple_dos_33(35);
+        let mut data: Vec<u8> = (0..geometry.total_size()).map(|i| i as u8).collect();
+        let tracks = split_tracks(&data, &geometry);
+
+        let database = fingerprint_disk(&tracks, "Test DOS");
+        assert_eq!(database.fingerprints.len(), 2);
+        assert_eq!(database.fingerprints[0].area, SystemArea::AppleBootSector);
+        assert_eq!(database.fingerprints[1].name, "Test DOS apple-dos-image");
+
+        // Round trip through TOML
+        let database = FingerprintDatabase::from_toml(&database.to_toml().unwrap()).unwrap();
+        assert_eq!(database.identify(&tracks).len(), 2);
+
+        // A modified loader only matches the DOS image... and not even
+        // that, since the boot sector is part of it
+        data[0x10] = 0xEA;
+        let tracks = split_tracks(&data, &geometry);
+        assert!(database.identify(&tracks).is_empty());
+
+        let database = FingerprintDatabase::from_toml(
+            "[[fingerprint]]\nname = \"Example\"\narea = \"apple-boot-sector\"\ncrc32 = 0x12345678\n",
+        )
+        .unwrap();
+        assert_eq!(database.fingerprints[0].crc32, 0x1234_5678);
+    }
+
+    /// Test that the bundled database parses
+    #[test]
+    fn builtin_works() {
+        let _ = FingerprintDatabase::builtin();
+        FingerprintDatabase::from_toml(include_str!("../../data/fingerprints.toml")).unwrap();
+    }
+}
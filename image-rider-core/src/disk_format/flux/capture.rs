@@ -0,0 +1,347 @@
+//! Greaseweazle and FluxEngine capture ingestion
+//!
+//! Reading a disk with `gw read dump.raw` produces a directory of
+//! KryoFlux stream files named dump00.0.raw, dump00.1.raw,
+//! dump01.0.raw and so on, usually with several revolutions of each
+//! track.  This module decodes a whole capture into logical tracks and
+//! a flat sector image.
+//!
+//! Decoded MFM bit cell dumps (.mfm files) are also accepted.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+
+use crate::disk_format::commodore::g64::{decode_gcr_track, speed_zone_for_track};
+use crate::disk_format::flux::kryoflux::kryoflux_stream_parser;
+use crate::disk_format::flux::{
+    flux_to_bits, FluxEncoding, FluxTrack, DD_MFM_BITCELL_NS, GCR_1541_BITCELL_NS,
+    HD_MFM_BITCELL_NS,
+};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::logical::{flatten_tracks, infer_geometry, LogicalTrack};
+use crate::disk_format::mfm::{decode_track, unpack_bits};
+#[cfg(feature = "parity")]
+use crate::disk_format::parity::{Parity, RepairReport};
+use crate::error::{Error, ErrorKind};
+use crate::log_target::IO;
+
+/// Decode every revolution of a track as MFM, merging the sectors
+/// Double density is tried first, then high density if no sectors
+/// were found.
+pub fn decode_flux_track(flux_track: &FluxTrack) -> LogicalTrack {
+    let flux = flux_track.flux();
+    let mut logical_track = LogicalTrack::new(flux_track.track, flux_track.head);
+
+    for bitcell_ns in [DD_MFM_BITCELL_NS, HD_MFM_BITCELL_NS] {
+        let bits = flux_to_bits(&flux, flux_track.ticks_per_cell(bitcell_ns));
+        logical_track = decode_track(&bits, flux_track.track, flux_track.head);
+        if !logical_track.sectors.is_empty() {
+            break;
+        }
+    }
+    logical_track.merge_duplicates();
+
+    logical_track
+}
+
+/// Decode each revolution of a track as Commodore 1541 GCR, merging
+/// the sectors
+/// Commodore tracks are numbered from one, so physical track 0 is
+/// decoded as track 1, with the bit cell of its speed zone.
+pub fn decode_gcr_flux_track(flux_track: &FluxTrack) -> LogicalTrack {
+    let number = flux_track.track + 1;
+    let bitcell_ns = GCR_1541_BITCELL_NS[usize::from(speed_zone_for_track(number))];
+    let ticks_per_cell = flux_track.ticks_per_cell(bitcell_ns);
+
+    let mut logical_track = LogicalTrack::new(number, 0);
+    for revolution in &flux_track.revolutions {
+        let bits = flux_to_bits(revolution, ticks_per_cell);
+        logical_track
+            .sectors
+            .extend(decode_gcr_track(&bits, number).sectors);
+    }
+    logical_track.merge_duplicates();
+    logical_track.sectors.sort_by_key(|sector| sector.id.sector);
+
+    logical_track
+}
+
+/// A decoded capture
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    /// The decoded tracks, sorted by track and head
+    pub tracks: Vec<LogicalTrack>,
+}
+
+impl Capture {
+    /// Decode the sectors on flux tracks
+    pub fn decode(flux_tracks: &[FluxTrack], encoding: FluxEncoding) -> Capture {
+        let mut tracks: Vec<LogicalTrack> = flux_tracks
+            .iter()
+            .map(|flux_track| match encoding {
+                FluxEncoding::Mfm => decode_flux_track(flux_track),
+                FluxEncoding::CommodoreGcr => decode_gcr_flux_track(flux_track),
+            })
+            .collect();
+        tracks.sort_by_key(|t| (t.track, t.head));
+        Capture { tracks }
+    }
+
+    /// Guess the geometry of the captured disk
+    pub fn geometry(&self) -> Option<Geometry> {
+        infer_geometry(&self.tracks)
+    }
+
+    /// Build a flat sector image of the capture using the guessed
+    /// geometry
+    pub fn image(&self) -> Option<Vec<u8>> {
+        Some(flatten_tracks(&self.tracks, &self.geometry()?))
+    }
+
+    /// Return the sectors that had CRC errors on every read
+    pub fn bad_sectors(&self) -> Vec<SectorId> {
+        self.tracks
+            .iter()
+            .flat_map(|t| t.sectors.iter())
+            .filter(|s| s.crc_error)
+            .map(|s| s.id)
+            .collect()
+    }
+
+    /// Rebuild bad and missing sectors from a parity sidecar made from
+    /// an earlier read of the disk
+    #[cfg(feature = "parity")]
+    pub fn repair(&mut self, parity: &Parity) -> std::result::Result<RepairReport, Error> {
+        parity.repair(&mut self.tracks)
+    }
+
+    /// Return the sectors in the guessed geometry that weren't found
+    pub fn missing_sectors(&self) -> Vec<SectorId> {
+        let geometry = match self.geometry() {
+            Some(g) => g,
+            None => return Vec::new(),
+        };
+        geometry
+            .sector_ids()
+            .into_iter()
+            .filter(|id| {
+                !self.tracks.iter().any(|t| {
+                    t.track == id.track.get()
+                        && t.head == id.head.get()
+                        && t.sector(id.sector).is_some()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Display a summary of a Capture
+impl Display for Capture {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        for track in &self.tracks {
+            writeln!(
+                f,
+                "{}, bad: {}",
+                track,
+                track.sectors.iter().filter(|s| s.crc_error).count()
+            )?;
+        }
+        match self.geometry() {
+            Some(g) => write!(
+                f,
+                "tracks: {}, heads: {}, sectors: {}, sector size: {}, missing sectors: {}",
+                g.tracks(),
+                g.heads,
+                g.sectors_per_track.first().unwrap_or(&0),
+                g.sector_size,
+                self.missing_sectors().len()
+            ),
+            None => write!(f, "No sectors found"),
+        }
+    }
+}
+
+/// Split a capture filename like dump03.1.raw into its prefix, track
+/// and head
+pub fn parse_capture_filename(name: &str) -> Option<(String, u8, u8, &str)> {
+    let (rest, extension) = name.rsplit_once('.')?;
+    let (rest, head) = rest.rsplit_once('.')?;
+    if rest.len() < 2 || !rest.is_char_boundary(rest.len() - 2) {
+        return None;
+    }
+    let (prefix, track) = rest.split_at(rest.len() - 2);
+
+    Some((
+        prefix.to_string(),
+        track.parse().ok()?,
+        head.parse().ok()?,
+        extension,
+    ))
+}
+
+/// Decode a single capture file
+fn decode_capture_file(
+    path: &Path,
+    track: u8,
+    head: u8,
+    extension: &str,
+) -> std::result::Result<LogicalTrack, Error> {
+    let data = fs::read(path)?;
+    if extension == "mfm" {
+        let mut logical_track = decode_track(&unpack_bits(&data), track, head);
+        logical_track.merge_duplicates();
+        Ok(logical_track)
+    } else {
+        let (_, stream) = kryoflux_stream_parser(&data)?;
+        Ok(decode_flux_track(&stream.flux_track(track, head)))
+    }
+}
+
+/// A file in a capture directory: its path, track, head and extension
+pub type CaptureFile = (PathBuf, u8, u8, String);
+
+/// List the capture files in a directory with one of the extensions
+/// If the directory holds captures with more than one filename prefix,
+/// the files with the most common prefix are returned.
+pub fn capture_files(
+    dir: &Path,
+    extensions: &[&str],
+) -> std::result::Result<Vec<CaptureFile>, Error> {
+    let mut captures: BTreeMap<String, Vec<CaptureFile>> = BTreeMap::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let capture_file = parse_capture_filename(&name)
+            .filter(|(_, _, _, extension)| extensions.contains(extension));
+        if let Some((prefix, track, head, extension)) = capture_file {
+            captures.entry(prefix).or_default().push((
+                entry.path(),
+                track,
+                head,
+                extension.to_string(),
+            ));
+        }
+    }
+
+    if captures.len() > 1 {
+        warn!(
+            target: IO,
+            "Found more than one capture in {}: {:?}",
+            dir.display(),
+            captures.keys().collect::<Vec<&String>>()
+        );
+    }
+    captures
+        .into_values()
+        .max_by_key(|files| files.len())
+        .ok_or_else(|| {
+            Error::new(ErrorKind::NotFound(format!(
+                "No capture files in {}",
+                dir.display()
+            )))
+        })
+}
+
+/// Ingest a capture directory
+/// If the directory holds captures with more than one filename prefix,
+/// the prefix with the most files is used.
+pub fn ingest_capture(dir: &Path) -> std::result::Result<Capture, Error> {
+    let files = capture_files(dir, &["raw", "mfm"])?;
+
+    let mut capture = Capture::default();
+    for (path, track, head, extension) in files {
+        debug!(target: IO, "Decoding capture file {}", path.display());
+        capture
+            .tracks
+            .push(decode_capture_file(&path, track, head, &extension)?);
+    }
+    capture.tracks.sort_by_key(|t| (t.track, t.head));
+    info!(
+        target: IO,
+        "Decoded {} tracks from {}",
+        capture.tracks.len(),
+        dir.display()
+    );
+
+    Ok(capture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_flux_track, ingest_capture, parse_capture_filename};
+    use crate::disk_format::flux::bits_to_flux;
+    use crate::disk_format::flux::kryoflux::KryofluxStream;
+    use crate::disk_format::flux::DD_MFM_BITCELL_NS;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::mfm::encode_track;
+
+    /// Test splitting capture filenames
+    #[test]
+    fn parse_capture_filename_works() {
+        assert_eq!(
+            parse_capture_filename("dump03.1.raw"),
+            Some((String::from("dump"), 3, 1, "raw"))
+        );
+        assert_eq!(
+            parse_capture_filename("track79.0.mfm"),
+            Some((String::from("track"), 79, 0, "mfm"))
+        );
+        assert_eq!(parse_capture_filename("readme.txt"), None);
+    }
+
+    /// Test that a sector with a bad read on the first revolution is
+    /// recovered from the second
+    #[test]
+    fn decode_flux_track_revolutions_works() {
+        let geometry = Geometry::atari_st(1, 1, 9);
+        let data: Vec<u8> = (0..geometry.total_size()).map(|i| i as u8).collect();
+        let track = split_tracks(&data, &geometry).remove(0);
+
+        let mut bad_track = track.clone();
+        bad_track.sectors[3].crc_error = true;
+        let mut bits = encode_track(&bad_track);
+        bits.extend(encode_track(&track));
+
+        let mut stream = KryofluxStream::new(Vec::new());
+        stream.flux = bits_to_flux(&bits, stream.ticks_per_cell(DD_MFM_BITCELL_NS));
+
+        assert_eq!(decode_flux_track(&stream.flux_track(0, 0)), track);
+    }
+
+    /// Test ingesting a Greaseweazle style capture directory
+    #[test]
+    fn ingest_capture_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let dir = std::env::temp_dir().join(format!("image-rider-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for track in split_tracks(&data, &geometry) {
+            let mut stream = KryofluxStream::new(Vec::new());
+            stream.flux = bits_to_flux(
+                &encode_track(&track),
+                stream.ticks_per_cell(DD_MFM_BITCELL_NS),
+            );
+            std::fs::write(
+                dir.join(format!("dump{:02}.{}.raw", track.track, track.head)),
+                stream.to_bytes(),
+            )
+            .unwrap();
+        }
+
+        let capture = ingest_capture(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(capture.geometry(), Some(geometry));
+        assert_eq!(capture.image(), Some(data));
+        assert!(capture.bad_sectors().is_empty());
+        assert!(capture.missing_sectors().is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+//! KryoFlux stream files
+//!
+//! A KryoFlux stream file holds the raw flux intervals for one track
+//! on one side of a disk.  Files are named trackNN.S.raw, where NN is
+//! the two digit track number and S is the side.  Greaseweazle and
+//! FluxEngine both read and write this format.
+//!
+//! The stream is a sequence of blocks identified by their first byte:
+//!
+//! ```ignore
+//! 0x00-0x07 Flux2: two byte flux value, header byte is the high byte
+//! 0x08      Nop1: skip one byte
+//! 0x09      Nop2: skip two bytes
+//! 0x0A      Nop3: skip three bytes
+//! 0x0B      Ovl16: add 0x10000 to the next flux value
+//! 0x0C      Flux3: three byte flux value, big endian 16 bit value follows
+//! 0x0D      OOB: out of band block, type byte, 16 bit little endian size, data
+//! 0x0E-0xFF Flux1: one byte flux value
+//! ```
+//!
+//! The index blocks mark where the drive's index pulse fell in the
+//! stream.  A stream is split into revolutions at them, and the stream
+//! files of a whole disk are read into a [FluxTrack] for each track,
+//! the same as the tracks of an SCP image.
+//!
+//! Information from:\
+//! [KryoFlux stream protocol](https://www.kryoflux.com/download/kryoflux_stream_protocol_rev1.1.pdf)\
+//! [Greaseweazle](https://github.com/keirf/greaseweazle.git) image/kryoflux.py
+use std::fs;
+use std::path::Path;
+
+use crate::disk_format::flux::capture::capture_files;
+use crate::disk_format::flux::FluxTrack;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::error::Error;
+use crate::log_target::{CONVERT, IO};
+use log::{debug, info};
+
+use nom::bytes::complete::take;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+/// A KryoFlux stream file, one per track and side
+pub const KRYOFLUX_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "KryoFlux",
+    description: "KryoFlux stream files, one for each track and side",
+    extensions: &["raw"],
+    sizes: &[SizeRange::at_least(1)],
+    references: &[Reference {
+        title: "KryoFlux stream protocol",
+        url: Some("https://www.kryoflux.com/download/kryoflux_stream_protocol_rev1.1.pdf"),
+    }],
+    capabilities: Capabilities {
+        tracks: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The default KryoFlux sample clock in Hz
+pub const SAMPLE_CLOCK: f64 = 24027428.571_428_5;
+
+/// The default KryoFlux index clock in Hz
+pub const INDEX_CLOCK: f64 = SAMPLE_CLOCK / 8.0;
+
+/// Out of band block types
+const OOB_STREAM_INFO: u8 = 0x01;
+const OOB_INDEX: u8 = 0x02;
+const OOB_STREAM_END: u8 = 0x03;
+const OOB_KF_INFO: u8 = 0x04;
+const OOB_EOF: u8 = 0x0D;
+
+/// A decoded KryoFlux stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct KryofluxStream {
+    /// The sample clock in Hz
+    pub sample_clock: f64,
+    /// The flux intervals in sample clock ticks
+    pub flux: Vec<u32>,
+    /// The index of the flux interval following each index pulse
+    pub index: Vec<usize>,
+}
+
+impl KryofluxStream {
+    /// Create a stream with the default sample clock
+    pub fn new(flux: Vec<u32>) -> KryofluxStream {
+        KryofluxStream {
+            sample_clock: SAMPLE_CLOCK,
+            flux,
+            index: Vec::new(),
+        }
+    }
+
+    /// The number of sample clock ticks in one bit cell of the given
+    /// length in nanoseconds
+    pub fn ticks_per_cell(&self, bitcell_ns: f64) -> f64 {
+        self.sample_clock * bitcell_ns / 1_000_000_000.0
+    }
+
+    /// Split the stream into revolutions at the index pulses
+    /// Flux before the first index pulse and after the last is a
+    /// partial revolution and is dropped.  A stream with fewer than two
+    /// index pulses is a single revolution.
+    pub fn revolutions(&self) -> Vec<Vec<u32>> {
+        if self.index.len() < 2 {
+            return vec![self.flux.clone()];
+        }
+        self.index
+            .windows(2)
+            .filter_map(|index| self.flux.get(index[0]..index[1]))
+            .filter(|revolution| !revolution.is_empty())
+            .map(|revolution| revolution.to_vec())
+            .collect()
+    }
+
+    /// Return the flux of a track read from this stream
+    pub fn flux_track(&self, track: u8, head: u8) -> FluxTrack {
+        FluxTrack {
+            track,
+            head,
+            sample_clock: self.sample_clock,
+            revolutions: self.revolutions(),
+        }
+    }
+
+    /// Encode the stream
+    /// An index pulse is written before each flux interval in the
+    /// index, or at the start and end of the flux data if there isn't
+    /// one, so the track is read as a single revolution.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        let index = if self.index.is_empty() {
+            vec![0, self.flux.len()]
+        } else {
+            self.index.clone()
+        };
+
+        let info = format!(
+            "sck={}, ick={}\0",
+            self.sample_clock,
+            self.sample_clock / 8.0
+        );
+        write_oob(&mut bytes, OOB_KF_INFO, info.as_bytes());
+
+        let mut stream_position: u32 = 0;
+        let mut total_ticks: u64 = 0;
+        for (i, value) in self.flux.iter().enumerate() {
+            if index.contains(&i) {
+                let index_counter = (total_ticks as f64 / 8.0) as u32;
+                write_oob(
+                    &mut bytes,
+                    OOB_INDEX,
+                    &index_block(stream_position, index_counter),
+                );
+            }
+            total_ticks += u64::from(*value);
+            let mut value = *value;
+            let start = bytes.len();
+            while value > 0xFFFF {
+                bytes.push(0x0B);
+                value -= 0x10000;
+            }
+            if (0x0E..=0xFF).contains(&value) {
+                bytes.push(value as u8);
+            } else if value < 0x800 {
+                bytes.push((value >> 8) as u8);
+                bytes.push((value & 0xFF) as u8);
+            } else {
+                bytes.push(0x0C);
+                bytes.push((value >> 8) as u8);
+                bytes.push((value & 0xFF) as u8);
+            }
+            stream_position += (bytes.len() - start) as u32;
+        }
+
+        if index.contains(&self.flux.len()) {
+            let index_counter = (total_ticks as f64 / 8.0) as u32;
+            write_oob(
+                &mut bytes,
+                OOB_INDEX,
+                &index_block(stream_position, index_counter),
+            );
+        }
+        let mut end = stream_position.to_le_bytes().to_vec();
+        end.extend_from_slice(&0_u32.to_le_bytes());
+        write_oob(&mut bytes, OOB_STREAM_END, &end);
+
+        bytes.extend_from_slice(&[0x0D, OOB_EOF, 0x0D, 0x0D]);
+
+        bytes
+    }
+}
+
+/// Build the data for an index block
+fn index_block(stream_position: u32, index_counter: u32) -> Vec<u8> {
+    let mut data = stream_position.to_le_bytes().to_vec();
+    data.extend_from_slice(&0_u32.to_le_bytes());
+    data.extend_from_slice(&index_counter.to_le_bytes());
+    data
+}
+
+/// Write an out of band block
+fn write_oob(bytes: &mut Vec<u8>, block_type: u8, data: &[u8]) {
+    bytes.push(0x0D);
+    bytes.push(block_type);
+    bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(data);
+}
+
+/// Parse the sample clock out of a KFInfo string, e.g.
+/// "sck=24027428.5714285, ick=3003428.5714285625"
+fn parse_sample_clock(info: &[u8]) -> Option<f64> {
+    let info = String::from_utf8_lossy(info);
+    info.split(',')
+        .filter_map(|field| field.trim().trim_end_matches('\0').split_once('='))
+        .find(|(key, _)| *key == "sck")
+        .and_then(|(_, value)| value.parse::<f64>().ok())
+}
+
+/// Parse a KryoFlux stream file
+pub fn kryoflux_stream_parser(i: &[u8]) -> IResult<&[u8], KryofluxStream> {
+    let mut stream = KryofluxStream::new(Vec::new());
+    // The stream position where each flux value starts, not counting
+    // out of band blocks
+    let mut flux_positions: Vec<usize> = Vec::new();
+    let mut index_positions: Vec<usize> = Vec::new();
+    let mut stream_position: usize = 0;
+    let mut overflow: u32 = 0;
+    let mut i = i;
+
+    while !i.is_empty() {
+        let (rest, header) = le_u8(i)?;
+        let start = stream_position;
+        match header {
+            0x00..=0x07 => {
+                let (rest, low) = le_u8(rest)?;
+                flux_positions.push(start);
+                stream
+                    .flux
+                    .push(overflow + (u32::from(header) << 8) + u32::from(low));
+                overflow = 0;
+                stream_position += 2;
+                i = rest;
+            }
+            0x08..=0x0A => {
+                let skip = usize::from(header - 0x07);
+                let (rest, _) = take(skip - 1)(rest)?;
+                stream_position += skip;
+                i = rest;
+            }
+            0x0B => {
+                overflow += 0x10000;
+                stream_position += 1;
+                i = rest;
+            }
+            0x0C => {
+                let (rest, value) = take(2_usize)(rest)?;
+                flux_positions.push(start);
+                stream
+                    .flux
+                    .push(overflow + (u32::from(value[0]) << 8) + u32::from(value[1]));
+                overflow = 0;
+                stream_position += 3;
+                i = rest;
+            }
+            0x0D => {
+                let (rest, block_type) = le_u8(rest)?;
+                if block_type == OOB_EOF {
+                    break;
+                }
+                let (rest, size) = le_u16(rest)?;
+                let (rest, data) = take(size)(rest)?;
+                match block_type {
+                    OOB_INDEX if data.len() >= 4 => {
+                        index_positions.push(
+                            u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize,
+                        );
+                    }
+                    OOB_KF_INFO => {
+                        if let Some(clock) = parse_sample_clock(data) {
+                            stream.sample_clock = clock;
+                        }
+                    }
+                    OOB_STREAM_INFO | OOB_STREAM_END => {}
+                    _ => debug!(target: CONVERT, "Unknown KryoFlux OOB block type: {}", block_type),
+                }
+                i = rest;
+            }
+            _ => {
+                flux_positions.push(start);
+                stream.flux.push(overflow + u32::from(header));
+                overflow = 0;
+                stream_position += 1;
+                i = rest;
+            }
+        }
+    }
+
+    stream.index = index_positions
+        .iter()
+        .map(|position| flux_positions.partition_point(|p| p < position))
+        .collect();
+
+    Ok((i, stream))
+}
+
+/// Read a directory of stream files, one for each track and side,
+/// named trackNN.S.raw by the KryoFlux software
+/// Files with another prefix, like Greaseweazle's dumpNN.S.raw, are
+/// read too, if there's only one set in the directory.  The tracks are
+/// sorted by track and head.
+pub fn read_stream_set(dir: &Path) -> std::result::Result<Vec<FluxTrack>, Error> {
+    let mut tracks = Vec::new();
+    for (path, track, head, _) in capture_files(dir, &["raw"])? {
+        debug!(target: IO, "Reading stream file {}", path.display());
+        let (_, stream) = kryoflux_stream_parser(&fs::read(&path)?)?;
+        tracks.push(stream.flux_track(track, head));
+    }
+    tracks.sort_by_key(|t| (t.track, t.head));
+    info!(
+        target: IO,
+        "Read {} stream files from {}",
+        tracks.len(),
+        dir.display()
+    );
+
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{kryoflux_stream_parser, read_stream_set, KryofluxStream, SAMPLE_CLOCK};
+
+    /// Test that encoding and parsing a stream round-trips, including
+    /// every flux block size
+    #[test]
+    fn kryoflux_stream_round_trip_works() {
+        let stream = KryofluxStream::new(vec![0x30, 0x05, 0x7FF, 0x800, 0xFFFF, 0x12345, 0x60]);
+        let bytes = stream.to_bytes();
+
+        let (_, parsed) = kryoflux_stream_parser(&bytes).unwrap();
+        assert_eq!(parsed.flux, stream.flux);
+        assert_eq!(parsed.index, vec![0, 7]);
+        assert!((parsed.sample_clock - SAMPLE_CLOCK).abs() < 0.001);
+    }
+
+    /// Test reading a stream set and splitting each stream into
+    /// revolutions at the index pulses
+    #[test]
+    fn read_stream_set_works() {
+        let dir =
+            std::env::temp_dir().join(format!("image-rider-stream-set-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (track, head) in [(1, 0), (0, 1), (0, 0)] {
+            let mut stream = KryofluxStream::new((0..7).map(|i| 0x20 + i + track).collect());
+            // A partial revolution, two whole revolutions and another
+            // partial revolution
+            stream.index = vec![1, 3, 5];
+            std::fs::write(
+                dir.join(format!("track{:02}.{}.raw", track, head)),
+                stream.to_bytes(),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.join("track00.0.log"), b"log").unwrap();
+
+        let tracks = read_stream_set(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let ids: Vec<(u8, u8)> = tracks.iter().map(|t| (t.track, t.head)).collect();
+        assert_eq!(ids, vec![(0, 0), (0, 1), (1, 0)]);
+        assert_eq!(
+            tracks[2].revolutions,
+            vec![vec![0x22, 0x23], vec![0x24, 0x25]]
+        );
+        assert_eq!(tracks[2].flux(), vec![0x22, 0x23, 0x24, 0x25]);
+    }
+
+    /// Test the bit cell length for double density MFM
+    #[test]
+    fn ticks_per_cell_works() {
+        let stream = KryofluxStream::new(Vec::new());
+        let ticks = stream.ticks_per_cell(2000.0);
+        assert!((ticks - 48.054857).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,135 @@
+//! Flux transition data
+//!
+//! Flux imaging hardware (KryoFlux, Greaseweazle, FluxEngine) records
+//! the time between magnetic flux transitions on a track.  A
+//! transition is a one bit cell, so the time between transitions
+//! divided by the bit cell time gives the number of cells.
+//!
+//! This module converts between bit cells and flux intervals.  Flux
+//! streams and images are read into a [FluxTrack] for each track, so
+//! the same decoders work on all of them.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// KryoFlux stream files
+pub mod kryoflux;
+
+/// Greaseweazle and FluxEngine capture ingestion
+pub mod capture;
+
+/// SuperCard Pro flux images
+pub mod scp;
+
+/// The bit cell time in nanoseconds for double density MFM at 250 kbit/s
+pub const DD_MFM_BITCELL_NS: f64 = 2000.0;
+
+/// The bit cell time in nanoseconds for high density MFM at 500 kbit/s
+pub const HD_MFM_BITCELL_NS: f64 = 1000.0;
+
+/// The bit cell times in nanoseconds of the Commodore 1541 speed
+/// zones, from zone 0 on the inner tracks to zone 3 on the outer
+pub const GCR_1541_BITCELL_NS: [f64; 4] = [4000.0, 3750.0, 3500.0, 3250.0];
+
+/// The flux of one track, split into revolutions at the index pulses
+#[derive(Clone, Debug, PartialEq)]
+pub struct FluxTrack {
+    /// The physical track, counting from zero
+    pub track: u8,
+    /// The head
+    pub head: u8,
+    /// The sample clock in Hz
+    pub sample_clock: f64,
+    /// The flux intervals of each revolution, in sample clock ticks
+    pub revolutions: Vec<Vec<u32>>,
+}
+
+impl FluxTrack {
+    /// The number of sample clock ticks in one bit cell of the given
+    /// length in nanoseconds
+    pub fn ticks_per_cell(&self, bitcell_ns: f64) -> f64 {
+        self.sample_clock * bitcell_ns / 1_000_000_000.0
+    }
+
+    /// Return the flux intervals of every revolution, one after another
+    pub fn flux(&self) -> Vec<u32> {
+        self.revolutions.concat()
+    }
+}
+
+/// The way bit cells are grouped into bytes and sectors on a track
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FluxEncoding {
+    /// IBM MFM, at double or high density
+    Mfm,
+    /// Commodore 1541 GCR, with the bit cell of each speed zone
+    CommodoreGcr,
+}
+
+/// Convert bit cells to flux intervals measured in sample clock ticks
+/// Rounding errors are carried forward so the total track time stays
+/// accurate.
+pub fn bits_to_flux(bits: &[bool], ticks_per_cell: f64) -> Vec<u32> {
+    let mut intervals = Vec::new();
+    let mut last_transition: u64 = 0;
+
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit {
+            let transition = ((i + 1) as f64 * ticks_per_cell).round() as u64;
+            intervals.push((transition - last_transition) as u32);
+            last_transition = transition;
+        }
+    }
+
+    intervals
+}
+
+/// Convert flux intervals measured in sample clock ticks to bit cells
+/// The bit cell clock is adjusted a little on each transition to
+/// follow drive speed variations.
+pub fn flux_to_bits(intervals: &[u32], ticks_per_cell: f64) -> Vec<bool> {
+    let mut bits = Vec::new();
+    let mut clock = ticks_per_cell;
+
+    for interval in intervals {
+        let interval = f64::from(*interval);
+        let cells = (interval / clock).round().max(1.0);
+        bits.extend(std::iter::repeat(false).take(cells as usize - 1));
+        bits.push(true);
+
+        // Nudge the clock towards the measured cell time, but don't
+        // let it drift more than ten percent from nominal
+        let measured = interval / cells;
+        clock += (measured - clock) * 0.05;
+        clock = clock.clamp(ticks_per_cell * 0.9, ticks_per_cell * 1.1);
+    }
+
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bits_to_flux, flux_to_bits};
+
+    /// Test that converting bits to flux and back round-trips
+    #[test]
+    fn bits_to_flux_round_trip_works() {
+        let bits = vec![
+            true, false, true, false, false, true, false, false, false, true, true,
+        ];
+        let flux = bits_to_flux(&bits, 50.0);
+        assert_eq!(flux, vec![50, 100, 150, 200, 50]);
+
+        assert_eq!(flux_to_bits(&flux, 50.0), bits);
+    }
+
+    /// Test that slightly fast or slow intervals still decode
+    #[test]
+    fn flux_to_bits_jitter_works() {
+        let flux = vec![52, 95, 155, 190, 47];
+        assert_eq!(
+            flux_to_bits(&flux, 50.0),
+            vec![true, false, true, false, false, true, false, false, false, true, true]
+        );
+    }
+}
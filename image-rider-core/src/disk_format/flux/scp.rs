@@ -0,0 +1,509 @@
+//! SuperCard Pro flux images
+//!
+//! An .scp file holds the flux intervals of several revolutions of
+//! every track on a disk, as read by the SuperCard Pro and written by
+//! Greaseweazle and FluxEngine.  The file starts with a header of
+//! little-endian fields:
+//!
+//! ```ignore
+//! 00: "SCP"
+//! 03: the version, major and minor in the high and low nybbles
+//! 04: the disk type, the manufacturer in the high nybble
+//! 05: the number of revolutions of each track
+//! 06: the first track
+//! 07: the last track
+//! 08: the flags: bit 0 reads start at the index, bit 1 96 TPI, bit 2
+//!     360 RPM, bit 3 normalized flux, bit 4 read/write image, bit 5
+//!     has a footer, bit 6 extended header
+//! 09: the flux value width in bits, 0 for 16
+//! 0A: the heads, 0 for both, 1 for side 0 only, 2 for side 1 only
+//! 0B: the sample resolution, in multiples of 25 ns minus one
+//! 0C: the checksum, the sum of every byte after the header
+//! 10: the offset of each track's data, 0x80 with an extended header
+//! ```
+//!
+//! Each track entry is numbered by its cylinder times two plus its
+//! head.  The track data starts with "TRK", the track number, and the
+//! index time, number of flux values and offset of each revolution.
+//! Flux values are big-endian, a zero value adds 65536 to the next.
+//!
+//! Tracks are decoded by the same MFM decoder as flux captures, or as
+//! Commodore 1541 GCR for C64 disks, and resolved to sector images
+//! with a [Capture].
+//!
+//! Information from:\
+//! [SuperCard Pro image file specification](https://www.cbmstuff.com/downloads/scp/scp_image_specs.txt)\
+//! [Greaseweazle](https://github.com/keirf/greaseweazle.git) image/scp.py
+use std::fmt::{Display, Formatter, Result};
+
+use log::{debug, error};
+use nom::bytes::complete::{tag, take};
+use nom::multi::count;
+use nom::number::complete::{be_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::flux::capture::Capture;
+use crate::disk_format::flux::{FluxEncoding, FluxTrack};
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::limits::{limit_allocation, limit_tracks, with_limits};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::Location;
+use crate::log_target::{CONVERT, PARSE};
+use crate::options::Options;
+
+/// A SuperCard Pro flux image
+pub const SCP_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "SCP",
+    description: "SuperCard Pro flux image",
+    extensions: &["scp"],
+    sizes: &[SizeRange::at_least(SCP_HEADER_SIZE)],
+    references: &[Reference {
+        title: "SuperCard Pro image file specification",
+        url: Some("https://www.cbmstuff.com/downloads/scp/scp_image_specs.txt"),
+    }],
+    capabilities: Capabilities {
+        tracks: true,
+        checksums: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature at the start of an SCP image
+pub const SCP_MAGIC: &[u8] = b"SCP";
+
+/// The signature at the start of each track's data
+const TRACK_MAGIC: &[u8] = b"TRK";
+
+/// The size of the SCP header, before the track offsets
+pub const SCP_HEADER_SIZE: usize = 16;
+
+/// The offset of the track offsets with an extended header
+const EXTENDED_TRACK_LIST_OFFSET: usize = 0x80;
+
+/// The most track entries in an image
+pub const SCP_TRACK_ENTRIES: usize = 168;
+
+/// The time in nanoseconds of a sample at the finest resolution
+const RESOLUTION_NS: f64 = 25.0;
+
+/// The flag set when the image has an extended header
+const FLAG_EXTENDED: u8 = 0x40;
+
+/// The disk type of Commodore 64 disks
+const DISK_TYPE_C64: u8 = 0x00;
+
+/// True if the data starts with the SCP signature
+pub fn is_scp(data: &[u8]) -> bool {
+    data.starts_with(SCP_MAGIC)
+}
+
+/// The SCP header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScpHeader {
+    /// The version, major and minor in the high and low nybbles
+    pub version: u8,
+    /// The disk type, the manufacturer in the high nybble
+    pub disk_type: u8,
+    /// The number of revolutions of each track
+    pub revolutions: u8,
+    /// The first track
+    pub start_track: u8,
+    /// The last track
+    pub end_track: u8,
+    /// The flags
+    pub flags: u8,
+    /// The flux value width in bits, 0 for 16
+    pub cell_width: u8,
+    /// The heads, 0 for both, 1 for side 0 only, 2 for side 1 only
+    pub heads: u8,
+    /// The sample resolution, in multiples of 25 ns minus one
+    pub resolution: u8,
+    /// The sum of every byte after the header
+    pub checksum: u32,
+}
+
+impl ScpHeader {
+    /// The manufacturer of the disk type, e.g. "Commodore"
+    pub fn manufacturer(&self) -> &'static str {
+        match self.disk_type >> 4 {
+            0x0 => "Commodore",
+            0x1 => "Atari",
+            0x2 => "Apple",
+            0x3 => "PC",
+            0x4 => "Tandy",
+            0x5 => "Texas Instruments",
+            0x6 => "Roland",
+            0x7 => "Amstrad",
+            _ => "other",
+        }
+    }
+
+    /// The sample clock of the flux values in Hz
+    pub fn sample_clock(&self) -> f64 {
+        1_000_000_000.0 / (RESOLUTION_NS * (f64::from(self.resolution) + 1.0))
+    }
+
+    /// Return true if the image has an extended header, with the track
+    /// offsets at 0x80
+    pub fn extended(&self) -> bool {
+        self.flags & FLAG_EXTENDED != 0
+    }
+}
+
+impl Display for ScpHeader {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "SCP version {}.{}, {} disk type {:02X}, tracks: {} to {}, revolutions: {}, resolution: {} ns",
+            self.version >> 4,
+            self.version & 0x0F,
+            self.manufacturer(),
+            self.disk_type,
+            self.start_track,
+            self.end_track,
+            self.revolutions,
+            RESOLUTION_NS * (f64::from(self.resolution) + 1.0)
+        )
+    }
+}
+
+impl SanityCheck for ScpHeader {
+    fn check(&self) -> bool {
+        if self.revolutions == 0 {
+            debug!(target: PARSE, "SCP header has no revolutions");
+            return false;
+        }
+        if self.start_track > self.end_track || usize::from(self.end_track) >= SCP_TRACK_ENTRIES {
+            debug!(
+                target: PARSE,
+                "SCP tracks {} to {} aren't valid", self.start_track, self.end_track
+            );
+            return false;
+        }
+        if !matches!(self.cell_width, 0 | 16) {
+            debug!(
+                target: PARSE,
+                "Unsupported SCP flux value width: {}", self.cell_width
+            );
+            return false;
+        }
+        if self.heads > 2 {
+            debug!(target: PARSE, "Unknown SCP heads: {}", self.heads);
+            return false;
+        }
+        true
+    }
+}
+
+/// The flux of one track entry in an SCP image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScpTrack {
+    /// The track entry, the cylinder times two plus the head
+    pub entry: u8,
+    /// The time of each revolution, in 25 ns units
+    pub index_times: Vec<u32>,
+    /// The flux intervals of each revolution, in sample clock ticks
+    pub revolutions: Vec<Vec<u32>>,
+}
+
+impl ScpTrack {
+    /// The cylinder of the track
+    pub fn cylinder(&self) -> u8 {
+        self.entry / 2
+    }
+
+    /// The head of the track
+    pub fn head(&self) -> u8 {
+        self.entry % 2
+    }
+
+    /// Return the flux of the track at a sample clock
+    pub fn flux_track(&self, sample_clock: f64) -> FluxTrack {
+        FluxTrack {
+            track: self.cylinder(),
+            head: self.head(),
+            sample_clock,
+            revolutions: self.revolutions.clone(),
+        }
+    }
+}
+
+/// An SCP image
+pub struct ScpImage<'a> {
+    /// The image data
+    pub data: &'a [u8],
+    /// The header
+    pub header: ScpHeader,
+    /// The tracks in the image, in track entry order
+    pub tracks: Vec<ScpTrack>,
+}
+
+impl ScpImage<'_> {
+    /// Return the flux of every track
+    pub fn flux_tracks(&self) -> Vec<FluxTrack> {
+        let sample_clock = self.header.sample_clock();
+        self.tracks
+            .iter()
+            .map(|track| track.flux_track(sample_clock))
+            .collect()
+    }
+
+    /// Return the encoding of the tracks, from the disk type
+    pub fn encoding(&self) -> FluxEncoding {
+        if self.header.disk_type == DISK_TYPE_C64 {
+            FluxEncoding::CommodoreGcr
+        } else {
+            FluxEncoding::Mfm
+        }
+    }
+
+    /// Decode the sectors on every track
+    /// C64 disks are decoded as GCR, one revolution at a time with the
+    /// bit cell of each track's speed zone, and tracks are numbered
+    /// from one.  Other disks are decoded as double or high density
+    /// MFM.
+    pub fn capture(&self) -> Capture {
+        let capture = Capture::decode(&self.flux_tracks(), self.encoding());
+        debug!(
+            target: CONVERT,
+            "Decoded {} SCP tracks",
+            capture.tracks.len()
+        );
+        capture
+    }
+}
+
+impl Display for ScpImage<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}, track entries: {}", self.header, self.tracks.len())
+    }
+}
+
+/// Parse the SCP header
+pub fn scp_header_parser(i: &[u8]) -> IResult<&[u8], ScpHeader> {
+    let (i, _magic) = tag(SCP_MAGIC)(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, disk_type) = le_u8(i)?;
+    let (i, revolutions) = le_u8(i)?;
+    let (i, start_track) = le_u8(i)?;
+    let (i, end_track) = le_u8(i)?;
+    let (i, flags) = le_u8(i)?;
+    let (i, cell_width) = le_u8(i)?;
+    let (i, heads) = le_u8(i)?;
+    let (i, resolution) = le_u8(i)?;
+    let (i, checksum) = le_u32(i)?;
+
+    Ok((
+        i,
+        ScpHeader {
+            version,
+            disk_type,
+            revolutions,
+            start_track,
+            end_track,
+            flags,
+            cell_width,
+            heads,
+            resolution,
+            checksum,
+        },
+    ))
+}
+
+/// Parse the data of one track entry
+fn scp_track_parser(revolutions: u8) -> impl Fn(&[u8]) -> IResult<&[u8], ScpTrack> {
+    move |data| {
+        let (i, _magic) = tag(TRACK_MAGIC)(data)?;
+        let (i, entry) = le_u8(i)?;
+        let (i, entries) = count(
+            nom::sequence::tuple((le_u32, le_u32, le_u32)),
+            usize::from(revolutions),
+        )(i)?;
+
+        let mut track = ScpTrack {
+            entry,
+            index_times: Vec::new(),
+            revolutions: Vec::new(),
+        };
+        for (index_time, length, offset) in entries {
+            limit_allocation(i, length as usize * 4)?;
+            let (flux, _) = take(offset)(data)?;
+            let (_, flux) = count(be_u16, length as usize)(flux)?;
+            let mut overflow = 0;
+            let mut revolution = Vec::with_capacity(flux.len());
+            for value in flux {
+                if value == 0 {
+                    overflow += 0x10000;
+                } else {
+                    revolution.push(overflow + u32::from(value));
+                    overflow = 0;
+                }
+            }
+            track.index_times.push(index_time);
+            track.revolutions.push(revolution);
+        }
+
+        Ok((i, track))
+    }
+}
+
+/// Parse an SCP image, reading the flux of every track entry
+/// The checksum is checked unless ignore-checksums is set, in which
+/// case a mismatch is recorded as a warning.  Images written by tools
+/// that update them in place have a zero checksum, which isn't
+/// checked.
+pub fn scp_image_parser(config: &Options) -> impl Fn(&[u8]) -> IResult<&[u8], ScpImage<'_>> + '_ {
+    move |data| {
+        with_limits(config.limits(), || {
+            let (i, header) = scp_header_parser(data)?;
+            if !header.check() {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    &data[4..],
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+
+            let sum = data[SCP_HEADER_SIZE..]
+                .iter()
+                .fold(0_u32, |sum, byte| sum.wrapping_add(u32::from(*byte)));
+            if header.checksum != 0 && sum != header.checksum {
+                if config.get_bool("ignore-checksums").unwrap_or(false) {
+                    warning(
+                        Warning::new("ignored SCP checksum mismatch")
+                            .with_location(Location::offset(0x0C).with_format("SCP")),
+                    );
+                } else {
+                    error!(target: PARSE, "SCP checksum doesn't match");
+                    return Err(nom::Err::Error(nom::error::Error::new(
+                        &data[0x0C..],
+                        nom::error::ErrorKind::Verify,
+                    )));
+                }
+            }
+
+            let i = if header.extended() {
+                take(EXTENDED_TRACK_LIST_OFFSET)(data)?.0
+            } else {
+                i
+            };
+            let entries = usize::from(header.end_track) + 1;
+            limit_tracks(i, entries)?;
+            let (i, offsets) = count(le_u32, entries)(i)?;
+
+            let mut tracks = Vec::new();
+            for (entry, offset) in offsets.iter().enumerate() {
+                if *offset == 0 || entry < usize::from(header.start_track) {
+                    continue;
+                }
+                let (track_data, _) = take(*offset)(data)?;
+                let (_, track) = scp_track_parser(header.revolutions)(track_data)?;
+                if usize::from(track.entry) != entry {
+                    debug!(
+                        target: PARSE,
+                        "SCP track entry {} holds track {}", entry, track.entry
+                    );
+                }
+                tracks.push(track);
+            }
+
+            Ok((
+                i,
+                ScpImage {
+                    data,
+                    header,
+                    tracks,
+                },
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_scp, scp_image_parser};
+    use crate::disk_format::commodore::g64::g64_disk_parser;
+    use crate::disk_format::commodore::g64::speed_zone_for_track;
+    use crate::disk_format::flux::{bits_to_flux, DD_MFM_BITCELL_NS, GCR_1541_BITCELL_NS};
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::mfm::{encode_track, unpack_bits};
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test decoding the MFM tracks of an SCP image to a sector image
+    #[test]
+    fn scp_image_parser_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i % 251) as u8)
+            .collect();
+        // The default resolution samples every 25 ns
+        let ticks_per_cell = DD_MFM_BITCELL_NS / 25.0;
+        let tracks: Vec<(u8, Vec<u32>)> = split_tracks(&data, &geometry)
+            .iter()
+            .map(|track| {
+                let flux = bits_to_flux(&encode_track(track), ticks_per_cell);
+                (track.track * 2 + track.head, flux)
+            })
+            .collect();
+        let scp = testgen::scp(0x15, &tracks, 2);
+        assert!(is_scp(&scp));
+
+        let options = Options::default();
+        let (_, image) = scp_image_parser(&options)(&scp).unwrap();
+        assert_eq!(image.header.manufacturer(), "Atari");
+        assert_eq!(image.tracks.len(), 4);
+        assert_eq!(image.tracks[3].cylinder(), 1);
+        assert_eq!(image.tracks[3].head(), 1);
+        assert_eq!(image.tracks[0].revolutions.len(), 2);
+
+        let capture = image.capture();
+        assert_eq!(capture.geometry(), Some(geometry));
+        assert_eq!(capture.image(), Some(data));
+
+        // A damaged checksum
+        let mut damaged = scp.clone();
+        damaged[0x0C] ^= 0xFF;
+        assert!(scp_image_parser(&options)(&damaged).is_err());
+        let ignore = options.with_override("ignore-checksums", true).unwrap();
+        assert!(scp_image_parser(&ignore)(&damaged).is_ok());
+    }
+
+    /// Test decoding the GCR tracks of a C64 SCP image
+    #[test]
+    fn scp_gcr_works() {
+        let d64 = testgen::d64("SCP", &[("FILE", b"data")]).unwrap();
+        let g64 = testgen::g64(&d64, &[]);
+        let (_, disk) = g64_disk_parser(&g64).unwrap();
+        let tracks: Vec<(u8, Vec<u32>)> = disk
+            .tracks
+            .iter()
+            .filter(|track| !track.half && matches!(track.track, 1 | 18 | 35))
+            .map(|track| {
+                let zone = usize::from(speed_zone_for_track(track.track));
+                let flux = bits_to_flux(&unpack_bits(track.data), GCR_1541_BITCELL_NS[zone] / 25.0);
+                ((track.track - 1) * 2, flux)
+            })
+            .collect();
+        let scp = testgen::scp(0x00, &tracks, 1);
+
+        let (_, image) = scp_image_parser(&Options::default())(&scp).unwrap();
+        let capture = image.capture();
+        let geometry = Geometry::commodore_1541(35);
+        for track in &capture.tracks {
+            assert_eq!(
+                track.sectors.len(),
+                usize::from(geometry.sectors_per_track[usize::from(track.track - 1)])
+            );
+            assert!(track.sectors.iter().all(|sector| !sector.crc_error));
+        }
+        assert_eq!(capture.tracks[1].track, 18);
+        assert_eq!(
+            capture.tracks[1].sectors[0].data,
+            geometry
+                .sector(&d64, &capture.tracks[1].sectors[0].id)
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Descriptions of the supported formats
+//!
+//! Each parser module describes the formats it reads with a
+//! [FormatInfo]: the name, the usual file extensions, the sizes images
+//! come in, where the format is documented and what the library can do
+//! with images in the format.  [formats] lists them all, so a front end
+//! can show help text or a table of capabilities without knowing about
+//! each format itself.
+use std::fmt::{Display, Formatter, Result};
+
+use serde::Serialize;
+
+use crate::disk_format::amiga::disk::ADF_FORMAT_INFO;
+use crate::disk_format::apple::disk::APPLE_DSK_FORMAT_INFO;
+use crate::disk_format::apple::nibble::NIB_FORMAT_INFO;
+use crate::disk_format::apple::twoimg::TWOIMG_FORMAT_INFO;
+use crate::disk_format::apple::woz::WOZ_FORMAT_INFO;
+use crate::disk_format::commodore::d64::{D64_FORMAT_INFO, D71_FORMAT_INFO};
+use crate::disk_format::commodore::g64::G64_FORMAT_INFO;
+use crate::disk_format::dsk::disk::DSK_FORMAT_INFO;
+use crate::disk_format::flux::kryoflux::KRYOFLUX_FORMAT_INFO;
+use crate::disk_format::flux::scp::SCP_FORMAT_INFO;
+use crate::disk_format::hfe::HFE_FORMAT_INFO;
+use crate::disk_format::imd::IMD_FORMAT_INFO;
+use crate::disk_format::mac::diskcopy::DC42_FORMAT_INFO;
+use crate::disk_format::stx::disk::STX_FORMAT_INFO;
+use crate::disk_format::stx::st::{MSA_FORMAT_INFO, ST_FORMAT_INFO};
+use crate::disk_format::td0::disk::TD0_FORMAT_INFO;
+use crate::error::Error;
+use crate::rom_format::atari::{A26_FORMAT_INFO, A78_FORMAT_INFO};
+use crate::rom_format::nes::NES_FORMAT_INFO;
+use crate::rom_format::snes::SFC_FORMAT_INFO;
+
+/// The range of sizes images of a format come in, in bytes
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct SizeRange {
+    /// The smallest size
+    pub min: usize,
+    /// The largest size, None if there's no fixed limit
+    pub max: Option<usize>,
+}
+
+impl SizeRange {
+    /// Images of exactly one size
+    pub const fn exact(size: usize) -> SizeRange {
+        SizeRange {
+            min: size,
+            max: Some(size),
+        }
+    }
+
+    /// Images between two sizes, inclusive
+    pub const fn between(min: usize, max: usize) -> SizeRange {
+        SizeRange {
+            min,
+            max: Some(max),
+        }
+    }
+
+    /// Images of at least a size, e.g. the size of the header
+    pub const fn at_least(min: usize) -> SizeRange {
+        SizeRange { min, max: None }
+    }
+
+    /// True if a size is in the range
+    pub fn contains(&self, size: usize) -> bool {
+        size >= self.min && self.max.map_or(true, |max| size <= max)
+    }
+}
+
+impl Display for SizeRange {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", self.min),
+            Some(max) => write!(f, "{}-{}", self.min, max),
+            None => write!(f, "{}+", self.min),
+        }
+    }
+}
+
+/// A document or program that describes a format
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Reference {
+    /// The title of the document or the name of the program
+    pub title: &'static str,
+    /// Where to find it, None for documents that aren't online
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<&'static str>,
+}
+
+/// What the library can do with images of a format
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// Files can be listed and saved from a filesystem on the image
+    pub files: bool,
+    /// The tracks and sectors are decoded, for raw and track exports
+    pub tracks: bool,
+    /// The tracks can be exported as MFM or flux track files
+    pub track_export: bool,
+    /// Checksums or CRCs stored in the image are verified
+    pub checksums: bool,
+    /// The image records whether the disk is write protected
+    pub write_protect: bool,
+    /// Parsed structures are mapped to their offsets in the image
+    pub source_map: bool,
+}
+
+impl Capabilities {
+    /// No capabilities, for building up the capabilities of a format
+    pub const NONE: Capabilities = Capabilities {
+        files: false,
+        tracks: false,
+        track_export: false,
+        checksums: false,
+        write_protect: false,
+        source_map: false,
+    };
+
+    /// The names and values of each capability, in a fixed order
+    pub fn flags(&self) -> [(&'static str, bool); 6] {
+        [
+            ("files", self.files),
+            ("tracks", self.tracks),
+            ("track export", self.track_export),
+            ("checksums", self.checksums),
+            ("write protect", self.write_protect),
+            ("source map", self.source_map),
+        ]
+    }
+}
+
+/// A description of a format
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct FormatInfo {
+    /// The short name of the format, e.g. "D64"
+    pub name: &'static str,
+    /// A one line description
+    pub description: &'static str,
+    /// The usual file extensions, in lower case without the dot
+    pub extensions: &'static [&'static str],
+    /// The sizes images come in
+    pub sizes: &'static [SizeRange],
+    /// Where the format is documented
+    pub references: &'static [Reference],
+    /// What the library can do with images in the format
+    pub capabilities: Capabilities,
+}
+
+impl FormatInfo {
+    /// True if the format uses a file extension, ignoring case and a
+    /// leading dot
+    pub fn has_extension(&self, extension: &str) -> bool {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        self.extensions
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(extension))
+    }
+
+    /// True if images of the format can be this size
+    pub fn has_size(&self, size: usize) -> bool {
+        self.sizes.iter().any(|range| range.contains(size))
+    }
+
+    /// Serialize the description as a single line of JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// The name and description, then the extensions, sizes, capabilities
+/// and references on their own lines
+impl Display for FormatInfo {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "{}: {}", self.name, self.description)?;
+        let extensions: Vec<String> = self.extensions.iter().map(|e| format!(".{}", e)).collect();
+        writeln!(f, "  extensions: {}", extensions.join(" "))?;
+        let sizes: Vec<String> = self.sizes.iter().map(SizeRange::to_string).collect();
+        writeln!(f, "  sizes: {} bytes", sizes.join(", "))?;
+        let capabilities: Vec<&str> = self
+            .capabilities
+            .flags()
+            .iter()
+            .filter(|(_, supported)| *supported)
+            .map(|(name, _)| *name)
+            .collect();
+        if !capabilities.is_empty() {
+            writeln!(f, "  capabilities: {}", capabilities.join(", "))?;
+        }
+        for reference in self.references {
+            match reference.url {
+                Some(url) => writeln!(f, "  see: {} <{}>", reference.title, url)?,
+                None => writeln!(f, "  see: {}", reference.title)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every supported format, disk images first, then flux images and
+/// cartridge ROMs
+static FORMATS: [&FormatInfo; 22] = [
+    &D64_FORMAT_INFO,
+    &D71_FORMAT_INFO,
+    &G64_FORMAT_INFO,
+    &APPLE_DSK_FORMAT_INFO,
+    &NIB_FORMAT_INFO,
+    &WOZ_FORMAT_INFO,
+    &TWOIMG_FORMAT_INFO,
+    &STX_FORMAT_INFO,
+    &ST_FORMAT_INFO,
+    &MSA_FORMAT_INFO,
+    &ADF_FORMAT_INFO,
+    &DSK_FORMAT_INFO,
+    &DC42_FORMAT_INFO,
+    &HFE_FORMAT_INFO,
+    &TD0_FORMAT_INFO,
+    &IMD_FORMAT_INFO,
+    &SCP_FORMAT_INFO,
+    &KRYOFLUX_FORMAT_INFO,
+    &NES_FORMAT_INFO,
+    &A26_FORMAT_INFO,
+    &A78_FORMAT_INFO,
+    &SFC_FORMAT_INFO,
+];
+
+/// Return the descriptions of every supported format
+pub fn formats() -> &'static [&'static FormatInfo] {
+    &FORMATS
+}
+
+/// Find a format by name, ignoring case
+pub fn format_info(name: &str) -> Option<&'static FormatInfo> {
+    formats()
+        .iter()
+        .copied()
+        .find(|info| info.name.eq_ignore_ascii_case(name))
+}
+
+/// Return the formats that use a file extension
+/// Some extensions are shared, .dsk is used by Apple and CPCEMU
+/// images.
+pub fn formats_for_extension(extension: &str) -> Vec<&'static FormatInfo> {
+    formats()
+        .iter()
+        .copied()
+        .filter(|info| info.has_extension(extension))
+        .collect()
+}
+
+/// A table of the capabilities of every format, one format per line
+pub fn capability_matrix() -> String {
+    let flags = Capabilities::NONE.flags();
+    let width = formats()
+        .iter()
+        .map(|info| info.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut lines = vec![format!(
+        "{:width$}  {}",
+        "format",
+        flags
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join("  "),
+        width = width
+    )];
+    for info in formats() {
+        let cells: Vec<String> = info
+            .capabilities
+            .flags()
+            .iter()
+            .map(|(name, supported)| {
+                format!(
+                    "{:^width$}",
+                    if *supported { "x" } else { "-" },
+                    width = name.len()
+                )
+            })
+            .collect();
+        let line = format!("{:width$}  {}", info.name, cells.join("  "), width = width);
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{capability_matrix, format_info, formats, formats_for_extension, SizeRange};
+    use std::collections::BTreeSet;
+
+    /// Test looking up formats and their capabilities
+    #[test]
+    fn format_info_works() {
+        let names: BTreeSet<&str> = formats().iter().map(|info| info.name).collect();
+        assert_eq!(names.len(), formats().len());
+
+        let d64 = format_info("d64").unwrap();
+        assert!(d64.has_extension(".D64"));
+        assert!(d64.has_size(174848));
+        assert!(!d64.has_size(174849));
+        assert!(d64.capabilities.files);
+
+        let dsk: Vec<&str> = formats_for_extension("dsk")
+            .iter()
+            .map(|info| info.name)
+            .collect();
+        assert_eq!(dsk, ["Apple DSK", "DSK"]);
+        assert!(format_info("WOZ").unwrap().capabilities.write_protect);
+        assert!(format_info("TD0").unwrap().capabilities.checksums);
+        assert!(format_info("XYZ").is_none());
+
+        assert_eq!(SizeRange::between(10, 20).to_string(), "10-20");
+        assert_eq!(SizeRange::at_least(10).to_string(), "10+");
+        assert!(SizeRange::at_least(10).contains(usize::MAX));
+
+        let matrix = capability_matrix();
+        assert_eq!(matrix.lines().count(), formats().len() + 1);
+        assert!(matrix.lines().any(|line| line.starts_with("HFE ")));
+
+        let json = format_info("HFE").unwrap().to_json().unwrap();
+        assert!(json.contains("\"extensions\":[\"hfe\"]"));
+        assert!(json.contains("\"write_protect\":true"));
+    }
+}
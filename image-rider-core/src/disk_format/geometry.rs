@@ -0,0 +1,563 @@
+//! Track and sector geometry for flat sector-dump images
+//!
+//! Many image formats (Apple DOS order .dsk, Commodore .d64, Atari ST
+//! .st) are simple dumps of every sector on the disk, one after the
+//! other.  A Geometry describes how track, head and sector numbers map
+//! onto byte offsets in such a dump.
+use std::fmt::{Display, Formatter, Result};
+
+use config::Config;
+
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// A track (cylinder) number, as it's recorded on the disk
+/// Commodore disks number their tracks from one and most other disks
+/// from zero, so a track number is only turned into a position on the
+/// disk with the number of the first track.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Track(pub u8);
+
+/// A head (side) number, counting from zero
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Head(pub u8);
+
+/// A sector number, as it's recorded in the sector's address field
+/// IBM-style disks number their sectors from one and Apple and
+/// Commodore disks from zero, so a sector number is only turned into
+/// a position on the track with the number of the first sector.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Sector(pub u8);
+
+/// A block number on a block device, counting from zero
+/// ProDOS and AmigaDOS address the disk in blocks that are mapped to
+/// tracks and sectors by the disk's layout.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Block(pub u32);
+
+/// Conversions between the raw numbers in images and the number types
+macro_rules! number_conversions {
+    ($name:ident, $raw:ty) => {
+        impl $name {
+            /// The raw number
+            pub const fn get(self) -> $raw {
+                self.0
+            }
+        }
+
+        impl From<$raw> for $name {
+            fn from(n: $raw) -> $name {
+                $name(n)
+            }
+        }
+
+        impl From<$name> for $raw {
+            fn from(n: $name) -> $raw {
+                n.0
+            }
+        }
+
+        /// Display the raw number
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter) -> Result {
+                write!(f, "{}", self.0)
+            }
+        }
+    };
+}
+
+number_conversions!(Track, u8);
+number_conversions!(Head, u8);
+number_conversions!(Sector, u8);
+number_conversions!(Block, u32);
+
+/// ProDOS block numbers are 16 bits
+impl From<u16> for Block {
+    fn from(n: u16) -> Block {
+        Block(u32::from(n))
+    }
+}
+
+impl Track {
+    /// The position of the track counting from a first track, None if
+    /// the track is before the first track
+    pub fn index_from(self, first: Track) -> Option<usize> {
+        self.0.checked_sub(first.0).map(usize::from)
+    }
+
+    /// The track at a position counting from a first track, None if
+    /// the track number doesn't fit
+    pub fn from_index(index: usize, first: Track) -> Option<Track> {
+        u8::try_from(index)
+            .ok()
+            .and_then(|index| first.0.checked_add(index))
+            .map(Track)
+    }
+}
+
+impl Sector {
+    /// The position of the sector on its track counting from a first
+    /// sector, None if the sector is before the first sector
+    pub fn index_from(self, first: Sector) -> Option<usize> {
+        self.0.checked_sub(first.0).map(usize::from)
+    }
+
+    /// The sector at a position on a track counting from a first
+    /// sector, None if the sector number doesn't fit
+    pub fn from_index(index: usize, first: Sector) -> Option<Sector> {
+        u8::try_from(index)
+            .ok()
+            .and_then(|index| first.0.checked_add(index))
+            .map(Sector)
+    }
+}
+
+impl Block {
+    /// The position of the block on the device
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// The location of a sector on a disk
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SectorId {
+    /// The track (cylinder) number
+    pub track: Track,
+    /// The head (side) number
+    pub head: Head,
+    /// The sector number
+    pub sector: Sector,
+}
+
+impl SectorId {
+    /// Create a new SectorId from track, head and sector numbers
+    pub fn new(
+        track: impl Into<Track>,
+        head: impl Into<Head>,
+        sector: impl Into<Sector>,
+    ) -> SectorId {
+        SectorId {
+            track: track.into(),
+            head: head.into(),
+            sector: sector.into(),
+        }
+    }
+}
+
+/// Display a SectorId
+impl Display for SectorId {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "track: {}, head: {}, sector: {}",
+            self.track, self.head, self.sector
+        )
+    }
+}
+
+/// The geometry of a flat sector image
+///
+/// Tracks are stored in order, and if there is more than one head
+/// the sides of a track are stored next to each other (track 0 side
+/// 0, track 0 side 1, track 1 side 0...).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    /// The number of sectors on each track, starting at first_track
+    pub sectors_per_track: Vec<u8>,
+    /// The number of heads (sides)
+    pub heads: u8,
+    /// The size of each sector in bytes
+    pub sector_size: usize,
+    /// The number of the first track, Commodore disks start at one
+    pub first_track: u8,
+    /// The number of the first sector, IBM-style disks start at one
+    pub first_sector: u8,
+}
+
+/// Sectors per track for each zone on a Commodore 1541 disk
+/// Tracks 36 through 40 are only found on extended disks
+const COMMODORE_1541_SECTORS_PER_TRACK: [u8; 40] = [
+    21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 21, 19, 19, 19, 19, 19, 19, 19,
+    18, 18, 18, 18, 18, 18, 17, 17, 17, 17, 17, 17, 17, 17, 17, 17,
+];
+
+/// Sectors per track for each zone of 16 tracks on a Macintosh GCR
+/// 400K or 800K disk
+const MACINTOSH_ZONE_SECTORS: [u8; 5] = [12, 11, 10, 9, 8];
+
+impl Geometry {
+    /// Create a geometry where every track has the same number of sectors
+    pub fn uniform(
+        tracks: u8,
+        heads: u8,
+        sectors: u8,
+        sector_size: usize,
+        first_track: u8,
+        first_sector: u8,
+    ) -> Geometry {
+        Geometry {
+            sectors_per_track: vec![sectors; tracks as usize],
+            heads,
+            sector_size,
+            first_track,
+            first_sector,
+        }
+    }
+
+    /// An Apple ][ DOS 3.3 disk in DOS order, 16 sectors of 256 bytes per track
+    pub fn apple_dos_33(tracks: u8) -> Geometry {
+        Geometry::uniform(tracks, 1, 16, 256, 0, 0)
+    }
+
+    /// An Apple ][ DOS 3.2 disk, 13 sectors of 256 bytes per track in
+    /// physical order
+    pub fn apple_dos_32(tracks: u8) -> Geometry {
+        Geometry::uniform(tracks, 1, 13, 256, 0, 0)
+    }
+
+    /// A Commodore 1541 disk, 35 or 40 tracks, numbered starting at one
+    pub fn commodore_1541(tracks: u8) -> Geometry {
+        let tracks = usize::from(tracks).min(COMMODORE_1541_SECTORS_PER_TRACK.len());
+        Geometry {
+            sectors_per_track: COMMODORE_1541_SECTORS_PER_TRACK[..tracks].to_vec(),
+            heads: 1,
+            sector_size: 256,
+            first_track: 1,
+            first_sector: 0,
+        }
+    }
+
+    /// A Commodore 1571 double-sided disk as stored in a D71 image
+    /// The second side is numbered as tracks 36 to 70, after the first,
+    /// with the same sector zones.
+    pub fn commodore_1571() -> Geometry {
+        let side = &COMMODORE_1541_SECTORS_PER_TRACK[..35];
+        Geometry {
+            sectors_per_track: [side, side].concat(),
+            heads: 1,
+            sector_size: 256,
+            first_track: 1,
+            first_sector: 0,
+        }
+    }
+
+    /// An Atari ST disk with 512 byte sectors numbered starting at one
+    pub fn atari_st(tracks: u8, heads: u8, sectors: u8) -> Geometry {
+        Geometry::uniform(tracks, heads, sectors, 512, 0, 1)
+    }
+
+    /// An Amiga disk, 80 tracks of two sides with 11 sectors of 512
+    /// bytes on double density disks and 22 on high density disks
+    pub fn amiga(sectors: u8) -> Geometry {
+        Geometry::uniform(80, 2, sectors, 512, 0, 0)
+    }
+
+    /// A Macintosh GCR disk, 80 tracks of 512 byte sectors in five
+    /// zones of 12 down to 8 sectors, one side for 400K disks and two
+    /// for 800K disks
+    pub fn macintosh(heads: u8) -> Geometry {
+        Geometry {
+            sectors_per_track: MACINTOSH_ZONE_SECTORS
+                .iter()
+                .flat_map(|sectors| [*sectors; 16])
+                .collect(),
+            heads,
+            sector_size: 512,
+            first_track: 0,
+            first_sector: 0,
+        }
+    }
+
+    /// Read a geometry given explicitly in the "tracks", "sectors",
+    /// "sector-size" and "sides" settings
+    /// Tracks and sectors are required, sectors are 512 bytes and
+    /// disks single-sided unless the settings say otherwise.  Tracks
+    /// start at zero and sectors at one, as on IBM-style disks.
+    /// Returns None if no geometry was given, and an error if it's
+    /// incomplete or out of range.
+    pub fn from_config(config: &Config) -> std::result::Result<Option<Geometry>, Error> {
+        let get = |key: &str| config.get_int(key).ok();
+        let (tracks, sectors, sector_size, sides) = (
+            get("tracks"),
+            get("sectors"),
+            get("sector-size"),
+            get("sides"),
+        );
+        if tracks.is_none() && sectors.is_none() && sector_size.is_none() && sides.is_none() {
+            return Ok(None);
+        }
+        let invalid =
+            |message: String| Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)));
+
+        let (Some(tracks), Some(sectors)) = (tracks, sectors) else {
+            return Err(invalid(String::from(
+                "A geometry needs both the number of tracks and sectors",
+            )));
+        };
+        let sector_size = sector_size.unwrap_or(512);
+        let sides = sides.unwrap_or(1);
+        if !(1..=255).contains(&tracks) || !(1..=255).contains(&sectors) {
+            return Err(invalid(format!(
+                "Invalid number of tracks or sectors: {}, {}",
+                tracks, sectors
+            )));
+        }
+        if !(128..=8192).contains(&sector_size) || !(sector_size as u64).is_power_of_two() {
+            return Err(invalid(format!("Invalid sector size: {}", sector_size)));
+        }
+        if !(1..=2).contains(&sides) {
+            return Err(invalid(format!("Invalid number of sides: {}", sides)));
+        }
+
+        Ok(Some(Geometry::uniform(
+            tracks as u8,
+            sides as u8,
+            sectors as u8,
+            sector_size as usize,
+            0,
+            1,
+        )))
+    }
+
+    /// Guess a geometry from the size of a flat image
+    /// Returns None if the size isn't a known size
+    pub fn from_size(size: usize) -> Option<Geometry> {
+        match size {
+            143360 => Some(Geometry::apple_dos_33(35)),
+            163840 => Some(Geometry::apple_dos_33(40)),
+            // D64 images, with and without the error info bytes
+            174848 | 175531 => Some(Geometry::commodore_1541(35)),
+            196608 | 197376 => Some(Geometry::commodore_1541(40)),
+            // D71 images, with and without the error info bytes
+            349696 | 351062 => Some(Geometry::commodore_1571()),
+            368640 => Some(Geometry::atari_st(80, 1, 9)),
+            409600 => Some(Geometry::atari_st(80, 1, 10)),
+            737280 => Some(Geometry::atari_st(80, 2, 9)),
+            819200 => Some(Geometry::atari_st(80, 2, 10)),
+            // ADF images
+            901120 => Some(Geometry::amiga(11)),
+            1802240 => Some(Geometry::amiga(22)),
+            _ => None,
+        }
+    }
+
+    /// The number of tracks (cylinders) in this geometry
+    pub fn tracks(&self) -> usize {
+        self.sectors_per_track.len()
+    }
+
+    /// The total number of bytes of sector data
+    pub fn total_size(&self) -> usize {
+        self.sectors_per_track
+            .iter()
+            .map(|s| usize::from(*s) * self.sector_size * usize::from(self.heads))
+            .sum()
+    }
+
+    /// Return the byte offset of a sector in the flat image, or None if
+    /// the sector doesn't exist in this geometry
+    pub fn offset(&self, id: &SectorId) -> Option<usize> {
+        if id.head.get() >= self.heads {
+            return None;
+        }
+        let track_index = id.track.index_from(Track(self.first_track))?;
+        let sector_index = id.sector.index_from(Sector(self.first_sector))?;
+        let sectors = usize::from(*self.sectors_per_track.get(track_index)?);
+        if sector_index >= sectors {
+            return None;
+        }
+
+        let preceding_sectors: usize = self.sectors_per_track[..track_index]
+            .iter()
+            .map(|s| usize::from(*s) * usize::from(self.heads))
+            .sum::<usize>()
+            + usize::from(id.head.get()) * sectors
+            + sector_index;
+
+        Some(preceding_sectors * self.sector_size)
+    }
+
+    /// Return every sector in the geometry, in image order
+    pub fn sector_ids(&self) -> Vec<SectorId> {
+        let mut ids = Vec::new();
+        for (track_index, sectors) in self.sectors_per_track.iter().enumerate() {
+            let Some(track) = Track::from_index(track_index, Track(self.first_track)) else {
+                break;
+            };
+            for head in 0..self.heads {
+                for sector_index in 0..usize::from(*sectors) {
+                    if let Some(sector) =
+                        Sector::from_index(sector_index, Sector(self.first_sector))
+                    {
+                        ids.push(SectorId::new(track, head, sector));
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    /// Return the sector holding a byte offset in the flat image, or
+    /// None if the offset is past the end of the geometry
+    pub fn sector_at(&self, offset: usize) -> Option<SectorId> {
+        self.sector_ids()
+            .get(offset / self.sector_size.max(1))
+            .copied()
+    }
+
+    /// Return the data for a sector in a flat image, or None if the
+    /// sector doesn't exist or the image is too short
+    pub fn sector<'a>(&self, data: &'a [u8], id: &SectorId) -> Option<&'a [u8]> {
+        let offset = self.offset(id)?;
+        data.get(offset..offset + self.sector_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{Block, Geometry, Head, Sector, SectorId, Track};
+
+    /// Test that sector offsets on an Apple DOS 3.3 disk are correct
+    #[test]
+    fn apple_dos_33_offset_works() {
+        let geometry = Geometry::apple_dos_33(35);
+
+        assert_eq!(geometry.total_size(), 143360);
+        assert_eq!(geometry.offset(&SectorId::new(0, 0, 0)), Some(0));
+        // The VTOC lives at track 17, sector 0
+        assert_eq!(geometry.offset(&SectorId::new(17, 0, 0)), Some(0x11000));
+        assert_eq!(geometry.offset(&SectorId::new(17, 0, 16)), None);
+        assert_eq!(geometry.offset(&SectorId::new(35, 0, 0)), None);
+        assert_eq!(geometry.sector_at(0x11010), Some(SectorId::new(17, 0, 0)));
+        assert_eq!(geometry.sector_at(143360), None);
+
+        let geometry = Geometry::apple_dos_32(35);
+        assert_eq!(geometry.total_size(), 116480);
+        assert_eq!(geometry.offset(&SectorId::new(17, 0, 0)), Some(0xDD00));
+        assert_eq!(geometry.offset(&SectorId::new(17, 0, 13)), None);
+    }
+
+    /// Test the sector zones and interleaved sides of a Macintosh disk
+    #[test]
+    fn macintosh_offset_works() {
+        assert_eq!(Geometry::macintosh(1).total_size(), 409600);
+        let geometry = Geometry::macintosh(2);
+        assert_eq!(geometry.total_size(), 819200);
+        assert_eq!(geometry.offset(&SectorId::new(0, 1, 0)), Some(12 * 512));
+        assert_eq!(geometry.offset(&SectorId::new(16, 0, 0)), Some(384 * 512));
+        assert_eq!(geometry.offset(&SectorId::new(16, 0, 11)), None);
+        assert_eq!(geometry.sector_ids().len(), 1600);
+    }
+
+    /// Test that the variable sector zones on a D64 disk are handled
+    #[test]
+    fn commodore_1541_offset_works() {
+        let geometry = Geometry::commodore_1541(35);
+
+        assert_eq!(geometry.total_size(), 174848);
+        assert_eq!(geometry.offset(&SectorId::new(0, 0, 0)), None);
+        assert_eq!(geometry.offset(&SectorId::new(1, 0, 0)), Some(0));
+        // The BAM lives at track 18, sector 0
+        assert_eq!(geometry.offset(&SectorId::new(18, 0, 0)), Some(0x16500));
+        assert_eq!(geometry.offset(&SectorId::new(18, 0, 19)), None);
+        assert_eq!(geometry.sector_ids().len(), 683);
+
+        // The second side of a D71 image starts at track 36
+        let geometry = Geometry::commodore_1571();
+        assert_eq!(Geometry::from_size(349696), Some(geometry.clone()));
+        assert_eq!(geometry.offset(&SectorId::new(36, 0, 0)), Some(174848));
+        assert_eq!(
+            geometry.offset(&SectorId::new(53, 0, 0)),
+            Some(174848 + 0x16500)
+        );
+        assert_eq!(geometry.offset(&SectorId::new(70, 0, 17)), None);
+    }
+
+    /// Test that double-sided disks interleave the sides of each track
+    #[test]
+    fn atari_st_offset_works() {
+        let geometry = Geometry::atari_st(80, 2, 9);
+
+        assert_eq!(Geometry::from_size(737280), Some(geometry.clone()));
+        assert_eq!(geometry.offset(&SectorId::new(0, 0, 0)), None);
+        assert_eq!(geometry.offset(&SectorId::new(0, 0, 1)), Some(0));
+        assert_eq!(geometry.offset(&SectorId::new(0, 1, 1)), Some(9 * 512));
+        assert_eq!(geometry.offset(&SectorId::new(1, 0, 1)), Some(18 * 512));
+    }
+
+    /// Test converting track, sector and block numbers to positions
+    #[test]
+    fn number_conversions_work() {
+        // Commodore tracks count from one, there's no track zero
+        assert_eq!(Track(1).index_from(Track(1)), Some(0));
+        assert_eq!(Track(0).index_from(Track(1)), None);
+        assert_eq!(Track::from_index(17, Track(1)), Some(Track(18)));
+        assert_eq!(Track::from_index(255, Track(1)), None);
+
+        // IBM-style sectors count from one
+        assert_eq!(Sector(9).index_from(Sector(1)), Some(8));
+        assert_eq!(Sector(0).index_from(Sector(1)), None);
+        assert_eq!(Sector::from_index(0, Sector(1)), Some(Sector(1)));
+        assert_eq!(Sector::from_index(256, Sector(0)), None);
+
+        assert_eq!(Block::from(280_u16), Block(280));
+        assert_eq!(Block(1759).index(), 1759);
+        assert_eq!(u8::from(Head(1)), 1);
+        assert_eq!(
+            SectorId::new(17, 0, 15),
+            SectorId {
+                track: Track(17),
+                head: Head(0),
+                sector: Sector(15)
+            }
+        );
+        assert_eq!(
+            SectorId::new(17, 0, 15).to_string(),
+            "track: 17, head: 0, sector: 15"
+        );
+    }
+
+    /// Test reading a geometry given in the settings
+    #[test]
+    fn from_config_works() {
+        let config = |settings: &[(&str, i64)]| {
+            settings
+                .iter()
+                .fold(Config::builder(), |builder, (key, value)| {
+                    builder.set_override(*key, *value).unwrap()
+                })
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(Geometry::from_config(&config(&[])).unwrap(), None);
+        assert_eq!(
+            Geometry::from_config(&config(&[("tracks", 80), ("sectors", 10), ("sides", 2)]))
+                .unwrap(),
+            Some(Geometry::atari_st(80, 2, 10))
+        );
+        let geometry = Geometry::from_config(&config(&[
+            ("tracks", 40),
+            ("sectors", 5),
+            ("sector-size", 1024),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(geometry.total_size(), 40 * 5 * 1024);
+
+        assert!(Geometry::from_config(&config(&[("tracks", 40)])).is_err());
+        assert!(Geometry::from_config(&config(&[("tracks", 40), ("sectors", 0)])).is_err());
+        assert!(Geometry::from_config(&config(&[
+            ("tracks", 40),
+            ("sectors", 9),
+            ("sector-size", 500)
+        ]))
+        .is_err());
+        assert!(
+            Geometry::from_config(&config(&[("tracks", 40), ("sectors", 9), ("sides", 3)]))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Format guesses from the contents of an image
+//!
+//! Raw dumps are often imported without a name, or with a name like
+//! IMAGE.BIN that says nothing about the format.  Formats with a
+//! signature are easy to recognize, flat images need heuristics:
+//!
+//! - The Apple DOS 3.3 VTOC and boot sector, and the ProDOS volume
+//!   directory
+//! - A plausible FAT BIOS parameter block that matches the size of the
+//!   image
+//! - The Commodore BAM signature on track 18
+//! - The Amiga boot block
+//! - Boot sector opcodes: a 68000 or x86 branch over the parameter
+//!   block, an executable Atari ST boot sector, or 6502 boot code
+//!
+//! Each heuristic that matches is a piece of evidence with a weight
+//! between zero and one.  The evidence for a format is combined so
+//! that any one piece can only raise the confidence, and every format
+//! with evidence is returned, most likely first.
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::amiga::disk::{ADF_DD_SIZE, ADF_HD_SIZE};
+use crate::disk_format::apple::prodos::find_volume_directory;
+use crate::disk_format::apple::twoimg::is_2mg;
+use crate::disk_format::apple::woz::is_woz;
+use crate::disk_format::carve::machine_code_score;
+use crate::disk_format::commodore::g64::is_g64;
+use crate::disk_format::dsk::disk::is_dsk;
+use crate::disk_format::fat::bpb::bios_parameter_block_parser;
+use crate::disk_format::hfe::is_hfe;
+use crate::disk_format::imd::is_imd;
+use crate::disk_format::mac::diskcopy::is_dc42;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::stx::st::is_msa;
+use crate::disk_format::td0::disk::is_td0;
+
+/// Guesses at or above this confidence are trusted when parsing an
+/// image without a known extension
+pub const LIKELY_CONFIDENCE: f32 = 0.5;
+
+/// The size of a 35 track Apple II disk
+const APPLE_DISK_SIZE: usize = 143360;
+
+/// The sizes of 35 and 40 track D64 images, with and without error
+/// bytes
+const D64_SIZES: [usize; 4] = [174848, 175531, 196608, 197376];
+
+/// The offset of the BAM, track 18 sector 0, in a D64 image
+const D64_BAM_OFFSET: usize = 0x16500;
+
+/// The offset of the VTOC, track 17 sector 0, in a DOS order image
+const DOS33_VTOC_OFFSET: usize = 0x11000;
+
+/// The start of the DOS 3.3 boot sector
+const DOS33_BOOT: [u8; 9] = [0x01, 0xA5, 0x27, 0xC9, 0x09, 0xD0, 0x18, 0xA5, 0x2B];
+
+/// The checksum of the words of an executable Atari ST boot sector
+const ST_BOOT_CHECKSUM: u16 = 0x1234;
+
+/// The size of a boot sector
+const BOOT_SECTOR_SIZE: usize = 512;
+
+/// A format the contents of an image point to
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatGuess {
+    /// The format, as named by the parse failure explanations, e.g. "D64"
+    pub format: &'static str,
+    /// How likely the image is in this format, between zero and one
+    pub confidence: f32,
+    /// The heuristics that matched
+    pub reasons: Vec<&'static str>,
+}
+
+impl Display for FormatGuess {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}, confidence: {:.2} ({})",
+            self.format,
+            self.confidence,
+            self.reasons.join(", ")
+        )
+    }
+}
+
+/// A piece of evidence for a format
+struct Evidence {
+    /// The format the evidence points to
+    format: &'static str,
+    /// How strongly it points to it, between zero and one
+    weight: f32,
+    /// What was found
+    reason: &'static str,
+}
+
+impl Evidence {
+    fn new(format: &'static str, weight: f32, reason: &'static str) -> Evidence {
+        Evidence {
+            format,
+            weight,
+            reason,
+        }
+    }
+}
+
+/// Evidence from the signatures of formats that have one
+fn signature_evidence(data: &[u8]) -> Vec<Evidence> {
+    [
+        ("WOZ", is_woz(data)),
+        ("2MG", is_2mg(data)),
+        ("DC42", is_dc42(data)),
+        ("DSK", is_dsk(data)),
+        ("G64", is_g64(data)),
+        ("HFE", is_hfe(data)),
+        ("TD0", is_td0(data)),
+        ("IMD", is_imd(data)),
+        ("MSA", is_msa(data)),
+        ("STX", data.starts_with(b"RSY\0")),
+    ]
+    .into_iter()
+    .filter(|(_, found)| *found)
+    .map(|(format, _)| Evidence::new(format, 0.99, "signature"))
+    .collect()
+}
+
+/// Evidence for Apple DOS 3.3 and ProDOS disks
+fn apple_evidence(data: &[u8]) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    if data.len() == APPLE_DISK_SIZE {
+        evidence.push(Evidence::new("Apple DOS", 0.2, "Apple II disk size"));
+        evidence.push(Evidence::new("ProDOS", 0.2, "Apple II disk size"));
+    }
+    if find_volume_directory(data).is_some() {
+        evidence.push(Evidence::new("ProDOS", 0.9, "ProDOS volume directory"));
+    }
+    // The catalog track and sector and the DOS release
+    if data.get(DOS33_VTOC_OFFSET + 1..DOS33_VTOC_OFFSET + 4) == Some(&[0x11, 0x0F, 0x03]) {
+        evidence.push(Evidence::new("Apple DOS", 0.6, "DOS 3.3 VTOC"));
+    }
+    if data.starts_with(&DOS33_BOOT) {
+        evidence.push(Evidence::new("Apple DOS", 0.6, "DOS 3.3 boot sector"));
+    } else if data.first() == Some(&0x01)
+        && data.len() == APPLE_DISK_SIZE
+        && machine_code_score(&data[1..256]) >= 0.9
+    {
+        // Boot sector 0 starts with the number of sectors to load
+        evidence.push(Evidence::new("Apple DOS", 0.2, "6502 boot code"));
+    }
+
+    evidence
+}
+
+/// Evidence for FAT volumes, read as flat .st images
+fn fat_evidence(data: &[u8]) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    let bpb = match bios_parameter_block_parser(data) {
+        Ok((_, bpb)) if bpb.check() => bpb,
+        _ => return evidence,
+    };
+    evidence.push(Evidence::new("ST", 0.2, "plausible BIOS parameter block"));
+    if matches!(bpb.media_descriptor, 0xF0 | 0xF8..=0xFF) {
+        evidence.push(Evidence::new("ST", 0.2, "FAT media descriptor"));
+    }
+    if usize::from(bpb.total_sectors) * usize::from(bpb.bytes_per_sector) == data.len() {
+        evidence.push(Evidence::new(
+            "ST",
+            0.5,
+            "BIOS parameter block matches the image size",
+        ));
+    }
+    // A BRA.S on the Atari ST, a JMP SHORT and NOP or a JMP on a PC
+    match data {
+        [0x60, ..] => evidence.push(Evidence::new("ST", 0.1, "68000 branch in the boot sector")),
+        [0xEB, _, 0x90, ..] | [0xE9, ..] => {
+            evidence.push(Evidence::new("ST", 0.1, "x86 jump in the boot sector"))
+        }
+        _ => (),
+    }
+    if data.len() >= BOOT_SECTOR_SIZE && st_boot_checksum(&data[..BOOT_SECTOR_SIZE]) {
+        evidence.push(Evidence::new("ST", 0.3, "executable Atari ST boot sector"));
+    }
+
+    evidence
+}
+
+/// Return true if the big-endian words of the boot sector add up to
+/// the executable boot sector checksum
+fn st_boot_checksum(sector: &[u8]) -> bool {
+    sector.chunks_exact(2).fold(0_u16, |sum, word| {
+        sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]))
+    }) == ST_BOOT_CHECKSUM
+}
+
+/// Evidence for Commodore 1541 disks
+fn commodore_evidence(data: &[u8]) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    if D64_SIZES.contains(&data.len()) {
+        evidence.push(Evidence::new("D64", 0.3, "D64 image size"));
+    }
+    // The BAM links to the first directory sector and holds the DOS
+    // version, and later the DOS type
+    if data.get(D64_BAM_OFFSET..D64_BAM_OFFSET + 3) == Some(&[0x12, 0x01, 0x41]) {
+        evidence.push(Evidence::new("D64", 0.7, "CBM BAM signature"));
+    }
+    if data.get(D64_BAM_OFFSET + 0xA5..D64_BAM_OFFSET + 0xA7) == Some(b"2A") {
+        evidence.push(Evidence::new("D64", 0.3, "CBM DOS type"));
+    }
+
+    evidence
+}
+
+/// Evidence for Amiga disks
+fn amiga_evidence(data: &[u8]) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    if matches!(data.len(), ADF_DD_SIZE | ADF_HD_SIZE) {
+        evidence.push(Evidence::new("ADF", 0.3, "ADF image size"));
+    }
+    // "DOS" and the filesystem flags
+    if matches!(data, [b'D', b'O', b'S', 0..=7, ..]) {
+        evidence.push(Evidence::new("ADF", 0.6, "Amiga boot block"));
+    }
+
+    evidence
+}
+
+/// Guess the format of an image from its contents
+/// Every format with any evidence is returned, most likely first.
+/// The confidence of a format is one minus the product of one minus
+/// the weight of each piece of evidence, so more evidence always
+/// raises it but never above one.
+pub fn guess_formats(data: &[u8]) -> Vec<FormatGuess> {
+    let evidence = [
+        signature_evidence(data),
+        apple_evidence(data),
+        fat_evidence(data),
+        commodore_evidence(data),
+        amiga_evidence(data),
+    ];
+
+    let mut guesses: Vec<FormatGuess> = Vec::new();
+    for item in evidence.into_iter().flatten() {
+        match guesses.iter_mut().find(|guess| guess.format == item.format) {
+            Some(guess) => {
+                guess.confidence = 1.0 - (1.0 - guess.confidence) * (1.0 - item.weight);
+                guess.reasons.push(item.reason);
+            }
+            None => guesses.push(FormatGuess {
+                format: item.format,
+                confidence: item.weight,
+                reasons: vec![item.reason],
+            }),
+        }
+    }
+    // The sort is stable, so ties keep the order the heuristics ran in
+    guesses.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+    guesses
+}
+
+/// Return the most likely format of an image, or None if nothing
+/// about it looks like a known format
+pub fn best_guess(data: &[u8]) -> Option<FormatGuess> {
+    guess_formats(data).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{best_guess, guess_formats, LIKELY_CONFIDENCE};
+    use crate::disk_format::testgen;
+
+    /// Test guessing the formats of generated images
+    #[test]
+    fn guess_formats_works() {
+        let data = testgen::d64("GUESS", &[("FILE", b"data")]).unwrap();
+        let guess = best_guess(&data).unwrap();
+        assert_eq!(guess.format, "D64");
+        assert!(guess.confidence > 0.8);
+        assert_eq!(
+            guess.reasons,
+            vec!["D64 image size", "CBM BAM signature", "CBM DOS type"]
+        );
+
+        let data = testgen::atari_st_fat("GUESS", &[("FILE.TXT", b"data")]).unwrap();
+        let guess = best_guess(&data).unwrap();
+        assert_eq!(guess.format, "ST");
+        assert!(guess.confidence >= LIKELY_CONFIDENCE);
+        assert!(guess
+            .reasons
+            .contains(&"BIOS parameter block matches the image size"));
+        assert!(guess.reasons.contains(&"68000 branch in the boot sector"));
+
+        let data = testgen::apple_dos_33(&[("FILE", b"data")]).unwrap();
+        let guess = best_guess(&data).unwrap();
+        assert_eq!(guess.format, "Apple DOS");
+        assert!(guess.reasons.contains(&"DOS 3.3 VTOC"));
+
+        let data = testgen::prodos("GUESS", &[("FILE", b"data")]).unwrap();
+        assert_eq!(best_guess(&data).unwrap().format, "ProDOS");
+
+        let data = testgen::adf("GUESS", false, &[]).unwrap();
+        let guess = best_guess(&data).unwrap();
+        assert_eq!(guess.format, "ADF");
+        assert!(guess.confidence >= LIKELY_CONFIDENCE);
+
+        assert!(guess_formats(&[0_u8; 1000]).is_empty());
+    }
+
+    /// Test the FAT heuristics don't trust a parameter block that
+    /// doesn't match the image
+    #[test]
+    fn fat_evidence_works() {
+        let mut data = testgen::atari_st_fat("GUESS", &[]).unwrap();
+        data.truncate(data.len() / 2);
+        let guess = best_guess(&data).unwrap();
+        assert_eq!(guess.format, "ST");
+        assert!(guess.confidence < LIKELY_CONFIDENCE);
+
+        // Make the boot sector executable
+        let mut data = testgen::atari_st_fat("GUESS", &[]).unwrap();
+        let sum = data[..510].chunks_exact(2).fold(0_u16, |sum, word| {
+            sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]))
+        });
+        data[510..512].copy_from_slice(&0x1234_u16.wrapping_sub(sum).to_be_bytes());
+        assert!(best_guess(&data)
+            .unwrap()
+            .reasons
+            .contains(&"executable Atari ST boot sector"));
+    }
+}
@@ -0,0 +1,615 @@
+//! HxC Floppy Emulator HFE disk images
+//!
+//! HFE images hold the bit cells of each track, as written by the HxC
+//! and Gotek floppy emulators and read by Greaseweazle.  The file is
+//! made of 512 byte blocks, starting with a header of little-endian
+//! fields:
+//!
+//! ```ignore
+//! 00: "HXCPICFE" for version 1, "HXCHFEV3" for version 3
+//! 08: the format revision, 0
+//! 09: the number of tracks
+//! 0A: the number of sides
+//! 0B: the track encoding, 0 for IBM MFM, 1 for Amiga MFM, 2 for IBM
+//!     FM and 3 for EMU FM
+//! 0C: the data rate in kbit/s, e.g. 250 for double density
+//! 0E: the rotation speed in RPM
+//! 10: the floppy interface mode
+//! 12: the block of the track list
+//! 14: 0xFF if the image can be written
+//! ```
+//!
+//! The track list holds the block and the length in bytes of each
+//! track's data.  Track data alternates between the sides every 256
+//! bytes, so each 512 byte block holds 256 bytes of side 0 followed by
+//! 256 bytes of side 1.  Bit cells are stored least significant bit
+//! first.
+//!
+//! Version 3 adds opcodes to the bit cells, bytes that can't appear in
+//! MFM data because they have four one bits in a row:
+//!
+//! ```ignore
+//! F0: no operation
+//! F1: the index pulse is here
+//! F2: change the data rate, the next byte is the new rate
+//! F3: skip bits, the next byte is the number of cells to skip at the
+//!     start of the byte after it
+//! F4: a byte of weak bits
+//! ```
+//!
+//! IBM MFM tracks are decoded into sectors, which are read as a flat
+//! image so the disk can be saved as an .st or .img file.  Other track
+//! encodings are parsed but not decoded.
+//!
+//! Information from:\
+//! [HxC Floppy Emulator HFE file format](https://hxc2001.com/download/floppy_drive_emulator/SDCard_HxC_Floppy_Emulator_HFE_file_format.pdf)\
+//! [Greaseweazle](https://github.com/keirf/greaseweazle.git) image/hfe.py
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, warn};
+use nom::bytes::complete::take;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::limit_tracks;
+use crate::disk_format::logical::{flatten_tracks, infer_geometry, LogicalTrack, RawExporter};
+use crate::disk_format::mfm::decode_track;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::stx::st::{flat_disk_parser, STDisk};
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// An HFE bit stream image
+pub const HFE_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "HFE",
+    description: "HxC Floppy Emulator version 1 or 3 bit stream disk image",
+    extensions: &["hfe"],
+    sizes: &[SizeRange::at_least(HFE_BLOCK_SIZE)],
+    references: &[
+        Reference {
+            title: "HxC Floppy Emulator HFE file format",
+            url: Some("https://hxc2001.com/download/floppy_drive_emulator/SDCard_HxC_Floppy_Emulator_HFE_file_format.pdf"),
+        },
+        Reference {
+            title: "Greaseweazle",
+            url: Some("https://github.com/keirf/greaseweazle.git"),
+        },
+    ],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        track_export: true,
+        write_protect: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature of a version 1 HFE image
+pub const HFE_V1_MAGIC: &[u8] = b"HXCPICFE";
+
+/// The signature of a version 3 HFE image
+pub const HFE_V3_MAGIC: &[u8] = b"HXCHFEV3";
+
+/// The size of the blocks the header, track list and tracks are
+/// stored in
+pub const HFE_BLOCK_SIZE: usize = 512;
+
+/// The number of bytes of one side in each block of track data
+const SIDE_CHUNK_SIZE: usize = 256;
+
+/// The size of the header fields, the rest of the block is padding
+const HEADER_FIELDS_SIZE: usize = 26;
+
+/// The size of each track list entry
+const TRACK_ENTRY_SIZE: usize = 4;
+
+/// Version 3 opcodes
+const NOP_OPCODE: u8 = 0xF0;
+const SETINDEX_OPCODE: u8 = 0xF1;
+const SETBITRATE_OPCODE: u8 = 0xF2;
+const SKIPBITS_OPCODE: u8 = 0xF3;
+const RAND_OPCODE: u8 = 0xF4;
+
+/// True if the data starts with an HFE signature
+pub fn is_hfe(data: &[u8]) -> bool {
+    data.starts_with(HFE_V1_MAGIC) || data.starts_with(HFE_V3_MAGIC)
+}
+
+/// The version of an HFE image
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HfeVersion {
+    /// Version 1, plain bit cells
+    V1,
+    /// Version 3, bit cells with opcodes
+    V3,
+}
+
+impl Display for HfeVersion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            HfeVersion::V1 => write!(f, "HFE v1"),
+            HfeVersion::V3 => write!(f, "HFE v3"),
+        }
+    }
+}
+
+/// The encoding of the tracks in an HFE image
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrackEncoding {
+    /// IBM MFM, as used by the Atari ST and PC
+    IsoIbmMfm,
+    /// Amiga MFM
+    AmigaMfm,
+    /// IBM FM
+    IsoIbmFm,
+    /// E-mu FM
+    EmuFm,
+    /// An encoding this parser doesn't know about
+    Unknown(u8),
+}
+
+impl From<u8> for TrackEncoding {
+    fn from(encoding: u8) -> TrackEncoding {
+        match encoding {
+            0 => TrackEncoding::IsoIbmMfm,
+            1 => TrackEncoding::AmigaMfm,
+            2 => TrackEncoding::IsoIbmFm,
+            3 => TrackEncoding::EmuFm,
+            _ => TrackEncoding::Unknown(encoding),
+        }
+    }
+}
+
+impl Display for TrackEncoding {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            TrackEncoding::IsoIbmMfm => write!(f, "IBM MFM"),
+            TrackEncoding::AmigaMfm => write!(f, "Amiga MFM"),
+            TrackEncoding::IsoIbmFm => write!(f, "IBM FM"),
+            TrackEncoding::EmuFm => write!(f, "EMU FM"),
+            TrackEncoding::Unknown(encoding) => write!(f, "unknown encoding {:02X}", encoding),
+        }
+    }
+}
+
+/// The HFE header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HfeHeader {
+    /// The version, from the signature
+    pub version: HfeVersion,
+    /// The format revision, 0
+    pub format_revision: u8,
+    /// The number of tracks
+    pub tracks: u8,
+    /// The number of sides
+    pub sides: u8,
+    /// The encoding of the tracks
+    pub encoding: TrackEncoding,
+    /// The data rate in kbit/s
+    pub bit_rate: u16,
+    /// The rotation speed in RPM, zero if it isn't known
+    pub rpm: u16,
+    /// The floppy interface mode the emulator presents
+    pub interface_mode: u8,
+    /// The block the track list starts at
+    pub track_list_offset: u16,
+    /// True if the emulator can write to the image
+    pub write_allowed: bool,
+}
+
+impl Display for HfeHeader {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}, tracks: {}, sides: {}, {}, {} kbit/s",
+            self.version, self.tracks, self.sides, self.encoding, self.bit_rate
+        )?;
+        if self.rpm != 0 {
+            write!(f, ", {} RPM", self.rpm)?;
+        }
+        if !self.write_allowed {
+            write!(f, ", write protected")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for HfeHeader {
+    fn check(&self) -> bool {
+        if self.format_revision != 0 {
+            debug!(
+                target: PARSE,
+                "Unsupported HFE format revision {}", self.format_revision
+            );
+            return false;
+        }
+        if self.tracks == 0 || !(1..=2).contains(&self.sides) {
+            debug!(
+                target: PARSE,
+                "HFE image has {} tracks and {} sides", self.tracks, self.sides
+            );
+            return false;
+        }
+        if self.track_list_offset == 0 {
+            debug!(target: PARSE, "HFE track list overlaps the header");
+            return false;
+        }
+        true
+    }
+}
+
+/// An entry in the track list: where a track's data is in the file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HfeTrack {
+    /// The offset of the track data in the file
+    pub offset: usize,
+    /// The length of the track data in bytes, both sides together
+    pub length: usize,
+}
+
+/// Return the bit cells of one side of a track
+/// Version 3 opcodes are removed, skipped cells are dropped and weak
+/// bytes are read as stored.
+pub fn hfe_track_bits(track_data: &[u8], side: u8, version: HfeVersion) -> Vec<bool> {
+    let side_length = track_data.len() / 2;
+    let mut bytes = Vec::with_capacity(side_length);
+    for start in (0..side_length).step_by(SIDE_CHUNK_SIZE) {
+        let offset = start * 2 + usize::from(side) * SIDE_CHUNK_SIZE;
+        let length = SIDE_CHUNK_SIZE.min(side_length - start);
+        bytes.extend(
+            track_data
+                .get(offset..offset + length)
+                .unwrap_or_default()
+                .iter()
+                .map(|byte| byte.reverse_bits()),
+        );
+    }
+
+    let mut bits = Vec::with_capacity(bytes.len() * 8);
+    let mut bytes = bytes.into_iter();
+    while let Some(byte) = bytes.next() {
+        let (byte, skip) = match byte {
+            NOP_OPCODE | SETINDEX_OPCODE if version == HfeVersion::V3 => continue,
+            SETBITRATE_OPCODE if version == HfeVersion::V3 => {
+                bytes.next();
+                continue;
+            }
+            SKIPBITS_OPCODE if version == HfeVersion::V3 => {
+                let skip = bytes.next().unwrap_or(0).min(8);
+                match bytes.next() {
+                    Some(byte) => (byte, skip),
+                    None => break,
+                }
+            }
+            RAND_OPCODE if version == HfeVersion::V3 => match bytes.next() {
+                Some(byte) => (byte, 0),
+                None => break,
+            },
+            _ => (byte, 0),
+        };
+        bits.extend((0..8 - skip).rev().map(|i| (byte >> i) & 0x01 == 0x01));
+    }
+    bits
+}
+
+/// An HFE disk, with the sectors decoded from its tracks
+pub struct HfeDisk<'a> {
+    /// The header
+    pub header: HfeHeader,
+    /// The whole image
+    pub data: &'a [u8],
+    /// The track list
+    pub track_list: Vec<HfeTrack>,
+    /// The sectors decoded from each side of each track, empty for
+    /// encodings that aren't decoded
+    pub tracks: Vec<LogicalTrack>,
+    /// The decoded sectors as a flat image, None if no sectors were
+    /// decoded
+    pub disk: Option<STDisk>,
+}
+
+impl HfeDisk<'_> {
+    /// Return the geometry of the decoded sectors, None if no sectors
+    /// were decoded
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.disk.as_ref().map(|disk| disk.geometry.clone())
+    }
+
+    /// Return the sectors that had CRC errors
+    pub fn bad_sectors(&self) -> Vec<SectorId> {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.sectors.iter())
+            .filter(|sector| sector.crc_error)
+            .map(|sector| sector.id)
+            .collect()
+    }
+}
+
+impl Display for HfeDisk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.header)?;
+        match &self.disk {
+            Some(disk) => write!(f, "\n{}, bad sectors: {}", disk, self.bad_sectors().len()),
+            None => write!(f, "\nNo sectors decoded"),
+        }
+    }
+}
+
+impl SanityCheck for HfeDisk<'_> {
+    fn check(&self) -> bool {
+        self.header.check() && self.disk.as_ref().is_some_and(STDisk::check)
+    }
+}
+
+impl DiskImageSaver for HfeDisk<'_> {
+    /// Save the decoded sectors as a flat image, or a file from the
+    /// FAT filesystem if one is selected
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        match &self.disk {
+            Some(disk) => disk.save_to_writer(config, selected_filename, writer),
+            None => {
+                error!(target: IO, "No sectors were decoded from the HFE image");
+                Err(Error::new(ErrorKind::Unimplemented(format!(
+                    "Saving {} HFE images",
+                    self.header.encoding
+                ))))
+            }
+        }
+    }
+
+    /// The files in the FAT filesystem on the decoded sectors
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.disk
+            .as_ref()
+            .map(DiskImageSaver::disk_files)
+            .unwrap_or_default()
+    }
+}
+
+impl RawExporter for HfeDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.tracks.clone()
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        self.geometry()
+    }
+}
+
+/// The header, the track list and the data of each track
+impl SourceMapper for HfeDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        map.add(RegionKind::Header, "HFE header", start, HEADER_FIELDS_SIZE);
+        map.add(
+            RegionKind::Header,
+            "track list",
+            start + usize::from(self.header.track_list_offset) * HFE_BLOCK_SIZE,
+            self.track_list.len() * TRACK_ENTRY_SIZE,
+        );
+        for (track, entry) in self.track_list.iter().enumerate() {
+            map.add(
+                RegionKind::Data,
+                &format!("track {}", track),
+                start + entry.offset,
+                entry.length,
+            );
+        }
+        map
+    }
+}
+
+/// Parse the HFE header
+pub fn hfe_header_parser(i: &[u8]) -> IResult<&[u8], HfeHeader> {
+    let (i, magic) = take(8_usize)(i)?;
+    let version = match magic {
+        HFE_V1_MAGIC => HfeVersion::V1,
+        HFE_V3_MAGIC => HfeVersion::V3,
+        _ => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                magic,
+                nom::error::ErrorKind::Tag,
+            )))
+        }
+    };
+    let (i, format_revision) = le_u8(i)?;
+    let (i, tracks) = le_u8(i)?;
+    let (i, sides) = le_u8(i)?;
+    let (i, encoding) = le_u8(i)?;
+    let (i, bit_rate) = le_u16(i)?;
+    let (i, rpm) = le_u16(i)?;
+    let (i, interface_mode) = le_u8(i)?;
+    let (i, _reserved) = le_u8(i)?;
+    let (i, track_list_offset) = le_u16(i)?;
+    let (i, write_allowed) = le_u8(i)?;
+    let (i, _) = take(HEADER_FIELDS_SIZE - 21)(i)?;
+
+    Ok((
+        i,
+        HfeHeader {
+            version,
+            format_revision,
+            tracks,
+            sides,
+            encoding: TrackEncoding::from(encoding),
+            bit_rate,
+            rpm,
+            interface_mode,
+            track_list_offset,
+            write_allowed: write_allowed == 0xFF,
+        },
+    ))
+}
+
+/// Parse a track list entry
+fn hfe_track_parser(i: &[u8]) -> IResult<&[u8], HfeTrack> {
+    let (i, offset) = le_u16(i)?;
+    let (i, length) = le_u16(i)?;
+
+    Ok((
+        i,
+        HfeTrack {
+            offset: usize::from(offset) * HFE_BLOCK_SIZE,
+            length: usize::from(length),
+        },
+    ))
+}
+
+/// Parse an HFE image, decoding the sectors on IBM MFM tracks
+pub fn hfe_disk_parser(data: &[u8]) -> IResult<&[u8], HfeDisk<'_>> {
+    let (_, header) = hfe_header_parser(data)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[8..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    limit_tracks(data, usize::from(header.tracks))?;
+    debug!(target: PARSE, "HFE header: {}", header);
+
+    let (i, _) = take(usize::from(header.track_list_offset) * HFE_BLOCK_SIZE)(data)?;
+    let mut i = i;
+    let mut track_list = Vec::with_capacity(usize::from(header.tracks));
+    for _ in 0..header.tracks {
+        let (rest, track) = hfe_track_parser(i)?;
+        track_list.push(track);
+        i = rest;
+    }
+
+    let mut tracks = Vec::new();
+    for (track, entry) in track_list.iter().enumerate() {
+        let (rest, _) = take(entry.offset)(data)?;
+        let (_, track_data) = take(entry.length)(rest)?;
+        for side in 0..header.sides {
+            if header.encoding != TrackEncoding::IsoIbmMfm {
+                tracks.push(LogicalTrack::new(track as u8, side));
+                continue;
+            }
+            let bits = hfe_track_bits(track_data, side, header.version);
+            let mut logical_track = decode_track(&bits, track as u8, side);
+            logical_track.merge_duplicates();
+            tracks.push(logical_track);
+        }
+    }
+    if header.encoding != TrackEncoding::IsoIbmMfm {
+        warn!(
+            target: PARSE,
+            "{} tracks aren't decoded, only IBM MFM", header.encoding
+        );
+    }
+
+    let disk = infer_geometry(&tracks).and_then(|geometry| {
+        let image = flatten_tracks(&tracks, &geometry);
+        flat_disk_parser(geometry)(&image)
+            .ok()
+            .map(|(_, disk)| disk)
+    });
+
+    let end = track_list
+        .iter()
+        .map(|entry| entry.offset + entry.length)
+        .max()
+        .unwrap_or(0)
+        .max(data.len() - i.len());
+    Ok((
+        &data[end.min(data.len())..],
+        HfeDisk {
+            header,
+            data,
+            track_list,
+            tracks,
+            disk,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hfe_disk_parser, hfe_track_bits, is_hfe, HfeVersion, TrackEncoding};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::logical::RawExporter;
+    use crate::disk_format::testgen;
+
+    /// Test decoding the sectors and files of version 1 and 3 images
+    #[test]
+    fn hfe_disk_parser_works() {
+        let st = testgen::atari_st_fat("HFE", &[("README.TXT", b"bit cells")]).unwrap();
+        let geometry = Geometry::atari_st(80, 2, 9);
+
+        for version in [1, 3] {
+            let data = testgen::hfe(&st, &geometry, version);
+            assert!(is_hfe(&data));
+            let (_, disk) = hfe_disk_parser(&data).unwrap();
+            assert_eq!(disk.header.tracks, 80);
+            assert_eq!(disk.header.sides, 2);
+            assert_eq!(disk.header.encoding, TrackEncoding::IsoIbmMfm);
+            assert_eq!(disk.geometry(), Some(geometry.clone()));
+            assert!(disk.bad_sectors().is_empty());
+
+            // The decoded sectors are the .st image
+            let mut flat = Vec::new();
+            disk.save_to_writer(&config::Config::default(), None, &mut flat)
+                .unwrap();
+            assert_eq!(flat, st);
+            let files = disk.disk_files();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].data, b"bit cells");
+            assert_eq!(
+                disk.logical_tracks()[3].sector(1).unwrap().id,
+                SectorId::new(1, 1, 1)
+            );
+        }
+        let data = testgen::hfe(&st, &geometry, 3);
+        let (_, disk) = hfe_disk_parser(&data).unwrap();
+        assert_eq!(disk.header.version, HfeVersion::V3);
+        assert_eq!(
+            disk.header.to_string(),
+            "HFE v3, tracks: 80, sides: 2, IBM MFM, 250 kbit/s, 300 RPM"
+        );
+
+        // Tracks past the end of the file
+        assert!(hfe_disk_parser(&data[..data.len() / 2]).is_err());
+        // An unsupported format revision
+        let mut bad = data.clone();
+        bad[8] = 1;
+        assert!(hfe_disk_parser(&bad).is_err());
+    }
+
+    /// Test reading the sides of a track and the version 3 opcodes
+    #[test]
+    fn hfe_track_bits_works() {
+        // Side 0 holds 0x80, 0x0F with its first 4 cells skipped and a
+        // no operation, side 1 holds 0x01
+        // Bytes are stored least significant bit first
+        let mut track = vec![0_u8; 1024];
+        track[0..5].copy_from_slice(&[0x01, 0xCF, 0x20, 0xF0, 0x0F]);
+        track[256] = 0x80;
+
+        let side_0 = hfe_track_bits(&track, 0, HfeVersion::V3);
+        assert_eq!(side_0.len(), 8 + 4 + 507 * 8);
+        assert!(side_0[0]);
+        assert_eq!(&side_0[8..12], &[true; 4]);
+        let side_1 = hfe_track_bits(&track, 1, HfeVersion::V1);
+        assert_eq!(side_1.len(), 512 * 8);
+        assert!(side_1[7]);
+        assert_eq!(side_1.iter().filter(|b| **b).count(), 1);
+    }
+}
@@ -0,0 +1,491 @@
+//! Format identification without parsing the image
+//!
+//! File managers and file(1)-like tools want a one line description of
+//! every file in a directory, quickly.  [identify] only looks at the
+//! signature and header of formats that have one, and falls back to
+//! the heuristics in [guess](crate::disk_format::guess) for flat
+//! images.  Tracks, sectors and filesystems are never parsed, so a
+//! damaged image is identified as well as a good one:
+//!
+//! ```ignore
+//! HFE (v3), tracks: 80, sides: 2, encoding: IBM MFM, bit rate: 250 kbit/s, confidence: 0.99
+//! D64, evidence: D64 image size; CBM BAM signature, confidence: 0.79
+//! ```
+use std::fmt::{Display, Formatter, Result};
+
+use serde::Serialize;
+
+use crate::disk_format::apple::twoimg::{is_2mg, twoimg_header_parser};
+use crate::disk_format::apple::woz::{is_woz, woz_info_parser};
+use crate::disk_format::commodore::g64::{g64_header_parser, is_g64};
+use crate::disk_format::dsk::disk::{dsk_disk_header_parser, is_dsk};
+use crate::disk_format::flux::scp::{is_scp, scp_header_parser};
+use crate::disk_format::guess::best_guess;
+use crate::disk_format::hfe::{hfe_header_parser, is_hfe, HfeVersion};
+use crate::disk_format::imd::{imd_header_parser, is_imd};
+use crate::disk_format::mac::diskcopy::{dc42_header_parser, is_dc42};
+use crate::disk_format::stx::disk::stx_disk_header_parser;
+use crate::disk_format::stx::st::{is_msa, msa_header_parser};
+use crate::disk_format::td0::disk::{is_td0, td0_header_parser};
+use crate::error::Error;
+use crate::rom_format::atari::{a78_header_parser, is_a78};
+use crate::rom_format::nes::{is_nes, nes_header_parser, NESHeaderFormat};
+
+/// The confidence of a format found by its signature, the same as the
+/// weight of a signature in the guesses
+const SIGNATURE_CONFIDENCE: f32 = 0.99;
+
+/// The format of data that doesn't look like any known format
+pub const UNKNOWN_FORMAT: &str = "unknown";
+
+/// The offset of the INFO chunk data in a WOZ image, always the first
+/// chunk
+const WOZ_INFO_OFFSET: usize = 20;
+
+/// A signature check, and the function that reads the header of
+/// data with that signature
+type Identifier = (fn(&[u8]) -> bool, fn(&[u8]) -> FormatId);
+
+/// A field read from the header of an image
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HeaderField {
+    /// The name of the field, e.g. "tracks"
+    pub name: &'static str,
+    /// The value, formatted for display
+    pub value: String,
+}
+
+impl HeaderField {
+    fn new<T: ToString>(name: &'static str, value: T) -> HeaderField {
+        HeaderField {
+            name,
+            value: value.to_string(),
+        }
+    }
+}
+
+/// The identity of an image: what format it's in, and the key fields
+/// of its header
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FormatId {
+    /// The format, as named by the guesses, e.g. "D64", or
+    /// [UNKNOWN_FORMAT]
+    pub format: &'static str,
+    /// The variant of the format, e.g. "extended" for an extended DSK
+    /// image
+    pub variant: Option<String>,
+    /// How likely the data is in this format, between zero and one
+    pub confidence: f32,
+    /// Key fields from the header, or the evidence for a guessed
+    /// format
+    pub fields: Vec<HeaderField>,
+}
+
+impl FormatId {
+    /// Build the identity of a format found by its signature
+    fn signature(format: &'static str, variant: Option<String>, fields: Vec<HeaderField>) -> Self {
+        FormatId {
+            format,
+            variant,
+            confidence: SIGNATURE_CONFIDENCE,
+            fields,
+        }
+    }
+
+    /// Return true if the data looked like a known format
+    pub fn is_known(&self) -> bool {
+        self.format != UNKNOWN_FORMAT
+    }
+
+    /// Return the value of a header field
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| field.value.as_str())
+    }
+
+    /// Serialize the identity as a single line of JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Format an identity as a single line, like file(1)
+impl Display for FormatId {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.format)?;
+        if let Some(variant) = &self.variant {
+            write!(f, " ({})", variant)?;
+        }
+        for field in &self.fields {
+            write!(f, ", {}: {}", field.name, field.value)?;
+        }
+        write!(f, ", confidence: {:.2}", self.confidence)
+    }
+}
+
+/// The variant and header fields of a WOZ image
+fn woz_id(data: &[u8]) -> FormatId {
+    let variant = Some(format!("WOZ{}", char::from(data[3])));
+    let info = match data.get(12..16) {
+        Some(b"INFO") => data
+            .get(WOZ_INFO_OFFSET..)
+            .and_then(|i| woz_info_parser(i).ok()),
+        _ => None,
+    };
+    let Some((_, info)) = info else {
+        return FormatId::signature("WOZ", variant, Vec::new());
+    };
+    let mut fields = vec![
+        HeaderField::new(
+            "disk type",
+            match info.disk_type {
+                1 => String::from("5.25 inch"),
+                2 => String::from("3.5 inch"),
+                disk_type => format!("unknown {}", disk_type),
+            },
+        ),
+        HeaderField::new("sides", info.sides),
+        HeaderField::new("creator", info.creator),
+    ];
+    if info.write_protected {
+        fields.push(HeaderField::new("write protected", "yes"));
+    }
+    FormatId::signature("WOZ", variant, fields)
+}
+
+/// The variant and header fields of a 2MG image
+fn twoimg_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = twoimg_header_parser(data) else {
+        return FormatId::signature("2MG", None, Vec::new());
+    };
+    let mut fields = vec![HeaderField::new("creator", &header.creator)];
+    if header.blocks != 0 {
+        fields.push(HeaderField::new("blocks", header.blocks));
+    }
+    if let Some(volume) = header.volume() {
+        fields.push(HeaderField::new("volume", volume));
+    }
+    if header.locked() {
+        fields.push(HeaderField::new("locked", "yes"));
+    }
+    FormatId::signature("2MG", Some(header.format.to_string()), fields)
+}
+
+/// The variant and header fields of a DiskCopy 4.2 image
+fn dc42_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = dc42_header_parser(data) else {
+        return FormatId::signature("DC42", None, Vec::new());
+    };
+    FormatId::signature(
+        "DC42",
+        Some(header.encoding.to_string()),
+        vec![
+            HeaderField::new("name", header.name),
+            HeaderField::new("data size", header.data_size),
+        ],
+    )
+}
+
+/// The variant and header fields of a DSK image
+fn dsk_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = dsk_disk_header_parser(data) else {
+        return FormatId::signature("DSK", None, Vec::new());
+    };
+    FormatId::signature(
+        "DSK",
+        Some(String::from(if header.extended {
+            "extended"
+        } else {
+            "standard"
+        })),
+        vec![
+            HeaderField::new("tracks", header.tracks),
+            HeaderField::new("sides", header.sides),
+            HeaderField::new(
+                "creator",
+                String::from_utf8_lossy(&header.creator).trim_end_matches(['\0', ' ']),
+            ),
+        ],
+    )
+}
+
+/// The header fields of a G64 image
+fn g64_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = g64_header_parser(data) else {
+        return FormatId::signature("G64", None, Vec::new());
+    };
+    FormatId::signature(
+        "G64",
+        None,
+        vec![
+            HeaderField::new("version", header.version),
+            HeaderField::new("tracks", header.track_entries / 2),
+            HeaderField::new("largest track", header.max_track_size),
+        ],
+    )
+}
+
+/// The variant and header fields of an HFE image
+fn hfe_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = hfe_header_parser(data) else {
+        return FormatId::signature("HFE", None, Vec::new());
+    };
+    let variant = match header.version {
+        HfeVersion::V1 => "v1",
+        HfeVersion::V3 => "v3",
+    };
+    let mut fields = vec![
+        HeaderField::new("tracks", header.tracks),
+        HeaderField::new("sides", header.sides),
+        HeaderField::new("encoding", header.encoding),
+        HeaderField::new("bit rate", format!("{} kbit/s", header.bit_rate)),
+    ];
+    if !header.write_allowed {
+        fields.push(HeaderField::new("write protected", "yes"));
+    }
+    FormatId::signature("HFE", Some(String::from(variant)), fields)
+}
+
+/// The header fields of a TD0 image
+fn td0_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = td0_header_parser(data) else {
+        return FormatId::signature("TD0", None, Vec::new());
+    };
+    let variant = if header.advanced_compression {
+        "advanced compression"
+    } else {
+        "normal"
+    };
+    let mut fields = vec![
+        HeaderField::new(
+            "version",
+            format!("{}.{}", header.version / 10, header.version % 10),
+        ),
+        HeaderField::new("sides", header.sides),
+        HeaderField::new("encoding", if header.is_fm() { "FM" } else { "MFM" }),
+    ];
+    if header.bit_rate() != 0 {
+        fields.push(HeaderField::new(
+            "bit rate",
+            format!("{} kbit/s", header.bit_rate()),
+        ));
+    }
+    if header.has_comment() {
+        fields.push(HeaderField::new("comment", "yes"));
+    }
+    FormatId::signature("TD0", Some(String::from(variant)), fields)
+}
+
+/// The header fields of an IMD image
+fn imd_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = imd_header_parser(data) else {
+        return FormatId::signature("IMD", None, Vec::new());
+    };
+    let mut fields = vec![
+        HeaderField::new("version", &header.version),
+        HeaderField::new("created", &header.timestamp),
+    ];
+    if let Some(line) = header.comment.lines().next() {
+        fields.push(HeaderField::new("comment", line));
+    }
+    FormatId::signature("IMD", None, fields)
+}
+
+/// The header fields of an MSA image
+fn msa_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = msa_header_parser(data) else {
+        return FormatId::signature("MSA", None, Vec::new());
+    };
+    FormatId::signature(
+        "MSA",
+        None,
+        vec![
+            HeaderField::new("tracks", header.tracks()),
+            HeaderField::new("sides", header.heads()),
+            HeaderField::new("sectors per track", header.sectors_per_track),
+        ],
+    )
+}
+
+/// The variant and header fields of an STX image
+fn stx_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = stx_disk_header_parser(data) else {
+        return FormatId::signature("STX", None, Vec::new());
+    };
+    FormatId::signature(
+        "STX",
+        Some(format!("version {}", header.version)),
+        vec![
+            HeaderField::new("tracks", header.track_count),
+            HeaderField::new("tool", header.tool()),
+        ],
+    )
+}
+
+/// The variant and header fields of an SCP flux image
+fn scp_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = scp_header_parser(data) else {
+        return FormatId::signature("SCP", None, Vec::new());
+    };
+    FormatId::signature(
+        "SCP",
+        Some(format!(
+            "version {}.{}",
+            header.version >> 4,
+            header.version & 0x0F
+        )),
+        vec![
+            HeaderField::new(
+                "disk type",
+                format!("{} {:02X}", header.manufacturer(), header.disk_type),
+            ),
+            HeaderField::new(
+                "tracks",
+                format!("{} to {}", header.start_track, header.end_track),
+            ),
+            HeaderField::new("revolutions", header.revolutions),
+        ],
+    )
+}
+
+/// The variant and header fields of an NES ROM
+fn nes_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = nes_header_parser(data) else {
+        return FormatId::signature("NES", None, Vec::new());
+    };
+    let variant = match header.format {
+        NESHeaderFormat::INES => "iNES",
+        NESHeaderFormat::NES20 => "NES 2.0",
+    };
+    FormatId::signature(
+        "NES",
+        Some(String::from(variant)),
+        vec![
+            HeaderField::new("mapper", header.mapper),
+            HeaderField::new("PRG ROM", format!("{}KB", header.prg_rom_size / 1024)),
+            HeaderField::new("CHR ROM", format!("{}KB", header.chr_rom_size / 1024)),
+        ],
+    )
+}
+
+/// The variant and header fields of an Atari 7800 ROM
+fn a78_id(data: &[u8]) -> FormatId {
+    let Ok((_, header)) = a78_header_parser(data) else {
+        return FormatId::signature("A78", None, Vec::new());
+    };
+    FormatId::signature(
+        "A78",
+        Some(format!("version {}", header.version)),
+        vec![
+            HeaderField::new("title", header.title),
+            HeaderField::new("ROM", format!("{}KB", header.rom_size / 1024)),
+            HeaderField::new("TV type", format!("{:?}", header.tv_type)),
+        ],
+    )
+}
+
+/// Identify the format of an image from its signature and header
+/// Only the header is read, formats without a signature are guessed
+/// from their contents.  A format with a signature but a damaged
+/// header is still identified, without any header fields.  Data that
+/// doesn't look like any known format is [UNKNOWN_FORMAT], with a
+/// confidence of zero.
+pub fn identify(data: &[u8]) -> FormatId {
+    let signatures: [Identifier; 13] = [
+        (is_woz, woz_id),
+        (is_2mg, twoimg_id),
+        (is_dc42, dc42_id),
+        (is_dsk, dsk_id),
+        (is_g64, g64_id),
+        (is_hfe, hfe_id),
+        (is_td0, td0_id),
+        (is_imd, imd_id),
+        (is_msa, msa_id),
+        (|data| data.starts_with(b"RSY\0"), stx_id),
+        (is_scp, scp_id),
+        (is_nes, nes_id),
+        (is_a78, a78_id),
+    ];
+    if let Some((_, id)) = signatures.iter().find(|(detect, _)| detect(data)) {
+        return id(data);
+    }
+
+    match best_guess(data) {
+        Some(guess) => FormatId {
+            format: guess.format,
+            variant: None,
+            confidence: guess.confidence,
+            fields: vec![HeaderField::new("evidence", guess.reasons.join("; "))],
+        },
+        None => FormatId {
+            format: UNKNOWN_FORMAT,
+            variant: None,
+            confidence: 0.0,
+            fields: Vec::new(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::identify;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::testgen;
+
+    /// Test identifying images by their headers and contents
+    #[test]
+    fn identify_works() {
+        let payload = testgen::atari_st_sectors(80, 2, 9);
+        let geometry = Geometry::uniform(80, 2, 9, 512, 0, 1);
+        let id = identify(&testgen::hfe(&payload, &geometry, 3));
+        assert_eq!(id.format, "HFE");
+        assert_eq!(id.variant.as_deref(), Some("v3"));
+        assert_eq!(id.field("tracks"), Some("80"));
+        assert_eq!(id.field("encoding"), Some("IBM MFM"));
+
+        let payload = testgen::apple_dos_33(&[("HELLO", b"data")]).unwrap();
+        let id = identify(&testgen::two_img(&payload, 0, Some(254), ""));
+        assert_eq!(
+            id.to_string(),
+            "2MG (DOS order), creator: RIDR, volume: 254, confidence: 0.99"
+        );
+
+        let id = identify(&testgen::nes(4, 8, 16, false, true));
+        assert_eq!(id.variant.as_deref(), Some("NES 2.0"));
+        assert_eq!(id.field("mapper"), Some("4"));
+
+        let payload = testgen::atari_st_sectors(80, 2, 9);
+        let tracks = split_tracks(&payload, &Geometry::atari_st(80, 2, 9));
+        let id = identify(&testgen::td0(&tracks, true, "identify"));
+        assert_eq!(id.format, "TD0");
+        assert_eq!(id.variant.as_deref(), Some("advanced compression"));
+        assert_eq!(id.field("sides"), Some("2"));
+        assert_eq!(id.field("comment"), Some("yes"));
+
+        let id = identify(&testgen::imd(&tracks, "Games disk\r\nSide A"));
+        assert_eq!(id.format, "IMD");
+        assert_eq!(id.field("version"), Some("1.18"));
+        assert_eq!(id.field("comment"), Some("Games disk"));
+
+        // Flat images are guessed from their contents
+        let id = identify(&testgen::d64("IDENTIFY", &[]).unwrap());
+        assert_eq!(id.format, "D64");
+        assert!(id.confidence < 0.99);
+        assert!(id.field("evidence").unwrap().contains("CBM BAM signature"));
+
+        // A damaged header still identifies the format
+        let id = identify(b"HXCPICFE");
+        assert_eq!(id.format, "HFE");
+        assert!(id.fields.is_empty());
+
+        let id = identify(&[0_u8; 1000]);
+        assert!(!id.is_known());
+        assert_eq!(
+            id.to_json().unwrap(),
+            r#"{"format":"unknown","variant":null,"confidence":0.0,"fields":[]}"#
+        );
+    }
+}
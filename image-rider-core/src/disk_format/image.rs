@@ -0,0 +1,2224 @@
+//! The image_rider_core::disk_format::image module provides a set of common functions
+//! and trait definitions for reading disks and cartridges.
+use config::Config;
+use log::{debug, info};
+
+use nom::branch::alt;
+use nom::combinator::map;
+use nom::IResult;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "parity")]
+use crate::disk_format::parity::{Parity, ParityOptions};
+use crate::disk_format::tree::ImageTree;
+use crate::log_target::{IO, PARSE};
+use crate::options::Options;
+use crate::{
+    disk_format::{
+        amiga::{
+            block::OFS_DATA_HEADER_SIZE,
+            disk::{adf_disk_parser, AmigaDisk},
+        },
+        apple::{
+            self,
+            disk::{
+                apple_disk_parser, nibble_dos_parser, AppleDOSDisk, AppleDisk, AppleDiskData,
+                AppleDiskGuess,
+            },
+            nibble::NibbleDisk,
+            prodos::ProDOSDisk,
+            twoimg::{is_2mg, twoimg_parser},
+            woz::{is_woz, woz_disk_parser, WozDisk},
+        },
+        archive::{identify_archive, list_archive, Archive},
+        cancel::CancellationToken,
+        carve::{carve_linked_sectors, carve_sequential_sectors, CarvedFile},
+        charset::Charset,
+        commodore::{
+            d64::{d64_disk_parser, D64Disk, D64DiskGuess},
+            g64::{g64_disk_parser, is_g64, G64Disk},
+        },
+        cpm::{dpb::CpmFormat, volume::CpmVolume},
+        device::is_read_only_source,
+        diagnose::explain_parse_failure,
+        dsk::disk::{dsk_disk_parser, is_dsk, DskDisk},
+        file_select::{DiskFile, FileMetadata, FileSelection, NamingPolicy, METADATA_EXTENSION},
+        fingerprint::{Fingerprint, FingerprintDatabase},
+        geometry::{Geometry, SectorId},
+        guess::{best_guess, LIKELY_CONFIDENCE},
+        hfe::{hfe_disk_parser, is_hfe, HfeDisk},
+        imd::{imd_disk_parser, is_imd, ImdDisk},
+        limits::{check_file_size, with_limits},
+        logical::{LogicalTrack, RawExporter, RawOrder},
+        mac::diskcopy::{dc42_disk_parser, is_dc42, DiskCopyDisk},
+        manifest::{Manifest, ManifestDiff},
+        parsed::{collect_warnings, Parsed},
+        report::{track_reports, Report},
+        sanity_check::SanityCheck,
+        search::{search_tracks, Pattern, SearchHit, TextEncoding},
+        source_map::{slice_offset, SourceMap, SourceMapper},
+        stats::Stats,
+        stx::{
+            disk::{stx_disk_parser, STXDisk, STXDiskGuess},
+            st::{
+                flat_disk_parser, is_msa, is_st_filename, msa_disk_parser, st_disk_parser, STDisk,
+            },
+            track_image::{export_track_images, TrackImageIndex},
+        },
+        summary::Summary,
+        td0::disk::{is_td0, td0_disk_parser, Td0Disk},
+        track_files::{self, TrackFormat},
+        usage::{hidden_regions, FileExtent, HiddenRegion, SectorUsage, UsageMap},
+    },
+    error::{Error, ErrorKind, InvalidErrorKind, Location},
+    init,
+};
+
+/// DiskImage is the primary enumeration for holding disk images.
+///
+/// The DiskImageParser and DiskImageSaver trait functions return and
+/// operate on this enumeration.
+///
+/// Each variant holds its disk boxed, so a DiskImage is the size of a
+/// pointer and a tag however large the disk structures are, and
+/// adding a format doesn't grow every DiskImage value.  Match on the
+/// variant to get at the disk, or use as_d64, as_stx, as_apple,
+/// as_woz, as_amiga, as_dsk, as_g64, as_st, as_dc42, as_hfe, as_td0
+/// and as_imd.
+pub enum DiskImage<'a> {
+    /// A Commodore 64 D64 Disk Image
+    D64(Box<D64Disk<'a>>),
+    /// An Atari ST STX Disk Image.
+    /// Usually the raw data in a STX disk image is a FAT12 filesystem.
+    STX(Box<STXDisk<'a>>),
+    /// An Apple ][ Disk Image There are several different encodings,
+    /// formats, and filesystems for Apple2 disks.  This includes
+    /// nibble encoding and DOS 3.x and ProDOS filesystems.
+    Apple(Box<AppleDisk<'a>>),
+    /// An Apple ][ WOZ bit stream image, with the sectors decoded from
+    /// the tracks of 5.25 inch disks
+    Woz(Box<WozDisk<'a>>),
+    /// An Amiga ADF Disk Image with an AmigaDOS OFS or FFS filesystem
+    Amiga(Box<AmigaDisk<'a>>),
+    /// A CPCEMU DSK or extended DSK Disk Image, as used by Amstrad CPC,
+    /// Spectrum +3 and MSX emulators
+    Dsk(Box<DskDisk<'a>>),
+    /// A Commodore 1541 G64 GCR bit stream image, with the sectors
+    /// decoded from the whole tracks
+    G64(Box<G64Disk<'a>>),
+    /// An Atari ST flat .st or compressed MSA Disk Image, with the
+    /// sectors decompressed into a flat image
+    ST(Box<STDisk>),
+    /// A DiskCopy 4.2 Disk Image of a Macintosh or Apple II 3.5 inch
+    /// disk, with the sector data and tags
+    DC42(Box<DiskCopyDisk<'a>>),
+    /// An HxC Floppy Emulator HFE bit stream image, with the sectors
+    /// decoded from the MFM tracks
+    HFE(Box<HfeDisk<'a>>),
+    /// A Teledisk TD0 Disk Image, decompressed if it uses advanced
+    /// compression, with the sectors as a flat image
+    TD0(Box<Td0Disk<'a>>),
+    /// An ImageDisk IMD Disk Image, with the sectors as a flat image
+    IMD(Box<ImdDisk<'a>>),
+}
+
+/// Display a DiskImage
+/// Display a multi-line summary of the disk, with the track table for
+/// STX, DSK and G64 disks and the directory listing for FAT and AmigaDOS
+/// disks, including FAT disks decoded from HFE tracks and read from
+/// TD0 and IMD images
+impl Display for DiskImage<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", Summary::new(self))?;
+        if let DiskImage::STX(stx_disk) = self {
+            write!(f, "\n{}", stx_disk.track_table())?;
+            if let Ok((volume, _)) = stx_disk.fat_volume() {
+                write!(f, "\n{}", volume)?;
+            }
+        }
+        if let Some(prodos_disk) = self.prodos_disk() {
+            write!(f, "\n{}", prodos_disk)?;
+        }
+        if let Some(amiga_disk) = self.as_amiga() {
+            write!(f, "\n{}", amiga_disk)?;
+        }
+        if let Some(dsk_disk) = self.as_dsk() {
+            write!(f, "\n{}", dsk_disk.track_table())?;
+        }
+        if let Some(g64_disk) = self.as_g64() {
+            write!(f, "\n{}", g64_disk.track_table())?;
+        }
+        if let Some(st_disk) = self.as_st() {
+            write!(f, "\n{}", st_disk)?;
+            if let Ok(volume) = st_disk.fat_volume() {
+                write!(f, "\n{}", volume)?;
+            }
+        }
+        if let Some(hfe_disk) = self.as_hfe() {
+            write!(f, "\n{}", hfe_disk)?;
+            if let Some(Ok(volume)) = hfe_disk.disk.as_ref().map(STDisk::fat_volume) {
+                write!(f, "\n{}", volume)?;
+            }
+        }
+        if let Some(td0_disk) = self.as_td0() {
+            write!(f, "\n{}\n{}", td0_disk, td0_disk.track_table())?;
+            if let Some(Ok(volume)) = td0_disk.disk.as_ref().map(STDisk::fat_volume) {
+                write!(f, "\n{}", volume)?;
+            }
+        }
+        if let Some(imd_disk) = self.as_imd() {
+            write!(f, "\n{}\n{}", imd_disk, imd_disk.track_table())?;
+            if let Some(Ok(volume)) = imd_disk.disk.as_ref().map(STDisk::fat_volume) {
+                write!(f, "\n{}", volume)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> DiskImage<'a> {
+    /// Return the D64 disk, None for other images
+    pub fn as_d64(&self) -> Option<&D64Disk<'a>> {
+        match self {
+            DiskImage::D64(d64_disk) => Some(d64_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the STX disk, None for other images
+    pub fn as_stx(&self) -> Option<&STXDisk<'a>> {
+        match self {
+            DiskImage::STX(stx_disk) => Some(stx_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the Apple disk, None for other images
+    pub fn as_apple(&self) -> Option<&AppleDisk<'a>> {
+        match self {
+            DiskImage::Apple(apple_disk) => Some(apple_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the WOZ disk, None for other images
+    pub fn as_woz(&self) -> Option<&WozDisk<'a>> {
+        match self {
+            DiskImage::Woz(woz_disk) => Some(woz_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the Amiga disk, None for other images
+    pub fn as_amiga(&self) -> Option<&AmigaDisk<'a>> {
+        match self {
+            DiskImage::Amiga(amiga_disk) => Some(amiga_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the DSK disk, None for other images
+    pub fn as_dsk(&self) -> Option<&DskDisk<'a>> {
+        match self {
+            DiskImage::Dsk(dsk_disk) => Some(dsk_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the G64 disk, None for other images
+    pub fn as_g64(&self) -> Option<&G64Disk<'a>> {
+        match self {
+            DiskImage::G64(g64_disk) => Some(g64_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the .st or MSA disk, None for other images
+    pub fn as_st(&self) -> Option<&STDisk> {
+        match self {
+            DiskImage::ST(st_disk) => Some(st_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the DiskCopy disk, None for other images
+    pub fn as_dc42(&self) -> Option<&DiskCopyDisk<'a>> {
+        match self {
+            DiskImage::DC42(dc42_disk) => Some(dc42_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the HFE disk, None for other images
+    pub fn as_hfe(&self) -> Option<&HfeDisk<'a>> {
+        match self {
+            DiskImage::HFE(hfe_disk) => Some(hfe_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the TD0 disk, None for other images
+    pub fn as_td0(&self) -> Option<&Td0Disk<'a>> {
+        match self {
+            DiskImage::TD0(td0_disk) => Some(td0_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the IMD disk, None for other images
+    pub fn as_imd(&self) -> Option<&ImdDisk<'a>> {
+        match self {
+            DiskImage::IMD(imd_disk) => Some(imd_disk),
+            _ => None,
+        }
+    }
+
+    /// Return the Apple DOS disk, None for other images
+    pub fn dos_disk(&self) -> Option<&AppleDOSDisk<'a>> {
+        self.as_apple().and_then(AppleDisk::dos_disk)
+    }
+
+    /// Return the decoded Apple nibble disk, None for other images
+    /// The sectors decoded from a WOZ image are a nibble disk too.
+    pub fn nibble_disk(&self) -> Option<&NibbleDisk> {
+        match self {
+            DiskImage::Woz(woz_disk) => Some(&woz_disk.nibble_disk),
+            _ => self.as_apple().and_then(AppleDisk::nibble_disk),
+        }
+    }
+
+    /// Return the Apple ProDOS disk, None for other images
+    pub fn prodos_disk(&self) -> Option<&ProDOSDisk<'a>> {
+        self.as_apple().and_then(AppleDisk::prodos_disk)
+    }
+
+    /// Return a tree of the volumes, directories, files and sectors on
+    /// the image, for browsing
+    pub fn tree(&'a self) -> ImageTree<'a> {
+        ImageTree::new(self)
+    }
+}
+
+impl DiskImage<'_> {
+    /// Return the type of image on one line, e.g. "D64 Disk"
+    pub fn format_name(&self) -> String {
+        match self {
+            DiskImage::D64(d64_disk) if d64_disk.is_double_sided() => String::from("D71 Disk"),
+            DiskImage::D64(_) => String::from("D64 Disk"),
+            DiskImage::STX(_) => String::from("STX Disk"),
+            DiskImage::Apple(d) => format!("Apple Disk: {}", d),
+            DiskImage::Woz(woz_disk) => format!("WOZ {}.0 Disk", woz_disk.version),
+            DiskImage::Amiga(amiga_disk) => {
+                format!("ADF Disk: {}", amiga_disk.boot_block.filesystem_name())
+            }
+            DiskImage::Dsk(dsk_disk) if dsk_disk.header.extended => {
+                String::from("Extended DSK Disk")
+            }
+            DiskImage::Dsk(_) => String::from("DSK Disk"),
+            DiskImage::G64(_) => String::from("G64 Disk"),
+            DiskImage::ST(st_disk) if st_disk.is_msa() => String::from("MSA Disk"),
+            DiskImage::ST(_) => String::from("ST Disk"),
+            DiskImage::DC42(dc42_disk) => format!("DiskCopy 4.2 Disk: {}", dc42_disk),
+            DiskImage::HFE(hfe_disk) => format!("{} Disk", hfe_disk.header.version),
+            DiskImage::TD0(_) => String::from("TD0 Disk"),
+            DiskImage::IMD(_) => String::from("IMD Disk"),
+        }
+    }
+
+    /// Return the decoded sectors on the disk, grouped by track and side
+    /// Returns None if the image type doesn't support track access
+    pub fn tracks(&self) -> Option<Vec<LogicalTrack>> {
+        disk_image_tracks(self)
+    }
+
+    /// Write one file per track and side to a directory, in a format
+    /// that hardware writers like Greaseweazle and FluxEngine can use
+    /// Returns the paths of the files written.
+    pub fn export_tracks(
+        &self,
+        dir: &Path,
+        format: TrackFormat,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<Vec<PathBuf>, Error> {
+        let tracks = self.tracks().ok_or_else(|| {
+            Error::new(ErrorKind::Unimplemented(format!(
+                "Track export not supported for {}",
+                self.format_name()
+            )))
+        })?;
+        if format != TrackFormat::Raw
+            && !matches!(
+                self,
+                DiskImage::STX(_)
+                    | DiskImage::ST(_)
+                    | DiskImage::HFE(_)
+                    | DiskImage::TD0(_)
+                    | DiskImage::IMD(_)
+            )
+        {
+            return Err(Error::new(ErrorKind::Unimplemented(String::from(
+                "MFM and flux track export is only supported for MFM disks",
+            ))));
+        }
+
+        track_files::export_tracks(&tracks, dir, format, cancel)
+    }
+
+    /// Write the raw track images of an STX disk to a directory, one
+    /// file per track and side, with an index.json of their sync
+    /// offsets and sizes
+    /// Returns the index written.
+    pub fn export_track_images(
+        &self,
+        dir: &Path,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<TrackImageIndex, Error> {
+        match self {
+            DiskImage::STX(stx_disk) => export_track_images(stx_disk, dir, cancel),
+            _ => Err(Error::new(ErrorKind::Unimplemented(format!(
+                "Track image export not supported for {}",
+                self.format_name()
+            )))),
+        }
+    }
+
+    /// Export the disk as a flat sector dump with a side order and
+    /// interleave
+    /// The disk's own geometry is used if no geometry is given.
+    pub fn export_raw(
+        &self,
+        order: &RawOrder,
+        geometry: Option<&Geometry>,
+    ) -> std::result::Result<Vec<u8>, Error> {
+        let exporter = disk_image_raw_exporter(self).ok_or_else(|| {
+            Error::new(ErrorKind::Unimplemented(format!(
+                "Raw export not supported for {}",
+                self.format_name()
+            )))
+        })?;
+        let geometry = match geometry {
+            Some(geometry) => geometry.clone(),
+            None => exporter.raw_geometry().ok_or_else(|| {
+                Error::new(ErrorKind::Message(String::from(
+                    "The disk has no sectors to export",
+                )))
+            })?,
+        };
+
+        Ok(exporter.export_raw(order, &geometry))
+    }
+
+    /// Read the disk's sectors as a CP/M volume in a format
+    /// The sectors are flattened in the disk's own geometry, with the
+    /// sides of each track next to each other, before the format's
+    /// skew is applied.
+    pub fn cpm_volume(&self, format: &CpmFormat) -> std::result::Result<CpmVolume, Error> {
+        let data = self.export_raw(&RawOrder::default(), None)?;
+        CpmVolume::parse(data, format.clone())
+    }
+
+    /// Search the disk for a pattern
+    /// Text patterns are only searched for in the encodings used on
+    /// the disk's platform.  Hits are attributed to files when the
+    /// filesystem is parsed.
+    pub fn search(&self, pattern: &Pattern) -> Vec<SearchHit> {
+        let tracks = match self.tracks() {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+        let mut needles = pattern.encodings();
+        if let Pattern::Text(_) = pattern {
+            needles.retain(|(encoding, _)| match self {
+                DiskImage::D64(_) | DiskImage::G64(_) => *encoding != TextEncoding::AppleHighAscii,
+                DiskImage::STX(_)
+                | DiskImage::ST(_)
+                | DiskImage::Amiga(_)
+                | DiskImage::Dsk(_)
+                | DiskImage::DC42(_)
+                | DiskImage::HFE(_)
+                | DiskImage::TD0(_)
+                | DiskImage::IMD(_) => *encoding == TextEncoding::Ascii,
+                DiskImage::Apple(_) | DiskImage::Woz(_) => *encoding != TextEncoding::Petscii,
+            });
+        }
+
+        search_tracks(&tracks, &needles, &disk_image_file_sectors(self))
+    }
+
+    /// Scan the sectors for recognizable files, for recovering data
+    /// from disks with a damaged catalog or directory
+    /// Files are returned most confident first.
+    pub fn carve(&self, cancel: &CancellationToken) -> std::result::Result<Vec<CarvedFile>, Error> {
+        let tracks = match self.tracks() {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+        let mut files = match self {
+            DiskImage::D64(_) | DiskImage::G64(_) => carve_linked_sectors(&tracks, cancel)?,
+            DiskImage::STX(_)
+            | DiskImage::ST(_)
+            | DiskImage::Amiga(_)
+            | DiskImage::Dsk(_)
+            | DiskImage::DC42(_)
+            | DiskImage::HFE(_)
+            | DiskImage::TD0(_)
+            | DiskImage::IMD(_) => carve_sequential_sectors(&tracks, false, cancel)?,
+            DiskImage::Apple(_) | DiskImage::Woz(_) => {
+                carve_sequential_sectors(&tracks, true, cancel)?
+            }
+        };
+        files.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+        Ok(files)
+    }
+
+    /// Build a manifest of the CRC-32 of every sector, for checking
+    /// the image for bit rot later
+    pub fn manifest(&self) -> std::result::Result<Manifest, Error> {
+        let tracks = self.tracks().ok_or_else(|| {
+            Error::new(ErrorKind::Unimplemented(format!(
+                "Manifests not supported for {}",
+                self.format_name()
+            )))
+        })?;
+
+        Ok(Manifest::from_tracks(&tracks))
+    }
+
+    /// Compare the sectors of the image with a manifest made earlier
+    pub fn verify_manifest(&self, manifest: &Manifest) -> std::result::Result<ManifestDiff, Error> {
+        Ok(manifest.verify(&self.manifest()?))
+    }
+
+    /// Generate Reed-Solomon parity data over the sectors of the image,
+    /// for repairing them later
+    #[cfg(feature = "parity")]
+    pub fn parity(
+        &self,
+        options: &ParityOptions,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<Parity, Error> {
+        let tracks = self.tracks().ok_or_else(|| {
+            Error::new(ErrorKind::Unimplemented(format!(
+                "Parity not supported for {}",
+                self.format_name()
+            )))
+        })?;
+
+        Parity::new(&tracks, options, cancel)
+    }
+
+    /// Build a report on the image, with an entropy, content and usage
+    /// map of every track and sector
+    pub fn report(&self) -> Report {
+        let usage = self.usage();
+        let mut extra_tracks: Vec<u8> = self
+            .hidden_regions()
+            .iter()
+            .filter(|region| region.usage == SectorUsage::Unmanaged)
+            .map(|region| region.id.track.get())
+            .collect();
+        extra_tracks.dedup();
+
+        Report {
+            format: self.format_name(),
+            sane: self.check(),
+            extra_tracks,
+            volumes: self.volumes(),
+            dos: match self {
+                DiskImage::Apple(apple_disk) => apple_disk.dos_image().map(|dos| dos.to_string()),
+                _ => None,
+            },
+            system: self
+                .fingerprints(&FingerprintDatabase::builtin())
+                .into_iter()
+                .map(|fingerprint| fingerprint.name)
+                .collect(),
+            archives: self.archives(),
+            creator: match self {
+                DiskImage::STX(stx_disk) => Some(stx_disk.stx_disk_header.creator()),
+                _ => None,
+            },
+            write_protected: self.write_protected(),
+            stats: self.stats(),
+            tracks: self
+                .tracks()
+                .map(|tracks| track_reports(&tracks, usage.as_ref()))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Count the sectors by size, CRC result and usage, and the files
+    /// by type
+    pub fn stats(&self) -> Stats {
+        Stats::new(self)
+    }
+
+    /// Return the fingerprints in a database that match the system
+    /// areas on the disk, e.g. a standard DOS boot sector
+    pub fn fingerprints(&self, database: &FingerprintDatabase) -> Vec<Fingerprint> {
+        self.tracks()
+            .map(|tracks| database.identify(&tracks).into_iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Return true if changes to the image can't be written back in
+    /// its format
+    /// Images decoded from track or bit stream encodings are read-only:
+    /// STX, nibble, WOZ, G64 and HFE images.  Sector images can be
+    /// saved after editing.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            DiskImage::STX(_) | DiskImage::Woz(_) | DiskImage::G64(_) | DiskImage::HFE(_)
+        ) || self.nibble_disk().is_some()
+    }
+
+    /// Return true if the image is marked write protected, so
+    /// emulators mount it read-only
+    /// WOZ images record it in the INFO chunk, 2MG images as the locked
+    /// flag and HFE images in the header.  Other formats don't record
+    /// it.
+    pub fn write_protected(&self) -> bool {
+        match self {
+            DiskImage::Woz(woz_disk) => woz_disk.info.write_protected,
+            DiskImage::Apple(apple_disk) => apple_disk
+                .two_img
+                .as_ref()
+                .is_some_and(|header| header.locked()),
+            DiskImage::HFE(hfe_disk) => !hfe_disk.header.write_allowed,
+            _ => false,
+        }
+    }
+
+    /// Return true if images converted from this one should be marked
+    /// write protected
+    /// The write-protected setting is used if it's set, otherwise the
+    /// protection of this image is carried over.
+    pub fn converted_write_protected(&self, config: &Config) -> bool {
+        config
+            .get_bool("write-protected")
+            .unwrap_or_else(|_| self.write_protected())
+    }
+
+    /// Return the volume label of the disk, for grouping images in a
+    /// collection
+    /// Commodore disks use the disk name and the two character ID,
+    /// Apple DOS disks the volume number and Amiga disks the volume
+    /// name.
+    pub fn label(&self) -> Option<String> {
+        match self {
+            DiskImage::D64(d64_disk) => Some(d64_disk.label()),
+            DiskImage::G64(g64_disk) => g64_disk.label(),
+            DiskImage::STX(stx_disk) => stx_disk
+                .fat_volume()
+                .ok()
+                .and_then(|(volume, _)| volume.label()),
+            DiskImage::ST(st_disk) => st_disk.fat_volume().ok().and_then(|volume| volume.label()),
+            DiskImage::Apple(apple_disk) => match &apple_disk.data {
+                AppleDiskData::DOS(dos_disk) => Some(format!(
+                    "DOS volume {}",
+                    dos_disk.volume_table_of_contents.diskette_volume_number
+                )),
+                AppleDiskData::ProDOS(prodos_disk) => Some(format!("/{}", prodos_disk.header.name)),
+                AppleDiskData::Nibble(_) => None,
+            },
+            DiskImage::Woz(_) | DiskImage::Dsk(_) => None,
+            DiskImage::Amiga(amiga_disk) => Some(amiga_disk.label()),
+            DiskImage::DC42(dc42_disk) => Some(dc42_disk.header.name.clone()),
+            DiskImage::HFE(hfe_disk) => hfe_disk
+                .disk
+                .as_ref()
+                .and_then(|disk| disk.fat_volume().ok())
+                .and_then(|volume| volume.label()),
+            DiskImage::TD0(td0_disk) => td0_disk
+                .disk
+                .as_ref()
+                .and_then(|disk| disk.fat_volume().ok())
+                .and_then(|volume| volume.label()),
+            DiskImage::IMD(imd_disk) => imd_disk
+                .disk
+                .as_ref()
+                .and_then(|disk| disk.fat_volume().ok())
+                .and_then(|volume| volume.label()),
+        }
+    }
+
+    /// Return the character set file names on the disk are shown in
+    pub fn charset(&self) -> Charset {
+        match self {
+            DiskImage::D64(d64_disk) => d64_disk.charset,
+            DiskImage::G64(g64_disk) => g64_disk.charset,
+            DiskImage::Apple(apple_disk) => match &apple_disk.data {
+                AppleDiskData::DOS(dos_disk) => dos_disk.charset,
+                _ => Charset::DEFAULT,
+            },
+            _ => Charset::DEFAULT,
+        }
+    }
+
+    /// Set the character set file names on the disk are shown in
+    /// Only Commodore and Apple DOS disks have 8-bit file names.
+    pub fn set_charset(&mut self, charset: Charset) {
+        match self {
+            DiskImage::D64(d64_disk) => d64_disk.charset = charset,
+            DiskImage::G64(g64_disk) => g64_disk.charset = charset,
+            DiskImage::Apple(apple_disk) => {
+                if let AppleDiskData::DOS(dos_disk) = &mut apple_disk.data {
+                    dos_disk.charset = charset;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Mark the filesystem structures on the disk read-only, so
+    /// allocating and freeing sectors fails with ErrorKind::ReadOnly
+    pub fn set_read_only(&mut self, read_only: bool) {
+        match self {
+            DiskImage::D64(d64_disk) => d64_disk.bam.read_only = read_only,
+            DiskImage::Apple(apple_disk) => {
+                if let AppleDiskData::DOS(dos_disk) = &mut apple_disk.data {
+                    dos_disk.volume_table_of_contents.read_only = read_only;
+                }
+            }
+            DiskImage::ST(st_disk) => st_disk.read_only = read_only,
+            DiskImage::HFE(hfe_disk) => {
+                if let Some(disk) = &mut hfe_disk.disk {
+                    disk.read_only = read_only;
+                }
+            }
+            DiskImage::TD0(td0_disk) => {
+                if let Some(disk) = &mut td0_disk.disk {
+                    disk.read_only = read_only;
+                }
+            }
+            DiskImage::IMD(imd_disk) => {
+                if let Some(disk) = &mut imd_disk.disk {
+                    disk.read_only = read_only;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Return the volume numbers of the disk
+    /// Only Apple disks have volume numbers, a nibble dump can hold
+    /// sectors from more than one volume.
+    pub fn volumes(&self) -> Vec<u8> {
+        match self {
+            DiskImage::Apple(apple_disk) => apple_disk.volumes(),
+            DiskImage::Woz(woz_disk) => woz_disk.nibble_disk.volume_numbers(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Find the files on the disk that are archives and list their
+    /// contents, by file name
+    pub fn archives(&self) -> BTreeMap<String, Archive> {
+        disk_image_file_data(self)
+            .into_iter()
+            .filter_map(|(name, data)| {
+                // Apple DOS binary files start with a load address and
+                // length
+                let data = match (self, identify_archive(&data)) {
+                    (DiskImage::Apple(_), None) => data.get(4..)?,
+                    _ => &data[..],
+                };
+                list_archive(data, &self.charset())
+                    .ok()
+                    .map(|archive| (name, archive))
+            })
+            .collect()
+    }
+
+    /// Build a map of how every sector is used, combining the
+    /// allocation bitmap with the sectors referenced by the catalog
+    /// Returns None if the image has no parsed filesystem
+    pub fn usage(&self) -> Option<UsageMap> {
+        disk_image_usage(self)
+    }
+
+    /// Find data hidden in allocated but unreferenced sectors and in the
+    /// slack space after the end of files
+    pub fn hidden_regions(&self) -> Vec<HiddenRegion> {
+        match (self.tracks(), self.usage()) {
+            (Some(tracks), Some(usage)) => {
+                hidden_regions(&tracks, &usage, &disk_image_file_extents(self))
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Run the sanity checks for the structures in a disk image
+impl SanityCheck for DiskImage<'_> {
+    fn check(&self) -> bool {
+        match self {
+            DiskImage::D64(d64_disk) => d64_disk.bam.check(),
+            DiskImage::STX(stx_disk) => {
+                stx_disk.stx_disk_header.check()
+                    && stx_disk.stx_tracks.iter().all(|t| t.header.check())
+            }
+            DiskImage::Apple(apple_disk) => match &apple_disk.data {
+                AppleDiskData::DOS(dos_disk) => dos_disk.volume_table_of_contents.check(),
+                _ => true,
+            },
+            DiskImage::Woz(woz_disk) => woz_disk.info.check(),
+            DiskImage::Amiga(amiga_disk) => amiga_disk.check(),
+            DiskImage::Dsk(dsk_disk) => dsk_disk.check(),
+            DiskImage::G64(g64_disk) => g64_disk.check(),
+            DiskImage::ST(st_disk) => st_disk.check(),
+            DiskImage::DC42(dc42_disk) => dc42_disk.check(),
+            DiskImage::HFE(hfe_disk) => hfe_disk.check(),
+            DiskImage::TD0(td0_disk) => td0_disk.check(),
+            DiskImage::IMD(imd_disk) => imd_disk.check(),
+        }
+    }
+}
+
+/// A trait for disk or ROM image parsers
+/// New image guessers should implement this trait
+/// It's also implemented for &[u8]
+///
+/// The disk parsing and the disk image access (data, saving, etc.)
+/// functions have been moved into separate traits.  This supports the
+/// new data flow in the library.
+/// It's assumed that image type is guessed from raw image data and a
+/// DiskImageGuess structure is created.
+/// From this structure, an image can be parsed.
+///
+/// It allows easy guiding of the parsing from the command line,
+/// just specify the file type on the command line, along with guesses on
+/// things like directory table locations and an DiskImageGuess can be generated.
+///
+/// The DiskImageParser trait and DiskImageSaver trait are the primary
+/// API access points to the image-rider crate.  Application
+/// developers should use these two traits to load and store data in
+/// their application.
+///
+/// The individual DiskImage structures provide additional fields for
+/// users familiar with a specific image type.
+pub trait DiskImageParser<'a, 'b> {
+    /// This function parses an entire disk, returning a DiskImage
+    ///
+    /// # Arguments
+    ///
+    /// - `config` - The Options that guide parsing.
+    /// - `filename` - The name of the file to parse.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the DiskImage and any warnings found while
+    /// parsing it, like ignored checksums, or an Error.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// // Start of setup code
+    /// use std::path::Path;
+    /// use std::io::Read;
+    /// use std::fs::{File, OpenOptions};
+    /// use config::Config;
+    /// use image_rider_core::disk_format::image::DiskImageParser;
+    /// use image_rider_core::options::Options;
+    /// let filename = "parse_disk_image-tmpfile-1234.img";
+    /// let path = Path::new(&filename);
+    /// let mut file = OpenOptions::new()
+    ///     .create(true)
+    ///     .write(true)
+    ///     .open(path)
+    ///     .unwrap_or_else(|e| {
+    ///         panic!("Couldn't open file: {}", e);
+    ///     });
+    /// let data: Vec<u8> = Vec::new();
+    /// let settings = Options::new(Config::builder().build().unwrap());
+    /// // End of the setup code
+    ///
+    /// // The main method call
+    /// let result = data.parse_disk_image(&settings, &filename);
+    /// if let Ok(disk_image) = result {
+    ///     println!("Successful parse");
+    ///     for warning in &disk_image.warnings {
+    ///         println!("Warning: {}", warning);
+    ///     }
+    /// }
+    ///
+    /// // Teardown code
+    /// std::fs::remove_file(filename).unwrap_or_else(|e| {
+    ///         panic!("Error removing test file: {}", e);
+    /// });
+    ///
+    /// ```
+    fn parse_disk_image(
+        &'a self,
+        config: &'b Options,
+        filename: &str,
+    ) -> std::result::Result<Parsed<DiskImage<'a>>, Error>;
+}
+
+/// Test trait for getting parsing and ownership transferral working
+/// with DiskImageGuess
+pub trait TestParser<'a, 'b> {
+    /// Parse an entire disk, returning a DiskImage.
+    ///
+    /// # Arguments
+    ///
+    /// - `config` - The Options that guide parsing.
+    /// - `filename` - The name of the file to parse.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the DiskImage or an error message.
+    ///
+    fn parse_disk_image(
+        self,
+        config: &'b Options,
+        filename: &str,
+    ) -> std::result::Result<DiskImage<'a>, Error>;
+}
+
+/// This trait provides convenient functions for getting and saving
+/// data for the parsed disk image data in a DiskImage
+pub trait DiskImageSaver {
+    // Return the primary data contents of a disk image
+    // The meaning of the data contents will differ between image formats, but
+    // it's usually all the volume, track, and sector data, or the enclosed file format
+    // if the outer image is a wrapper
+    // fn disk_image_data(&self, config: &Config) -> Vec<&[u8]>;
+
+    /// Save the primary data contents of a disk image to disk
+    /// The meaning of the data contents will differ between image formats, but
+    /// it's usually all the volume, track, and sector data, or the enclosed file format
+    /// if the outer image is a wrapper.
+    /// This function parses an entire disk, returning a DiskImage.
+    ///
+    /// # Arguments
+    ///
+    /// - `config` - A Config object that contains information to guide parsing.
+    /// - `filename` - The name of the file to parse.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// // Start of setup code
+    /// use std::path::Path;
+    /// use std::io::Read;
+    /// use std::fs::{File, OpenOptions};
+    /// use config::Config;
+    /// use image_rider_core::disk_format::image::{DiskImageParser, DiskImageSaver};
+    /// use image_rider_core::options::Options;
+    /// let filename = "parse_disk_image-tmpfile-1234.img";
+    /// let path = Path::new(&filename);
+    /// let mut file = OpenOptions::new()
+    ///     .create(true)
+    ///     .write(true)
+    ///     .open(path)
+    ///     .unwrap_or_else(|e| {
+    ///         panic!("Couldn't open file: {}", e);
+    ///     });
+    /// let data: Vec<u8> = Vec::new();
+    /// let settings = Options::new(Config::builder().build().unwrap());
+    /// // End of the setup code
+    ///
+    /// // The main method call
+    /// let result = data.parse_disk_image(&settings, &filename);
+    /// let tmp_out_filename = "parse_disk_image-tmpfile-out-1234.img";
+    /// if let Ok(disk_image) = result {
+    ///     println!("Successful parse");
+    ///     // Save the data
+    ///     disk_image.save_disk_image(&settings, None, tmp_out_filename);
+    /// }
+    ///
+    /// // Teardown code
+    /// std::fs::remove_file(tmp_out_filename).unwrap_or_else(|e| {
+    ///         println!("Error removing test file: {}", e);
+    /// });
+    /// std::fs::remove_file(filename).unwrap_or_else(|e| {
+    ///         println!("Error removing test file: {}", e);
+    /// });
+    ///
+    /// ```
+    fn save_disk_image(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        filename: &str,
+    ) -> std::result::Result<(), crate::error::Error> {
+        let data = self.to_bytes(config, selected_filename)?;
+        fs::write(filename, data)?;
+        Ok(())
+    }
+
+    /// Write the primary data contents of a disk image to a writer
+    /// This is the data save_disk_image writes to a file.
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error>;
+
+    /// Return the files on the disk, for saving more than one file
+    /// Disks without a parsed filesystem have no files.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        Vec::new()
+    }
+
+    /// Save the files matching a selection to a directory
+    /// Each file is named with the naming policy, which also sets what
+    /// happens when two files get the same name and whether a metadata
+    /// file is written next to each file.
+    /// Returns the paths of the files written, not counting metadata
+    /// files.
+    fn save_files(
+        &self,
+        _config: &Config,
+        selection: &FileSelection,
+        dir: &Path,
+        naming: &NamingPolicy,
+    ) -> std::result::Result<Vec<PathBuf>, crate::error::Error> {
+        fs::create_dir_all(dir)?;
+        let mut namer = naming.namer();
+        let mut paths = Vec::new();
+        for file in self.disk_files() {
+            if !selection.matches(&file.name) {
+                continue;
+            }
+            let name = namer.name(&file)?;
+            let path = dir.join(&name);
+            info!(target: IO, "Saving {} to {}", file.name, path.display());
+            fs::write(&path, &file.data)?;
+            if naming.sidecar {
+                let metadata = FileMetadata::new(&file).to_json()?;
+                fs::write(
+                    dir.join(format!("{}.{}", name, METADATA_EXTENSION)),
+                    metadata,
+                )?;
+            }
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// Return the primary data contents of a disk image
+    /// This is the data save_disk_image writes to a file.
+    fn to_bytes(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+    ) -> std::result::Result<Vec<u8>, crate::error::Error> {
+        let mut data = Vec::new();
+        self.save_to_writer(config, selected_filename, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// The result of heuristics to guess a disk image
+/// Certain disk images can be guessed accurately based on filenames
+/// This returns a guess that can be used to guide the parsing process
+/// Later versions can include a parser generator trait that returns the recommended
+/// parser
+/// The DiskImageGuess structures should have a field that contains
+/// the raw image data When A DiskImageGuess is created, it becomes
+/// the new owner of the image data
+pub enum DiskImageGuess<'a> {
+    /// A Commodore D64 Disk Image
+    D64(D64DiskGuess<'a>),
+    /// An Atari ST STX Disk Image
+    STX(STXDiskGuess<'a>),
+    /// An Apple ][ Disk Image
+    Apple(AppleDiskGuess<'a>),
+}
+
+/// Display a DiskImageGuess
+impl<'a> Display for DiskImageGuess<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            DiskImageGuess::D64(_) => write!(f, "D64 Disk"),
+            DiskImageGuess::STX(_) => write!(f, "STX Disk"),
+            DiskImageGuess::Apple(d) => write!(f, "Apple Disk: {}", d),
+        }
+    }
+}
+
+/// Implement a parser for a DiskImageGuess
+/// The intention is that the DiskImage owns the raw data afterwards
+impl<'a, 'b> TestParser<'a, 'b> for DiskImageGuess<'a> {
+    fn parse_disk_image(
+        self,
+        config: &'b Options,
+        _filename: &str,
+    ) -> std::result::Result<DiskImage<'a>, crate::error::Error> {
+        // Initialize the image-rider module
+        init();
+
+        match self {
+            DiskImageGuess::D64(_) => Err(Error::new(ErrorKind::Unimplemented(String::from(
+                "Error parsing image from guess",
+            )))),
+            DiskImageGuess::STX(_) => Err(Error::new(ErrorKind::Unimplemented(String::from(
+                "Error parsing image from guess",
+            )))),
+            DiskImageGuess::Apple(guess) => {
+                let parser_result = apple_disk_parser(guess, config);
+                match parser_result {
+                    Ok(res) => Ok(DiskImage::Apple(Box::new(res.1))),
+                    Err(e) => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                        nom::Err::Error(e).to_string(),
+                    )))),
+                }
+            }
+        }
+    }
+}
+
+impl DiskImageSaver for DiskImage<'_> {
+    /// Save the disk image data to a file
+    /// Apple DOS 3.3 files are saved by the DOS disk, which can unwrap
+    /// archives into a directory.
+    fn save_disk_image(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        filename: &str,
+    ) -> std::result::Result<(), crate::error::Error> {
+        if let Some(dos_image) = self.dos_disk() {
+            info!(target: IO, "Saving DOS 3.3 file");
+            return dos_image.save_disk_image(config, selected_filename, filename);
+        }
+        let data = self.to_bytes(config, selected_filename)?;
+        fs::write(filename, data)?;
+        Ok(())
+    }
+
+    fn disk_files(&self) -> Vec<DiskFile> {
+        match self {
+            DiskImage::D64(d64_disk) => d64_disk.disk_files(),
+            DiskImage::STX(stx_disk) => stx_disk.disk_files(),
+            DiskImage::Apple(apple_disk) => match &apple_disk.data {
+                AppleDiskData::DOS(dos_disk) => dos_disk.disk_files(),
+                AppleDiskData::ProDOS(prodos_disk) => prodos_disk.disk_files(),
+                AppleDiskData::Nibble(_) => Vec::new(),
+            },
+            DiskImage::Woz(_) => Vec::new(),
+            DiskImage::Amiga(amiga_disk) => amiga_disk.disk_files(),
+            DiskImage::Dsk(dsk_disk) => dsk_disk.disk_files(),
+            DiskImage::G64(g64_disk) => g64_disk.disk_files(),
+            DiskImage::ST(st_disk) => st_disk.disk_files(),
+            DiskImage::DC42(dc42_disk) => dc42_disk.disk_files(),
+            DiskImage::HFE(hfe_disk) => hfe_disk.disk_files(),
+            DiskImage::TD0(td0_disk) => td0_disk.disk_files(),
+            DiskImage::IMD(imd_disk) => imd_disk.disk_files(),
+        }
+    }
+
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error> {
+        match self {
+            DiskImage::D64(d64_image) => {
+                d64_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::STX(image_data) => {
+                image_data.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::Apple(apple_image) => match &apple_image.data {
+                AppleDiskData::Nibble(nibble_image) => {
+                    nibble_image.save_to_writer(config, None, writer)
+                }
+                AppleDiskData::DOS(dos_image) => {
+                    dos_image.save_to_writer(config, selected_filename, writer)
+                }
+                AppleDiskData::ProDOS(prodos_image) => {
+                    prodos_image.save_to_writer(config, selected_filename, writer)
+                }
+            },
+            DiskImage::Woz(woz_image) => woz_image.nibble_disk.save_to_writer(config, None, writer),
+            DiskImage::Amiga(amiga_image) => {
+                amiga_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::Dsk(dsk_image) => {
+                dsk_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::G64(g64_image) => {
+                g64_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::ST(st_image) => st_image.save_to_writer(config, selected_filename, writer),
+            DiskImage::DC42(dc42_image) => {
+                dc42_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::HFE(hfe_image) => {
+                hfe_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::TD0(td0_image) => {
+                td0_image.save_to_writer(config, selected_filename, writer)
+            }
+            DiskImage::IMD(imd_image) => {
+                imd_image.save_to_writer(config, selected_filename, writer)
+            }
+        }
+    }
+}
+
+/// Saving a parse result refuses to save a read-only image as a whole
+/// Files can still be saved from it.  The DiskImage saver writes the
+/// sector data of any image, for converting it.
+impl DiskImageSaver for Parsed<DiskImage<'_>> {
+    fn save_disk_image(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        filename: &str,
+    ) -> std::result::Result<(), crate::error::Error> {
+        self.check_writable(selected_filename)?;
+        self.value
+            .save_disk_image(config, selected_filename, filename)
+    }
+
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.value.disk_files()
+    }
+
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error> {
+        self.check_writable(selected_filename)?;
+        self.value.save_to_writer(config, selected_filename, writer)
+    }
+}
+
+impl Parsed<DiskImage<'_>> {
+    /// Return an error if the whole image is being saved and it's
+    /// read-only
+    fn check_writable(&self, selected_filename: Option<&str>) -> std::result::Result<(), Error> {
+        if self.read_only && selected_filename.is_none() {
+            return Err(Error::new(ErrorKind::ReadOnly(self.value.format_name())));
+        }
+        Ok(())
+    }
+}
+
+/// Parses a file given a filename, returning a DiskImage
+pub fn file_parser<'a>(
+    filename: &str,
+    data: &'a [u8],
+    config: &Options,
+) -> IResult<&'a [u8], DiskImage<'a>> {
+    let guess_image_type = format_from_filename_and_data(filename, data);
+
+    debug!(
+        target: PARSE,
+        "config ignore-checksums: {:?}",
+        config.get_bool("ignore-checksums")
+    );
+
+    // WOZ images have a signature, but their tracks may hold a DOS or
+    // ProDOS disk the Apple guesses would find
+    if is_woz(data) {
+        debug!(target: PARSE, "Attempting to parse WOZ disk");
+        let (i, woz_disk) = woz_disk_parser(config)(data)?;
+        return Ok((i, DiskImage::Woz(Box::new(woz_disk))));
+    }
+
+    // 2MG images wrap a DOS order, ProDOS order or nibble image, which
+    // is parsed by the parser for that format
+    if is_2mg(data) {
+        debug!(target: PARSE, "Attempting to parse 2MG disk");
+        let (i, two_img) = twoimg_parser(data)?;
+        let (_, mut apple_disk) = apple_disk_parser(two_img.guess(), config)?;
+        apple_disk.two_img = Some(two_img.header);
+        return Ok((i, DiskImage::Apple(Box::new(apple_disk))));
+    }
+
+    // DiskCopy images have no signature, but the sizes in the header
+    // have to add up to the size of the file
+    if is_dc42(data) {
+        debug!(target: PARSE, "Attempting to parse DiskCopy 4.2 disk");
+        let (i, dc42_disk) = dc42_disk_parser(config)(data)?;
+        return Ok((i, DiskImage::DC42(Box::new(dc42_disk))));
+    }
+
+    // CPC and Spectrum DSK images share the .dsk extension with Apple
+    // DOS order images, so check their signature first
+    if is_dsk(data) {
+        debug!(target: PARSE, "Attempting to parse DSK disk");
+        let (i, dsk_disk) = dsk_disk_parser(data)?;
+        return Ok((i, DiskImage::Dsk(Box::new(dsk_disk))));
+    }
+
+    if is_g64(data) {
+        debug!(target: PARSE, "Attempting to parse G64 disk");
+        let (i, g64_disk) = g64_disk_parser(data)?;
+        return Ok((i, DiskImage::G64(Box::new(g64_disk))));
+    }
+
+    if is_hfe(data) {
+        debug!(target: PARSE, "Attempting to parse HFE disk");
+        let (i, hfe_disk) = hfe_disk_parser(data)?;
+        return Ok((i, DiskImage::HFE(Box::new(hfe_disk))));
+    }
+
+    if is_td0(data) {
+        debug!(target: PARSE, "Attempting to parse TD0 disk");
+        let (i, td0_disk) = td0_disk_parser(config)(data)?;
+        return Ok((i, DiskImage::TD0(Box::new(td0_disk))));
+    }
+
+    if is_imd(data) {
+        debug!(target: PARSE, "Attempting to parse IMD disk");
+        let (i, imd_disk) = imd_disk_parser(data)?;
+        return Ok((i, DiskImage::IMD(Box::new(imd_disk))));
+    }
+
+    if is_msa(data) {
+        debug!(target: PARSE, "Attempting to parse MSA disk");
+        let (i, st_disk) = msa_disk_parser(data)?;
+        return Ok((i, DiskImage::ST(Box::new(st_disk))));
+    }
+
+    // A geometry given in the settings is trusted over the name and
+    // size of a headerless image
+    if let Ok(Some(geometry)) = Geometry::from_config(config) {
+        debug!(target: PARSE, "Attempting to parse flat disk with geometry from the settings");
+        let (i, st_disk) = flat_disk_parser(geometry)(data)?;
+        return Ok((i, DiskImage::ST(Box::new(st_disk))));
+    }
+
+    // Flat Atari ST images have no signature, so they're only read
+    // when they're named .st
+    if is_st_filename(filename) {
+        debug!(target: PARSE, "Attempting to parse ST disk");
+        let (i, st_disk) = st_disk_parser(data)?;
+        return Ok((i, DiskImage::ST(Box::new(st_disk))));
+    }
+
+    // Unnamed raw dumps of FAT volumes are read as flat images when
+    // their contents make it likely
+    if guess_image_type.is_none()
+        && best_guess(data)
+            .is_some_and(|guess| guess.format == "ST" && guess.confidence >= LIKELY_CONFIDENCE)
+    {
+        debug!(target: PARSE, "Attempting to parse guessed ST disk");
+        let (i, st_disk) = st_disk_parser(data)?;
+        return Ok((i, DiskImage::ST(Box::new(st_disk))));
+    }
+
+    match guess_image_type {
+        Some(i) => match i {
+            DiskImageGuess::Apple(guess) => {
+                // Before this can be refactored to the
+                // DiskImageParser trait, the code needs to be
+                // rewritten to transfer ownership from
+                // the DiskImageGuess to the DiskImage
+                debug!(target: PARSE, "Attempting to parse Apple disk");
+                let res = apple_disk_parser(guess, config)?;
+                Ok((res.0, DiskImage::Apple(Box::new(res.1))))
+            }
+            _ => panic!("Exiting"),
+        },
+        None => disk_image_parser(data),
+    }
+}
+
+/// Parse a disk image
+/// This attempts to parse the different file types supported by this library
+/// It returns the remaining input and a DiskImage
+pub fn disk_image_parser(i: &[u8]) -> IResult<&[u8], DiskImage<'_>> {
+    // Assume the alt parser is greedy and checks the next parser on the first error
+    alt((
+        map(d64_disk_parser, |d64_disk| {
+            DiskImage::D64(Box::new(d64_disk))
+        }),
+        map(adf_disk_parser, |amiga_disk| {
+            DiskImage::Amiga(Box::new(amiga_disk))
+        }),
+        map(dsk_disk_parser, |dsk_disk| {
+            DiskImage::Dsk(Box::new(dsk_disk))
+        }),
+        map(stx_disk_parser, |stx_disk| {
+            DiskImage::STX(Box::new(stx_disk))
+        }),
+    ))(i)
+}
+
+// Implementation of DiskImageParser for references to 8-bit integer arrays
+// impl<'a, 'b> DiskImageParser<'a, 'b> for &[u8] {
+//     fn parse_disk_image(
+//         self,
+//         config: &'b Options,
+//         filename: &str,
+//     ) -> IResult<&'a [u8], DiskImage<'a>> {
+//         file_parser(filename, self, config)
+//     }
+// }
+
+/// Implementation of DiskImageParser for 8-bit integer vectors
+impl<'a, 'b> DiskImageParser<'a, 'b> for Vec<u8> {
+    fn parse_disk_image(
+        &'a self,
+        config: &'b Options,
+        filename: &str,
+    ) -> std::result::Result<Parsed<DiskImage<'a>>, Error> {
+        // Initialize the image-rider module
+        init();
+
+        with_limits(config.limits(), || {
+            check_file_size(self.len())?;
+            // Check a geometry given in the settings before trying any parser
+            Geometry::from_config(config)?;
+
+            let mut parsed = collect_warnings(|| match file_parser(filename, self, config) {
+                Ok(res) => Ok(res.1),
+                Err(e) => {
+                    let guessed = format_from_filename_and_data(filename, self).is_some();
+                    Err(explain_parse_failure(config, self, filename, guessed, e))
+                }
+            })?;
+            parsed.value.set_charset(config.charset());
+            let read_only = config.get_bool("read-only").unwrap_or(false)
+                || parsed.value.is_read_only()
+                || is_read_only_source(Path::new(filename));
+            parsed.value.set_read_only(read_only);
+            let source_map = parsed.value.source_map(self);
+            Ok(parsed.with_source_map(source_map).with_read_only(read_only))
+        })
+    }
+}
+
+/// Find where in an image a parser failed
+/// The byte offset comes from the input remaining at the failure,
+/// flat images also get the track and sector.  Returns None if the
+/// parser failed on data that isn't part of the image, e.g. decoded
+/// nibble data.
+pub fn nom_error_location(
+    data: &[u8],
+    e: &nom::Err<nom::error::Error<&[u8]>>,
+    filename: &str,
+) -> Option<Location> {
+    let input = match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.input,
+        nom::Err::Incomplete(_) => return None,
+    };
+    let offset = slice_offset(data, input)?;
+
+    let mut location = Location::offset(offset);
+    if let Some(guess) = format_from_filename_and_data(filename, data) {
+        location = location.with_format(&guess.to_string());
+    }
+    if let Some(id) =
+        Geometry::from_size(data.len()).and_then(|geometry| geometry.sector_at(offset))
+    {
+        location.track = Some(id.track.get());
+        location.head = Some(id.head.get());
+        location.sector = Some(id.sector.get());
+    }
+
+    Some(location)
+}
+
+/// Guess an image format from a filename.  Builds and returns a
+/// DiskImageGuess for a given filename and file data.
+///
+/// # Arguments
+///
+/// - `filename` - The name of the file to generate a guess for.
+/// - `data` - The disk image data as a reference to a byte array.
+///
+/// # Returns
+///
+/// An Option containing the DiskImageGuess
+pub fn format_from_filename_and_data<'a>(
+    filename: &str,
+    data: &'a [u8],
+) -> Option<DiskImageGuess<'a>> {
+    // TODO: format_from_filename should be defined by a trait, and
+    // each module should expose a type that implements that trait
+    let apple_res = apple::disk::format_from_filename_and_data(filename, data);
+    let apple_res = if apple_res.is_none() {
+        // Try using the magic number to identify the file
+        let res = apple::disk::format_from_data(data);
+        match res {
+            Err(_) => {
+                info!(target: PARSE, "Couldn't detect disk type");
+                None
+            }
+            Ok(s) => s,
+        }
+    } else {
+        apple_res
+    };
+
+    apple_res.map(DiskImageGuess::Apple)
+    // match apple_res {
+    //     None => None,
+    //     Some(res) => Some(DiskImageGuess::Apple(res)),
+    // }
+}
+
+/// Function to collect the actual disk image data from a disk image and return
+/// it as an Option<Vec<u8>>
+/// It should have more tests around the different disk types
+pub fn disk_image_data(disk_image: &DiskImage) -> Option<Vec<u8>> {
+    match disk_image {
+        DiskImage::STX(image_data) => {
+            // It may be more efficient to return sector-size &[u8] iterators
+            Some(
+                image_data
+                    .stx_tracks
+                    .iter()
+                    .filter(|s| s.sector_data.is_some())
+                    .flat_map(|s| s.sector_data.as_ref().unwrap().iter())
+                    .flat_map(|bytes| (*bytes).iter())
+                    .copied()
+                    .collect(),
+            )
+        }
+        _ => {
+            info!(target: IO, "Unsupported image for file saving");
+            None
+        }
+    }
+}
+
+/// Collect the decoded sectors from a disk image, grouped by track
+/// and side
+/// Returns None if the image type doesn't support track access
+pub fn disk_image_tracks(disk_image: &DiskImage) -> Option<Vec<LogicalTrack>> {
+    disk_image_raw_exporter(disk_image).map(|exporter| exporter.logical_tracks())
+}
+
+/// Return the sectors of a nibble encoded Apple disk as a DOS 3.3
+/// order image, or a 13 sector image for DOS 3.2 disks
+/// Nibble disks are parsed as encoded sectors, parse the returned data
+/// with parse_nibble_dos_image to read the DOS catalog and files.
+/// Returns None for other images.
+pub fn disk_image_nibble_dos_data(disk_image: &DiskImage) -> Option<Vec<u8>> {
+    disk_image.nibble_disk()?.dos_order_image()
+}
+
+/// Parse the DOS 3.2 or 3.3 filesystem on a nibble encoded Apple disk
+/// from the data returned by disk_image_nibble_dos_data
+/// The image has no source map, the offsets of the decoded sectors
+/// don't match the nibble image.
+pub fn parse_nibble_dos_image(data: &[u8]) -> std::result::Result<Parsed<DiskImage<'_>>, Error> {
+    collect_warnings(|| match nibble_dos_parser(data) {
+        Ok((_, apple_disk)) => Ok(DiskImage::Apple(Box::new(apple_disk))),
+        Err(e) => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!("No DOS filesystem on the nibble disk: {}", e),
+        )))),
+    })
+}
+
+/// The source map of whichever disk the image holds, sorted by offset
+impl SourceMapper for DiskImage<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut source_map = match self {
+            DiskImage::D64(d64_disk) => d64_disk.source_map(data),
+            DiskImage::STX(stx_disk) => stx_disk.source_map(data),
+            DiskImage::Apple(apple_disk) => apple_disk.source_map(data),
+            DiskImage::Woz(woz_disk) => woz_disk.source_map(data),
+            DiskImage::Amiga(amiga_disk) => amiga_disk.source_map(data),
+            DiskImage::Dsk(dsk_disk) => dsk_disk.source_map(data),
+            DiskImage::G64(g64_disk) => g64_disk.source_map(data),
+            DiskImage::ST(st_disk) => st_disk.source_map(data),
+            DiskImage::DC42(dc42_disk) => dc42_disk.source_map(data),
+            DiskImage::HFE(hfe_disk) => hfe_disk.source_map(data),
+            DiskImage::TD0(td0_disk) => td0_disk.source_map(data),
+            DiskImage::IMD(imd_disk) => imd_disk.source_map(data),
+        };
+        source_map.sort();
+        source_map
+    }
+}
+
+/// Return the raw sector exporter for a disk image
+/// Returns None if the image type doesn't support track access
+fn disk_image_raw_exporter<'a>(disk_image: &'a DiskImage) -> Option<&'a dyn RawExporter> {
+    match disk_image {
+        DiskImage::D64(d64_disk) => Some(d64_disk.as_ref()),
+        DiskImage::STX(stx_disk) => Some(stx_disk.as_ref()),
+        DiskImage::Apple(apple_disk) => match &apple_disk.data {
+            AppleDiskData::DOS(dos_disk) => Some(dos_disk),
+            AppleDiskData::ProDOS(prodos_disk) => Some(prodos_disk),
+            AppleDiskData::Nibble(nibble_disk) => Some(nibble_disk),
+        },
+        DiskImage::Woz(woz_disk) => Some(&woz_disk.nibble_disk),
+        DiskImage::Amiga(amiga_disk) => Some(amiga_disk.as_ref()),
+        DiskImage::Dsk(dsk_disk) => Some(dsk_disk.as_ref()),
+        DiskImage::G64(g64_disk) => Some(g64_disk.as_ref()),
+        DiskImage::ST(st_disk) => Some(st_disk.as_ref()),
+        DiskImage::DC42(dc42_disk) => Some(dc42_disk.as_ref()),
+        DiskImage::HFE(hfe_disk) => Some(hfe_disk.as_ref()),
+        DiskImage::TD0(td0_disk) => Some(td0_disk.as_ref()),
+        DiskImage::IMD(imd_disk) => Some(imd_disk.as_ref()),
+    }
+}
+
+/// Map the sectors of each file on a disk image to the file's name
+/// Returns an empty map if the filesystem isn't parsed
+pub fn disk_image_file_sectors(disk_image: &DiskImage) -> BTreeMap<SectorId, String> {
+    disk_image_file_extents(disk_image)
+        .into_iter()
+        .flat_map(|extent| {
+            let name = extent.name;
+            extent.sectors.into_iter().map(move |id| (id, name.clone()))
+        })
+        .collect()
+}
+
+/// Return the sectors of each file on a disk image
+/// Returns an empty list if the filesystem isn't parsed
+pub fn disk_image_file_extents(disk_image: &DiskImage) -> Vec<FileExtent> {
+    match disk_image {
+        DiskImage::D64(d64_disk) => d64_disk.file_extents(),
+        DiskImage::STX(stx_disk) => stx_disk.file_extents(),
+        DiskImage::Apple(apple_disk) => match &apple_disk.data {
+            AppleDiskData::DOS(dos_disk) => dos_disk.file_extents(),
+            AppleDiskData::ProDOS(prodos_disk) => prodos_disk.file_extents(),
+            AppleDiskData::Nibble(_) => Vec::new(),
+        },
+        DiskImage::Woz(_) | DiskImage::Dsk(_) | DiskImage::DC42(_) => Vec::new(),
+        DiskImage::Amiga(amiga_disk) => amiga_disk.file_extents(),
+        DiskImage::G64(g64_disk) => g64_disk.file_extents(),
+        DiskImage::ST(st_disk) => st_disk.file_extents(),
+        DiskImage::HFE(hfe_disk) => hfe_disk
+            .disk
+            .as_ref()
+            .map(STDisk::file_extents)
+            .unwrap_or_default(),
+        DiskImage::TD0(td0_disk) => td0_disk
+            .disk
+            .as_ref()
+            .map(STDisk::file_extents)
+            .unwrap_or_default(),
+        DiskImage::IMD(imd_disk) => imd_disk
+            .disk
+            .as_ref()
+            .map(STDisk::file_extents)
+            .unwrap_or_default(),
+    }
+}
+
+/// Read the data of each file on a disk image, by following the
+/// file's sectors
+/// Commodore link bytes and Amiga OFS data block headers are removed
+/// and files are cut at their end when it's known.  Returns an empty list if the filesystem isn't
+/// parsed.
+pub fn disk_image_file_data(disk_image: &DiskImage) -> Vec<(String, Vec<u8>)> {
+    let sectors: BTreeMap<SectorId, Vec<u8>> = disk_image_tracks(disk_image)
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|track| {
+            track.sectors.into_iter().map(move |sector| {
+                (
+                    SectorId::new(track.track, track.head, sector.id.sector),
+                    sector.data,
+                )
+            })
+        })
+        .collect();
+    let link_bytes = match disk_image {
+        DiskImage::D64(_) | DiskImage::G64(_) => 2,
+        DiskImage::Amiga(amiga_disk) if !amiga_disk.is_ffs() => OFS_DATA_HEADER_SIZE,
+        _ => 0,
+    };
+
+    disk_image_file_extents(disk_image)
+        .into_iter()
+        .map(|extent| {
+            let mut data = Vec::new();
+            for (n, id) in extent.sectors.iter().enumerate() {
+                let sector = match sectors.get(id) {
+                    Some(sector) => sector,
+                    None => break,
+                };
+                match extent.end {
+                    Some((last, end)) if n == last => {
+                        data.extend(sector.get(link_bytes..end).unwrap_or_default());
+                        break;
+                    }
+                    _ => data.extend(sector.get(link_bytes..).unwrap_or_default()),
+                }
+            }
+            (extent.name, data)
+        })
+        .collect()
+}
+
+/// Build the usage map for a disk image
+/// Returns None if the image has no parsed filesystem
+pub fn disk_image_usage(disk_image: &DiskImage) -> Option<UsageMap> {
+    let (free, system, unmanaged) = match disk_image {
+        DiskImage::D64(d64_disk) => (
+            d64_disk.free_sectors(),
+            d64_disk.system_sectors(),
+            d64_disk.unmanaged_sectors(),
+        ),
+        DiskImage::STX(stx_disk) => {
+            let system = stx_disk.system_sectors();
+            if system.is_empty() {
+                return None;
+            }
+            (stx_disk.free_sectors(), system, BTreeSet::new())
+        }
+        DiskImage::ST(st_disk) => {
+            let system = st_disk.system_sectors();
+            if system.is_empty() {
+                return None;
+            }
+            (st_disk.free_sectors(), system, BTreeSet::new())
+        }
+        DiskImage::HFE(hfe_disk) => {
+            let st_disk = hfe_disk.disk.as_ref()?;
+            let system = st_disk.system_sectors();
+            if system.is_empty() {
+                return None;
+            }
+            (st_disk.free_sectors(), system, BTreeSet::new())
+        }
+        DiskImage::TD0(td0_disk) => {
+            let st_disk = td0_disk.disk.as_ref()?;
+            let system = st_disk.system_sectors();
+            if system.is_empty() {
+                return None;
+            }
+            (st_disk.free_sectors(), system, BTreeSet::new())
+        }
+        DiskImage::IMD(imd_disk) => {
+            let st_disk = imd_disk.disk.as_ref()?;
+            let system = st_disk.system_sectors();
+            if system.is_empty() {
+                return None;
+            }
+            (st_disk.free_sectors(), system, BTreeSet::new())
+        }
+        DiskImage::Apple(apple_disk) => match &apple_disk.data {
+            AppleDiskData::DOS(dos_disk) => (
+                dos_disk.free_sectors(),
+                dos_disk.system_sectors(),
+                BTreeSet::new(),
+            ),
+            AppleDiskData::ProDOS(prodos_disk) => (
+                prodos_disk.free_sectors(),
+                prodos_disk.system_sectors(),
+                BTreeSet::new(),
+            ),
+            AppleDiskData::Nibble(_) => return None,
+        },
+        DiskImage::Woz(_) | DiskImage::Dsk(_) | DiskImage::DC42(_) => return None,
+        DiskImage::Amiga(amiga_disk) => (
+            amiga_disk.free_sectors(),
+            amiga_disk.system_sectors(),
+            BTreeSet::new(),
+        ),
+        DiskImage::G64(g64_disk) => {
+            let data = g64_disk.d64_image();
+            let (_, d64_disk) = d64_disk_parser(&data).ok()?;
+            (
+                d64_disk.free_sectors(),
+                d64_disk.system_sectors(),
+                d64_disk.unmanaged_sectors(),
+            )
+        }
+    };
+    let sectors: Vec<SectorId> = disk_image_tracks(disk_image)?
+        .iter()
+        .flat_map(|track| {
+            track
+                .sectors
+                .iter()
+                .map(|sector| SectorId::new(track.track, track.head, sector.id.sector))
+        })
+        .collect();
+
+    Some(UsageMap::new(
+        &sectors,
+        &free,
+        &system,
+        &unmanaged,
+        &disk_image_file_extents(disk_image),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::Path;
+
+    use std::collections::BTreeMap;
+
+    use config::Config;
+
+    use super::apple::disk::{Encoding, Format};
+    use super::{
+        disk_image_nibble_dos_data, format_from_filename_and_data, parse_nibble_dos_image,
+        DiskImage, DiskImageGuess, DiskImageParser, DiskImageSaver,
+    };
+    use super::{AppleDiskData, AppleDiskGuess};
+    use crate::disk_format::apple::woz::disk_image_to_woz;
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::{Geometry, Track};
+    use crate::disk_format::limits::{limits, Limits};
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::search::{Pattern, TextEncoding};
+    use crate::disk_format::source_map::{RegionKind, SourceMapper};
+    use crate::disk_format::stx::disk::stx_disk_parser;
+    use crate::disk_format::stx::writer::write_stx;
+    use crate::disk_format::testgen;
+    use crate::error::ErrorKind;
+    use crate::options::Options;
+
+    /// Test collecting heuristics on disk image type
+    #[test]
+    fn format_from_filename_works() {
+        let filename = "testdata/test-image_format_from_filename_works.dsk";
+
+        /* Version where we build the file in the test instead of
+         * saving it to version control */
+        let path = Path::new(&filename);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)
+            .unwrap_or_else(|e| {
+                panic!("Couldn't open file: {}", e);
+            });
+        let data: [u8; 143360] = [0; 143360];
+
+        file.write_all(&data).unwrap_or_else(|e| {
+            panic!("Error writing test file: {}", e);
+        });
+        file.flush().unwrap_or_else(|e| {
+            panic!("Couldn't flush file stream: {}", e);
+        });
+
+        let guess = format_from_filename_and_data(filename, &data).unwrap_or_else(|| {
+            panic!("Invalid filename guess");
+        });
+
+        match guess {
+            DiskImageGuess::Apple(g) => {
+                assert_eq!(
+                    g,
+                    AppleDiskGuess::new(Encoding::Plain, Format::DOS33(143360), &data)
+                );
+            }
+            _ => {
+                panic!("Invalid filename guess");
+            }
+        }
+
+        std::fs::remove_file(filename).unwrap_or_else(|e| {
+            panic!("Error removing test file: {}", e);
+        });
+    }
+
+    /// Test getting the saved data of a disk without writing a file
+    #[test]
+    fn to_bytes_works() {
+        let geometry = Geometry::atari_st(2, 1, 9);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i / 512) as u8)
+            .collect();
+        let stx = write_stx(&split_tracks(&data, &geometry), &BTreeMap::new());
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+        let disk_image = DiskImage::STX(Box::new(disk));
+        let config = Config::builder().build().unwrap();
+
+        assert_eq!(disk_image.to_bytes(&config, None).unwrap(), data);
+
+        let mut writer = Vec::new();
+        disk_image
+            .save_to_writer(&config, None, &mut writer)
+            .unwrap();
+        assert_eq!(writer, data);
+    }
+
+    /// Test parsing unnamed raw dumps by their contents
+    #[test]
+    fn unnamed_image_works() {
+        let config = Options::default();
+        let data = testgen::atari_st_fat("UNNAMED", &[("FILE.TXT", b"data")]).unwrap();
+        let image = data.parse_disk_image(&config, "IMAGE").unwrap();
+        assert!(image.as_st().is_some());
+
+        let data = testgen::d64("UNNAMED", &[("FILE", b"data")]).unwrap();
+        let image = data.parse_disk_image(&config, "IMAGE").unwrap();
+        assert!(image.as_d64().is_some());
+    }
+
+    /// Test showing names in the character set from the parse options
+    #[test]
+    fn parse_charset_works() {
+        let data = testgen::d64("DISK", &[("FILE", b"data")]).unwrap();
+
+        let image = data.parse_disk_image(&Options::default(), "a.d64").unwrap();
+        assert!(image.label().unwrap().starts_with("DISK,"));
+        assert_eq!(image.disk_files()[0].name, "FILE");
+
+        let options = Options::default()
+            .with_override("petscii-charset", "lowercase")
+            .unwrap();
+        let image = data.parse_disk_image(&options, "a.d64").unwrap();
+        assert_eq!(image.charset(), options.charset());
+        assert!(image.label().unwrap().starts_with("disk,"));
+        assert_eq!(image.disk_files()[0].name, "file");
+    }
+
+    /// Test parsing a headerless dump with a geometry from the settings
+    #[test]
+    fn geometry_override_works() {
+        let data = testgen::atari_st_sectors(40, 2, 10);
+        let tracks = Options::default().with_override("tracks", 40).unwrap();
+        let config = tracks
+            .with_override("sectors", 10)
+            .unwrap()
+            .with_override("sides", 2)
+            .unwrap();
+        let image = data.parse_disk_image(&config, "IMAGE").unwrap();
+        assert_eq!(
+            image.as_st().unwrap().geometry,
+            Geometry::atari_st(40, 2, 10)
+        );
+
+        assert!(data.parse_disk_image(&tracks, "IMAGE").is_err());
+    }
+
+    /// Test parsing DOS order, ProDOS order and nibble images wrapped
+    /// in 2MG headers
+    #[test]
+    fn two_img_works() {
+        let config = Options::default();
+        let dos = testgen::apple_dos_33(&[("HELLO", b"data")]).unwrap();
+        let data = testgen::two_img(&dos, 0, Some(254), "");
+        let image = data.parse_disk_image(&config, "IMAGE.2MG").unwrap();
+        let apple_disk = image.as_apple().unwrap();
+        assert!(matches!(apple_disk.data, AppleDiskData::DOS(_)));
+        assert_eq!(
+            apple_disk.two_img.as_ref().unwrap().to_string(),
+            "2MG DOS order from RIDR, volume: 254"
+        );
+        // The source map points into the wrapped image
+        let source_map = image.source_map(&data);
+        assert_eq!(source_map.regions_at(0)[0].name, "2MG header");
+        assert_eq!(source_map.regions_at(64 + 0x11000)[0].name, "VTOC");
+
+        let prodos = testgen::prodos("DISK", &[("HELLO", b"data")]).unwrap();
+        let data = testgen::two_img(&prodos, 1, None, "A COMMENT");
+        let image = data.parse_disk_image(&config, "IMAGE.2MG").unwrap();
+        assert!(matches!(
+            image.as_apple().unwrap().data,
+            AppleDiskData::ProDOS(_)
+        ));
+
+        let data = testgen::two_img(&testgen::nib_from_dos_order(&dos), 2, None, "");
+        let image = data.parse_disk_image(&config, "IMAGE.2MG").unwrap();
+        assert!(matches!(
+            image.as_apple().unwrap().data,
+            AppleDiskData::Nibble(_)
+        ));
+    }
+
+    /// Test carrying the write protection of an image through a
+    /// conversion, unless the setting overrides it
+    #[test]
+    fn write_protected_works() {
+        let config = Options::default();
+        let dos = testgen::apple_dos_33(&[("HELLO", b"data")]).unwrap();
+        let mut data = testgen::two_img(&dos, 0, None, "");
+        // The locked flag
+        data[0x13] |= 0x80;
+        let image = data.parse_disk_image(&config, "IMAGE.2MG").unwrap();
+        assert!(image.write_protected());
+        assert!(image.report().write_protected);
+        assert!(image.converted_write_protected(&config));
+
+        let woz = disk_image_to_woz(
+            &image,
+            None,
+            image.converted_write_protected(&config),
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        let woz_image = woz.parse_disk_image(&config, "IMAGE.WOZ").unwrap();
+        assert!(woz_image.write_protected());
+
+        let writable = config.with_override("write-protected", false).unwrap();
+        assert!(!image.converted_write_protected(&writable));
+        let image = dos.parse_disk_image(&config, "IMAGE.DSK").unwrap();
+        assert!(!image.write_protected());
+        let protected = config.with_override("write-protected", true).unwrap();
+        assert!(image.converted_write_protected(&protected));
+    }
+
+    /// Test parsing a DiskCopy 4.2 image and saving its sector data
+    #[test]
+    fn dc42_works() {
+        let sectors = vec![0xE5; 737280];
+        let data = testgen::dc42("Files", 2, &sectors, false);
+        let image = data
+            .parse_disk_image(&Options::default(), "files.image")
+            .unwrap();
+        assert_eq!(
+            image.format_name(),
+            "DiskCopy 4.2 Disk: \"Files\", 720K MFM, format 22, 1440 blocks"
+        );
+        assert_eq!(image.label().as_deref(), Some("Files"));
+        assert_eq!(image.tracks().unwrap().len(), 160);
+        assert_eq!(image.to_bytes(&Config::default(), None).unwrap(), sectors);
+        assert_eq!(image.source_map.regions[0].name, "DiskCopy header");
+
+        // A damaged image is explained
+        let mut damaged = data.clone();
+        damaged[0x1000] ^= 0xFF;
+        let e = damaged
+            .parse_disk_image(&Options::default(), "files.image")
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("DiskCopy data checksum"));
+    }
+
+    /// Test reading the sectors of an HFE image as a flat image
+    #[test]
+    fn hfe_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let sectors = testgen::atari_st_sectors(2, 2, 9);
+        let data = testgen::hfe(&sectors, &geometry, 1);
+        let image = data
+            .parse_disk_image(&Options::default(), "disk.hfe")
+            .unwrap();
+        assert_eq!(image.format_name(), "HFE v1 Disk");
+        assert_eq!(image.tracks().unwrap().len(), 4);
+        // HFE images can't be saved, the sector data can be exported
+        assert!(image.read_only);
+        assert!(image.to_bytes(&Config::default(), None).is_err());
+        assert_eq!(
+            image.value.to_bytes(&Config::default(), None).unwrap(),
+            sectors
+        );
+        assert_eq!(image.source_map.regions[0].name, "HFE header");
+
+        // A damaged image is explained
+        let mut damaged = data.clone();
+        damaged[8] = 1;
+        let e = damaged
+            .parse_disk_image(&Options::default(), "disk.hfe")
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("unsupported HFE format revision"));
+    }
+
+    /// Test reading a compressed TD0 image and explaining a damaged one
+    #[test]
+    fn td0_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let sectors = testgen::atari_st_sectors(2, 2, 9);
+        let data = testgen::td0(&split_tracks(&sectors, &geometry), true, "");
+        let image = data
+            .parse_disk_image(&Options::default(), "disk.td0")
+            .unwrap();
+        assert_eq!(image.format_name(), "TD0 Disk");
+        assert_eq!(image.tracks().unwrap().len(), 4);
+        assert_eq!(image.to_bytes(&Config::default(), None).unwrap(), sectors);
+
+        let mut damaged = data.clone();
+        damaged[10] ^= 0xFF;
+        let e = damaged
+            .parse_disk_image(&Options::default(), "disk.td0")
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("TD0 header CRC doesn't match"));
+    }
+
+    /// Test reading an IMD image through the image parser
+    #[test]
+    fn imd_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let sectors = testgen::atari_st_sectors(2, 2, 9);
+        let data = testgen::imd(&split_tracks(&sectors, &geometry), "");
+        let image = data
+            .parse_disk_image(&Options::default(), "disk.imd")
+            .unwrap();
+        assert_eq!(image.format_name(), "IMD Disk");
+        assert_eq!(image.tracks().unwrap().len(), 4);
+        assert_eq!(image.to_bytes(&Config::default(), None).unwrap(), sectors);
+        assert_eq!(image.source_map.regions[0].name, "IMD header");
+    }
+
+    /// Test that limits overridden for one parse only apply to it
+    #[test]
+    fn parse_limits_work() {
+        let geometry = Geometry::atari_st(2, 1, 9);
+        let data = vec![0xE5; geometry.total_size()];
+        let stx = write_stx(&split_tracks(&data, &geometry), &BTreeMap::new());
+
+        let options = Options::default();
+        let limited = options.with_override("max-tracks", 1).unwrap();
+        assert!(stx.parse_disk_image(&limited, "test.stx").is_err());
+        assert!(stx.parse_disk_image(&options, "test.stx").is_ok());
+        assert_eq!(limits(), Limits::default());
+    }
+
+    /// Test finding the headers and sectors of a STX image in the
+    /// source map
+    #[test]
+    fn source_map_works() {
+        let geometry = Geometry::atari_st(2, 1, 9);
+        let data = vec![0xE5; geometry.total_size()];
+        let stx = write_stx(&split_tracks(&data, &geometry), &BTreeMap::new());
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+        let source_map = DiskImage::STX(Box::new(disk)).source_map(&stx);
+
+        assert_eq!(source_map.regions[0].name, "file header");
+        assert_eq!(source_map.regions[1].offset, 16);
+        assert_eq!(source_map.regions[1].name, "track 0 side 0");
+        let sector = source_map
+            .regions
+            .iter()
+            .find(|region| region.name == "track 1 side 0 sector 3")
+            .unwrap();
+        assert_eq!(sector.kind, RegionKind::Data);
+        assert_eq!(sector.length, 512);
+        assert!(stx[sector.offset..sector.offset + 512]
+            .iter()
+            .all(|b| *b == 0xE5));
+
+        // The track record, track header and sector, outermost first
+        let names: Vec<&str> = source_map
+            .regions_at(sector.offset)
+            .iter()
+            .map(|region| region.name.as_str())
+            .collect();
+        assert_eq!(names, ["track 1 side 0", "track 1 side 0 sector 3"]);
+        let track_header = source_map.regions_at(source_map.regions[1].offset + 4);
+        assert_eq!(track_header[1].kind, RegionKind::Header);
+    }
+
+    /// Test that disks are boxed and can be got at without matching
+    /// Test that parsing with the read-only setting marks the image and
+    /// its filesystem read-only
+    #[test]
+    fn parse_read_only_works() {
+        let data = testgen::atari_st_fat("DISK", &[("FILE.TXT", b"data")]).unwrap();
+        let image = data.parse_disk_image(&Options::default(), "a.st").unwrap();
+        assert!(!image.read_only);
+        let st_disk = image.as_st().unwrap();
+        assert!(!st_disk.fat_volume().unwrap().fat.read_only);
+        assert_eq!(image.to_bytes(&Config::default(), None).unwrap(), data);
+
+        let options = Options::default().with_override("read-only", true).unwrap();
+        let image = data.parse_disk_image(&options, "a.st").unwrap();
+        assert!(image.read_only);
+        let st_disk = image.as_st().unwrap();
+        assert!(st_disk.fat_volume().unwrap().fat.read_only);
+        let e = image.to_bytes(&Config::default(), None).err().unwrap();
+        assert!(matches!(e.kind(), ErrorKind::ReadOnly(_)));
+        assert_eq!(
+            image
+                .to_bytes(&Config::default(), Some("FILE.TXT"))
+                .unwrap(),
+            b"data"
+        );
+    }
+
+    #[test]
+    fn boxed_variants_work() {
+        assert_eq!(
+            std::mem::size_of::<DiskImage>(),
+            2 * std::mem::size_of::<usize>()
+        );
+
+        let data = testgen::d64("GAMES", &[("ONE", &[1; 10])]).unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "games.d64")
+            .unwrap();
+        assert!(image.as_d64().is_some());
+        assert!(image.as_stx().is_none());
+        assert!(image.as_apple().is_none());
+        assert!(image.dos_disk().is_none());
+    }
+
+    /// Test searching a D64 for its directory names
+    #[test]
+    fn search_directory_names_works() {
+        let data = testgen::d64("GAMES", &[("ONE", &[1; 10])]).unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "games.d64")
+            .unwrap();
+
+        for text in ["GAMES", "ONE", "one"] {
+            let hits = image.search(&Pattern::Petscii(String::from(text)));
+            assert!(hits
+                .iter()
+                .any(|h| h.id.track == Track(18) && h.encoding == TextEncoding::Petscii));
+        }
+    }
+
+    /// Test reading the DOS catalog and files of a nibble disk
+    #[test]
+    fn nibble_dos_image_works() {
+        let program = testgen::apple_binary(0x0803, b"HELLO");
+        let dos = testgen::apple_dos_33(&[("HELLO", &program)]).unwrap();
+        let nib = testgen::nib_from_dos_order(&dos);
+        let image = nib
+            .parse_disk_image(&Options::default(), "test.nib")
+            .unwrap();
+        assert!(image.disk_files().is_empty());
+
+        let dos_data = disk_image_nibble_dos_data(&image).unwrap();
+        assert_eq!(dos_data, dos);
+        let dos_image = parse_nibble_dos_image(&dos_data).unwrap();
+        let apple_disk = dos_image.as_apple().unwrap();
+        assert_eq!(apple_disk.encoding, Encoding::Nibble);
+        assert!(apple_disk.dos_disk().is_some());
+        let files = dos_image.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "HELLO");
+        assert_eq!(files[0].data, b"HELLO");
+
+        assert!(disk_image_nibble_dos_data(&dos_image).is_none());
+        assert!(parse_nibble_dos_image(&[0; 35 * 4096]).is_err());
+    }
+
+    /// Test reading the DOS 3.2 catalog and files of a 13 sector nibble
+    /// disk
+    #[test]
+    fn nibble_dos_32_image_works() {
+        let program = testgen::apple_binary(0x0803, &[0xA5; 600]);
+        let dos = testgen::apple_dos_32(&[("HELLO", &program)]).unwrap();
+        let nib = testgen::nib_from_dos_32(&dos);
+        let image = nib
+            .parse_disk_image(&Options::default(), "test.nib")
+            .unwrap();
+        let apple_disk = image.as_apple().unwrap();
+        assert_eq!(apple_disk.format, Format::DOS32(nib.len() as u64));
+
+        let dos_data = disk_image_nibble_dos_data(&image).unwrap();
+        assert_eq!(dos_data, dos);
+        let dos_image = parse_nibble_dos_image(&dos_data).unwrap();
+        let apple_disk = dos_image.as_apple().unwrap();
+        assert_eq!(apple_disk.format, Format::DOS32(dos.len() as u64));
+        let files = dos_image.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].data, &program[4..]);
+
+        // Exporting the nibble disk writes the 13 sector image
+        let mut saved = Vec::new();
+        image
+            .value
+            .save_to_writer(&Config::default(), None, &mut saved)
+            .unwrap();
+        assert_eq!(saved, dos);
+    }
+}
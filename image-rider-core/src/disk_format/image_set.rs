@@ -0,0 +1,427 @@
+//! Multi-volume image sets
+//!
+//! Software that shipped on more than one disk is dumped to one image
+//! per disk, named like "Game (1986)(Disk 1 of 3).d64",
+//! "game_disk2.dsk" or "Game - Side B.st".  The disks of a set are
+//! found from the word disk, disc or side and the number or letter
+//! after it, first in the file name and then in the volume name.  The
+//! text before it names the set, or the directory holding the image
+//! when the name is just "Disk 1".  Images with the same set name are
+//! grouped into an [ImageSet], ordered by disk number, for indexers
+//! and emulator launchers that need to mount the disks in order.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::disk_format::image::{disk_image_file_extents, DiskImage};
+use crate::error::Error;
+
+/// The words that come before a disk number
+const DISK_WORDS: [&str; 3] = ["disk", "disc", "side"];
+
+/// The position of a disk in a set
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct DiskNumber {
+    /// The disk number, starting at one
+    /// Sides given as letters are numbered from one, side A is 1.
+    pub number: u32,
+    /// The side of the disk, when the name gives both a disk and a side
+    pub side: Option<u32>,
+    /// The number of disks in the set, if the name gives it
+    pub total: Option<u32>,
+}
+
+impl Display for DiskNumber {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "disk {}", self.number)?;
+        if let Some(side) = self.side {
+            write!(f, " side {}", side)?;
+        }
+        if let Some(total) = self.total {
+            write!(f, " of {}", total)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a number or a single letter at the start of a string
+/// Returns the value and the number of bytes used.  Letters are
+/// numbered from one.
+fn number_or_letter(s: &str) -> Option<(u32, usize)> {
+    let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+    if digits > 0 {
+        return s[..digits].parse().ok().map(|n| (n, digits));
+    }
+    match s.as_bytes() {
+        [letter, rest @ ..]
+            if letter.is_ascii_lowercase()
+                && rest.first().map_or(true, |b| !b.is_ascii_alphanumeric()) =>
+        {
+            Some((u32::from(letter - b'a') + 1, 1))
+        }
+        _ => None,
+    }
+}
+
+/// Find a disk word followed by a number in a lowercase name
+/// Returns where the word starts, the number and the total if it's
+/// followed by "of N" or "/N".
+fn find_disk_word(name: &str, word: &str) -> Option<(usize, u32, Option<u32>)> {
+    name.match_indices(word).find_map(|(start, _)| {
+        // The word has to start a word, "gamedisk2" isn't matched
+        if name[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+        let rest = &name[start + word.len()..];
+        let rest = rest.trim_start_matches([' ', '_', '-', '.', '#']);
+        let (number, used) = number_or_letter(rest)?;
+        let rest = rest[used..].trim_start();
+        let total = rest
+            .strip_prefix("of")
+            .or_else(|| rest.strip_prefix('/'))
+            .and_then(|rest| number_or_letter(rest.trim_start()))
+            .map(|(total, _)| total);
+
+        Some((start, number, total))
+    })
+}
+
+/// Find the disk number in a name and the text before it
+/// Returns None if the name doesn't have a disk number.
+pub fn disk_number(name: &str) -> Option<(String, DiskNumber)> {
+    // Lowercasing ASCII doesn't move byte offsets
+    let lower = name.to_ascii_lowercase();
+    let mut found: Vec<(&str, usize, u32, Option<u32>)> = DISK_WORDS
+        .iter()
+        .filter_map(|word| {
+            find_disk_word(&lower, word).map(|(start, number, total)| (*word, start, number, total))
+        })
+        .collect();
+    found.sort_by_key(|(_, start, _, _)| *start);
+
+    let (_, start, number, total) = *found.first()?;
+    let side = found
+        .iter()
+        .find(|(word, position, _, _)| *word == "side" && *position != start)
+        .map(|(_, _, side, _)| *side);
+
+    let prefix = name[..start].trim_end_matches(|c: char| !c.is_alphanumeric() && c != ')');
+
+    Some((
+        String::from(prefix),
+        DiskNumber {
+            number,
+            side,
+            total: total.filter(|total| *total > 1),
+        },
+    ))
+}
+
+/// Reduce a set name to lowercase words, so names that differ only in
+/// case and punctuation group together
+fn set_key(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// A disk in an image set
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SetMember {
+    /// Where the image came from, usually a path
+    pub path: String,
+    /// The name of the set the disk belongs to
+    pub set_name: String,
+    /// The position of the disk in the set
+    pub disk: DiskNumber,
+    /// The format of the image
+    pub format: String,
+    /// The volume name or disk ID, if the image has one
+    pub label: Option<String>,
+    /// The names of the files on the disk
+    pub files: Vec<String>,
+}
+
+impl SetMember {
+    /// Find the set a parsed image belongs to from its path and volume
+    /// name
+    /// Returns None if neither gives a disk number.
+    pub fn new(path: &str, image: &DiskImage) -> Option<SetMember> {
+        let label = image.label();
+        let file_path = Path::new(path);
+        let stem = file_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let (mut set_name, disk) =
+            disk_number(&stem).or_else(|| label.as_deref().and_then(disk_number))?;
+        if set_key(&set_name).is_empty() {
+            // Use the directory for names like "Disk 1.d64"
+            set_name = file_path
+                .parent()
+                .and_then(Path::file_name)
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+        }
+        if set_key(&set_name).is_empty() {
+            return None;
+        }
+
+        Some(SetMember {
+            path: String::from(path),
+            set_name,
+            disk,
+            format: image.format_name(),
+            label,
+            files: disk_image_file_extents(image)
+                .into_iter()
+                .map(|extent| extent.name)
+                .collect(),
+        })
+    }
+}
+
+/// The disks of one piece of software
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImageSet {
+    /// The name of the set, from the first disk
+    pub name: String,
+    /// The number of disks in the set, if any disk names it
+    pub total: Option<u32>,
+    /// The format of the images, if they all have the same format
+    pub format: Option<String>,
+    /// The disks, ordered by disk number and side
+    pub members: Vec<SetMember>,
+}
+
+impl ImageSet {
+    /// Build a set from its disks
+    fn new(mut members: Vec<SetMember>) -> ImageSet {
+        members.sort_by(|a, b| a.disk.cmp(&b.disk).then_with(|| a.path.cmp(&b.path)));
+        let format = members
+            .first()
+            .map(|member| member.format.clone())
+            .filter(|format| members.iter().all(|member| &member.format == format));
+
+        ImageSet {
+            name: members
+                .first()
+                .map(|member| member.set_name.clone())
+                .unwrap_or_default(),
+            total: members.iter().filter_map(|member| member.disk.total).max(),
+            format,
+            members,
+        }
+    }
+
+    /// Return the disk numbers missing from the set
+    /// Only sets that give the number of disks can be missing the last
+    /// disks.
+    pub fn missing(&self) -> Vec<u32> {
+        let last = self
+            .total
+            .or_else(|| self.members.iter().map(|member| member.disk.number).max())
+            .unwrap_or(0);
+        (1..=last)
+            .filter(|number| {
+                !self
+                    .members
+                    .iter()
+                    .any(|member| member.disk.number == *number)
+            })
+            .collect()
+    }
+
+    /// True if no disks are missing from the set
+    pub fn is_complete(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    /// Return every file in the set and the disks it's on
+    pub fn catalog(&self) -> BTreeMap<&str, Vec<u32>> {
+        let mut catalog: BTreeMap<&str, Vec<u32>> = BTreeMap::new();
+        for member in &self.members {
+            for file in &member.files {
+                let disks = catalog.entry(file.as_str()).or_default();
+                if !disks.contains(&member.disk.number) {
+                    disks.push(member.disk.number);
+                }
+            }
+        }
+
+        catalog
+    }
+
+    /// Return an M3U playlist of the images in disk order, the disk
+    /// list format emulators read to swap disks
+    pub fn to_m3u(&self) -> String {
+        self.members
+            .iter()
+            .map(|member| format!("{}\n", member.path))
+            .collect()
+    }
+
+    /// Serialize the set as a single line of JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+impl Display for ImageSet {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}: {} disks", self.name, self.members.len())?;
+        if let Some(total) = self.total {
+            write!(f, " of {}", total)?;
+        }
+        let missing = self.missing();
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.iter().map(u32::to_string).collect();
+            write!(f, ", missing {}", missing.join(", "))?;
+        }
+        writeln!(f)?;
+        for member in &self.members {
+            write!(f, "  {}: {}", member.disk, member.path)?;
+            if let Some(label) = &member.label {
+                write!(f, " ({})", label)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Group disks into sets by set name
+/// A disk is only a set on its own if its name gives more than one
+/// disk, like "Disk 1 of 2".
+pub fn group_image_sets(members: Vec<SetMember>) -> Vec<ImageSet> {
+    let mut groups: BTreeMap<String, Vec<SetMember>> = BTreeMap::new();
+    for member in members {
+        groups
+            .entry(set_key(&member.set_name))
+            .or_default()
+            .push(member);
+    }
+
+    groups
+        .into_values()
+        .filter(|members| {
+            members.len() > 1 || members.iter().any(|member| member.disk.total.is_some())
+        })
+        .map(ImageSet::new)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disk_number, group_image_sets, DiskNumber, SetMember};
+    use crate::disk_format::image::DiskImageParser;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test finding disk numbers in file and volume names
+    #[test]
+    fn disk_number_works() {
+        let disk = |number, side, total| DiskNumber {
+            number,
+            side,
+            total,
+        };
+        assert_eq!(
+            disk_number("Game (1986)(Publisher)(Disk 1 of 3)"),
+            Some((
+                String::from("Game (1986)(Publisher)"),
+                disk(1, None, Some(3))
+            ))
+        );
+        assert_eq!(
+            disk_number("game_disk2"),
+            Some((String::from("game"), disk(2, None, None)))
+        );
+        assert_eq!(
+            disk_number("Game - Side B"),
+            Some((String::from("Game"), disk(2, None, None)))
+        );
+        assert_eq!(
+            disk_number("Game [Disk 2/2][Side A]"),
+            Some((String::from("Game"), disk(2, Some(1), Some(2))))
+        );
+        assert_eq!(
+            disk_number("GAME DISK 3,01"),
+            Some((String::from("GAME"), disk(3, None, None)))
+        );
+        assert_eq!(
+            disk_number("Disk 1"),
+            Some((String::new(), disk(1, None, None)))
+        );
+        assert_eq!(disk_number("Diskmaster"), None);
+        assert_eq!(disk_number("Bedside"), None);
+        assert_eq!(disk_number("Ultima 4"), None);
+    }
+
+    /// Test grouping parsed images into sets with a combined catalog
+    #[test]
+    fn group_image_sets_works() {
+        let config = Options::default();
+        let disk_1 = testgen::d64("ADVENTURE", &[("BOOT", b"boot"), ("PART1", b"one")]).unwrap();
+        let disk_2 =
+            testgen::d64("ADVENTURE DISK 2", &[("BOOT", b"boot"), ("PART2", b"two")]).unwrap();
+        let other = testgen::d64("TOOLS", &[("COPY", b"copy")]).unwrap();
+        let disk_4 = testgen::d64("SAVES", &[]).unwrap();
+
+        let members: Vec<SetMember> = [
+            ("adventure/adv-b.d64", &disk_2),
+            ("adventure/Adventure (Disk 1 of 4).d64", &disk_1),
+            ("tools.d64", &other),
+            ("adventure/Adventure (Disk 4 of 4).d64", &disk_4),
+            ("Backup/Disk 1.d64", &other),
+        ]
+        .iter()
+        .filter_map(|(path, data)| {
+            let image = data.parse_disk_image(&config, path).unwrap();
+            SetMember::new(path, &image)
+        })
+        .collect();
+        // tools.d64 has no disk number
+        assert_eq!(members.len(), 4);
+        // The volume name gives the disk number when the file name
+        // doesn't
+        assert_eq!(members[0].set_name, "ADVENTURE");
+        assert_eq!(members[0].disk.number, 2);
+        assert_eq!(members[3].set_name, "Backup");
+
+        // Backup/Disk 1.d64 is a single disk without a total
+        let sets = group_image_sets(members);
+        assert_eq!(sets.len(), 1);
+        let set = &sets[0];
+        assert_eq!(set.name, "Adventure");
+        assert_eq!(set.total, Some(4));
+        assert_eq!(set.format.as_deref(), Some("D64 Disk"));
+        assert_eq!(set.missing(), vec![3]);
+        assert!(!set.is_complete());
+        assert_eq!(
+            set.to_m3u(),
+            "adventure/Adventure (Disk 1 of 4).d64\nadventure/adv-b.d64\n\
+             adventure/Adventure (Disk 4 of 4).d64\n"
+        );
+
+        let catalog = set.catalog();
+        assert_eq!(catalog["BOOT"], vec![1, 2]);
+        assert_eq!(catalog["PART2"], vec![2]);
+        assert_eq!(catalog.len(), 3);
+
+        assert!(set.to_string().starts_with(
+            "Adventure: 3 disks of 4, missing 3\n  disk 1 of 4: adventure/Adventure (Disk 1 of 4).d64"
+        ));
+        assert!(set.to_json().unwrap().contains("\"total\":4"));
+    }
+}
@@ -0,0 +1,612 @@
+//! ImageDisk IMD disk images
+//!
+//! IMD images start with an ASCII header line, "IMD v.vv: dd/mm/yyyy
+//! hh:mm:ss" followed by CR LF, then a comment ended by an ASCII EOF
+//! (0x1A).  Each track after the comment starts with a five byte
+//! header:
+//!
+//! ```ignore
+//! 0: the mode, 0 to 2 for FM at 500, 300 and 250 kbit/s and 3 to 5
+//!    for MFM at 500, 300 and 250 kbit/s
+//! 1: the physical cylinder
+//! 2: the physical head, with bit 7 set if there's a cylinder map and
+//!    bit 6 set if there's a head map
+//! 3: the number of sectors
+//! 4: the size code, the sectors are 128 << size code bytes
+//! ```
+//!
+//! The header is followed by the sector numbering map, the sector
+//! number of each sector in the order they were read, then the
+//! cylinder map and the head map if there are any.  The maps hold the
+//! cylinder and head in each sector ID when they differ from the
+//! physical cylinder and head.  Each sector then has a data record, a
+//! type byte and the data:
+//!
+//! ```ignore
+//! 0: the data couldn't be read, there's no data
+//! 1: normal data
+//! 2: compressed data, one byte repeated for the whole sector
+//! 3: normal data with a deleted data mark
+//! 4: compressed data with a deleted data mark
+//! 5: normal data read with a CRC error
+//! 6: compressed data read with a CRC error
+//! 7: deleted data read with a CRC error
+//! 8: compressed deleted data read with a CRC error
+//! ```
+//!
+//! The format has no checksums of its own.
+//!
+//! Information from:\
+//! ImageDisk 1.18 by Dave Dunfield, IMD.TXT
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error};
+use nom::bytes::complete::{tag, take, take_until};
+use nom::number::complete::le_u8;
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::{limit_sectors_per_track, limit_tracks};
+use crate::disk_format::logical::{
+    flatten_tracks, infer_geometry, LogicalSector, LogicalTrack, RawExporter,
+};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::stx::st::{flat_disk_parser, STDisk};
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// An ImageDisk image
+pub const IMD_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "IMD",
+    description: "ImageDisk disk image",
+    extensions: &["imd"],
+    sizes: &[SizeRange::at_least(IMD_MAGIC.len() + 2)],
+    references: &[Reference {
+        title: "ImageDisk 1.18 by Dave Dunfield, IMD.TXT",
+        url: None,
+    }],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        track_export: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature at the start of the header line
+pub const IMD_MAGIC: &[u8] = b"IMD ";
+
+/// The byte after the comment
+pub const IMD_COMMENT_END: u8 = 0x1A;
+
+/// The largest mode, MFM at 250 kbit/s
+const MAX_MODE: u8 = 5;
+
+/// The largest sector size code, 8192 byte sectors
+const MAX_SIZE_CODE: u8 = 6;
+
+/// The largest data record type
+const MAX_RECORD: u8 = 8;
+
+/// The track has a cylinder map
+pub const CYLINDER_MAP: u8 = 0x80;
+
+/// The track has a head map
+pub const HEAD_MAP: u8 = 0x40;
+
+/// Return true if the data starts with an IMD header line
+pub fn is_imd(data: &[u8]) -> bool {
+    data.starts_with(IMD_MAGIC) && imd_header_parser(data).is_ok()
+}
+
+/// The header line and the comment
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImdHeader {
+    /// The ImageDisk version, e.g. "1.18"
+    pub version: String,
+    /// The date and time the image was made, as written in the header
+    pub timestamp: String,
+    /// The comment, with lines separated by newlines
+    pub comment: String,
+    /// The length of the header line, the comment and the EOF byte
+    pub length: usize,
+}
+
+impl Display for ImdHeader {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "ImageDisk {}, {}", self.version, self.timestamp)?;
+        if !self.comment.is_empty() {
+            write!(f, "\n{}", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// A sector and its data record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImdSector {
+    /// The offset of the data record in the image
+    pub offset: usize,
+    /// The cylinder in the sector ID
+    pub cylinder: u8,
+    /// The head in the sector ID
+    pub head: u8,
+    /// The sector number in the sector ID
+    pub sector: u8,
+    /// The type of the data record
+    pub record: u8,
+    /// The sector data, expanded if it was compressed
+    pub data: Vec<u8>,
+}
+
+impl ImdSector {
+    /// True if the sector data was read
+    pub fn has_data(&self) -> bool {
+        self.record != 0
+    }
+
+    /// True if the sector data was stored as a single repeated byte
+    pub fn compressed(&self) -> bool {
+        self.has_data() && self.record % 2 == 0
+    }
+
+    /// True if the sector has a deleted data mark
+    pub fn deleted(&self) -> bool {
+        matches!(self.record, 3 | 4 | 7 | 8)
+    }
+
+    /// True if the sector was read with a CRC error
+    pub fn crc_error(&self) -> bool {
+        self.record >= 5
+    }
+}
+
+/// A track record and its sectors
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImdTrack {
+    /// The offset of the track header in the image
+    pub offset: usize,
+    /// The recording mode and data rate
+    pub mode: u8,
+    /// The physical cylinder
+    pub cylinder: u8,
+    /// The physical head
+    pub head: u8,
+    /// The size of each sector in bytes
+    pub sector_size: usize,
+    /// The sectors, in the order they were read
+    pub sectors: Vec<ImdSector>,
+}
+
+impl ImdTrack {
+    /// True if the track is single density
+    pub fn is_fm(&self) -> bool {
+        self.mode < 3
+    }
+
+    /// The data rate in kbit/s
+    pub fn bit_rate(&self) -> u16 {
+        [500, 300, 250][usize::from(self.mode % 3)]
+    }
+
+    /// Return the sectors with data
+    pub fn logical_track(&self) -> LogicalTrack {
+        let mut track = LogicalTrack::new(self.cylinder, self.head);
+        for sector in self.sectors.iter().filter(|sector| sector.has_data()) {
+            let mut logical_sector = LogicalSector::new(
+                SectorId::new(sector.cylinder, sector.head, sector.sector),
+                sector.data.clone(),
+            );
+            logical_sector.crc_error = sector.crc_error();
+            logical_sector.deleted = sector.deleted();
+            track.sectors.push(logical_sector);
+        }
+        track
+    }
+}
+
+/// Format a track as a line of a track table
+impl Display for ImdTrack {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "track {} side {}, {} {} kbit/s, sectors: {} x {} bytes",
+            self.cylinder,
+            self.head,
+            if self.is_fm() { "FM" } else { "MFM" },
+            self.bit_rate(),
+            self.sectors.len(),
+            self.sector_size
+        )?;
+        let order: Vec<String> = self
+            .sectors
+            .iter()
+            .map(|sector| sector.sector.to_string())
+            .collect();
+        write!(f, ", order: {}", order.join(" "))?;
+        let errors = self
+            .sectors
+            .iter()
+            .filter(|sector| sector.crc_error())
+            .count();
+        if errors > 0 {
+            write!(f, ", CRC errors: {}", errors)?;
+        }
+        let no_data = self
+            .sectors
+            .iter()
+            .filter(|sector| !sector.has_data())
+            .count();
+        if no_data > 0 {
+            write!(f, ", sectors without data: {}", no_data)?;
+        }
+        Ok(())
+    }
+}
+
+/// An ImageDisk image, with the sectors as a flat image
+pub struct ImdDisk<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The header line and the comment
+    pub header: ImdHeader,
+    /// The tracks, in image order
+    pub tracks: Vec<ImdTrack>,
+    /// The sectors as a flat image, None if there are no sectors
+    pub disk: Option<STDisk>,
+}
+
+impl ImdDisk<'_> {
+    /// Return the geometry of the sectors, None if there are no
+    /// sectors
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.disk.as_ref().map(|disk| disk.geometry.clone())
+    }
+
+    /// Return the sectors that were read with CRC errors
+    pub fn bad_sectors(&self) -> Vec<SectorId> {
+        self.logical_tracks()
+            .iter()
+            .flat_map(|track| track.sectors.iter())
+            .filter(|sector| sector.crc_error)
+            .map(|sector| sector.id)
+            .collect()
+    }
+
+    /// A table of the tracks, one per line
+    pub fn track_table(&self) -> String {
+        self.tracks
+            .iter()
+            .map(|track| track.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl Display for ImdDisk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.header)?;
+        match &self.disk {
+            Some(disk) => write!(f, "\n{}, bad sectors: {}", disk, self.bad_sectors().len()),
+            None => write!(f, "\nNo sectors"),
+        }
+    }
+}
+
+impl SanityCheck for ImdDisk<'_> {
+    fn check(&self) -> bool {
+        self.disk.as_ref().is_some_and(STDisk::check)
+    }
+}
+
+impl DiskImageSaver for ImdDisk<'_> {
+    /// Save the sectors as a flat image, or a file from the FAT
+    /// filesystem if one is selected
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        match &self.disk {
+            Some(disk) => disk.save_to_writer(config, selected_filename, writer),
+            None => {
+                error!(target: IO, "The IMD image has no sectors");
+                Err(Error::new(ErrorKind::Message(String::from(
+                    "The IMD image has no sectors",
+                ))))
+            }
+        }
+    }
+
+    /// The files in the FAT filesystem on the sectors
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.disk
+            .as_ref()
+            .map(DiskImageSaver::disk_files)
+            .unwrap_or_default()
+    }
+}
+
+impl RawExporter for ImdDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.tracks.iter().map(ImdTrack::logical_track).collect()
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        self.geometry()
+    }
+}
+
+/// The header and comment, and the records of each track
+impl SourceMapper for ImdDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        map.add(RegionKind::Header, "IMD header", start, self.header.length);
+        let ends = self
+            .tracks
+            .iter()
+            .skip(1)
+            .map(|track| track.offset)
+            .chain(std::iter::once(self.data.len()));
+        for (track, end) in self.tracks.iter().zip(ends) {
+            map.add(
+                RegionKind::Data,
+                &format!("track {} side {}", track.cylinder, track.head),
+                start + track.offset,
+                end - track.offset,
+            );
+        }
+        map
+    }
+}
+
+/// Parse the header line and the comment, up to and including the EOF
+/// byte
+pub fn imd_header_parser(data: &[u8]) -> IResult<&[u8], ImdHeader> {
+    let (i, _) = tag(IMD_MAGIC)(data)?;
+    let (i, text) = take_until(&[IMD_COMMENT_END][..])(i)?;
+    let (i, _) = take(1_usize)(i)?;
+
+    let text = String::from_utf8_lossy(text);
+    let (line, comment) = text.split_once("\r\n").unwrap_or((&text, ""));
+    let Some((version, timestamp)) = line.split_once(':') else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            data,
+            nom::error::ErrorKind::Tag,
+        )));
+    };
+    let comment: Vec<&str> = comment.lines().collect();
+
+    Ok((
+        i,
+        ImdHeader {
+            version: String::from(version.trim()),
+            timestamp: String::from(timestamp.trim()),
+            comment: comment.join("\n").trim_end().to_string(),
+            length: data.len() - i.len(),
+        },
+    ))
+}
+
+/// Parse the sector data record of a sector
+fn imd_sector_record_parser(size: usize) -> impl Fn(&[u8]) -> IResult<&[u8], (u8, Vec<u8>)> {
+    move |i| {
+        let (i, record) = le_u8(i)?;
+        match record {
+            0 => Ok((i, (record, Vec::new()))),
+            _ if record > MAX_RECORD => {
+                error!(target: PARSE, "Unknown IMD sector data record {}", record);
+                Err(nom::Err::Error(nom::error::Error::new(
+                    i,
+                    nom::error::ErrorKind::Verify,
+                )))
+            }
+            _ if record % 2 == 0 => {
+                let (i, fill) = le_u8(i)?;
+                Ok((i, (record, vec![fill; size])))
+            }
+            _ => {
+                let (i, data) = take(size)(i)?;
+                Ok((i, (record, data.to_vec())))
+            }
+        }
+    }
+}
+
+/// Parse a track header, its maps and its sectors
+/// base is the whole image, for the offsets of the records.
+fn imd_track_parser(base: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], ImdTrack> + '_ {
+    move |start| {
+        let offset = base.len() - start.len();
+        let (i, mode) = le_u8(start)?;
+        if mode > MAX_MODE {
+            error!(target: PARSE, "Unknown IMD track mode {}", mode);
+            return Err(nom::Err::Error(nom::error::Error::new(
+                start,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (i, cylinder) = le_u8(i)?;
+        let (i, head) = le_u8(i)?;
+        let (i, sector_count) = le_u8(i)?;
+        limit_sectors_per_track(i, usize::from(sector_count))?;
+        let (i, size_code) = le_u8(i)?;
+        if size_code > MAX_SIZE_CODE {
+            error!(target: PARSE, "Unknown IMD sector size code {}", size_code);
+            return Err(nom::Err::Error(nom::error::Error::new(
+                &start[4..],
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let sector_size = 128 << size_code;
+
+        let count = usize::from(sector_count);
+        let (i, numbers) = take(count)(i)?;
+        let (i, cylinders) = if head & CYLINDER_MAP == CYLINDER_MAP {
+            take(count)(i)?
+        } else {
+            (i, &[][..])
+        };
+        let (mut i, heads) = if head & HEAD_MAP == HEAD_MAP {
+            take(count)(i)?
+        } else {
+            (i, &[][..])
+        };
+        let physical_head = head & 0x01;
+
+        let mut sectors = Vec::with_capacity(count);
+        for (index, number) in numbers.iter().enumerate() {
+            let offset = base.len() - i.len();
+            let (rest, (record, data)) = imd_sector_record_parser(sector_size)(i)?;
+            sectors.push(ImdSector {
+                offset,
+                cylinder: cylinders.get(index).copied().unwrap_or(cylinder),
+                head: heads.get(index).copied().unwrap_or(physical_head),
+                sector: *number,
+                record,
+                data,
+            });
+            i = rest;
+        }
+
+        Ok((
+            i,
+            ImdTrack {
+                offset,
+                mode,
+                cylinder,
+                head: physical_head,
+                sector_size,
+                sectors,
+            },
+        ))
+    }
+}
+
+/// Parse an IMD image
+pub fn imd_disk_parser(data: &[u8]) -> IResult<&[u8], ImdDisk<'_>> {
+    let (mut i, header) = imd_header_parser(data)?;
+    debug!(target: PARSE, "IMD header: {}", header);
+
+    let mut tracks = Vec::new();
+    while !i.is_empty() {
+        let (rest, track) = imd_track_parser(data)(i)?;
+        limit_tracks(rest, tracks.len() + 1)?;
+        tracks.push(track);
+        i = rest;
+    }
+
+    let logical_tracks: Vec<LogicalTrack> = tracks.iter().map(ImdTrack::logical_track).collect();
+    let disk = infer_geometry(&logical_tracks).and_then(|geometry| {
+        let image = flatten_tracks(&logical_tracks, &geometry);
+        flat_disk_parser(geometry)(&image)
+            .ok()
+            .map(|(_, disk)| disk)
+    });
+
+    Ok((
+        i,
+        ImdDisk {
+            data,
+            header,
+            tracks,
+            disk,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{imd_disk_parser, imd_header_parser, is_imd};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::logical::{split_tracks, LogicalTrack, RawExporter};
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::source_map::SourceMapper;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test reading the header line and a comment
+    #[test]
+    fn imd_header_parser_works() {
+        let (rest, header) =
+            imd_header_parser(b"IMD 1.18: 23/06/2012 10:47:56\r\nSystem disk\r\nv2\r\n\x1a\x05")
+                .unwrap();
+        assert_eq!(rest, [5]);
+        assert_eq!(header.version, "1.18");
+        assert_eq!(header.timestamp, "23/06/2012 10:47:56");
+        assert_eq!(header.comment, "System disk\nv2");
+        assert_eq!(header.length, 49);
+
+        assert!(!is_imd(b"IMD 1.18 no EOF"));
+        assert!(!is_imd(b"IMD no colon\x1a"));
+    }
+
+    /// Test reading a FAT disk with compressed, bad and remapped
+    /// sectors
+    #[test]
+    fn imd_disk_parser_works() {
+        let geometry = Geometry::atari_st(80, 2, 9);
+        let flat = testgen::atari_st_fat("IMAGEDSK", &[("README.TXT", b"Hello from IMD")]).unwrap();
+        let mut tracks = split_tracks(&flat, &geometry);
+        tracks[3].sectors[2].crc_error = true;
+        tracks[3].sectors[4].deleted = true;
+        tracks[5].sectors.reverse();
+
+        let data = testgen::imd(&tracks, "Backup disk");
+        assert!(is_imd(&data));
+        let (rest, disk) = imd_disk_parser(&data).unwrap();
+        assert!(rest.is_empty());
+        assert!(disk.check());
+        assert_eq!(disk.header.comment, "Backup disk");
+        assert_eq!(disk.tracks.len(), 160);
+        assert_eq!(disk.tracks[5].sectors[0].sector, 9);
+        // Empty sectors are stored as a fill byte
+        assert!(disk.tracks[100].sectors.iter().all(|s| s.compressed()));
+        assert_eq!(disk.bad_sectors(), vec![SectorId::new(1, 1, 3)]);
+        assert!(disk.logical_tracks()[3].sectors[4].deleted);
+        assert_eq!(disk.geometry(), Some(geometry.clone()));
+
+        let mut saved = Vec::new();
+        disk.save_to_writer(&Options::default(), None, &mut saved)
+            .unwrap();
+        assert_eq!(saved, flat);
+        let files = disk.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].data, b"Hello from IMD");
+
+        let map = disk.source_map(&data);
+        assert_eq!(map.regions[0].name, "IMD header");
+        assert_eq!(map.regions.len(), 1 + 160);
+
+        // Sector IDs that differ from the physical track are stored in
+        // cylinder and head maps
+        let mut track = LogicalTrack::new(0, 0);
+        track.sectors = split_tracks(&flat, &geometry)[0].sectors.clone();
+        for sector in &mut track.sectors {
+            sector.id = SectorId::new(40, 1, sector.id.sector.get());
+        }
+        let data = testgen::imd(&[track], "");
+        let (_, disk) = imd_disk_parser(&data).unwrap();
+        assert_eq!(disk.tracks[0].cylinder, 0);
+        assert_eq!(disk.tracks[0].sectors[0].cylinder, 40);
+        assert_eq!(disk.tracks[0].sectors[0].head, 1);
+
+        // Unknown sector data records are errors
+        let mut data = testgen::imd(&tracks[..1], "");
+        let header_length = imd_header_parser(&data).unwrap().1.length;
+        data[header_length + 5 + 9] = 9;
+        assert!(imd_disk_parser(&data).is_err());
+    }
+}
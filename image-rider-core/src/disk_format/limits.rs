@@ -0,0 +1,332 @@
+//! Limits on allocations driven by image headers
+//!
+//! Most formats store track counts, sector counts and block sizes in
+//! their headers, and the parsers size vectors and loops from those
+//! fields.  A damaged or malicious image can claim far more than any
+//! real disk holds, so every header driven allocation is checked
+//! against a set of limits first.
+//!
+//! Containers get the same treatment: archives can hold archives,
+//! which can hold disk images holding more archives.  The depth of
+//! nesting and the total size of the data unpacked from containers
+//! while looking inside one image are limited too, so an archive bomb
+//! can't blow up a run over a large collection.
+//!
+//! The limits default to values well above any supported format.  The
+//! parsers read them from the options they're given, so each image can
+//! be parsed with its own limits, set in the configuration:
+//!
+//! ```toml
+//! max-tracks = 256
+//! max-sectors-per-track = 256
+//! max-file-size = 67108864
+//! max-allocation = 268435456
+//! max-nesting-depth = 4
+//! max-expanded-size = 268435456
+//! ```
+//!
+//! or overridden for a single parse with Options::with_override.
+use std::cell::Cell;
+
+use config::Config;
+use log::error;
+use nom::IResult;
+
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::PARSE;
+
+/// Limits on what an image header can make a parser allocate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// The maximum number of tracks, counting each side separately
+    pub max_tracks: usize,
+    /// The maximum number of sectors on a track
+    pub max_sectors_per_track: usize,
+    /// The maximum size of an image file
+    pub max_file_size: usize,
+    /// The maximum number of bytes headers can claim while parsing a
+    /// single image
+    pub max_allocation: usize,
+    /// The maximum number of containers inside containers that are
+    /// opened, the outermost container is at depth zero
+    pub max_nesting_depth: usize,
+    /// The maximum number of bytes unpacked from containers while
+    /// looking inside a single image
+    pub max_expanded_size: usize,
+}
+
+impl Limits {
+    /// The default limits
+    pub const DEFAULT: Limits = Limits {
+        max_tracks: 256,
+        max_sectors_per_track: 256,
+        max_file_size: 64 * 1024 * 1024,
+        max_allocation: 256 * 1024 * 1024,
+        max_nesting_depth: 4,
+        max_expanded_size: 256 * 1024 * 1024,
+    };
+
+    /// Build limits from a configuration, using the defaults for any
+    /// setting that's missing
+    pub fn from_config(config: &Config) -> Limits {
+        let get = |key: &str, default: usize| {
+            config
+                .get_int(key)
+                .ok()
+                .and_then(|value| usize::try_from(value).ok())
+                .unwrap_or(default)
+        };
+
+        Limits {
+            max_tracks: get("max-tracks", Limits::DEFAULT.max_tracks),
+            max_sectors_per_track: get(
+                "max-sectors-per-track",
+                Limits::DEFAULT.max_sectors_per_track,
+            ),
+            max_file_size: get("max-file-size", Limits::DEFAULT.max_file_size),
+            max_allocation: get("max-allocation", Limits::DEFAULT.max_allocation),
+            max_nesting_depth: get("max-nesting-depth", Limits::DEFAULT.max_nesting_depth),
+            max_expanded_size: get("max-expanded-size", Limits::DEFAULT.max_expanded_size),
+        }
+    }
+
+    /// The maximum number of sectors on a disk
+    /// Used to bound sector chains, which can loop on damaged disks.
+    pub fn max_sectors(&self) -> usize {
+        self.max_tracks.saturating_mul(self.max_sectors_per_track)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::DEFAULT
+    }
+}
+
+thread_local! {
+    /// The limits for the image being parsed
+    static LIMITS: Cell<Limits> = const { Cell::new(Limits::DEFAULT) };
+    /// The bytes claimed by headers in the image being parsed
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    /// The bytes unpacked from containers in the image being parsed
+    static EXPANDED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Return the limits for the image being parsed
+/// These are the defaults outside of with_limits.
+pub fn limits() -> Limits {
+    LIMITS.with(Cell::get)
+}
+
+/// The limits and budgets of an outer parse, put back when a nested
+/// parse ends
+/// Restoring them on drop puts them back even if the parse panics.
+struct OuterLimits {
+    /// The limits of the outer parse
+    limits: Limits,
+    /// The bytes claimed by the outer parse
+    allocated: usize,
+    /// The bytes unpacked by the outer parse
+    expanded: usize,
+}
+
+impl Drop for OuterLimits {
+    fn drop(&mut self) {
+        LIMITS.with(|current| current.set(self.limits));
+        ALLOCATED.with(|allocated| allocated.set(self.allocated));
+        EXPANDED.with(|expanded| expanded.set(self.expanded));
+    }
+}
+
+/// Run a parse with a set of limits
+/// The limits only apply to the current thread.  They and the
+/// allocation and expansion budgets are restored when the parse
+/// returns or panics, so nested parses can use their own.
+pub fn with_limits<T>(limits: Limits, parse: impl FnOnce() -> T) -> T {
+    let _outer = OuterLimits {
+        limits: LIMITS.with(|current| current.replace(limits)),
+        allocated: ALLOCATED.with(Cell::get),
+        expanded: EXPANDED.with(Cell::get),
+    };
+
+    parse()
+}
+
+/// Build the error for a limit that was exceeded
+fn limit_error(what: &str, value: usize, limit: usize) -> Error {
+    error!(target: PARSE, "{} {} exceeds the limit of {}", what, value, limit);
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "{} {} exceeds the limit of {}",
+        what, value, limit
+    ))))
+}
+
+/// Check the size of an image file and start a new allocation budget
+/// for parsing it
+/// Call this before parsing each image.
+pub fn check_file_size(size: usize) -> std::result::Result<(), Error> {
+    ALLOCATED.with(|allocated| allocated.set(0));
+    EXPANDED.with(|expanded| expanded.set(0));
+
+    let max = limits().max_file_size;
+    if size > max {
+        return Err(limit_error("File size", size, max));
+    }
+    Ok(())
+}
+
+/// Build a nom error for a limit check that failed
+fn nom_limit_error(i: &[u8]) -> nom::Err<nom::error::Error<&[u8]>> {
+    nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::TooLarge))
+}
+
+/// Check a track count from a header
+pub fn limit_tracks(i: &[u8], tracks: usize) -> IResult<&[u8], ()> {
+    let max = limits().max_tracks;
+    if tracks > max {
+        limit_error("Track count", tracks, max);
+        return Err(nom_limit_error(i));
+    }
+    Ok((i, ()))
+}
+
+/// Check a sectors per track count from a header
+pub fn limit_sectors_per_track(i: &[u8], sectors: usize) -> IResult<&[u8], ()> {
+    let max = limits().max_sectors_per_track;
+    if sectors > max {
+        limit_error("Sectors per track", sectors, max);
+        return Err(nom_limit_error(i));
+    }
+    Ok((i, ()))
+}
+
+/// Claim bytes from the allocation budget for the image being parsed
+/// Fails once the total claimed since check_file_size exceeds the
+/// limit.
+pub fn limit_allocation(i: &[u8], bytes: usize) -> IResult<&[u8], ()> {
+    let max = limits().max_allocation;
+    let total = ALLOCATED.with(|allocated| {
+        let total = allocated.get().saturating_add(bytes);
+        allocated.set(total);
+        total
+    });
+    if total > max {
+        limit_error("Allocation", total, max);
+        return Err(nom_limit_error(i));
+    }
+    Ok((i, ()))
+}
+
+/// Check the length of a sector chain, returning an error if it's
+/// longer than the disk could hold
+pub fn check_chain_length(length: usize) -> std::result::Result<(), Error> {
+    let max = limits().max_sectors();
+    if length > max {
+        return Err(limit_error("Sector chain length", length, max));
+    }
+    Ok(())
+}
+
+/// Check the depth of a container inside other containers
+/// Call this before opening a nested container.
+pub fn check_nesting_depth(depth: usize) -> std::result::Result<(), Error> {
+    let max = limits().max_nesting_depth;
+    if depth > max {
+        return Err(limit_error("Nesting depth", depth, max));
+    }
+    Ok(())
+}
+
+/// Claim bytes unpacked from a container from the expansion budget
+/// for the image being parsed
+/// Fails once the total claimed since check_file_size exceeds the
+/// limit.
+pub fn check_expanded_size(bytes: usize) -> std::result::Result<(), Error> {
+    let max = limits().max_expanded_size;
+    let total = EXPANDED.with(|expanded| {
+        let total = expanded.get().saturating_add(bytes);
+        expanded.set(total);
+        total
+    });
+    if total > max {
+        return Err(limit_error("Expanded size", total, max));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        check_expanded_size, check_file_size, check_nesting_depth, limit_allocation,
+        limit_sectors_per_track, limit_tracks, limits, with_limits, Limits,
+    };
+
+    /// Test the default limits and loading limits from a configuration
+    #[test]
+    fn limits_work() {
+        assert_eq!(limits(), Limits::default());
+        assert!(limit_tracks(&[], 84).is_ok());
+        assert!(limit_tracks(&[], 65535).is_err());
+        assert!(limit_sectors_per_track(&[], 65535).is_err());
+        assert!(check_file_size(1 << 30).is_err());
+
+        // The budget is per image and starts again for each one
+        check_file_size(174848).unwrap();
+        assert!(limit_allocation(&[], 200 * 1024 * 1024).is_ok());
+        assert!(limit_allocation(&[], 100 * 1024 * 1024).is_err());
+        check_file_size(174848).unwrap();
+        assert!(limit_allocation(&[], 100 * 1024 * 1024).is_ok());
+
+        // Containers nest to a depth and share an expansion budget
+        assert!(check_nesting_depth(4).is_ok());
+        assert!(check_nesting_depth(5).is_err());
+        assert!(check_expanded_size(200 * 1024 * 1024).is_ok());
+        assert!(check_expanded_size(100 * 1024 * 1024).is_err());
+        check_file_size(174848).unwrap();
+        assert!(check_expanded_size(100 * 1024 * 1024).is_ok());
+
+        let config = Config::builder()
+            .set_override("max-tracks", 40)
+            .unwrap()
+            .build()
+            .unwrap();
+        let limits = Limits::from_config(&config);
+        assert_eq!(limits.max_tracks, 40);
+        assert_eq!(limits.max_file_size, Limits::DEFAULT.max_file_size);
+        assert_eq!(limits.max_nesting_depth, Limits::DEFAULT.max_nesting_depth);
+        assert_eq!(limits.max_sectors(), 40 * 256);
+
+        // Limits apply to a single parse
+        with_limits(limits, || {
+            assert!(limit_tracks(&[], 41).is_err());
+            with_limits(Limits::default(), || assert!(limit_tracks(&[], 41).is_ok()));
+            assert!(limit_tracks(&[], 41).is_err());
+        });
+        assert!(limit_tracks(&[], 41).is_ok());
+    }
+
+    /// Test that the outer limits and budgets are restored after a
+    /// parse panics
+    #[test]
+    fn with_limits_restores_after_panic() {
+        check_file_size(174848).unwrap();
+        assert!(limit_allocation(&[], 200 * 1024 * 1024).is_ok());
+
+        let limited = Limits {
+            max_tracks: 40,
+            ..Limits::default()
+        };
+        let result = std::panic::catch_unwind(|| {
+            with_limits(limited, || {
+                check_file_size(174848).unwrap();
+                panic!("parser bug");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(limits(), Limits::default());
+        // The outer parse's budget is still claimed
+        assert!(limit_allocation(&[], 100 * 1024 * 1024).is_err());
+    }
+}
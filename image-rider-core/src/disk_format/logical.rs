@@ -0,0 +1,523 @@
+//! Logical track and sector model shared between image formats
+//!
+//! Each image format has its own structures that mirror the file
+//! layout.  These structures are a common, format-independent view of
+//! the decoded sectors on a disk, used when converting between formats
+//! or exporting tracks.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
+
+use crate::disk_format::geometry::{Geometry, Head, Sector, SectorId};
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// A single decoded sector
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogicalSector {
+    /// The location of the sector, taken from the sector's address
+    /// field when the format has one
+    pub id: SectorId,
+    /// The sector data
+    pub data: Vec<u8>,
+    /// True if the sector data failed a CRC or checksum test
+    pub crc_error: bool,
+    /// True if the sector was written with a deleted data mark
+    pub deleted: bool,
+}
+
+impl LogicalSector {
+    /// Create a new LogicalSector with good data
+    pub fn new(id: SectorId, data: Vec<u8>) -> LogicalSector {
+        LogicalSector {
+            id,
+            data,
+            crc_error: false,
+            deleted: false,
+        }
+    }
+
+    /// The IBM size code for this sector (0 = 128 bytes, 1 = 256
+    /// bytes, 2 = 512 bytes, 3 = 1024 bytes)
+    pub fn size_code(&self) -> u8 {
+        size_code_for(self.data.len())
+    }
+}
+
+/// Return the IBM sector size code for a sector size in bytes
+pub fn size_code_for(size: usize) -> u8 {
+    let mut code = 0;
+    while (128_usize << code) < size && code < 7 {
+        code += 1;
+    }
+    code
+}
+
+/// A single track on one side of the disk
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LogicalTrack {
+    /// The physical track (cylinder) number
+    pub track: u8,
+    /// The head (side) number
+    pub head: u8,
+    /// The sectors on the track, in the order they appear on disk
+    pub sectors: Vec<LogicalSector>,
+}
+
+impl LogicalTrack {
+    /// Create a new empty LogicalTrack
+    pub fn new(track: u8, head: u8) -> LogicalTrack {
+        LogicalTrack {
+            track,
+            head,
+            sectors: Vec::new(),
+        }
+    }
+
+    /// Find a sector on this track by sector number
+    pub fn sector(&self, sector: impl Into<Sector>) -> Option<&LogicalSector> {
+        let sector = sector.into();
+        self.sectors.iter().find(|s| s.id.sector == sector)
+    }
+
+    /// Merge repeated reads of the same sector, as found when more than
+    /// one revolution of a track is decoded
+    /// The first good copy of each sector is kept, or the first copy if
+    /// every read had a CRC error.  Sectors keep the order they were
+    /// first seen in.
+    pub fn merge_duplicates(&mut self) {
+        let mut merged: Vec<LogicalSector> = Vec::new();
+
+        for sector in self.sectors.drain(..) {
+            match merged.iter_mut().find(|s| s.id == sector.id) {
+                Some(existing) => {
+                    if existing.crc_error && !sector.crc_error {
+                        *existing = sector;
+                    }
+                }
+                None => merged.push(sector),
+            }
+        }
+
+        self.sectors = merged;
+    }
+}
+
+/// Display a LogicalTrack
+impl Display for LogicalTrack {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "track: {}, head: {}, sectors: {}",
+            self.track,
+            self.head,
+            self.sectors.len()
+        )
+    }
+}
+
+/// How the sides of a double-sided disk are ordered in a flat image
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SideOrder {
+    /// The sides of each track are next to each other (track 0 side
+    /// 0, track 0 side 1, track 1 side 0...), as in .st images
+    #[default]
+    Alternating,
+    /// Every track on side 0, then every track on side 1
+    Sequential,
+}
+
+impl FromStr for SideOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<SideOrder, Error> {
+        match s.to_lowercase().as_str() {
+            "alternating" => Ok(SideOrder::Alternating),
+            "sequential" => Ok(SideOrder::Sequential),
+            _ => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Unknown side order: {}", s),
+            )))),
+        }
+    }
+}
+
+/// The order sectors are written in a flat sector dump
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RawOrder {
+    /// The order of the sides of each track
+    pub sides: SideOrder,
+    /// The sector written at each position on a track, counting from
+    /// the geometry's first sector.  Empty, or a table that doesn't
+    /// match the number of sectors on a track, writes sectors in
+    /// sector number order.
+    pub interleave: Vec<u8>,
+}
+
+impl RawOrder {
+    /// Return the sector number written at a position on a track with
+    /// a number of sectors
+    fn sector_at(&self, position: u8, sectors: u8, first_sector: u8) -> u8 {
+        let index = match self.interleave.get(usize::from(position)) {
+            Some(index) if self.interleave.len() == usize::from(sectors) => *index,
+            _ => position,
+        };
+        first_sector.wrapping_add(index)
+    }
+}
+
+/// Build a flat sector dump from a set of tracks
+/// Sectors are matched to the geometry using the physical track and
+/// head numbers of the track and the sector number of the sector, and
+/// written in the chosen side and sector order.  Missing sectors are
+/// filled with zeros and sectors that don't fit in the geometry are
+/// ignored.
+pub fn export_raw(tracks: &[LogicalTrack], order: &RawOrder, geometry: &Geometry) -> Vec<u8> {
+    let mut sectors: BTreeMap<SectorId, &[u8]> = BTreeMap::new();
+    for track in tracks {
+        for sector in &track.sectors {
+            let id = SectorId::new(track.track, track.head, sector.id.sector);
+            sectors.insert(id, &sector.data);
+        }
+    }
+
+    let mut track_order: Vec<(u8, u8)> = Vec::new();
+    match order.sides {
+        SideOrder::Alternating => {
+            for track_index in 0..geometry.tracks() {
+                for head in 0..geometry.heads {
+                    track_order.push((track_index as u8, head));
+                }
+            }
+        }
+        SideOrder::Sequential => {
+            for head in 0..geometry.heads {
+                for track_index in 0..geometry.tracks() {
+                    track_order.push((track_index as u8, head));
+                }
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(geometry.total_size());
+    for (track_index, head) in track_order {
+        let sector_count = geometry.sectors_per_track[usize::from(track_index)];
+        for position in 0..sector_count {
+            let id = SectorId::new(
+                geometry.first_track + track_index,
+                head,
+                order.sector_at(position, sector_count, geometry.first_sector),
+            );
+            let start = data.len();
+            data.resize(start + geometry.sector_size, 0);
+            if let Some(sector_data) = sectors.get(&id) {
+                let len = sector_data.len().min(geometry.sector_size);
+                data[start..start + len].copy_from_slice(&sector_data[..len]);
+            }
+        }
+    }
+
+    data
+}
+
+/// Build a flat sector image from a set of tracks
+/// This is a sector dump in the standard order for the geometry, see
+/// export_raw.
+pub fn flatten_tracks(tracks: &[LogicalTrack], geometry: &Geometry) -> Vec<u8> {
+    export_raw(tracks, &RawOrder::default(), geometry)
+}
+
+/// Disk types that can be exported as a flat sector dump
+pub trait RawExporter {
+    /// Return the decoded sectors on the disk, grouped by track and side
+    fn logical_tracks(&self) -> Vec<LogicalTrack>;
+
+    /// Return the natural geometry of the disk, used when saving it
+    /// Returns None if the disk has no sectors.
+    fn raw_geometry(&self) -> Option<Geometry> {
+        infer_geometry(&self.logical_tracks())
+    }
+
+    /// Export the disk as a flat sector dump with a chosen geometry,
+    /// side order and interleave
+    fn export_raw(&self, order: &RawOrder, geometry: &Geometry) -> Vec<u8> {
+        export_raw(&self.logical_tracks(), order, geometry)
+    }
+}
+
+/// Guess a uniform geometry from a set of decoded tracks
+/// The most common sector count and sector size are used, which
+/// ignores extra or oversized sectors on copy-protected tracks.
+/// Returns None if there are no sectors, or if the track, head or
+/// sector counts don't fit a geometry.
+pub fn infer_geometry(tracks: &[LogicalTrack]) -> Option<Geometry> {
+    let mut sector_counts: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut sector_sizes: BTreeMap<usize, usize> = BTreeMap::new();
+
+    for track in tracks.iter().filter(|t| !t.sectors.is_empty()) {
+        *sector_counts.entry(track.sectors.len()).or_default() += 1;
+        for sector in &track.sectors {
+            *sector_sizes.entry(sector.data.len()).or_default() += 1;
+        }
+    }
+
+    let most_common = |counts: &BTreeMap<usize, usize>| {
+        counts
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map(|(value, _)| *value)
+    };
+    let sectors = most_common(&sector_counts)?;
+    let sector_size = most_common(&sector_sizes)?;
+    let track_count = tracks.iter().map(|t| t.track).max()?.checked_add(1)?;
+    let heads = tracks.iter().map(|t| t.head).max()?.checked_add(1)?;
+    let first_sector = tracks
+        .iter()
+        .flat_map(|t| t.sectors.iter())
+        .map(|s| s.id.sector.get())
+        .min()?;
+
+    Some(Geometry::uniform(
+        track_count,
+        heads,
+        u8::try_from(sectors).ok()?,
+        sector_size,
+        0,
+        first_sector,
+    ))
+}
+
+/// Split a flat sector image into tracks using a geometry
+pub fn split_tracks(data: &[u8], geometry: &Geometry) -> Vec<LogicalTrack> {
+    let mut tracks: Vec<LogicalTrack> = Vec::new();
+
+    for id in geometry.sector_ids() {
+        let sector_data = match geometry.sector(data, &id) {
+            Some(d) => d.to_vec(),
+            None => continue,
+        };
+        match tracks.last_mut() {
+            Some(t) if t.track == id.track.get() && t.head == id.head.get() => {}
+            _ => tracks.push(LogicalTrack::new(id.track.get(), id.head.get())),
+        }
+        if let Some(track) = tracks.last_mut() {
+            track.sectors.push(LogicalSector::new(id, sector_data));
+        }
+    }
+
+    tracks
+}
+
+/// Merge separate dumps of each side of a disk into one double-sided
+/// flat image
+/// Some archives store each side of a disk as its own single-sided
+/// dump, e.g. .d0 and .d1 files or BBC .ssd pairs.  Each side is split
+/// with the single-sided geometry, numbered by its position in the
+/// list and written in the chosen side order.  Returns the merged image
+/// and its geometry, or an error if the geometry has more than one
+/// side or a dump isn't the size of the geometry.
+pub fn merge_sides(
+    sides: &[&[u8]],
+    side_geometry: &Geometry,
+    order: SideOrder,
+) -> std::result::Result<(Vec<u8>, Geometry), Error> {
+    if side_geometry.heads != 1 {
+        return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!(
+                "Sides must be single-sided dumps, the geometry has {} sides",
+                side_geometry.heads
+            ),
+        ))));
+    }
+    let heads = u8::try_from(sides.len())
+        .ok()
+        .filter(|heads| *heads > 0)
+        .ok_or_else(|| {
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                "Can't merge {} sides",
+                sides.len()
+            ))))
+        })?;
+
+    let mut tracks = Vec::new();
+    for (head, side) in sides.iter().enumerate() {
+        if side.len() != side_geometry.total_size() {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "Side {} is {} bytes, expected {}",
+                    head,
+                    side.len(),
+                    side_geometry.total_size()
+                ),
+            ))));
+        }
+        for mut track in split_tracks(side, side_geometry) {
+            track.head = head as u8;
+            for sector in &mut track.sectors {
+                sector.id.head = Head(head as u8);
+            }
+            tracks.push(track);
+        }
+    }
+
+    let geometry = Geometry {
+        heads,
+        ..side_geometry.clone()
+    };
+    let order = RawOrder {
+        sides: order,
+        interleave: Vec::new(),
+    };
+    Ok((export_raw(&tracks, &order, &geometry), geometry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        export_raw, flatten_tracks, infer_geometry, merge_sides, size_code_for, split_tracks,
+        LogicalTrack, RawOrder, SideOrder,
+    };
+    use crate::disk_format::geometry::{Geometry, Sector};
+
+    /// Test merging separate side dumps in both side orders
+    #[test]
+    fn merge_sides_works() {
+        let side_geometry = Geometry::atari_st(80, 1, 9);
+        let side_a = vec![0xAA; side_geometry.total_size()];
+        let side_b = vec![0xBB; side_geometry.total_size()];
+        let track_size = 9 * 512;
+
+        let (merged, geometry) =
+            merge_sides(&[&side_a, &side_b], &side_geometry, SideOrder::Alternating).unwrap();
+        assert_eq!(geometry, Geometry::atari_st(80, 2, 9));
+        assert_eq!(merged.len(), 737280);
+        assert!(merged[..track_size].iter().all(|b| *b == 0xAA));
+        assert!(merged[track_size..2 * track_size]
+            .iter()
+            .all(|b| *b == 0xBB));
+        assert!(merged[2 * track_size..3 * track_size]
+            .iter()
+            .all(|b| *b == 0xAA));
+
+        let (merged, _) =
+            merge_sides(&[&side_a, &side_b], &side_geometry, SideOrder::Sequential).unwrap();
+        assert_eq!(merged[..side_a.len()], side_a[..]);
+        assert_eq!(merged[side_a.len()..], side_b[..]);
+
+        assert!(merge_sides(
+            &[&side_a, &side_b[1..]],
+            &side_geometry,
+            SideOrder::Alternating
+        )
+        .is_err());
+        assert!(merge_sides(&[], &side_geometry, SideOrder::Alternating).is_err());
+        assert!(merge_sides(
+            &[&side_a],
+            &Geometry::atari_st(40, 2, 9),
+            SideOrder::Alternating
+        )
+        .is_err());
+    }
+
+    /// Test computing sector size codes
+    #[test]
+    fn size_code_for_works() {
+        assert_eq!(size_code_for(128), 0);
+        assert_eq!(size_code_for(256), 1);
+        assert_eq!(size_code_for(512), 2);
+        assert_eq!(size_code_for(1024), 3);
+    }
+
+    /// Test that splitting and flattening a flat image round-trips
+    #[test]
+    fn split_and_flatten_tracks_works() {
+        let geometry = Geometry::atari_st(80, 2, 9);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i / 512) as u8)
+            .collect();
+
+        let tracks = split_tracks(&data, &geometry);
+        assert_eq!(tracks.len(), 160);
+        assert_eq!(tracks[1].track, 0);
+        assert_eq!(tracks[1].head, 1);
+        assert_eq!(tracks[1].sectors[0].id.sector, Sector(1));
+        assert_eq!(tracks[1].sectors[0].data[0], 9);
+
+        assert_eq!(flatten_tracks(&tracks, &geometry), data);
+    }
+
+    /// Test exporting sectors with a side order and interleave
+    #[test]
+    fn export_raw_works() {
+        let geometry = Geometry::atari_st(2, 2, 3);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i / 512) as u8)
+            .collect();
+        let tracks = split_tracks(&data, &geometry);
+
+        let order = RawOrder {
+            sides: SideOrder::Sequential,
+            interleave: vec![0, 2, 1],
+        };
+        let raw = export_raw(&tracks, &order, &geometry);
+        let sectors: Vec<u8> = raw.chunks(512).map(|sector| sector[0]).collect();
+        // Track 0 side 0, track 1 side 0, track 0 side 1, track 1 side 1
+        assert_eq!(sectors, [0, 2, 1, 6, 8, 7, 3, 5, 4, 9, 11, 10]);
+
+        // An interleave that doesn't match the track is ignored
+        let order = RawOrder {
+            sides: SideOrder::Alternating,
+            interleave: vec![1, 0],
+        };
+        assert_eq!(export_raw(&tracks, &order, &geometry), data);
+        assert_eq!(
+            "sequential".parse::<SideOrder>().unwrap(),
+            SideOrder::Sequential
+        );
+        assert!("backwards".parse::<SideOrder>().is_err());
+    }
+
+    /// Test guessing a geometry from decoded tracks
+    #[test]
+    fn infer_geometry_works() {
+        let geometry = Geometry::atari_st(80, 2, 10);
+        let data = vec![0_u8; geometry.total_size()];
+        let mut tracks = split_tracks(&data, &geometry);
+
+        // A copy-protected track with an extra sector shouldn't change
+        // the guess
+        let mut extra = tracks[5].sectors[0].clone();
+        extra.id.sector = Sector(66);
+        tracks[5].sectors.push(extra);
+
+        assert_eq!(infer_geometry(&tracks), Some(geometry));
+        assert_eq!(infer_geometry(&[]), None);
+
+        // Head and track numbers of 255 and more than 255 sectors on a
+        // track don't fit a geometry
+        let mut track = LogicalTrack::new(0, 255);
+        track.sectors.push(tracks[0].sectors[0].clone());
+        assert_eq!(infer_geometry(&[track.clone()]), None);
+        track.head = 0;
+        track.track = 255;
+        assert_eq!(infer_geometry(&[track.clone()]), None);
+        track.track = 0;
+        track.sectors = vec![tracks[0].sectors[0].clone(); 300];
+        assert_eq!(infer_geometry(&[track]), None);
+    }
+
+    /// Test merging sectors read on more than one revolution
+    #[test]
+    fn merge_duplicates_works() {
+        let geometry = Geometry::atari_st(1, 1, 2);
+        let data = vec![0_u8; geometry.total_size()];
+        let mut track = split_tracks(&data, &geometry).remove(0);
+        let mut bad_read = track.sectors.clone();
+        bad_read[0].crc_error = true;
+        bad_read[0].data[0] = 0xFF;
+        let good_read = track.sectors.clone();
+        track.sectors = bad_read;
+        track.sectors.extend(good_read.clone());
+
+        track.merge_duplicates();
+        assert_eq!(track.sectors, good_read);
+    }
+}
@@ -0,0 +1,464 @@
+//! DiskCopy 4.2 disk images
+//!
+//! DiskCopy 4.2 images hold Macintosh 400K and 800K GCR disks, Apple
+//! II 800K disks and 720K and 1.4M MFM disks.  The sectors are stored
+//! in block order after an 84 byte header of big-endian fields,
+//! followed by the 12 tag bytes of each sector, if the disk has them:
+//!
+//! ```ignore
+//! 00: the disk name, a Pascal string of up to 63 characters
+//! 40: the size of the sector data
+//! 44: the size of the tag data
+//! 48: the checksum of the sector data
+//! 4C: the checksum of the tag data
+//! 50: the disk encoding, 0 for 400K GCR, 1 for 800K GCR, 2 for 720K
+//!     MFM and 3 for 1.4M MFM
+//! 51: the format byte, e.g. 12 for 400K Macintosh disks, 22 for
+//!     larger Macintosh disks and 24 for Apple II 800K disks
+//! 52: 0100
+//! 54: the sector data, then the tag data
+//! ```
+//!
+//! Each checksum adds the data as 16 bit words, rotating the 32 bit
+//! sum right by one bit after each word.  The tag checksum skips the
+//! tags of the first sector.
+//!
+//! There's no signature, so images are recognized by the fixed word
+//! at 52 and sizes that add up to the size of the file.
+//!
+//! Information from:\
+//! [DiskCopy 4.2 format](https://www.discferret.com/wiki/Apple_DiskCopy_4.2)
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, info};
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{be_u32, be_u8};
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::Geometry;
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::logical::{split_tracks, LogicalTrack, RawExporter};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::error::{Error, ErrorKind, Location};
+use crate::log_target::{IO, PARSE};
+
+/// A DiskCopy 4.2 image
+pub const DC42_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "DC42",
+    description: "Macintosh or Apple ][ DiskCopy 4.2 disk image",
+    extensions: &["dc42", "image"],
+    sizes: &[SizeRange::at_least(DC42_HEADER_SIZE)],
+    references: &[Reference {
+        title: "DiskCopy 4.2 format",
+        url: Some("https://www.discferret.com/wiki/Apple_DiskCopy_4.2"),
+    }],
+    capabilities: Capabilities {
+        tracks: true,
+        checksums: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The size of the DiskCopy 4.2 header
+pub const DC42_HEADER_SIZE: usize = 0x54;
+
+/// The fixed word at the end of the header
+const DC42_PRIVATE: &[u8] = &[0x01, 0x00];
+
+/// The number of tag bytes for each sector
+pub const DC42_TAG_SIZE: usize = 12;
+
+/// The size of each sector
+const SECTOR_SIZE: usize = 512;
+
+/// The way the disk was recorded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiskEncoding {
+    /// A single-sided 400K Macintosh GCR disk
+    GCR400K,
+    /// A double-sided 800K Macintosh or Apple II GCR disk
+    GCR800K,
+    /// A 720K double density MFM disk
+    MFM720K,
+    /// A 1.4M high density MFM disk
+    MFM1440K,
+    /// An encoding this parser doesn't know
+    Unknown(u8),
+}
+
+impl DiskEncoding {
+    /// Return the geometry of disks with this encoding, None for
+    /// unknown encodings
+    pub fn geometry(&self) -> Option<Geometry> {
+        match self {
+            DiskEncoding::GCR400K => Some(Geometry::macintosh(1)),
+            DiskEncoding::GCR800K => Some(Geometry::macintosh(2)),
+            DiskEncoding::MFM720K => Some(Geometry::uniform(80, 2, 9, 512, 0, 1)),
+            DiskEncoding::MFM1440K => Some(Geometry::uniform(80, 2, 18, 512, 0, 1)),
+            DiskEncoding::Unknown(_) => None,
+        }
+    }
+}
+
+impl From<u8> for DiskEncoding {
+    fn from(encoding: u8) -> DiskEncoding {
+        match encoding {
+            0 => DiskEncoding::GCR400K,
+            1 => DiskEncoding::GCR800K,
+            2 => DiskEncoding::MFM720K,
+            3 => DiskEncoding::MFM1440K,
+            _ => DiskEncoding::Unknown(encoding),
+        }
+    }
+}
+
+impl Display for DiskEncoding {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            DiskEncoding::GCR400K => write!(f, "400K GCR"),
+            DiskEncoding::GCR800K => write!(f, "800K GCR"),
+            DiskEncoding::MFM720K => write!(f, "720K MFM"),
+            DiskEncoding::MFM1440K => write!(f, "1.4M MFM"),
+            DiskEncoding::Unknown(encoding) => write!(f, "unknown encoding {}", encoding),
+        }
+    }
+}
+
+/// The DiskCopy 4.2 header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiskCopyHeader {
+    /// The disk name
+    pub name: String,
+    /// The size of the sector data
+    pub data_size: u32,
+    /// The size of the tag data
+    pub tag_size: u32,
+    /// The checksum of the sector data
+    pub data_checksum: u32,
+    /// The checksum of the tag data
+    pub tag_checksum: u32,
+    /// The way the disk was recorded
+    pub encoding: DiskEncoding,
+    /// The format byte
+    pub format: u8,
+}
+
+impl SanityCheck for DiskCopyHeader {
+    fn check(&self) -> bool {
+        let sectors = self.data_size as usize / SECTOR_SIZE;
+        if self.data_size == 0 || self.data_size as usize % SECTOR_SIZE != 0 {
+            debug!(
+                target: PARSE,
+                "DiskCopy data size {} isn't a whole number of sectors", self.data_size
+            );
+            return false;
+        }
+        if self.tag_size != 0 && self.tag_size as usize != sectors * DC42_TAG_SIZE {
+            debug!(
+                target: PARSE,
+                "DiskCopy tag size {} doesn't match {} sectors", self.tag_size, sectors
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Return the DiskCopy checksum of some data
+/// The data is added as big-endian 16 bit words, and the sum is
+/// rotated right one bit after each word.
+pub fn dc42_checksum(data: &[u8]) -> u32 {
+    data.chunks(2).fold(0_u32, |sum, word| {
+        let word = u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0));
+        sum.wrapping_add(word).rotate_right(1)
+    })
+}
+
+/// Return the checksum of the tag data, which skips the tags of the
+/// first sector
+fn tag_checksum(tags: &[u8]) -> u32 {
+    dc42_checksum(tags.get(DC42_TAG_SIZE..).unwrap_or_default())
+}
+
+/// True if the data looks like a DiskCopy 4.2 image
+/// The name length, the fixed word and the sizes in the header have
+/// to match the data.
+pub fn is_dc42(data: &[u8]) -> bool {
+    let Ok((_, header)) = dc42_header_parser(data) else {
+        return false;
+    };
+    header.check()
+        && DC42_HEADER_SIZE as u64 + u64::from(header.data_size) + u64::from(header.tag_size)
+            == data.len() as u64
+}
+
+/// A DiskCopy 4.2 disk
+pub struct DiskCopyDisk<'a> {
+    /// The header
+    pub header: DiskCopyHeader,
+    /// The whole image
+    pub data: &'a [u8],
+    /// The sector data, in block order
+    pub sectors: &'a [u8],
+    /// The tag data, 12 bytes for each sector, empty if the disk
+    /// has no tags
+    pub tags: &'a [u8],
+    /// True if the checksum of the sector data matches the header
+    pub data_checksum_valid: bool,
+    /// True if the checksum of the tag data matches the header
+    pub tag_checksum_valid: bool,
+}
+
+impl DiskCopyDisk<'_> {
+    /// Return the geometry of the disk, None if the encoding is unknown
+    /// or the data doesn't fill the disk
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.header
+            .encoding
+            .geometry()
+            .filter(|geometry| geometry.total_size() == self.sectors.len())
+    }
+
+    /// Return the tags of a block, None if the disk has no tags
+    pub fn block_tags(&self, block: usize) -> Option<&[u8]> {
+        self.tags
+            .get(block * DC42_TAG_SIZE..(block + 1) * DC42_TAG_SIZE)
+    }
+}
+
+impl Display for DiskCopyDisk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "\"{}\", {}, format {:02X}, {} blocks",
+            self.header.name,
+            self.header.encoding,
+            self.header.format,
+            self.sectors.len() / SECTOR_SIZE
+        )?;
+        if !self.tags.is_empty() {
+            write!(f, " with tags")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for DiskCopyDisk<'_> {
+    fn check(&self) -> bool {
+        self.header.check() && self.data_checksum_valid && self.tag_checksum_valid
+    }
+}
+
+impl DiskImageSaver for DiskCopyDisk<'_> {
+    /// Save the sector data, without the header and tags
+    /// There's no filesystem to select files from.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        if let Some(selected_filename) = selected_filename {
+            error!(target: IO, "No filesystem to read {} from", selected_filename);
+            return Err(Error::new(ErrorKind::NotFound(format!(
+                "File not found: {}",
+                selected_filename
+            ))));
+        }
+        info!(target: IO, "Found image data, writing data");
+        writer.write_all(self.sectors)?;
+        Ok(())
+    }
+
+    /// DiskCopy images are read as sectors, they have no files
+    fn disk_files(&self) -> Vec<DiskFile> {
+        Vec::new()
+    }
+}
+
+impl RawExporter for DiskCopyDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.geometry()
+            .map(|geometry| split_tracks(self.sectors, &geometry))
+            .unwrap_or_default()
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        self.geometry()
+    }
+}
+
+impl SourceMapper for DiskCopyDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        map.add(
+            RegionKind::Header,
+            "DiskCopy header",
+            start,
+            DC42_HEADER_SIZE,
+        );
+        map.add_slice(data, self.sectors, RegionKind::Data, "sector data");
+        if !self.tags.is_empty() {
+            map.add_slice(data, self.tags, RegionKind::Data, "tag data");
+        }
+        map
+    }
+}
+
+/// Parse the DiskCopy 4.2 header
+pub fn dc42_header_parser(i: &[u8]) -> IResult<&[u8], DiskCopyHeader> {
+    let (i, name_length) = be_u8(i)?;
+    let (i, name) = take(63_usize)(i)?;
+    let (i, data_size) = be_u32(i)?;
+    let (i, tag_size) = be_u32(i)?;
+    let (i, data_checksum) = be_u32(i)?;
+    let (i, tag_checksum) = be_u32(i)?;
+    let (i, encoding) = be_u8(i)?;
+    let (i, format) = be_u8(i)?;
+    let (i, _private) = tag(DC42_PRIVATE)(i)?;
+    let name = name
+        .get(..usize::from(name_length))
+        .ok_or_else(|| nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify)))?;
+
+    Ok((
+        i,
+        DiskCopyHeader {
+            name: name.iter().map(|c| char::from(*c)).collect(),
+            data_size,
+            tag_size,
+            data_checksum,
+            tag_checksum,
+            encoding: DiskEncoding::from(encoding),
+            format,
+        },
+    ))
+}
+
+/// Parse a DiskCopy 4.2 image
+/// The checksums are checked unless ignore-checksums is set, in which
+/// case a mismatch is recorded as a warning.
+pub fn dc42_disk_parser(
+    config: &Config,
+) -> impl Fn(&[u8]) -> IResult<&[u8], DiskCopyDisk<'_>> + '_ {
+    move |data| {
+        let (i, header) = dc42_header_parser(data)?;
+        if !header.check() {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                &data[0x40..],
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (i, sectors) = take(header.data_size)(i)?;
+        let (i, tags) = take(header.tag_size)(i)?;
+
+        let ignore_checksums = config.get_bool("ignore-checksums").unwrap_or(false);
+        let data_checksum_valid = dc42_checksum(sectors) == header.data_checksum;
+        if !data_checksum_valid {
+            if ignore_checksums {
+                warning(
+                    Warning::new("ignored DiskCopy data checksum mismatch")
+                        .with_location(Location::offset(0x48).with_format("DC42")),
+                );
+            } else {
+                error!(target: PARSE, "DiskCopy data checksum doesn't match");
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    &data[0x48..],
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+        }
+        // Some tools write a bad tag checksum, and the tags aren't
+        // needed to read the disk
+        let tag_checksum_valid = tag_checksum(tags) == header.tag_checksum;
+        if !tag_checksum_valid {
+            warning(
+                Warning::new("DiskCopy tag checksum mismatch")
+                    .with_location(Location::offset(0x4C).with_format("DC42")),
+            );
+        }
+
+        Ok((
+            i,
+            DiskCopyDisk {
+                header,
+                data: &data[..data.len() - i.len()],
+                sectors,
+                tags,
+                data_checksum_valid,
+                tag_checksum_valid,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{dc42_checksum, dc42_disk_parser, is_dc42, DiskEncoding};
+    use crate::disk_format::geometry::SectorId;
+    use crate::disk_format::logical::RawExporter;
+    use crate::disk_format::parsed::collect_warnings;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test parsing an 800K disk with tags and checking its checksums
+    #[test]
+    fn dc42_disk_parser_works() {
+        assert_eq!(dc42_checksum(&[0x00, 0x02, 0x00, 0x04]), 0x8000_0002);
+
+        let sectors: Vec<u8> = (0..819200).map(|i| (i / 512) as u8).collect();
+        let data = testgen::dc42("Backup", 1, &sectors, true);
+        assert!(is_dc42(&data));
+        assert!(!is_dc42(&data[..data.len() - 1]));
+
+        let config = Config::default();
+        let (_, disk) = dc42_disk_parser(&config)(&data).unwrap();
+        assert_eq!(disk.header.name, "Backup");
+        assert_eq!(disk.header.encoding, DiskEncoding::GCR800K);
+        assert_eq!(disk.sectors, &sectors[..]);
+        assert_eq!(disk.tags.len(), 1600 * 12);
+        assert_eq!(disk.block_tags(1), Some(&[1; 12][..]));
+        assert_eq!(
+            disk.to_string(),
+            "\"Backup\", 800K GCR, format 22, 1600 blocks with tags"
+        );
+        let tracks = disk.logical_tracks();
+        assert_eq!(tracks.len(), 160);
+        // Track 0 side 1 follows the 12 sectors of side 0
+        assert_eq!(tracks[1].sector(0).unwrap().id, SectorId::new(0, 1, 0));
+        assert_eq!(tracks[1].sector(0).unwrap().data, vec![12; 512]);
+
+        // A damaged sector fails the data checksum unless checksums
+        // are ignored
+        let mut damaged = data.clone();
+        damaged[0x54 + 1000] ^= 0xFF;
+        assert!(dc42_disk_parser(&config)(&damaged).is_err());
+        let ignore = Options::default()
+            .with_override("ignore-checksums", true)
+            .unwrap();
+        let parsed =
+            collect_warnings(|| dc42_disk_parser(&ignore)(&damaged).map(|(_, d)| d)).unwrap();
+        assert!(!parsed.value.data_checksum_valid);
+        assert_eq!(parsed.warnings.len(), 1);
+
+        // A damaged tag is only a warning
+        let mut damaged = data.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xFF;
+        let parsed =
+            collect_warnings(|| dc42_disk_parser(&config)(&damaged).map(|(_, d)| d)).unwrap();
+        assert!(!parsed.value.tag_checksum_valid);
+        assert_eq!(parsed.warnings.len(), 1);
+    }
+}
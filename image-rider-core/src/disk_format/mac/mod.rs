@@ -0,0 +1,10 @@
+//! Parse a Macintosh disk image
+//!
+//! Currently this includes support for reading the sectors and tags
+//! of DiskCopy 4.2 images.
+//!
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// DiskCopy 4.2 disk images
+pub mod diskcopy;
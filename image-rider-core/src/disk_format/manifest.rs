@@ -0,0 +1,200 @@
+//! Sector checksum manifests for detecting bit rot
+//!
+//! A manifest records the CRC-32 of every decoded sector of an image.
+//! Storing it next to an archived image lets the image be checked
+//! later without keeping a second copy: sectors whose checksums no
+//! longer match have changed.  Because it covers the decoded sectors,
+//! a manifest made from one container matches the same disk in
+//! another, e.g. a nibble image and the DOS order image made from it.
+//!
+//! The manifest is a text file with a header line and one line per
+//! sector, the track, head and sector number and the CRC-32 in hex:
+//!
+//! ```text
+//! image-rider manifest 1
+//! 0 0 0 1A2B3C4D
+//! 0 0 1 00000000
+//! ```
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
+
+use crate::disk_format::checksum::crc32;
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::LogicalTrack;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The first line of a manifest, with the format version
+const MANIFEST_HEADER: &str = "image-rider manifest 1";
+
+/// The CRC-32 of every sector of an image
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    /// The CRC-32 of each sector
+    pub sectors: BTreeMap<SectorId, u32>,
+}
+
+/// The differences between an image and a manifest
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ManifestDiff {
+    /// Sectors whose data has changed
+    pub changed: Vec<SectorId>,
+    /// Sectors in the manifest that are missing from the image
+    pub missing: Vec<SectorId>,
+    /// Sectors in the image that aren't in the manifest
+    pub added: Vec<SectorId>,
+}
+
+impl ManifestDiff {
+    /// True if the image matches the manifest
+    pub fn is_clean(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.added.is_empty()
+    }
+}
+
+impl Display for ManifestDiff {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        if self.is_clean() {
+            return write!(f, "All sectors match the manifest");
+        }
+        let lines: Vec<String> = [
+            ("changed", &self.changed),
+            ("missing", &self.missing),
+            ("added", &self.added),
+        ]
+        .iter()
+        .flat_map(|(what, sectors)| sectors.iter().map(move |id| format!("{}: {}", what, id)))
+        .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Manifest {
+    /// Build a manifest from the decoded sectors of an image
+    pub fn from_tracks(tracks: &[LogicalTrack]) -> Manifest {
+        Manifest {
+            sectors: tracks
+                .iter()
+                .flat_map(|track| {
+                    track.sectors.iter().map(|sector| {
+                        (
+                            SectorId::new(track.track, track.head, sector.id.sector),
+                            crc32(&sector.data),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Compare a later manifest of the same image with this one
+    pub fn verify(&self, current: &Manifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        for (id, crc) in &self.sectors {
+            match current.sectors.get(id) {
+                Some(current_crc) if current_crc != crc => diff.changed.push(*id),
+                Some(_) => (),
+                None => diff.missing.push(*id),
+            }
+        }
+        diff.added = current
+            .sectors
+            .keys()
+            .filter(|id| !self.sectors.contains_key(id))
+            .copied()
+            .collect();
+
+        diff
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "{}", MANIFEST_HEADER)?;
+        for (id, crc) in &self.sectors {
+            writeln!(f, "{} {} {} {:08X}", id.track, id.head, id.sector, crc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build an error for a bad manifest line
+fn invalid_line(number: usize, line: &str) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "Invalid manifest line {}: {}",
+        number, line
+    ))))
+}
+
+impl FromStr for Manifest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Manifest, Error> {
+        let mut lines = s.lines();
+        if lines.next().map(str::trim) != Some(MANIFEST_HEADER) {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                String::from("Not an image-rider manifest"),
+            ))));
+        }
+
+        let mut manifest = Manifest::default();
+        for (n, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let (id, crc) = match fields[..] {
+                [track, head, sector, crc] => (
+                    track.parse().and_then(|t: u8| {
+                        Ok(SectorId::new(t, head.parse::<u8>()?, sector.parse::<u8>()?))
+                    }),
+                    u32::from_str_radix(crc, 16),
+                ),
+                _ => return Err(invalid_line(n + 2, line)),
+            };
+            match (id, crc) {
+                (Ok(id), Ok(crc)) => manifest.sectors.insert(id, crc),
+                _ => return Err(invalid_line(n + 2, line)),
+            };
+        }
+
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Manifest;
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test writing, reading and verifying a manifest
+    #[test]
+    fn manifest_works() {
+        let geometry = Geometry::apple_dos_33(2);
+        let mut data = vec![0_u8; geometry.total_size()];
+        let manifest = Manifest::from_tracks(&split_tracks(&data, &geometry));
+        assert_eq!(manifest.sectors.len(), 32);
+
+        let text = manifest.to_string();
+        assert!(text.starts_with("image-rider manifest 1\n0 0 0 "));
+        let read: Manifest = text.parse().unwrap();
+        assert_eq!(read, manifest);
+        assert!(manifest.verify(&read).is_clean());
+
+        data[4096 + 3 * 256 + 10] = 0x55;
+        let current = Manifest::from_tracks(&split_tracks(&data[..4096 + 4 * 256], &geometry));
+        let diff = manifest.verify(&current);
+        assert_eq!(diff.changed, vec![SectorId::new(1, 0, 3)]);
+        assert_eq!(diff.missing.len(), 12);
+        assert!(diff.added.is_empty());
+        assert!(diff
+            .to_string()
+            .starts_with("changed: track: 1, head: 0, sector: 3\n"));
+
+        assert!("image-rider manifest 1\n0 0 x 0\n"
+            .parse::<Manifest>()
+            .is_err());
+        assert!("0 0 0 0\n".parse::<Manifest>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! MFM track encoding and decoding
+//!
+//! IBM System/34 style MFM tracks are used by the Atari ST, PC and
+//! many other machines.  Each data bit is written as two bit cells, a
+//! clock bit followed by the data bit.  The clock bit is one only when
+//! the previous and current data bits are both zero.
+//!
+//! Address and data fields are preceded by three 0xA1 sync bytes with
+//! a missing clock bit (the raw pattern 0x4489), which can't appear in
+//! normally encoded data.
+//!
+//! The track layout written here is:
+//!
+//! ```ignore
+//! Gap 1: 60 x 0x4E
+//! For each sector:
+//!   12 x 0x00, 3 x 0xA1 (sync), 0xFE, track, head, sector, size, CRC
+//!   Gap 2: 22 x 0x4E
+//!   12 x 0x00, 3 x 0xA1 (sync), 0xFB (0xF8 if deleted), data, CRC
+//!   Gap 3: 40 x 0x4E
+//! Gap 4: 0x4E until the end of the track
+//! ```
+//!
+//! Information from:\
+//! [Hatari](https://github.com/hatari/hatari.git) fdc.c\
+//! [Greaseweazle](https://github.com/keirf/greaseweazle.git) codec/ibm
+use log::{debug, warn};
+
+use crate::disk_format::checksum::Crc16;
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+use crate::log_target::CONVERT;
+
+/// The raw bit pattern of an 0xA1 sync byte with a missing clock bit
+pub const MFM_SYNC: u16 = 0x4489;
+
+/// The number of data bytes on a double density track spinning at 300
+/// RPM with a 250 kbit/s data rate
+pub const DD_TRACK_LENGTH: usize = 6250;
+
+/// ID address mark
+pub const ID_ADDRESS_MARK: u8 = 0xFE;
+
+/// Data address mark
+pub const DATA_ADDRESS_MARK: u8 = 0xFB;
+
+/// Deleted data address mark
+pub const DELETED_DATA_ADDRESS_MARK: u8 = 0xF8;
+
+/// Gap filler byte
+const GAP_BYTE: u8 = 0x4E;
+
+/// Encodes data bytes into MFM bit cells
+#[derive(Debug, Default)]
+pub struct MfmEncoder {
+    /// The encoded bit cells
+    bits: Vec<bool>,
+    /// The last data bit written, used to compute the next clock bit
+    last_data_bit: bool,
+}
+
+impl MfmEncoder {
+    /// Create a new empty encoder
+    pub fn new() -> MfmEncoder {
+        MfmEncoder::default()
+    }
+
+    /// Encode a single data byte
+    pub fn write_byte(&mut self, byte: u8) {
+        for i in (0..8).rev() {
+            let data_bit = (byte >> i) & 0x01 == 0x01;
+            let clock_bit = !self.last_data_bit && !data_bit;
+            self.bits.push(clock_bit);
+            self.bits.push(data_bit);
+            self.last_data_bit = data_bit;
+        }
+    }
+
+    /// Encode a byte count times
+    pub fn write_bytes(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Write an 0xA1 sync byte with the missing clock bit
+    pub fn write_sync(&mut self) {
+        for i in (0..16).rev() {
+            self.bits.push((MFM_SYNC >> i) & 0x01 == 0x01);
+        }
+        self.last_data_bit = true;
+    }
+
+    /// The number of bit cells written so far
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// True if nothing has been written
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Return the encoded bit cells
+    pub fn into_bits(self) -> Vec<bool> {
+        self.bits
+    }
+}
+
+/// Compute the CRC of an address or data field, including the three
+/// sync bytes and the address mark
+pub fn field_crc(mark: u8, data: &[u8]) -> u16 {
+    Crc16::new()
+        .update(&[0xA1, 0xA1, 0xA1, mark])
+        .update(data)
+        .finish()
+}
+
+/// Encode a track of sectors as MFM bit cells
+/// Sectors with crc_error set are written with a bad data CRC so the
+/// error survives the round trip.
+pub fn encode_track(track: &LogicalTrack) -> Vec<bool> {
+    let mut encoder = MfmEncoder::new();
+
+    encoder.write_bytes(GAP_BYTE, 60);
+
+    for sector in &track.sectors {
+        let id = [
+            sector.id.track.get(),
+            sector.id.head.get(),
+            sector.id.sector.get(),
+            sector.size_code(),
+        ];
+        encoder.write_bytes(0x00, 12);
+        for _ in 0..3 {
+            encoder.write_sync();
+        }
+        encoder.write_byte(ID_ADDRESS_MARK);
+        for byte in id {
+            encoder.write_byte(byte);
+        }
+        for byte in field_crc(ID_ADDRESS_MARK, &id).to_be_bytes() {
+            encoder.write_byte(byte);
+        }
+        encoder.write_bytes(GAP_BYTE, 22);
+
+        let mark = if sector.deleted {
+            DELETED_DATA_ADDRESS_MARK
+        } else {
+            DATA_ADDRESS_MARK
+        };
+        encoder.write_bytes(0x00, 12);
+        for _ in 0..3 {
+            encoder.write_sync();
+        }
+        encoder.write_byte(mark);
+        for byte in &sector.data {
+            encoder.write_byte(*byte);
+        }
+        let mut crc = field_crc(mark, &sector.data);
+        if sector.crc_error {
+            crc = !crc;
+        }
+        for byte in crc.to_be_bytes() {
+            encoder.write_byte(byte);
+        }
+        encoder.write_bytes(GAP_BYTE, 40);
+    }
+
+    let bytes_written = encoder.len() / 16;
+    if bytes_written < DD_TRACK_LENGTH {
+        encoder.write_bytes(GAP_BYTE, DD_TRACK_LENGTH - bytes_written);
+    } else {
+        warn!(
+            target: CONVERT,
+            "Track {} head {} is longer than a standard track: {} bytes",
+            track.track, track.head, bytes_written
+        );
+    }
+
+    encoder.into_bits()
+}
+
+/// Read sixteen raw bit cells starting at position
+fn raw_word(bits: &[bool], position: usize) -> Option<u16> {
+    let cells = bits.get(position..position + 16)?;
+    Some(cells.iter().fold(0, |acc, b| (acc << 1) | u16::from(*b)))
+}
+
+/// Decode the data byte stored in the sixteen bit cells starting at
+/// position
+fn decode_byte(bits: &[bool], position: usize) -> Option<u8> {
+    let cells = bits.get(position..position + 16)?;
+    Some(
+        cells
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .fold(0, |acc, b| (acc << 1) | u8::from(*b)),
+    )
+}
+
+/// Decode count data bytes starting at position
+fn decode_bytes(bits: &[bool], position: usize, count: usize) -> Option<Vec<u8>> {
+    (0..count)
+        .map(|i| decode_byte(bits, position + i * 16))
+        .collect()
+}
+
+/// Decode the sectors on an MFM track
+/// The track and head are the physical location of the track, sector
+/// IDs are taken from the address fields on the track.  Sectors with a
+/// bad data CRC are returned with crc_error set, sectors with a bad
+/// address field CRC are skipped.
+pub fn decode_track(bits: &[bool], track: u8, head: u8) -> LogicalTrack {
+    let mut logical_track = LogicalTrack::new(track, head);
+    let mut pending_id: Option<(SectorId, u8)> = None;
+    let mut shift: u16 = 0;
+    let mut position = 0;
+
+    while position < bits.len() {
+        shift = (shift << 1) | u16::from(bits[position]);
+        position += 1;
+        if shift != MFM_SYNC {
+            continue;
+        }
+
+        // Skip the remaining sync bytes
+        while raw_word(bits, position) == Some(MFM_SYNC) {
+            position += 16;
+        }
+        let mark = match decode_byte(bits, position) {
+            Some(m) => m,
+            None => break,
+        };
+        position += 16;
+        shift = 0;
+
+        match mark {
+            ID_ADDRESS_MARK => {
+                let field = match decode_bytes(bits, position, 6) {
+                    Some(f) => f,
+                    None => break,
+                };
+                position += 6 * 16;
+                let crc = u16::from_be_bytes([field[4], field[5]]);
+                if crc != field_crc(ID_ADDRESS_MARK, &field[0..4]) {
+                    debug!(
+                        target: CONVERT,
+                        "Bad address field CRC on track {} head {}",
+                        track,
+                        head,
+                    );
+                    pending_id = None;
+                    continue;
+                }
+                pending_id = Some((SectorId::new(field[0], field[1], field[2]), field[3]));
+            }
+            DATA_ADDRESS_MARK | DELETED_DATA_ADDRESS_MARK => {
+                let (id, size_code) = match pending_id.take() {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let size = 128_usize << (size_code & 0x07);
+                let field = match decode_bytes(bits, position, size + 2) {
+                    Some(f) => f,
+                    None => break,
+                };
+                position += (size + 2) * 16;
+                let crc = u16::from_be_bytes([field[size], field[size + 1]]);
+                let data = field[0..size].to_vec();
+                let crc_error = crc != field_crc(mark, &data);
+                if crc_error {
+                    debug!(target: CONVERT, "Bad data CRC for sector {}", id);
+                }
+                logical_track.sectors.push(LogicalSector {
+                    id,
+                    data,
+                    crc_error,
+                    deleted: mark == DELETED_DATA_ADDRESS_MARK,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    logical_track
+}
+
+/// Pack bit cells into bytes, most significant bit first
+/// The last byte is padded with zero bits.
+pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, b)| acc | (u8::from(*b) << (7 - i)))
+        })
+        .collect()
+}
+
+/// Unpack bytes into bit cells, most significant bit first
+pub fn unpack_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 0x01 == 0x01))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_track, encode_track, pack_bits, unpack_bits, MfmEncoder, DD_TRACK_LENGTH};
+    use crate::disk_format::geometry::SectorId;
+    use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+
+    /// Build a test track with nine 512 byte sectors
+    fn test_track() -> LogicalTrack {
+        let mut track = LogicalTrack::new(3, 1);
+        for sector in 1..=9 {
+            track.sectors.push(LogicalSector::new(
+                SectorId::new(3, 1, sector),
+                (0..512).map(|i| (i as u8).wrapping_mul(sector)).collect(),
+            ));
+        }
+        track
+    }
+
+    /// Test encoding single bytes
+    #[test]
+    fn encode_byte_works() {
+        let mut encoder = MfmEncoder::new();
+        encoder.write_byte(0x4E);
+        encoder.write_sync();
+        encoder.write_byte(0x00);
+        let bytes = pack_bits(&encoder.into_bits());
+
+        // 0x4E after a zero bit encodes as 0x9254, 0x00 after the
+        // sync byte's final one bit encodes as 0x2AAA
+        assert_eq!(bytes, vec![0x92, 0x54, 0x44, 0x89, 0x2A, 0xAA]);
+    }
+
+    /// Test that encoding and decoding a track round-trips
+    #[test]
+    fn encode_decode_track_works() {
+        let mut track = test_track();
+        track.sectors[2].deleted = true;
+        track.sectors[4].crc_error = true;
+
+        let bits = encode_track(&track);
+        assert_eq!(bits.len(), DD_TRACK_LENGTH * 16);
+
+        let decoded = decode_track(&bits, 3, 1);
+        assert_eq!(decoded, track);
+    }
+
+    /// Test that decoding works when the track doesn't start on a byte
+    /// boundary
+    #[test]
+    fn decode_unaligned_track_works() {
+        let track = test_track();
+        let mut bits = vec![false, true, false];
+        bits.extend(encode_track(&track));
+
+        let packed = pack_bits(&bits);
+        let decoded = decode_track(&unpack_bits(&packed), 3, 1);
+        assert_eq!(decoded, track);
+    }
+}
@@ -0,0 +1,156 @@
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+//!
+//! Disk format parsers
+//!
+
+/// Sanity checking trait
+pub mod sanity_check;
+
+/// image parser, parses disk images and ROM images
+pub mod image;
+
+/// Track and sector geometry for flat images
+pub mod geometry;
+
+/// Sector overlays, differential images
+pub mod overlay;
+
+/// Blank formatted images and their fill patterns
+pub mod blank;
+
+/// Logical tracks and sectors shared between formats
+pub mod logical;
+
+/// MFM track encoding and decoding
+pub mod mfm;
+
+/// Flux transition data
+pub mod flux;
+
+/// Individual track files for hardware tools
+pub mod track_files;
+
+/// Sector editing sessions with undo and redo
+pub mod editor;
+
+/// Search for bytes and strings on a disk
+pub mod search;
+
+/// File selection and naming for exporting files
+pub mod file_select;
+
+/// File carving for disks with damaged filesystems
+pub mod carve;
+
+/// Entropy analysis and content classification
+pub mod analysis;
+
+/// JSON reports on disk images
+pub mod report;
+
+/// Human readable summaries of disk images
+pub mod summary;
+
+/// Image-wide statistics
+pub mod stats;
+
+/// Sector usage maps and hidden data detection
+pub mod usage;
+
+/// Source maps of where parsed structures are in an image
+pub mod source_map;
+
+/// Tree views of disk images for browsers
+pub mod tree;
+
+/// Limits on allocations driven by image headers
+pub mod limits;
+
+/// Character sets for names and text on disks
+pub mod charset;
+
+/// Minimal valid images for tests and fuzz seeds
+pub mod testgen;
+
+/// Checksums shared between formats and tools
+pub mod checksum;
+
+/// Fingerprints of known system areas
+pub mod fingerprint;
+
+/// Format guesses from the contents of an image
+pub mod guess;
+
+/// Format identification from signatures and headers alone
+pub mod identify;
+
+/// Descriptions of the supported formats and their capabilities
+pub mod format_info;
+
+/// Reading images from block devices
+pub mod device;
+
+/// A cache of parsed results keyed by image content
+pub mod cache;
+
+/// Cancelling long operations and following their progress
+pub mod cancel;
+
+/// Sector checksum manifests for detecting bit rot
+pub mod manifest;
+
+/// Reed-Solomon parity sidecars for repairing damaged sectors
+#[cfg(feature = "parity")]
+pub mod parity;
+
+/// Recognition of archives stored in files on disks
+pub mod archive;
+
+/// External converters for formats that aren't read natively
+pub mod converter;
+
+/// Parse results with warnings
+pub mod parsed;
+
+/// Explanations for parser failures
+pub mod diagnose;
+
+/// Duplicate and variant detection across a collection of images
+pub mod collection;
+
+/// Multi-volume image sets
+pub mod image_set;
+
+/// Commodore disk images
+pub mod commodore;
+
+/// STX disk images
+pub mod stx;
+
+/// FAT filesystems
+pub mod fat;
+
+/// Apple disk images
+pub mod apple;
+
+/// Amiga disk images
+pub mod amiga;
+
+/// CP/M filesystems
+pub mod cpm;
+
+/// CPCEMU DSK and extended DSK disk images
+pub mod dsk;
+
+/// Macintosh disk images
+pub mod mac;
+
+/// HxC Floppy Emulator HFE disk images
+pub mod hfe;
+
+/// Teledisk TD0 disk images
+pub mod td0;
+
+/// ImageDisk IMD disk images
+pub mod imd;
@@ -0,0 +1,290 @@
+//! Sector overlays, differential images stored separately from a base image
+//!
+//! An overlay contains only the sectors that differ from a pristine
+//! base image.  Emulators and flash carts use the same idea to keep
+//! save-game changes out of an original dump.  Applying the overlay to
+//! the base image produces the modified image.
+//!
+//! The overlay file layout is:
+//!
+//! ```ignore
+//! Magic "IROV"
+//! Version (1 byte, currently 1)
+//! Reserved (1 byte)
+//! Sector size (2 bytes, little endian)
+//! Base image size (4 bytes, little endian)
+//! Sector count (4 bytes, little endian)
+//! Sector records x sector count:
+//!   Track, head, sector, reserved (1 byte each)
+//!   Sector data (sector size bytes)
+//! ```
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use nom::bytes::complete::{tag, take};
+use nom::combinator::verify;
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::CONVERT;
+use crate::serialize::Serializer;
+
+/// The current overlay file version
+pub const OVERLAY_VERSION: u8 = 1;
+
+/// A set of modified sectors for a base image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Overlay {
+    /// The size of each sector in bytes
+    pub sector_size: usize,
+    /// The size of the base image this overlay was created from
+    pub base_size: usize,
+    /// The modified sectors, indexed by location
+    pub sectors: BTreeMap<SectorId, Vec<u8>>,
+}
+
+/// Compare two flat images sector by sector, returning the sectors
+/// that differ
+pub fn changed_sectors(
+    base: &[u8],
+    modified: &[u8],
+    geometry: &Geometry,
+) -> std::result::Result<Vec<SectorId>, Error> {
+    if base.len() != modified.len() {
+        return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+            format!(
+                "Image sizes differ: base {}, modified {}",
+                base.len(),
+                modified.len()
+            ),
+        ))));
+    }
+
+    Ok(geometry
+        .sector_ids()
+        .into_iter()
+        .filter(|id| geometry.sector(base, id) != geometry.sector(modified, id))
+        .collect())
+}
+
+impl Overlay {
+    /// Build an overlay containing every sector of modified that differs
+    /// from base
+    pub fn diff(
+        base: &[u8],
+        modified: &[u8],
+        geometry: &Geometry,
+    ) -> std::result::Result<Overlay, Error> {
+        let mut sectors = BTreeMap::new();
+
+        for id in changed_sectors(base, modified, geometry)? {
+            if let Some(data) = geometry.sector(modified, &id) {
+                debug!(target: CONVERT, "Sector changed: {}", id);
+                sectors.insert(id, data.to_vec());
+            }
+        }
+
+        Ok(Overlay {
+            sector_size: geometry.sector_size,
+            base_size: base.len(),
+            sectors,
+        })
+    }
+
+    /// Apply the overlay to a base image, returning the modified image
+    pub fn apply(&self, base: &[u8], geometry: &Geometry) -> std::result::Result<Vec<u8>, Error> {
+        if base.len() != self.base_size {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "Overlay was created for a {} byte image, base image is {} bytes",
+                    self.base_size,
+                    base.len()
+                ),
+            ))));
+        }
+        if geometry.sector_size != self.sector_size {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "Overlay sector size {} doesn't match geometry sector size {}",
+                    self.sector_size, geometry.sector_size
+                ),
+            ))));
+        }
+
+        let mut data = base.to_vec();
+
+        for (id, sector_data) in &self.sectors {
+            let offset = geometry.offset(id).ok_or_else(|| {
+                Error::new(ErrorKind::NotFound(format!(
+                    "Overlay sector not in image: {}",
+                    id
+                )))
+            })?;
+            let sector = data
+                .get_mut(offset..offset + self.sector_size)
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound(format!(
+                        "Overlay sector past end of image: {}",
+                        id
+                    )))
+                })?;
+            sector.copy_from_slice(sector_data);
+        }
+
+        Ok(data)
+    }
+}
+
+impl<'a> Serializer<'a> for Overlay {
+    fn as_vec(&'a self) -> std::result::Result<Vec<u8>, Error> {
+        let sector_size: u16 = self.sector_size.try_into().map_err(|_| {
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                "Sector size too large for overlay: {}",
+                self.sector_size
+            ))))
+        })?;
+        let base_size: u32 = self.base_size.try_into().map_err(|_| {
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                "Base image too large for overlay: {}",
+                self.base_size
+            ))))
+        })?;
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"IROV");
+        bytes.push(OVERLAY_VERSION);
+        bytes.push(0);
+        bytes.extend_from_slice(&sector_size.to_le_bytes());
+        bytes.extend_from_slice(&base_size.to_le_bytes());
+        bytes.extend_from_slice(&(self.sectors.len() as u32).to_le_bytes());
+
+        for (id, data) in &self.sectors {
+            if data.len() != self.sector_size {
+                return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    format!("Overlay sector has the wrong size: {}", id),
+                ))));
+            }
+            bytes.extend_from_slice(&[id.track.get(), id.head.get(), id.sector.get(), 0]);
+            bytes.extend_from_slice(data);
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Parse a single overlay sector record
+fn overlay_sector_parser(
+    sector_size: usize,
+) -> impl Fn(&[u8]) -> IResult<&[u8], (SectorId, Vec<u8>)> {
+    move |i| {
+        let (i, track) = le_u8(i)?;
+        let (i, head) = le_u8(i)?;
+        let (i, sector) = le_u8(i)?;
+        let (i, _reserved) = le_u8(i)?;
+        let (i, data) = take(sector_size)(i)?;
+
+        Ok((i, (SectorId::new(track, head, sector), data.to_vec())))
+    }
+}
+
+/// Parse an overlay file
+pub fn overlay_parser(i: &[u8]) -> IResult<&[u8], Overlay> {
+    let (i, _magic) = tag("IROV")(i)?;
+    let (i, _version) = verify(le_u8, |v: &u8| *v == OVERLAY_VERSION)(i)?;
+    let (i, _reserved) = le_u8(i)?;
+    let (i, sector_size) = le_u16(i)?;
+    let (i, base_size) = le_u32(i)?;
+    let (i, sector_count) = le_u32(i)?;
+
+    // Every record is at least four bytes, don't trust the count
+    // beyond what the data can hold
+    let record_size = usize::from(sector_size) + 4;
+    if (sector_count as usize).saturating_mul(record_size) > i.len() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Eof,
+        )));
+    }
+
+    let (i, records) = count(
+        overlay_sector_parser(sector_size.into()),
+        sector_count as usize,
+    )(i)?;
+
+    Ok((
+        i,
+        Overlay {
+            sector_size: sector_size.into(),
+            base_size: base_size as usize,
+            sectors: records.into_iter().collect(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_sectors, overlay_parser, Overlay};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::serialize::Serializer;
+
+    /// Test that diffing two images and applying the overlay round-trips
+    #[test]
+    fn overlay_diff_and_apply_works() {
+        let geometry = Geometry::apple_dos_33(35);
+        let base = vec![0_u8; 143360];
+        let mut modified = base.clone();
+        modified[0x11000] = 0x04;
+        modified[0x11005] = 0x05;
+        modified[143359] = 0xFF;
+
+        let changed = changed_sectors(&base, &modified, &geometry).unwrap();
+        assert_eq!(
+            changed,
+            vec![SectorId::new(17, 0, 0), SectorId::new(34, 0, 15)]
+        );
+
+        let overlay = Overlay::diff(&base, &modified, &geometry).unwrap();
+        assert_eq!(overlay.sectors.len(), 2);
+
+        let applied = overlay.apply(&base, &geometry).unwrap();
+        assert_eq!(applied, modified);
+    }
+
+    /// Test that serializing and parsing an overlay round-trips
+    #[test]
+    fn overlay_serialize_round_trip_works() {
+        let geometry = Geometry::commodore_1541(35);
+        let base = vec![0_u8; 174848];
+        let mut modified = base.clone();
+        modified[0x16500] = 0x12;
+
+        let overlay = Overlay::diff(&base, &modified, &geometry).unwrap();
+        let bytes = overlay.as_vec().unwrap();
+        assert_eq!(bytes.len(), 16 + 4 + 256);
+        assert_eq!(&bytes[0..4], b"IROV");
+
+        let (_, parsed) = overlay_parser(&bytes).unwrap();
+        assert_eq!(parsed, overlay);
+        assert!(parsed.sectors.contains_key(&SectorId::new(18, 0, 0)));
+    }
+
+    /// Test that applying an overlay to the wrong base image fails
+    #[test]
+    fn overlay_apply_wrong_base_fails() {
+        let geometry = Geometry::apple_dos_33(35);
+        let base = vec![0_u8; 143360];
+        let overlay = Overlay::diff(&base, &base, &geometry).unwrap();
+
+        match overlay.apply(&base[0..1024], &geometry) {
+            Ok(_) => panic!("Should fail applying overlay to a different image"),
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "Image is invalid: Overlay was created for a 143360 byte image, base image is 1024 bytes"
+            ),
+        }
+    }
+}
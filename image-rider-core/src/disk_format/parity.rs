@@ -0,0 +1,727 @@
+//! Reed-Solomon parity sidecars for repairing damaged sectors
+//!
+//! Like PAR2 files for archives, a parity sidecar holds enough
+//! redundant data to rebuild sectors of an image that have rotted or
+//! couldn't be read, without keeping a second copy of the image.
+//!
+//! The sectors of the image are taken in track, head and sector order
+//! and split into stripes.  Each stripe gets a number of parity blocks
+//! computed with a systematic Reed-Solomon code over GF(2^8), so any
+//! sectors of a stripe can be rebuilt as long as no more of them are
+//! damaged than there are parity blocks.  The sidecar also records the
+//! CRC-32 of every sector and parity block, which is how damaged ones
+//! are found.
+//!
+//! The sidecar file layout is:
+//!
+//! ```ignore
+//! Magic "IRPA"
+//! Version (1 byte, currently 1)
+//! Reserved (1 byte)
+//! Sectors per stripe (2 bytes, little endian)
+//! Parity blocks per stripe (2 bytes, little endian)
+//! Block size (4 bytes, little endian)
+//! Sector count (4 bytes, little endian)
+//! Sector records x sector count:
+//!   Track, head, sector (1 byte each)
+//!   Sector size (4 bytes, little endian)
+//!   Sector CRC-32 (4 bytes, little endian)
+//! Parity blocks x stripes x parity blocks per stripe:
+//!   Block CRC-32 (4 bytes, little endian)
+//!   Block data (block size bytes)
+//! ```
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+
+use config::Config;
+use log::{debug, warn};
+
+use nom::bytes::complete::{tag, take};
+use nom::combinator::verify;
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::checksum::crc32;
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+use crate::log_target::CONVERT;
+use crate::serialize::Serializer;
+
+/// The current parity file version
+pub const PARITY_VERSION: u8 = 1;
+
+/// The usual file extension for parity sidecars
+pub const PARITY_EXTENSION: &str = "irpar";
+
+/// How much parity to generate
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParityOptions {
+    /// The number of sectors in each stripe
+    pub stripe_sectors: usize,
+    /// The number of parity blocks for each stripe, the most sectors
+    /// of a stripe that can be repaired
+    pub parity_sectors: usize,
+}
+
+impl ParityOptions {
+    /// The default options, eight parity blocks for every 64 sectors
+    pub const DEFAULT: ParityOptions = ParityOptions {
+        stripe_sectors: 64,
+        parity_sectors: 8,
+    };
+
+    /// Load the options from a configuration, using the defaults for
+    /// any setting that's missing
+    pub fn from_config(config: &Config) -> ParityOptions {
+        let get = |key: &str, default: usize| {
+            config
+                .get_int(key)
+                .ok()
+                .and_then(|value| usize::try_from(value).ok())
+                .unwrap_or(default)
+        };
+
+        ParityOptions {
+            stripe_sectors: get(
+                "parity-stripe-sectors",
+                ParityOptions::DEFAULT.stripe_sectors,
+            ),
+            parity_sectors: get("parity-sectors", ParityOptions::DEFAULT.parity_sectors),
+        }
+    }
+
+    /// Check the stripe fits in the code
+    /// GF(2^8) has room for 256 data and parity blocks in a stripe.
+    fn check(&self) -> std::result::Result<(), Error> {
+        if self.stripe_sectors == 0
+            || self.parity_sectors == 0
+            || self.stripe_sectors + self.parity_sectors > 256
+        {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!(
+                    "Invalid parity stripe: {} sectors with {} parity blocks, at least one of each and at most 256 in total",
+                    self.stripe_sectors, self.parity_sectors
+                ),
+            ))));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ParityOptions {
+    fn default() -> ParityOptions {
+        ParityOptions::DEFAULT
+    }
+}
+
+/// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1
+mod gf {
+    /// The field polynomial, without the x^8 term
+    const POLYNOMIAL: u16 = 0x11D;
+
+    /// Powers of the generator, twice over so products don't need a
+    /// modulo
+    const EXP: [u8; 512] = {
+        let mut exp = [0_u8; 512];
+        let mut value: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = value as u8;
+            exp[i + 255] = value as u8;
+            value <<= 1;
+            if value & 0x100 != 0 {
+                value ^= POLYNOMIAL;
+            }
+            i += 1;
+        }
+        exp
+    };
+
+    /// Discrete logarithms of the non-zero elements
+    const LOG: [u8; 256] = {
+        let mut log = [0_u8; 256];
+        let mut i = 0;
+        while i < 255 {
+            log[EXP[i] as usize] = i as u8;
+            i += 1;
+        }
+        log
+    };
+
+    /// Multiply two elements
+    pub fn mul(a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        EXP[usize::from(LOG[usize::from(a)]) + usize::from(LOG[usize::from(b)])]
+    }
+
+    /// The multiplicative inverse of a non-zero element
+    pub fn inv(a: u8) -> u8 {
+        EXP[255 - usize::from(LOG[usize::from(a)])]
+    }
+
+    /// Add a multiple of one block to another
+    pub fn mul_add(dest: &mut [u8], factor: u8, src: &[u8]) {
+        if factor == 0 {
+            return;
+        }
+        for (d, s) in dest.iter_mut().zip(src) {
+            *d ^= mul(factor, *s);
+        }
+    }
+}
+
+/// The coefficient of a data sector in a parity block
+/// The parity rows form a Cauchy matrix, every square submatrix of it
+/// can be inverted so any set of damaged sectors up to the parity
+/// count can be solved for.
+fn coefficient(parity_sectors: usize, row: usize, column: usize) -> u8 {
+    gf::inv((row as u8) ^ ((parity_sectors + column) as u8))
+}
+
+/// Invert a square matrix over GF(2^8)
+/// Returns None if the matrix is singular.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|row| (0..n).map(|column| u8::from(row == column)).collect())
+        .collect();
+
+    for column in 0..n {
+        let pivot = (column..n).find(|row| matrix[*row][column] != 0)?;
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = gf::inv(matrix[column][column]);
+        for value in matrix[column].iter_mut().chain(inverse[column].iter_mut()) {
+            *value = gf::mul(*value, scale);
+        }
+
+        for row in (0..n).filter(|row| *row != column) {
+            let factor = matrix[row][column];
+            if factor != 0 {
+                let pivot_row = matrix[column].clone();
+                gf::mul_add(&mut matrix[row], factor, &pivot_row);
+                let pivot_row = inverse[column].clone();
+                gf::mul_add(&mut inverse[row], factor, &pivot_row);
+            }
+        }
+    }
+
+    Some(inverse)
+}
+
+/// A sector covered by a parity sidecar
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParitySector {
+    /// The location of the sector
+    pub id: SectorId,
+    /// The size of the sector in bytes
+    pub size: usize,
+    /// The CRC-32 of the sector data
+    pub crc: u32,
+}
+
+/// A block of parity data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParityBlock {
+    /// The CRC-32 of the parity data
+    pub crc: u32,
+    /// The parity data, one block size long
+    pub data: Vec<u8>,
+}
+
+/// Parity data over the sectors of an image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Parity {
+    /// The stripe layout
+    pub options: ParityOptions,
+    /// The size of the parity blocks, the largest sector size
+    /// Shorter sectors are padded with zeroes.
+    pub block_size: usize,
+    /// The sectors, in the order they're striped
+    pub sectors: Vec<ParitySector>,
+    /// The parity blocks, options.parity_sectors for each stripe
+    pub blocks: Vec<ParityBlock>,
+}
+
+/// The outcome of a repair
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    /// Sectors that were damaged or missing and have been rebuilt
+    pub repaired: Vec<SectorId>,
+    /// Sectors that are damaged or missing and couldn't be rebuilt,
+    /// because too many sectors or parity blocks in their stripe are
+    /// damaged
+    pub unrecoverable: Vec<SectorId>,
+}
+
+impl RepairReport {
+    /// True if every sector now matches the parity data
+    pub fn is_complete(&self) -> bool {
+        self.unrecoverable.is_empty()
+    }
+}
+
+impl Display for RepairReport {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let lines: Vec<String> = [
+            ("repaired", &self.repaired),
+            ("unrecoverable", &self.unrecoverable),
+        ]
+        .iter()
+        .flat_map(|(what, sectors)| sectors.iter().map(move |id| format!("{}: {}", what, id)))
+        .collect();
+        if lines.is_empty() {
+            write!(f, "No damaged sectors found")
+        } else {
+            write!(f, "{}", lines.join("\n"))
+        }
+    }
+}
+
+/// Return the first copy of each sector of a set of tracks, by location
+fn sector_data(tracks: &[LogicalTrack]) -> BTreeMap<SectorId, &[u8]> {
+    let mut sectors = BTreeMap::new();
+    for track in tracks {
+        for sector in &track.sectors {
+            sectors
+                .entry(SectorId::new(track.track, track.head, sector.id.sector))
+                .or_insert(sector.data.as_slice());
+        }
+    }
+    sectors
+}
+
+impl Parity {
+    /// Generate parity data for the sectors of a set of tracks
+    pub fn new(
+        tracks: &[LogicalTrack],
+        options: &ParityOptions,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<Parity, Error> {
+        options.check()?;
+        let data = sector_data(tracks);
+        let block_size = data.values().map(|d| d.len()).max().ok_or_else(|| {
+            Error::new(ErrorKind::Message(String::from(
+                "The disk has no sectors to protect",
+            )))
+        })?;
+
+        let sectors: Vec<ParitySector> = data
+            .iter()
+            .map(|(id, d)| ParitySector {
+                id: *id,
+                size: d.len(),
+                crc: crc32(d),
+            })
+            .collect();
+        let stripes: Vec<Vec<&[u8]>> = data
+            .values()
+            .copied()
+            .collect::<Vec<&[u8]>>()
+            .chunks(options.stripe_sectors)
+            .map(|stripe| stripe.to_vec())
+            .collect();
+
+        cancel.start(stripes.len());
+        let mut blocks = Vec::new();
+        for stripe in &stripes {
+            cancel.check()?;
+            for row in 0..options.parity_sectors {
+                let mut block = vec![0; block_size];
+                for (column, sector) in stripe.iter().enumerate() {
+                    gf::mul_add(
+                        &mut block,
+                        coefficient(options.parity_sectors, row, column),
+                        sector,
+                    );
+                }
+                blocks.push(ParityBlock {
+                    crc: crc32(&block),
+                    data: block,
+                });
+            }
+            cancel.advance();
+        }
+
+        Ok(Parity {
+            options: *options,
+            block_size,
+            sectors,
+            blocks,
+        })
+    }
+
+    /// Find the damaged and missing sectors of a set of tracks
+    pub fn damaged_sectors(&self, tracks: &[LogicalTrack]) -> Vec<SectorId> {
+        let data = sector_data(tracks);
+        self.sectors
+            .iter()
+            .filter(|sector| {
+                !data
+                    .get(&sector.id)
+                    .is_some_and(|d| d.len() == sector.size && crc32(d) == sector.crc)
+            })
+            .map(|sector| sector.id)
+            .collect()
+    }
+
+    /// Rebuild the damaged and missing sectors of a set of tracks
+    /// Rebuilt sectors replace the damaged ones and have their CRC
+    /// error flag cleared, missing sectors are added to their track.
+    pub fn repair(
+        &self,
+        tracks: &mut Vec<LogicalTrack>,
+    ) -> std::result::Result<RepairReport, Error> {
+        self.options.check()?;
+        let parity_sectors = self.options.parity_sectors;
+        let mut report = RepairReport::default();
+        let mut rebuilt: Vec<(SectorId, Vec<u8>)> = Vec::new();
+        {
+            let data = sector_data(tracks);
+            let good = |sector: &ParitySector| {
+                data.get(&sector.id)
+                    .filter(|d| d.len() == sector.size && crc32(d) == sector.crc)
+                    .copied()
+            };
+
+            for (stripe, sectors) in self.sectors.chunks(self.options.stripe_sectors).enumerate() {
+                let damaged: Vec<usize> = (0..sectors.len())
+                    .filter(|column| good(&sectors[*column]).is_none())
+                    .collect();
+                if damaged.is_empty() {
+                    continue;
+                }
+
+                let rows: Vec<(usize, &ParityBlock)> = self
+                    .blocks
+                    .iter()
+                    .skip(stripe * parity_sectors)
+                    .take(parity_sectors)
+                    .enumerate()
+                    .filter(|(_, block)| {
+                        block.data.len() == self.block_size && crc32(&block.data) == block.crc
+                    })
+                    .take(damaged.len())
+                    .collect();
+                if rows.len() < damaged.len() {
+                    warn!(
+                        target: CONVERT,
+                        "Stripe {} has {} damaged sectors and {} good parity blocks",
+                        stripe,
+                        damaged.len(),
+                        rows.len()
+                    );
+                    report
+                        .unrecoverable
+                        .extend(damaged.iter().map(|column| sectors[*column].id));
+                    continue;
+                }
+
+                // Take the good sectors out of each parity block, leaving
+                // the sum of the damaged ones
+                let mut syndromes: Vec<Vec<u8>> = rows
+                    .iter()
+                    .map(|(row, block)| {
+                        let mut syndrome = block.data.clone();
+                        for (column, sector) in sectors.iter().enumerate() {
+                            if let Some(d) = good(sector) {
+                                gf::mul_add(
+                                    &mut syndrome,
+                                    coefficient(parity_sectors, *row, column),
+                                    d,
+                                );
+                            }
+                        }
+                        syndrome
+                    })
+                    .collect();
+                let matrix: Vec<Vec<u8>> = rows
+                    .iter()
+                    .map(|(row, _)| {
+                        damaged
+                            .iter()
+                            .map(|column| coefficient(parity_sectors, *row, *column))
+                            .collect()
+                    })
+                    .collect();
+                let inverse = invert(matrix).ok_or_else(|| {
+                    Error::new(ErrorKind::Message(String::from(
+                        "Parity matrix couldn't be inverted",
+                    )))
+                })?;
+
+                for (i, column) in damaged.iter().enumerate() {
+                    let sector = &sectors[*column];
+                    let mut block = vec![0; self.block_size];
+                    for (j, syndrome) in syndromes.iter_mut().enumerate() {
+                        gf::mul_add(&mut block, inverse[i][j], syndrome);
+                    }
+                    block.truncate(sector.size);
+                    if crc32(&block) == sector.crc {
+                        debug!(target: CONVERT, "Repaired sector {}", sector.id);
+                        rebuilt.push((sector.id, block));
+                    } else {
+                        report.unrecoverable.push(sector.id);
+                    }
+                }
+            }
+        }
+
+        for (id, block) in rebuilt {
+            store_sector(tracks, id, block);
+            report.repaired.push(id);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Replace a sector on a set of tracks, adding the sector and its
+/// track if they're missing
+fn store_sector(tracks: &mut Vec<LogicalTrack>, id: SectorId, data: Vec<u8>) {
+    let index = match tracks
+        .iter()
+        .position(|t| t.track == id.track.get() && t.head == id.head.get())
+    {
+        Some(index) => index,
+        None => {
+            let index = tracks
+                .iter()
+                .position(|t| (t.track, t.head) > (id.track.get(), id.head.get()))
+                .unwrap_or(tracks.len());
+            tracks.insert(index, LogicalTrack::new(id.track.get(), id.head.get()));
+            index
+        }
+    };
+
+    let track = &mut tracks[index];
+    match track
+        .sectors
+        .iter_mut()
+        .find(|sector| sector.id.sector == id.sector)
+    {
+        Some(sector) => {
+            sector.data = data;
+            sector.crc_error = false;
+        }
+        None => track.sectors.push(LogicalSector::new(id, data)),
+    }
+}
+
+/// Build an error for a value too large for the parity file
+fn too_large(what: &str, value: usize) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+        "{} too large for a parity file: {}",
+        what, value
+    ))))
+}
+
+impl<'a> Serializer<'a> for Parity {
+    fn as_vec(&'a self) -> std::result::Result<Vec<u8>, Error> {
+        let stripe_sectors: u16 = self
+            .options
+            .stripe_sectors
+            .try_into()
+            .map_err(|_| too_large("Stripe size", self.options.stripe_sectors))?;
+        let parity_sectors: u16 = self
+            .options
+            .parity_sectors
+            .try_into()
+            .map_err(|_| too_large("Parity count", self.options.parity_sectors))?;
+        let block_size: u32 = self
+            .block_size
+            .try_into()
+            .map_err(|_| too_large("Block size", self.block_size))?;
+        let sector_count: u32 = self
+            .sectors
+            .len()
+            .try_into()
+            .map_err(|_| too_large("Sector count", self.sectors.len()))?;
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"IRPA");
+        bytes.push(PARITY_VERSION);
+        bytes.push(0);
+        bytes.extend_from_slice(&stripe_sectors.to_le_bytes());
+        bytes.extend_from_slice(&parity_sectors.to_le_bytes());
+        bytes.extend_from_slice(&block_size.to_le_bytes());
+        bytes.extend_from_slice(&sector_count.to_le_bytes());
+
+        for sector in &self.sectors {
+            let size: u32 = sector
+                .size
+                .try_into()
+                .map_err(|_| too_large("Sector size", sector.size))?;
+            bytes.extend_from_slice(&[
+                sector.id.track.get(),
+                sector.id.head.get(),
+                sector.id.sector.get(),
+            ]);
+            bytes.extend_from_slice(&size.to_le_bytes());
+            bytes.extend_from_slice(&sector.crc.to_le_bytes());
+        }
+        for block in &self.blocks {
+            if block.data.len() != self.block_size {
+                return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    String::from("Parity block has the wrong size"),
+                ))));
+            }
+            bytes.extend_from_slice(&block.crc.to_le_bytes());
+            bytes.extend_from_slice(&block.data);
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Parse a single sector record
+fn parity_sector_parser(i: &[u8]) -> IResult<&[u8], ParitySector> {
+    let (i, track) = le_u8(i)?;
+    let (i, head) = le_u8(i)?;
+    let (i, sector) = le_u8(i)?;
+    let (i, size) = le_u32(i)?;
+    let (i, crc) = le_u32(i)?;
+
+    Ok((
+        i,
+        ParitySector {
+            id: SectorId::new(track, head, sector),
+            size: size as usize,
+            crc,
+        },
+    ))
+}
+
+/// Parse a single parity block
+fn parity_block_parser(block_size: usize) -> impl Fn(&[u8]) -> IResult<&[u8], ParityBlock> {
+    move |i| {
+        let (i, crc) = le_u32(i)?;
+        let (i, data) = take(block_size)(i)?;
+
+        Ok((
+            i,
+            ParityBlock {
+                crc,
+                data: data.to_vec(),
+            },
+        ))
+    }
+}
+
+/// Fail unless the data can hold count records of a size, so counts
+/// from a damaged header aren't trusted
+fn expect_records(i: &[u8], records: usize, size: usize) -> IResult<&[u8], ()> {
+    if records.saturating_mul(size) > i.len() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Eof,
+        )));
+    }
+    Ok((i, ()))
+}
+
+/// Parse a parity file
+pub fn parity_parser(i: &[u8]) -> IResult<&[u8], Parity> {
+    let (i, _magic) = tag("IRPA")(i)?;
+    let (i, _version) = verify(le_u8, |v: &u8| *v == PARITY_VERSION)(i)?;
+    let (i, _reserved) = le_u8(i)?;
+    let (i, stripe_sectors) = verify(le_u16, |v: &u16| *v > 0)(i)?;
+    let (i, parity_sectors) = le_u16(i)?;
+    let (i, block_size) = le_u32(i)?;
+    let (i, sector_count) = le_u32(i)?;
+
+    let sector_count = sector_count as usize;
+    let (i, _) = expect_records(i, sector_count, 11)?;
+    let (i, sectors) = count(parity_sector_parser, sector_count)(i)?;
+
+    let block_count = sector_count.div_ceil(stripe_sectors.into()) * usize::from(parity_sectors);
+    let (i, _) = expect_records(i, block_count, block_size as usize + 4)?;
+    let (i, blocks) = count(parity_block_parser(block_size as usize), block_count)(i)?;
+
+    Ok((
+        i,
+        Parity {
+            options: ParityOptions {
+                stripe_sectors: stripe_sectors.into(),
+                parity_sectors: parity_sectors.into(),
+            },
+            block_size: block_size as usize,
+            sectors,
+            blocks,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parity_parser, Parity, ParityOptions};
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::split_tracks;
+    use crate::serialize::Serializer;
+
+    /// Test generating parity, round-tripping the sidecar and repairing
+    /// damaged and missing sectors
+    #[test]
+    fn parity_repair_works() {
+        let geometry = Geometry::apple_dos_33(35);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i * 7 + i / 256) as u8)
+            .collect();
+        let tracks = split_tracks(&data, &geometry);
+        let options = ParityOptions {
+            stripe_sectors: 32,
+            parity_sectors: 3,
+        };
+        let parity = Parity::new(&tracks, &options, &CancellationToken::new()).unwrap();
+        assert_eq!(parity.sectors.len(), 560);
+        assert_eq!(parity.blocks.len(), 18 * 3);
+
+        let bytes = parity.as_vec().unwrap();
+        let (_, read) = parity_parser(&bytes).unwrap();
+        assert_eq!(read, parity);
+
+        // Three damaged sectors in the first stripe, one missing from
+        // the second and a CRC error in the last
+        let mut damaged = tracks.clone();
+        damaged[0].sectors[1].data[0] ^= 0xFF;
+        damaged[0].sectors[5].data[100] = 0;
+        damaged[1].sectors[15].data.fill(0);
+        damaged[2].sectors.remove(3);
+        damaged[34].sectors[15].data[255] ^= 1;
+        damaged[34].sectors[15].crc_error = true;
+        assert_eq!(read.damaged_sectors(&damaged).len(), 5);
+
+        let report = read.repair(&mut damaged).unwrap();
+        assert!(report.is_complete());
+        assert_eq!(report.repaired.len(), 5);
+        assert!(report.repaired.contains(&SectorId::new(2, 0, 3)));
+        damaged[2].sectors.sort_by_key(|sector| sector.id.sector);
+        assert_eq!(damaged, tracks);
+
+        // Four damaged sectors in a stripe is more than the parity covers
+        let mut damaged = tracks.clone();
+        for sector in 0..4 {
+            damaged[0].sectors[sector].data[0] ^= 0xFF;
+        }
+        let report = read.repair(&mut damaged).unwrap();
+        assert_eq!(report.unrecoverable.len(), 4);
+        assert!(report.repaired.is_empty());
+
+        assert!(Parity::new(
+            &tracks,
+            &ParityOptions {
+                stripe_sectors: 250,
+                parity_sectors: 10
+            },
+            &CancellationToken::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Parse results with warnings
+//!
+//! Many problems shouldn't stop a parse: a checksum that's ignored
+//! with ignore-checksums, a sector with a CRC error or a nonstandard
+//! header value.  Parsers record these as warnings while they run and
+//! the parse APIs return them with the value in a Parsed, so callers
+//! can show what was recovered from a damaged disk.  Disk images also
+//! come with a source map of where each structure was found.
+//!
+//! Warnings are collected per thread, like the allocation budget in
+//! the limits module, so deeply nested nom parsers don't need an extra
+//! argument.
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter, Result};
+use std::ops::Deref;
+
+use log::warn;
+
+use crate::disk_format::source_map::SourceMap;
+use crate::error::Location;
+use crate::log_target::PARSE;
+
+thread_local! {
+    /// The warnings recorded by the parse running on this thread
+    static WARNINGS: RefCell<Vec<Warning>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A problem found while parsing that didn't stop the parse
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Warning {
+    /// What was wrong
+    pub message: String,
+    /// Where in the image, if known
+    pub location: Option<Location>,
+}
+
+impl Warning {
+    /// Create a warning without a location
+    pub fn new(message: &str) -> Warning {
+        Warning {
+            message: String::from(message),
+            location: None,
+        }
+    }
+
+    /// Add the location of the problem
+    pub fn with_location(mut self, location: Location) -> Warning {
+        self.location = Some(location);
+        self
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed value and the warnings found while parsing it
+/// Parsed dereferences to the value, so it can be used in its place.
+#[derive(Debug)]
+pub struct Parsed<T> {
+    /// The parsed value
+    pub value: T,
+    /// The warnings, in the order they were found
+    pub warnings: Vec<Warning>,
+    /// Where the parsed structures are in the input, empty if the
+    /// value doesn't have a source map
+    pub source_map: SourceMap,
+    /// True if the value can't be changed, because it was read from a
+    /// read-only source, the read-only setting is true or its format
+    /// can't be saved
+    pub read_only: bool,
+}
+
+impl<T> Parsed<T> {
+    /// Wrap a value without warnings
+    pub fn new(value: T) -> Parsed<T> {
+        Parsed {
+            value,
+            warnings: Vec::new(),
+            source_map: SourceMap::new(),
+            read_only: false,
+        }
+    }
+
+    /// Attach a source map
+    pub fn with_source_map(mut self, source_map: SourceMap) -> Parsed<T> {
+        self.source_map = source_map;
+        self
+    }
+
+    /// Mark the value read-only
+    pub fn with_read_only(mut self, read_only: bool) -> Parsed<T> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Return the value, dropping the warnings
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// True if the parse found no problems
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl<T> Deref for Parsed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: Display> Display for Parsed<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+/// Record a warning for the parse running on this thread
+pub fn warning(warning: Warning) {
+    warn!(target: PARSE, "{}", warning);
+    WARNINGS.with(|warnings| warnings.borrow_mut().push(warning));
+}
+
+/// Run a parse, collecting the warnings it records
+pub fn collect_warnings<T, E>(
+    parse: impl FnOnce() -> std::result::Result<T, E>,
+) -> std::result::Result<Parsed<T>, E> {
+    let outer = WARNINGS.with(|warnings| warnings.take());
+    let result = parse();
+    let warnings = WARNINGS.with(|warnings| warnings.replace(outer));
+
+    result.map(|value| Parsed {
+        value,
+        warnings,
+        source_map: SourceMap::new(),
+        read_only: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect_warnings, warning, Warning};
+    use crate::error::Location;
+
+    /// Test that warnings are collected for each parse, including
+    /// nested parses
+    #[test]
+    fn collect_warnings_works() {
+        let parsed = collect_warnings(|| -> Result<u8, ()> {
+            warning(Warning::new("first"));
+            let inner = collect_warnings(|| -> Result<u8, ()> {
+                warning(Warning::new("inner"));
+                Ok(1)
+            })?;
+            assert_eq!(inner.warnings.len(), 1);
+            warning(Warning::new("checksum ignored").with_location(Location::sector(17, 3)));
+            Ok(*inner + 1)
+        })
+        .unwrap();
+
+        assert_eq!(*parsed, 2);
+        assert_eq!(parsed.warnings.len(), 2);
+        assert_eq!(
+            parsed.warnings[1].to_string(),
+            "checksum ignored at track 17 sector 3"
+        );
+        assert!(collect_warnings(|| Ok::<_, ()>(0)).unwrap().is_clean());
+    }
+}
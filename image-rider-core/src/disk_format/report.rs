@@ -0,0 +1,199 @@
+//! JSON reports on disk images
+//!
+//! A report describes a parsed image in a machine readable form for
+//! scripts and archival tools.  It currently holds the image type, the
+//! result of the sanity checks, the volume numbers, any known system
+//! areas, the archives stored in files, the tool that created the
+//! image, counts of the sectors and files and an entropy, content and
+//! usage map of every track and sector.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::analysis::{classify, corrected_entropy, ContentClass};
+use crate::disk_format::archive::Archive;
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::LogicalTrack;
+use crate::disk_format::stats::Stats;
+use crate::disk_format::usage::{SectorUsage, UsageMap};
+use crate::error::Error;
+
+/// A report on a disk image
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Report {
+    /// The type of image
+    pub format: String,
+    /// True if the image passed the sanity checks
+    pub sane: bool,
+    /// Tracks outside the filesystem that hold data, like tracks 36 to
+    /// 40 on a Commodore disk.  Standard DOS never reads them, so they
+    /// often hold copy protection or hidden data.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_tracks: Vec<u8>,
+    /// The volume numbers of the disk, only Apple disks have them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<u8>,
+    /// The copy of DOS on an Apple DOS 3.3 disk, e.g. "master DOS"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dos: Option<String>,
+    /// Known system areas on the disk, from the bundled fingerprint
+    /// database
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<String>,
+    /// Archives stored in files on the disk, by file name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub archives: BTreeMap<String, Archive>,
+    /// The tool that created the image, for formats that record it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<Creator>,
+    /// True if the image is marked write protected
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub write_protected: bool,
+    /// Counts of the sectors by size and CRC result, and of the files
+    /// by type
+    #[serde(default)]
+    pub stats: Stats,
+    /// The tracks on the disk, empty if the image doesn't support
+    /// track access
+    pub tracks: Vec<TrackReport>,
+}
+
+/// The tool that created an image
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Creator {
+    /// The name of the tool, or "Unknown tool" and the code for tools
+    /// that aren't known
+    pub tool: String,
+    /// The raw tool code stored in the image
+    pub code: u16,
+    /// The version of the image format the tool wrote
+    pub version: String,
+}
+
+impl Report {
+    /// Serialize the report as pretty-printed JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Entropy and content of a track
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TrackReport {
+    /// The track number
+    pub track: u8,
+    /// The side of the disk
+    pub head: u8,
+    /// The entropy of all the sector data on the track, in bits per byte
+    pub entropy: f64,
+    /// The kind of data on the track
+    pub class: ContentClass,
+    /// The sectors on the track
+    pub sectors: Vec<SectorReport>,
+}
+
+/// Entropy and content of a sector
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SectorReport {
+    /// The sector number
+    pub sector: u8,
+    /// The number of data bytes in the sector
+    pub size: usize,
+    /// The entropy of the sector data, in bits per byte
+    pub entropy: f64,
+    /// The kind of data in the sector
+    pub class: ContentClass,
+    /// True if the sector was read with a CRC error
+    pub crc_error: bool,
+    /// True if the sector has a deleted data mark
+    pub deleted: bool,
+    /// How the filesystem uses the sector, if the filesystem is parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<SectorUsage>,
+}
+
+/// Round an entropy to three decimal places to keep reports readable
+fn round_entropy(entropy: f64) -> f64 {
+    (entropy * 1000.0).round() / 1000.0
+}
+
+/// Build the track and sector entropy map for a set of tracks, with the
+/// usage of each sector if there is a usage map
+pub fn track_reports(tracks: &[LogicalTrack], usage: Option<&UsageMap>) -> Vec<TrackReport> {
+    tracks
+        .iter()
+        .map(|track| {
+            let data: Vec<u8> = track
+                .sectors
+                .iter()
+                .flat_map(|s| s.data.iter().copied())
+                .collect();
+            TrackReport {
+                track: track.track,
+                head: track.head,
+                entropy: round_entropy(corrected_entropy(&data)),
+                class: classify(&data),
+                sectors: track
+                    .sectors
+                    .iter()
+                    .map(|sector| SectorReport {
+                        sector: sector.id.sector.get(),
+                        size: sector.data.len(),
+                        entropy: round_entropy(corrected_entropy(&sector.data)),
+                        class: classify(&sector.data),
+                        crc_error: sector.crc_error,
+                        deleted: sector.deleted,
+                        usage: usage
+                            .and_then(|u| {
+                                u.usage(&SectorId::new(track.track, track.head, sector.id.sector))
+                            })
+                            .cloned(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{track_reports, Report, Stats};
+    use crate::disk_format::analysis::ContentClass;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test building a report and serializing it
+    #[test]
+    fn report_works() {
+        let geometry = Geometry::apple_dos_33(2);
+        let mut data = vec![0_u8; geometry.total_size()];
+        data[256..256 + 44].copy_from_slice(b"THE QUICK BROWN FOX JUMPS OVER THE LAZY DOG\r");
+
+        let report = Report {
+            format: String::from("Test Disk"),
+            sane: true,
+            extra_tracks: Vec::new(),
+            volumes: Vec::new(),
+            dos: None,
+            system: Vec::new(),
+            archives: BTreeMap::new(),
+            creator: None,
+            write_protected: false,
+            stats: Stats::default(),
+            tracks: track_reports(&split_tracks(&data, &geometry), None),
+        };
+        assert_eq!(report.tracks.len(), 2);
+        assert_eq!(report.tracks[0].sectors.len(), 16);
+        assert_eq!(report.tracks[0].sectors[0].class, ContentClass::Empty);
+        assert_eq!(report.tracks[0].sectors[1].class, ContentClass::Text);
+        assert_eq!(report.tracks[1].class, ContentClass::Empty);
+        assert_eq!(report.tracks[1].entropy, 0.0);
+
+        let json = report.to_json().unwrap();
+        assert!(json.contains("\"format\": \"Test Disk\""));
+        assert!(json.contains("\"class\": \"text\""));
+        assert!(!json.contains("usage"));
+    }
+}
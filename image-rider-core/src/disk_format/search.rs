@@ -0,0 +1,292 @@
+//! Search for byte patterns and strings on a disk
+//!
+//! Text is stored differently on each platform.  A text search looks
+//! for the string in plain ASCII, PETSCII (Commodore) and Apple ][
+//! high-bit ASCII.  Hits are reported by sector and offset, along with
+//! the file that owns the sector when the filesystem has been parsed.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::LogicalTrack;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The encoding a pattern was found in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TextEncoding {
+    /// Raw bytes, not text
+    Bytes,
+    /// Plain seven bit ASCII
+    Ascii,
+    /// Commodore PETSCII
+    Petscii,
+    /// Apple ][ ASCII with the high bit set
+    AppleHighAscii,
+}
+
+impl Display for TextEncoding {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            TextEncoding::Bytes => write!(f, "bytes"),
+            TextEncoding::Ascii => write!(f, "ASCII"),
+            TextEncoding::Petscii => write!(f, "PETSCII"),
+            TextEncoding::AppleHighAscii => write!(f, "Apple high ASCII"),
+        }
+    }
+}
+
+/// A search pattern
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Pattern {
+    /// An exact sequence of bytes
+    Bytes(Vec<u8>),
+    /// A string in plain ASCII
+    Ascii(String),
+    /// A string in PETSCII
+    Petscii(String),
+    /// A string in Apple ][ high-bit ASCII
+    AppleHighAscii(String),
+    /// A string in any of the text encodings
+    Text(String),
+}
+
+impl Pattern {
+    /// Build a byte pattern from a string of hex digits
+    /// Spaces between bytes are allowed, e.g. "A9 00 8D"
+    pub fn from_hex(hex: &str) -> std::result::Result<Pattern, Error> {
+        let digits: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+        if digits.is_empty() || digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Invalid hex pattern: {}", hex),
+            ))));
+        }
+
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| {
+                Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                    "Invalid hex pattern: {}",
+                    hex
+                ))))
+            })?;
+
+        Ok(Pattern::Bytes(bytes))
+    }
+
+    /// Return the byte sequences to search for and their encodings
+    /// Strings with characters that can't be encoded produce no byte
+    /// sequences for that encoding.
+    pub fn encodings(&self) -> Vec<(TextEncoding, Vec<u8>)> {
+        let encodings = match self {
+            Pattern::Bytes(bytes) => vec![(TextEncoding::Bytes, Some(bytes.clone()))],
+            Pattern::Ascii(text) => vec![(TextEncoding::Ascii, ascii_bytes(text))],
+            Pattern::Petscii(text) => vec![
+                (TextEncoding::Petscii, petscii_bytes(text)),
+                (TextEncoding::Petscii, petscii_shifted_bytes(text)),
+            ],
+            Pattern::AppleHighAscii(text) => {
+                vec![(TextEncoding::AppleHighAscii, apple_high_ascii_bytes(text))]
+            }
+            Pattern::Text(text) => vec![
+                (TextEncoding::Ascii, ascii_bytes(text)),
+                (TextEncoding::Petscii, petscii_bytes(text)),
+                (TextEncoding::Petscii, petscii_shifted_bytes(text)),
+                (TextEncoding::AppleHighAscii, apple_high_ascii_bytes(text)),
+            ],
+        };
+
+        let mut needles: Vec<(TextEncoding, Vec<u8>)> = Vec::new();
+        for (encoding, bytes) in encodings {
+            if let Some(bytes) = bytes.filter(|b| !b.is_empty()) {
+                if !needles.contains(&(encoding, bytes.clone())) {
+                    needles.push((encoding, bytes));
+                }
+            }
+        }
+
+        needles
+    }
+}
+
+/// Encode a string as ASCII, None if it has non-ASCII characters
+pub fn ascii_bytes(text: &str) -> Option<Vec<u8>> {
+    text.is_ascii().then(|| text.as_bytes().to_vec())
+}
+
+/// Encode a string as unshifted PETSCII, None if it has non-ASCII
+/// characters
+/// Letters of either case map to 0x41-0x5A, the way directory names
+/// and most text are stored.
+pub fn petscii_bytes(text: &str) -> Option<Vec<u8>> {
+    ascii_bytes(text).map(|bytes| bytes.to_ascii_uppercase())
+}
+
+/// Encode a string as shifted PETSCII, None if it has non-ASCII
+/// characters
+/// Lowercase letters map to 0x41-0x5A and uppercase letters to
+/// 0xC1-0xDA, as in the lowercase/uppercase character set.
+pub fn petscii_shifted_bytes(text: &str) -> Option<Vec<u8>> {
+    ascii_bytes(text).map(|bytes| {
+        bytes
+            .iter()
+            .map(|b| match b {
+                b'a'..=b'z' => b - 0x20,
+                b'A'..=b'Z' => b + 0x80,
+                _ => *b,
+            })
+            .collect()
+    })
+}
+
+/// Encode a string as Apple ][ high-bit ASCII, None if it has
+/// non-ASCII characters
+pub fn apple_high_ascii_bytes(text: &str) -> Option<Vec<u8>> {
+    ascii_bytes(text).map(|bytes| bytes.iter().map(|b| b | 0x80).collect())
+}
+
+/// A single search hit
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SearchHit {
+    /// The sector the match starts in
+    pub id: SectorId,
+    /// The offset of the match in the sector
+    pub offset: usize,
+    /// The encoding of the match
+    pub encoding: TextEncoding,
+    /// The file that owns the sector, if known
+    pub file: Option<String>,
+}
+
+impl Display for SearchHit {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}, offset: 0x{:02X}, encoding: {}",
+            self.id, self.offset, self.encoding
+        )?;
+        if let Some(file) = &self.file {
+            write!(f, ", file: {}", file)?;
+        }
+        Ok(())
+    }
+}
+
+/// Search the sectors on a set of tracks for a set of encoded patterns
+/// Matches may cross sector boundaries, they are reported at the sector
+/// they start in.  If more than one encoding matches at the same place
+/// the first encoding is reported.  files maps sectors to the name of
+/// the file that owns them.
+pub fn search_tracks(
+    tracks: &[LogicalTrack],
+    needles: &[(TextEncoding, Vec<u8>)],
+    files: &BTreeMap<SectorId, String>,
+) -> Vec<SearchHit> {
+    // Join the sectors together, remembering where each one starts
+    let mut data: Vec<u8> = Vec::new();
+    let mut starts: Vec<(usize, SectorId)> = Vec::new();
+    for track in tracks {
+        for sector in &track.sectors {
+            let id = SectorId::new(track.track, track.head, sector.id.sector);
+            starts.push((data.len(), id));
+            data.extend_from_slice(&sector.data);
+        }
+    }
+
+    let mut hits = Vec::new();
+    for (encoding, needle) in needles {
+        if needle.is_empty() || needle.len() > data.len() {
+            continue;
+        }
+        for position in 0..=(data.len() - needle.len()) {
+            if data[position..position + needle.len()] != needle[..] {
+                continue;
+            }
+            let index = starts.partition_point(|(start, _)| *start <= position) - 1;
+            let (start, id) = starts[index];
+            hits.push(SearchHit {
+                id,
+                offset: position - start,
+                encoding: *encoding,
+                file: files.get(&id).cloned(),
+            });
+        }
+    }
+    // The sort is stable, so the first encoding stays first
+    hits.sort_by_key(|h| (h.id, h.offset));
+    hits.dedup_by_key(|h| (h.id, h.offset));
+
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{search_tracks, Pattern, TextEncoding};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test building text encodings
+    #[test]
+    fn encodings_work() {
+        let encodings = Pattern::Text(String::from("Hi!")).encodings();
+        assert_eq!(
+            encodings,
+            vec![
+                (TextEncoding::Ascii, vec![0x48, 0x69, 0x21]),
+                (TextEncoding::Petscii, vec![0x48, 0x49, 0x21]),
+                (TextEncoding::Petscii, vec![0xC8, 0x49, 0x21]),
+                (TextEncoding::AppleHighAscii, vec![0xC8, 0xE9, 0xA1]),
+            ]
+        );
+
+        assert_eq!(
+            Pattern::from_hex("a9 00 8D").unwrap(),
+            Pattern::Bytes(vec![0xA9, 0x00, 0x8D])
+        );
+        assert!(Pattern::from_hex("A9 0").is_err());
+        assert!(Pattern::from_hex("ZZ").is_err());
+        // Both PETSCII forms of "GAMES" are the same bytes
+        assert_eq!(
+            Pattern::Petscii(String::from("games")).encodings(),
+            vec![(TextEncoding::Petscii, b"GAMES".to_vec())]
+        );
+        assert!(Pattern::Ascii(String::from("\u{e9}"))
+            .encodings()
+            .is_empty());
+    }
+
+    /// Test finding text in different encodings, across sector boundaries
+    #[test]
+    fn search_tracks_works() {
+        let geometry = Geometry::apple_dos_33(3);
+        let mut data = vec![0_u8; geometry.total_size()];
+        // Apple high ASCII "HELLO" at track 1, sector 2, offset 0x10
+        let offset = geometry.offset(&SectorId::new(1, 0, 2)).unwrap() + 0x10;
+        data[offset..offset + 5].copy_from_slice(&[0xC8, 0xC5, 0xCC, 0xCC, 0xCF]);
+        // ASCII "HELLO" crossing from track 2 sector 0 into sector 1
+        let offset = geometry.offset(&SectorId::new(2, 0, 1)).unwrap() - 2;
+        data[offset..offset + 5].copy_from_slice(b"HELLO");
+
+        let tracks = split_tracks(&data, &geometry);
+        let mut files = BTreeMap::new();
+        files.insert(SectorId::new(1, 0, 2), String::from("GREETING"));
+
+        let mut needles = Pattern::Text(String::from("HELLO")).encodings();
+        // Uppercase PETSCII and Apple high ASCII are the same bytes,
+        // leave PETSCII out for an Apple disk
+        needles.retain(|(encoding, _)| *encoding != TextEncoding::Petscii);
+        let hits = search_tracks(&tracks, &needles, &files);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].id, SectorId::new(1, 0, 2));
+        assert_eq!(hits[0].offset, 0x10);
+        assert_eq!(hits[0].encoding, TextEncoding::AppleHighAscii);
+        assert_eq!(hits[0].file, Some(String::from("GREETING")));
+        assert_eq!(hits[1].id, SectorId::new(2, 0, 0));
+        assert_eq!(hits[1].offset, 254);
+        assert_eq!(hits[1].encoding, TextEncoding::Ascii);
+        assert_eq!(hits[1].file, None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Source maps, where parsed structures are in an image
+//!
+//! A source map lists the byte ranges of the structures a parser found:
+//! image and track headers, directory sectors, and the sectors of each
+//! file.  Hex editor front-ends can use it to highlight regions and
+//! diff and patch tools to refer to exact locations.
+//!
+//! Parsed structures borrow their bytes from the image, so most
+//! offsets are found from where those slices point into the image
+//! data.  Structures that are decoded, like nibble encoded sectors,
+//! don't have an offset and are left out.
+use std::fmt::{Display, Formatter, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::error::Error;
+
+/// The kind of structure in a region
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    /// An image, track or sector header
+    Header,
+    /// A filesystem structure: a directory or catalog sector, an
+    /// allocation bitmap or a track/sector list
+    Directory,
+    /// The data of a file
+    File,
+    /// Track or sector data outside of a filesystem
+    Data,
+}
+
+impl Display for RegionKind {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            RegionKind::Header => write!(f, "header"),
+            RegionKind::Directory => write!(f, "directory"),
+            RegionKind::File => write!(f, "file"),
+            RegionKind::Data => write!(f, "data"),
+        }
+    }
+}
+
+/// A range of bytes holding a parsed structure
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Region {
+    /// The offset of the region from the start of the image
+    pub offset: usize,
+    /// The length of the region in bytes
+    pub length: usize,
+    /// The kind of structure in the region
+    pub kind: RegionKind,
+    /// What the structure is, e.g. "VTOC" or a file name
+    pub name: String,
+}
+
+impl Region {
+    /// Return true if the region holds a byte offset
+    pub fn contains(&self, offset: usize) -> bool {
+        offset >= self.offset && offset - self.offset < self.length
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "0x{:06X}-0x{:06X} {}: {}",
+            self.offset,
+            self.offset + self.length,
+            self.kind,
+            self.name
+        )
+    }
+}
+
+/// The regions of an image, in the order they were added
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SourceMap {
+    /// The regions
+    pub regions: Vec<Region>,
+}
+
+impl SourceMap {
+    /// Create an empty source map
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// Add a region
+    pub fn add(&mut self, kind: RegionKind, name: &str, offset: usize, length: usize) {
+        self.regions.push(Region {
+            offset,
+            length,
+            kind,
+            name: String::from(name),
+        });
+    }
+
+    /// Add a region for a slice of the image data
+    /// Returns false, without adding a region, if the slice isn't part
+    /// of the data.
+    pub fn add_slice(&mut self, data: &[u8], slice: &[u8], kind: RegionKind, name: &str) -> bool {
+        match slice_offset(data, slice) {
+            Some(offset) => {
+                self.add(kind, name, offset, slice.len());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add a region for each sector, merging sectors that follow each
+    /// other in the image
+    /// base is the offset in the image of the data the geometry
+    /// describes.
+    pub fn add_sectors(
+        &mut self,
+        geometry: &Geometry,
+        base: usize,
+        sectors: &[SectorId],
+        kind: RegionKind,
+        name: &str,
+    ) {
+        let mut current: Option<(usize, usize)> = None;
+        for id in sectors {
+            let Some(offset) = geometry.offset(id).map(|offset| base + offset) else {
+                continue;
+            };
+            let length = geometry.sector_size;
+            current = match current {
+                Some((start, end)) if end == offset => Some((start, offset + length)),
+                Some((start, end)) => {
+                    self.add(kind, name, start, end - start);
+                    Some((offset, offset + length))
+                }
+                None => Some((offset, offset + length)),
+            };
+        }
+        if let Some((start, end)) = current {
+            self.add(kind, name, start, end - start);
+        }
+    }
+
+    /// Return the regions that hold a byte offset, outermost first
+    pub fn regions_at(&self, offset: usize) -> Vec<&Region> {
+        let mut regions: Vec<&Region> = self
+            .regions
+            .iter()
+            .filter(|region| region.contains(offset))
+            .collect();
+        regions.sort_by_key(|region| std::cmp::Reverse(region.length));
+        regions
+    }
+
+    /// Sort the regions by offset, longer regions first when they
+    /// start at the same offset
+    pub fn sort(&mut self) {
+        self.regions
+            .sort_by_key(|region| (region.offset, std::cmp::Reverse(region.length)));
+    }
+
+    /// Serialize the source map as pretty-printed JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl Display for SourceMap {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for region in &self.regions {
+            writeln!(f, "{}", region)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build a source map for a parsed structure
+pub trait SourceMapper {
+    /// Return the regions of the structure in the image data it was
+    /// parsed from
+    fn source_map(&self, data: &[u8]) -> SourceMap;
+}
+
+/// Return the offset of a slice in the data it was taken from, or None
+/// if it wasn't taken from the data
+pub fn slice_offset(data: &[u8], slice: &[u8]) -> Option<usize> {
+    let offset = (slice.as_ptr() as usize).checked_sub(data.as_ptr() as usize)?;
+    Some(offset).filter(|offset| offset + slice.len() <= data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slice_offset, RegionKind, SourceMap};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+
+    /// Test adding regions from slices and sectors
+    #[test]
+    fn source_map_works() {
+        let data = vec![0_u8; 4096];
+        let other = vec![0_u8; 16];
+        assert_eq!(slice_offset(&data, &data[16..32]), Some(16));
+        assert_eq!(slice_offset(&data[16..], &data[..4]), None);
+
+        let mut map = SourceMap::new();
+        assert!(map.add_slice(&data, &data[0x100..0x200], RegionKind::Directory, "VTOC"));
+        assert!(!map.add_slice(&data, &other, RegionKind::Header, "decoded"));
+
+        // Sectors next to each other in the image are merged
+        let geometry = Geometry::apple_dos_33(1);
+        let sectors = [
+            SectorId::new(0, 0, 2),
+            SectorId::new(0, 0, 3),
+            SectorId::new(0, 0, 8),
+        ];
+        map.add_sectors(&geometry, 0, &sectors, RegionKind::File, "HELLO");
+        map.sort();
+
+        assert_eq!(map.regions.len(), 3);
+        assert_eq!(map.regions[1].to_string(), "0x000200-0x000400 file: HELLO");
+        let at = map.regions_at(0x180);
+        assert_eq!(at.len(), 1);
+        assert_eq!(at[0].name, "VTOC");
+        assert!(map.regions_at(0x400).is_empty());
+    }
+}
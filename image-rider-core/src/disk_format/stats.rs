@@ -0,0 +1,194 @@
+//! Image-wide statistics
+//!
+//! Statistics are counts over a whole image: how many sectors there
+//! are of each size, how many passed their CRC or checksum, how many
+//! are fuzzy, how much of the filesystem is free and what types of
+//! files it holds.  They're small enough to print as a single line
+//! per image when triaging a large collection, and they're included in
+//! the JSON report.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::image::{DiskImage, DiskImageSaver};
+use crate::disk_format::logical::LogicalTrack;
+use crate::disk_format::usage::{SectorUsage, UsageMap};
+use crate::error::Error;
+
+/// Counts of the sectors and files on a disk image
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Stats {
+    /// The number of sectors
+    pub sectors: usize,
+    /// The number of sectors of each size, by size in bytes
+    pub sector_sizes: BTreeMap<usize, usize>,
+    /// The number of sectors that passed their CRC or checksum
+    pub crc_passed: usize,
+    /// The number of sectors read with a CRC or checksum error
+    pub crc_failed: usize,
+    /// The number of sectors with a deleted data mark
+    pub deleted: usize,
+    /// The number of sectors with fuzzy bits, which read differently
+    /// each time
+    pub fuzzy: usize,
+    /// The number of sectors marked free, None if the filesystem isn't
+    /// parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free: Option<usize>,
+    /// The number of sectors in use by files or the filesystem, None
+    /// if the filesystem isn't parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub used: Option<usize>,
+    /// The number of files of each type, by the type name the
+    /// filesystem uses
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub file_types: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    /// Count the sectors on a set of tracks
+    /// The fuzzy count and the filesystem counts are left empty.
+    pub fn from_tracks(tracks: &[LogicalTrack]) -> Stats {
+        let mut stats = Stats::default();
+        for sector in tracks.iter().flat_map(|track| track.sectors.iter()) {
+            stats.sectors += 1;
+            *stats.sector_sizes.entry(sector.data.len()).or_default() += 1;
+            if sector.crc_error {
+                stats.crc_failed += 1;
+            } else {
+                stats.crc_passed += 1;
+            }
+            if sector.deleted {
+                stats.deleted += 1;
+            }
+        }
+        stats
+    }
+
+    /// Add the free and used sector counts from a usage map
+    /// Sectors on tracks the filesystem doesn't manage aren't counted.
+    pub fn add_usage(&mut self, usage: &UsageMap) {
+        let free = usage.sectors_with(&SectorUsage::Free).len();
+        let unmanaged = usage.sectors_with(&SectorUsage::Unmanaged).len();
+        self.free = Some(free);
+        self.used = Some(usage.sectors.len() - free - unmanaged);
+    }
+
+    /// Gather the statistics of a disk image
+    pub fn new(image: &DiskImage) -> Stats {
+        let mut stats = Stats::from_tracks(&image.tracks().unwrap_or_default());
+        if let DiskImage::STX(stx_disk) = image {
+            stats.fuzzy = stx_disk
+                .stx_tracks
+                .iter()
+                .flat_map(|track| track.sector_headers.iter().flatten())
+                .filter(|header| header.fdc_status.fuzzy)
+                .count();
+        }
+        if let Some(usage) = image.usage() {
+            stats.add_usage(&usage);
+        }
+        for file in image.disk_files() {
+            *stats.file_types.entry(file.file_type).or_default() += 1;
+        }
+        stats
+    }
+
+    /// Serialize the statistics as a single line of JSON
+    pub fn to_json(&self) -> std::result::Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Format the statistics as a single line
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let sizes: Vec<String> = self
+            .sector_sizes
+            .iter()
+            .map(|(size, count)| format!("{}x{}", count, size))
+            .collect();
+        write!(
+            f,
+            "sectors: {} ({}), CRC ok: {}, CRC bad: {}, deleted: {}, fuzzy: {}",
+            self.sectors,
+            sizes.join(" "),
+            self.crc_passed,
+            self.crc_failed,
+            self.deleted,
+            self.fuzzy
+        )?;
+        if let (Some(free), Some(used)) = (self.free, self.used) {
+            write!(f, ", free: {}, used: {}", free, used)?;
+        }
+        if !self.file_types.is_empty() {
+            let types: Vec<String> = self
+                .file_types
+                .iter()
+                .map(|(file_type, count)| format!("{} {}", count, file_type))
+                .collect();
+            write!(f, ", files: {}", types.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::options::Options;
+
+    use super::Stats;
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::DiskImageParser;
+    use crate::disk_format::logical::{split_tracks, LogicalSector, LogicalTrack};
+    use crate::disk_format::testgen;
+
+    /// Test counting the sectors on a set of tracks
+    #[test]
+    fn stats_from_tracks_works() {
+        let mut tracks = split_tracks(&[0; 2 * 16 * 256], &Geometry::apple_dos_33(2));
+        let mut track = LogicalTrack::new(2, 0);
+        let mut bad = LogicalSector::new(SectorId::new(2, 0, 1), vec![0; 512]);
+        bad.crc_error = true;
+        bad.deleted = true;
+        track.sectors.push(bad);
+        tracks.push(track);
+
+        let stats = Stats::from_tracks(&tracks);
+        assert_eq!(stats.sectors, 33);
+        assert_eq!(stats.sector_sizes, BTreeMap::from([(256, 32), (512, 1)]));
+        assert_eq!((stats.crc_passed, stats.crc_failed), (32, 1));
+        assert_eq!(stats.deleted, 1);
+        assert_eq!(stats.free, None);
+        assert_eq!(
+            stats.to_string(),
+            "sectors: 33 (32x256 1x512), CRC ok: 32, CRC bad: 1, deleted: 1, fuzzy: 0"
+        );
+    }
+
+    /// Test gathering the statistics of a generated D64 image
+    #[test]
+    fn stats_works() {
+        let data = testgen::d64("GAMES", &[("ONE", &[1; 300]), ("TWO", &[2; 10])]).unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "games.d64")
+            .unwrap();
+        let stats = image.stats();
+        assert_eq!(stats.sectors, 683);
+        assert_eq!(stats.sector_sizes, BTreeMap::from([(256, 683)]));
+        assert_eq!(stats.crc_failed, 0);
+        let (free, used) = (stats.free.unwrap(), stats.used.unwrap());
+        assert_eq!(free + used, 683);
+        // The BAM, one directory sector, two sectors of ONE and one of
+        // TWO
+        assert_eq!(used, 5);
+        assert_eq!(stats.file_types.values().sum::<usize>(), 2);
+
+        let json = stats.to_json().unwrap();
+        assert!(!json.contains('\n'));
+        assert_eq!(serde_json::from_str::<Stats>(&json).unwrap(), stats);
+    }
+}
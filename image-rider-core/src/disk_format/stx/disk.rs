@@ -0,0 +1,576 @@
+use config::Config;
+
+use log::{debug, error, info};
+
+use std::io::Write;
+
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::fat::volume::FatVolume;
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::logical::{LogicalSector, LogicalTrack, RawExporter, RawOrder};
+use crate::disk_format::report::Creator;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::stx::track::{stx_tracks_parser, STXTrack, STXTrackSummary};
+use crate::disk_format::stx::SanityCheck;
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// An Atari ST STX image
+pub const STX_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "STX",
+    description: "Atari ST Pasti STX disk image",
+    extensions: &["stx"],
+    sizes: &[SizeRange::at_least(16)],
+    references: &[
+        Reference {
+            title: "STXdesc",
+            url: Some("https://atari.8bitchip.info/STXdesc.html"),
+        },
+        Reference {
+            title: "Pasti-documentation.pdf",
+            url: Some("http://info-coach.fr/atari/documents/_mydoc/Pasti-documentation.pdf"),
+        },
+    ],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        track_export: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// A STX disk image
+#[derive(Debug)]
+pub struct STXDisk<'a> {
+    /// The disk header
+    pub stx_disk_header: STXDiskHeader<'a>,
+
+    /// The disk tracks
+    pub stx_tracks: Vec<STXTrack<'a>>,
+}
+
+/// Format a STXDisk for display, the file header and a table with a
+/// row for each track
+impl Display for STXDisk<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "header: {}", self.stx_disk_header)?;
+        write!(f, "{}", self.track_table())
+    }
+}
+
+impl STXDisk<'_> {
+    /// Parse the FAT filesystem in the disk's sectors
+    /// The sectors are flattened in the geometry used by most of the
+    /// tracks, which is returned with the volume.  STX images can't be
+    /// saved, so the volume is read-only.
+    pub fn fat_volume(&self) -> std::result::Result<(FatVolume, Geometry), Error> {
+        let geometry = self.raw_geometry().ok_or_else(|| {
+            Error::new(ErrorKind::NotFound(String::from(
+                "The disk has no sectors to hold a filesystem",
+            )))
+        })?;
+        let mut volume = FatVolume::parse(self.export_raw(&RawOrder::default(), &geometry))?;
+        volume.fat.read_only = true;
+        Ok((volume, geometry))
+    }
+
+    /// Return the free sectors of the FAT filesystem
+    /// Returns an empty set if the disk doesn't hold one.
+    pub fn free_sectors(&self) -> BTreeSet<SectorId> {
+        self.fat_volume()
+            .map(|(volume, geometry)| {
+                volume
+                    .sector_ids(&geometry, volume.free_sectors())
+                    .into_iter()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the sectors used by the FAT filesystem: the boot sector,
+    /// the tables and the directories
+    /// Returns an empty set if the disk doesn't hold one.
+    pub fn system_sectors(&self) -> BTreeSet<SectorId> {
+        self.fat_volume()
+            .map(|(volume, geometry)| {
+                volume
+                    .sector_ids(&geometry, volume.system_sectors())
+                    .into_iter()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the sectors of each file in the FAT filesystem
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        self.fat_volume()
+            .map(|(volume, geometry)| volume.file_extents(&geometry))
+            .unwrap_or_default()
+    }
+
+    /// Return a table of the layout of each track: the sector count
+    /// and sizes, the decoded track flags and the sectors with CRC
+    /// errors
+    pub fn track_table(&self) -> String {
+        let mut table = String::from(STXTrackSummary::HEADINGS);
+        for stx_track in &self.stx_tracks {
+            table.push('\n');
+            table.push_str(&stx_track.summary().to_string());
+        }
+        table
+    }
+}
+
+// impl DiskImageParser for STXDisk<'_> {
+//     fn parse_disk_image<'a>(
+//         &self,
+//         _config: &Config,
+//         _filename: &str,
+//         data: &'a [u8],
+//     ) -> IResult<&'a [u8], DiskImage<'a>> {
+//         let (i, parse_result) = stx_disk_parser(data)?;
+//         Ok((i, DiskImage::STX(parse_result)))
+//     }
+// }
+
+/// Heuristic guesses for what kind of disk this is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct STXDiskGuess<'a> {
+    /// The raw image data
+    pub data: &'a [u8],
+}
+
+impl DiskImageSaver for STXDisk<'_> {
+    /// This saves the underlying image on this disk.
+    /// This can be a FAT disk image, an ST disk, or a custom disk image
+    /// that may or may not be copy-protected.
+    /// The sectors are written as a flat dump in the geometry used by
+    /// most of the tracks.  If a file is selected, it's read from the
+    /// FAT filesystem on the disk instead.
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), crate::error::Error> {
+        if let Some(selected_filename) = selected_filename {
+            let file = self
+                .disk_files()
+                .into_iter()
+                .find(|file| file.name.eq_ignore_ascii_case(selected_filename))
+                .ok_or_else(|| {
+                    error!(target: IO, "File not found: {}", selected_filename);
+                    Error::new(ErrorKind::NotFound(format!(
+                        "File not found: {}",
+                        selected_filename
+                    )))
+                })?;
+            info!(target: IO, "Found file {}, writing data", file.name);
+            writer.write_all(&file.data)?;
+            return Ok(());
+        }
+
+        let disk_image_data = self
+            .raw_geometry()
+            .map(|geometry| self.export_raw(&RawOrder::default(), &geometry))
+            .unwrap_or_default();
+        info!(target: IO, "Found image data, writing data");
+        writer.write_all(&disk_image_data)?;
+        Ok(())
+    }
+
+    /// The files in the FAT filesystem on the disk, with their paths
+    /// Returns no files if the disk doesn't hold a FAT filesystem.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        match self.fat_volume() {
+            Ok((volume, _)) => volume.disk_files(),
+            Err(e) => {
+                debug!(target: PARSE, "No FAT filesystem on the disk: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl RawExporter for STXDisk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.stx_tracks
+            .iter()
+            .map(|stx_track| {
+                let mut track = LogicalTrack::new(
+                    stx_track.header.track_number & 0x7F,
+                    stx_track.header.track_number >> 7,
+                );
+                if let (Some(headers), Some(data)) =
+                    (&stx_track.sector_headers, &stx_track.sector_data)
+                {
+                    for (header, sector_data) in headers.iter().zip(data.iter()) {
+                        track.sectors.push(LogicalSector {
+                            id: SectorId::new(header.id_track, header.id_head, header.id_sector),
+                            data: sector_data.to_vec(),
+                            // Sectors the FDC couldn't read stay
+                            // unreadable when they're converted
+                            crc_error: header.fdc_status.is_read_error(),
+                            deleted: header.fdc_status.deleted,
+                        });
+                    }
+                }
+                track
+            })
+            .collect()
+    }
+}
+
+/// The file header and each track record, with the track headers,
+/// sector descriptors and sector data inside them
+impl SourceMapper for STXDisk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.stx_disk_header.disk_id) else {
+            return map;
+        };
+        map.add(RegionKind::Header, "file header", start, 16);
+
+        let mut offset = start + 16;
+        for stx_track in &self.stx_tracks {
+            let header = &stx_track.header;
+            let name = format!(
+                "track {} side {}",
+                header.track_number & 0x7F,
+                header.track_number >> 7
+            );
+            map.add(RegionKind::Data, &name, offset, header.block_size as usize);
+            map.add(RegionKind::Header, &format!("{} header", name), offset, 16);
+            match (&stx_track.sector_headers, &stx_track.sector_data) {
+                (Some(headers), Some(sector_data)) => {
+                    // Sectors found in the track image have no
+                    // descriptors
+                    if header.sectors_count > 0 {
+                        map.add(
+                            RegionKind::Header,
+                            &format!("{} sector descriptors", name),
+                            offset + 16,
+                            usize::from(header.sectors_count) * 16,
+                        );
+                    }
+                    for (sector_header, sector) in headers.iter().zip(sector_data.iter()) {
+                        map.add_slice(
+                            data,
+                            sector,
+                            RegionKind::Data,
+                            &format!("{} sector {}", name, sector_header.id_sector),
+                        );
+                    }
+                }
+                // Tracks without sector descriptors are a plain dump of
+                // 512 byte sectors
+                _ if header.flags & 0x01 == 0 => map.add(
+                    RegionKind::Data,
+                    &format!("{} sectors", name),
+                    offset + 16,
+                    usize::from(header.sectors_count) * 512,
+                ),
+                _ => (),
+            }
+            offset += header.block_size as usize;
+        }
+
+        map
+    }
+}
+
+/// The tool used value for images made with the Atari imaging tool
+const STX_TOOL_ATARI: u16 = 0x01;
+
+/// The tool used value for images made with the Discovery Cartridge
+const STX_TOOL_DISCOVERY_CARTRIDGE: u16 = 0xCC;
+
+/// The tool that created an STX image, from the tool_used field
+/// Pasti images were made with one of two imaging tools, other values
+/// are kept so images from unknown tools can still be traced.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum STXTool {
+    /// The Pasti imaging tool running on an Atari ST
+    Atari,
+    /// The Pasti imaging tool using a Discovery Cartridge
+    DiscoveryCartridge,
+    /// A tool this crate doesn't know, with the raw tool_used value
+    Unknown(u16),
+}
+
+impl STXTool {
+    /// Return the raw tool_used value
+    pub fn code(&self) -> u16 {
+        match self {
+            STXTool::Atari => STX_TOOL_ATARI,
+            STXTool::DiscoveryCartridge => STX_TOOL_DISCOVERY_CARTRIDGE,
+            STXTool::Unknown(code) => *code,
+        }
+    }
+}
+
+impl From<u16> for STXTool {
+    fn from(code: u16) -> STXTool {
+        match code {
+            STX_TOOL_ATARI => STXTool::Atari,
+            STX_TOOL_DISCOVERY_CARTRIDGE => STXTool::DiscoveryCartridge,
+            _ => STXTool::Unknown(code),
+        }
+    }
+}
+
+impl Display for STXTool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            STXTool::Atari => write!(f, "Pasti Atari imaging tool"),
+            STXTool::DiscoveryCartridge => write!(f, "Pasti Discovery Cartridge imaging tool"),
+            STXTool::Unknown(code) => write!(f, "Unknown tool 0x{:02X}", code),
+        }
+    }
+}
+
+/// STXDiskHeader contains information about an Atari ST STX floppy disk image header
+/// 16 bytes
+#[derive(Debug)]
+pub struct STXDiskHeader<'a> {
+    /// The disk identifier, "RSY\0"
+    pub disk_id: &'a [u8],
+    /// The version of the disk image
+    /// Usually version 3
+    pub version: u16,
+    /// A 16-bit integer that indicates the tool used to create the image
+    pub tool_used: u16,
+    /// First reserved area, two reserved bytes
+    pub reserved_area_1: &'a [u8],
+    /// The number of tracks on the disk
+    pub track_count: u8,
+    /// Whether the disk is in the new format
+    pub new_format: u8,
+    /// Second reserved area, four reserved bytes
+    pub reserved_area_2: &'a [u8],
+}
+
+impl STXDiskHeader<'_> {
+    /// Return the tool that created the image
+    pub fn tool(&self) -> STXTool {
+        STXTool::from(self.tool_used)
+    }
+
+    /// Return who created the image: the tool and the file version
+    /// and revision it wrote
+    pub fn creator(&self) -> Creator {
+        Creator {
+            tool: self.tool().to_string(),
+            code: self.tool_used,
+            version: format!("{} revision {}", self.version, self.new_format),
+        }
+    }
+}
+
+/// Perform sanity checks for a disk header
+/// For now, these are done post-parsing of the section
+/// These are generally less strict than things like magic number identification
+/// but are good indicators the data may be corrupted
+impl SanityCheck for STXDiskHeader<'_> {
+    fn check(&self) -> bool {
+        if self.track_count > 164 {
+            debug!(target: PARSE, "Disk track count is greater than 164: {}", self.track_count);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// Format a STXDiskHeader for display
+impl Display for STXDiskHeader<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "version: {}, tool_used: {} (0x{:02X}), reserved_area_1: {:?}, track_count: {}, ",
+            self.version,
+            self.tool(),
+            self.tool_used,
+            self.reserved_area_1,
+            self.track_count
+        )?;
+        write!(
+            f,
+            "new_format: {}, reserved_area_2: {:?}",
+            self.new_format, self.reserved_area_2
+        )
+    }
+}
+
+/// The track or sector image data can be located in several places, depending on the
+/// fuzzy masks and track flags
+pub fn stx_disk_parser(i: &[u8]) -> IResult<&[u8], STXDisk<'_>> {
+    let (i, stx_disk_header) = stx_disk_header_parser(i)?;
+
+    if !stx_disk_header.check() {
+        error!(target: PARSE, "Invalid data");
+        panic!("Invalid data");
+    }
+
+    debug!(target: PARSE, "Disk header: {}", stx_disk_header);
+
+    let (i, tracks) = stx_tracks_parser(stx_disk_header.track_count as usize)(i)?;
+
+    let stx_disk = STXDisk {
+        stx_disk_header,
+        stx_tracks: tracks,
+    };
+
+    Ok((i, stx_disk))
+}
+
+// TODO: Verify that this is reading correctly
+/// Parse STX disks
+pub fn stx_disk_header_parser(i: &[u8]) -> IResult<&[u8], STXDiskHeader<'_>> {
+    // will consume bytes if the input begins with "RSY" + 0
+    // magic number
+    let (i, disk_id) = tag("RSY\0")(i)?;
+
+    // version
+    let (i, version) = le_u16(i)?;
+
+    // tool used
+    let (i, tool_used) = le_u16(i)?;
+
+    // 2 reserved bytes
+    let (i, reserved_area_1) = take(2_usize)(i)?;
+
+    let (i, track_count) = le_u8(i)?;
+    let (i, new_format) = le_u8(i)?;
+
+    // 4 reserved bytes
+    let (i, reserved_area_2) = take(4_usize)(i)?;
+
+    let stx_disk_header = STXDiskHeader {
+        disk_id,
+        version,
+        tool_used,
+        reserved_area_1,
+        track_count,
+        new_format,
+        reserved_area_2,
+    };
+
+    Ok((i, stx_disk_header))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{stx_disk_header_parser, stx_disk_parser, STXTool};
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::{split_tracks, LogicalTrack};
+    use crate::disk_format::stx::writer::write_stx;
+    use crate::disk_format::testgen;
+
+    /// Test parsing a STX disk header
+    #[test]
+    fn stx_disk_valid_header_parser_works() {
+        // Standard "public tool" / stock Atari ST image
+        // version 3, tool 1, 82 tracks, new format 2
+        let stx_disk_header: [u8; 16] = [
+            0x52, 0x53, 0x59, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x52, 0x02, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+
+        let stx_disk_header_parser_result = stx_disk_header_parser(&stx_disk_header);
+
+        match stx_disk_header_parser_result {
+            Ok((_, res)) => {
+                assert_eq!(res.disk_id, [0x52, 0x53, 0x59, 0x00]);
+                assert_eq!(res.version, 0x03);
+                assert_eq!(res.tool_used, 0x01);
+                assert_eq!(res.reserved_area_1, [0x00, 0x00]);
+                assert_eq!(res.track_count, 0x52);
+                assert_eq!(res.new_format, 0x02);
+                assert_eq!(res.reserved_area_2, [0x00, 0x00, 0x00, 0x00]);
+            }
+            Err(e) => panic!("Parsing failed on the STX disk header: {}", e),
+        }
+    }
+
+    /// Test parsing an invalid STX disk header
+    #[test]
+    #[should_panic(
+        expected = "Parsing failed on the STX disk header: Parsing Error: Error { input: [82, 83, 96, 0, 3, 0, 1, 0, 0, 0, 82, 2, 0, 0, 0, 0], code: Tag }"
+    )]
+    fn stx_disk_invalid_header_parser_fails() {
+        // Standard "public tool" / stock Atari ST image
+        // version 3, tool 1, 82 tracks, new format 2
+        // invalid magic number
+        let stx_disk_header: [u8; 16] = [
+            0x52, 0x53, 0x60, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x52, 0x02, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+
+        let stx_disk_header_parser_result = stx_disk_header_parser(&stx_disk_header);
+
+        match stx_disk_header_parser_result {
+            Ok((_, _)) => panic!("Should fail parsing"),
+            Err(e) => panic!("Parsing failed on the STX disk header: {}", e),
+        }
+    }
+
+    /// Test the track table for a disk with a CRC error and an empty
+    /// track
+    #[test]
+    fn stx_track_table_works() {
+        let geometry = Geometry::atari_st(1, 2, 9);
+        let mut tracks = split_tracks(&testgen::atari_st_sectors(1, 2, 9), &geometry);
+        tracks[1].sectors[2].crc_error = true;
+        tracks.push(LogicalTrack::new(1, 0));
+        let stx = write_stx(&tracks, &BTreeMap::new());
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+
+        let table = disk.track_table();
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            rows,
+            [
+                "track side sectors sizes      flags                    CRC",
+                "    0    0       9 512        0x61 sectors,protected,image ok",
+                "    0    1       9 512        0x61 sectors,protected,image 1 bad",
+                "    1    0       0            0x21 sectors,protected   ok",
+            ]
+        );
+    }
+
+    /// Test decoding the tool that created an image
+    #[test]
+    fn stx_tool_works() {
+        let geometry = Geometry::atari_st(1, 1, 9);
+        let mut stx = testgen::stx(&testgen::atari_st_sectors(1, 1, 9), &geometry);
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+        assert_eq!(disk.stx_disk_header.tool(), STXTool::Atari);
+        let creator = disk.stx_disk_header.creator();
+        assert_eq!(creator.tool, "Pasti Atari imaging tool");
+        assert_eq!(creator.version, "3 revision 2");
+
+        stx[6] = 0xCC;
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+        assert_eq!(disk.stx_disk_header.tool(), STXTool::DiscoveryCartridge);
+
+        stx[6..8].copy_from_slice(&[0x34, 0x12]);
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+        assert_eq!(disk.stx_disk_header.tool(), STXTool::Unknown(0x1234));
+        assert_eq!(disk.stx_disk_header.tool().code(), 0x1234);
+        assert_eq!(disk.stx_disk_header.creator().tool, "Unknown tool 0x1234");
+    }
+}
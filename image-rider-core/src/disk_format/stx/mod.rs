@@ -27,8 +27,8 @@
 //! [Pasti-documentation.pdf](http://info-coach.fr/atari/documents/_mydoc/Pasti-documentation.pdf)\
 //!   Pasti File Documentation Jean Louis-Guérin\
 //!
-#[warn(missing_docs)]
-#[warn(unsafe_code)]
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
 
 /// STX disk image module
 pub mod disk;
@@ -39,6 +39,18 @@ pub mod track;
 /// STX sector module
 pub mod sector;
 
+/// STX image writer
+pub mod writer;
+
+/// Fast sector CRC verification without a full parse
+pub mod verify;
+
+/// Raw track image export with a JSON index
+pub mod track_image;
+
+/// Flat .st and compressed MSA images
+pub mod st;
+
 use crate::disk_format::sanity_check::SanityCheck;
 
 const CCITT_CRC16_POLY: u16 = 0x1021;
@@ -3,7 +3,7 @@
 //!
 use std::fmt::{Display, Formatter, Result};
 
-use log::{debug, error, info};
+use log::{debug, error};
 
 use nom::bytes::complete::take;
 use nom::multi::count;
@@ -12,6 +12,105 @@ use nom::IResult;
 
 use crate::disk_format::sanity_check::SanityCheck;
 use crate::disk_format::stx::crc16_add_byte;
+use crate::log_target::PARSE;
+
+/// FDC status: the computer didn't take a byte in time
+const FDC_LOST_DATA: u8 = 0x04;
+
+/// FDC status: the sector data had a CRC error
+const FDC_CRC_ERROR: u8 = 0x08;
+
+/// FDC status: the sector wasn't found
+const FDC_RECORD_NOT_FOUND: u8 = 0x10;
+
+/// FDC status: the sector has a deleted data mark
+const FDC_DELETED: u8 = 0x20;
+
+/// FDC status: the sector has fuzzy bits
+const FDC_FUZZY: u8 = 0x80;
+
+/// The WD1772 status register after reading a sector
+/// Pasti saves the status the FDC returned, so sectors that were
+/// damaged on purpose as copy protection keep their errors.  Bit 7 is
+/// the motor on bit on the FDC, Pasti uses it to mark sectors with
+/// fuzzy bits.  The busy, data request and write protect bits aren't
+/// decoded but are kept, so the status converts back to the same byte.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct FdcStatus {
+    /// The computer didn't read a byte before the next one arrived
+    pub lost_data: bool,
+    /// The sector data failed its CRC check
+    pub crc_error: bool,
+    /// The sector wasn't found, or its data field was missing
+    pub record_not_found: bool,
+    /// The sector was written with a deleted data mark
+    pub deleted: bool,
+    /// The sector has bits that read differently each time
+    pub fuzzy: bool,
+    /// The bits that aren't decoded
+    pub other: u8,
+}
+
+impl FdcStatus {
+    /// Return true if reading the sector fails, with a CRC error, a
+    /// missing record or lost data
+    /// Sectors written this way on purpose are copy protection, a
+    /// conversion should keep them unreadable.
+    pub fn is_read_error(&self) -> bool {
+        self.crc_error || self.record_not_found || self.lost_data
+    }
+}
+
+impl From<u8> for FdcStatus {
+    fn from(status: u8) -> FdcStatus {
+        FdcStatus {
+            lost_data: status & FDC_LOST_DATA != 0,
+            crc_error: status & FDC_CRC_ERROR != 0,
+            record_not_found: status & FDC_RECORD_NOT_FOUND != 0,
+            deleted: status & FDC_DELETED != 0,
+            fuzzy: status & FDC_FUZZY != 0,
+            other: status
+                & !(FDC_LOST_DATA | FDC_CRC_ERROR | FDC_RECORD_NOT_FOUND | FDC_DELETED | FDC_FUZZY),
+        }
+    }
+}
+
+impl From<FdcStatus> for u8 {
+    fn from(status: FdcStatus) -> u8 {
+        [
+            (status.lost_data, FDC_LOST_DATA),
+            (status.crc_error, FDC_CRC_ERROR),
+            (status.record_not_found, FDC_RECORD_NOT_FOUND),
+            (status.deleted, FDC_DELETED),
+            (status.fuzzy, FDC_FUZZY),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(status.other, |bits, (_, bit)| bits | bit)
+    }
+}
+
+/// Display the decoded status bits, e.g. "CRC error, deleted" or "OK"
+impl Display for FdcStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let names: Vec<&str> = [
+            (self.record_not_found, "record not found"),
+            (self.crc_error, "CRC error"),
+            (self.lost_data, "lost data"),
+            (self.deleted, "deleted"),
+            (self.fuzzy, "fuzzy"),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| name)
+        .collect();
+        if names.is_empty() {
+            write!(f, "OK")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
 
 /// STXSector contains information about a single sector in a STX disk image
 /// This is when we have a custom-size byte standard sector dump
@@ -42,9 +141,39 @@ pub struct STXSectorHeader {
     /// address block CRC
     pub id_crc: u16,
     /// Floppy Drive Controller (FDC) status register after reading the sector
-    pub fdc_status: u8,
+    pub fdc_status: FdcStatus,
     /// reserved sector flags, always zero
     pub reserved: u8,
+    /// The fuzzy mask from the track's fuzzy mask record, with bits
+    /// set for the bits of the sector that read reliably
+    /// None if the sector has no fuzzy bits.
+    pub fuzzy_mask: Option<Vec<u8>>,
+}
+
+impl STXSectorHeader {
+    /// Return the sector data with the fuzzy bits cleared, keeping the
+    /// bits that read the same every time
+    /// Data without a fuzzy mask is returned unchanged.
+    pub fn masked_data(&self, data: &[u8]) -> Vec<u8> {
+        self.fuzzed_data(data, || 0)
+    }
+
+    /// Return the sector data with the fuzzy bits filled in from a
+    /// source of random bytes, the way a real drive reads them
+    /// Data without a fuzzy mask is returned unchanged.
+    pub fn fuzzed_data(&self, data: &[u8], mut random: impl FnMut() -> u8) -> Vec<u8> {
+        match &self.fuzzy_mask {
+            Some(mask) => data
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| {
+                    let mask = mask.get(index).copied().unwrap_or(0xFF);
+                    (byte & mask) | (random() & !mask)
+                })
+                .collect(),
+            None => data.to_vec(),
+        }
+    }
 }
 
 /// A single sector on the disk, including the header
@@ -83,8 +212,10 @@ impl Display for STXSectorHeader {
         write!(f, "id_crc: {}, ", self.id_crc)?;
         write!(
             f,
-            "fdc_status: {}, reserved: {}, ",
-            self.fdc_status, self.reserved
+            "fdc_status: {:02X} ({}), reserved: {}, ",
+            u8::from(self.fdc_status),
+            self.fdc_status,
+            self.reserved
         )
         //write!(f, "sector_size: {}", self.sector_size)
     }
@@ -106,6 +237,7 @@ impl SanityCheck for STXSectorHeader {
         let crc = calculate_crc16(self);
         if crc != self.id_crc {
             debug!(
+                target: PARSE,
                 "Sector CRC is bad: expected: {}, calculated: {}",
                 self.id_crc, crc
             );
@@ -178,7 +310,7 @@ pub fn stx_sector_data_parser_plain(i: &[u8]) -> IResult<&[u8], &[u8]> {
 /// Read in n sectors of data
 pub fn stx_sector_parser_plain(n: usize) -> impl Fn(&[u8]) -> IResult<&[u8], STXPlainSector> {
     move |i| {
-        info!("Reading in plain sector data");
+        debug!(target: PARSE, "Reading in plain sector data");
         let (i, data) = count(stx_sector_data_parser_plain, n)(i)?;
 
         Ok((i, STXPlainSector { contents: data }))
@@ -188,6 +320,19 @@ pub fn stx_sector_parser_plain(n: usize) -> impl Fn(&[u8]) -> IResult<&[u8], STX
 /// Parse a custom STX sector
 /// The sector parser needs the track flags and fuzzy sector mask settings
 pub fn stx_sector_header_parser(i: &[u8]) -> IResult<&[u8], STXSectorHeader> {
+    let (i, sector_header) = stx_sector_header_unchecked_parser(i)?;
+
+    if !sector_header.check() {
+        error!(target: PARSE, "Invalid sector header");
+        panic!("Invalid sector header");
+    }
+
+    Ok((i, sector_header))
+}
+
+/// Parse a sector header without checking the address field CRC, for
+/// reporting bad CRCs instead of failing on them
+pub fn stx_sector_header_unchecked_parser(i: &[u8]) -> IResult<&[u8], STXSectorHeader> {
     let (i, data_offset) = le_u32(i)?;
     let (i, bit_position) = le_u16(i)?;
     let (i, read_time) = le_u16(i)?;
@@ -209,19 +354,15 @@ pub fn stx_sector_header_parser(i: &[u8]) -> IResult<&[u8], STXSectorHeader> {
         id_sector,
         id_size,
         id_crc,
-        fdc_status,
+        fdc_status: FdcStatus::from(fdc_status),
         reserved,
+        fuzzy_mask: None,
     };
 
-    if !sector_header.check() {
-        error!("Invalid sector header");
-        panic!("Invalid sector header");
-    }
-
     Ok((i, sector_header))
 }
 
-/// Parses the sector header and the sector data
+// Parses the sector header and the sector data
 // pub fn stx_sector_parser(
 //     _fuzzy_size: u32,
 //     _flags: u16,
@@ -263,7 +404,7 @@ impl Display for STXSyncMarker<'_> {
 }
 
 /// Read in the four sync markers at the start of the track image data
-pub fn stx_sync_markers_parser(i: &[u8]) -> IResult<&[u8], STXSyncMarker> {
+pub fn stx_sync_markers_parser(i: &[u8]) -> IResult<&[u8], STXSyncMarker<'_>> {
     let (i, stx_sync_markers) = take(4_usize)(i)?;
 
     Ok((
@@ -290,20 +431,24 @@ pub fn parse_boot_sector_as_words(sector_data: &[u8]) -> IResult<&[u8], Vec<u16>
 /// This is the checksum for FAT disks, not STX disks
 /// TODO: Double check this code is in the right crate
 pub fn calculate_boot_sector_sum_from_words(sector_data: &[u8]) -> bool {
-    let mut sum: u32 = 0;
-
-    let words_result = parse_boot_sector_as_words(sector_data);
-
-    match words_result {
-        Ok((_, words)) => {
-            for word in words {
-                sum = (sum + (word as u32)) % 0xFFFF;
-            }
-        }
-        Err(_) => panic!("Parsing failed for boot sector checksum"),
+    match boot_sector_checksum(sector_data) {
+        Some(sum) => sum == BOOT_SECTOR_EXECUTABLE_SUM,
+        None => panic!("Parsing failed for boot sector checksum"),
     }
+}
 
-    sum == 0x1234
+/// The boot sector checksum of an executable boot sector
+pub const BOOT_SECTOR_EXECUTABLE_SUM: u16 = 0x1234;
+
+/// Sum the 256 big-endian words of a boot sector, wrapping at 16 bits
+/// Returns None if the sector is shorter than 512 bytes.
+pub fn boot_sector_checksum(sector_data: &[u8]) -> Option<u16> {
+    let (_, words) = parse_boot_sector_as_words(sector_data).ok()?;
+    Some(
+        words
+            .iter()
+            .fold(0_u16, |sum, word| sum.wrapping_add(*word)),
+    )
 }
 
 /// Calculate the CRC-16 value for the sector header
@@ -326,7 +471,27 @@ pub fn calculate_crc16(sector_header: &STXSectorHeader, /*, sync_markers: &STXSy
 
 #[cfg(test)]
 mod tests {
-    use super::{calculate_boot_sector_sum_from_words, parse_boot_sector_as_words};
+    use super::{calculate_boot_sector_sum_from_words, parse_boot_sector_as_words, FdcStatus};
+
+    /// Test decoding the FDC status and converting it back
+    #[test]
+    fn fdc_status_works() {
+        let status = FdcStatus::from(0x2B);
+        assert!(status.crc_error);
+        assert!(status.deleted);
+        assert!(!status.record_not_found);
+        assert!(status.is_read_error());
+        assert_eq!(status.other, 0x03);
+        assert_eq!(u8::from(status), 0x2B);
+        assert_eq!(status.to_string(), "CRC error, deleted");
+
+        let status = FdcStatus::from(0x94);
+        assert_eq!(status.to_string(), "record not found, lost data, fuzzy");
+        assert_eq!(u8::from(status), 0x94);
+
+        assert!(!FdcStatus::from(0x80).is_read_error());
+        assert_eq!(FdcStatus::default().to_string(), "OK");
+    }
 
     /// Test that converting the boot sector to words works
     #[test]
@@ -336,8 +501,8 @@ mod tests {
         // equivalent to: for i in 0..256 { ... sector_data[i] }
         // for item in sector_data.iter().take(256) {
 
-        for i in 0..512 {
-            boot_sector[i] = (i & 0x00FF) as u8;
+        for (i, byte) in boot_sector.iter_mut().enumerate() {
+            *byte = (i & 0x00FF) as u8;
         }
 
         let words_result = parse_boot_sector_as_words(&boot_sector);
@@ -372,6 +537,6 @@ mod tests {
 
         let checksum = calculate_boot_sector_sum_from_words(&boot_sector);
 
-        assert_eq!(checksum, true);
+        assert!(checksum);
     }
 }
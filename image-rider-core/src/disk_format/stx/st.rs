@@ -0,0 +1,731 @@
+//! Atari ST .st and .msa disk images
+//!
+//! An .st image is a flat dump of the sectors on the disk, track by
+//! track with the sides of each track next to each other.  It has no
+//! header, the number of sectors on a track and the number of sides
+//! are read from the BIOS parameter block in the boot sector, and the
+//! number of tracks from the size of the image.  A geometry given in
+//! the settings is used instead, for raw device reads and dumps of
+//! other IBM-style disks.
+//!
+//! An MSA (Magic Shadow Archiver) image holds the same sectors in
+//! track records after a ten byte header of big-endian words:
+//!
+//! ```ignore
+//! 0x0E0F magic
+//! Sectors per track
+//! Sides, minus one
+//! First track
+//! Last track
+//! ```
+//!
+//! Each track record starts with the length of its data.  A track whose
+//! data is shorter than the track is run-length encoded: a run is
+//! written as 0xE5, the byte and a big-endian count, and every other
+//! byte stands for itself.  Both formats are read into a flat sector
+//! image, so an MSA image is saved as an .st image.
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+use std::path::Path;
+
+use config::Config;
+use log::{debug, error, info, warn};
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::be_u16;
+use nom::IResult;
+
+use crate::disk_format::fat::bpb::{bios_parameter_block_parser, BiosParameterBlock};
+use crate::disk_format::fat::volume::FatVolume;
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::{limit_allocation, limit_sectors_per_track, limit_tracks};
+use crate::disk_format::logical::{split_tracks, LogicalTrack, RawExporter};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::usage::FileExtent;
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// A flat Atari ST image
+pub const ST_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "ST",
+    description: "Atari ST flat disk image",
+    extensions: &["st"],
+    sizes: &[SizeRange::at_least(512)],
+    references: &[],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        track_export: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// A Magic Shadow Archiver image
+pub const MSA_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "MSA",
+    description: "Atari ST Magic Shadow Archiver compressed disk image",
+    extensions: &["msa"],
+    sizes: &[SizeRange::at_least(MSA_HEADER_SIZE)],
+    references: &[],
+    capabilities: ST_FORMAT_INFO.capabilities,
+};
+
+/// The magic at the start of an MSA image
+pub const MSA_MAGIC: [u8; 2] = [0x0E, 0x0F];
+
+/// The size of the MSA header
+pub const MSA_HEADER_SIZE: usize = 10;
+
+/// The byte that starts a run in a compressed MSA track
+pub const MSA_RUN_MARKER: u8 = 0xE5;
+
+/// The size of an Atari ST sector
+const ST_SECTOR_SIZE: usize = 512;
+
+/// The most sectors on an Atari ST track, on extended high density
+/// formats
+const ST_MAX_SECTORS: u16 = 22;
+
+/// The most tracks Atari ST drives can step to
+const ST_MAX_TRACKS: u16 = 86;
+
+/// True if the data starts with the MSA magic
+pub fn is_msa(data: &[u8]) -> bool {
+    data.starts_with(&MSA_MAGIC)
+}
+
+/// True if a filename has the .st extension
+/// Flat images don't have a signature, so they're only read as .st
+/// images when they're named that way.
+pub fn is_st_filename(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("st"))
+}
+
+/// The header of an MSA image
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MSAHeader {
+    /// The number of sectors on each track
+    pub sectors_per_track: u16,
+    /// The number of sides, minus one
+    pub sides: u16,
+    /// The first track in the image
+    pub start_track: u16,
+    /// The last track in the image
+    pub end_track: u16,
+}
+
+impl MSAHeader {
+    /// The number of sides
+    pub fn heads(&self) -> u16 {
+        self.sides + 1
+    }
+
+    /// The number of tracks in the image
+    pub fn tracks(&self) -> u16 {
+        self.end_track.saturating_sub(self.start_track) + 1
+    }
+
+    /// The size of a track once it's decompressed
+    pub fn track_size(&self) -> usize {
+        usize::from(self.sectors_per_track) * ST_SECTOR_SIZE
+    }
+}
+
+/// Format an MSAHeader for display
+impl Display for MSAHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "sectors per track: {}, sides: {}, tracks: {} to {}",
+            self.sectors_per_track,
+            self.heads(),
+            self.start_track,
+            self.end_track
+        )
+    }
+}
+
+impl SanityCheck for MSAHeader {
+    fn check(&self) -> bool {
+        if self.sectors_per_track == 0 || self.sectors_per_track > ST_MAX_SECTORS {
+            debug!(
+                target: PARSE,
+                "Invalid MSA sectors per track: {}", self.sectors_per_track
+            );
+            return false;
+        }
+        if self.sides > 1 {
+            debug!(target: PARSE, "Invalid MSA sides: {}", self.sides);
+            return false;
+        }
+        if self.start_track > self.end_track || self.end_track >= ST_MAX_TRACKS {
+            debug!(
+                target: PARSE,
+                "Invalid MSA track range: {} to {}", self.start_track, self.end_track
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// A track record in an MSA image
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MSATrack {
+    /// The track number
+    pub track: u8,
+    /// The side of the track
+    pub head: u8,
+    /// The offset of the record in the image, where its length is
+    pub offset: usize,
+    /// The length of the track data in the record
+    pub length: u16,
+    /// True if the track data is run-length encoded
+    pub compressed: bool,
+}
+
+/// Decompress the data of an MSA track
+/// Returns None if the runs don't decompress to exactly the size of
+/// the track.
+pub fn msa_decompress(data: &[u8], track_size: usize) -> Option<Vec<u8>> {
+    let mut track = Vec::with_capacity(track_size);
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == MSA_RUN_MARKER {
+            let run = data.get(i + 1..i + 4)?;
+            let count = usize::from(u16::from_be_bytes([run[1], run[2]]));
+            if track.len() + count > track_size {
+                return None;
+            }
+            track.resize(track.len() + count, run[0]);
+            i += 4;
+        } else {
+            track.push(data[i]);
+            i += 1;
+        }
+        if track.len() > track_size {
+            return None;
+        }
+    }
+    (track.len() == track_size).then_some(track)
+}
+
+/// Compress the data of an MSA track
+/// Runs of four or more bytes, and every 0xE5 byte, are written as
+/// runs.  The track is returned as it is if compressing it doesn't
+/// make it shorter.
+pub fn msa_compress(track: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut i = 0;
+    while i < track.len() {
+        let byte = track[i];
+        let run = track[i..]
+            .iter()
+            .take(usize::from(u16::MAX))
+            .take_while(|b| **b == byte)
+            .count();
+        if run >= 4 || byte == MSA_RUN_MARKER {
+            compressed.push(MSA_RUN_MARKER);
+            compressed.push(byte);
+            compressed.extend_from_slice(&(run as u16).to_be_bytes());
+        } else {
+            compressed.extend(std::iter::repeat(byte).take(run));
+        }
+        i += run;
+    }
+    if compressed.len() < track.len() {
+        compressed
+    } else {
+        track.to_vec()
+    }
+}
+
+/// Build an MSA image from a flat Atari ST image in a geometry
+pub fn msa_image(data: &[u8], geometry: &Geometry) -> Vec<u8> {
+    let sectors = geometry.sectors_per_track.first().copied().unwrap_or(0);
+    let mut image = MSA_MAGIC.to_vec();
+    for word in [
+        u16::from(sectors),
+        u16::from(geometry.heads).saturating_sub(1),
+        0,
+        (geometry.tracks() as u16).saturating_sub(1),
+    ] {
+        image.extend_from_slice(&word.to_be_bytes());
+    }
+    let track_size = usize::from(sectors) * geometry.sector_size;
+    for track in data.chunks(track_size.max(1)) {
+        let compressed = msa_compress(track);
+        image.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+        image.extend_from_slice(&compressed);
+    }
+    image
+}
+
+/// An Atari ST disk read from an .st or MSA image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct STDisk {
+    /// The sectors of the disk as a flat image
+    pub data: Vec<u8>,
+    /// The layout of the sectors
+    pub geometry: Geometry,
+    /// The BIOS parameter block in the boot sector, if it's valid
+    pub bpb: Option<BiosParameterBlock>,
+    /// The MSA header, None for .st images
+    pub msa_header: Option<MSAHeader>,
+    /// The MSA track records, empty for .st images
+    pub msa_tracks: Vec<MSATrack>,
+    /// True if the disk is read-only, the file allocation table of its
+    /// FAT volume refuses changes
+    pub read_only: bool,
+}
+
+impl STDisk {
+    /// True if the disk was read from an MSA image
+    pub fn is_msa(&self) -> bool {
+        self.msa_header.is_some()
+    }
+
+    /// Parse the FAT filesystem in the disk's sectors
+    pub fn fat_volume(&self) -> std::result::Result<FatVolume, Error> {
+        let mut volume = FatVolume::parse(self.data.clone())?;
+        volume.fat.read_only = self.read_only;
+        Ok(volume)
+    }
+
+    /// Return the free sectors of the FAT filesystem
+    /// Returns an empty set if the disk doesn't hold one.
+    pub fn free_sectors(&self) -> BTreeSet<SectorId> {
+        self.fat_volume()
+            .map(|volume| {
+                volume
+                    .sector_ids(&self.geometry, volume.free_sectors())
+                    .into_iter()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the sectors used by the FAT filesystem: the boot sector,
+    /// the tables and the directories
+    /// Returns an empty set if the disk doesn't hold one.
+    pub fn system_sectors(&self) -> BTreeSet<SectorId> {
+        self.fat_volume()
+            .map(|volume| {
+                volume
+                    .sector_ids(&self.geometry, volume.system_sectors())
+                    .into_iter()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the sectors of each file in the FAT filesystem
+    pub fn file_extents(&self) -> Vec<FileExtent> {
+        self.fat_volume()
+            .map(|volume| volume.file_extents(&self.geometry))
+            .unwrap_or_default()
+    }
+}
+
+/// Format an STDisk for display, the geometry and the MSA header
+impl Display for STDisk {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "tracks: {}, sides: {}, sectors per track: {}",
+            self.geometry.tracks(),
+            self.geometry.heads,
+            self.geometry.sectors_per_track.first().unwrap_or(&0)
+        )?;
+        if let Some(header) = &self.msa_header {
+            let compressed = self
+                .msa_tracks
+                .iter()
+                .filter(|track| track.compressed)
+                .count();
+            write!(
+                f,
+                "\nMSA header: {}, compressed tracks: {}",
+                header, compressed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for STDisk {
+    fn check(&self) -> bool {
+        self.msa_header.as_ref().map_or(true, MSAHeader::check)
+            && self.bpb.as_ref().is_some_and(BiosParameterBlock::check)
+    }
+}
+
+impl DiskImageSaver for STDisk {
+    /// Write the disk as a flat .st image, or a file from the FAT
+    /// filesystem if one is selected
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        if let Some(selected_filename) = selected_filename {
+            let file = self
+                .disk_files()
+                .into_iter()
+                .find(|file| file.name.eq_ignore_ascii_case(selected_filename))
+                .ok_or_else(|| {
+                    error!(target: IO, "File not found: {}", selected_filename);
+                    Error::new(ErrorKind::NotFound(format!(
+                        "File not found: {}",
+                        selected_filename
+                    )))
+                })?;
+            info!(target: IO, "Found file {}, writing data", file.name);
+            writer.write_all(&file.data)?;
+            return Ok(());
+        }
+
+        info!(target: IO, "Found image data, writing data");
+        writer.write_all(&self.data)?;
+        Ok(())
+    }
+
+    /// The files in the FAT filesystem on the disk, with their paths
+    /// Returns no files if the disk doesn't hold a FAT filesystem.
+    fn disk_files(&self) -> Vec<DiskFile> {
+        match self.fat_volume() {
+            Ok(volume) => volume.disk_files(),
+            Err(e) => {
+                debug!(target: PARSE, "No FAT filesystem on the disk: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl RawExporter for STDisk {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        split_tracks(&self.data, &self.geometry)
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        Some(self.geometry.clone())
+    }
+}
+
+/// The boot sector and the sectors of an .st image, or the header and
+/// track records of an MSA image
+impl SourceMapper for STDisk {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        if !self.is_msa() {
+            map.add(RegionKind::Header, "boot sector", 0, ST_SECTOR_SIZE);
+            map.add(
+                RegionKind::Data,
+                "sectors",
+                0,
+                self.geometry.total_size().min(data.len()),
+            );
+            return map;
+        }
+
+        map.add(RegionKind::Header, "MSA header", 0, MSA_HEADER_SIZE);
+        for track in &self.msa_tracks {
+            let name = format!("track {} side {}", track.track, track.head);
+            map.add(
+                RegionKind::Header,
+                &format!("{} length", name),
+                track.offset,
+                2,
+            );
+            map.add(
+                RegionKind::Data,
+                &name,
+                track.offset + 2,
+                usize::from(track.length),
+            );
+        }
+        map
+    }
+}
+
+/// Read the geometry of a flat image from the BIOS parameter block
+/// The number of tracks comes from the size of the image.  Returns
+/// None if the boot sector doesn't describe the image.
+fn bpb_geometry(bpb: &BiosParameterBlock, size: usize) -> Option<Geometry> {
+    if !bpb.check()
+        || usize::from(bpb.bytes_per_sector) != ST_SECTOR_SIZE
+        || bpb.sectors_per_track == 0
+        || bpb.sectors_per_track > ST_MAX_SECTORS
+        || !(1..=2).contains(&bpb.heads)
+    {
+        return None;
+    }
+    let track_size = usize::from(bpb.sectors_per_track) * usize::from(bpb.heads) * ST_SECTOR_SIZE;
+    let tracks = size / track_size;
+    if tracks == 0 || tracks > usize::from(ST_MAX_TRACKS) {
+        return None;
+    }
+    if usize::from(bpb.total_sectors) * ST_SECTOR_SIZE != tracks * track_size {
+        warn!(
+            target: PARSE,
+            "The boot sector says the disk has {} sectors, the image holds {}",
+            bpb.total_sectors,
+            tracks * track_size / ST_SECTOR_SIZE
+        );
+    }
+    Some(Geometry::atari_st(
+        tracks as u8,
+        bpb.heads as u8,
+        bpb.sectors_per_track as u8,
+    ))
+}
+
+/// Parse a flat .st image
+/// The geometry comes from the boot sector, or from the size of the
+/// image if the boot sector doesn't hold a valid BIOS parameter block,
+/// as on many game disks.
+pub fn st_disk_parser(i: &[u8]) -> IResult<&[u8], STDisk> {
+    let (_, bpb) = bios_parameter_block_parser(i)?;
+    let geometry = match bpb_geometry(&bpb, i.len()) {
+        Some(geometry) => geometry,
+        None => Geometry::from_size(i.len())
+            .filter(|geometry| geometry.sector_size == ST_SECTOR_SIZE && geometry.first_sector == 1)
+            .ok_or_else(|| {
+                error!(target: PARSE, "Couldn't find the geometry of the .st image");
+                nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify))
+            })?,
+    };
+    let (rest, data) = take(geometry.total_size())(i)?;
+
+    Ok((
+        rest,
+        STDisk {
+            data: data.to_vec(),
+            geometry,
+            bpb: bpb.check().then_some(bpb),
+            msa_header: None,
+            msa_tracks: Vec::new(),
+            read_only: false,
+        },
+    ))
+}
+
+/// Parse a flat image with a geometry given explicitly, e.g. in the
+/// "tracks" and "sectors" settings for a raw device read
+/// The geometry is trusted over the size of the image and the boot
+/// sector, which is only kept if it holds a valid BIOS parameter block.
+pub fn flat_disk_parser(geometry: Geometry) -> impl Fn(&[u8]) -> IResult<&[u8], STDisk> {
+    move |i| {
+        let bpb = bios_parameter_block_parser(i)
+            .ok()
+            .map(|(_, bpb)| bpb)
+            .filter(|bpb| bpb.check());
+        if let Some(bpb) = bpb {
+            if usize::from(bpb.bytes_per_sector) != geometry.sector_size
+                || Some(&bpb.sectors_per_track)
+                    != geometry
+                        .sectors_per_track
+                        .first()
+                        .map(|s| u16::from(*s))
+                        .as_ref()
+            {
+                warn!(
+                    target: PARSE,
+                    "The boot sector says the disk has {} sectors of {} bytes per track",
+                    bpb.sectors_per_track,
+                    bpb.bytes_per_sector
+                );
+            }
+        }
+        let (rest, data) = take(geometry.total_size())(i)?;
+
+        Ok((
+            rest,
+            STDisk {
+                data: data.to_vec(),
+                geometry: geometry.clone(),
+                bpb,
+                msa_header: None,
+                msa_tracks: Vec::new(),
+                read_only: false,
+            },
+        ))
+    }
+}
+
+/// Parse the MSA header
+pub fn msa_header_parser(i: &[u8]) -> IResult<&[u8], MSAHeader> {
+    let (i, _magic) = tag(&MSA_MAGIC[..])(i)?;
+    let (i, sectors_per_track) = be_u16(i)?;
+    let (i, sides) = be_u16(i)?;
+    let (i, start_track) = be_u16(i)?;
+    let (i, end_track) = be_u16(i)?;
+
+    Ok((
+        i,
+        MSAHeader {
+            sectors_per_track,
+            sides,
+            start_track,
+            end_track,
+        },
+    ))
+}
+
+/// Parse an MSA image, decompressing each track into a flat image
+pub fn msa_disk_parser(i: &[u8]) -> IResult<&[u8], STDisk> {
+    let data = i;
+    let (i, header) = msa_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[2..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    limit_tracks(i, usize::from(header.tracks()))?;
+    limit_sectors_per_track(i, usize::from(header.sectors_per_track))?;
+    let track_size = header.track_size();
+    limit_allocation(
+        i,
+        track_size * usize::from(header.tracks()) * usize::from(header.heads()),
+    )?;
+
+    let mut i = i;
+    let mut sectors = Vec::new();
+    let mut msa_tracks = Vec::new();
+    for track in header.start_track..=header.end_track {
+        for head in 0..header.heads() {
+            let offset = data.len() - i.len();
+            let (rest, length) = be_u16(i)?;
+            let (rest, track_data) = take(length)(rest)?;
+            let compressed = usize::from(length) != track_size;
+            if compressed {
+                let decompressed = msa_decompress(track_data, track_size).ok_or_else(|| {
+                    error!(
+                        target: PARSE,
+                        "MSA track {} side {} doesn't decompress to a whole track", track, head
+                    );
+                    nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify))
+                })?;
+                sectors.extend_from_slice(&decompressed);
+            } else {
+                sectors.extend_from_slice(track_data);
+            }
+            msa_tracks.push(MSATrack {
+                track: track as u8,
+                head: head as u8,
+                offset,
+                length,
+                compressed,
+            });
+            i = rest;
+        }
+    }
+    debug!(target: PARSE, "MSA header: {}", header);
+
+    // Images that don't start at track zero have no boot sector
+    let bpb = bios_parameter_block_parser(&sectors)
+        .ok()
+        .map(|(_, bpb)| bpb)
+        .filter(|bpb| header.start_track == 0 && bpb.check());
+    Ok((
+        i,
+        STDisk {
+            data: sectors,
+            geometry: Geometry::uniform(
+                header.tracks() as u8,
+                header.heads() as u8,
+                header.sectors_per_track as u8,
+                ST_SECTOR_SIZE,
+                header.start_track as u8,
+                1,
+            ),
+            bpb,
+            msa_header: Some(header),
+            msa_tracks,
+            read_only: false,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        is_msa, is_st_filename, msa_compress, msa_decompress, msa_disk_parser, msa_image,
+        st_disk_parser,
+    };
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::logical::RawExporter;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+
+    /// Test reading .st and MSA images into the same sectors and FAT
+    /// filesystem
+    #[test]
+    fn st_disk_parser_works() {
+        assert_eq!(msa_compress(&[1, 1, 1, 1, 1, 2]), [0xE5, 1, 0, 5, 2]);
+        assert_eq!(msa_compress(&[0xE5, 3]), [0xE5, 3]);
+        assert_eq!(
+            msa_decompress(&[0xE5, 0xE5, 0, 1, 3], 2),
+            Some(vec![0xE5, 3])
+        );
+        assert_eq!(msa_decompress(&[0xE5, 0, 0, 9], 4), None);
+        assert_eq!(msa_decompress(&[0xE5, 0, 0], 0), None);
+        assert!(is_st_filename("GAME.ST"));
+        assert!(!is_st_filename("game.stx"));
+
+        let st = testgen::atari_st_fat("GAMEDISK", &[("README.TXT", b"HELLO ATARI")]).unwrap();
+        let (_, st_disk) = st_disk_parser(&st).unwrap();
+        assert!(st_disk.check());
+        assert!(!st_disk.is_msa());
+        assert_eq!(st_disk.geometry, Geometry::atari_st(80, 2, 9));
+        let files = st_disk.disk_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].data, b"HELLO ATARI");
+        assert_eq!(
+            st_disk.file_extents()[0].sectors,
+            [SectorId::new(1, 0, 1), SectorId::new(1, 0, 2)]
+        );
+        assert_eq!(st_disk.system_sectors().len(), 18);
+
+        let msa = msa_image(&st, &st_disk.geometry);
+        assert!(is_msa(&msa));
+        assert!(msa.len() < st.len() / 10);
+        let (_, msa_disk) = msa_disk_parser(&msa).unwrap();
+        assert!(msa_disk.check());
+        assert_eq!(msa_disk.data, st);
+        assert_eq!(msa_disk.msa_tracks.len(), 160);
+        assert!(msa_disk.msa_tracks.iter().all(|track| track.compressed));
+        assert_eq!(msa_disk.logical_tracks(), st_disk.logical_tracks());
+        assert_eq!(msa_disk.to_bytes(&Config::default(), None).unwrap(), st);
+
+        // A flat image without a boot sector falls back to its size
+        let sectors = testgen::atari_st_sectors(80, 1, 9);
+        let (_, blank_disk) = st_disk_parser(&sectors).unwrap();
+        assert_eq!(blank_disk.geometry, Geometry::atari_st(80, 1, 9));
+        assert!(blank_disk.bpb.is_none());
+        assert!(st_disk_parser(&sectors[..1000]).is_err());
+
+        let mut bad_header = msa.clone();
+        bad_header[5] = 2;
+        assert!(msa_disk_parser(&bad_header).is_err());
+        // A run longer than the track
+        let mut bad_run = msa.clone();
+        let run = bad_run[12..].iter().position(|b| *b == 0xE5).unwrap() + 12;
+        bad_run[run + 2] = 0xFF;
+        assert!(msa_disk_parser(&bad_run).is_err());
+        assert!(msa_disk_parser(&msa[..msa.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,853 @@
+use log::{debug, error};
+use nom::bytes::complete::take;
+use nom::combinator::cond;
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::IResult;
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::limits::{limit_allocation, limit_sectors_per_track, limit_tracks};
+use crate::disk_format::mfm::{
+    field_crc, DATA_ADDRESS_MARK, DELETED_DATA_ADDRESS_MARK, ID_ADDRESS_MARK,
+};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::stx::sector::{
+    sector_size_as_bytes, stx_sector_data_parser, stx_sector_header_parser,
+    stx_sector_parser_plain, FdcStatus, STXSectorHeader,
+};
+use crate::disk_format::stx::writer::find_mark;
+use crate::disk_format::stx::SanityCheck;
+use crate::error::Location;
+use crate::log_target::PARSE;
+
+/// The STXTrackHeader structure contains information about a single track in a STX disk image
+/// 16 bytes
+#[derive(Debug)]
+pub struct STXTrackHeader {
+    /// The block size of this track, in bytes
+    /// byte 0 in the track header
+    pub block_size: u32,
+    /// The fuzzy sector mask size, in bytes
+    /// byte 3 in the track header
+    /// The fuzzy sector mask is used for copy protection.
+    /// It has bits set for every bit in the sector that are not random
+    /// (the bits that are real data)
+    pub fuzzy_size: u32,
+    /// The number of sectors in this track
+    /// THis includes sector address blocks and sector data blocks
+    /// byte 7 in the header
+    pub sectors_count: u16,
+    /// Flags for this track
+    /// byte 9 in the header
+    /// bit 0: if bit 0 is set, the track contains sector blocks
+    ///        the track is protected with a custom sector-size
+    ///        One of the "custom" sizes is still 512-bytes long
+    ///        if bit 0 is set, a standard sector size of 512 bytes is used
+    ///        This is like a .ST disk image dump, data is just after the header
+    /// bit 5: the track is protected
+    /// bit 6: the track contains a track image
+    /// bit 7: the track image has a sync position
+    ///        the sync position is a word at the start of the track image
+    pub flags: u16,
+    /// The MFM size, also known as the track length
+    /// byte 11 in the header
+    pub mfm_size: u16,
+    /// The track number
+    /// byte 13 in the header
+    /// bit 7 determines the side of the floppy (0 is side A, 1 is side B)
+    pub track_number: u8,
+    /// The record type or track type
+    /// byte 14 in the header
+    /// 0 == WDC track dump, 0xCC == DC type track dump
+    /// bits 0-6 are
+    /// bit 7 is
+    pub record_type: u8,
+}
+
+/// Perform sanity checks for a track header
+/// For now, these are done post-parsing of the section
+/// These are generally less strict than things like magic number identification
+/// but are good indicators the data may be corrupted
+impl SanityCheck for STXTrackHeader {
+    fn check(&self) -> bool {
+        if (self.flags != 0x21) && (self.flags != 0x61) && (self.flags != 0xc1) {
+            debug!(target: PARSE, "Disk flags are a nonstandard value: 0x{:X}", self.flags);
+            return false;
+        }
+
+        if ((self.flags & 0x40) == 0) && (self.sectors_count > 0) {
+            debug!(target: PARSE, "If flags bit 6 is not set, the sector count should be zero");
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Format a STXTrackHeader for display
+impl Display for STXTrackHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "block_size: {}, fuzzy_size: {}, ",
+            self.block_size, self.fuzzy_size
+        )?;
+        writeln!(f, "sectors_count: {}", self.sectors_count)?;
+        writeln!(f, "       Flags: 0x{:X} {:b}", self.flags, self.flags)?;
+        writeln!(
+            f,
+            "         bit0(custom-size-byte-sector): {}",
+            // bit 0
+            if (self.flags & 0x01) == 0x01 {
+                "T"
+            } else {
+                "F"
+            }
+        )?;
+        writeln!(
+            f,
+            "         bit5(track-is-proteced): {}",
+            // bit 5
+            if (self.flags & 0x20) == 0x20 {
+                "T"
+            } else {
+                "F"
+            }
+        )?;
+        writeln!(
+            f,
+            "         bit6(has-track-image): {}",
+            // bit 6
+            if (self.flags & 0x40) == 0x40 {
+                "T"
+            } else {
+                "F"
+            }
+        )?;
+        writeln!(
+            f,
+            "         bit7(track-image-has-sync-pos): {}",
+            // bit 7
+            if (self.flags & 0x80) == 0x80 {
+                "T"
+            } else {
+                "F"
+            }
+        )?;
+        write!(
+            f,
+            "       mfm_size: {}, track_number: {}, ",
+            self.mfm_size, self.track_number
+        )?;
+        write!(f, "record_type: {}", self.record_type)
+    }
+}
+
+/// Parse a single track on the disk
+/// Returns the remaining bytes an STXTrackHeader filled out with the track information
+pub fn stx_track_header_parser(i: &[u8]) -> IResult<&[u8], STXTrackHeader> {
+    // The track header is 16 bytes long
+    let (i, block_size) = le_u32(i)?;
+    let (i, fuzzy_size) = le_u32(i)?;
+    let (i, sectors_count) = le_u16(i)?;
+    let (i, flags) = le_u16(i)?;
+    let (i, mfm_size) = le_u16(i)?;
+    let (i, track_number) = le_u8(i)?;
+    let (i, record_type) = le_u8(i)?;
+
+    let stx_track_header = STXTrackHeader {
+        block_size,
+        fuzzy_size,
+        sectors_count,
+        flags,
+        mfm_size,
+        track_number,
+        record_type,
+    };
+
+    Ok((i, stx_track_header))
+}
+
+/// A STXTrack contains a STXTrackHeader
+#[derive(Debug)]
+pub struct STXTrack<'a> {
+    /// Thea header for this track
+    pub header: STXTrackHeader,
+
+    /// The sector headers in this track
+    pub sector_headers: Option<Vec<STXSectorHeader>>,
+
+    /// The sector data for this track
+    pub sector_data: Option<Vec<&'a [u8]>>,
+
+    /// The raw track image, if the track was imaged with a Read Track
+    /// command
+    pub track_image: Option<STXTrackImage<'a>>,
+}
+
+/// The bytes a Read Track command returned when the track was imaged
+/// Copy protections often hide data between sectors, and the track
+/// image is the only place it's kept.
+#[derive(Debug)]
+pub struct STXTrackImage<'a> {
+    /// The offset of the first sync mark in the image, None if the
+    /// track doesn't record one
+    pub first_sync_offset: Option<u16>,
+    /// The offset of the image from the start of the track data, the
+    /// size of the image header
+    /// Sector data offsets are relative to the start of the track data.
+    pub offset: usize,
+    /// The track image bytes
+    pub data: &'a [u8],
+}
+
+/// Display a single track
+impl Display for STXTrack<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "header: {}", self.header)
+    }
+}
+
+/// The track header flags and their names in the track table
+const TRACK_FLAG_NAMES: [(u16, &str); 4] = [
+    (0x01, "sectors"),
+    (0x20, "protected"),
+    (0x40, "image"),
+    (0x80, "sync"),
+];
+
+/// A row of the track table, the layout of one track
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct STXTrackSummary {
+    /// The track number
+    pub track: u8,
+    /// The side of the disk
+    pub side: u8,
+    /// The number of sectors
+    pub sectors: usize,
+    /// The sector sizes on the track, in bytes
+    pub sizes: BTreeSet<usize>,
+    /// The track header flags
+    pub flags: u16,
+    /// True if the track has a fuzzy sector mask
+    pub fuzzy: bool,
+    /// The number of sectors read with a CRC error
+    pub crc_errors: usize,
+}
+
+impl STXTrackSummary {
+    /// The column headings of the track table
+    pub const HEADINGS: &'static str = "track side sectors sizes      flags                    CRC";
+}
+
+impl STXTrack<'_> {
+    /// Return the data of the sector at an index on the track with its
+    /// fuzzy bits cleared
+    /// Returns None if the track has no sector there.
+    pub fn masked_sector_data(&self, index: usize) -> Option<Vec<u8>> {
+        let header = self.sector_headers.as_ref()?.get(index)?;
+        let data = self.sector_data.as_ref()?.get(index)?;
+        Some(header.masked_data(data))
+    }
+
+    /// Return the data of the sector at an index on the track with its
+    /// fuzzy bits filled in from a source of random bytes
+    /// Returns None if the track has no sector there.
+    pub fn fuzzed_sector_data(&self, index: usize, random: impl FnMut() -> u8) -> Option<Vec<u8>> {
+        let header = self.sector_headers.as_ref()?.get(index)?;
+        let data = self.sector_data.as_ref()?.get(index)?;
+        Some(header.fuzzed_data(data, random))
+    }
+
+    /// Summarize the layout of the track
+    pub fn summary(&self) -> STXTrackSummary {
+        let headers = self.sector_headers.as_deref().unwrap_or_default();
+        let sizes = match &self.sector_data {
+            Some(data) => data.iter().map(|sector| sector.len()).collect(),
+            None if headers.is_empty() && self.header.sectors_count > 0 => BTreeSet::from([512]),
+            None => BTreeSet::new(),
+        };
+        STXTrackSummary {
+            track: self.header.track_number & 0x7F,
+            side: self.header.track_number >> 7,
+            sectors: usize::from(self.header.sectors_count).max(headers.len()),
+            sizes,
+            flags: self.header.flags,
+            fuzzy: self.header.fuzzy_size > 0,
+            crc_errors: headers
+                .iter()
+                .filter(|header| header.fdc_status.crc_error)
+                .count(),
+        }
+    }
+}
+
+/// Display a track as a row of the track table
+impl Display for STXTrackSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let sizes: Vec<String> = self.sizes.iter().map(|size| size.to_string()).collect();
+        let mut flags: Vec<&str> = TRACK_FLAG_NAMES
+            .iter()
+            .filter(|(bit, _)| self.flags & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        if self.fuzzy {
+            flags.push("fuzzy");
+        }
+        let flags = format!("0x{:02X} {}", self.flags, flags.join(","));
+        let crc = match self.crc_errors {
+            0 => String::from("ok"),
+            errors => format!("{} bad", errors),
+        };
+        write!(
+            f,
+            "{:>5} {:>4} {:>7} {:<10} {:<24} {}",
+            self.track,
+            self.side,
+            self.sectors,
+            sizes.join(","),
+            flags,
+            crc
+        )
+    }
+}
+
+/// Parse the track data, including sector headers in the track
+/// TODO: Implement full parsing
+/// This currently doesn't parse track data, just the headers
+/// TODO: Simplify this parser
+pub fn stx_track_parser(i: &[u8]) -> IResult<&[u8], STXTrack<'_>> {
+    // Record the starting position so we can figure out how much was missed
+    let starting_position = i;
+    let stx_track_header_result = stx_track_header_parser(i)?;
+
+    let stx_track_header = stx_track_header_result.1;
+    let i = stx_track_header_result.0;
+
+    if !stx_track_header.check() {
+        error!(target: PARSE, "Invalid data");
+        panic!("Invalid data");
+    }
+
+    let sectors_count = usize::from(stx_track_header.sectors_count);
+    let (i, _) = limit_sectors_per_track(i, sectors_count)?;
+    let (i, _) = limit_allocation(i, sectors_count * std::mem::size_of::<STXSectorHeader>())?;
+
+    // The track data starts after the sector headers and fuzzy mask
+    let track_image = if (stx_track_header.flags & 0x01) != 0 {
+        i.get(16 * sectors_count + stx_track_header.fuzzy_size as usize..)
+            .and_then(|data| stx_track_image(stx_track_header.flags, data))
+    } else {
+        None
+    };
+
+    let (_, sector_headers, sector_data) = if (stx_track_header.flags & 0x01) != 0x01 {
+        // Parse a plain data track
+        if stx_track_header.sectors_count > 0 {
+            let stx_sector = stx_sector_parser_plain(stx_track_header.sectors_count as usize)(i)?;
+            (stx_sector.0, None, None)
+        } else {
+            (i, None, None)
+        }
+    } else {
+        // Parse a set of sector headers
+        // Find out how many sector headers to parse
+
+        debug!(target: PARSE, "Track header: {}", stx_track_header);
+        // Parse the STX sector headers
+        // The last track has issues parsing in some cases, we hit EOF
+        // The last tracks are sometimes flag 0x21 and not 0x61, we need to
+        // deal with each track image data separately
+        let (i, sector_headers, sector_data) = if stx_track_header.sectors_count > 0 {
+            let stx_sector_headers_result = count(
+                stx_sector_header_parser,
+                stx_track_header.sectors_count as usize,
+            )(stx_track_header_result.0)?;
+            let mut stx_sector_headers = stx_sector_headers_result.1;
+            let sector_header_iter = stx_sector_headers.iter();
+            for header in sector_header_iter {
+                debug!(target: PARSE, "stx_sector_header: {}", header);
+                // CRC errors are usually copy protection
+                if header.fdc_status.crc_error {
+                    warning(
+                        Warning::new("sector has a CRC error").with_location(Location {
+                            head: Some(header.id_head),
+                            ..Location::sector(header.id_track, header.id_sector).with_format("STX")
+                        }),
+                    );
+                }
+            }
+
+            // The fuzzy mask record holds the masks of the fuzzy
+            // sectors
+            let (i, fuzzy_masks) = take(stx_track_header.fuzzy_size)(stx_sector_headers_result.0)?;
+            attach_fuzzy_masks(&mut stx_sector_headers, fuzzy_masks, &stx_track_header);
+
+            // The track image data
+            // First the header, two or four bytes depending on the flags
+            // If track flags bit six (starting from bit zero) is set
+            //   Then also test bit seven.
+            //     If bit seven is set, read in two bytes, the first sync offset
+            //   Then read read in the track image size, two bytes
+            // If bit seven is not set, the first sync offset is zero, size is
+            // calculated from other data
+            // just read in the track image data
+            let stx_track_image_header_result =
+                stx_track_image_header_parser(stx_track_header.flags)(i)?;
+            debug!(
+                target: PARSE,
+                "stx_track_image_header: {}",
+                stx_track_image_header_result.1
+            );
+
+            let stx_sector_data_parser_result =
+                //stx_sector_data_parser(&stx_track_header, &stx_sector_headers)(stx_track_image_header_result.0)?;
+                stx_sector_data_parser(&stx_sector_headers)(i)?;
+
+            (
+                stx_track_image_header_result.0,
+                Some(stx_sector_headers),
+                Some(stx_sector_data_parser_result.1),
+            )
+        } else {
+            (i, None, None)
+        };
+
+        (i, sector_headers, sector_data)
+    };
+
+    // Tracks imaged without sector descriptors only have their sectors
+    // in the track image
+    let (sector_headers, sector_data) = match (&track_image, sector_headers) {
+        (Some(image), None) => {
+            let (headers, data) = stx_track_image_sectors(image);
+            if headers.is_empty() {
+                warning(
+                    Warning::new("no sectors found in the track image").with_location(
+                        Location {
+                            track: Some(stx_track_header.track_number & 0x7F),
+                            head: Some(stx_track_header.track_number >> 7),
+                            ..Location::default()
+                        }
+                        .with_format("STX"),
+                    ),
+                );
+                (None, None)
+            } else {
+                (Some(headers), Some(data))
+            }
+        }
+        (_, sector_headers) => (sector_headers, sector_data),
+    };
+
+    // We don't use the i returned from the sector headers parsing block above, because
+    // the sector data and track image are read by offset rather than in order.
+    // But we know the total length of the tracks, so we can skip to the next block
+    let (i, _) = take(stx_track_header.block_size)(starting_position)?;
+
+    Ok((
+        i,
+        STXTrack {
+            header: stx_track_header,
+            sector_headers,
+            sector_data,
+            track_image,
+        },
+    ))
+}
+
+/// The most bytes searched after an address field for its data field
+/// The WD1772 gives up on a sector if the data mark isn't found within
+/// 43 bytes.
+const DATA_MARK_WINDOW: usize = 43;
+
+/// Find the sectors in a track image by their address marks
+/// The search starts at the first sync mark when the track records
+/// one.  Each address field with a good CRC is matched to the data
+/// field that follows it, and a sector header is built for it the way
+/// the Pasti tool would have recorded it, with the data offset
+/// pointing into the image.  Sectors with a bad data CRC keep their
+/// data with the CRC error status bit set.
+pub fn stx_track_image_sectors<'a>(
+    image: &STXTrackImage<'a>,
+) -> (Vec<STXSectorHeader>, Vec<&'a [u8]>) {
+    let data = image.data;
+    let mut headers = Vec::new();
+    let mut sectors = Vec::new();
+    let mut position = image
+        .first_sync_offset
+        .map(usize::from)
+        .filter(|offset| *offset < data.len())
+        .unwrap_or(0);
+
+    while let Some(id_mark) = find_mark(data, position, &[ID_ADDRESS_MARK]) {
+        let Some(id) = data.get(id_mark + 1..id_mark + 7) else {
+            break;
+        };
+        let id_crc = u16::from_be_bytes([id[4], id[5]]);
+        if id_crc != field_crc(ID_ADDRESS_MARK, &id[..4]) {
+            debug!(target: PARSE, "Bad address field CRC at {}", id_mark);
+            position = id_mark + 1;
+            continue;
+        }
+        position = id_mark + 7;
+
+        let Some(data_mark) = find_mark(
+            data,
+            position,
+            &[DATA_ADDRESS_MARK, DELETED_DATA_ADDRESS_MARK],
+        )
+        .filter(|data_mark| *data_mark <= position + DATA_MARK_WINDOW) else {
+            debug!(target: PARSE, "No data field for sector {}", id[2]);
+            continue;
+        };
+        // The WD1772 only uses the low two bits of the size code
+        let size = 128_usize << (id[3] & 0x03);
+        let Some(field) = data.get(data_mark + 1..data_mark + 1 + size + 2) else {
+            debug!(target: PARSE, "Sector {} runs past the track image", id[2]);
+            break;
+        };
+        let sector = &field[..size];
+        let crc = u16::from_be_bytes([field[size], field[size + 1]]);
+
+        headers.push(STXSectorHeader {
+            data_offset: (image.offset + data_mark + 1) as u32,
+            bit_position: (id_mark * 8) as u16,
+            read_time: 0,
+            id_track: id[0],
+            id_head: id[1],
+            id_sector: id[2],
+            id_size: id[3],
+            id_crc,
+            fdc_status: FdcStatus {
+                crc_error: crc != field_crc(data[data_mark], sector),
+                deleted: data[data_mark] == DELETED_DATA_ADDRESS_MARK,
+                ..FdcStatus::default()
+            },
+            reserved: 0,
+            fuzzy_mask: None,
+        });
+        sectors.push(sector);
+        position = data_mark + 1 + size + 2;
+    }
+
+    debug!(
+        target: PARSE,
+        "Found {} sectors in the track image",
+        headers.len()
+    );
+    (headers, sectors)
+}
+
+/// Split a fuzzy mask record into the masks of the sectors with fuzzy
+/// bits
+/// The masks are stored one after another in sector order, each the
+/// size of its sector.  A record that's too short for every fuzzy
+/// sector is recorded as a warning, the sectors past its end are left
+/// without a mask.
+fn attach_fuzzy_masks(
+    sector_headers: &mut [STXSectorHeader],
+    fuzzy_masks: &[u8],
+    track_header: &STXTrackHeader,
+) {
+    let mut offset = 0;
+    for header in sector_headers
+        .iter_mut()
+        .filter(|header| header.fdc_status.fuzzy)
+    {
+        let size = usize::from(sector_size_as_bytes(header.id_size));
+        match fuzzy_masks.get(offset..offset + size) {
+            Some(mask) => header.fuzzy_mask = Some(mask.to_vec()),
+            None => {
+                warning(
+                    Warning::new("fuzzy mask record is too short").with_location(
+                        Location {
+                            track: Some(track_header.track_number & 0x7F),
+                            head: Some(track_header.track_number >> 7),
+                            ..Location::default()
+                        }
+                        .with_format("STX"),
+                    ),
+                );
+                return;
+            }
+        }
+        offset += size;
+    }
+    if offset < fuzzy_masks.len() {
+        debug!(
+            target: PARSE,
+            "{} bytes of the fuzzy mask record are unused",
+            fuzzy_masks.len() - offset
+        );
+    }
+}
+
+/// Read the track image at the start of the track data
+/// Returns None if the track has no image or the image runs past the
+/// end of the file.
+fn stx_track_image(flags: u16, data: &[u8]) -> Option<STXTrackImage<'_>> {
+    if (flags & 0x40) == 0 {
+        return None;
+    }
+    let (i, header) = stx_track_image_header_parser(flags)(data).ok()?;
+    let image = i.get(..usize::from(header.track_image_size));
+    if image.is_none() {
+        debug!(target: PARSE, "Track image is past the end of the file: {}", header);
+    }
+
+    Some(STXTrackImage {
+        first_sync_offset: ((flags & 0x80) != 0).then_some(header.first_sync_offset),
+        offset: data.len() - i.len(),
+        data: image?,
+    })
+}
+
+/// Get n tracks from the disk
+/// Returns a vector of the tracks
+pub fn stx_tracks_parser(n: usize) -> impl Fn(&[u8]) -> IResult<&[u8], Vec<STXTrack>> {
+    move |i| {
+        let (i, _) = limit_tracks(i, n)?;
+        let (i, _) = limit_allocation(i, n * std::mem::size_of::<STXTrack>())?;
+        count(stx_track_parser, n)(i)
+    }
+}
+
+/// The track image data on the disk, appears in each track,
+/// after the sector headers if they exist, or just after the track headers
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct STXTrackImageHeader {
+    /// The first sync offset
+    /// This field exists if the track flags bit 7
+    pub first_sync_offset: u16,
+    /// The track image size
+    /// This field exists if the track flags bit 6 is set
+    pub track_image_size: u16,
+}
+
+/// Display a single sector
+impl Display for STXTrackImageHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "track image header: ")?;
+        write!(
+            f,
+            "first_sync_offset: {}, track_image_size: {}",
+            self.first_sync_offset, self.track_image_size
+        )
+    }
+}
+
+/// Parse a STX track image header
+pub fn stx_track_image_header_parser(
+    flags: u16,
+) -> impl Fn(&[u8]) -> IResult<&[u8], STXTrackImageHeader> {
+    // Create and return a closure as the main result of this function
+    // i is not a simple value, even though it may appear to operate as one
+    // so it doesn't get copied by default
+    // This is why we need the move to capture ownership of the values it uses in the
+    // environment
+    move |i| {
+        // If flag bit 6 and 7 are set, get the first sync offset
+        let (i, first_sync_offset) =
+            cond(((flags & 0x40) != 0) && ((flags & 0x80) != 0), le_u16)(i)?;
+        // If flag bit 6 is set, get the track image size
+        let (i, track_image_size) = cond((flags & 0x40) != 0, le_u16)(i)?;
+
+        let stx_track_image_header = STXTrackImageHeader {
+            first_sync_offset: first_sync_offset.unwrap_or(0),
+            track_image_size: track_image_size.unwrap_or(0),
+        };
+
+        Ok((i, stx_track_image_header))
+    }
+}
+
+/// The actual track data
+pub struct STXTrackData<'a> {
+    /// The track image data
+    data: &'a [u8],
+}
+
+/// Display metadata for the track data
+impl Display for STXTrackData<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "size of contents: {}", self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::SanityCheck;
+
+    use super::{stx_track_header_parser, stx_track_parser};
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::{disk_image_data, DiskImage};
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::mfm::field_crc;
+    use crate::disk_format::parsed::collect_warnings;
+    use crate::disk_format::stx::disk::stx_disk_parser;
+    use crate::disk_format::stx::writer::{write_stx, SectorProtection};
+    use crate::disk_format::testgen;
+
+    /// Test parsing a STX track header
+    #[test]
+    fn stx_valid_track_header_parser_works() {
+        // image_rider::disk_format::stx] Track header: block_size: 11022, fuzzy_size: 0, sectors_count: 9
+        let stx_track_header: [u8; 16] = [
+            0x43, 0x2b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x61, 0x00, 0x74, 0x18,
+            0x00, 0x00,
+        ];
+
+        let stx_track_header_parser_result = stx_track_header_parser(&stx_track_header);
+
+        match stx_track_header_parser_result {
+            Ok((_, res)) => {
+                assert_eq!(res.block_size, 0x2b43);
+                assert_eq!(res.fuzzy_size, 0x00);
+                assert_eq!(res.sectors_count, 0x09);
+                assert_eq!(res.flags, 0x61);
+                assert_eq!(res.mfm_size, 0x1874);
+                assert_eq!(res.track_number, 0x00);
+                assert_eq!(res.record_type, 0x00);
+            }
+            Err(e) => panic!("Parsing failed on the STX disk header: {}", e),
+        }
+    }
+
+    /// Test parsing a STX track header with an unknown flags field
+    #[test]
+    fn stx_unknown_track_header_parser_works() {
+        // image_rider::disk_format::stx] Track header: block_size: 11022, fuzzy_size: 0, sectors_count: 9
+        let stx_track_header: [u8; 16] = [
+            0x43, 0x2b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x00, 0x62, 0x00, 0x74, 0x18,
+            0x00, 0x00,
+        ];
+
+        let stx_track_header_parser_result = stx_track_header_parser(&stx_track_header);
+
+        match stx_track_header_parser_result {
+            Ok((_, res)) => {
+                assert_eq!(res.block_size, 0x2b43);
+                assert_eq!(res.fuzzy_size, 0x00);
+                assert_eq!(res.sectors_count, 0x09);
+                assert_eq!(res.flags, 0x62);
+                assert_eq!(res.mfm_size, 0x1874);
+                assert_eq!(res.track_number, 0x00);
+                assert_eq!(res.record_type, 0x00);
+
+                // Should fail because of the flags
+                assert!(!res.check());
+            }
+            Err(e) => panic!("Parsing failed on the STX disk header: {}", e),
+        }
+    }
+
+    /// Test attaching fuzzy masks to the fuzzy sectors of a track and
+    /// reading the sectors through them
+    #[test]
+    fn stx_track_fuzzy_masks_work() {
+        let geometry = Geometry::atari_st(1, 1, 9);
+        let sectors = testgen::atari_st_sectors(1, 1, 9);
+        let tracks = split_tracks(&sectors, &geometry);
+        let mut mask = vec![0xFF; 512];
+        mask[..4].copy_from_slice(&[0x00, 0x0F, 0xF0, 0xFF]);
+        let mut protection = BTreeMap::new();
+        protection.insert(
+            SectorId::new(0, 0, 3),
+            SectorProtection {
+                fuzzy_mask: mask.clone(),
+                read_time: 0,
+            },
+        );
+        let stx = write_stx(&tracks, &protection);
+
+        let (rest, track) = stx_track_parser(&stx[16..]).unwrap();
+        assert!(rest.is_empty());
+        let headers = track.sector_headers.as_ref().unwrap();
+        assert_eq!(headers[2].fuzzy_mask, Some(mask));
+        assert!(headers.iter().filter(|h| h.fuzzy_mask.is_some()).count() == 1);
+
+        let data = &tracks[0].sectors[2].data;
+        let masked = track.masked_sector_data(2).unwrap();
+        assert_eq!(masked[..4], [0x00, data[1] & 0x0F, data[2] & 0xF0, data[3]]);
+        assert_eq!(masked[4..], data[4..]);
+        let fuzzed = track.fuzzed_sector_data(2, || 0xFF).unwrap();
+        assert_eq!(fuzzed[..4], [0xFF, data[1] | 0xF0, data[2] | 0x0F, data[3]]);
+        // Sectors without a mask are unchanged
+        assert_eq!(
+            track.fuzzed_sector_data(0, || 0xFF).unwrap(),
+            tracks[0].sectors[0].data
+        );
+        assert!(track.masked_sector_data(9).is_none());
+
+        // A record too short for the fuzzy sectors is a warning
+        let mut short = stx[16..].to_vec();
+        short[4..8].copy_from_slice(&0_u32.to_le_bytes());
+        let parsed = collect_warnings::<_, ()>(|| Ok(stx_track_parser(&short).unwrap().1)).unwrap();
+        assert!(parsed.value.sector_headers.unwrap()[2].fuzzy_mask.is_none());
+        assert_eq!(parsed.warnings.len(), 1);
+    }
+
+    /// Test decoding the sectors of a track imaged without sector
+    /// descriptors, starting from the first sync mark
+    #[test]
+    fn stx_track_image_sectors_work() {
+        let geometry = Geometry::atari_st(1, 1, 9);
+        let sectors = testgen::atari_st_sectors(1, 1, 9);
+        let tracks = split_tracks(&sectors, &geometry);
+        let stx = write_stx(&tracks, &BTreeMap::new());
+        let image = &stx[16 + 16 + 9 * 16 + 2..];
+
+        // A sector before the first sync mark isn't part of the track
+        let id = [0, 0, 99, 2];
+        let mut before = vec![0xA1, 0xA1, 0xA1, 0xFE];
+        before.extend_from_slice(&id);
+        before.extend_from_slice(&field_crc(0xFE, &id).to_be_bytes());
+        before.extend_from_slice(&[0xA1, 0xA1, 0xA1, 0xFB]);
+        before.extend_from_slice(&[0xE5; 512]);
+        before.extend_from_slice(&field_crc(0xFB, &[0xE5; 512]).to_be_bytes());
+        let sync = before.len() as u16;
+        before.extend_from_slice(image);
+        let image = before;
+
+        let mut record = ((16 + 4 + image.len()) as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&0_u32.to_le_bytes());
+        record.extend_from_slice(&0_u16.to_le_bytes());
+        record.extend_from_slice(&0xC1_u16.to_le_bytes());
+        record.extend_from_slice(&(image.len() as u16).to_le_bytes());
+        record.extend_from_slice(&[0, 0]);
+        record.extend_from_slice(&sync.to_le_bytes());
+        record.extend_from_slice(&(image.len() as u16).to_le_bytes());
+        record.extend_from_slice(&image);
+
+        let (rest, track) = stx_track_parser(&record).unwrap();
+        assert!(rest.is_empty());
+        let headers = track.sector_headers.as_ref().unwrap();
+        let ids: Vec<u8> = headers.iter().map(|header| header.id_sector).collect();
+        assert_eq!(ids, (1..=9).collect::<Vec<u8>>());
+        assert!(headers.iter().all(|header| header.check()));
+        let data: Vec<u8> = track.sector_data.as_ref().unwrap().concat();
+        assert_eq!(data, sectors);
+        assert_eq!(track.summary().sectors, 9);
+
+        // The sectors are in the disk's data
+        let mut file = stx[..16].to_vec();
+        file[10] = 1;
+        file.extend_from_slice(&record);
+        let (_, disk) = stx_disk_parser(&file).unwrap();
+        let disk_image = DiskImage::STX(Box::new(disk));
+        assert_eq!(disk_image_data(&disk_image).unwrap(), sectors);
+
+        // A track image without address marks is a warning
+        let mut blank = record.clone();
+        let image_start = 16 + 4;
+        blank[image_start..].fill(0x4E);
+        let parsed = collect_warnings::<_, ()>(|| Ok(stx_track_parser(&blank).unwrap().1)).unwrap();
+        assert!(parsed.value.sector_headers.is_none());
+        assert_eq!(parsed.warnings.len(), 1);
+    }
+}
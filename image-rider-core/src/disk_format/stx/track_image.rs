@@ -0,0 +1,209 @@
+//! Export the raw track images of an STX disk
+//!
+//! Protected tracks are often imaged with a Read Track command as well
+//! as sector by sector, and the track image keeps the gaps, sync marks
+//! and hidden data between sectors that the decoded sectors lose.  The
+//! export writes each track image to its own file, trackNN.S.img, and
+//! an index.json listing the first sync offset and size of each image
+//! and where each sector's data lies inside it.
+use std::fs;
+use std::path::Path;
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::stx::disk::STXDisk;
+use crate::disk_format::stx::sector::sector_size_as_bytes;
+use crate::error::Error;
+use crate::log_target::IO;
+
+/// The name of the index file written with the track images
+pub const TRACK_IMAGE_INDEX: &str = "index.json";
+
+/// A sector found on an imaged track
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TrackImageSector {
+    /// The track number from the address field
+    pub track: u8,
+    /// The head number from the address field
+    pub head: u8,
+    /// The sector number from the address field
+    pub sector: u8,
+    /// The size of the sector data in bytes
+    pub size: usize,
+    /// The position of the address field on the track, in bits
+    pub bit_position: u16,
+    /// The FDC status when the sector was read
+    pub fdc_status: u8,
+    /// The offset of the sector data in the track image file, None if
+    /// the data isn't stored inside the image
+    pub image_offset: Option<usize>,
+}
+
+/// An exported track image
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TrackImageEntry {
+    /// The track number
+    pub track: u8,
+    /// The side of the disk
+    pub side: u8,
+    /// The name of the track image file
+    pub file: String,
+    /// The offset of the first sync mark in the image, None if the
+    /// track doesn't record one
+    pub first_sync_offset: Option<u16>,
+    /// The size of the track image in bytes
+    pub size: usize,
+    /// The length of the track in bytes, from the track header
+    pub track_length: u16,
+    /// The sectors on the track
+    pub sectors: Vec<TrackImageSector>,
+}
+
+/// The index of the track images written by an export
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TrackImageIndex {
+    /// The exported tracks, in disk order
+    pub tracks: Vec<TrackImageEntry>,
+}
+
+/// Return the filename for a track image
+pub fn track_image_filename(track: u8, side: u8) -> String {
+    format!("track{:02}.{}.img", track, side)
+}
+
+/// Build the index of the track images on a disk
+/// Tracks without a track image aren't listed.
+pub fn track_image_index(disk: &STXDisk) -> TrackImageIndex {
+    let tracks = disk
+        .stx_tracks
+        .iter()
+        .filter_map(|track| {
+            let image = track.track_image.as_ref()?;
+            let number = track.header.track_number & 0x7F;
+            let side = track.header.track_number >> 7;
+            let sectors = track
+                .sector_headers
+                .iter()
+                .flatten()
+                .map(|header| {
+                    let size = usize::from(sector_size_as_bytes(header.id_size));
+                    TrackImageSector {
+                        track: header.id_track,
+                        head: header.id_head,
+                        sector: header.id_sector,
+                        size,
+                        bit_position: header.bit_position,
+                        fdc_status: u8::from(header.fdc_status),
+                        image_offset: (header.data_offset as usize)
+                            .checked_sub(image.offset)
+                            .filter(|offset| offset + size <= image.data.len()),
+                    }
+                })
+                .collect();
+            Some(TrackImageEntry {
+                track: number,
+                side,
+                file: track_image_filename(number, side),
+                first_sync_offset: image.first_sync_offset,
+                size: image.data.len(),
+                track_length: track.header.mfm_size,
+                sectors,
+            })
+        })
+        .collect();
+
+    TrackImageIndex { tracks }
+}
+
+/// Write the track images of a disk to a directory, one file per
+/// track and side, and an index of them to index.json
+/// The directory is created if it doesn't exist.  The token is checked
+/// before each track.
+/// Returns the index written.
+pub fn export_track_images(
+    disk: &STXDisk,
+    dir: &Path,
+    cancel: &CancellationToken,
+) -> std::result::Result<TrackImageIndex, Error> {
+    fs::create_dir_all(dir)?;
+
+    let index = track_image_index(disk);
+    let images = disk
+        .stx_tracks
+        .iter()
+        .filter_map(|track| track.track_image.as_ref());
+
+    cancel.start(index.tracks.len());
+    for (entry, image) in index.tracks.iter().zip(images) {
+        cancel.check()?;
+        let path = dir.join(&entry.file);
+        debug!(target: IO, "Writing track image {}", path.display());
+        fs::write(&path, image.data)?;
+        cancel.advance();
+    }
+    fs::write(
+        dir.join(TRACK_IMAGE_INDEX),
+        serde_json::to_string_pretty(&index)?,
+    )?;
+    info!(
+        target: IO,
+        "Wrote {} track images to {}",
+        index.tracks.len(),
+        dir.display()
+    );
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{export_track_images, TrackImageIndex, TRACK_IMAGE_INDEX};
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::stx::disk::stx_disk_parser;
+    use crate::disk_format::stx::writer::write_stx;
+    use crate::disk_format::testgen;
+
+    /// Test exporting the track images of a written STX image and
+    /// finding the sector data in them through the index
+    #[test]
+    fn export_track_images_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let sectors = testgen::atari_st_sectors(2, 2, 9);
+        let tracks = split_tracks(&sectors, &geometry);
+        let stx = write_stx(&tracks, &BTreeMap::new());
+        let (_, disk) = stx_disk_parser(&stx).unwrap();
+
+        let dir =
+            std::env::temp_dir().join(format!("image-rider-track-images-{}", std::process::id()));
+        let index = export_track_images(&disk, &dir, &CancellationToken::new()).unwrap();
+        assert_eq!(index.tracks.len(), 4);
+        let entry = &index.tracks[3];
+        assert_eq!((entry.track, entry.side), (1, 1));
+        assert_eq!(entry.file, "track01.1.img");
+        assert_eq!(entry.first_sync_offset, None);
+        assert_eq!(entry.sectors.len(), 9);
+
+        let image = std::fs::read(dir.join(&entry.file)).unwrap();
+        assert_eq!(image.len(), entry.size);
+        let sector = &entry.sectors[4];
+        let offset = sector.image_offset.unwrap();
+        assert_eq!(
+            &image[offset..offset + sector.size],
+            &tracks[3].sectors[4].data[..]
+        );
+
+        let json = std::fs::read_to_string(dir.join(TRACK_IMAGE_INDEX)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TrackImageIndex>(&json).unwrap(),
+            index
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Fast verification of STX images
+//!
+//! Checking a large collection only needs to know whether every sector
+//! read cleanly, not the parsed disk.  The verifier walks the track and
+//! sector headers in place, checks each address field CRC against the
+//! sector ID and the FDC status recorded when the sector was imaged,
+//! and sums the boot sector.  Nothing is allocated per track, and
+//! damaged headers are reported rather than stopping the walk with a
+//! panic like the full parser does.
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::limits::limit_tracks;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::stx::disk::stx_disk_header_parser;
+use crate::disk_format::stx::sector::{
+    boot_sector_checksum, calculate_crc16, sector_size_as_bytes,
+    stx_sector_header_unchecked_parser, BOOT_SECTOR_EXECUTABLE_SUM,
+};
+use crate::disk_format::stx::track::stx_track_header_parser;
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The size of the file, track and sector headers
+const HEADER_SIZE: usize = 16;
+
+/// The size of a sector on a track without sector headers
+const PLAIN_SECTOR_SIZE: usize = 512;
+
+/// The result of verifying an STX image
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct STXVerification {
+    /// The number of tracks
+    pub tracks: usize,
+    /// The number of sectors
+    pub sectors: usize,
+    /// The number of sectors whose address field CRC doesn't match
+    /// the sector ID
+    pub id_crc_errors: usize,
+    /// The number of sectors the FDC read with a CRC error
+    pub data_crc_errors: usize,
+    /// The sectors with either kind of CRC error, from their address
+    /// fields
+    pub bad_sectors: Vec<SectorId>,
+    /// The boot sector checksum, None if track 0 has no sector 1
+    pub boot_checksum: Option<u16>,
+}
+
+impl STXVerification {
+    /// Return true if every sector read without a CRC error
+    pub fn passed(&self) -> bool {
+        self.id_crc_errors == 0 && self.data_crc_errors == 0
+    }
+
+    /// Return true if the boot sector checksum marks it executable
+    pub fn is_bootable(&self) -> bool {
+        self.boot_checksum == Some(BOOT_SECTOR_EXECUTABLE_SUM)
+    }
+}
+
+/// Format the verification as a single line
+impl Display for STXVerification {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}: {} tracks, {} sectors, {} ID CRC errors, {} data CRC errors",
+            if self.passed() { "PASS" } else { "FAIL" },
+            self.tracks,
+            self.sectors,
+            self.id_crc_errors,
+            self.data_crc_errors
+        )?;
+        match self.boot_checksum {
+            Some(sum) if self.is_bootable() => {
+                write!(f, ", boot sector executable (0x{:04X})", sum)
+            }
+            Some(sum) => write!(f, ", boot sector not executable (0x{:04X})", sum),
+            None => write!(f, ", no boot sector"),
+        }
+    }
+}
+
+/// Build the error for a header the walk can't get past
+fn verify_error(message: String) -> Error {
+    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(message)))
+}
+
+/// Verify the sector CRCs and boot sector checksum of an STX image
+/// without parsing it into a disk
+/// Returns an error if the file or a track header is damaged so badly
+/// the walk can't continue.
+pub fn verify_stx(data: &[u8]) -> std::result::Result<STXVerification, Error> {
+    let (mut i, header) = stx_disk_header_parser(data)?;
+    if !header.check() {
+        return Err(verify_error(format!(
+            "Invalid STX track count: {}",
+            header.track_count
+        )));
+    }
+    limit_tracks(i, usize::from(header.track_count))?;
+
+    let mut verification = STXVerification::default();
+    for _ in 0..header.track_count {
+        let track_start = i;
+        let (rest, track_header) = stx_track_header_parser(i)?;
+        let next = track_start
+            .get(track_header.block_size as usize..)
+            .ok_or_else(|| {
+                verify_error(format!(
+                    "Track {} is past the end of the image",
+                    verification.tracks
+                ))
+            })?;
+        let first_track = track_header.track_number == 0;
+        verification.tracks += 1;
+
+        if track_header.flags & 0x01 == 0 {
+            // A plain dump of 512 byte sectors after the track header
+            verification.sectors += usize::from(track_header.sectors_count);
+            if first_track {
+                verification.boot_checksum =
+                    rest.get(..PLAIN_SECTOR_SIZE).and_then(boot_sector_checksum);
+            }
+            i = next;
+            continue;
+        }
+
+        let sectors_count = usize::from(track_header.sectors_count);
+        let data_start = HEADER_SIZE * (1 + sectors_count) + track_header.fuzzy_size as usize;
+        let mut headers = rest;
+        for _ in 0..sectors_count {
+            let (rest, sector_header) = stx_sector_header_unchecked_parser(headers)?;
+            headers = rest;
+            verification.sectors += 1;
+
+            let id = SectorId::new(
+                sector_header.id_track,
+                sector_header.id_head,
+                sector_header.id_sector,
+            );
+            let id_crc_error = calculate_crc16(&sector_header) != sector_header.id_crc;
+            let data_crc_error = sector_header.fdc_status.crc_error;
+            if id_crc_error {
+                verification.id_crc_errors += 1;
+            }
+            if data_crc_error {
+                verification.data_crc_errors += 1;
+            }
+            if id_crc_error || data_crc_error {
+                verification.bad_sectors.push(id);
+            }
+
+            if first_track && sector_header.id_sector == 1 && verification.boot_checksum.is_none() {
+                let start = data_start + sector_header.data_offset as usize;
+                let size = usize::from(sector_size_as_bytes(sector_header.id_size));
+                verification.boot_checksum = track_start
+                    .get(start..start + size)
+                    .and_then(boot_sector_checksum);
+            }
+        }
+        i = next;
+    }
+
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::verify_stx;
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::stx::writer::write_stx;
+    use crate::disk_format::testgen;
+
+    /// Test verifying an image with a bad address field CRC, a sector
+    /// read with a CRC error and an executable boot sector
+    #[test]
+    fn verify_stx_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let mut sectors = testgen::atari_st_sectors(2, 2, 9);
+        // Make the boot sector words sum to 0x1234
+        sectors[..512].fill(0);
+        sectors[510..512].copy_from_slice(&[0x12, 0x34]);
+        let mut tracks = split_tracks(&sectors, &geometry);
+
+        let stx = write_stx(&tracks, &BTreeMap::new());
+        let verification = verify_stx(&stx).unwrap();
+        assert!(verification.passed());
+        assert!(verification.is_bootable());
+        assert_eq!((verification.tracks, verification.sectors), (4, 36));
+        assert_eq!(
+            verification.to_string(),
+            "PASS: 4 tracks, 36 sectors, 0 ID CRC errors, 0 data CRC errors, boot sector executable (0x1234)"
+        );
+
+        tracks[3].sectors[4].crc_error = true;
+        let mut stx = write_stx(&tracks, &BTreeMap::new());
+        // The address field CRC of the first sector header
+        stx[32 + 12] ^= 0xFF;
+        let verification = verify_stx(&stx).unwrap();
+        assert!(!verification.passed());
+        assert_eq!(verification.id_crc_errors, 1);
+        assert_eq!(verification.data_crc_errors, 1);
+        assert_eq!(
+            verification.bad_sectors,
+            [SectorId::new(0, 0, 1), SectorId::new(1, 1, 5)]
+        );
+
+        assert!(verify_stx(&stx[..100]).is_err());
+        assert!(verify_stx(b"HELLO").is_err());
+    }
+}
@@ -0,0 +1,246 @@
+//!
+//! STX (Pasti) image writer
+//!
+//! Tracks are written the way the Pasti tool records a protected disk:
+//! a sector descriptor for every sector, the fuzzy masks, then a track
+//! image holding the bytes a WD1772 Read Track command returns.  The
+//! track image is built from the standard MFM track layout, and each
+//! sector descriptor points at the sector data inside it.
+//!
+//! ```ignore
+//! File Header (16 bytes)
+//! For each track:
+//!  Track Header (16 bytes), flags 0x61, or 0x21 for an empty track
+//!  Sector descriptor x sector count (16 bytes each)
+//!  Fuzzy masks for the sectors with fuzzy bits, in sector order
+//!  Track image size (2 bytes)
+//!  Track image
+//! ```
+//!
+//! Copy protection details that the logical sector model doesn't
+//! hold, the fuzzy masks and read times, are passed separately.
+//! Intra-sector timing records aren't written.
+use std::collections::BTreeMap;
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::LogicalTrack;
+use crate::disk_format::mfm::{encode_track, field_crc, pack_bits};
+use crate::disk_format::stx::sector::FdcStatus;
+
+/// The Pasti file format version written
+const STX_VERSION: u16 = 3;
+
+/// The tool used value for images written by the Pasti tool
+const STX_TOOL_ATARI: u16 = 0x01;
+
+/// The file revision for images with the new track format
+const STX_REVISION: u8 = 2;
+
+/// Track flags: the track has sector descriptors
+const TRACK_FLAG_SECTORS: u16 = 0x01;
+
+/// Track flags: set on every track written by the Pasti tool
+const TRACK_FLAG_PROTECTED: u16 = 0x20;
+
+/// Track flags: the track has a track image
+const TRACK_FLAG_IMAGE: u16 = 0x40;
+
+/// Copy protection details for a sector
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SectorProtection {
+    /// A mask with bits set for the bits of the sector that read
+    /// reliably, empty if the sector has no fuzzy bits
+    pub fuzzy_mask: Vec<u8>,
+    /// The time to read the sector in microseconds, zero for standard
+    /// timing
+    pub read_time: u16,
+}
+
+/// Find the next sync and address mark in a track image, returning
+/// the offset of the mark
+pub fn find_mark(image: &[u8], start: usize, marks: &[u8]) -> Option<usize> {
+    image
+        .get(start..)?
+        .windows(4)
+        .position(|w| w[..3] == [0xA1, 0xA1, 0xA1] && marks.contains(&w[3]))
+        .map(|position| start + position + 3)
+}
+
+/// Build the bytes a Read Track command would return for a track
+fn track_image(track: &LogicalTrack) -> Vec<u8> {
+    let data_bits: Vec<bool> = encode_track(track).into_iter().skip(1).step_by(2).collect();
+    pack_bits(&data_bits)
+}
+
+/// Write a single track record
+fn write_track(track: &LogicalTrack, protection: &BTreeMap<SectorId, SectorProtection>) -> Vec<u8> {
+    let track_number = (track.track & 0x7F) | (track.head << 7);
+
+    if track.sectors.is_empty() {
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&16_u32.to_le_bytes());
+        record.extend_from_slice(&0_u32.to_le_bytes());
+        record.extend_from_slice(&0_u16.to_le_bytes());
+        record.extend_from_slice(&(TRACK_FLAG_SECTORS | TRACK_FLAG_PROTECTED).to_le_bytes());
+        record.extend_from_slice(&0_u16.to_le_bytes());
+        record.extend_from_slice(&[track_number, 0]);
+        return record;
+    }
+
+    let image = track_image(track);
+    let mut descriptors = Vec::with_capacity(track.sectors.len() * 16);
+    let mut fuzzy = Vec::new();
+    let mut position = 0;
+
+    for sector in &track.sectors {
+        let sector_protection = protection.get(&sector.id).cloned().unwrap_or_default();
+        let id = [
+            sector.id.track.get(),
+            sector.id.head.get(),
+            sector.id.sector.get(),
+            sector.size_code(),
+        ];
+        // The track image is built from these sectors, so the marks
+        // are always found
+        let id_mark = find_mark(&image, position, &[0xFE]).unwrap_or(position);
+        let data_mark = find_mark(&image, id_mark + 7, &[0xFB, 0xF8]).unwrap_or(id_mark);
+        position = data_mark + 1 + sector.data.len() + 2;
+
+        let fdc_status = FdcStatus {
+            crc_error: sector.crc_error,
+            deleted: sector.deleted,
+            fuzzy: !sector_protection.fuzzy_mask.is_empty(),
+            ..FdcStatus::default()
+        };
+        fuzzy.extend_from_slice(&sector_protection.fuzzy_mask);
+
+        // Sector data offsets are from the start of the track data,
+        // which begins with the two byte track image size
+        let data_offset = (2 + data_mark + 1) as u32;
+        descriptors.extend_from_slice(&data_offset.to_le_bytes());
+        descriptors.extend_from_slice(&((id_mark * 8) as u16).to_le_bytes());
+        descriptors.extend_from_slice(&sector_protection.read_time.to_le_bytes());
+        descriptors.extend_from_slice(&id);
+        descriptors.extend_from_slice(&field_crc(0xFE, &id).to_be_bytes());
+        descriptors.extend_from_slice(&[u8::from(fdc_status), 0]);
+    }
+
+    let block_size = 16 + descriptors.len() + fuzzy.len() + 2 + image.len();
+    let mut record = Vec::with_capacity(block_size);
+    record.extend_from_slice(&(block_size as u32).to_le_bytes());
+    record.extend_from_slice(&(fuzzy.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(track.sectors.len() as u16).to_le_bytes());
+    record.extend_from_slice(
+        &(TRACK_FLAG_SECTORS | TRACK_FLAG_PROTECTED | TRACK_FLAG_IMAGE).to_le_bytes(),
+    );
+    record.extend_from_slice(&(image.len() as u16).to_le_bytes());
+    record.extend_from_slice(&[track_number, 0]);
+    record.extend(descriptors);
+    record.extend(fuzzy);
+    record.extend_from_slice(&(image.len() as u16).to_le_bytes());
+    record.extend(image);
+
+    record
+}
+
+/// Build a STX image from a set of tracks
+/// Protection details are looked up by the sector ID from each
+/// sector's address field.
+pub fn write_stx(
+    tracks: &[LogicalTrack],
+    protection: &BTreeMap<SectorId, SectorProtection>,
+) -> Vec<u8> {
+    let mut stx = b"RSY\0".to_vec();
+    stx.extend_from_slice(&STX_VERSION.to_le_bytes());
+    stx.extend_from_slice(&STX_TOOL_ATARI.to_le_bytes());
+    stx.extend_from_slice(&[0, 0]);
+    stx.push(tracks.len().min(usize::from(u8::MAX)) as u8);
+    stx.push(STX_REVISION);
+    stx.extend_from_slice(&[0, 0, 0, 0]);
+
+    for track in tracks.iter().take(usize::from(u8::MAX)) {
+        stx.extend(write_track(track, protection));
+    }
+
+    stx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{write_stx, SectorProtection};
+    use crate::disk_format::geometry::SectorId;
+    use crate::disk_format::image::{disk_image_tracks, DiskImage};
+    use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+    use crate::disk_format::stx::disk::stx_disk_parser;
+
+    /// Build a test track with nine 512 byte sectors
+    fn test_track(track: u8, head: u8) -> LogicalTrack {
+        let mut logical_track = LogicalTrack::new(track, head);
+        for sector in 1..=9 {
+            logical_track.sectors.push(LogicalSector::new(
+                SectorId::new(track, head, sector),
+                vec![track ^ sector; 512],
+            ));
+        }
+        logical_track
+    }
+
+    /// Test writing a STX image and parsing it again
+    #[test]
+    fn write_stx_works() {
+        let mut tracks = vec![test_track(0, 0), test_track(0, 1), LogicalTrack::new(1, 0)];
+        tracks[1].sectors[2].crc_error = true;
+        tracks[1].sectors[3].deleted = true;
+        let mut protection = BTreeMap::new();
+        protection.insert(
+            SectorId::new(0, 0, 5),
+            SectorProtection {
+                fuzzy_mask: Vec::new(),
+                read_time: 16384,
+            },
+        );
+
+        let stx = write_stx(&tracks, &protection);
+        let (rest, disk) = stx_disk_parser(&stx).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(disk.stx_disk_header.track_count, 3);
+        assert_eq!(disk.stx_tracks[1].header.track_number, 0x80);
+        assert_eq!(disk.stx_tracks[1].header.flags, 0x61);
+        assert_eq!(disk.stx_tracks[2].header.flags, 0x21);
+        let headers = disk.stx_tracks[0].sector_headers.as_ref().unwrap();
+        assert_eq!(headers[4].read_time, 16384);
+        assert_eq!(headers[0].bit_position, (60 + 15) * 8);
+
+        let decoded = disk_image_tracks(&DiskImage::STX(Box::new(disk))).unwrap();
+        assert_eq!(decoded[0], tracks[0]);
+        assert_eq!(decoded[1], tracks[1]);
+        assert!(decoded[2].sectors.is_empty());
+    }
+
+    /// Test that fuzzy masks are written after the sector descriptors
+    #[test]
+    fn write_stx_fuzzy_masks_work() {
+        let tracks = vec![test_track(0, 0)];
+        let mut protection = BTreeMap::new();
+        protection.insert(
+            SectorId::new(0, 0, 2),
+            SectorProtection {
+                fuzzy_mask: vec![0xFF; 512],
+                read_time: 0,
+            },
+        );
+
+        let stx = write_stx(&tracks, &protection);
+        // The fuzzy size is in the track header
+        assert_eq!(stx[20..24], 512_u32.to_le_bytes());
+        // The second sector descriptor has the fuzzy FDC status bit
+        let descriptor = &stx[32 + 16..32 + 32];
+        assert_eq!(descriptor[14], 0x80);
+        let fuzzy_start = 32 + 9 * 16;
+        assert_eq!(stx[fuzzy_start..fuzzy_start + 512], [0xFF; 512]);
+        let block_size = u32::from_le_bytes(stx[16..20].try_into().unwrap()) as usize;
+        assert_eq!(stx.len(), 16 + block_size);
+    }
+}
@@ -0,0 +1,362 @@
+//! Human readable summaries of disk images
+//!
+//! A summary is what someone looking at an unknown image wants first:
+//! what it is, its name, its geometry, how many files it holds and how
+//! much space is free, and anything that looks like copy protection.
+//! Display for DiskImage prints the summary.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::image::{disk_image_file_extents, DiskImage};
+use crate::disk_format::logical::LogicalTrack;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::stx::sector::FdcStatus;
+use crate::disk_format::usage::SectorUsage;
+
+/// The layout of the sectors on a disk
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GeometrySummary {
+    /// The number of tracks, counting each track number once
+    pub tracks: usize,
+    /// The number of sides
+    pub sides: usize,
+    /// The number of sectors
+    pub sectors: usize,
+    /// The sector sizes used on the disk, in bytes
+    pub sector_sizes: BTreeSet<usize>,
+}
+
+impl GeometrySummary {
+    /// Summarize the geometry of a set of tracks
+    pub fn from_tracks(tracks: &[LogicalTrack]) -> GeometrySummary {
+        let track_numbers: BTreeSet<u8> = tracks.iter().map(|track| track.track).collect();
+        let heads: BTreeSet<u8> = tracks.iter().map(|track| track.head).collect();
+        GeometrySummary {
+            tracks: track_numbers.len(),
+            sides: heads.len(),
+            sectors: tracks.iter().map(|track| track.sectors.len()).sum(),
+            sector_sizes: tracks
+                .iter()
+                .flat_map(|track| track.sectors.iter().map(|sector| sector.data.len()))
+                .collect(),
+        }
+    }
+}
+
+/// Add an s to a word for counts other than one
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+impl Display for GeometrySummary {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{} track{}, {} side{}, {} sector{}",
+            self.tracks,
+            plural(self.tracks),
+            self.sides,
+            plural(self.sides),
+            self.sectors,
+            plural(self.sectors)
+        )?;
+        let sizes: Vec<String> = self.sector_sizes.iter().map(|s| s.to_string()).collect();
+        if !sizes.is_empty() {
+            write!(f, " of {} bytes", sizes.join(" and "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A summary of a disk image
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Summary {
+    /// The type of image
+    pub format: String,
+    /// The volume name or number, if the filesystem has one
+    pub label: Option<String>,
+    /// The layout of the sectors, None if the image doesn't support
+    /// track access
+    pub geometry: Option<GeometrySummary>,
+    /// The number of files, None if the filesystem isn't parsed
+    pub files: Option<usize>,
+    /// The number of free sectors and their size in bytes, None if the
+    /// filesystem isn't parsed
+    pub free: Option<(usize, usize)>,
+    /// Signs of copy protection or damage, e.g. fuzzy sectors or data
+    /// on tracks the filesystem doesn't use
+    pub protection: Vec<String>,
+    /// True if the image passed the sanity checks
+    pub sane: bool,
+}
+
+impl Summary {
+    /// Summarize a disk image
+    pub fn new(image: &DiskImage) -> Summary {
+        let tracks = image.tracks();
+        let usage = image.usage();
+        let sizes: BTreeMap<SectorId, usize> = tracks
+            .iter()
+            .flatten()
+            .flat_map(|track| {
+                track.sectors.iter().map(|sector| {
+                    (
+                        SectorId::new(track.track, track.head, sector.id.sector),
+                        sector.data.len(),
+                    )
+                })
+            })
+            .collect();
+
+        let free = usage.as_ref().map(|usage| {
+            let sectors = usage.sectors_with(&SectorUsage::Free);
+            let bytes = sectors.iter().filter_map(|id| sizes.get(id)).sum();
+            (sectors.len(), bytes)
+        });
+
+        Summary {
+            format: image.format_name(),
+            label: image.label(),
+            geometry: tracks.as_deref().map(GeometrySummary::from_tracks),
+            files: usage.as_ref().map(|_| disk_image_file_extents(image).len()),
+            free,
+            protection: protection(image, tracks.as_deref().unwrap_or_default()),
+            sane: image.check(),
+        }
+    }
+}
+
+/// List the signs of copy protection or damage on a disk
+fn protection(image: &DiskImage, tracks: &[LogicalTrack]) -> Vec<String> {
+    let mut found = Vec::new();
+    let count = |wanted: fn(&crate::disk_format::logical::LogicalSector) -> bool| {
+        tracks
+            .iter()
+            .flat_map(|track| track.sectors.iter())
+            .filter(|sector| wanted(sector))
+            .count()
+    };
+
+    // Converted STX sectors are marked as CRC errors for any read
+    // error, so STX disks count the errors from the FDC status
+    let stx_status = |wanted: fn(&FdcStatus) -> bool| match image {
+        DiskImage::STX(stx_disk) => stx_disk
+            .stx_tracks
+            .iter()
+            .flat_map(|track| track.sector_headers.iter().flatten())
+            .filter(|header| wanted(&header.fdc_status))
+            .count(),
+        _ => 0,
+    };
+
+    let crc_errors = match image {
+        DiskImage::STX(_) => stx_status(|status| status.crc_error),
+        _ => count(|sector| sector.crc_error),
+    };
+    if crc_errors > 0 {
+        found.push(format!(
+            "{} sector{} with CRC errors",
+            crc_errors,
+            plural(crc_errors)
+        ));
+    }
+    let deleted = count(|sector| sector.deleted);
+    if deleted > 0 {
+        found.push(format!(
+            "{} sector{} with deleted data marks",
+            deleted,
+            plural(deleted)
+        ));
+    }
+
+    match image {
+        DiskImage::STX(stx_disk) => {
+            let fuzzy = stx_disk
+                .stx_tracks
+                .iter()
+                .filter(|track| track.header.fuzzy_size > 0)
+                .count();
+            if fuzzy > 0 {
+                found.push(format!(
+                    "{} track{} with fuzzy sectors",
+                    fuzzy,
+                    plural(fuzzy)
+                ));
+            }
+            let not_found = stx_status(|status| status.record_not_found);
+            if not_found > 0 {
+                found.push(format!(
+                    "{} sector{} with record not found",
+                    not_found,
+                    plural(not_found)
+                ));
+            }
+            let lost_data = stx_status(|status| status.lost_data);
+            if lost_data > 0 {
+                found.push(format!(
+                    "{} sector{} with lost data",
+                    lost_data,
+                    plural(lost_data)
+                ));
+            }
+        }
+        DiskImage::Dsk(dsk_disk) => {
+            let weak = dsk_disk.weak_sectors().len();
+            if weak > 0 {
+                found.push(format!("{} weak sector{}", weak, plural(weak)));
+            }
+        }
+        DiskImage::G64(g64_disk) => {
+            let half = g64_disk.half_tracks().len();
+            if half > 0 {
+                found.push(format!("{} half track{}", half, plural(half)));
+            }
+            let speed = g64_disk.nonstandard_speed_tracks().len();
+            if speed > 0 {
+                found.push(format!(
+                    "{} track{} with nonstandard speed zones",
+                    speed,
+                    plural(speed)
+                ));
+            }
+        }
+        DiskImage::Apple(_) | DiskImage::Woz(_) => {
+            let failed = image
+                .nibble_disk()
+                .map_or(0, |nibble_disk| nibble_disk.failed_sectors.len());
+            if failed > 0 {
+                found.push(format!(
+                    "{} sector{} that couldn't be decoded",
+                    failed,
+                    plural(failed)
+                ));
+            }
+        }
+        _ => (),
+    }
+
+    let mut extra_tracks: Vec<u8> = image
+        .hidden_regions()
+        .iter()
+        .filter(|region| region.usage == SectorUsage::Unmanaged)
+        .map(|region| region.id.track.get())
+        .collect();
+    extra_tracks.dedup();
+    if !extra_tracks.is_empty() {
+        let tracks: Vec<String> = extra_tracks.iter().map(|t| t.to_string()).collect();
+        found.push(format!(
+            "data on track{} {} outside the filesystem",
+            plural(tracks.len()),
+            tracks.join(", ")
+        ));
+    }
+
+    found
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        writeln!(f, "{}", self.format)?;
+        if let Some(label) = &self.label {
+            writeln!(f, "  Label: {}", label)?;
+        }
+        if let Some(geometry) = &self.geometry {
+            writeln!(f, "  Geometry: {}", geometry)?;
+        }
+        if let Some(files) = self.files {
+            writeln!(f, "  Files: {}", files)?;
+        }
+        if let Some((sectors, bytes)) = self.free {
+            writeln!(
+                f,
+                "  Free: {} sector{}, {} bytes",
+                sectors,
+                plural(sectors),
+                bytes
+            )?;
+        }
+        if self.protection.is_empty() {
+            writeln!(f, "  Protection: none found")?;
+        } else {
+            writeln!(f, "  Protection: {}", self.protection.join(", "))?;
+        }
+        write!(
+            f,
+            "  Sanity checks: {}",
+            if self.sane { "passed" } else { "failed" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::Options;
+
+    use super::Summary;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::image::DiskImageParser;
+    use crate::disk_format::testgen;
+
+    /// Test summarizing generated D64 and STX images
+    #[test]
+    fn summary_works() {
+        let data = testgen::d64("GAMES", &[("ONE", &[1; 300]), ("TWO", &[2; 10])]).unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "games.d64")
+            .unwrap();
+        let summary = Summary::new(&image);
+        assert_eq!(summary.files, Some(2));
+        assert_eq!(summary.free, Some((678, 678 * 256)));
+        assert_eq!(
+            image.to_string(),
+            "D64 Disk\n\
+             \x20 Label: GAMES,00\n\
+             \x20 Geometry: 35 tracks, 1 side, 683 sectors of 256 bytes\n\
+             \x20 Files: 2\n\
+             \x20 Free: 678 sectors, 173568 bytes\n\
+             \x20 Protection: none found\n\
+             \x20 Sanity checks: passed"
+        );
+
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let stx = testgen::stx(&testgen::atari_st_sectors(2, 2, 9), &geometry);
+        let image = stx
+            .parse_disk_image(&Options::default(), "test.stx")
+            .unwrap();
+        let summary = Summary::new(&image);
+        assert_eq!(summary.format, "STX Disk");
+        assert_eq!(
+            summary.geometry.unwrap().to_string(),
+            "2 tracks, 2 sides, 36 sectors of 512 bytes"
+        );
+        assert_eq!(summary.files, None);
+        assert!(summary.protection.is_empty());
+
+        // The FDC status of the first two sectors: record not found,
+        // and a CRC error with lost data
+        let mut protected = stx.clone();
+        protected[16 + 16 + 14] = 0x10;
+        protected[16 + 16 + 16 + 14] = 0x0C;
+        let image = protected
+            .parse_disk_image(&Options::default(), "test.stx")
+            .unwrap();
+        assert_eq!(
+            Summary::new(&image).protection,
+            [
+                "1 sector with CRC errors",
+                "1 sector with record not found",
+                "1 sector with lost data"
+            ]
+        );
+        // Both sectors are unreadable when the disk is converted
+        let tracks = image.tracks().unwrap();
+        assert!(tracks[0].sectors[0].crc_error);
+        assert!(tracks[0].sectors[1].crc_error);
+        assert!(!tracks[0].sectors[2].crc_error);
+    }
+}
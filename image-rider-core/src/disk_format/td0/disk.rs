@@ -0,0 +1,964 @@
+//! TD0 disk images, tracks and sectors
+//!
+//! The image header is 12 bytes:
+//!
+//! ```ignore
+//! 00: "TD", or "td" for advanced compression
+//! 02: the volume sequence, zero for the first image of a set
+//! 03: a signature shared by the images of a set
+//! 04: the Teledisk version, e.g. 21 for 2.1
+//! 05: the data rate, 0 for 250 kbit/s, 1 for 300 and 2 for 500, with
+//!     bit 7 set for FM
+//! 06: the drive type
+//! 07: the stepping, with bit 7 set if there's a comment
+//! 08: nonzero if only the sectors allocated by DOS were copied
+//! 09: the number of sides
+//! 0A: the CRC of the first 10 bytes
+//! ```
+//!
+//! Each track starts with its sector count, cylinder, head and the
+//! low byte of the CRC of those three bytes.  Each sector has a six
+//! byte header, the cylinder, head, sector number, size code, flags
+//! and the low byte of the CRC of the sector data, followed by a data
+//! block if the sector has data.  A data block is its length, an
+//! encoding and the encoded data:
+//!
+//! ```ignore
+//! 0: the data as it is
+//! 1: a count and a two byte pattern repeated that many times
+//! 2: blocks of a literal length and data, or a pattern length and a
+//!    count for a repeated pattern
+//! ```
+//!
+//! The CRCs are CRC-16 with the polynomial 0xA097, starting at zero.
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error};
+use nom::bytes::complete::take;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::limits::{limit_sectors_per_track, limit_tracks, with_limits};
+use crate::disk_format::logical::{
+    flatten_tracks, infer_geometry, LogicalSector, LogicalTrack, RawExporter,
+};
+use crate::disk_format::parsed::{warning, Warning};
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::disk_format::source_map::{slice_offset, RegionKind, SourceMap, SourceMapper};
+use crate::disk_format::stx::st::{flat_disk_parser, STDisk};
+use crate::disk_format::td0::lzhuf::lzhuf_decompress;
+use crate::error::{Error, ErrorKind, Location};
+use crate::log_target::{IO, PARSE};
+use crate::options::Options;
+
+/// A Teledisk image
+pub const TD0_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "TD0",
+    description: "Teledisk disk image, with or without advanced compression",
+    extensions: &["td0"],
+    sizes: &[SizeRange::at_least(TD0_HEADER_SIZE)],
+    references: &[Reference {
+        title: "SAMdisk",
+        url: Some("https://github.com/simonowen/samdisk"),
+    }],
+    capabilities: Capabilities {
+        files: true,
+        tracks: true,
+        track_export: true,
+        checksums: true,
+        source_map: true,
+        ..Capabilities::NONE
+    },
+};
+
+/// The signature of an image without compression
+pub const TD0_MAGIC: &[u8] = b"TD";
+
+/// The signature of an image with advanced compression
+pub const TD0_ADVANCED_MAGIC: &[u8] = b"td";
+
+/// The size of the image header
+pub const TD0_HEADER_SIZE: usize = 12;
+
+/// The size of the comment header
+const COMMENT_HEADER_SIZE: usize = 10;
+
+/// The sector count of the record after the last track
+const END_OF_TRACKS: u8 = 0xFF;
+
+/// The largest sector size code with data, 8192 byte sectors
+const MAX_SIZE_CODE: u8 = 6;
+
+/// The polynomial of the Teledisk CRC
+const TD0_CRC_POLYNOMIAL: u16 = 0xA097;
+
+/// The sector ID was found more than once on the track
+pub const SECTOR_DUPLICATE: u8 = 0x01;
+
+/// The sector was read with a CRC error
+pub const SECTOR_CRC_ERROR: u8 = 0x02;
+
+/// The sector has a deleted data mark
+pub const SECTOR_DELETED: u8 = 0x04;
+
+/// The sector wasn't allocated by DOS, so its data wasn't copied
+pub const SECTOR_SKIPPED: u8 = 0x10;
+
+/// The sector has an ID but no data
+pub const SECTOR_NO_DATA: u8 = 0x20;
+
+/// Return the Teledisk CRC of some data
+pub fn td0_crc(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+            if crc & 0x8000 == 0x8000 {
+                (crc << 1) ^ TD0_CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Return true if the data starts with a TD0 signature and a known
+/// Teledisk version
+/// The signature is only two bytes, so the version and the number of
+/// sides have to be plausible too.
+pub fn is_td0(data: &[u8]) -> bool {
+    td0_header_parser(data).is_ok_and(|(_, header)| {
+        (10..=21).contains(&header.version) && (1..=2).contains(&header.sides)
+    })
+}
+
+/// The TD0 image header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Td0Header {
+    /// True if everything after the header is compressed
+    pub advanced_compression: bool,
+    /// The position of the image in a set, zero for the first
+    pub volume_sequence: u8,
+    /// A signature shared by the images of a set
+    pub check_signature: u8,
+    /// The Teledisk version times ten
+    pub version: u8,
+    /// The data rate and density
+    pub data_rate: u8,
+    /// The type of drive the disk was read in
+    pub drive_type: u8,
+    /// The stepping, and whether there's a comment
+    pub stepping: u8,
+    /// True if only the sectors allocated by DOS were copied
+    pub dos_allocation: bool,
+    /// The number of sides
+    pub sides: u8,
+    /// The CRC of the rest of the header
+    pub crc: u16,
+}
+
+impl Td0Header {
+    /// True if a comment follows the header
+    pub fn has_comment(&self) -> bool {
+        self.stepping & 0x80 == 0x80
+    }
+
+    /// The data rate in kbit/s, zero if it isn't known
+    pub fn bit_rate(&self) -> u16 {
+        match self.data_rate & 0x03 {
+            0 => 250,
+            1 => 300,
+            2 => 500,
+            _ => 0,
+        }
+    }
+
+    /// True if the disk is single density
+    pub fn is_fm(&self) -> bool {
+        self.data_rate & 0x80 == 0x80
+    }
+}
+
+impl Display for Td0Header {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Teledisk {}.{}, sides: {}, {} kbit/s {}",
+            self.version / 10,
+            self.version % 10,
+            self.sides,
+            self.bit_rate(),
+            if self.is_fm() { "FM" } else { "MFM" }
+        )?;
+        if self.advanced_compression {
+            write!(f, ", advanced compression")?;
+        }
+        if self.dos_allocation {
+            write!(f, ", DOS allocated sectors only")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for Td0Header {
+    fn check(&self) -> bool {
+        if !(1..=2).contains(&self.sides) {
+            debug!(target: PARSE, "TD0 image has {} sides", self.sides);
+            return false;
+        }
+        if !(10..=21).contains(&self.version) {
+            debug!(target: PARSE, "Unknown Teledisk version {}", self.version);
+            return false;
+        }
+        // Teledisk 1.x used an older LZW compression
+        if self.advanced_compression && self.version < 20 {
+            debug!(
+                target: PARSE,
+                "Unsupported Teledisk {}.{} compression",
+                self.version / 10,
+                self.version % 10
+            );
+            return false;
+        }
+        if self.volume_sequence != 0 {
+            debug!(
+                target: PARSE,
+                "TD0 image is volume {} of a set, only the first volume is read",
+                self.volume_sequence
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// The comment and the time the image was made
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Td0Comment {
+    /// The CRC of the rest of the comment header and the comment
+    pub crc: u16,
+    /// The length of the comment as stored
+    pub length: usize,
+    /// The year, month, day, hour, minute and second the image was
+    /// made, the year counted from 1900 and the month from zero
+    pub timestamp: [u8; 6],
+    /// The comment, with lines separated by newlines
+    pub text: String,
+}
+
+impl Display for Td0Comment {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let [year, month, day, hour, minute, second] = self.timestamp;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            1900 + u16::from(year),
+            month + 1,
+            day,
+            hour,
+            minute,
+            second
+        )?;
+        if !self.text.is_empty() {
+            write!(f, ": {}", self.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// A sector record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Td0Sector {
+    /// The offset of the sector header after the image header, in the
+    /// decompressed data if the image is compressed
+    pub offset: usize,
+    /// The cylinder in the sector ID
+    pub cylinder: u8,
+    /// The head in the sector ID
+    pub head: u8,
+    /// The sector number in the sector ID
+    pub sector: u8,
+    /// The size code, the sector is 128 << size_code bytes
+    pub size_code: u8,
+    /// The sector flags
+    pub flags: u8,
+    /// The low byte of the CRC of the sector data
+    pub crc: u8,
+    /// How the data was encoded, None if the sector has no data
+    pub encoding: Option<u8>,
+    /// The decoded sector data
+    pub data: Vec<u8>,
+}
+
+impl Td0Sector {
+    /// True if the sector data was stored
+    pub fn has_data(&self) -> bool {
+        self.flags & (SECTOR_SKIPPED | SECTOR_NO_DATA) == 0 && self.size_code <= MAX_SIZE_CODE
+    }
+
+    /// True if the stored data matches the sector's CRC
+    pub fn crc_valid(&self) -> bool {
+        !self.has_data() || td0_crc(&self.data) as u8 == self.crc
+    }
+}
+
+/// A track record and its sectors
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Td0Track {
+    /// The offset of the track header after the image header, in the
+    /// decompressed data if the image is compressed
+    pub offset: usize,
+    /// The physical cylinder
+    pub cylinder: u8,
+    /// The physical head
+    pub head: u8,
+    /// True if the track is single density
+    pub fm: bool,
+    /// The low byte of the CRC of the first three header bytes
+    pub crc: u8,
+    /// The CRC the header should have
+    pub computed_crc: u8,
+    /// The sectors, in the order they were read
+    pub sectors: Vec<Td0Sector>,
+}
+
+impl Td0Track {
+    /// Return the sectors with data, leaving out duplicate IDs
+    pub fn logical_track(&self) -> LogicalTrack {
+        let mut track = LogicalTrack::new(self.cylinder, self.head);
+        for sector in self
+            .sectors
+            .iter()
+            .filter(|sector| sector.has_data() && sector.flags & SECTOR_DUPLICATE == 0)
+        {
+            let mut logical_sector = LogicalSector::new(
+                SectorId::new(sector.cylinder, sector.head, sector.sector),
+                sector.data.clone(),
+            );
+            logical_sector.crc_error = sector.flags & SECTOR_CRC_ERROR == SECTOR_CRC_ERROR;
+            logical_sector.deleted = sector.flags & SECTOR_DELETED == SECTOR_DELETED;
+            track.sectors.push(logical_sector);
+        }
+        track
+    }
+}
+
+/// Format a track as a line of a track table
+impl Display for Td0Track {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "track {} side {}, sectors: {}",
+            self.cylinder,
+            self.head,
+            self.sectors.len()
+        )?;
+        if self.fm {
+            write!(f, ", FM")?;
+        }
+        let errors = self
+            .sectors
+            .iter()
+            .filter(|sector| sector.flags & SECTOR_CRC_ERROR == SECTOR_CRC_ERROR)
+            .count();
+        if errors > 0 {
+            write!(f, ", CRC errors: {}", errors)?;
+        }
+        let no_data = self
+            .sectors
+            .iter()
+            .filter(|sector| !sector.has_data())
+            .count();
+        if no_data > 0 {
+            write!(f, ", sectors without data: {}", no_data)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Teledisk image, with the sectors as a flat image
+pub struct Td0Disk<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The image header
+    pub header: Td0Header,
+    /// The comment, if there is one
+    pub comment: Option<Td0Comment>,
+    /// The tracks, in image order
+    pub tracks: Vec<Td0Track>,
+    /// The sectors as a flat image, None if there are no sectors
+    pub disk: Option<STDisk>,
+}
+
+impl Td0Disk<'_> {
+    /// Return the geometry of the sectors, None if there are no
+    /// sectors
+    pub fn geometry(&self) -> Option<Geometry> {
+        self.disk.as_ref().map(|disk| disk.geometry.clone())
+    }
+
+    /// Return the sectors that were read with CRC errors
+    pub fn bad_sectors(&self) -> Vec<SectorId> {
+        self.logical_tracks()
+            .iter()
+            .flat_map(|track| track.sectors.iter())
+            .filter(|sector| sector.crc_error)
+            .map(|sector| sector.id)
+            .collect()
+    }
+
+    /// A table of the tracks, one per line
+    pub fn track_table(&self) -> String {
+        self.tracks
+            .iter()
+            .map(|track| track.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+impl Display for Td0Disk<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.header)?;
+        if let Some(comment) = &self.comment {
+            write!(f, "\n{}", comment)?;
+        }
+        match &self.disk {
+            Some(disk) => write!(f, "\n{}, bad sectors: {}", disk, self.bad_sectors().len()),
+            None => write!(f, "\nNo sectors"),
+        }
+    }
+}
+
+impl SanityCheck for Td0Disk<'_> {
+    fn check(&self) -> bool {
+        self.header.check() && self.disk.as_ref().is_some_and(STDisk::check)
+    }
+}
+
+impl DiskImageSaver for Td0Disk<'_> {
+    /// Save the sectors as a flat image, or a file from the FAT
+    /// filesystem if one is selected
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        match &self.disk {
+            Some(disk) => disk.save_to_writer(config, selected_filename, writer),
+            None => {
+                error!(target: IO, "The TD0 image has no sectors");
+                Err(Error::new(ErrorKind::Message(String::from(
+                    "The TD0 image has no sectors",
+                ))))
+            }
+        }
+    }
+
+    /// The files in the FAT filesystem on the sectors
+    fn disk_files(&self) -> Vec<DiskFile> {
+        self.disk
+            .as_ref()
+            .map(DiskImageSaver::disk_files)
+            .unwrap_or_default()
+    }
+}
+
+impl RawExporter for Td0Disk<'_> {
+    fn logical_tracks(&self) -> Vec<LogicalTrack> {
+        self.tracks.iter().map(Td0Track::logical_track).collect()
+    }
+
+    fn raw_geometry(&self) -> Option<Geometry> {
+        self.geometry()
+    }
+}
+
+/// The header, and the comment and the records of each track if the
+/// image isn't compressed
+impl SourceMapper for Td0Disk<'_> {
+    fn source_map(&self, data: &[u8]) -> SourceMap {
+        let mut map = SourceMap::new();
+        let Some(start) = slice_offset(data, self.data) else {
+            return map;
+        };
+        map.add(RegionKind::Header, "TD0 header", start, TD0_HEADER_SIZE);
+        let start = start + TD0_HEADER_SIZE;
+        if self.header.advanced_compression {
+            map.add(
+                RegionKind::Data,
+                "compressed data",
+                start,
+                self.data.len() - TD0_HEADER_SIZE,
+            );
+            return map;
+        }
+
+        if let Some(comment) = &self.comment {
+            map.add(
+                RegionKind::Header,
+                "comment",
+                start,
+                COMMENT_HEADER_SIZE + comment.length,
+            );
+        }
+        let ends = self
+            .tracks
+            .iter()
+            .skip(1)
+            .map(|track| track.offset)
+            .chain(std::iter::once(self.data.len() - TD0_HEADER_SIZE));
+        for (track, end) in self.tracks.iter().zip(ends) {
+            map.add(
+                RegionKind::Data,
+                &format!("track {} side {}", track.cylinder, track.head),
+                start + track.offset,
+                end - track.offset,
+            );
+        }
+        map
+    }
+}
+
+/// Parse the image header
+pub fn td0_header_parser(i: &[u8]) -> IResult<&[u8], Td0Header> {
+    let (i, magic) = take(2_usize)(i)?;
+    let advanced_compression = match magic {
+        TD0_MAGIC => false,
+        TD0_ADVANCED_MAGIC => true,
+        _ => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                magic,
+                nom::error::ErrorKind::Tag,
+            )))
+        }
+    };
+    let (i, volume_sequence) = le_u8(i)?;
+    let (i, check_signature) = le_u8(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, data_rate) = le_u8(i)?;
+    let (i, drive_type) = le_u8(i)?;
+    let (i, stepping) = le_u8(i)?;
+    let (i, dos_allocation) = le_u8(i)?;
+    let (i, sides) = le_u8(i)?;
+    let (i, crc) = le_u16(i)?;
+
+    Ok((
+        i,
+        Td0Header {
+            advanced_compression,
+            volume_sequence,
+            check_signature,
+            version,
+            data_rate,
+            drive_type,
+            stepping,
+            dos_allocation: dos_allocation != 0,
+            sides,
+            crc,
+        },
+    ))
+}
+
+/// Parse the comment header and the comment
+fn td0_comment_parser(i: &[u8]) -> IResult<&[u8], Td0Comment> {
+    let (i, crc) = le_u16(i)?;
+    let (i, length) = le_u16(i)?;
+    let (i, timestamp) = take(6_usize)(i)?;
+    let (i, text) = take(length)(i)?;
+    let lines: Vec<String> = text
+        .split(|b| *b == 0)
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect();
+
+    Ok((
+        i,
+        Td0Comment {
+            crc,
+            length: usize::from(length),
+            timestamp: timestamp.try_into().unwrap(),
+            text: lines.join("\n"),
+        },
+    ))
+}
+
+/// Decode the data of a sector data block
+/// Returns None if the encoding isn't known or the data is cut short.
+pub fn td0_decode_sector_data(encoding: u8, data: &[u8]) -> Option<Vec<u8>> {
+    match encoding {
+        0 => Some(data.to_vec()),
+        1 => {
+            let count = usize::from(u16::from_le_bytes([*data.first()?, *data.get(1)?]));
+            Some(data.get(2..4)?.repeat(count))
+        }
+        2 => {
+            let mut decoded = Vec::new();
+            let mut data = data;
+            while let [kind, length, rest @ ..] = data {
+                if *kind == 0 {
+                    let length = usize::from(*length);
+                    decoded.extend_from_slice(rest.get(..length)?);
+                    data = &rest[length..];
+                } else {
+                    let pattern = rest.get(..usize::from(*kind) * 2)?;
+                    decoded.extend_from_slice(&pattern.repeat(usize::from(*length)));
+                    data = &rest[pattern.len()..];
+                }
+            }
+            data.is_empty().then_some(decoded)
+        }
+        _ => None,
+    }
+}
+
+/// Parse a sector header and its data block
+/// offset is the offset of the sector header after the image header.
+fn td0_sector_parser(offset: usize) -> impl Fn(&[u8]) -> IResult<&[u8], Td0Sector> {
+    move |i| {
+        let (i, cylinder) = le_u8(i)?;
+        let (i, head) = le_u8(i)?;
+        let (i, sector) = le_u8(i)?;
+        let (i, size_code) = le_u8(i)?;
+        let (i, flags) = le_u8(i)?;
+        let (i, crc) = le_u8(i)?;
+        let mut sector = Td0Sector {
+            offset,
+            cylinder,
+            head,
+            sector,
+            size_code,
+            flags,
+            crc,
+            encoding: None,
+            data: Vec::new(),
+        };
+        if !sector.has_data() {
+            return Ok((i, sector));
+        }
+
+        let (i, length) = le_u16(i)?;
+        let (rest, block) = take(length)(i)?;
+        let size = 128 << size_code;
+        let data = block
+            .split_first()
+            .and_then(|(encoding, data)| {
+                sector.encoding = Some(*encoding);
+                td0_decode_sector_data(*encoding, data)
+            })
+            .filter(|data| data.len() == size)
+            .ok_or_else(|| {
+                error!(
+                    target: PARSE,
+                    "TD0 sector {} on track {} side {} doesn't decode to {} bytes",
+                    sector.sector,
+                    sector.cylinder,
+                    sector.head,
+                    size
+                );
+                nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify))
+            })?;
+        sector.data = data;
+
+        Ok((rest, sector))
+    }
+}
+
+/// Parse a track header and its sectors
+/// Returns None at the end of the tracks.  base is the offset of the
+/// data being parsed after the image header.
+fn td0_track_parser(base: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], Option<Td0Track>> + '_ {
+    move |i| {
+        let offset = base.len() - i.len();
+        let (i, sector_count) = le_u8(i)?;
+        if sector_count == END_OF_TRACKS {
+            return Ok((i, None));
+        }
+        limit_sectors_per_track(i, usize::from(sector_count))?;
+        let (i, cylinder) = le_u8(i)?;
+        let (i, head) = le_u8(i)?;
+        let (mut i, crc) = le_u8(i)?;
+
+        let mut sectors = Vec::with_capacity(usize::from(sector_count));
+        for _ in 0..sector_count {
+            let (rest, sector) = td0_sector_parser(base.len() - i.len())(i)?;
+            sectors.push(sector);
+            i = rest;
+        }
+
+        Ok((
+            i,
+            Some(Td0Track {
+                offset,
+                cylinder,
+                head: head & 0x7F,
+                fm: head & 0x80 == 0x80,
+                crc,
+                computed_crc: td0_crc(&[sector_count, cylinder, head]) as u8,
+                sectors,
+            }),
+        ))
+    }
+}
+
+/// The optional comment and the tracks after the image header
+type Td0Body = (Option<Td0Comment>, Vec<Td0Track>);
+
+/// Parse the comment and the tracks after the image header
+fn td0_body_parser(has_comment: bool) -> impl Fn(&[u8]) -> IResult<&[u8], Td0Body> {
+    move |body| {
+        let (mut i, comment) = if has_comment {
+            let (i, comment) = td0_comment_parser(body)?;
+            (i, Some(comment))
+        } else {
+            (body, None)
+        };
+
+        let mut tracks = Vec::new();
+        // Some images end without the end of tracks record
+        while !i.is_empty() {
+            let (rest, track) = td0_track_parser(body)(i)?;
+            i = rest;
+            match track {
+                Some(track) => {
+                    limit_tracks(i, tracks.len() + 1)?;
+                    tracks.push(track);
+                }
+                None => break,
+            }
+        }
+
+        Ok((i, (comment, tracks)))
+    }
+}
+
+/// Fail on a CRC mismatch, or record a warning if ignore-checksums is
+/// set
+fn check_crc<'a>(
+    valid: bool,
+    ignore_checksums: bool,
+    what: &str,
+    offset: usize,
+    i: &'a [u8],
+) -> std::result::Result<(), nom::Err<nom::error::Error<&'a [u8]>>> {
+    if valid {
+        return Ok(());
+    }
+    if ignore_checksums {
+        warning(
+            Warning::new(&format!("ignored {} CRC mismatch", what))
+                .with_location(Location::offset(offset).with_format("TD0")),
+        );
+        return Ok(());
+    }
+    error!(target: PARSE, "{} CRC doesn't match", what);
+    Err(nom::Err::Error(nom::error::Error::new(
+        i,
+        nom::error::ErrorKind::Verify,
+    )))
+}
+
+/// Parse a TD0 image, decompressing it if it uses advanced compression
+/// The CRCs are checked unless ignore-checksums is set, in which case a
+/// mismatch is recorded as a warning.  Offsets in the warnings after
+/// the header are in the decompressed data.
+pub fn td0_disk_parser(config: &Options) -> impl Fn(&[u8]) -> IResult<&[u8], Td0Disk<'_>> + '_ {
+    move |data| {
+        with_limits(config.limits(), || {
+            let (i, header) = td0_header_parser(data)?;
+            if !header.check() {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    &data[2..],
+                    nom::error::ErrorKind::Verify,
+                )));
+            }
+            debug!(target: PARSE, "TD0 header: {}", header);
+            let ignore_checksums = config.get_bool("ignore-checksums").unwrap_or(false);
+            check_crc(
+                td0_crc(&data[..TD0_HEADER_SIZE - 2]) == header.crc,
+                ignore_checksums,
+                "TD0 header",
+                TD0_HEADER_SIZE - 2,
+                &data[TD0_HEADER_SIZE - 2..],
+            )?;
+
+            let body: Cow<[u8]> = if header.advanced_compression {
+                Cow::Owned(lzhuf_decompress(i).map_err(|e| {
+                    error!(target: PARSE, "Couldn't decompress the TD0 image: {}", e);
+                    nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::TooLarge))
+                })?)
+            } else {
+                Cow::Borrowed(i)
+            };
+            let (rest, (comment, tracks)) = td0_body_parser(header.has_comment())(&body)
+                .map_err(|e| e.map(|e| nom::error::Error::new(i, e.code)))?;
+            let consumed = body.len() - rest.len();
+
+            if let Some(comment) = &comment {
+                let end = COMMENT_HEADER_SIZE + comment.length;
+                check_crc(
+                    body.get(2..end).map(td0_crc) == Some(comment.crc),
+                    ignore_checksums,
+                    "TD0 comment",
+                    TD0_HEADER_SIZE,
+                    i,
+                )?;
+            }
+            for track in &tracks {
+                check_crc(
+                    track.crc == track.computed_crc,
+                    ignore_checksums,
+                    &format!("TD0 track {} side {} header", track.cylinder, track.head),
+                    TD0_HEADER_SIZE + track.offset,
+                    i,
+                )?;
+                for sector in &track.sectors {
+                    check_crc(
+                        sector.crc_valid(),
+                        ignore_checksums,
+                        &format!(
+                            "TD0 track {} side {} sector {}",
+                            track.cylinder, track.head, sector.sector
+                        ),
+                        TD0_HEADER_SIZE + sector.offset,
+                        i,
+                    )?;
+                }
+            }
+
+            let logical_tracks: Vec<LogicalTrack> =
+                tracks.iter().map(Td0Track::logical_track).collect();
+            let disk = infer_geometry(&logical_tracks).and_then(|geometry| {
+                let image = flatten_tracks(&logical_tracks, &geometry);
+                flat_disk_parser(geometry)(&image)
+                    .ok()
+                    .map(|(_, disk)| disk)
+            });
+
+            // Compressed images are read to the end
+            let (rest, image) = if header.advanced_compression {
+                (&data[data.len()..], data)
+            } else {
+                let end = TD0_HEADER_SIZE + consumed;
+                (&data[end..], &data[..end])
+            };
+            Ok((
+                rest,
+                Td0Disk {
+                    data: image,
+                    header,
+                    comment,
+                    tracks,
+                    disk,
+                },
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_td0, td0_crc, td0_decode_sector_data, td0_disk_parser, SECTOR_CRC_ERROR, TD0_HEADER_SIZE,
+    };
+    use crate::disk_format::geometry::{Geometry, SectorId};
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::parsed::collect_warnings;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::source_map::SourceMapper;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test decoding the three sector data encodings
+    #[test]
+    fn td0_decode_sector_data_works() {
+        assert_eq!(td0_decode_sector_data(0, b"abc"), Some(b"abc".to_vec()));
+        assert_eq!(
+            td0_decode_sector_data(1, &[3, 0, 0xE5, 0xF6]),
+            Some(vec![0xE5, 0xF6, 0xE5, 0xF6, 0xE5, 0xF6])
+        );
+        assert_eq!(
+            td0_decode_sector_data(2, &[0, 2, b'a', b'b', 1, 3, 0, 1, 2, 2, 1, 2, 3, 4]),
+            Some(vec![b'a', b'b', 0, 1, 0, 1, 0, 1, 1, 2, 3, 4, 1, 2, 3, 4])
+        );
+        assert_eq!(td0_decode_sector_data(2, &[0, 4, b'a']), None);
+        assert_eq!(td0_decode_sector_data(3, b"abc"), None);
+
+        // Data followed by its CRC has a CRC of zero
+        let mut header = b"TD\0\0\x15\0\x01\0\0\x02".to_vec();
+        header.extend(td0_crc(&header).to_be_bytes());
+        assert_eq!(td0_crc(&header), 0);
+    }
+
+    /// Test reading a FAT disk from plain and compressed images, with a
+    /// comment and a sector read with a CRC error
+    #[test]
+    fn td0_disk_parser_works() {
+        let config = Options::default();
+        let geometry = Geometry::atari_st(80, 2, 9);
+        let flat = testgen::atari_st_fat("TELEDISK", &[("README.TXT", b"Hello from TD0")]).unwrap();
+        let mut tracks = split_tracks(&flat, &geometry);
+        tracks[3].sectors[2].crc_error = true;
+
+        for advanced in [false, true] {
+            let data = testgen::td0(&tracks, advanced, "Backup disk\0Side 1");
+            assert!(is_td0(&data));
+            let (rest, disk) = td0_disk_parser(&config)(&data).unwrap();
+            assert!(rest.is_empty());
+            assert!(disk.check());
+            assert_eq!(disk.header.advanced_compression, advanced);
+            assert_eq!(disk.header.sides, 2);
+            assert_eq!(
+                disk.comment.as_ref().unwrap().to_string(),
+                "1994-06-01 12:30:00: Backup disk\nSide 1"
+            );
+            assert_eq!(disk.tracks.len(), 160);
+            assert_eq!(disk.geometry(), Some(geometry.clone()));
+            assert_eq!(disk.bad_sectors(), vec![SectorId::new(1, 1, 3)]);
+            assert_eq!(disk.tracks[3].sectors[2].flags, SECTOR_CRC_ERROR);
+            assert_eq!(
+                disk.tracks[3].to_string(),
+                "track 1 side 1, sectors: 9, CRC errors: 1"
+            );
+
+            // The sectors are the .st image
+            let mut saved = Vec::new();
+            disk.save_to_writer(&config, None, &mut saved).unwrap();
+            assert_eq!(saved, flat);
+            let files = disk.disk_files();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].data, b"Hello from TD0");
+
+            let map = disk.source_map(&data);
+            assert_eq!(map.regions[0].name, "TD0 header");
+            assert_eq!(map.regions.len(), if advanced { 2 } else { 2 + 160 });
+        }
+
+        // A damaged sector fails its CRC unless checksums are ignored
+        let mut data = testgen::td0(&tracks, false, "");
+        let last = data.len() - 2;
+        data[last] ^= 0xFF;
+        assert!(td0_disk_parser(&config)(&data).is_err());
+        let ignore = Options::default()
+            .with_override("ignore-checksums", true)
+            .unwrap();
+        let parsed =
+            collect_warnings(|| td0_disk_parser(&ignore)(&data).map(|(_, d)| d.tracks)).unwrap();
+        assert_eq!(parsed.warnings.len(), 1);
+        assert!(parsed.warnings[0]
+            .message
+            .contains("track 79 side 1 sector 9"));
+
+        // So does the header
+        let mut data = testgen::td0(&tracks, false, "");
+        data[TD0_HEADER_SIZE - 1] ^= 0xFF;
+        assert!(td0_disk_parser(&config)(&data).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! LZSS compression with adaptive Huffman coding
+//!
+//! Teledisk's advanced compression is LZHUF, by Haruyasu Yoshizaki,
+//! applied to everything after the image header.  The data is a
+//! stream of symbols read most significant bit first.  Symbols 0 to
+//! 255 are literal bytes, and the rest are matches of 3 to 60 bytes
+//! copied from a 4096 byte window of the output, which starts filled
+//! with spaces.  Symbols are coded with a Huffman tree that's updated
+//! after every symbol, and a match is followed by its position in the
+//! window: the upper six bits with a fixed code of 3 to 8 bits and the
+//! lower six bits as they are.
+//!
+//! There's no length or end marker, so the whole stream is decoded.
+//! The padding bits at the end of the last byte can decode to a few
+//! extra bytes after the data.
+//!
+//! Information from:\
+//! LZHUF.C by Haruyasu Yoshizaki, Haruhiko Okumura and Kenji Rikitake\
+//! Dave Dunfield's notes on the Teledisk format
+use std::collections::HashMap;
+
+use crate::disk_format::limits::{check_expanded_size, limits};
+use crate::error::Error;
+
+/// The size of the window matches are copied from
+const WINDOW_SIZE: usize = 4096;
+
+/// The longest match
+const MAX_MATCH: usize = 60;
+
+/// Matches this long or shorter are stored as literals
+const THRESHOLD: usize = 2;
+
+/// The number of symbols, the literal bytes and the match lengths
+const SYMBOL_COUNT: usize = 256 - THRESHOLD + MAX_MATCH;
+
+/// The number of nodes in the Huffman tree
+const TABLE_SIZE: usize = SYMBOL_COUNT * 2 - 1;
+
+/// The root node of the Huffman tree
+const ROOT: usize = TABLE_SIZE - 1;
+
+/// The tree is rebuilt with halved frequencies when the root reaches
+/// this frequency
+const MAX_FREQUENCY: u32 = 0x8000;
+
+/// The lengths of the codes for the upper six bits of match
+/// positions, and how many codes have each length
+const POSITION_CODE_LENGTHS: [(u8, usize); 6] = [(3, 1), (4, 3), (5, 8), (6, 12), (7, 24), (8, 16)];
+
+/// Return the code for the upper six bits of each match position, left
+/// aligned in a byte, and the length of the code
+/// The codes are canonical: shorter codes for nearer positions,
+/// assigned in order.
+fn position_codes() -> Vec<(u8, u8)> {
+    let mut codes = Vec::with_capacity(64);
+    let mut code: u32 = 0;
+    let mut previous_length = POSITION_CODE_LENGTHS[0].0;
+    for (length, count) in POSITION_CODE_LENGTHS {
+        code <<= length - previous_length;
+        previous_length = length;
+        for _ in 0..count {
+            codes.push(((code << (8 - length)) as u8, length));
+            code += 1;
+        }
+    }
+    codes
+}
+
+/// Read bits most significant bit first, reading zeros past the end
+struct BitReader<'a> {
+    /// The data being read
+    data: &'a [u8],
+    /// The number of bits read
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    /// Read a bit
+    fn bit(&mut self) -> usize {
+        let byte = self.data.get(self.position / 8).copied().unwrap_or(0);
+        self.position += 1;
+        usize::from((byte >> (7 - (self.position - 1) % 8)) & 0x01)
+    }
+
+    /// Read a number of bits as an integer
+    fn bits(&mut self, count: u8) -> usize {
+        (0..count).fold(0, |value, _| (value << 1) | self.bit())
+    }
+
+    /// True once every bit has been read
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len() * 8
+    }
+}
+
+/// Write bits most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    /// The bytes written
+    data: Vec<u8>,
+    /// The number of bits written
+    position: usize,
+}
+
+impl BitWriter {
+    /// Write the low bits of a value
+    fn bits(&mut self, value: usize, count: u8) {
+        for n in (0..count).rev() {
+            if self.position % 8 == 0 {
+                self.data.push(0);
+            }
+            if (value >> n) & 0x01 == 0x01 {
+                *self.data.last_mut().unwrap() |= 0x80 >> (self.position % 8);
+            }
+            self.position += 1;
+        }
+    }
+}
+
+/// The adaptive Huffman tree for the symbols
+/// Nodes are kept in order of frequency, the leaves and then the
+/// internal nodes.  son holds the first of the two children of an
+/// internal node, or the symbol plus TABLE_SIZE for a leaf.  parent
+/// holds the parent of each node, followed by the node of each leaf.
+struct AdaptiveHuffman {
+    /// The frequency of each node, and a guard at the end
+    frequency: [u32; TABLE_SIZE + 1],
+    /// The parent of each node, then the node of each symbol
+    parent: [usize; TABLE_SIZE + SYMBOL_COUNT],
+    /// The first child of each node, or the symbol of a leaf
+    son: [usize; TABLE_SIZE],
+}
+
+impl AdaptiveHuffman {
+    /// Build the starting tree, with every symbol seen once
+    fn new() -> AdaptiveHuffman {
+        let mut tree = AdaptiveHuffman {
+            frequency: [0; TABLE_SIZE + 1],
+            parent: [0; TABLE_SIZE + SYMBOL_COUNT],
+            son: [0; TABLE_SIZE],
+        };
+        for symbol in 0..SYMBOL_COUNT {
+            tree.frequency[symbol] = 1;
+            tree.son[symbol] = symbol + TABLE_SIZE;
+            tree.parent[symbol + TABLE_SIZE] = symbol;
+        }
+        for (node, child) in (SYMBOL_COUNT..TABLE_SIZE).zip((0..).step_by(2)) {
+            tree.frequency[node] = tree.frequency[child] + tree.frequency[child + 1];
+            tree.son[node] = child;
+            tree.parent[child] = node;
+            tree.parent[child + 1] = node;
+        }
+        // A guard that stops the search for a node to swap with
+        tree.frequency[TABLE_SIZE] = 0xFFFF;
+        tree.parent[ROOT] = 0;
+
+        tree
+    }
+
+    /// Rebuild the tree with the frequencies halved
+    fn rebuild(&mut self) {
+        // Gather the leaves at the start
+        let mut leaf = 0;
+        for node in 0..TABLE_SIZE {
+            if self.son[node] >= TABLE_SIZE {
+                self.frequency[leaf] = self.frequency[node].div_ceil(2);
+                self.son[leaf] = self.son[node];
+                leaf += 1;
+            }
+        }
+
+        // Join pairs of nodes, inserting each new node in frequency
+        // order
+        for (node, child) in (SYMBOL_COUNT..TABLE_SIZE).zip((0..).step_by(2)) {
+            let frequency = self.frequency[child] + self.frequency[child + 1];
+            let mut position = node;
+            while frequency < self.frequency[position - 1] {
+                position -= 1;
+            }
+            self.frequency.copy_within(position..node, position + 1);
+            self.frequency[position] = frequency;
+            self.son.copy_within(position..node, position + 1);
+            self.son[position] = child;
+        }
+
+        for node in 0..TABLE_SIZE {
+            let son = self.son[node];
+            self.parent[son] = node;
+            if son < TABLE_SIZE {
+                self.parent[son + 1] = node;
+            }
+        }
+    }
+
+    /// Count a symbol, swapping nodes to keep them in frequency order
+    fn update(&mut self, symbol: usize) {
+        if self.frequency[ROOT] == MAX_FREQUENCY {
+            self.rebuild();
+        }
+        let mut node = self.parent[symbol + TABLE_SIZE];
+        loop {
+            self.frequency[node] += 1;
+            let frequency = self.frequency[node];
+            if frequency > self.frequency[node + 1] {
+                // Swap with the last node that's now less frequent
+                let mut other = node + 1;
+                while frequency > self.frequency[other + 1] {
+                    other += 1;
+                }
+                self.frequency[node] = self.frequency[other];
+                self.frequency[other] = frequency;
+
+                let son = self.son[node];
+                self.parent[son] = other;
+                if son < TABLE_SIZE {
+                    self.parent[son + 1] = other;
+                }
+                let other_son = self.son[other];
+                self.son[other] = son;
+                self.parent[other_son] = node;
+                if other_son < TABLE_SIZE {
+                    self.parent[other_son + 1] = node;
+                }
+                self.son[node] = other_son;
+                node = other;
+            }
+            node = self.parent[node];
+            if node == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Read a symbol
+    fn decode(&mut self, bits: &mut BitReader) -> usize {
+        let mut node = self.son[ROOT];
+        while node < TABLE_SIZE {
+            node = self.son[node + bits.bit()];
+        }
+        let symbol = node - TABLE_SIZE;
+        self.update(symbol);
+        symbol
+    }
+
+    /// Write a symbol
+    fn encode(&mut self, symbol: usize, bits: &mut BitWriter) {
+        let mut code = Vec::new();
+        let mut node = self.parent[symbol + TABLE_SIZE];
+        while node != ROOT {
+            let parent = self.parent[node];
+            code.push(node - self.son[parent]);
+            node = parent;
+        }
+        for bit in code.iter().rev() {
+            bits.bits(*bit, 1);
+        }
+        self.update(symbol);
+    }
+}
+
+/// Read the position of a match, counted back from the byte before
+/// the end of the window
+fn decode_position(codes: &[(u8, u8)], bits: &mut BitReader) -> usize {
+    let byte = bits.bits(8);
+    let (upper, length) = codes
+        .iter()
+        .enumerate()
+        .find(|(_, (code, length))| byte >> (8 - length) == usize::from(code >> (8 - length)))
+        .map(|(upper, (_, length))| (upper, *length))
+        .unwrap_or((0, 3));
+    let lower = (0..length - 2).fold(byte, |lower, _| (lower << 1) | bits.bit());
+
+    (upper << 6) | (lower & 0x3F)
+}
+
+/// Write the position of a match
+fn encode_position(codes: &[(u8, u8)], position: usize, bits: &mut BitWriter) {
+    let (code, length) = codes[position >> 6];
+    bits.bits(usize::from(code >> (8 - length)), length);
+    bits.bits(position & 0x3F, 6);
+}
+
+/// Decompress LZHUF data
+/// Fails if the data decompresses to more than the expanded size
+/// limit.
+pub fn lzhuf_decompress(data: &[u8]) -> std::result::Result<Vec<u8>, Error> {
+    let max = limits().max_expanded_size;
+    let codes = position_codes();
+    let mut tree = AdaptiveHuffman::new();
+    let mut bits = BitReader::new(data);
+    let mut window = [b' '; WINDOW_SIZE];
+    let mut end = WINDOW_SIZE - MAX_MATCH;
+    let mut output = Vec::new();
+
+    while !bits.is_empty() && output.len() <= max {
+        let symbol = tree.decode(&mut bits);
+        if symbol < 256 {
+            output.push(symbol as u8);
+            window[end] = symbol as u8;
+            end = (end + 1) % WINDOW_SIZE;
+            continue;
+        }
+        let position = decode_position(&codes, &mut bits);
+        let start = (end + WINDOW_SIZE - position - 1) % WINDOW_SIZE;
+        for n in 0..symbol - 255 + THRESHOLD {
+            let byte = window[(start + n) % WINDOW_SIZE];
+            output.push(byte);
+            window[end] = byte;
+            end = (end + 1) % WINDOW_SIZE;
+        }
+    }
+    check_expanded_size(output.len())?;
+
+    Ok(output)
+}
+
+/// Compress data with LZHUF
+/// Matches are found from the last place each three bytes were seen,
+/// which compresses less than a full search but is fast.
+pub fn lzhuf_compress(data: &[u8]) -> Vec<u8> {
+    let codes = position_codes();
+    let mut tree = AdaptiveHuffman::new();
+    let mut bits = BitWriter::default();
+    let mut seen: HashMap<&[u8], usize> = HashMap::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let (length, distance) = data
+            .get(position..position + THRESHOLD + 1)
+            .and_then(|key| seen.get(key))
+            .filter(|start| position - **start <= WINDOW_SIZE - MAX_MATCH)
+            .map(|start| {
+                let length = data[*start..]
+                    .iter()
+                    .zip(&data[position..])
+                    .take(MAX_MATCH)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (length, position - start)
+            })
+            .unwrap_or((0, 0));
+
+        let step = if length > THRESHOLD {
+            tree.encode(length + 255 - THRESHOLD, &mut bits);
+            encode_position(&codes, distance - 1, &mut bits);
+            length
+        } else {
+            tree.encode(usize::from(data[position]), &mut bits);
+            1
+        };
+        for start in position..position + step {
+            if let Some(key) = data.get(start..start + THRESHOLD + 1) {
+                seen.insert(key, start);
+            }
+        }
+        position += step;
+    }
+
+    bits.data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lzhuf_compress, lzhuf_decompress, position_codes};
+
+    /// Test compressing and decompressing literals, near and far
+    /// matches, and enough data to rebuild the Huffman tree
+    #[test]
+    fn lzhuf_works() {
+        let codes = position_codes();
+        assert_eq!(codes[0], (0x00, 3));
+        assert_eq!(codes[1], (0x20, 4));
+        assert_eq!(codes[4], (0x50, 5));
+        assert_eq!(codes[12], (0x90, 6));
+        assert_eq!(codes[24], (0xC0, 7));
+        assert_eq!(codes[47], (0xEE, 7));
+        assert_eq!(codes[63], (0xFF, 8));
+
+        let mut data: Vec<u8> = b"TELEDISK TELEDISK TELEDISK".to_vec();
+        data.extend(std::iter::repeat(0xE5).take(1000));
+        data.extend((0..3000).map(|n: u32| (n * 7 % 251) as u8));
+        data.extend_from_slice(b"TELEDISK");
+        // Enough literals for the tree to be rebuilt
+        let mut seed: u32 = 1;
+        for _ in 0..40_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            data.push((seed >> 16) as u8);
+        }
+
+        let decompressed = lzhuf_decompress(&lzhuf_compress(&data)).unwrap();
+        assert!(decompressed.starts_with(&data));
+        assert!(decompressed.len() < data.len() + 8);
+
+        assert!(lzhuf_compress(&[0xE5; 1000]).len() < 50);
+        assert_eq!(lzhuf_decompress(&[]).unwrap(), Vec::<u8>::new());
+    }
+}
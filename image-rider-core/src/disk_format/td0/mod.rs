@@ -0,0 +1,33 @@
+//! Parse Teledisk TD0 disk images
+//!
+//! Teledisk, by Sydex, was a common way to archive PC, CP/M and other
+//! IBM format disks.  An image is a header followed by an optional
+//! comment and the records of each track and sector:
+//!
+//! ```ignore
+//! Image header (12 bytes)
+//! Comment header (10 bytes) and comment, if the header says so
+//! Track header (4 bytes)
+//!  Sector header (6 bytes)
+//!  Sector data block, if the sector has data
+//!  ...
+//! Track header
+//! etc.
+//! Track header with a sector count of 0xFF
+//! ```
+//!
+//! Images made with advanced compression, which have the signature
+//! "td" instead of "TD", compress everything after the image header
+//! with LZHUF.
+//!
+//! Information from:\
+//! Dave Dunfield's notes on the Teledisk format\
+//! [SAMdisk](https://github.com/simonowen/samdisk) src/types/td0.cpp
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+
+/// TD0 disk images, tracks and sectors
+pub mod disk;
+
+/// LZHUF compression, used by Teledisk advanced compression
+pub mod lzhuf;
@@ -0,0 +1,1932 @@
+//! Minimal valid images built in memory
+//!
+//! Tests and fuzz seeds need images of every supported format, and
+//! real disk dumps are usually copyrighted.  The builders here make
+//! small, valid images from a list of files or a flat sector image,
+//! using the same writers the converters use, so every parser can be
+//! tested without shipping dumps.
+//!
+//! Files are allocated one after another and the allocation maps are
+//! kept consistent with them, so sanity checks and usage maps pass.
+//! The images aren't bootable, the boot sectors only hold the magic
+//! numbers the format guesses look for.
+use std::collections::BTreeMap;
+
+use crate::disk_format::amiga::block::{
+    block_checksum, name_hash, BLOCK_SIZE as AMIGA_BLOCK_SIZE, HEADER_CHECKSUM_OFFSET,
+    MAX_NAME_LENGTH, OFS_DATA_HEADER_SIZE, OFS_DATA_SIZE, ST_FILE, ST_ROOT, ST_USERDIR, TABLE_SIZE,
+    T_DATA, T_HEADER, T_LIST,
+};
+use crate::disk_format::apple::catalog::{
+    track_sector_list_sectors, FileEntry, FileType, TrackSectorPair, PAIRS_PER_TRACK_SECTOR_LIST,
+};
+use crate::disk_format::apple::nibble::{
+    encode_address_field, encode_address_field_5_and_3, encode_data_field,
+    encode_data_field_5_and_3,
+};
+use crate::disk_format::apple::prodos::{
+    FileEntry as ProDOSFileEntry, StorageType, VolumeDirectoryHeader, BLOCK_SIZE,
+    DIRECTORY_ENTRIES_OFFSET, ENTRIES_PER_BLOCK, ENTRY_LENGTH,
+};
+use crate::disk_format::apple::woz::{woz_from_dos_order, DOS_33_PHYSICAL_ORDER};
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::commodore::g64::{gcr_encode, speed_zone_for_track};
+use crate::disk_format::cpm::dpb::{CpmFormat, DIRECTORY_ENTRY_SIZE as CPM_DIRECTORY_ENTRY_SIZE};
+use crate::disk_format::cpm::dpb::{RECORDS_PER_EXTENT, RECORD_SIZE};
+use crate::disk_format::cpm::volume::CpmVolume;
+use crate::disk_format::fat::bpb::{BiosParameterBlock, DIRECTORY_ENTRY_SIZE};
+use crate::disk_format::fat::chain::{AllocationStrategy, FileAllocationTable};
+use crate::disk_format::fat::directory::{DirectoryEntry, ATTRIBUTE_ARCHIVE, ATTRIBUTE_DIRECTORY};
+use crate::disk_format::geometry::{Geometry, SectorId};
+use crate::disk_format::imd::{CYLINDER_MAP, HEAD_MAP, IMD_COMMENT_END};
+use crate::disk_format::logical::{split_tracks, LogicalSector, LogicalTrack};
+use crate::disk_format::mac::diskcopy::{dc42_checksum, DC42_TAG_SIZE};
+use crate::disk_format::mfm::{encode_track, pack_bits};
+use crate::disk_format::stx::writer::write_stx;
+use crate::disk_format::td0::disk::{
+    td0_crc, SECTOR_CRC_ERROR, SECTOR_DELETED, TD0_ADVANCED_MAGIC, TD0_MAGIC,
+};
+use crate::disk_format::td0::lzhuf::lzhuf_compress;
+use crate::error::{Error, ErrorKind};
+use crate::rom_format::atari::{A78_MAGIC, ATARI_2600_BANK_SIZE, SUPERGAME_BANK_SIZE};
+use crate::rom_format::nes::{
+    CHR_BANK_SIZE, NES_HEADER_SIZE, NES_MAGIC, PRG_BANK_SIZE, TRAINER_SIZE,
+};
+use crate::rom_format::snes::{rom_checksum, SNESMapMode, COPIER_HEADER_SIZE};
+use crate::serialize::Serializer;
+
+/// The start of the DOS 3.3 boot sector, checked by the Apple format
+/// guess
+const APPLE_BOOT_MAGIC: [u8; 9] = [0x01, 0xA5, 0x27, 0xC9, 0x09, 0xD0, 0x18, 0xA5, 0x2B];
+
+/// The end of the DOS 3.3 boot sector, the page and number of sectors
+/// the rest of the boot loader is read into, as on a disk initialized
+/// with a 48K slave DOS
+const APPLE_BOOT_LOAD: [u8; 2] = [0xB6, 0x09];
+
+/// The track holding the VTOC and catalog on an Apple DOS 3.3 disk
+const APPLE_CATALOG_TRACK: u8 = 17;
+
+/// The volume number written on generated Apple disks
+pub const APPLE_VOLUME: u8 = 254;
+
+/// The number of nibbles in each track of a .nib image
+pub const NIB_TRACK_SIZE: usize = 6656;
+
+/// The track holding the BAM and directory on a Commodore 1541 disk
+const D64_DIRECTORY_TRACK: u8 = 18;
+
+/// The track holding the second side BAM on a Commodore 1571 disk
+const D71_SECOND_BAM_TRACK: u8 = 53;
+
+/// The number of file data bytes in each sector of a Commodore file,
+/// after the two link bytes
+const D64_BYTES_PER_SECTOR: usize = 254;
+
+/// Allocate free sectors in a fixed order
+struct Allocator {
+    /// The sectors that haven't been allocated, in allocation order
+    free: Vec<SectorId>,
+}
+
+impl Allocator {
+    /// Create an allocator over the sectors of a set of tracks
+    fn new(geometry: &Geometry, tracks: &[u8]) -> Allocator {
+        let ids = geometry.sector_ids();
+        let free = tracks
+            .iter()
+            .flat_map(|track| {
+                ids.iter()
+                    .filter(move |id| id.track.get() == *track)
+                    .copied()
+            })
+            .collect();
+        Allocator { free }
+    }
+
+    /// Allocate count sectors, failing if the disk is full
+    fn allocate(&mut self, count: usize) -> std::result::Result<Vec<SectorId>, Error> {
+        if count > self.free.len() {
+            return Err(Error::new(crate::error::ErrorKind::Message(format!(
+                "{} sectors are needed but only {} are free",
+                count,
+                self.free.len()
+            ))));
+        }
+        Ok(self.free.drain(..count).collect())
+    }
+}
+
+/// Copy a sector into a flat image
+fn write_sector(data: &mut [u8], geometry: &Geometry, id: &SectorId, sector: &[u8]) {
+    if let Some(offset) = geometry.offset(id) {
+        let length = sector.len().min(geometry.sector_size);
+        data[offset..offset + length].copy_from_slice(&sector[..length]);
+    }
+}
+
+/// Build the data of an Apple DOS binary file, the load address and
+/// length followed by the program
+pub fn apple_binary(address: u16, program: &[u8]) -> Vec<u8> {
+    let mut data = address.to_le_bytes().to_vec();
+    data.extend_from_slice(&(program.len() as u16).to_le_bytes());
+    data.extend_from_slice(program);
+    data
+}
+
+/// Build a 35 track DOS-order Apple DOS 3.3 image holding binary files
+/// The file data is stored as given, see apple_binary for building
+/// the address and length header.  Names must be 1 to 30 characters.
+pub fn apple_dos_33(files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    apple_dos(files, &Geometry::apple_dos_33(35))
+}
+
+/// Build a 35 track, 13 sector Apple DOS 3.2 image holding binary
+/// files, like apple_dos_33
+pub fn apple_dos_32(files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    apple_dos(files, &Geometry::apple_dos_32(35))
+}
+
+/// Build an Apple DOS image with 13 or 16 sectors per track
+/// The catalog fills the catalog track below the VTOC.
+fn apple_dos(files: &[(&str, &[u8])], geometry: &Geometry) -> std::result::Result<Vec<u8>, Error> {
+    let sectors = geometry.sectors_per_track[0];
+    let mut data = vec![0_u8; geometry.total_size()];
+    data[..APPLE_BOOT_MAGIC.len()].copy_from_slice(&APPLE_BOOT_MAGIC);
+    data[0xFE..0x100].copy_from_slice(&APPLE_BOOT_LOAD);
+
+    // DOS allocates outward from the catalog track, and tracks zero to
+    // two hold DOS itself
+    let tracks: Vec<u8> = (APPLE_CATALOG_TRACK + 1..35)
+        .chain((3..APPLE_CATALOG_TRACK).rev())
+        .collect();
+    let mut allocator = Allocator::new(geometry, &tracks);
+
+    // The catalog sectors are linked from the last sector down to
+    // sector 1
+    let mut catalog: Vec<Vec<u8>> = (1..sectors)
+        .rev()
+        .map(|sector| {
+            let mut bytes = vec![0_u8; 256];
+            if sector > 1 {
+                bytes[1] = APPLE_CATALOG_TRACK;
+                bytes[2] = sector - 1;
+            }
+            bytes
+        })
+        .collect();
+    let slots = catalog.len() * 7;
+    if files.len() > slots {
+        return Err(Error::new(crate::error::ErrorKind::Message(format!(
+            "A catalog holds {} files, not {}",
+            slots,
+            files.len()
+        ))));
+    }
+
+    for (n, (name, contents)) in files.iter().enumerate() {
+        let data_sectors = allocator.allocate(contents.len().div_ceil(256))?;
+        let lists = allocator.allocate(
+            data_sectors
+                .len()
+                .div_ceil(PAIRS_PER_TRACK_SECTOR_LIST)
+                .max(1),
+        )?;
+        let pair = |id: &SectorId| TrackSectorPair {
+            track_number: id.track.get(),
+            sector_number: id.sector.get(),
+        };
+
+        let pairs: Vec<TrackSectorPair> = data_sectors.iter().map(pair).collect();
+        let list_pairs: Vec<TrackSectorPair> = lists.iter().map(pair).collect();
+        for (id, list) in lists
+            .iter()
+            .zip(track_sector_list_sectors(&pairs, &list_pairs)?)
+        {
+            write_sector(&mut data, geometry, id, &list);
+        }
+        for (id, chunk) in data_sectors.iter().zip(contents.chunks(256)) {
+            write_sector(&mut data, geometry, id, chunk);
+        }
+
+        let length = (lists.len() + data_sectors.len()) as u16;
+        let entry = FileEntry::new(
+            lists[0].track.get(),
+            lists[0].sector.get(),
+            FileType::Binary,
+            false,
+            name,
+            length,
+        );
+        let offset = 0x0B + (n % 7) * 35;
+        catalog[n / 7][offset..offset + 35].copy_from_slice(&entry.as_vec()?);
+    }
+    for (sector, bytes) in (1..sectors).rev().zip(&catalog) {
+        write_sector(
+            &mut data,
+            geometry,
+            &SectorId::new(APPLE_CATALOG_TRACK, 0, sector),
+            bytes,
+        );
+    }
+
+    let mut vtoc = vec![0_u8; 256];
+    vtoc[0x01] = APPLE_CATALOG_TRACK;
+    vtoc[0x02] = sectors - 1;
+    // DOS 3.2 disks are release 2
+    vtoc[0x03] = if sectors == 13 { 2 } else { 3 };
+    vtoc[0x06] = APPLE_VOLUME;
+    vtoc[0x27] = PAIRS_PER_TRACK_SECTOR_LIST as u8;
+    vtoc[0x30] = APPLE_CATALOG_TRACK;
+    vtoc[0x31] = 1;
+    vtoc[0x34] = 35;
+    vtoc[0x35] = sectors;
+    vtoc[0x36..0x38].copy_from_slice(&256_u16.to_le_bytes());
+    for id in &allocator.free {
+        let bits = 1_u16 << id.sector.get();
+        let offset = 0x38 + usize::from(id.track.get()) * 4;
+        let bitmap = u16::from_be_bytes([vtoc[offset], vtoc[offset + 1]]) | bits;
+        vtoc[offset..offset + 2].copy_from_slice(&bitmap.to_be_bytes());
+    }
+    write_sector(
+        &mut data,
+        geometry,
+        &SectorId::new(APPLE_CATALOG_TRACK, 0, 0),
+        &vtoc,
+    );
+
+    Ok(data)
+}
+
+/// Build a 35 track .nib image from a DOS-order image
+/// Each track holds the sixteen sectors in physical order with the
+/// DOS 3.3 gaps, padded with sync bytes to the length of a track.
+pub fn nib_from_dos_order(data: &[u8]) -> Vec<u8> {
+    let mut nib = Vec::with_capacity(35 * NIB_TRACK_SIZE);
+
+    for (track, track_data) in data.chunks_exact(16 * 256).take(35).enumerate() {
+        let mut nibbles = vec![0xFF_u8; 48];
+        for (physical, logical) in DOS_33_PHYSICAL_ORDER.iter().enumerate() {
+            let start = usize::from(*logical) * 256;
+            nibbles.extend(encode_address_field(
+                APPLE_VOLUME,
+                track as u8,
+                physical as u8,
+            ));
+            nibbles.extend([0xFF; 6]);
+            nibbles.extend(encode_data_field(&track_data[start..start + 256]));
+            nibbles.extend([0xFF; 27]);
+        }
+        nibbles.resize(NIB_TRACK_SIZE, 0xFF);
+        nib.extend(nibbles);
+    }
+
+    nib
+}
+
+/// Build a 35 track .nib image from a 13 sector DOS 3.2 image
+/// Each track holds the thirteen sectors in physical order with 5 and
+/// 3 data fields, padded with sync bytes to the length of a track.
+pub fn nib_from_dos_32(data: &[u8]) -> Vec<u8> {
+    let mut nib = Vec::with_capacity(35 * NIB_TRACK_SIZE);
+
+    for (track, track_data) in data.chunks_exact(13 * 256).take(35).enumerate() {
+        let mut nibbles = vec![0xFF_u8; 48];
+        for (sector, sector_data) in track_data.chunks_exact(256).enumerate() {
+            nibbles.extend(encode_address_field_5_and_3(
+                APPLE_VOLUME,
+                track as u8,
+                sector as u8,
+            ));
+            nibbles.extend([0xFF; 6]);
+            nibbles.extend(encode_data_field_5_and_3(sector_data));
+            nibbles.extend([0xFF; 27]);
+        }
+        nibbles.resize(NIB_TRACK_SIZE, 0xFF);
+        nib.extend(nibbles);
+    }
+
+    nib
+}
+
+/// Build a WOZ 2.0 image from a 35 track DOS-order image
+pub fn woz_from_dos(data: &[u8]) -> std::result::Result<Vec<u8>, Error> {
+    woz_from_dos_order(data, APPLE_VOLUME, false, &CancellationToken::new())
+}
+
+/// Build a 2MG image wrapping an Apple image
+/// The format is 0 for DOS order, 1 for ProDOS order and 2 for nibbles.
+/// The creator is RIDR, and the comment follows the image data.
+pub fn two_img(data: &[u8], format: u32, volume: Option<u8>, comment: &str) -> Vec<u8> {
+    let flags = volume.map_or(0, |volume| 0x100 | u32::from(volume));
+    let blocks = if format == 1 { data.len() / 512 } else { 0 };
+    let comment_offset = if comment.is_empty() {
+        0
+    } else {
+        64 + data.len()
+    };
+    let mut image = Vec::from(&b"2IMGRIDR"[..]);
+    image.extend(64_u16.to_le_bytes());
+    image.extend(1_u16.to_le_bytes());
+    for field in [
+        format,
+        flags,
+        blocks as u32,
+        64,
+        data.len() as u32,
+        comment_offset as u32,
+        comment.len() as u32,
+        0,
+        0,
+    ] {
+        image.extend(field.to_le_bytes());
+    }
+    image.resize(64, 0);
+    image.extend(data);
+    image.extend(comment.bytes());
+    image
+}
+
+/// Build a DiskCopy 4.2 image from sector data
+/// The encoding is 0 for 400K GCR, 1 for 800K GCR, 2 for 720K MFM and
+/// 3 for 1.4M MFM.  The tags of each block are filled with the block
+/// number.
+pub fn dc42(name: &str, encoding: u8, sectors: &[u8], tags: bool) -> Vec<u8> {
+    let tags: Vec<u8> = if tags {
+        (0..sectors.len() / 512)
+            .flat_map(|block| [block as u8; DC42_TAG_SIZE])
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut data = vec![name.len() as u8];
+    data.extend(name.bytes());
+    data.resize(64, 0);
+    data.extend((sectors.len() as u32).to_be_bytes());
+    data.extend((tags.len() as u32).to_be_bytes());
+    data.extend(dc42_checksum(sectors).to_be_bytes());
+    data.extend(dc42_checksum(tags.get(DC42_TAG_SIZE..).unwrap_or_default()).to_be_bytes());
+    data.extend([
+        encoding,
+        if encoding == 0 { 0x12 } else { 0x22 },
+        0x01,
+        0x00,
+    ]);
+    data.extend(sectors);
+    data.extend(tags);
+    data
+}
+
+/// Build a 35 track Commodore 1541 D64 image holding PRG files
+/// Names must be at most 16 characters and are stored as given, in
+/// PETSCII.
+pub fn d64(disk_name: &str, files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    commodore_disk(disk_name, files, false)
+}
+
+/// Build a double-sided Commodore 1571 D71 image holding PRG files
+/// Files are written to the first side and continue on the second
+/// side, tracks 36 to 70, when it's full.
+pub fn d71(disk_name: &str, files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    commodore_disk(disk_name, files, true)
+}
+
+/// Build a D64 or D71 image holding PRG files
+fn commodore_disk(
+    disk_name: &str,
+    files: &[(&str, &[u8])],
+    double_sided: bool,
+) -> std::result::Result<Vec<u8>, Error> {
+    let (geometry, last_track) = if double_sided {
+        (Geometry::commodore_1571(), 70)
+    } else {
+        (Geometry::commodore_1541(35), 35)
+    };
+    let mut data = vec![0_u8; geometry.total_size()];
+
+    let tracks: Vec<u8> = (1..=last_track)
+        .filter(|t| *t != D64_DIRECTORY_TRACK && *t != D71_SECOND_BAM_TRACK)
+        .collect();
+    let mut allocator = Allocator::new(&geometry, &tracks);
+
+    // The directory uses sectors 1 to 18 of the directory track, eight
+    // entries to a sector
+    let directory_sectors = files.len().div_ceil(8).max(1);
+    if directory_sectors > 18 {
+        return Err(Error::new(crate::error::ErrorKind::Message(format!(
+            "A directory holds 144 files, not {}",
+            files.len()
+        ))));
+    }
+    let mut directory: Vec<Vec<u8>> = (0..directory_sectors)
+        .map(|n| {
+            let mut bytes = vec![0_u8; 256];
+            if n + 1 < directory_sectors {
+                bytes[0] = D64_DIRECTORY_TRACK;
+                bytes[1] = (n + 2) as u8;
+            } else {
+                bytes[1] = 0xFF;
+            }
+            bytes
+        })
+        .collect();
+
+    for (n, (name, contents)) in files.iter().enumerate() {
+        let chunks: Vec<&[u8]> = if contents.is_empty() {
+            vec![&[]]
+        } else {
+            contents.chunks(D64_BYTES_PER_SECTOR).collect()
+        };
+        let sectors = allocator.allocate(chunks.len())?;
+        for (index, (id, chunk)) in sectors.iter().zip(&chunks).enumerate() {
+            let mut sector = vec![0_u8; 256];
+            match sectors.get(index + 1) {
+                Some(next) => {
+                    sector[0] = next.track.get();
+                    sector[1] = next.sector.get();
+                }
+                // The last sector holds the offset of its last byte
+                None => sector[1] = (chunk.len() + 1) as u8,
+            }
+            sector[2..2 + chunk.len()].copy_from_slice(chunk);
+            write_sector(&mut data, &geometry, id, &sector);
+        }
+
+        let entry = &mut directory[n / 8][(n % 8) * 32..(n % 8) * 32 + 32];
+        entry[0x02] = 0x82;
+        entry[0x03] = sectors[0].track.get();
+        entry[0x04] = sectors[0].sector.get();
+        entry[0x05..0x15].copy_from_slice(&d64_name(name));
+        entry[0x1E..0x20].copy_from_slice(&(sectors.len() as u16).to_le_bytes());
+    }
+    for (n, bytes) in directory.iter().enumerate() {
+        let id = SectorId::new(D64_DIRECTORY_TRACK, 0, (n + 1) as u8);
+        write_sector(&mut data, &geometry, &id, bytes);
+    }
+
+    let mut bam = vec![0_u8; 256];
+    bam[..4].copy_from_slice(&[D64_DIRECTORY_TRACK, 0x01, 0x41, 0x00]);
+    let mut free: BTreeMap<u8, u32> = BTreeMap::new();
+    // Directory track sectors past the directory are free too
+    let unused_directory = (directory_sectors + 1..19)
+        .map(|sector| SectorId::new(D64_DIRECTORY_TRACK, 0, sector as u8));
+    for id in allocator.free.iter().copied().chain(unused_directory) {
+        *free.entry(id.track.get()).or_default() |= 1 << id.sector.get();
+    }
+    for track in 1..=35_u8 {
+        let bits = free.get(&track).copied().unwrap_or(0);
+        let offset = usize::from(track) * 4;
+        bam[offset] = bits.count_ones() as u8;
+        bam[offset + 1..offset + 4].copy_from_slice(&bits.to_le_bytes()[..3]);
+    }
+    if double_sided {
+        // The second side has its free counts after the disk name and
+        // its bitmaps on track 53, which is reserved
+        bam[3] = 0x80;
+        let mut bitmaps = vec![0_u8; 256];
+        for track in 36..=70_u8 {
+            let bits = free.get(&track).copied().unwrap_or(0);
+            let index = usize::from(track - 36);
+            bam[0xDD + index] = bits.count_ones() as u8;
+            bitmaps[index * 3..index * 3 + 3].copy_from_slice(&bits.to_le_bytes()[..3]);
+        }
+        write_sector(
+            &mut data,
+            &geometry,
+            &SectorId::new(D71_SECOND_BAM_TRACK, 0, 0),
+            &bitmaps,
+        );
+    }
+    bam[0x90..0xA0].copy_from_slice(&d64_name(disk_name));
+    bam[0xA0..0xAB].copy_from_slice(&[
+        0xA0, 0xA0, 0x30, 0x30, 0xA0, 0x32, 0x41, 0xA0, 0xA0, 0xA0, 0xA0,
+    ]);
+    write_sector(
+        &mut data,
+        &geometry,
+        &SectorId::new(D64_DIRECTORY_TRACK, 0, 0),
+        &bam,
+    );
+
+    Ok(data)
+}
+
+/// Pad a name to sixteen bytes with 0xA0
+fn d64_name(name: &str) -> [u8; 16] {
+    let mut bytes = [0xA0_u8; 16];
+    for (byte, c) in bytes.iter_mut().zip(name.bytes()) {
+        *byte = c;
+    }
+    bytes
+}
+
+/// Build a flat Atari ST image whose sectors are filled with a
+/// pattern of their track, head and sector numbers
+pub fn atari_st_sectors(tracks: u8, heads: u8, sectors: u8) -> Vec<u8> {
+    let geometry = Geometry::atari_st(tracks, heads, sectors);
+    let mut data = vec![0_u8; geometry.total_size()];
+    for id in geometry.sector_ids() {
+        let fill = id.track.get() ^ (id.head.get() << 7) ^ (id.sector.get() << 4);
+        let sector = vec![fill; geometry.sector_size];
+        write_sector(&mut data, &geometry, &id, &sector);
+    }
+    data
+}
+
+/// Build an SCP image from the flux of each track entry
+/// Every revolution of a track holds the same flux, in 25 ns samples.
+/// Flux values over 65535 are written with overflow values.
+pub fn scp(disk_type: u8, tracks: &[(u8, Vec<u32>)], revolutions: u8) -> Vec<u8> {
+    const TRACK_ENTRIES: usize = 168;
+    let end_track = tracks.iter().map(|(entry, _)| *entry).max().unwrap_or(0);
+    let mut offsets = vec![0_u32; TRACK_ENTRIES];
+    let mut body = Vec::new();
+    let tracks_start = 16 + TRACK_ENTRIES * 4;
+    for (entry, flux) in tracks {
+        offsets[usize::from(*entry)] = (tracks_start + body.len()) as u32;
+        let mut values = Vec::new();
+        for value in flux {
+            values.extend(std::iter::repeat(0_u16).take((*value >> 16) as usize));
+            values.push(*value as u16);
+        }
+        let header_size = 4 + usize::from(revolutions) * 12;
+        let index_time: u32 = flux.iter().sum();
+        body.extend(b"TRK");
+        body.push(*entry);
+        for revolution in 0..usize::from(revolutions) {
+            body.extend(index_time.to_le_bytes());
+            body.extend((values.len() as u32).to_le_bytes());
+            body.extend(((header_size + revolution * values.len() * 2) as u32).to_le_bytes());
+        }
+        for _ in 0..revolutions {
+            body.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+        }
+    }
+
+    let mut rest: Vec<u8> = offsets
+        .iter()
+        .flat_map(|offset| offset.to_le_bytes())
+        .collect();
+    rest.extend(body);
+    let checksum = rest
+        .iter()
+        .fold(0_u32, |sum, byte| sum.wrapping_add(u32::from(*byte)));
+    let mut data = b"SCP".to_vec();
+    data.extend([0x24, disk_type, revolutions, 0, end_track, 0x01, 0, 0, 0]);
+    data.extend(checksum.to_le_bytes());
+    data.extend(rest);
+    data
+}
+
+/// Build an HFE image of IBM MFM tracks from a flat image
+/// The version is 1 or 3, version 3 tracks start with an index opcode.
+pub fn hfe(data: &[u8], geometry: &Geometry, version: u8) -> Vec<u8> {
+    let tracks = split_tracks(data, geometry);
+    let track_count = tracks.iter().map(|t| t.track).max().map_or(0, |t| t + 1);
+    let mut image = Vec::from(if version == 3 {
+        &b"HXCHFEV3"[..]
+    } else {
+        &b"HXCPICFE"[..]
+    });
+    image.extend([0, track_count, geometry.heads, 0]);
+    image.extend(250_u16.to_le_bytes());
+    image.extend(300_u16.to_le_bytes());
+    image.extend([7, 1]);
+    image.extend(1_u16.to_le_bytes());
+    image.resize(512, 0xFF);
+
+    let mut track_list = Vec::new();
+    let mut track_data = Vec::new();
+    for track in 0..track_count {
+        let sides: Vec<Vec<u8>> = tracks
+            .iter()
+            .filter(|t| t.track == track)
+            .map(|t| {
+                let mut bytes = if version == 3 { vec![0xF1] } else { Vec::new() };
+                bytes.extend(pack_bits(&encode_track(t)));
+                bytes.iter().map(|byte| byte.reverse_bits()).collect()
+            })
+            .collect();
+        let side_length = sides.iter().map(Vec::len).max().unwrap_or(0);
+        let offset = 1024 + track_data.len();
+        track_list.extend(((offset / 512) as u16).to_le_bytes());
+        track_list.extend(((side_length * 2) as u16).to_le_bytes());
+        for start in (0..side_length).step_by(256) {
+            for side in 0..2 {
+                let mut chunk = sides
+                    .get(side)
+                    .and_then(|bytes| bytes.get(start..(start + 256).min(bytes.len())))
+                    .unwrap_or_default()
+                    .to_vec();
+                chunk.resize(256, 0);
+                track_data.extend(chunk);
+            }
+        }
+    }
+    track_list.resize(512, 0xFF);
+    image.extend(track_list);
+    image.extend(track_data);
+    image
+}
+
+/// Encode the data of a TD0 sector, as a repeated pattern if it is
+/// one, with run length encoding if it has runs of a byte, or as it
+/// is
+fn td0_sector_block(data: &[u8]) -> Vec<u8> {
+    if data.len() >= 2 && data.chunks(2).all(|pair| pair == &data[..2]) {
+        let mut block = vec![1];
+        block.extend(((data.len() / 2) as u16).to_le_bytes());
+        block.extend(&data[..2]);
+        return block;
+    }
+    if !data.windows(4).any(|run| run.iter().all(|b| *b == run[0])) {
+        let mut block = vec![0];
+        block.extend(data);
+        return block;
+    }
+
+    // Runs of a byte are stored as a repeated two byte pattern
+    let mut block = vec![2];
+    let mut literal: Vec<u8> = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let run = rest.iter().take_while(|b| **b == rest[0]).count() / 2 * 2;
+        if run >= 4 {
+            for chunk in literal.chunks(255) {
+                block.extend([0, chunk.len() as u8]);
+                block.extend(chunk);
+            }
+            literal.clear();
+            let count = (run / 2).min(255);
+            block.extend([1, count as u8, rest[0], rest[0]]);
+            rest = &rest[count * 2..];
+        } else {
+            literal.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    for chunk in literal.chunks(255) {
+        block.extend([0, chunk.len() as u8]);
+        block.extend(chunk);
+    }
+    block
+}
+
+/// Build a Teledisk TD0 image of a set of tracks, with a comment if
+/// it isn't empty
+/// Lines of the comment are separated by NULs.  Sectors with CRC
+/// errors or deleted data marks are flagged, and images with advanced
+/// compression are compressed with LZHUF.
+pub fn td0(tracks: &[LogicalTrack], advanced: bool, comment: &str) -> Vec<u8> {
+    let sides = tracks.iter().map(|t| t.head + 1).max().unwrap_or(1);
+    let stepping = if comment.is_empty() { 0 } else { 0x80 };
+    let mut image = Vec::from(if advanced {
+        TD0_ADVANCED_MAGIC
+    } else {
+        TD0_MAGIC
+    });
+    image.extend([0, 0x2A, 21, 0, 3, stepping, 0, sides]);
+    image.extend(td0_crc(&image).to_le_bytes());
+
+    let mut body = Vec::new();
+    if !comment.is_empty() {
+        let mut block = (comment.len() as u16).to_le_bytes().to_vec();
+        block.extend([94, 5, 1, 12, 30, 0]);
+        block.extend(comment.as_bytes());
+        body.extend(td0_crc(&block).to_le_bytes());
+        body.extend(block);
+    }
+    for track in tracks {
+        let header = [track.sectors.len() as u8, track.track, track.head];
+        body.extend(header);
+        body.push(td0_crc(&header) as u8);
+        for sector in &track.sectors {
+            let flags = if sector.crc_error {
+                SECTOR_CRC_ERROR
+            } else {
+                0
+            } | if sector.deleted { SECTOR_DELETED } else { 0 };
+            body.extend([
+                sector.id.track.get(),
+                sector.id.head.get(),
+                sector.id.sector.get(),
+                sector.size_code(),
+                flags,
+                td0_crc(&sector.data) as u8,
+            ]);
+            let block = td0_sector_block(&sector.data);
+            body.extend((block.len() as u16).to_le_bytes());
+            body.extend(block);
+        }
+    }
+    body.push(0xFF);
+
+    if advanced {
+        image.extend(lzhuf_compress(&body));
+    } else {
+        image.extend(body);
+    }
+    image
+}
+
+/// Build an ImageDisk IMD image of a set of tracks, with a comment
+/// Tracks are written as MFM at 250 kbit/s.  Sectors filled with one
+/// byte are compressed, sectors with CRC errors or deleted data marks
+/// are flagged, and sector IDs that differ from the physical track are
+/// written to cylinder and head maps.
+pub fn imd(tracks: &[LogicalTrack], comment: &str) -> Vec<u8> {
+    let mut image = b"IMD 1.18: 01/06/1994 12:30:00\r\n".to_vec();
+    image.extend(comment.as_bytes());
+    image.push(IMD_COMMENT_END);
+    for track in tracks {
+        let cylinder_map = track
+            .sectors
+            .iter()
+            .any(|s| s.id.track.get() != track.track);
+        let head_map = track.sectors.iter().any(|s| s.id.head.get() != track.head);
+        let flags =
+            if cylinder_map { CYLINDER_MAP } else { 0 } | if head_map { HEAD_MAP } else { 0 };
+        let size_code = track.sectors.first().map_or(2, LogicalSector::size_code);
+        image.extend([
+            5,
+            track.track,
+            track.head | flags,
+            track.sectors.len() as u8,
+            size_code,
+        ]);
+        image.extend(track.sectors.iter().map(|s| s.id.sector.get()));
+        if cylinder_map {
+            image.extend(track.sectors.iter().map(|s| s.id.track.get()));
+        }
+        if head_map {
+            image.extend(track.sectors.iter().map(|s| s.id.head.get()));
+        }
+        for sector in &track.sectors {
+            let compressed = sector.data.iter().all(|b| *b == sector.data[0]);
+            let record = 1
+                + u8::from(compressed)
+                + if sector.deleted { 2 } else { 0 }
+                + if sector.crc_error { 4 } else { 0 };
+            image.push(record);
+            if compressed {
+                image.push(sector.data[0]);
+            } else {
+                image.extend(&sector.data);
+            }
+        }
+    }
+    image
+}
+
+/// Build a STX image from a flat Atari ST image, without protection
+pub fn stx(data: &[u8], geometry: &Geometry) -> Vec<u8> {
+    write_stx(&split_tracks(data, geometry), &BTreeMap::new())
+}
+
+/// The layout of a double sided, nine sector Atari ST disk, as TOS
+/// formats it
+const ATARI_ST_BPB: BiosParameterBlock = BiosParameterBlock {
+    bytes_per_sector: 512,
+    sectors_per_cluster: 2,
+    reserved_sectors: 1,
+    fat_count: 2,
+    root_entries: 112,
+    total_sectors: 1440,
+    media_descriptor: 0xF9,
+    sectors_per_fat: 5,
+    sectors_per_track: 9,
+    heads: 2,
+    hidden_sectors: 0,
+};
+
+/// The modification date of generated FAT files, 1989-04-12
+const FAT_DATE: u16 = (9 << 9) | (4 << 5) | 12;
+
+/// Write data to newly allocated clusters of an Atari ST image
+/// Returns the first cluster, zero for empty data.
+fn fat_write_chain(
+    data: &mut [u8],
+    fat: &mut FileAllocationTable,
+    contents: &[u8],
+) -> std::result::Result<u16, Error> {
+    if contents.is_empty() {
+        return Ok(0);
+    }
+    let bpb = ATARI_ST_BPB;
+    let cluster_size = bpb.cluster_size();
+    let clusters = fat.allocate(
+        contents.len().div_ceil(cluster_size),
+        AllocationStrategy::FirstFit,
+        None,
+    )?;
+    for (cluster, chunk) in clusters.iter().zip(contents.chunks(cluster_size)) {
+        if let Some(sectors) = bpb.cluster_sectors(*cluster) {
+            let start = sectors.start * usize::from(bpb.bytes_per_sector);
+            data[start..start + chunk.len()].copy_from_slice(chunk);
+        }
+    }
+    Ok(clusters[0] as u16)
+}
+
+/// Build a flat double sided Atari ST image with a FAT12 filesystem
+/// A file path can hold one directory, e.g. "AUTO/PROGRAM.PRG", and the
+/// directories are created after the files in the root directory.
+pub fn atari_st_fat(label: &str, files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    let bpb = ATARI_ST_BPB;
+    let sector_size = usize::from(bpb.bytes_per_sector);
+    let mut data = vec![0_u8; usize::from(bpb.total_sectors) * sector_size];
+
+    // A BRA.S over the parameter block and a serial number
+    data[0..2].copy_from_slice(&[0x60, 0x38]);
+    data[8..11].copy_from_slice(&[0x12, 0x34, 0x56]);
+    data[11..11 + 19].copy_from_slice(&bpb.as_vec()?);
+
+    let mut fat = FileAllocationTable::new(bpb.fat_type(), bpb.clusters(), bpb.media_descriptor);
+    let mut root = vec![DirectoryEntry::volume_label(label)];
+    let mut directories: BTreeMap<&str, Vec<DirectoryEntry>> = BTreeMap::new();
+    for (path, contents) in files {
+        let start_cluster = fat_write_chain(&mut data, &mut fat, contents)?;
+        let (directory, name) = match path.split_once('/') {
+            Some((directory, name)) => (Some(directory), name),
+            None => (None, *path),
+        };
+        let mut entry = DirectoryEntry::new(
+            name,
+            ATTRIBUTE_ARCHIVE,
+            start_cluster,
+            contents.len() as u32,
+        );
+        entry.date = FAT_DATE;
+        match directory {
+            Some(directory) => directories.entry(directory).or_default().push(entry),
+            None => root.push(entry),
+        }
+    }
+
+    for (name, entries) in directories {
+        let size = (entries.len() + 2) * DIRECTORY_ENTRY_SIZE;
+        let start_cluster = fat_write_chain(&mut data, &mut fat, &vec![0; size])?;
+        let mut directory =
+            DirectoryEntry::new(".", ATTRIBUTE_DIRECTORY, start_cluster, 0).as_vec()?;
+        directory.extend(DirectoryEntry::new("..", ATTRIBUTE_DIRECTORY, 0, 0).as_vec()?);
+        for entry in entries {
+            directory.extend(entry.as_vec()?);
+        }
+        let start = bpb.cluster_sectors(u32::from(start_cluster)).unwrap().start * sector_size;
+        data[start..start + directory.len()].copy_from_slice(&directory);
+        let mut entry = DirectoryEntry::new(name, ATTRIBUTE_DIRECTORY, start_cluster, 0);
+        entry.date = FAT_DATE;
+        root.push(entry);
+    }
+
+    let table = fat.to_bytes();
+    for copy in 0..usize::from(bpb.fat_count) {
+        let start = (usize::from(bpb.reserved_sectors) + copy * usize::from(bpb.sectors_per_fat))
+            * sector_size;
+        data[start..start + table.len()].copy_from_slice(&table);
+    }
+    let mut start = bpb.root_directory_sector() * sector_size;
+    for entry in root {
+        data[start..start + DIRECTORY_ENTRY_SIZE].copy_from_slice(&entry.as_vec()?);
+        start += DIRECTORY_ENTRY_SIZE;
+    }
+
+    Ok(data)
+}
+
+/// The number of blocks on a 140K ProDOS disk
+const PRODOS_BLOCKS: u16 = 280;
+
+/// The start of the ProDOS boot block
+const PRODOS_BOOT_MAGIC: [u8; 4] = [0x01, 0x38, 0xB0, 0x03];
+
+/// The blocks of the volume directory on generated ProDOS disks
+const PRODOS_VOLUME_DIRECTORY_BLOCKS: std::ops::Range<u16> = 2..6;
+
+/// The block holding the volume bitmap on generated ProDOS disks
+const PRODOS_BITMAP_BLOCK: u16 = 6;
+
+/// The modification date of generated ProDOS files, 12-APR-89
+const PRODOS_DATE: u16 = (89 << 9) | (4 << 5) | 12;
+
+/// Build a directory entry for a generated ProDOS file
+fn prodos_entry(
+    name: &str,
+    storage_type: StorageType,
+    file_type: u8,
+    key_pointer: u16,
+    blocks_used: u16,
+    eof: u32,
+) -> ProDOSFileEntry {
+    ProDOSFileEntry {
+        storage_type,
+        name: String::from(name),
+        file_type,
+        key_pointer,
+        blocks_used,
+        eof,
+        created: (PRODOS_DATE, 0),
+        version: 0,
+        min_version: 0,
+        access: 0xE3,
+        aux_type: 0x2000,
+        modified: (PRODOS_DATE, 0),
+        header_pointer: 0,
+    }
+}
+
+/// Write the blocks of a directory, a header entry followed by the
+/// file entries, linking the blocks to each other
+fn write_prodos_directory(
+    volume: &mut [u8],
+    blocks: &[u16],
+    header: &[u8],
+    entries: &[ProDOSFileEntry],
+) -> std::result::Result<(), Error> {
+    let mut entry_data = vec![header.to_vec()];
+    for entry in entries {
+        let mut entry = entry.clone();
+        entry.header_pointer = blocks[0];
+        entry_data.push(entry.as_vec()?);
+    }
+    let per_block = usize::from(ENTRIES_PER_BLOCK);
+    let mut chunks = entry_data.chunks(per_block);
+    for (n, number) in blocks.iter().enumerate() {
+        let start = usize::from(*number) * BLOCK_SIZE;
+        let previous = if n == 0 { 0 } else { blocks[n - 1] };
+        let next = blocks.get(n + 1).copied().unwrap_or(0);
+        volume[start..start + 2].copy_from_slice(&previous.to_le_bytes());
+        volume[start + 2..start + 4].copy_from_slice(&next.to_le_bytes());
+        for (index, entry) in chunks.next().unwrap_or_default().iter().enumerate() {
+            let offset = start + DIRECTORY_ENTRIES_OFFSET + index * usize::from(ENTRY_LENGTH);
+            volume[offset..offset + entry.len()].copy_from_slice(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Build a 140K ProDOS disk in ProDOS block order, a .po image
+/// Files of one block are seedling files and larger files are sapling
+/// files, up to 128K.  A file path can hold one directory, e.g.
+/// "GAMES/CHESS", and the directories are created after the files in
+/// the volume directory.
+pub fn prodos(volume_name: &str, files: &[(&str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    let mut volume = vec![0_u8; usize::from(PRODOS_BLOCKS) * BLOCK_SIZE];
+    volume[..PRODOS_BOOT_MAGIC.len()].copy_from_slice(&PRODOS_BOOT_MAGIC);
+    let mut next_block = PRODOS_BITMAP_BLOCK + 1;
+    let mut allocate = |count: usize| {
+        let blocks: Vec<u16> = (next_block..next_block + count as u16).collect();
+        next_block += count as u16;
+        blocks
+    };
+
+    let mut root: Vec<ProDOSFileEntry> = Vec::new();
+    let mut directories: BTreeMap<&str, Vec<ProDOSFileEntry>> = BTreeMap::new();
+    for (path, data) in files {
+        let data_blocks = data.len().div_ceil(BLOCK_SIZE).max(1);
+        if data_blocks > 256 {
+            return Err(Error::new(ErrorKind::Unimplemented(String::from(
+                "Generated ProDOS files can't be larger than 128K",
+            ))));
+        }
+        let (storage_type, key_pointer, blocks_used) = if data_blocks == 1 {
+            let block = allocate(1)[0];
+            let start = usize::from(block) * BLOCK_SIZE;
+            volume[start..start + data.len()].copy_from_slice(data);
+            (StorageType::Seedling, block, 1)
+        } else {
+            let index = allocate(1)[0];
+            let blocks = allocate(data_blocks);
+            for (n, (block, chunk)) in blocks.iter().zip(data.chunks(BLOCK_SIZE)).enumerate() {
+                let start = usize::from(*block) * BLOCK_SIZE;
+                volume[start..start + chunk.len()].copy_from_slice(chunk);
+                let index_start = usize::from(index) * BLOCK_SIZE;
+                volume[index_start + n] = *block as u8;
+                volume[index_start + 256 + n] = (*block >> 8) as u8;
+            }
+            (StorageType::Sapling, index, data_blocks as u16 + 1)
+        };
+        let (directory, name) = match path.split_once('/') {
+            Some((directory, name)) => (Some(directory), name),
+            None => (None, *path),
+        };
+        let entry = prodos_entry(
+            name,
+            storage_type,
+            0x06,
+            key_pointer,
+            blocks_used,
+            data.len() as u32,
+        );
+        match directory {
+            Some(directory) => directories.entry(directory).or_default().push(entry),
+            None => root.push(entry),
+        }
+    }
+
+    let per_block = usize::from(ENTRIES_PER_BLOCK);
+    for (name, entries) in directories {
+        let blocks = allocate((entries.len() + 1).div_ceil(per_block));
+        let mut header = VolumeDirectoryHeader {
+            name: String::from(name),
+            created: (PRODOS_DATE, 0),
+            version: 0,
+            min_version: 0,
+            access: 0xE3,
+            entry_length: ENTRY_LENGTH,
+            entries_per_block: ENTRIES_PER_BLOCK,
+            file_count: entries.len() as u16,
+            bit_map_pointer: PRODOS_VOLUME_DIRECTORY_BLOCKS.start,
+            total_blocks: 0,
+        }
+        .as_vec()?;
+        // A subdirectory header, with the parent's key block where the
+        // volume bitmap pointer is
+        header[0] = 0xE0 | (name.len() as u8);
+        header[0x10] = 0x75;
+        let (key_pointer, blocks_used) = (blocks[0], blocks.len() as u16);
+        write_prodos_directory(&mut volume, &blocks, &header, &entries)?;
+        root.push(prodos_entry(
+            name,
+            StorageType::Subdirectory,
+            0x0F,
+            key_pointer,
+            blocks_used,
+            u32::from(blocks_used) * BLOCK_SIZE as u32,
+        ));
+    }
+
+    let volume_blocks: Vec<u16> = PRODOS_VOLUME_DIRECTORY_BLOCKS.collect();
+    if root.len() + 1 > volume_blocks.len() * per_block {
+        return Err(Error::new(ErrorKind::Unimplemented(String::from(
+            "Too many files for the volume directory",
+        ))));
+    }
+    let header = VolumeDirectoryHeader {
+        name: String::from(volume_name),
+        created: (PRODOS_DATE, 0),
+        version: 0,
+        min_version: 0,
+        access: 0xC3,
+        entry_length: ENTRY_LENGTH,
+        entries_per_block: ENTRIES_PER_BLOCK,
+        file_count: root.len() as u16,
+        bit_map_pointer: PRODOS_BITMAP_BLOCK,
+        total_blocks: PRODOS_BLOCKS,
+    };
+    write_prodos_directory(&mut volume, &volume_blocks, &header.as_vec()?, &root)?;
+
+    let bitmap = usize::from(PRODOS_BITMAP_BLOCK) * BLOCK_SIZE;
+    for block in next_block..PRODOS_BLOCKS {
+        volume[bitmap + usize::from(block / 8)] |= 0x80 >> (block % 8);
+    }
+
+    Ok(volume)
+}
+
+/// The number of blocks on a double density Amiga disk
+const ADF_BLOCKS: u32 = 1760;
+
+/// The root block of a double density Amiga disk
+const ADF_ROOT_BLOCK: u32 = ADF_BLOCKS / 2;
+
+/// The block holding the free block bitmap on generated Amiga disks
+const ADF_BITMAP_BLOCK: u32 = ADF_ROOT_BLOCK + 1;
+
+/// The date of generated Amiga files and volumes, 1989-04-12 in days
+/// since 1978-01-01
+const ADF_DAYS: u32 = 4119;
+
+/// Write a big-endian long into a block of an Amiga image
+fn adf_put(data: &mut [u8], block: u32, offset: usize, value: u32) {
+    let start = block as usize * AMIGA_BLOCK_SIZE + offset;
+    data[start..start + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Read a big-endian long from a block of an Amiga image
+fn adf_get(data: &[u8], block: u32, offset: usize) -> u32 {
+    let start = block as usize * AMIGA_BLOCK_SIZE + offset;
+    u32::from_be_bytes(data[start..start + 4].try_into().unwrap())
+}
+
+/// Write the header fields shared by the root block and file and
+/// directory headers: the type, name, date and secondary type
+fn adf_header(data: &mut [u8], block: u32, name: &str, secondary_type: i32) {
+    adf_put(data, block, 0, T_HEADER);
+    adf_put(data, block, 420, ADF_DAYS);
+    adf_put(data, block, 508, secondary_type as u32);
+    let start = block as usize * AMIGA_BLOCK_SIZE + 432;
+    let name = &name.as_bytes()[..name.len().min(MAX_NAME_LENGTH)];
+    data[start] = name.len() as u8;
+    data[start + 1..start + 1 + name.len()].copy_from_slice(name);
+}
+
+/// Add an entry to the hash table of a directory, in front of the
+/// entries with the same hash
+fn adf_link(data: &mut [u8], directory: u32, entry: u32, name: &str) {
+    let slot = 24 + 4 * name_hash(name.as_bytes(), false);
+    adf_put(data, entry, 496, adf_get(data, directory, slot));
+    adf_put(data, entry, 500, directory);
+    adf_put(data, directory, slot, entry);
+}
+
+/// Set the checksum of a block
+fn adf_seal(data: &mut [u8], block: u32, offset: usize) {
+    let start = block as usize * AMIGA_BLOCK_SIZE;
+    let checksum = block_checksum(&data[start..start + AMIGA_BLOCK_SIZE], offset);
+    adf_put(data, block, offset, checksum);
+}
+
+/// Build an 880K Amiga ADF image with an OFS or FFS filesystem
+/// A file path can hold one directory, e.g. "S/Startup-Sequence", and
+/// the directories are created before the files.  Files with more than
+/// 72 data blocks get extension blocks.
+pub fn adf(
+    volume_name: &str,
+    ffs: bool,
+    files: &[(&str, &[u8])],
+) -> std::result::Result<Vec<u8>, Error> {
+    let mut data = vec![0_u8; ADF_BLOCKS as usize * AMIGA_BLOCK_SIZE];
+    data[..4].copy_from_slice(&[b'D', b'O', b'S', u8::from(ffs)]);
+    adf_put(&mut data, 0, 8, ADF_ROOT_BLOCK);
+
+    let mut next_block = ADF_BITMAP_BLOCK + 1;
+    let mut allocate = |count: usize| -> std::result::Result<Vec<u32>, Error> {
+        let end = next_block + count as u32;
+        if end > ADF_BLOCKS {
+            return Err(Error::new(ErrorKind::Message(format!(
+                "{} blocks are needed but only {} are free",
+                count,
+                ADF_BLOCKS - next_block
+            ))));
+        }
+        let blocks = (next_block..end).collect();
+        next_block = end;
+        Ok(blocks)
+    };
+    let mut headers = vec![ADF_ROOT_BLOCK];
+
+    adf_header(&mut data, ADF_ROOT_BLOCK, volume_name, ST_ROOT);
+    adf_put(&mut data, ADF_ROOT_BLOCK, 12, TABLE_SIZE as u32);
+    adf_put(&mut data, ADF_ROOT_BLOCK, 312, 0xFFFF_FFFF);
+    adf_put(&mut data, ADF_ROOT_BLOCK, 316, ADF_BITMAP_BLOCK);
+    adf_put(&mut data, ADF_ROOT_BLOCK, 472, ADF_DAYS);
+    adf_put(&mut data, ADF_ROOT_BLOCK, 484, ADF_DAYS);
+
+    let mut directories: BTreeMap<&str, u32> = BTreeMap::new();
+    for (path, _) in files {
+        if let Some((name, _)) = path.split_once('/') {
+            if !directories.contains_key(name) {
+                let header = allocate(1)?[0];
+                adf_header(&mut data, header, name, ST_USERDIR);
+                adf_put(&mut data, header, 4, header);
+                adf_link(&mut data, ADF_ROOT_BLOCK, header, name);
+                directories.insert(name, header);
+                headers.push(header);
+            }
+        }
+    }
+
+    let per_block = if ffs { AMIGA_BLOCK_SIZE } else { OFS_DATA_SIZE };
+    for (path, contents) in files {
+        let (directory, name) = match path.split_once('/') {
+            Some((directory, name)) => (directories[directory], name),
+            None => (ADF_ROOT_BLOCK, *path),
+        };
+        let header = allocate(1)?[0];
+        let blocks = allocate(contents.len().div_ceil(per_block))?;
+        let tables: Vec<&[u32]> = blocks.chunks(TABLE_SIZE).collect();
+        let extensions = allocate(tables.len().saturating_sub(1))?;
+
+        adf_header(&mut data, header, name, ST_FILE);
+        adf_put(&mut data, header, 324, contents.len() as u32);
+        adf_put(&mut data, header, 16, blocks.first().copied().unwrap_or(0));
+        adf_link(&mut data, directory, header, name);
+        let table_blocks = std::iter::once(header).chain(extensions.iter().copied());
+        for (n, (table_block, table)) in table_blocks.zip(&tables).enumerate() {
+            if table_block != header {
+                adf_put(&mut data, table_block, 0, T_LIST);
+                adf_put(&mut data, table_block, 500, header);
+                adf_put(&mut data, table_block, 508, ST_FILE as u32);
+            }
+            adf_put(&mut data, table_block, 4, table_block);
+            adf_put(&mut data, table_block, 8, table.len() as u32);
+            for (k, number) in table.iter().enumerate() {
+                adf_put(
+                    &mut data,
+                    table_block,
+                    24 + 4 * (TABLE_SIZE - 1 - k),
+                    *number,
+                );
+            }
+            adf_put(
+                &mut data,
+                table_block,
+                504,
+                extensions.get(n).copied().unwrap_or(0),
+            );
+            headers.push(table_block);
+        }
+        if tables.is_empty() {
+            adf_put(&mut data, header, 4, header);
+            headers.push(header);
+        }
+
+        for (n, (number, chunk)) in blocks.iter().zip(contents.chunks(per_block)).enumerate() {
+            let start = *number as usize * AMIGA_BLOCK_SIZE;
+            if ffs {
+                data[start..start + chunk.len()].copy_from_slice(chunk);
+                continue;
+            }
+            adf_put(&mut data, *number, 0, T_DATA);
+            adf_put(&mut data, *number, 4, header);
+            adf_put(&mut data, *number, 8, n as u32 + 1);
+            adf_put(&mut data, *number, 12, chunk.len() as u32);
+            adf_put(
+                &mut data,
+                *number,
+                16,
+                blocks.get(n + 1).copied().unwrap_or(0),
+            );
+            let payload = start + OFS_DATA_HEADER_SIZE;
+            data[payload..payload + chunk.len()].copy_from_slice(chunk);
+            adf_seal(&mut data, *number, HEADER_CHECKSUM_OFFSET);
+        }
+    }
+
+    for header in headers {
+        adf_seal(&mut data, header, HEADER_CHECKSUM_OFFSET);
+    }
+
+    // The bitmap covers blocks 2 to 1759, a set bit is free
+    for number in (2..ADF_ROOT_BLOCK).chain(next_block..ADF_BLOCKS) {
+        let bit = (number - 2) as usize;
+        let offset = 4 + 4 * (bit / 32);
+        let long = adf_get(&data, ADF_BITMAP_BLOCK, offset) | (1 << (bit % 32));
+        adf_put(&mut data, ADF_BITMAP_BLOCK, offset, long);
+    }
+    adf_seal(&mut data, ADF_BITMAP_BLOCK, 0);
+
+    Ok(data)
+}
+
+/// Build a flat CP/M image in a format, holding files for a user
+/// number
+/// Names are split into an eight character name and three character
+/// extension at the dot and stored as given.  Files are padded to
+/// whole records with the 0x1A end of file marker and the sectors are
+/// laid out through the format's skew table.  The system tracks are
+/// left formatted.
+pub fn cpm(format: &CpmFormat, files: &[(u8, &str, &[u8])]) -> std::result::Result<Vec<u8>, Error> {
+    let dpb = &format.dpb;
+    let block_size = dpb.block_size();
+    let data_tracks = (dpb.blocks() * block_size).div_ceil(dpb.track_size());
+    let size = (usize::from(dpb.reserved_tracks) + data_tracks) * dpb.track_size();
+    let mut volume = CpmVolume {
+        data: vec![0xE5; size],
+        format: format.clone(),
+        directory: Vec::new(),
+        files: Vec::new(),
+    };
+
+    let mut directory = Vec::new();
+    let mut next_block = dpb.directory_blocks();
+    let entry_records = dpb.blocks_per_entry() * block_size / RECORD_SIZE;
+    for (user, name, data) in files {
+        let (name, extension) = name.split_once('.').unwrap_or((name, ""));
+        let records = data.len().div_ceil(RECORD_SIZE);
+        let blocks = data.len().div_ceil(block_size);
+        if next_block + blocks > dpb.blocks() {
+            return Err(Error::new(ErrorKind::Message(format!(
+                "{} blocks are needed but only {} are free",
+                blocks,
+                dpb.blocks() - next_block
+            ))));
+        }
+
+        let mut padded = data.to_vec();
+        padded.resize(blocks * block_size, 0x1A);
+        for (index, chunk) in padded.chunks(block_size).enumerate() {
+            for (sector, sector_data) in volume
+                .block_sectors(next_block + index)
+                .into_iter()
+                .zip(chunk.chunks(format.sector_size))
+            {
+                let offset = sector * format.sector_size;
+                volume.data[offset..offset + format.sector_size].copy_from_slice(sector_data);
+            }
+        }
+
+        let entries = records.div_ceil(entry_records).max(1);
+        for entry in 0..entries {
+            let end = records.min((entry + 1) * entry_records);
+            let extent = end.saturating_sub(1) / RECORDS_PER_EXTENT;
+            let mut raw = vec![*user];
+            raw.extend(pad_name::<8>(name));
+            raw.extend(pad_name::<3>(extension));
+            raw.extend([
+                (extent & 0x1F) as u8,
+                0,
+                (extent >> 5) as u8,
+                (end - extent * RECORDS_PER_EXTENT) as u8,
+            ]);
+            let first = next_block + entry * dpb.blocks_per_entry();
+            let last = (next_block + blocks).min(first + dpb.blocks_per_entry());
+            let mut allocation = Vec::new();
+            for block in first..last {
+                if dpb.is_large() {
+                    allocation.extend((block as u16).to_le_bytes());
+                } else {
+                    allocation.push(block as u8);
+                }
+            }
+            allocation.resize(16, 0);
+            raw.extend(allocation);
+            directory.extend(raw);
+        }
+        next_block += blocks;
+    }
+
+    if directory.len() > dpb.directory_entries() * CPM_DIRECTORY_ENTRY_SIZE {
+        return Err(Error::new(ErrorKind::Message(format!(
+            "{} directory entries are needed but the directory holds {}",
+            directory.len() / CPM_DIRECTORY_ENTRY_SIZE,
+            dpb.directory_entries()
+        ))));
+    }
+    directory.resize(dpb.directory_blocks() * block_size, 0xE5);
+    for (block, chunk) in directory.chunks(block_size).enumerate() {
+        for (sector, sector_data) in volume
+            .block_sectors(block)
+            .into_iter()
+            .zip(chunk.chunks(format.sector_size))
+        {
+            let offset = sector * format.sector_size;
+            volume.data[offset..offset + format.sector_size].copy_from_slice(sector_data);
+        }
+    }
+
+    Ok(volume.data)
+}
+
+/// Build a CPCEMU DSK image, or an extended DSK image, from a set of
+/// tracks
+/// The tracks must be complete and in image order, with the sides of
+/// each track next to each other.  Sectors with CRC errors get the
+/// data error status bits and the weak sectors of an extended image
+/// are stored as three copies, the second and third with their bits
+/// flipped.  Standard images use the size of the largest track for
+/// every track.
+pub fn dsk(tracks: &[LogicalTrack], extended: bool, weak: &[SectorId]) -> Vec<u8> {
+    let sides = tracks.iter().map(|track| track.head).max().unwrap_or(0) + 1;
+    let mut blocks: Vec<Vec<u8>> = Vec::new();
+    for track in tracks {
+        let size_code = track.sectors.first().map_or(2, LogicalSector::size_code);
+        let mut block = b"Track-Info\r\n\0\0\0\0".to_vec();
+        block.extend([track.track, track.head, 0, 0, size_code]);
+        block.extend([track.sectors.len() as u8, 0x4E, 0xE5]);
+        let mut sector_data = Vec::new();
+        for sector in &track.sectors {
+            let id = SectorId::new(track.track, track.head, sector.id.sector);
+            let copies = if extended && weak.contains(&id) { 3 } else { 1 };
+            for copy in 0..copies {
+                sector_data.extend(sector.data.iter().map(|byte| byte ^ copy as u8));
+            }
+            let (status_1, status_2) = if sector.crc_error {
+                (0x20, 0x20)
+            } else {
+                (0, 0)
+            };
+            block.extend([
+                id.track.get(),
+                id.head.get(),
+                id.sector.get(),
+                sector.size_code(),
+            ]);
+            block.extend([status_1, status_2]);
+            block.extend((sector.data.len() as u16 * copies).to_le_bytes());
+        }
+        block.resize(256, 0);
+        block.extend(sector_data);
+        blocks.push(block);
+    }
+
+    let mut data = Vec::new();
+    if extended {
+        data.extend(b"EXTENDED CPC DSK File\r\nDisk-Info\r\nimage-rider\0\0\0");
+        data.extend([(tracks.len() / usize::from(sides)) as u8, sides, 0, 0]);
+        for block in blocks.iter_mut() {
+            block.resize(block.len().div_ceil(256) * 256, 0);
+            data.push((block.len() / 256) as u8);
+        }
+    } else {
+        data.extend(b"MV - CPCEMU Disk-File\r\nDisk-Info\r\nimage-rider\0\0\0");
+        let size = blocks.iter().map(Vec::len).max().unwrap_or(256);
+        data.extend([(tracks.len() / usize::from(sides)) as u8, sides]);
+        data.extend((size as u16).to_le_bytes());
+        for block in blocks.iter_mut() {
+            block.resize(size, 0xE5);
+        }
+    }
+    data.resize(256, 0);
+    data.extend(blocks.concat());
+    data
+}
+
+/// Build a G64 image from a 35 track D64 image
+/// Each track holds its sectors in order, each a header block and a
+/// data block after five sync bytes with the standard gaps, padded to
+/// the length of a track in its speed zone.  The data checksums of the
+/// damaged sectors, by track and sector, are flipped.
+pub fn g64(d64: &[u8], damaged: &[(u8, u8)]) -> Vec<u8> {
+    const TRACK_ENTRIES: usize = 84;
+    const MAX_TRACK_SIZE: usize = 7928;
+    const TRACK_SIZES: [usize; 4] = [6250, 6666, 7142, 7692];
+    let geometry = Geometry::commodore_1541(35);
+    // The disk ID in the BAM, written second byte first
+    let disk_id = [d64[0x165A3], d64[0x165A2]];
+
+    let mut offsets = vec![0_u32; TRACK_ENTRIES];
+    let mut zones = vec![0_u32; TRACK_ENTRIES];
+    let mut tracks = Vec::new();
+    let tracks_start = 12 + TRACK_ENTRIES * 8;
+    for track in 1..=35_u8 {
+        let mut bytes = Vec::new();
+        for sector in 0..geometry.sectors_per_track[usize::from(track - 1)] {
+            let sector_data = geometry
+                .sector(d64, &SectorId::new(track, 0, sector))
+                .unwrap();
+            let checksum = sector ^ track ^ disk_id[0] ^ disk_id[1];
+            bytes.extend([0xFF; 5]);
+            bytes.extend(gcr_encode(&[
+                0x08, checksum, sector, track, disk_id[0], disk_id[1], 0x0F, 0x0F,
+            ]));
+            bytes.extend([0x55; 9]);
+
+            let mut checksum = sector_data.iter().fold(0, |acc, byte| acc ^ byte);
+            if damaged.contains(&(track, sector)) {
+                checksum ^= 0xFF;
+            }
+            let mut block = vec![0x07];
+            block.extend(sector_data);
+            block.extend([checksum, 0, 0]);
+            bytes.extend([0xFF; 5]);
+            bytes.extend(gcr_encode(&block));
+            bytes.extend([0x55; 8]);
+        }
+        let zone = speed_zone_for_track(track);
+        bytes.resize(TRACK_SIZES[usize::from(zone)], 0x55);
+
+        let entry = usize::from(track - 1) * 2;
+        offsets[entry] = (tracks_start + tracks.len()) as u32;
+        zones[entry] = u32::from(zone);
+        tracks.extend((bytes.len() as u16).to_le_bytes());
+        bytes.resize(MAX_TRACK_SIZE, 0);
+        tracks.extend(bytes);
+    }
+
+    let mut data = b"GCR-1541\0".to_vec();
+    data.push(TRACK_ENTRIES as u8);
+    data.extend((MAX_TRACK_SIZE as u16).to_le_bytes());
+    data.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+    data.extend(zones.iter().flat_map(|zone| zone.to_le_bytes()));
+    data.extend(tracks);
+    data
+}
+
+/// Build a .nes cartridge image
+/// Each PRG bank is filled with its bank number, each CHR bank with 80
+/// plus its bank number and the trainer with 70.  The image has
+/// vertical mirroring and battery-backed RAM, and an NES 2.0 header if
+/// nes20 is set, otherwise the mapper is cut to eight bits.
+pub fn nes(mapper: u16, prg_banks: u8, chr_banks: u8, trainer: bool, nes20: bool) -> Vec<u8> {
+    let mut data = NES_MAGIC.to_vec();
+    data.extend([
+        prg_banks,
+        chr_banks,
+        (mapper as u8) << 4 | u8::from(trainer) << 2 | 0x03,
+        (mapper as u8) & 0xF0 | if nes20 { 0x08 } else { 0 },
+    ]);
+    data.push(if nes20 { (mapper >> 8) as u8 & 0x0F } else { 0 });
+    data.resize(NES_HEADER_SIZE, 0);
+    if trainer {
+        data.extend([0x70; TRAINER_SIZE]);
+    }
+    for bank in 0..prg_banks {
+        data.extend(vec![bank; PRG_BANK_SIZE]);
+    }
+    for bank in 0..chr_banks {
+        data.extend(vec![0x80 + bank; CHR_BANK_SIZE]);
+    }
+    data
+}
+
+/// Build a 2600 cartridge image with a number of 4K banks
+/// Each bank is filled with its bank number, and banked cartridges
+/// start each bank with an LDA of the 1FF9 hotspot, which every
+/// Atari scheme has.
+pub fn atari_2600(banks: u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    for bank in 0..banks {
+        let mut bytes = vec![bank; ATARI_2600_BANK_SIZE];
+        if banks > 1 {
+            bytes[..3].copy_from_slice(&[0xAD, 0xF9, 0x1F]);
+        }
+        data.extend(bytes);
+    }
+    data
+}
+
+/// Build an .a78 cartridge image
+/// Each 16K of the ROM is filled with its bank number.
+pub fn a78(title: &str, rom_size: usize, cart_type: u16) -> Vec<u8> {
+    let mut data = vec![3];
+    data.extend(A78_MAGIC);
+    data.resize(17, 0);
+    data.extend(title.bytes().take(32));
+    data.resize(49, 0);
+    data.extend((rom_size as u32).to_be_bytes());
+    data.extend(cart_type.to_be_bytes());
+    data.extend([1, 1, 0]);
+    data.resize(100, 0);
+    data.extend(b"ACTUAL CART DATA STARTS HERE");
+    for n in 0..rom_size {
+        data.push((n / SUPERGAME_BANK_SIZE) as u8);
+    }
+    data
+}
+
+/// Build an SNES cartridge image with a number of banks
+/// The map mode picks the layout, 32K LoROM banks or 64K HiROM banks,
+/// and each bank is filled with its bank number.  The internal header
+/// has a valid checksum and a reset vector of 8000.
+pub fn snes(title: &str, map_mode: u8, banks: u8, copier_header: bool) -> Vec<u8> {
+    let layout = if map_mode & 0x01 != 0 {
+        SNESMapMode::HiROM
+    } else {
+        SNESMapMode::LoROM
+    };
+    let mut rom = Vec::new();
+    for bank in 0..banks {
+        rom.extend(vec![bank; layout.bank_size()]);
+    }
+    let offset = layout.header_offset();
+    let mut header = pad_name::<21>(title).to_vec();
+    header.extend([
+        map_mode,
+        0x00,
+        rom.len().ilog2() as u8 - 10,
+        0,
+        0x01,
+        0x01,
+        0,
+    ]);
+    header.extend([0xFF, 0xFF, 0x00, 0x00]);
+    header.resize(0x3C, 0);
+    header.extend(0x8000_u16.to_le_bytes());
+    rom[offset..offset + header.len()].copy_from_slice(&header);
+    let checksum = rom_checksum(&rom);
+    rom[offset + 0x1C..offset + 0x20]
+        .copy_from_slice(&[(!checksum).to_le_bytes(), checksum.to_le_bytes()].concat());
+
+    let mut data = if copier_header {
+        vec![0; COPIER_HEADER_SIZE]
+    } else {
+        Vec::new()
+    };
+    data.extend(rom);
+    data
+}
+
+/// Pad a name with spaces, cutting it if it's too long
+fn pad_name<const N: usize>(name: &str) -> [u8; N] {
+    let mut padded = [b' '; N];
+    for (byte, c) in padded.iter_mut().zip(name.bytes()) {
+        *byte = c;
+    }
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{adf, apple_binary, apple_dos_33, atari_st_fat, atari_st_sectors, cpm, d64, d71};
+    use super::{dsk, nib_from_dos_order, prodos, stx};
+    use super::{woz_from_dos, APPLE_VOLUME, NIB_TRACK_SIZE};
+    use crate::disk_format::apple::nibble::{parse_nib_disk, SectorOrder};
+    use crate::disk_format::apple::prodos::sector_order_image;
+    use crate::disk_format::cpm::dpb::CpmFormat;
+    use crate::disk_format::geometry::{Geometry, SectorId, Track};
+    use crate::disk_format::image::{
+        disk_image_data, disk_image_file_data, disk_image_usage, DiskImage, DiskImageParser,
+        DiskImageSaver,
+    };
+    use crate::disk_format::logical::split_tracks;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::usage::SectorUsage;
+    use crate::options::Options;
+
+    /// Build the test files, one of them longer than a track/sector
+    /// list can hold
+    fn test_files() -> Vec<(String, Vec<u8>)> {
+        let long: Vec<u8> = (0..40_000_usize).map(|i| (i % 251) as u8).collect();
+        vec![
+            (String::from("HELLO"), apple_binary(0x0803, b"HELLO WORLD")),
+            (String::from("LONG"), apple_binary(0x2000, &long[..30_000])),
+            (String::from("EMPTY"), apple_binary(0x0300, &[])),
+        ]
+    }
+
+    /// Test that a generated Apple DOS 3.3 image parses and its files
+    /// read back
+    #[test]
+    fn apple_dos_33_works() {
+        let files = test_files();
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let data = apple_dos_33(&file_refs).unwrap();
+        assert_eq!(data.len(), 143360);
+
+        let image = data
+            .parse_disk_image(&Options::default(), "test.dsk")
+            .unwrap();
+        assert!(matches!(*image, DiskImage::Apple(_)));
+        let mut read: Vec<(String, Vec<u8>)> = disk_image_file_data(&image);
+        let mut expected = files.clone();
+        read.sort();
+        expected.sort();
+        assert_eq!(read, expected);
+
+        let usage = disk_image_usage(&image).unwrap();
+        assert!(usage.unreferenced().is_empty());
+        assert_eq!(image.report().dos.as_deref(), Some("slave DOS for 48K"));
+    }
+
+    /// Test that a generated D64 image parses and its files read back
+    #[test]
+    fn d64_works() {
+        let files: Vec<(String, Vec<u8>)> = (0..10)
+            .map(|n| (format!("FILE{}", n), vec![n as u8; n * 200]))
+            .collect();
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let data = d64("TEST DISK", &file_refs).unwrap();
+        assert_eq!(data.len(), 174848);
+
+        let image = data
+            .parse_disk_image(&Options::default(), "test.d64")
+            .unwrap();
+        let DiskImage::D64(ref disk) = *image else {
+            panic!("Expected a D64 image");
+        };
+        assert!(disk.bam.check());
+        assert_eq!(image.label().as_deref(), Some("TEST DISK,00"));
+        assert_eq!(disk_image_file_data(&image), files);
+        assert!(disk_image_usage(&image).unwrap().unreferenced().is_empty());
+    }
+
+    /// Test that a generated D71 image parses with files on both sides
+    #[test]
+    fn d71_works() {
+        // The long file fills the first side and continues on the
+        // second
+        let long: Vec<u8> = (0..200_000_usize).map(|i| (i % 251) as u8).collect();
+        let files: Vec<(String, Vec<u8>)> = vec![
+            (String::from("SHORT"), b"HELLO 1571".to_vec()),
+            (String::from("LONG"), long),
+        ];
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let data = d71("TWO SIDES", &file_refs).unwrap();
+        assert_eq!(data.len(), 349696);
+
+        let image = data
+            .parse_disk_image(&Options::default(), "test.d71")
+            .unwrap();
+        let disk = image.as_d64().unwrap();
+        assert!(disk.is_double_sided());
+        assert!(disk.extra_tracks().is_empty());
+        let second_side = disk.second_side_bam().unwrap();
+        assert_eq!(second_side.len(), 35);
+        assert_eq!(second_side[53 - 36].free_sectors_on_track, 0);
+        assert_eq!(second_side[70 - 36].free_sectors_on_track, 17);
+        assert_eq!(image.format_name(), "D71 Disk");
+        assert_eq!(disk_image_file_data(&image), files);
+        assert!(disk.file_extents()[1]
+            .sectors
+            .iter()
+            .any(|id| id.track > Track(35)));
+
+        let usage = disk_image_usage(&image).unwrap();
+        assert!(usage.unreferenced().is_empty());
+        assert!(usage.sectors_with(&SectorUsage::Unmanaged).is_empty());
+        // Track 53 is reserved, the rest of the second side is free
+        // after the long file
+        assert_eq!(
+            usage.usage(&SectorId::new(53, 0, 1)),
+            Some(&SectorUsage::System)
+        );
+        assert_eq!(
+            usage.usage(&SectorId::new(70, 0, 0)),
+            Some(&SectorUsage::Free)
+        );
+    }
+
+    /// Test that a generated Atari ST FAT disk parses as a STX image
+    /// and its files read back
+    #[test]
+    fn atari_st_fat_works() {
+        let files: Vec<(String, Vec<u8>)> = (0..6)
+            .map(|n| (format!("FILE{}.DAT", n), vec![n as u8; n * 700]))
+            .chain([(String::from("AUTO/BOOT.PRG"), vec![0x60; 1500])])
+            .collect();
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let data = atari_st_fat("TEST DISK", &file_refs).unwrap();
+        assert_eq!(data.len(), 737280);
+
+        let stx_data = stx(&data, &Geometry::atari_st(80, 2, 9));
+        let image = stx_data
+            .parse_disk_image(&Options::default(), "test.stx")
+            .unwrap();
+        assert!(matches!(*image, DiskImage::STX(_)));
+        assert_eq!(image.label().as_deref(), Some("TEST DISK"));
+        let mut read = disk_image_file_data(&image);
+        let mut expected = files.clone();
+        read.sort();
+        expected.sort();
+        assert_eq!(read, expected);
+        assert_eq!(image.disk_files().len(), files.len());
+        assert!(disk_image_usage(&image).unwrap().unreferenced().is_empty());
+
+        let mut saved = Vec::new();
+        image
+            .save_to_writer(&Config::default(), Some("auto/boot.prg"), &mut saved)
+            .unwrap();
+        assert_eq!(saved, vec![0x60; 1500]);
+        assert!(image
+            .save_to_writer(&Config::default(), Some("MISSING"), &mut Vec::new())
+            .is_err());
+    }
+
+    /// Test that a generated ProDOS disk parses in both sector orders
+    /// and its files read back
+    #[test]
+    fn prodos_works() {
+        let files: Vec<(String, Vec<u8>)> = (0..6)
+            .map(|n| (format!("FILE{}", n), vec![n as u8; n * 300]))
+            .chain([(String::from("DOCS/MANUAL"), vec![0x41; 9000])])
+            .collect();
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let data = prodos("TEST.DISK", &file_refs).unwrap();
+        assert_eq!(data.len(), 143360);
+        let dos_order = sector_order_image(&data, SectorOrder::Dos33);
+
+        for (image_data, filename) in [(&data, "test.po"), (&dos_order, "test.dsk")] {
+            let image = image_data
+                .parse_disk_image(&Options::default(), filename)
+                .unwrap();
+            assert!(image.prodos_disk().is_some());
+            assert_eq!(image.label().as_deref(), Some("/TEST.DISK"));
+            let mut read = disk_image_file_data(&image);
+            let mut expected = files.clone();
+            read.sort();
+            expected.sort();
+            assert_eq!(read, expected);
+            assert!(disk_image_usage(&image).unwrap().unreferenced().is_empty());
+
+            let mut saved = Vec::new();
+            image
+                .save_to_writer(&Config::default(), Some("docs/manual"), &mut saved)
+                .unwrap();
+            assert_eq!(saved, vec![0x41; 9000]);
+            assert!(image
+                .save_to_writer(&Config::default(), Some("MISSING"), &mut Vec::new())
+                .is_err());
+        }
+    }
+
+    /// Test that generated OFS and FFS Amiga disks parse and their
+    /// files read back
+    #[test]
+    fn adf_works() {
+        // The long file needs an extension block on both filesystems
+        let long: Vec<u8> = (0..60_000_usize).map(|i| (i % 251) as u8).collect();
+        let files: Vec<(String, Vec<u8>)> = (0..6)
+            .map(|n| (format!("File{}", n), vec![n as u8; n * 500]))
+            .chain([
+                (
+                    String::from("S/Startup-Sequence"),
+                    b"LoadWB\nEndCLI\n".to_vec(),
+                ),
+                (String::from("C/Long"), long),
+            ])
+            .collect();
+        let file_refs: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+
+        for (ffs, format_name) in [(false, "ADF Disk: OFS"), (true, "ADF Disk: FFS")] {
+            let data = adf("Workbench", ffs, &file_refs).unwrap();
+            assert_eq!(data.len(), 901120);
+
+            let image = data
+                .parse_disk_image(&Options::default(), "test.adf")
+                .unwrap();
+            assert!(image.is_clean());
+            let disk = image.as_amiga().unwrap();
+            assert!(image.check());
+            assert!(!disk.is_bootable());
+            assert_eq!(image.format_name(), format_name);
+            assert_eq!(image.label().as_deref(), Some("Workbench"));
+            assert_eq!(disk.catalog().len(), files.len() + 2);
+
+            let mut read = disk_image_file_data(&image);
+            let mut expected = files.clone();
+            read.sort();
+            expected.sort();
+            assert_eq!(read, expected);
+            assert_eq!(image.disk_files().len(), files.len());
+            assert!(disk_image_usage(&image).unwrap().unreferenced().is_empty());
+
+            let mut saved = Vec::new();
+            image
+                .save_to_writer(&Config::default(), Some("s/startup-sequence"), &mut saved)
+                .unwrap();
+            assert_eq!(saved, b"LoadWB\nEndCLI\n");
+            assert!(image
+                .save_to_writer(&Config::default(), Some("MISSING"), &mut Vec::new())
+                .is_err());
+
+            let listing = image.to_string();
+            assert!(listing.contains("\nVolume: Workbench ("));
+            assert!(listing.contains("\n  Startup-Sequence "));
+        }
+    }
+
+    /// Test that generated STX, .nib and WOZ images hold the sectors
+    /// they were built from
+    #[test]
+    fn track_images_work() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let flat = atari_st_sectors(2, 2, 9);
+        let stx_data = stx(&flat, &geometry);
+        let image = stx_data
+            .parse_disk_image(&Options::default(), "test.stx")
+            .unwrap();
+        assert!(matches!(*image, DiskImage::STX(_)));
+        assert_eq!(disk_image_data(&image), Some(flat));
+
+        let dos = apple_dos_33(&[("HELLO", &apple_binary(0x0803, b"HELLO"))]).unwrap();
+        let nib = nib_from_dos_order(&dos);
+        assert_eq!(nib.len(), 35 * NIB_TRACK_SIZE);
+        let config = Options::default();
+        let (_, disk) = parse_nib_disk(&config)(&nib).unwrap();
+        assert!(disk.failed_sectors.is_empty());
+        let volume = &disk.volumes[&APPLE_VOLUME];
+        assert_eq!(volume.tracks.len(), 35);
+        // Sectors are numbered in DOS 3.3 order
+        assert_eq!(
+            volume.tracks[&17].sectors[&7].data,
+            dos[0x11700..0x11800].to_vec()
+        );
+
+        let woz = woz_from_dos(&dos).unwrap();
+        assert_eq!(&woz[..4], b"WOZ2");
+    }
+
+    /// Test that an extended DSK image of an Amstrad CPC data disk
+    /// parses despite its .dsk name and its CP/M files read back
+    #[test]
+    fn dsk_works() {
+        let format = CpmFormat::named("amstrad-data").unwrap();
+        let flat = cpm(&format, &[(0, "HELLO.BAS", b"10 PRINT \"HELLO\"")]).unwrap();
+        let geometry = Geometry::uniform(40, 1, 9, 512, 0, 0xC1);
+        let data = dsk(&split_tracks(&flat, &geometry), true, &[]);
+        let image = data
+            .parse_disk_image(&Options::default(), "game.dsk")
+            .unwrap();
+        assert!(matches!(*image, DiskImage::Dsk(_)));
+        assert_eq!(image.format_name(), "Extended DSK Disk");
+        assert!(image.check());
+        assert_eq!(disk_image_data(&image), None);
+
+        let volume = image.cpm_volume(&format).unwrap();
+        let files = volume.disk_files();
+        assert_eq!(files[0].name, "HELLO.BAS");
+        assert_eq!(&files[0].data[..16], b"10 PRINT \"HELLO\"");
+    }
+}
@@ -0,0 +1,293 @@
+//! Export and import individual track files
+//!
+//! Hardware floppy writers like Greaseweazle and FluxEngine work with
+//! one file per track and side.  The files are named trackNN.S.ext,
+//! where NN is the two digit track number, S is the side and ext
+//! depends on the format:
+//!
+//! - raw: trackNN.S.bin, the sector data in the order it appears on the track
+//! - mfm: trackNN.S.mfm, the MFM encoded bit cells, most significant bit first
+//! - flux: trackNN.S.raw, a KryoFlux stream file
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::{debug, info};
+
+use crate::disk_format::cancel::CancellationToken;
+use crate::disk_format::flux::capture::decode_flux_track;
+use crate::disk_format::flux::kryoflux::{kryoflux_stream_parser, KryofluxStream};
+use crate::disk_format::flux::{bits_to_flux, DD_MFM_BITCELL_NS};
+use crate::disk_format::geometry::{Geometry, Sector, SectorId, Track};
+use crate::disk_format::logical::{LogicalSector, LogicalTrack};
+use crate::disk_format::mfm::{decode_track, encode_track, pack_bits, unpack_bits};
+use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+use crate::log_target::IO;
+
+/// The format of exported track files
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrackFormat {
+    /// Plain sector data
+    Raw,
+    /// MFM encoded bit cells
+    Mfm,
+    /// KryoFlux flux stream
+    Flux,
+}
+
+impl TrackFormat {
+    /// The file extension for this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TrackFormat::Raw => "bin",
+            TrackFormat::Mfm => "mfm",
+            TrackFormat::Flux => "raw",
+        }
+    }
+}
+
+impl FromStr for TrackFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<TrackFormat, Error> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(TrackFormat::Raw),
+            "mfm" => Ok(TrackFormat::Mfm),
+            "flux" => Ok(TrackFormat::Flux),
+            _ => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                format!("Unknown track format: {}", s),
+            )))),
+        }
+    }
+}
+
+/// Return the filename for a track
+pub fn track_filename(track: u8, head: u8, format: TrackFormat) -> String {
+    format!("track{:02}.{}.{}", track, head, format.extension())
+}
+
+/// Parse the track and head out of a track filename
+/// Returns None if the name doesn't match the format
+pub fn parse_track_filename(name: &str, format: TrackFormat) -> Option<(u8, u8)> {
+    let name = name.strip_prefix("track")?;
+    let name = name.strip_suffix(format.extension())?;
+    let name = name.strip_suffix('.')?;
+    let (track, head) = name.split_once('.')?;
+    Some((track.parse().ok()?, head.parse().ok()?))
+}
+
+/// Build the file contents for a single track
+fn encode_track_file(track: &LogicalTrack, format: TrackFormat) -> Vec<u8> {
+    match format {
+        TrackFormat::Raw => track
+            .sectors
+            .iter()
+            .flat_map(|s| s.data.iter())
+            .copied()
+            .collect(),
+        TrackFormat::Mfm => pack_bits(&encode_track(track)),
+        TrackFormat::Flux => {
+            let mut stream = KryofluxStream::new(Vec::new());
+            let ticks_per_cell = stream.ticks_per_cell(DD_MFM_BITCELL_NS);
+            stream.flux = bits_to_flux(&encode_track(track), ticks_per_cell);
+            stream.to_bytes()
+        }
+    }
+}
+
+/// Write one file per track and side to a directory
+/// The directory is created if it doesn't exist.  The token is checked
+/// before each track.
+/// Returns the paths of the files written.
+pub fn export_tracks(
+    tracks: &[LogicalTrack],
+    dir: &Path,
+    format: TrackFormat,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<PathBuf>, Error> {
+    fs::create_dir_all(dir)?;
+
+    cancel.start(tracks.len());
+    let mut paths = Vec::new();
+    for track in tracks {
+        cancel.check()?;
+        let path = dir.join(track_filename(track.track, track.head, format));
+        debug!(target: IO, "Writing track file {}", path.display());
+        fs::write(&path, encode_track_file(track, format))?;
+        paths.push(path);
+        cancel.advance();
+    }
+    info!(target: IO, "Wrote {} track files to {}", paths.len(), dir.display());
+
+    Ok(paths)
+}
+
+/// Decode the contents of a single track file
+/// Raw files don't contain sector IDs, so a geometry is required to
+/// split them into sectors.
+pub fn decode_track_file(
+    data: &[u8],
+    track: u8,
+    head: u8,
+    format: TrackFormat,
+    geometry: Option<&Geometry>,
+) -> std::result::Result<LogicalTrack, Error> {
+    match format {
+        TrackFormat::Raw => {
+            let geometry = geometry.ok_or_else(|| {
+                Error::new(ErrorKind::Message(String::from(
+                    "A geometry is required to import raw track files",
+                )))
+            })?;
+            let location = Location::track(track, head).with_format("raw track");
+            let sectors = Track(track)
+                .index_from(Track(geometry.first_track))
+                .and_then(|index| geometry.sectors_per_track.get(index))
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(String::from(
+                        "The track isn't in the geometry",
+                    ))))
+                    .with_location(location.clone())
+                })?;
+            let chunks = data.chunks(geometry.sector_size.max(1));
+            if chunks.len() > usize::from(*sectors) {
+                return Err(
+                    Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                        "The track file holds {} sectors, the track has {}",
+                        chunks.len(),
+                        sectors
+                    ))))
+                    .with_location(location),
+                );
+            }
+
+            let mut logical_track = LogicalTrack::new(track, head);
+            for (i, chunk) in chunks.enumerate() {
+                let sector =
+                    Sector::from_index(i, Sector(geometry.first_sector)).ok_or_else(|| {
+                        Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(String::from(
+                            "Sector number out of range",
+                        ))))
+                        .with_location(location.clone())
+                    })?;
+                let id = SectorId::new(track, head, sector);
+                logical_track
+                    .sectors
+                    .push(LogicalSector::new(id, chunk.to_vec()));
+            }
+            Ok(logical_track)
+        }
+        TrackFormat::Mfm => Ok(decode_track(&unpack_bits(data), track, head)),
+        TrackFormat::Flux => {
+            let (_, stream) = kryoflux_stream_parser(data)?;
+            Ok(decode_flux_track(&stream.flux_track(track, head)))
+        }
+    }
+}
+
+/// Read every track file in a directory
+/// Files that don't match the naming convention for the format are
+/// ignored.  Tracks are returned sorted by track and head.  The token
+/// is checked before each track.
+pub fn import_tracks(
+    dir: &Path,
+    format: TrackFormat,
+    geometry: Option<&Geometry>,
+    cancel: &CancellationToken,
+) -> std::result::Result<Vec<LogicalTrack>, Error> {
+    let mut tracks = Vec::new();
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some((track, head)) = parse_track_filename(&name.to_string_lossy(), format) {
+            files.push((entry.path(), track, head));
+        }
+    }
+
+    cancel.start(files.len());
+    for (path, track, head) in files {
+        cancel.check()?;
+        debug!(target: IO, "Reading track file {}", path.display());
+        let data = fs::read(&path)?;
+        tracks.push(decode_track_file(&data, track, head, format, geometry)?);
+        cancel.advance();
+    }
+
+    tracks.sort_by_key(|t| (t.track, t.head));
+
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_track_file, export_tracks, import_tracks, parse_track_filename, track_filename,
+        TrackFormat,
+    };
+    use crate::disk_format::cancel::CancellationToken;
+    use crate::disk_format::geometry::Geometry;
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test building and parsing track filenames
+    #[test]
+    fn track_filename_works() {
+        assert_eq!(track_filename(3, 1, TrackFormat::Flux), "track03.1.raw");
+        assert_eq!(
+            parse_track_filename("track03.1.raw", TrackFormat::Flux),
+            Some((3, 1))
+        );
+        assert_eq!(
+            parse_track_filename("track03.1.raw", TrackFormat::Mfm),
+            None
+        );
+        assert_eq!(parse_track_filename("notes.txt", TrackFormat::Raw), None);
+    }
+
+    /// Test that exporting and importing tracks round-trips for every format
+    #[test]
+    fn export_import_tracks_works() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let data: Vec<u8> = (0..geometry.total_size())
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let tracks = split_tracks(&data, &geometry);
+
+        for format in [TrackFormat::Raw, TrackFormat::Mfm, TrackFormat::Flux] {
+            let dir = std::env::temp_dir().join(format!(
+                "image-rider-track-files-{}-{:?}",
+                std::process::id(),
+                format
+            ));
+            let paths = export_tracks(&tracks, &dir, format, &CancellationToken::new()).unwrap();
+            assert_eq!(paths.len(), 4);
+
+            let imported =
+                import_tracks(&dir, format, Some(&geometry), &CancellationToken::new()).unwrap();
+            assert_eq!(imported, tracks);
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    /// Test that raw track files with more sectors than the geometry
+    /// has, or sector numbers past 255, are errors
+    #[test]
+    fn decode_raw_track_file_limits_work() {
+        let geometry = Geometry::atari_st(2, 2, 9);
+        let track = decode_track_file(&[0; 9 * 512], 1, 1, TrackFormat::Raw, Some(&geometry));
+        assert_eq!(track.unwrap().sectors.len(), 9);
+
+        let error = decode_track_file(&[0; 300 * 512], 1, 1, TrackFormat::Raw, Some(&geometry))
+            .unwrap_err();
+        let location = error.location().unwrap();
+        assert_eq!((location.track, location.head), (Some(1), Some(1)));
+        assert!(decode_track_file(&[0; 512], 2, 0, TrackFormat::Raw, Some(&geometry)).is_err());
+
+        let geometry = Geometry::uniform(1, 1, 16, 256, 0, 250);
+        assert!(
+            decode_track_file(&[0; 16 * 256], 0, 0, TrackFormat::Raw, Some(&geometry)).is_err()
+        );
+    }
+}
@@ -0,0 +1,446 @@
+//! A tree model of a disk image for browsers
+//!
+//! [DiskImage::tree](crate::disk_format::image::DiskImage::tree)
+//! returns an [ImageTree], which presents any parsed image as the same
+//! hierarchy:
+//!
+//! ```text
+//! image
+//! └── volume
+//!     ├── directory
+//!     │   └── file
+//!     │       └── sector
+//!     └── file
+//!         └── sector
+//! ```
+//!
+//! Children are listed one level at a time, when a browser expands a
+//! node.  The files are read from the image the first time they're
+//! needed and the sectors of each file the first time a file is
+//! expanded, then both are kept for the life of the tree.
+//!
+//! Nodes are identified by a [NodeId] built from the path to the node,
+//! so the same node has the same ID every time the image is opened.
+//! IDs can be stored as strings and parsed again, to remember which
+//! nodes a user had expanded or selected.
+//!
+//! Directories come from the paths of the files in them, so empty
+//! directories aren't shown.
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::image::{disk_image_file_extents, DiskImage, DiskImageSaver};
+use crate::error::{Error, ErrorKind, InvalidErrorKind};
+
+/// The separator between directories in file names
+const PATH_SEPARATOR: char = '/';
+
+/// The separator between a file and a sector in a node ID
+const SECTOR_SEPARATOR: char = '@';
+
+/// The stable ID of a node in an image tree
+/// Volumes are numbered from zero and directories and files are named
+/// by their path on the volume.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum NodeId {
+    /// The image
+    Image,
+    /// A volume on the image
+    Volume(usize),
+    /// A directory on a volume, by its path
+    Directory(usize, String),
+    /// A file on a volume, by its path
+    File(usize, String),
+    /// A sector of a file
+    Sector(usize, String, SectorId),
+}
+
+impl NodeId {
+    /// Return the ID of the node's parent, None for the image
+    pub fn parent(&self) -> Option<NodeId> {
+        let parent_of = |volume: usize, path: &str| match path.rsplit_once(PATH_SEPARATOR) {
+            Some((directory, _)) => NodeId::Directory(volume, String::from(directory)),
+            None => NodeId::Volume(volume),
+        };
+        match self {
+            NodeId::Image => None,
+            NodeId::Volume(_) => Some(NodeId::Image),
+            NodeId::Directory(volume, path) | NodeId::File(volume, path) => {
+                Some(parent_of(*volume, path))
+            }
+            NodeId::Sector(volume, path, _) => Some(NodeId::File(*volume, path.clone())),
+        }
+    }
+}
+
+/// Display a NodeId as a path
+/// The image is "/", volumes "/0", directories end with a slash and
+/// sectors follow their file after an @, e.g. "/0/GAMES/CHESS@17.0.3".
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            NodeId::Image => write!(f, "/"),
+            NodeId::Volume(volume) => write!(f, "/{}", volume),
+            NodeId::Directory(volume, path) => write!(f, "/{}/{}/", volume, path),
+            NodeId::File(volume, path) => write!(f, "/{}/{}", volume, path),
+            NodeId::Sector(volume, path, id) => write!(
+                f,
+                "/{}/{}{}{}.{}.{}",
+                volume, path, SECTOR_SEPARATOR, id.track.0, id.head.0, id.sector.0
+            ),
+        }
+    }
+}
+
+/// Parse a NodeId from its path
+impl FromStr for NodeId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<NodeId, Error> {
+        let invalid = || {
+            Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(format!(
+                "Invalid node ID: {}",
+                s
+            ))))
+        };
+        if s == "/" {
+            return Ok(NodeId::Image);
+        }
+        let s_rest = s.strip_prefix('/').ok_or_else(invalid)?;
+        let (volume, path) = match s_rest.split_once(PATH_SEPARATOR) {
+            Some((volume, path)) => (volume, Some(path)),
+            None => (s_rest, None),
+        };
+        let volume: usize = volume.parse().map_err(|_| invalid())?;
+        let path = match path {
+            None => return Ok(NodeId::Volume(volume)),
+            Some("") => return Err(invalid()),
+            Some(path) => path,
+        };
+        if let Some(directory) = path.strip_suffix(PATH_SEPARATOR) {
+            return Ok(NodeId::Directory(volume, String::from(directory)));
+        }
+        // File names can hold an @, the sector is after the last one
+        if let Some((file, sector)) = path.rsplit_once(SECTOR_SEPARATOR) {
+            let numbers: Vec<u8> = sector
+                .split('.')
+                .map(str::parse)
+                .collect::<std::result::Result<_, _>>()
+                .unwrap_or_default();
+            if let [track, head, sector] = numbers[..] {
+                return Ok(NodeId::Sector(
+                    volume,
+                    String::from(file),
+                    SectorId::new(track, head, sector),
+                ));
+            }
+        }
+        Ok(NodeId::File(volume, String::from(path)))
+    }
+}
+
+/// The kind of structure a node is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NodeKind {
+    /// The image
+    Image,
+    /// A volume or partition
+    Volume,
+    /// A directory
+    Directory,
+    /// A file
+    File,
+    /// A sector of a file
+    Sector,
+}
+
+/// A node in an image tree
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeNode {
+    /// The stable ID of the node
+    pub id: NodeId,
+    /// The kind of node
+    pub kind: NodeKind,
+    /// The name to show for the node
+    pub name: String,
+    /// The type of a file, in the platform's catalog notation
+    pub file_type: Option<String>,
+    /// The size of a file in bytes
+    pub size: Option<usize>,
+    /// True if the node has children, so a browser can show an
+    /// expander without listing them
+    pub has_children: bool,
+}
+
+impl TreeNode {
+    /// Create a node without a file type or size
+    fn new(id: NodeId, kind: NodeKind, name: &str, has_children: bool) -> TreeNode {
+        TreeNode {
+            id,
+            kind,
+            name: String::from(name),
+            file_type: None,
+            size: None,
+            has_children,
+        }
+    }
+}
+
+/// A file on a volume, as read for the tree
+struct TreeFile {
+    /// The path of the file
+    name: String,
+    /// The type of the file
+    file_type: String,
+    /// The size of the file in bytes
+    size: usize,
+}
+
+/// A tree model of a disk image, listing the children of each node
+/// when they're asked for
+pub struct ImageTree<'a> {
+    /// The image
+    image: &'a DiskImage<'a>,
+    /// The files on the image, read the first time they're needed
+    files: OnceCell<Vec<TreeFile>>,
+    /// The sectors of each file, read the first time a file is
+    /// expanded
+    extents: OnceCell<BTreeMap<String, Vec<SectorId>>>,
+}
+
+impl<'a> ImageTree<'a> {
+    /// Create a tree for a disk image
+    pub fn new(image: &'a DiskImage<'a>) -> ImageTree<'a> {
+        ImageTree {
+            image,
+            files: OnceCell::new(),
+            extents: OnceCell::new(),
+        }
+    }
+
+    /// Return the files on the image
+    fn files(&self) -> &[TreeFile] {
+        self.files.get_or_init(|| {
+            self.image
+                .disk_files()
+                .into_iter()
+                .map(|file| TreeFile {
+                    name: file.name,
+                    file_type: file.file_type,
+                    size: file.data.len(),
+                })
+                .collect()
+        })
+    }
+
+    /// Return the sectors of a file
+    fn sectors(&self, name: &str) -> &[SectorId] {
+        self.extents
+            .get_or_init(|| {
+                disk_image_file_extents(self.image)
+                    .into_iter()
+                    .map(|extent| (extent.name, extent.sectors))
+                    .collect()
+            })
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Return the root node, the image
+    pub fn root(&self) -> TreeNode {
+        TreeNode::new(
+            NodeId::Image,
+            NodeKind::Image,
+            &self.image.format_name(),
+            true,
+        )
+    }
+
+    /// Return a node, None if there's no node with the ID on this image
+    pub fn node(&self, id: &NodeId) -> Option<TreeNode> {
+        match id {
+            NodeId::Image => Some(self.root()),
+            NodeId::Volume(0) => Some(self.volume()),
+            NodeId::Volume(_) => None,
+            NodeId::Directory(0, path) => {
+                let prefix = format!("{}{}", path, PATH_SEPARATOR);
+                self.files()
+                    .iter()
+                    .any(|file| file.name.starts_with(&prefix))
+                    .then(|| self.directory(path))
+            }
+            NodeId::File(0, path) => self
+                .files()
+                .iter()
+                .find(|file| file.name == *path)
+                .map(|file| self.file(file)),
+            NodeId::Sector(0, path, sector) => self
+                .sectors(path)
+                .contains(sector)
+                .then(|| Self::sector(path, sector)),
+            _ => None,
+        }
+    }
+
+    /// Return the children of a node, in the order they're stored
+    /// Directories on a volume or in a directory come before the
+    /// files.  Returns an empty list for nodes without children and
+    /// IDs that aren't on this image.
+    pub fn children(&self, id: &NodeId) -> Vec<TreeNode> {
+        match id {
+            NodeId::Image => vec![self.volume()],
+            NodeId::Volume(0) => self.entries(""),
+            NodeId::Directory(0, path) => self.entries(&format!("{}{}", path, PATH_SEPARATOR)),
+            NodeId::File(0, path) => self
+                .sectors(path)
+                .iter()
+                .map(|sector| Self::sector(path, sector))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Return the volume node
+    /// Images hold a single volume, named by the volume label.
+    fn volume(&self) -> TreeNode {
+        let name = self.image.label().unwrap_or_else(|| String::from("volume"));
+        TreeNode::new(
+            NodeId::Volume(0),
+            NodeKind::Volume,
+            &name,
+            !self.files().is_empty(),
+        )
+    }
+
+    /// Return a directory node
+    fn directory(&self, path: &str) -> TreeNode {
+        let name = path.rsplit(PATH_SEPARATOR).next().unwrap_or(path);
+        TreeNode::new(
+            NodeId::Directory(0, String::from(path)),
+            NodeKind::Directory,
+            name,
+            true,
+        )
+    }
+
+    /// Return a file node
+    fn file(&self, file: &TreeFile) -> TreeNode {
+        let name = file
+            .name
+            .rsplit(PATH_SEPARATOR)
+            .next()
+            .unwrap_or(&file.name);
+        TreeNode {
+            file_type: Some(file.file_type.clone()),
+            size: Some(file.size),
+            ..TreeNode::new(
+                NodeId::File(0, file.name.clone()),
+                NodeKind::File,
+                name,
+                !self.sectors(&file.name).is_empty(),
+            )
+        }
+    }
+
+    /// Return a sector node
+    fn sector(path: &str, sector: &SectorId) -> TreeNode {
+        TreeNode::new(
+            NodeId::Sector(0, String::from(path), *sector),
+            NodeKind::Sector,
+            &sector.to_string(),
+            false,
+        )
+    }
+
+    /// Return the directories and files directly under a path prefix
+    fn entries(&self, prefix: &str) -> Vec<TreeNode> {
+        let mut directories: Vec<TreeNode> = Vec::new();
+        let mut files = Vec::new();
+        for file in self.files() {
+            let Some(rest) = file.name.strip_prefix(prefix) else {
+                continue;
+            };
+            match rest.split_once(PATH_SEPARATOR) {
+                Some((directory, _)) => {
+                    let path = format!("{}{}", prefix, directory);
+                    if !directories
+                        .iter()
+                        .any(|node| node.id == NodeId::Directory(0, path.clone()))
+                    {
+                        directories.push(self.directory(&path));
+                    }
+                }
+                None => files.push(self.file(file)),
+            }
+        }
+        directories.extend(files);
+        directories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeId, NodeKind};
+    use crate::disk_format::geometry::SectorId;
+    use crate::disk_format::image::DiskImageParser;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test walking the volume, directories, files and sectors of a
+    /// ProDOS disk
+    #[test]
+    fn tree_works() {
+        let data = testgen::prodos(
+            "DISK",
+            &[("HELLO", b"hello"), ("GAMES/CHESS", &[0x42; 600])],
+        )
+        .unwrap();
+        let image = data
+            .parse_disk_image(&Options::default(), "disk.po")
+            .unwrap();
+        let tree = image.tree();
+
+        let root = tree.root();
+        assert_eq!(root.kind, NodeKind::Image);
+        let volumes = tree.children(&root.id);
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].name, "/DISK");
+
+        let entries = tree.children(&volumes[0].id);
+        let names: Vec<&str> = entries.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, ["GAMES", "HELLO"]);
+        assert_eq!(entries[0].kind, NodeKind::Directory);
+        assert_eq!(entries[1].size, Some(5));
+
+        let games = tree.children(&entries[0].id);
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].id, NodeId::File(0, String::from("GAMES/CHESS")));
+        assert_eq!(games[0].size, Some(600));
+        assert!(games[0].has_children);
+
+        // The sectors of a file are its blocks
+        let sectors = tree.children(&games[0].id);
+        assert!(!sectors.is_empty());
+        assert!(sectors.iter().all(|node| node.kind == NodeKind::Sector));
+
+        // IDs round trip through strings and lead back to their nodes
+        for node in [&root, &volumes[0], &entries[0], &games[0], &sectors[0]] {
+            let id: NodeId = node.id.to_string().parse().unwrap();
+            assert_eq!(id, node.id);
+            assert_eq!(tree.node(&id).as_ref(), Some(node));
+        }
+        assert_eq!(games[0].id.parent(), Some(entries[0].id.clone()));
+        assert_eq!(entries[0].id.parent(), Some(NodeId::Volume(0)));
+        assert_eq!(
+            "/0/A@B@1.0.2".parse::<NodeId>().unwrap(),
+            NodeId::Sector(0, String::from("A@B"), SectorId::new(1, 0, 2))
+        );
+        assert!("0/HELLO".parse::<NodeId>().is_err());
+        assert_eq!(tree.node(&NodeId::File(0, String::from("MISSING"))), None);
+        assert!(tree.children(&NodeId::Volume(1)).is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+//! Sector usage maps and hidden data detection
+//!
+//! A usage map combines the filesystem's allocation bitmap (the BAM on
+//! Commodore disks, the VTOC on Apple DOS disks) with the sectors the
+//! catalog actually references.  Sectors that are marked as in use but
+//! that no file or filesystem structure points to, and the slack space
+//! after the end of a file in its last sector, are places where cracked
+//! releases and copy protection schemes often hide data.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+
+use serde::{Deserialize, Serialize};
+
+use crate::disk_format::geometry::SectorId;
+use crate::disk_format::logical::LogicalTrack;
+
+/// How a sector is used
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SectorUsage {
+    /// Marked free in the allocation bitmap
+    Free,
+    /// Used by the filesystem or operating system, e.g. the directory,
+    /// the allocation bitmap or boot tracks
+    System,
+    /// Part of a file, with the name of the file
+    File(String),
+    /// Marked as in use but not referenced by any file or filesystem
+    /// structure
+    Unreferenced,
+    /// On a track the filesystem doesn't manage, like tracks 36 to 40
+    /// on a Commodore disk without an extended BAM
+    Unmanaged,
+}
+
+impl Display for SectorUsage {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            SectorUsage::Free => write!(f, "free"),
+            SectorUsage::System => write!(f, "system"),
+            SectorUsage::File(name) => write!(f, "file: {}", name),
+            SectorUsage::Unreferenced => write!(f, "unreferenced"),
+            SectorUsage::Unmanaged => write!(f, "unmanaged"),
+        }
+    }
+}
+
+/// The sectors that hold a file's data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileExtent {
+    /// The name of the file
+    pub name: String,
+    /// The data sectors of the file, in file order
+    pub sectors: Vec<SectorId>,
+    /// Where the file data ends, as an index into sectors and an offset
+    /// into that sector.  None if the length of the file is unknown.
+    pub end: Option<(usize, usize)>,
+}
+
+/// The usage of every sector on a disk
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UsageMap {
+    /// The usage of each sector
+    pub sectors: BTreeMap<SectorId, SectorUsage>,
+}
+
+impl UsageMap {
+    /// Build a usage map
+    /// Sectors referenced by a file or the filesystem are marked as
+    /// used even if the allocation bitmap says they are free.
+    pub fn new(
+        sectors: &[SectorId],
+        free: &BTreeSet<SectorId>,
+        system: &BTreeSet<SectorId>,
+        unmanaged: &BTreeSet<SectorId>,
+        files: &[FileExtent],
+    ) -> UsageMap {
+        let mut usage: BTreeMap<SectorId, SectorUsage> = sectors
+            .iter()
+            .map(|id| {
+                let sector_usage = if system.contains(id) {
+                    SectorUsage::System
+                } else if unmanaged.contains(id) {
+                    SectorUsage::Unmanaged
+                } else if free.contains(id) {
+                    SectorUsage::Free
+                } else {
+                    SectorUsage::Unreferenced
+                };
+                (*id, sector_usage)
+            })
+            .collect();
+
+        for file in files {
+            for id in &file.sectors {
+                usage.insert(*id, SectorUsage::File(file.name.clone()));
+            }
+        }
+
+        UsageMap { sectors: usage }
+    }
+
+    /// Return the usage of a sector
+    pub fn usage(&self, id: &SectorId) -> Option<&SectorUsage> {
+        self.sectors.get(id)
+    }
+
+    /// Return the sectors with a given usage
+    pub fn sectors_with(&self, wanted: &SectorUsage) -> Vec<SectorId> {
+        self.sectors
+            .iter()
+            .filter(|(_, usage)| *usage == wanted)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Return the sectors that are allocated but not referenced
+    pub fn unreferenced(&self) -> Vec<SectorId> {
+        self.sectors_with(&SectorUsage::Unreferenced)
+    }
+}
+
+/// Display a summary of a usage map
+impl Display for UsageMap {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let count = |wanted: fn(&SectorUsage) -> bool| {
+            self.sectors.values().filter(|usage| wanted(usage)).count()
+        };
+        write!(
+            f,
+            "free: {}, system: {}, file: {}, unreferenced: {}, unmanaged: {}",
+            count(|u| *u == SectorUsage::Free),
+            count(|u| *u == SectorUsage::System),
+            count(|u| matches!(u, SectorUsage::File(_))),
+            count(|u| *u == SectorUsage::Unreferenced),
+            count(|u| *u == SectorUsage::Unmanaged)
+        )
+    }
+}
+
+/// A region of a sector that may hold hidden data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HiddenRegion {
+    /// The sector the region is in
+    pub id: SectorId,
+    /// The offset of the region in the sector
+    pub offset: usize,
+    /// The data in the region
+    pub data: Vec<u8>,
+    /// The usage of the sector: the file whose slack space holds the
+    /// region, an unreferenced sector or a sector outside the filesystem
+    pub usage: SectorUsage,
+}
+
+impl Display for HiddenRegion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "{}, offset: 0x{:02X}, length: {}",
+            self.id,
+            self.offset,
+            self.data.len()
+        )?;
+        match &self.usage {
+            SectorUsage::File(file) => write!(f, ", slack space of: {}", file),
+            usage => write!(f, ", {} sector", usage),
+        }
+    }
+}
+
+/// Return true if the data is all the same byte, like a formatting fill
+/// pattern or zeroed slack space
+fn is_fill(data: &[u8]) -> bool {
+    data.iter().all(|b| Some(b) == data.first())
+}
+
+/// Find regions that may hold hidden data: unreferenced sectors,
+/// sectors outside the filesystem and file slack space that contain
+/// something other than a fill pattern
+pub fn hidden_regions(
+    tracks: &[LogicalTrack],
+    usage_map: &UsageMap,
+    files: &[FileExtent],
+) -> Vec<HiddenRegion> {
+    let sectors: BTreeMap<SectorId, &[u8]> = tracks
+        .iter()
+        .flat_map(|track| {
+            track.sectors.iter().map(|sector| {
+                (
+                    SectorId::new(track.track, track.head, sector.id.sector),
+                    sector.data.as_slice(),
+                )
+            })
+        })
+        .collect();
+    let mut regions = Vec::new();
+
+    for usage in [SectorUsage::Unreferenced, SectorUsage::Unmanaged] {
+        for id in usage_map.sectors_with(&usage) {
+            if let Some(data) = sectors.get(&id).filter(|d| !is_fill(d)) {
+                regions.push(HiddenRegion {
+                    id,
+                    offset: 0,
+                    data: data.to_vec(),
+                    usage: usage.clone(),
+                });
+            }
+        }
+    }
+
+    for file in files {
+        let (last, offset) = match file.end {
+            Some(end) => end,
+            None => continue,
+        };
+        for (index, id) in file.sectors.iter().enumerate().skip(last) {
+            let offset = if index == last { offset } else { 0 };
+            let slack = sectors
+                .get(id)
+                .and_then(|data| data.get(offset..))
+                .filter(|slack| !slack.is_empty() && slack.iter().any(|b| *b != 0x00));
+            if let Some(slack) = slack {
+                regions.push(HiddenRegion {
+                    id: *id,
+                    offset,
+                    data: slack.to_vec(),
+                    usage: SectorUsage::File(file.name.clone()),
+                });
+            }
+        }
+    }
+    regions.sort_by_key(|r| (r.id, r.offset));
+
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{hidden_regions, FileExtent, SectorUsage, UsageMap};
+    use crate::disk_format::geometry::{Geometry, Sector, SectorId, Track};
+    use crate::disk_format::logical::split_tracks;
+
+    /// Test building a usage map and finding hidden data in unreferenced
+    /// sectors and file slack space
+    #[test]
+    fn hidden_regions_works() {
+        let geometry = Geometry::apple_dos_33(2);
+        let mut data = vec![0_u8; geometry.total_size()];
+        let tracks = geometry.sector_ids();
+
+        // A file in track 1 sectors 0 and 1, ending at offset 0x10 of
+        // sector 1, with a message after the end
+        let file = FileExtent {
+            name: String::from("HELLO"),
+            sectors: vec![SectorId::new(1, 0, 0), SectorId::new(1, 0, 1)],
+            end: Some((1, 0x10)),
+        };
+        let offset = geometry.offset(&SectorId::new(1, 0, 1)).unwrap();
+        data[offset + 0x20..offset + 0x24].copy_from_slice(b"SEEK");
+        // Allocated sectors 1/2 (with data) and 1/3 (zero filled)
+        let offset = geometry.offset(&SectorId::new(1, 0, 2)).unwrap();
+        data[offset..offset + 6].copy_from_slice(b"SECRET");
+
+        let free: BTreeSet<SectorId> = tracks
+            .iter()
+            .filter(|id| id.track == Track(1) && id.sector > Sector(3))
+            .copied()
+            .collect();
+        let system: BTreeSet<SectorId> = tracks
+            .iter()
+            .filter(|id| id.track == Track(0))
+            .copied()
+            .collect();
+        let unmanaged: BTreeSet<SectorId> = BTreeSet::new();
+        let usage_map = UsageMap::new(
+            &tracks,
+            &free,
+            &system,
+            &unmanaged,
+            std::slice::from_ref(&file),
+        );
+
+        assert_eq!(
+            usage_map.usage(&SectorId::new(1, 0, 1)),
+            Some(&SectorUsage::File(String::from("HELLO")))
+        );
+        assert_eq!(
+            usage_map.unreferenced(),
+            vec![SectorId::new(1, 0, 2), SectorId::new(1, 0, 3)]
+        );
+        assert_eq!(
+            usage_map.to_string(),
+            "free: 12, system: 16, file: 2, unreferenced: 2, unmanaged: 0"
+        );
+
+        let regions = hidden_regions(&split_tracks(&data, &geometry), &usage_map, &[file]);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].id, SectorId::new(1, 0, 1));
+        assert_eq!(regions[0].offset, 0x10);
+        assert_eq!(&regions[0].data[0x10..0x14], b"SEEK");
+        assert_eq!(regions[0].usage, SectorUsage::File(String::from("HELLO")));
+        assert_eq!(regions[1].id, SectorId::new(1, 0, 2));
+        assert_eq!(&regions[1].data[0..6], b"SECRET");
+        assert_eq!(regions[1].usage, SectorUsage::Unreferenced);
+    }
+}
@@ -0,0 +1,371 @@
+//! Error results that can occur working with images
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+use std::fmt::{Debug, Display, Formatter, Result};
+
+/// An error that can occur when processing an image, ROM or other
+/// file.
+/// Errors may carry the location in the image where they occurred and
+/// the lower level error that caused them.
+#[derive(Eq, PartialEq)]
+pub struct Error {
+    kind: ErrorKind,
+    location: Option<Location>,
+    source: Option<Box<Error>>,
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self)?;
+        if let Some(source) = &self.source {
+            write!(f, ": {:?}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl Error {
+    /// Create a new Error with a given ErrorKind variant
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            location: None,
+            source: None,
+        }
+    }
+
+    /// The kind of error
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The process exit code for the error, from its kind
+    pub fn exit_code(&self) -> i32 {
+        self.kind.exit_code()
+    }
+
+    /// Where in the image the error occurred, if known
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    /// Add the location the error occurred at
+    pub fn with_location(mut self, location: Location) -> Error {
+        self.location = Some(location);
+        self
+    }
+
+    /// Add the lower level error that caused this one
+    pub fn with_source(mut self, source: Error) -> Error {
+        self.source = Some(Box::new(source));
+        self
+    }
+}
+
+/// Where in an image an error occurred
+/// Every part is optional, parsers fill in what they know.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Location {
+    /// The image format, e.g. "D64"
+    pub format: Option<String>,
+    /// The track number
+    pub track: Option<u8>,
+    /// The head (side) number
+    pub head: Option<u8>,
+    /// The sector number
+    pub sector: Option<u8>,
+    /// The byte offset in the image file
+    pub offset: Option<usize>,
+}
+
+impl Location {
+    /// A location at a byte offset in the image file
+    pub fn offset(offset: usize) -> Location {
+        Location {
+            offset: Some(offset),
+            ..Location::default()
+        }
+    }
+
+    /// A location at a track and sector
+    pub fn sector(track: u8, sector: u8) -> Location {
+        Location {
+            track: Some(track),
+            sector: Some(sector),
+            ..Location::default()
+        }
+    }
+
+    /// A location at a track and head
+    pub fn track(track: u8, head: u8) -> Location {
+        Location {
+            track: Some(track),
+            head: Some(head),
+            ..Location::default()
+        }
+    }
+
+    /// Set the image format
+    pub fn with_format(mut self, format: &str) -> Location {
+        self.format = Some(String::from(format));
+        self
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let mut parts = Vec::new();
+        if let Some(track) = self.track {
+            parts.push(format!("track {}", track));
+        }
+        if let Some(head) = self.head.filter(|head| *head != 0) {
+            parts.push(format!("head {}", head));
+        }
+        if let Some(sector) = self.sector {
+            parts.push(format!("sector {}", sector));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset {:#x}", offset));
+        }
+        write!(f, "{}", parts.join(" "))?;
+        if let Some(format) = &self.format {
+            write!(f, " of the {} image", format)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<nom::Err<nom::error::Error<&[u8]>>> for Error {
+    fn from(e: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+// impl From<nom::Err<nom::error::ParseError<&[u8]>>> for Error {
+//     fn from(e: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+//         Error::new(ErrorKind::new(&e.to_string()))
+//     }
+// }
+
+impl<'a> nom::error::ParseError<&'a [u8]> for Error {
+    fn from_error_kind(_input: &'a [u8], kind: nom::error::ErrorKind) -> Self {
+        Error::new(ErrorKind::new(kind.description()))
+    }
+
+    fn append(_input: &'a [u8], _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(e: config::ConfigError) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::new(ErrorKind::new(&e.to_string()))
+    }
+}
+
+/// The kinds of errors that can occur when processing an image, ROM
+/// or other file.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// Generic error type
+    Message(String),
+
+    /// An error that occurs when dealing with invalid or unexpected
+    /// data.
+    Invalid(InvalidErrorKind),
+
+    /// The file was in a format that is unsupported or has
+    /// unsupported features.
+    Unimplemented(String),
+
+    /// The data requested was not found in the image.  This can occur
+    /// when attempting to extract a specific file from a file, or
+    /// when attempting to extract a certain sector or other item.
+    NotFound(String),
+
+    /// The image is read-only and can't be changed.  Images are
+    /// read-only when opened from a read-only source or when changes
+    /// can't be saved in their format.
+    ReadOnly(String),
+
+    /// The operation was cancelled with a CancellationToken before it
+    /// finished.
+    Cancelled,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            ErrorKind::Message(message) => write!(f, "An error occurred: {}", message),
+            ErrorKind::Invalid(e) => write!(f, "{}", e),
+            ErrorKind::Unimplemented(message) => {
+                write!(f, "Unimplemented feature: {}", message)
+            }
+            ErrorKind::NotFound(message) => {
+                write!(f, "Data not found: {}", message)
+            }
+            ErrorKind::ReadOnly(message) => {
+                write!(f, "Image is read-only: {}", message)
+            }
+            ErrorKind::Cancelled => write!(f, "The operation was cancelled"),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Return a new generic ErrorKind::Message with a given string message.
+    pub fn new(message: &str) -> ErrorKind {
+        ErrorKind::Message(message.to_string())
+    }
+
+    /// Return the process exit code for the kind of error, so scripts
+    /// processing collections can branch on the failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Message(_) => EXIT_ERROR,
+            ErrorKind::Invalid(_) => EXIT_CORRUPT_IMAGE,
+            ErrorKind::Unimplemented(_) => EXIT_UNKNOWN_FORMAT,
+            ErrorKind::NotFound(_) => EXIT_EXTRACTION_FAILED,
+            ErrorKind::ReadOnly(_) => EXIT_READ_ONLY,
+            ErrorKind::Cancelled => EXIT_CANCELLED,
+        }
+    }
+}
+
+/// The exit code when a command succeeds
+pub const EXIT_OK: i32 = 0;
+
+/// The exit code when a command ran but found nothing, e.g. a search
+/// with no hits
+pub const EXIT_NO_MATCH: i32 = 1;
+
+/// The exit code for an image in an unknown format, or a format or
+/// feature that isn't supported
+pub const EXIT_UNKNOWN_FORMAT: i32 = 2;
+
+/// The exit code for a damaged image, one that doesn't parse or fails
+/// its checksums or verification
+pub const EXIT_CORRUPT_IMAGE: i32 = 3;
+
+/// The exit code when a file or sector to extract isn't on the image
+pub const EXIT_EXTRACTION_FAILED: i32 = 4;
+
+/// The exit code when changes can't be written to a read-only image
+pub const EXIT_READ_ONLY: i32 = 5;
+
+/// The exit code when an operation was cancelled
+pub const EXIT_CANCELLED: i32 = 6;
+
+/// The exit code for any other error, e.g. a file that can't be read
+/// or written or a bad setting
+pub const EXIT_ERROR: i32 = 7;
+
+/// An InvalidErrorKind is returned when the data is invalid.
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidErrorKind {
+    /// The data was invalid
+    Invalid(String),
+    /// The data contains an invalid checksum
+    Checksum,
+}
+
+impl Display for InvalidErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            InvalidErrorKind::Invalid(message) => write!(f, "Image is invalid: {}", message),
+            InvalidErrorKind::Checksum => write!(f, "Image has an invalid checksum"),
+        }
+    }
+}
+
+/// Test the error code
+#[allow(unused_imports)]
+#[cfg(test)]
+pub mod tests {
+    use crate::error::{Error, ErrorKind, InvalidErrorKind, Location};
+
+    /// Test that comparing ErrorKinds works
+    #[test]
+    pub fn error_kind_partial_eq_works() {
+        let ek1 = ErrorKind::new("Test1");
+        let ek2 = ErrorKind::new("Test1");
+        let ek3 = ErrorKind::NotFound(String::from("Test1"));
+
+        assert_eq!(ek1, ek1);
+        assert_eq!(ek1, ek2);
+        assert_ne!(ek1, ek3);
+    }
+
+    /// Test that each kind of error gets its own exit code
+    #[test]
+    pub fn exit_code_works() {
+        use crate::error::{EXIT_CORRUPT_IMAGE, EXIT_ERROR, EXIT_EXTRACTION_FAILED};
+
+        let corrupt = Error::new(ErrorKind::Invalid(InvalidErrorKind::Checksum));
+        assert_eq!(corrupt.exit_code(), EXIT_CORRUPT_IMAGE);
+        let missing = Error::new(ErrorKind::NotFound(String::from("HELLO")));
+        assert_eq!(missing.exit_code(), EXIT_EXTRACTION_FAILED);
+        let io = Error::from(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(io.exit_code(), EXIT_ERROR);
+        assert_eq!(ErrorKind::Unimplemented(String::new()).exit_code(), 2);
+    }
+
+    /// Test that errors show their location and keep their source
+    #[test]
+    pub fn error_location_works() {
+        let error = Error::new(ErrorKind::Invalid(InvalidErrorKind::Checksum))
+            .with_location(Location::sector(34, 5).with_format("D64"))
+            .with_source(Error::new(ErrorKind::new("Tag")));
+
+        assert_eq!(
+            error.to_string(),
+            "Image has an invalid checksum at track 34 sector 5 of the D64 image"
+        );
+        assert_eq!(error.location().unwrap().track, Some(34));
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(source.to_string(), "An error occurred: Tag");
+        assert_eq!(Location::offset(0x100).to_string(), "offset 0x100");
+    }
+}
@@ -0,0 +1,76 @@
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+//! image_rider_core holds the disk and ROM image parsers and traits
+//! of image_rider.  It primarily focuses on older 8-bit and 16-bit
+//! systems.
+//!
+//! The image_rider crate re-exports every module here under the same
+//! path, along with the example command line tool.  Both crates depend
+//! on the same libraries: nom for parsing, config and toml for
+//! settings, serde and serde_json for serialization and log.  The
+//! library needs the standard library, it reads and writes image files
+//! with std::fs.
+//!
+//! The primary API for this library are a set of traits in the
+//! [image_rider_core::disk_format::image](crate::disk_format::image) module.
+//!
+//! The disk_format module contains everything to parse disk formats
+//!
+//! The rom_format module contains everything to parse ROM formats,
+//! starting with NES cartridges
+//!
+//! The parsers take their settings as [Options](crate::options::Options),
+//! which can be shared between threads and overridden for one image.
+//!
+//! Log messages are sent to one of three targets, listed in
+//! [log_target], so applications can set the verbosity of each part of
+//! the library separately.
+//!
+//! The minimum supported Rust version is 1.75.  Parts of the library
+//! that need a newer compiler are behind optional features.
+//!
+use log::error;
+
+pub mod disk_format;
+pub mod error;
+pub mod options;
+pub mod rom_format;
+pub mod serialize;
+
+/// Log targets for each part of the library
+///
+/// With env_logger, for example, RUST_LOG=image_rider::parse=warn,image_rider::io=info
+/// hides the per-sector parser messages but keeps a record of the
+/// files read and written.
+pub mod log_target {
+    /// Parsing images, headers and filesystems
+    pub const PARSE: &str = "image_rider::parse";
+    /// Reading and writing files and directories
+    pub const IO: &str = "image_rider::io";
+    /// Converting and editing data: encoding and decoding tracks,
+    /// unwrapping archives, overlays and sector edits
+    pub const CONVERT: &str = "image_rider::convert";
+}
+
+/// Initialize the module.
+/// This should be called before any parsing is performed.
+/// Panics on failure or if there are any incompatibilities.
+pub fn init() {
+    // If we're on a system with a usize < 32 bits then fail.  This
+    // crate is geared towards parsing file formats for 8-bit systems,
+    // but the code currently does not run on 8-bit systems.  For
+    // example, we read the entire file into a single image data array
+    // and access the data array with usize indexes for several of the
+    // file formats.
+    if usize::BITS < 32 {
+        error!(
+            target: log_target::PARSE,
+            "Architecture usize {} is too small for this library",
+            usize::BITS
+        );
+        panic!(
+            "Architecture usize {} is too small for this library",
+            usize::BITS
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Parser options shared between threads, with per-image overrides
+//!
+//! The parser entry points take an [Options] rather than a Config.
+//! An Options holds the settings behind a reference count, so cloning
+//! one for each image of a batch, or each thread parsing them, doesn't
+//! copy the settings.
+//!
+//! Settings for a single image are layered over the shared settings
+//! with [Options::with_override], which returns new Options and leaves
+//! the shared settings untouched:
+//!
+//! ```
+//! use config::Config;
+//! use image_rider_core::options::Options;
+//!
+//! let options = Options::new(Config::default());
+//! let damaged = options.with_override("ignore-checksums", true).unwrap();
+//! assert!(damaged.get_bool("ignore-checksums").unwrap());
+//! assert!(options.get_bool("ignore-checksums").is_err());
+//! ```
+//!
+//! Options dereference to the Config they hold, so the settings are
+//! read with the usual Config methods.
+use std::ops::Deref;
+use std::sync::Arc;
+
+use config::{Config, Value};
+
+use crate::disk_format::charset::Charset;
+use crate::disk_format::limits::Limits;
+use crate::error::Error;
+
+/// Settings for the parsers: the shared settings with any overrides
+/// for one image layered on top
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The shared settings, before any overrides
+    global: Arc<Config>,
+    /// The settings with the overrides applied
+    config: Arc<Config>,
+}
+
+impl Options {
+    /// Create Options from shared settings
+    pub fn new(config: Config) -> Options {
+        let config = Arc::new(config);
+        Options {
+            global: config.clone(),
+            config,
+        }
+    }
+
+    /// Return new Options with a setting overridden
+    /// Overrides are layered over the settings and any earlier
+    /// overrides, these Options aren't changed.
+    pub fn with_override<T: Into<Value>>(
+        &self,
+        key: &str,
+        value: T,
+    ) -> std::result::Result<Options, Error> {
+        let config = Config::builder()
+            .add_source(self.config.as_ref().clone())
+            .set_override(key, value)?
+            .build()?;
+        Ok(Options {
+            global: self.global.clone(),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Return the shared settings, without any overrides
+    pub fn global(&self) -> &Config {
+        &self.global
+    }
+
+    /// Return the settings with the overrides applied
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Return the limits on what image headers can make the parsers
+    /// allocate
+    pub fn limits(&self) -> Limits {
+        Limits::from_config(&self.config)
+    }
+
+    /// Return the character sets names and text are read with
+    pub fn charset(&self) -> Charset {
+        Charset::from_config(&self.config)
+    }
+
+    /// Return true if any settings are overridden
+    pub fn has_overrides(&self) -> bool {
+        !Arc::ptr_eq(&self.global, &self.config)
+    }
+}
+
+impl From<Config> for Options {
+    fn from(config: Config) -> Options {
+        Options::new(config)
+    }
+}
+
+impl Deref for Options {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use config::Config;
+
+    use super::Options;
+
+    /// Test layering overrides over shared settings
+    #[test]
+    fn with_override_works() {
+        let config = Config::builder()
+            .set_override("volume", 254)
+            .unwrap()
+            .build()
+            .unwrap();
+        let options = Options::new(config);
+        assert!(!options.has_overrides());
+
+        let overridden = options
+            .with_override("ignore-checksums", true)
+            .unwrap()
+            .with_override("volume", 1)
+            .unwrap();
+        assert!(overridden.has_overrides());
+        assert!(overridden.get_bool("ignore-checksums").unwrap());
+        assert_eq!(overridden.get_int("volume").unwrap(), 1);
+        assert_eq!(overridden.global().get_int("volume").unwrap(), 254);
+        let limited = options.with_override("max-tracks", 40).unwrap();
+        assert_eq!(limited.limits().max_tracks, 40);
+        assert_eq!(options.limits().max_tracks, 256);
+
+        // The shared settings are unchanged
+        assert!(options.get_bool("ignore-checksums").is_err());
+        assert_eq!(options.get_int("volume").unwrap(), 254);
+
+        // Options are shared between threads, each with its own overrides
+        let handles: Vec<_> = (0..4)
+            .map(|n| {
+                let options = options.clone();
+                thread::spawn(move || {
+                    let options = options.with_override("volume", n).unwrap();
+                    options.get_int("volume").unwrap()
+                })
+            })
+            .collect();
+        let volumes: Vec<i64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(volumes, vec![0, 1, 2, 3]);
+        assert_eq!(options.get_int("volume").unwrap(), 254);
+    }
+}
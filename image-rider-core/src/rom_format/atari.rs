@@ -0,0 +1,597 @@
+//! Atari 2600 and 7800 cartridge images
+//!
+//! 2600 cartridges are dumped without a header.  The console only has
+//! four kilobytes of cartridge address space, so larger cartridges
+//! switch banks when the program touches a hotspot address near the
+//! end of the space.  The scheme is guessed from the size of the dump
+//! and the hotspots the code reads or writes:
+//!
+//! ```ignore
+//! 2K: one bank, mirrored twice in the address space
+//! 4K: one bank
+//! F8: two 4K banks, hotspots 1FF8 to 1FF9
+//! F6: four 4K banks, hotspots 1FF6 to 1FF9
+//! F4: eight 4K banks, hotspots 1FF4 to 1FFB
+//! ```
+//!
+//! 7800 cartridges are usually stored as .a78 files, with a 128 byte
+//! header describing the cartridge:
+//!
+//! ```ignore
+//! 0: header version
+//! 1: "ATARI7800", padded with zeros to 16 bytes
+//! 17: the title, padded with zeros to 32 bytes
+//! 49: the ROM size without the header, 32 bit big endian
+//! 53: the cartridge type bits, 16 bit big endian
+//! 55: the controller types for ports one and two
+//! 57: the TV type, 0 for NTSC and 1 for PAL
+//! 100: "ACTUAL CART DATA STARTS HERE"
+//! ```
+//!
+//! SuperGame cartridges switch 16K banks into 8000 to BFFF, with the
+//! last bank fixed at C000.
+//!
+//! Information from:\
+//! [Bankswitching](https://www.atariage.com/2600/archives/schemes/index.html)\
+//! [A78 header](https://7800.8bitdev.org/index.php/A78_Header_Specification)
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, info};
+
+use nom::bytes::complete::{tag, take};
+use nom::combinator::rest;
+use nom::number::complete::{be_u16, be_u32, le_u8};
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// An Atari 2600 cartridge dump
+pub const A26_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "A26",
+    description: "Atari 2600 cartridge ROM, 2K, 4K, F8, F6 or F4 bank switched",
+    extensions: &["a26", "bin"],
+    sizes: &[
+        SizeRange::exact(2048),
+        SizeRange::exact(4096),
+        SizeRange::exact(8192),
+        SizeRange::exact(16384),
+        SizeRange::exact(32768),
+    ],
+    references: &[Reference {
+        title: "Bankswitching",
+        url: Some("https://www.atariage.com/2600/archives/schemes/index.html"),
+    }],
+    capabilities: Capabilities::NONE,
+};
+
+/// An Atari 7800 cartridge image with an A78 header
+pub const A78_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "A78",
+    description: "Atari 7800 cartridge ROM with an A78 header",
+    extensions: &["a78"],
+    sizes: &[SizeRange::at_least(A78_HEADER_SIZE)],
+    references: &[Reference {
+        title: "A78 header",
+        url: Some("https://7800.8bitdev.org/index.php/A78_Header_Specification"),
+    }],
+    capabilities: Capabilities::NONE,
+};
+
+/// The size of a 2600 bank, the size of the cartridge address space
+pub const ATARI_2600_BANK_SIZE: usize = 4096;
+
+/// The signature after the version byte of an .a78 header
+pub const A78_MAGIC: &[u8] = b"ATARI7800";
+
+/// The size of the .a78 header
+pub const A78_HEADER_SIZE: usize = 128;
+
+/// The size of a SuperGame bank
+pub const SUPERGAME_BANK_SIZE: usize = 16384;
+
+/// Instructions that read or write an absolute address, the ways code
+/// touches a hotspot: LDA, LDX, LDY, STA, STX, STY, BIT, CMP and NOP
+const ABSOLUTE_OPCODES: [u8; 9] = [0xAD, 0xAE, 0xAC, 0x8D, 0x8E, 0x8C, 0x2C, 0xCD, 0x0C];
+
+/// Return true if the filename is a 2600 cartridge image, .a26
+/// 2600 dumps have no signature, so they're only read by name.
+pub fn is_atari_2600_filename(filename: &str) -> bool {
+    filename.to_lowercase().ends_with(".a26")
+}
+
+/// Return true if the data starts with an .a78 header
+pub fn is_a78(data: &[u8]) -> bool {
+    data.get(1..1 + A78_MAGIC.len()) == Some(A78_MAGIC)
+}
+
+/// The bank switching scheme of a 2600 cartridge
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Atari2600BankSwitching {
+    /// A 2K cartridge, mirrored in the address space
+    Standard2K,
+    /// A 4K cartridge, without bank switching
+    Standard4K,
+    /// Atari 8K, two banks
+    F8,
+    /// Atari 16K, four banks
+    F6,
+    /// Atari 32K, eight banks
+    F4,
+}
+
+impl Atari2600BankSwitching {
+    /// Return the scheme for a cartridge size, before checking hotspots
+    fn from_size(size: usize) -> Option<Atari2600BankSwitching> {
+        match size {
+            2048 => Some(Atari2600BankSwitching::Standard2K),
+            4096 => Some(Atari2600BankSwitching::Standard4K),
+            8192 => Some(Atari2600BankSwitching::F8),
+            16384 => Some(Atari2600BankSwitching::F6),
+            32768 => Some(Atari2600BankSwitching::F4),
+            _ => None,
+        }
+    }
+
+    /// Return the offsets of the hotspots in the 4K address space,
+    /// empty for cartridges without bank switching
+    pub fn hotspots(&self) -> std::ops::Range<u16> {
+        match self {
+            Atari2600BankSwitching::Standard2K | Atari2600BankSwitching::Standard4K => 0..0,
+            Atari2600BankSwitching::F8 => 0xFF8..0xFFA,
+            Atari2600BankSwitching::F6 => 0xFF6..0xFFA,
+            Atari2600BankSwitching::F4 => 0xFF4..0xFFC,
+        }
+    }
+
+    /// Return the size of a bank
+    pub fn bank_size(&self) -> usize {
+        match self {
+            Atari2600BankSwitching::Standard2K => 2048,
+            _ => ATARI_2600_BANK_SIZE,
+        }
+    }
+}
+
+impl Display for Atari2600BankSwitching {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Atari2600BankSwitching::Standard2K => write!(f, "2K"),
+            Atari2600BankSwitching::Standard4K => write!(f, "4K"),
+            Atari2600BankSwitching::F8 => write!(f, "F8"),
+            Atari2600BankSwitching::F6 => write!(f, "F6"),
+            Atari2600BankSwitching::F4 => write!(f, "F4"),
+        }
+    }
+}
+
+/// Count the instructions that touch a hotspot of a scheme
+/// The cartridge is selected by address line 12, so any address with
+/// bit 12 set is in the cartridge space.
+pub fn hotspot_accesses(data: &[u8], scheme: Atari2600BankSwitching) -> usize {
+    let hotspots = scheme.hotspots();
+    data.windows(3)
+        .filter(|w| ABSOLUTE_OPCODES.contains(&w[0]))
+        .map(|w| u16::from_le_bytes([w[1], w[2]]))
+        .filter(|address| address & 0x1000 != 0 && hotspots.contains(&(address & 0x0FFF)))
+        .count()
+}
+
+/// Guess the bank switching scheme of a 2600 cartridge
+/// Returns None if the size isn't one of the known schemes, or the
+/// code never touches the hotspots of the scheme for its size, which
+/// usually means another scheme with the same size.
+pub fn detect_bank_switching(data: &[u8]) -> Option<Atari2600BankSwitching> {
+    let scheme = Atari2600BankSwitching::from_size(data.len())?;
+    if !scheme.hotspots().is_empty() && hotspot_accesses(data, scheme) == 0 {
+        debug!(
+            target: PARSE,
+            "No {} hotspot accesses in a {} byte cartridge",
+            scheme,
+            data.len()
+        );
+        return None;
+    }
+    Some(scheme)
+}
+
+/// A parsed 2600 cartridge image
+pub struct Atari2600Rom<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The bank switching scheme
+    pub bank_switching: Atari2600BankSwitching,
+}
+
+impl<'a> Atari2600Rom<'a> {
+    /// Return the banks, in the order they're selected
+    pub fn banks(&self) -> Vec<&'a [u8]> {
+        self.data.chunks(self.bank_switching.bank_size()).collect()
+    }
+}
+
+impl Display for Atari2600Rom<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "Atari 2600, bank switching: {}, banks: {}",
+            self.bank_switching,
+            self.banks().len()
+        )
+    }
+}
+
+impl SanityCheck for Atari2600Rom<'_> {
+    fn check(&self) -> bool {
+        Atari2600BankSwitching::from_size(self.data.len()) == Some(self.bank_switching)
+    }
+}
+
+impl DiskImageSaver for Atari2600Rom<'_> {
+    /// Write the whole image, or a bank if one is selected
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        save_rom(self.data, &self.disk_files(), selected_filename, writer)
+    }
+
+    /// The banks of the cartridge as files, BANK0.BIN and up
+    fn disk_files(&self) -> Vec<DiskFile> {
+        bank_files(&self.banks())
+    }
+}
+
+/// Parse a 2600 cartridge image and guess its bank switching scheme
+pub fn atari_2600_rom_parser(i: &[u8]) -> IResult<&[u8], Atari2600Rom<'_>> {
+    let Some(bank_switching) = detect_bank_switching(i) else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Verify,
+        )));
+    };
+    let (i, data) = rest(i)?;
+
+    Ok((
+        i,
+        Atari2600Rom {
+            data,
+            bank_switching,
+        },
+    ))
+}
+
+/// The TV system a 7800 cartridge was made for
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TVType {
+    /// NTSC, 60Hz
+    NTSC,
+    /// PAL, 50Hz
+    PAL,
+}
+
+/// The .a78 header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct A78Header {
+    /// The header version
+    pub version: u8,
+    /// The cartridge title
+    pub title: String,
+    /// The size of the ROM after the header
+    pub rom_size: usize,
+    /// The cartridge type bits
+    pub cart_type: u16,
+    /// The controller types for ports one and two, 1 for a joystick
+    pub controllers: [u8; 2],
+    /// The TV system
+    pub tv_type: TVType,
+}
+
+impl A78Header {
+    /// Return true if the cartridge has a POKEY sound chip at 4000
+    pub fn pokey(&self) -> bool {
+        self.cart_type & 0x0001 != 0
+    }
+
+    /// Return true if the cartridge switches 16K SuperGame banks
+    pub fn supergame(&self) -> bool {
+        self.cart_type & 0x0002 != 0
+    }
+
+    /// Return true if the cartridge has RAM at 4000
+    pub fn ram_at_4000(&self) -> bool {
+        self.cart_type & 0x0004 != 0
+    }
+
+    /// Return true if the cartridge maps ROM at 4000
+    pub fn rom_at_4000(&self) -> bool {
+        self.cart_type & 0x0008 != 0
+    }
+}
+
+impl Display for A78Header {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "A78 version {}, title: {}, ROM: {}KB, {:?}",
+            self.version,
+            self.title,
+            self.rom_size / 1024,
+            self.tv_type
+        )?;
+        for (set, name) in [
+            (self.supergame(), "SuperGame"),
+            (self.pokey(), "POKEY"),
+            (self.ram_at_4000(), "RAM at 4000"),
+            (self.rom_at_4000(), "ROM at 4000"),
+        ] {
+            if set {
+                write!(f, ", {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for A78Header {
+    fn check(&self) -> bool {
+        if self.rom_size == 0 || self.rom_size % 1024 != 0 {
+            debug!(
+                target: PARSE,
+                "A78 ROM size isn't a multiple of 1K: {}", self.rom_size
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// A parsed .a78 cartridge image
+pub struct Atari7800Rom<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The .a78 header
+    pub header: A78Header,
+    /// The ROM after the header
+    pub rom: &'a [u8],
+}
+
+impl<'a> Atari7800Rom<'a> {
+    /// Return the banks, 16K banks for SuperGame cartridges or the
+    /// whole ROM
+    pub fn banks(&self) -> Vec<&'a [u8]> {
+        if self.header.supergame() {
+            self.rom.chunks(SUPERGAME_BANK_SIZE).collect()
+        } else {
+            vec![self.rom]
+        }
+    }
+}
+
+impl Display for Atari7800Rom<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}\nbanks: {}", self.header, self.banks().len())
+    }
+}
+
+impl SanityCheck for Atari7800Rom<'_> {
+    fn check(&self) -> bool {
+        self.header.check()
+    }
+}
+
+impl DiskImageSaver for Atari7800Rom<'_> {
+    /// Write the whole image, or the ROM without the header or a bank
+    /// if one is selected
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        save_rom(self.data, &self.disk_files(), selected_filename, writer)
+    }
+
+    /// The ROM without the header as ROM.BIN, and the banks of
+    /// SuperGame cartridges as BANK0.BIN and up
+    fn disk_files(&self) -> Vec<DiskFile> {
+        let mut files = vec![rom_file("ROM.BIN", "ROM", self.rom)];
+        if self.header.supergame() {
+            files.extend(bank_files(&self.banks()));
+        }
+        files
+    }
+}
+
+/// Parse an .a78 header
+pub fn a78_header_parser(i: &[u8]) -> IResult<&[u8], A78Header> {
+    let (i, version) = le_u8(i)?;
+    let (i, _magic) = tag(A78_MAGIC)(i)?;
+    let (i, _padding) = take(16 - A78_MAGIC.len())(i)?;
+    let (i, title) = take(32_usize)(i)?;
+    let (i, rom_size) = be_u32(i)?;
+    let (i, cart_type) = be_u16(i)?;
+    let (i, controller_1) = le_u8(i)?;
+    let (i, controller_2) = le_u8(i)?;
+    let (i, tv_type) = le_u8(i)?;
+    let (i, _rest) = take(A78_HEADER_SIZE - 58)(i)?;
+
+    let title: String = title
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| char::from(*c))
+        .collect();
+
+    Ok((
+        i,
+        A78Header {
+            version,
+            title: String::from(title.trim_end()),
+            rom_size: rom_size as usize,
+            cart_type,
+            controllers: [controller_1, controller_2],
+            tv_type: if tv_type & 0x01 != 0 {
+                TVType::PAL
+            } else {
+                TVType::NTSC
+            },
+        },
+    ))
+}
+
+/// Parse an .a78 image
+pub fn a78_rom_parser(i: &[u8]) -> IResult<&[u8], Atari7800Rom<'_>> {
+    let data = i;
+    let (i, header) = a78_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[49..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let (i, rom) = take(header.rom_size)(i)?;
+
+    Ok((i, Atari7800Rom { data, header, rom }))
+}
+
+/// Make a file from an area of a ROM
+pub(crate) fn rom_file(name: &str, file_type: &str, data: &[u8]) -> DiskFile {
+    DiskFile {
+        name: String::from(name),
+        file_type: String::from(file_type),
+        raw_name: name.as_bytes().to_vec(),
+        data: data.to_vec(),
+    }
+}
+
+/// Make files from the banks of a ROM, BANK0.BIN and up
+pub(crate) fn bank_files(banks: &[&[u8]]) -> Vec<DiskFile> {
+    banks
+        .iter()
+        .enumerate()
+        .map(|(n, bank)| rom_file(&format!("BANK{}.BIN", n), "Bank", bank))
+        .collect()
+}
+
+/// Write a whole image, or one of its files if one is selected
+pub(crate) fn save_rom(
+    data: &[u8],
+    files: &[DiskFile],
+    selected_filename: Option<&str>,
+    writer: &mut impl Write,
+) -> std::result::Result<(), Error> {
+    if let Some(selected_filename) = selected_filename {
+        let file = files
+            .iter()
+            .find(|file| file.name.eq_ignore_ascii_case(selected_filename))
+            .ok_or_else(|| {
+                error!(target: IO, "File not found: {}", selected_filename);
+                Error::new(ErrorKind::NotFound(format!(
+                    "File not found: {}",
+                    selected_filename
+                )))
+            })?;
+        info!(target: IO, "Found file {}, writing data", file.name);
+        writer.write_all(&file.data)?;
+        return Ok(());
+    }
+
+    info!(target: IO, "Found image data, writing data");
+    writer.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        a78_rom_parser, atari_2600_rom_parser, detect_bank_switching, is_a78,
+        is_atari_2600_filename, Atari2600BankSwitching, TVType,
+    };
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+
+    /// Test guessing 2600 bank switching schemes
+    #[test]
+    fn detect_bank_switching_works() {
+        assert!(is_atari_2600_filename("Combat.A26"));
+        assert_eq!(
+            detect_bank_switching(&[0; 2048]),
+            Some(Atari2600BankSwitching::Standard2K)
+        );
+        assert_eq!(
+            detect_bank_switching(&testgen::atari_2600(1)),
+            Some(Atari2600BankSwitching::Standard4K)
+        );
+        assert_eq!(
+            detect_bank_switching(&testgen::atari_2600(2)),
+            Some(Atari2600BankSwitching::F8)
+        );
+        assert_eq!(
+            detect_bank_switching(&testgen::atari_2600(4)),
+            Some(Atari2600BankSwitching::F6)
+        );
+        assert_eq!(
+            detect_bank_switching(&testgen::atari_2600(8)),
+            Some(Atari2600BankSwitching::F4)
+        );
+        // 8K without F8 hotspot accesses is another scheme
+        assert_eq!(detect_bank_switching(&[0; 8192]), None);
+        assert_eq!(detect_bank_switching(&[0; 3000]), None);
+
+        let data = testgen::atari_2600(4);
+        let (_, rom) = atari_2600_rom_parser(&data).unwrap();
+        assert!(rom.check());
+        assert_eq!(rom.banks().len(), 4);
+        assert_eq!(rom.banks()[2][100], 2);
+        assert_eq!(rom.to_string(), "Atari 2600, bank switching: F6, banks: 4");
+        let config = Config::default();
+        assert_eq!(
+            rom.to_bytes(&config, Some("BANK3.BIN")).unwrap(),
+            rom.banks()[3]
+        );
+        assert!(rom.to_bytes(&config, Some("BANK4.BIN")).is_err());
+    }
+
+    /// Test parsing an .a78 header and splitting SuperGame banks
+    #[test]
+    fn a78_rom_parser_works() {
+        let data = testgen::a78("ROBOTRON", 128 * 1024, 0x0002);
+        assert!(is_a78(&data));
+        let (_, rom) = a78_rom_parser(&data).unwrap();
+        assert!(rom.check());
+        assert_eq!(rom.header.version, 3);
+        assert_eq!(rom.header.title, "ROBOTRON");
+        assert!(rom.header.supergame());
+        assert!(!rom.header.pokey());
+        assert_eq!(rom.header.tv_type, TVType::NTSC);
+        assert_eq!(rom.banks().len(), 8);
+        assert_eq!(rom.banks()[7][0], 7);
+        assert_eq!(
+            rom.header.to_string(),
+            "A78 version 3, title: ROBOTRON, ROM: 128KB, NTSC, SuperGame"
+        );
+        let config = Config::default();
+        assert_eq!(rom.to_bytes(&config, None).unwrap(), data);
+        assert_eq!(
+            rom.to_bytes(&config, Some("ROM.BIN")).unwrap(),
+            &data[128..]
+        );
+        assert_eq!(rom.disk_files().len(), 9);
+
+        let data = testgen::a78("ASTEROIDS", 48 * 1024, 0);
+        let (_, rom) = a78_rom_parser(&data).unwrap();
+        assert_eq!(rom.banks().len(), 1);
+        assert!(a78_rom_parser(&data[..1000]).is_err());
+    }
+}
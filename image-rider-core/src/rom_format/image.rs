@@ -0,0 +1,297 @@
+//! The image_rider_core::rom_format::image module holds RomImage, the
+//! ROM counterpart of DiskImage, and the parsers that return it.
+use config::Config;
+use log::debug;
+
+use nom::combinator::map;
+use nom::IResult;
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use crate::log_target::PARSE;
+use crate::options::Options;
+use crate::{
+    disk_format::{
+        file_select::DiskFile,
+        image::DiskImageSaver,
+        limits::{check_file_size, with_limits},
+        parsed::{collect_warnings, Parsed},
+        sanity_check::SanityCheck,
+    },
+    error::{Error, ErrorKind, InvalidErrorKind},
+    init,
+    rom_format::{
+        atari::{
+            a78_rom_parser, atari_2600_rom_parser, is_a78, is_atari_2600_filename, Atari2600Rom,
+            Atari7800Rom,
+        },
+        nes::{is_nes, nes_rom_parser, NESHeaderFormat, NESRom},
+        snes::{is_snes_filename, snes_rom_parser, SNESRom},
+    },
+};
+
+/// RomImage is the primary enumeration for holding ROM images
+///
+/// Like DiskImage, each variant holds its ROM boxed.  Match on the
+/// variant to get at the ROM, or use as_nes, as_atari_2600,
+/// as_atari_7800 and as_snes.
+pub enum RomImage<'a> {
+    /// An NES or Famicom cartridge in an iNES or NES 2.0 .nes file
+    NES(Box<NESRom<'a>>),
+    /// An Atari 2600 cartridge dump, with its bank switching scheme
+    Atari2600(Box<Atari2600Rom<'a>>),
+    /// An Atari 7800 cartridge in an .a78 file
+    Atari7800(Box<Atari7800Rom<'a>>),
+    /// An SNES cartridge dump, with or without a copier header
+    SNES(Box<SNESRom<'a>>),
+}
+
+/// Display a RomImage
+impl Display for RomImage<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            RomImage::NES(nes_rom) => write!(f, "{}\n{}", self.format_name(), nes_rom),
+            RomImage::Atari2600(atari_rom) => {
+                write!(f, "{}\n{}", self.format_name(), atari_rom)
+            }
+            RomImage::Atari7800(atari_rom) => {
+                write!(f, "{}\n{}", self.format_name(), atari_rom)
+            }
+            RomImage::SNES(snes_rom) => write!(f, "{}\n{}", self.format_name(), snes_rom),
+        }
+    }
+}
+
+impl<'a> RomImage<'a> {
+    /// Return the NES ROM, or None for other ROMs
+    pub fn as_nes(&self) -> Option<&NESRom<'a>> {
+        match self {
+            RomImage::NES(nes_rom) => Some(nes_rom),
+            _ => None,
+        }
+    }
+
+    /// Return the Atari 2600 ROM, or None for other ROMs
+    pub fn as_atari_2600(&self) -> Option<&Atari2600Rom<'a>> {
+        match self {
+            RomImage::Atari2600(atari_rom) => Some(atari_rom),
+            _ => None,
+        }
+    }
+
+    /// Return the Atari 7800 ROM, or None for other ROMs
+    pub fn as_atari_7800(&self) -> Option<&Atari7800Rom<'a>> {
+        match self {
+            RomImage::Atari7800(atari_rom) => Some(atari_rom),
+            _ => None,
+        }
+    }
+
+    /// Return the SNES ROM, or None for other ROMs
+    pub fn as_snes(&self) -> Option<&SNESRom<'a>> {
+        match self {
+            RomImage::SNES(snes_rom) => Some(snes_rom),
+            _ => None,
+        }
+    }
+
+    /// Return the banks of the cartridge, as an emulator maps them
+    /// NES cartridges return their PRG ROM banks.
+    pub fn banks(&self) -> Vec<&'a [u8]> {
+        match self {
+            RomImage::NES(nes_rom) => nes_rom.prg_banks(),
+            RomImage::Atari2600(atari_rom) => atari_rom.banks(),
+            RomImage::Atari7800(atari_rom) => atari_rom.banks(),
+            RomImage::SNES(snes_rom) => snes_rom.banks(),
+        }
+    }
+}
+
+impl RomImage<'_> {
+    /// Return the type of image on one line, e.g. "NES ROM"
+    pub fn format_name(&self) -> String {
+        match self {
+            RomImage::NES(nes_rom) if nes_rom.header.format == NESHeaderFormat::NES20 => {
+                String::from("NES 2.0 ROM")
+            }
+            RomImage::NES(_) => String::from("NES ROM"),
+            RomImage::Atari2600(_) => String::from("Atari 2600 ROM"),
+            RomImage::Atari7800(_) => String::from("Atari 7800 ROM"),
+            RomImage::SNES(_) => String::from("SNES ROM"),
+        }
+    }
+}
+
+impl SanityCheck for RomImage<'_> {
+    fn check(&self) -> bool {
+        match self {
+            RomImage::NES(nes_rom) => nes_rom.check(),
+            RomImage::Atari2600(atari_rom) => atari_rom.check(),
+            RomImage::Atari7800(atari_rom) => atari_rom.check(),
+            RomImage::SNES(snes_rom) => snes_rom.check(),
+        }
+    }
+}
+
+impl DiskImageSaver for RomImage<'_> {
+    fn save_to_writer(
+        &self,
+        config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        match self {
+            RomImage::NES(nes_rom) => nes_rom.save_to_writer(config, selected_filename, writer),
+            RomImage::Atari2600(atari_rom) => {
+                atari_rom.save_to_writer(config, selected_filename, writer)
+            }
+            RomImage::Atari7800(atari_rom) => {
+                atari_rom.save_to_writer(config, selected_filename, writer)
+            }
+            RomImage::SNES(snes_rom) => snes_rom.save_to_writer(config, selected_filename, writer),
+        }
+    }
+
+    fn disk_files(&self) -> Vec<DiskFile> {
+        match self {
+            RomImage::NES(nes_rom) => nes_rom.disk_files(),
+            RomImage::Atari2600(atari_rom) => atari_rom.disk_files(),
+            RomImage::Atari7800(atari_rom) => atari_rom.disk_files(),
+            RomImage::SNES(snes_rom) => snes_rom.disk_files(),
+        }
+    }
+}
+
+/// Return true if the data starts with the signature of a ROM format,
+/// or the filename is a ROM format without a signature
+pub fn is_rom_image(filename: &str, data: &[u8]) -> bool {
+    is_nes(data) || is_a78(data) || is_atari_2600_filename(filename) || is_snes_filename(filename)
+}
+
+/// Parse a ROM image given a filename
+/// ROM formats with a signature are checked first, 2600 and SNES dumps
+/// have no signature and are only read when they're named .a26 or
+/// .sfc and the like.
+pub fn rom_image_parser<'a>(filename: &str, i: &'a [u8]) -> IResult<&'a [u8], RomImage<'a>> {
+    if is_nes(i) {
+        debug!(target: PARSE, "Attempting to parse NES ROM");
+        return map(nes_rom_parser, |nes_rom| RomImage::NES(Box::new(nes_rom)))(i);
+    }
+    if is_a78(i) {
+        debug!(target: PARSE, "Attempting to parse Atari 7800 ROM");
+        return map(a78_rom_parser, |atari_rom| {
+            RomImage::Atari7800(Box::new(atari_rom))
+        })(i);
+    }
+    if is_atari_2600_filename(filename) {
+        debug!(target: PARSE, "Attempting to parse Atari 2600 ROM");
+        return map(atari_2600_rom_parser, |atari_rom| {
+            RomImage::Atari2600(Box::new(atari_rom))
+        })(i);
+    }
+    if is_snes_filename(filename) {
+        debug!(target: PARSE, "Attempting to parse SNES ROM");
+        return map(snes_rom_parser, |snes_rom| {
+            RomImage::SNES(Box::new(snes_rom))
+        })(i);
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        i,
+        nom::error::ErrorKind::Tag,
+    )))
+}
+
+/// This trait is the ROM counterpart of DiskImageParser
+pub trait RomImageParser<'a, 'b> {
+    /// Parse an entire ROM image, returning a RomImage
+    ///
+    /// # Arguments
+    ///
+    /// - `config` - The Options that guide parsing.
+    /// - `filename` - The name of the file to parse.
+    ///
+    /// # Returns
+    ///
+    /// A Result containing the RomImage and any warnings found while
+    /// parsing it, or an Error.
+    fn parse_rom_image(
+        &'a self,
+        config: &'b Options,
+        filename: &str,
+    ) -> std::result::Result<Parsed<RomImage<'a>>, Error>;
+}
+
+/// Implementation of RomImageParser for 8-bit integer vectors
+impl<'a, 'b> RomImageParser<'a, 'b> for Vec<u8> {
+    fn parse_rom_image(
+        &'a self,
+        config: &'b Options,
+        filename: &str,
+    ) -> std::result::Result<Parsed<RomImage<'a>>, Error> {
+        // Initialize the image-rider module
+        init();
+
+        with_limits(config.limits(), || {
+            check_file_size(self.len())?;
+
+            if !is_rom_image(filename, self) {
+                return Err(Error::new(ErrorKind::Unimplemented(format!(
+                    "Unknown ROM format: {}",
+                    filename
+                ))));
+            }
+            collect_warnings(|| match rom_image_parser(filename, self) {
+                Ok((_, rom_image)) => Ok(rom_image),
+                Err(e) => Err(Error::new(ErrorKind::Invalid(InvalidErrorKind::Invalid(
+                    e.to_string(),
+                )))),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RomImageParser;
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::testgen;
+    use crate::options::Options;
+
+    /// Test parsing a ROM image through the RomImageParser trait
+    #[test]
+    fn parse_rom_image_works() {
+        let config = Options::default();
+        let data = testgen::nes(1, 2, 2, false, true);
+        let image = data.parse_rom_image(&config, "game.nes").unwrap();
+        assert!(image.is_clean());
+        assert_eq!(image.format_name(), "NES 2.0 ROM");
+        assert_eq!(image.as_nes().unwrap().chr_banks().len(), 2);
+        assert_eq!(image.to_bytes(&config, None).unwrap(), data);
+        assert_eq!(image.disk_files().len(), 2);
+        assert!(image
+            .to_string()
+            .starts_with("NES 2.0 ROM\nNES 2.0, mapper: 1.0"));
+
+        let data = vec![0_u8; 16];
+        assert!(data.parse_rom_image(&config, "game.nes").is_err());
+        let data = testgen::nes(1, 2, 2, false, false)[..1000].to_vec();
+        assert!(data.parse_rom_image(&config, "game.nes").is_err());
+
+        let data = testgen::atari_2600(2);
+        let image = data.parse_rom_image(&config, "game.a26").unwrap();
+        assert_eq!(image.format_name(), "Atari 2600 ROM");
+        assert_eq!(image.banks().len(), 2);
+        assert!(data.parse_rom_image(&config, "game.bin").is_err());
+
+        let data = testgen::a78("GAME", 32768, 0);
+        let image = data.parse_rom_image(&config, "game.bin").unwrap();
+        assert_eq!(image.format_name(), "Atari 7800 ROM");
+        assert_eq!(image.as_atari_7800().unwrap().header.title, "GAME");
+
+        let data = testgen::snes("GAME", 0x20, 4, true);
+        let image = data.parse_rom_image(&config, "game.smc").unwrap();
+        assert_eq!(image.format_name(), "SNES ROM");
+        assert_eq!(image.as_snes().unwrap().header.title, "GAME");
+        assert!(data.parse_rom_image(&config, "game.bin").is_err());
+    }
+}
@@ -0,0 +1,21 @@
+#![warn(missing_docs)]
+#![warn(unsafe_code)]
+//!
+//! ROM format parsers
+//!
+//! Cartridge and other ROM dumps are parsed into a RomImage, the ROM
+//! counterpart of DiskImage.  The DiskImageSaver trait saves the whole
+//! image or its ROM areas.
+//!
+
+/// ROM image parser, parses cartridge images
+pub mod image;
+
+/// NES cartridge images
+pub mod nes;
+
+/// Atari 2600 and 7800 cartridge images
+pub mod atari;
+
+/// SNES cartridge images
+pub mod snes;
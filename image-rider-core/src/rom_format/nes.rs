@@ -0,0 +1,460 @@
+//! NES cartridge images
+//!
+//! .nes files are dumps of the ROM chips on an NES or Famicom
+//! cartridge, after a 16 byte header describing the cartridge board.
+//! The file layout is:
+//!
+//! ```ignore
+//! Header: "NES" 1A, PRG ROM size, CHR ROM size, flags 6 to 15
+//! Trainer: 512 bytes loaded at 7000, if flag 6 bit 2 is set
+//! PRG ROM: 16KB banks of program code
+//! CHR ROM: 8KB banks of pattern tables, none if the board has CHR RAM
+//! Miscellaneous ROM: anything left over, NES 2.0 only
+//! ```
+//!
+//! The mapper number is split across the high nybbles of flags 6 and
+//! 7.  NES 2.0 headers are marked with bits 2 and 3 of flag 7 set to
+//! 10, and add four more mapper bits, a submapper and the high bits of
+//! the ROM sizes in flags 8 and 9.  A high size nybble of F gives the
+//! size in bytes as an exponent and multiplier.
+//!
+//! Information from:\
+//! [iNES](https://www.nesdev.org/wiki/INES)\
+//! [NES 2.0](https://www.nesdev.org/wiki/NES_2.0)
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, error, info};
+
+use nom::bytes::complete::{tag, take};
+use nom::combinator::rest;
+use nom::number::complete::le_u8;
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::{Error, ErrorKind};
+use crate::log_target::{IO, PARSE};
+
+/// An iNES or NES 2.0 cartridge image
+pub const NES_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "NES",
+    description: "NES or Famicom cartridge ROM with an iNES or NES 2.0 header",
+    extensions: &["nes"],
+    sizes: &[SizeRange::at_least(NES_HEADER_SIZE)],
+    references: &[
+        Reference {
+            title: "iNES",
+            url: Some("https://www.nesdev.org/wiki/INES"),
+        },
+        Reference {
+            title: "NES 2.0",
+            url: Some("https://www.nesdev.org/wiki/NES_2.0"),
+        },
+    ],
+    capabilities: Capabilities::NONE,
+};
+
+/// The signature at the start of a .nes file
+pub const NES_MAGIC: &[u8] = b"NES\x1A";
+
+/// The size of the header
+pub const NES_HEADER_SIZE: usize = 16;
+
+/// The size of the trainer
+pub const TRAINER_SIZE: usize = 512;
+
+/// The size of a PRG ROM bank
+pub const PRG_BANK_SIZE: usize = 16384;
+
+/// The size of a CHR ROM bank
+pub const CHR_BANK_SIZE: usize = 8192;
+
+/// Return true if the data starts with the .nes signature
+pub fn is_nes(data: &[u8]) -> bool {
+    data.starts_with(NES_MAGIC)
+}
+
+/// The version of the header
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NESHeaderFormat {
+    /// An iNES header, with an eight bit mapper number
+    INES,
+    /// An NES 2.0 header, with a twelve bit mapper number, a submapper
+    /// and larger ROM sizes
+    NES20,
+}
+
+/// How the nametables are mirrored
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mirroring {
+    /// Horizontal mirroring, or mapper controlled
+    Horizontal,
+    /// Vertical mirroring
+    Vertical,
+    /// Four screens of nametable RAM on the cartridge
+    FourScreen,
+}
+
+/// The .nes header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NESHeader {
+    /// The version of the header
+    pub format: NESHeaderFormat,
+    /// The size of the PRG ROM in bytes
+    pub prg_rom_size: usize,
+    /// The size of the CHR ROM in bytes, zero for boards with CHR RAM
+    pub chr_rom_size: usize,
+    /// The mapper number
+    pub mapper: u16,
+    /// The submapper number, always zero in an iNES header
+    pub submapper: u8,
+    /// How the nametables are mirrored
+    pub mirroring: Mirroring,
+    /// True if the cartridge has battery-backed RAM
+    pub battery: bool,
+    /// True if a 512 byte trainer follows the header
+    pub trainer: bool,
+}
+
+impl Display for NESHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{}, mapper: {}",
+            match self.format {
+                NESHeaderFormat::INES => "iNES",
+                NESHeaderFormat::NES20 => "NES 2.0",
+            },
+            self.mapper
+        )?;
+        if self.format == NESHeaderFormat::NES20 {
+            write!(f, ".{}", self.submapper)?;
+        }
+        write!(
+            f,
+            ", PRG ROM: {}KB, CHR ROM: {}KB, mirroring: {:?}",
+            self.prg_rom_size / 1024,
+            self.chr_rom_size / 1024,
+            self.mirroring
+        )?;
+        if self.battery {
+            write!(f, ", battery")?;
+        }
+        if self.trainer {
+            write!(f, ", trainer")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for NESHeader {
+    fn check(&self) -> bool {
+        if self.prg_rom_size == 0 {
+            debug!(target: PARSE, "NES header has no PRG ROM");
+            return false;
+        }
+        true
+    }
+}
+
+/// A parsed .nes cartridge image
+pub struct NESRom<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The .nes header
+    pub header: NESHeader,
+    /// The trainer, if there is one
+    pub trainer: Option<&'a [u8]>,
+    /// The PRG ROM
+    pub prg_rom: &'a [u8],
+    /// The CHR ROM, empty for boards with CHR RAM
+    pub chr_rom: &'a [u8],
+    /// Any data after the CHR ROM
+    pub misc_rom: &'a [u8],
+}
+
+impl<'a> NESRom<'a> {
+    /// Return the 16KB PRG ROM banks
+    /// NES 2.0 sizes in exponent notation may leave a short last bank.
+    pub fn prg_banks(&self) -> Vec<&'a [u8]> {
+        self.prg_rom.chunks(PRG_BANK_SIZE).collect()
+    }
+
+    /// Return the 8KB CHR ROM banks
+    pub fn chr_banks(&self) -> Vec<&'a [u8]> {
+        self.chr_rom.chunks(CHR_BANK_SIZE).collect()
+    }
+}
+
+impl Display for NESRom<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{}\nPRG banks: {}, CHR banks: {}",
+            self.header,
+            self.prg_banks().len(),
+            self.chr_banks().len()
+        )?;
+        if !self.misc_rom.is_empty() {
+            write!(f, ", miscellaneous ROM: {} bytes", self.misc_rom.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for NESRom<'_> {
+    fn check(&self) -> bool {
+        self.header.check()
+    }
+}
+
+impl DiskImageSaver for NESRom<'_> {
+    /// Write the whole image, or the PRG ROM, CHR ROM or trainer if
+    /// one is selected
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        if let Some(selected_filename) = selected_filename {
+            let file = self
+                .disk_files()
+                .into_iter()
+                .find(|file| file.name.eq_ignore_ascii_case(selected_filename))
+                .ok_or_else(|| {
+                    error!(target: IO, "File not found: {}", selected_filename);
+                    Error::new(ErrorKind::NotFound(format!(
+                        "File not found: {}",
+                        selected_filename
+                    )))
+                })?;
+            info!(target: IO, "Found file {}, writing data", file.name);
+            writer.write_all(&file.data)?;
+            return Ok(());
+        }
+
+        info!(target: IO, "Found image data, writing data");
+        writer.write_all(self.data)?;
+        Ok(())
+    }
+
+    /// The ROM areas of the cartridge as files: PRG.BIN, CHR.BIN and
+    /// TRAINER.BIN
+    fn disk_files(&self) -> Vec<DiskFile> {
+        let trainer = self.trainer.unwrap_or_default();
+        [
+            ("TRAINER.BIN", "Trainer", trainer),
+            ("PRG.BIN", "PRG", self.prg_rom),
+            ("CHR.BIN", "CHR", self.chr_rom),
+        ]
+        .into_iter()
+        .filter(|(_, _, data)| !data.is_empty())
+        .map(|(name, file_type, data)| DiskFile {
+            name: String::from(name),
+            file_type: String::from(file_type),
+            raw_name: name.as_bytes().to_vec(),
+            data: data.to_vec(),
+        })
+        .collect()
+    }
+}
+
+/// Return a ROM size from its low byte and high nybble
+/// A high nybble of F means the low byte is an exponent and multiplier,
+/// 2^E * (MM * 2 + 1) bytes.  Returns None if the size doesn't fit.
+fn rom_size(low: u8, high: u8, unit: usize) -> Option<usize> {
+    if high == 0x0F {
+        let exponent = u32::from(low >> 2);
+        let multiplier = usize::from(low & 0x03) * 2 + 1;
+        return 1_usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier));
+    }
+    (usize::from(high) << 8 | usize::from(low)).checked_mul(unit)
+}
+
+/// Parse a .nes header
+pub fn nes_header_parser(i: &[u8]) -> IResult<&[u8], NESHeader> {
+    let (i, _magic) = tag(NES_MAGIC)(i)?;
+    let (i, prg_low) = le_u8(i)?;
+    let (i, chr_low) = le_u8(i)?;
+    let (i, flags6) = le_u8(i)?;
+    let (i, flags7) = le_u8(i)?;
+    let (i, flags) = take(8_usize)(i)?;
+
+    let format = if flags7 & 0x0C == 0x08 {
+        NESHeaderFormat::NES20
+    } else {
+        NESHeaderFormat::INES
+    };
+
+    let (prg_high, chr_high, submapper, mapper_high) = match format {
+        NESHeaderFormat::NES20 => (
+            flags[1] & 0x0F,
+            flags[1] >> 4,
+            flags[0] >> 4,
+            flags[0] & 0x0F,
+        ),
+        NESHeaderFormat::INES => (0, 0, 0, 0),
+    };
+    // Old dumping tools wrote their name into bytes 7 to 15, so an
+    // iNES header with anything past byte 11 only has a four bit mapper
+    let mapper_middle = if format == NESHeaderFormat::INES && flags[4..].iter().any(|b| *b != 0) {
+        debug!(target: PARSE, "Ignoring the high mapper nybble of an old iNES header");
+        0
+    } else {
+        flags7 >> 4
+    };
+    let mapper =
+        u16::from(mapper_high) << 8 | u16::from(mapper_middle) << 4 | u16::from(flags6 >> 4);
+
+    let invalid = || nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Verify));
+    let prg_rom_size = rom_size(prg_low, prg_high, PRG_BANK_SIZE).ok_or_else(invalid)?;
+    let chr_rom_size = rom_size(chr_low, chr_high, CHR_BANK_SIZE).ok_or_else(invalid)?;
+
+    let mirroring = if flags6 & 0x08 != 0 {
+        Mirroring::FourScreen
+    } else if flags6 & 0x01 != 0 {
+        Mirroring::Vertical
+    } else {
+        Mirroring::Horizontal
+    };
+
+    Ok((
+        i,
+        NESHeader {
+            format,
+            prg_rom_size,
+            chr_rom_size,
+            mapper,
+            submapper,
+            mirroring,
+            battery: flags6 & 0x02 != 0,
+            trainer: flags6 & 0x04 != 0,
+        },
+    ))
+}
+
+/// Parse a .nes image, splitting it into the trainer, PRG ROM and CHR
+/// ROM
+pub fn nes_rom_parser(i: &[u8]) -> IResult<&[u8], NESRom<'_>> {
+    let data = i;
+    let (i, header) = nes_header_parser(i)?;
+    if !header.check() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            &data[4..],
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let (i, trainer) = if header.trainer {
+        let (i, trainer) = take(TRAINER_SIZE)(i)?;
+        (i, Some(trainer))
+    } else {
+        (i, None)
+    };
+    let (i, prg_rom) = take(header.prg_rom_size)(i)?;
+    let (i, chr_rom) = take(header.chr_rom_size)(i)?;
+    let (i, misc_rom) = rest(i)?;
+    if !misc_rom.is_empty() && header.format == NESHeaderFormat::INES {
+        debug!(
+            target: PARSE,
+            "{} bytes after the CHR ROM of an iNES image",
+            misc_rom.len()
+        );
+    }
+
+    Ok((
+        i,
+        NESRom {
+            data,
+            header,
+            trainer,
+            prg_rom,
+            chr_rom,
+            misc_rom,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        is_nes, nes_header_parser, nes_rom_parser, rom_size, Mirroring, NESHeaderFormat,
+        CHR_BANK_SIZE, PRG_BANK_SIZE,
+    };
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+
+    /// Test parsing iNES and NES 2.0 headers and splitting the banks
+    #[test]
+    fn nes_rom_parser_works() {
+        let data = testgen::nes(4, 2, 1, true, false);
+        assert!(is_nes(&data));
+        let (_, rom) = nes_rom_parser(&data).unwrap();
+        assert!(rom.check());
+        assert_eq!(rom.header.format, NESHeaderFormat::INES);
+        assert_eq!(rom.header.mapper, 4);
+        assert_eq!(rom.header.mirroring, Mirroring::Vertical);
+        assert!(rom.header.battery);
+        assert_eq!(rom.trainer, Some(&[0x70; 512][..]));
+        assert_eq!(rom.prg_banks().len(), 2);
+        assert_eq!(rom.prg_banks()[1], [1; PRG_BANK_SIZE]);
+        assert_eq!(rom.chr_banks(), [&[0x80; CHR_BANK_SIZE][..]]);
+        assert!(rom.misc_rom.is_empty());
+        assert_eq!(
+            rom.header.to_string(),
+            "iNES, mapper: 4, PRG ROM: 32KB, CHR ROM: 8KB, mirroring: Vertical, battery, trainer"
+        );
+
+        let config = Config::default();
+        assert_eq!(rom.to_bytes(&config, None).unwrap(), data);
+        assert_eq!(
+            rom.to_bytes(&config, Some("chr.bin")).unwrap(),
+            [0x80; CHR_BANK_SIZE]
+        );
+        let names: Vec<String> = rom.disk_files().into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["TRAINER.BIN", "PRG.BIN", "CHR.BIN"]);
+
+        // An NES 2.0 header with a mapper past 255 and no CHR ROM
+        let data = testgen::nes(0x10A, 1, 0, false, true);
+        let (_, rom) = nes_rom_parser(&data).unwrap();
+        assert_eq!(rom.header.format, NESHeaderFormat::NES20);
+        assert_eq!(rom.header.mapper, 0x10A);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.trainer, None);
+
+        // Old dumping tools wrote their name over the end of the header
+        let mut data = testgen::nes(0x41, 1, 1, false, false);
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let (_, header) = nes_header_parser(&data).unwrap();
+        assert_eq!(header.mapper, 1);
+
+        // Truncated PRG ROM and no PRG ROM at all
+        let data = testgen::nes(0, 2, 0, false, false);
+        assert!(nes_rom_parser(&data[..20000]).is_err());
+        let mut data = testgen::nes(0, 1, 0, false, false);
+        data[4] = 0;
+        assert!(nes_rom_parser(&data).is_err());
+    }
+
+    /// Test NES 2.0 sizes in exponent and multiplier notation
+    #[test]
+    fn rom_size_works() {
+        assert_eq!(rom_size(2, 0, PRG_BANK_SIZE), Some(32768));
+        assert_eq!(
+            rom_size(0x00, 0x01, PRG_BANK_SIZE),
+            Some(256 * PRG_BANK_SIZE)
+        );
+        // 2^6 * 3 bytes
+        assert_eq!(rom_size(0x19, 0x0F, PRG_BANK_SIZE), Some(192));
+        assert_eq!(rom_size(0xFF, 0x0F, PRG_BANK_SIZE), None);
+    }
+}
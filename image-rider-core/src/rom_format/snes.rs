@@ -0,0 +1,518 @@
+//! SNES cartridge images
+//!
+//! SNES dumps (.sfc, .smc) have no signature.  Dumps made with copier
+//! devices start with a 512 byte copier header, which is found from
+//! the size of the file: a ROM is a multiple of 1K, so a file 512
+//! bytes longer has a header.
+//!
+//! The cartridge describes itself in an internal header at the end of
+//! the first bank the console maps into 8000 to FFFF.  Where that is in
+//! the ROM depends on how the board maps the ROM:
+//!
+//! ```ignore
+//! LoROM: 32K banks, header at 7FC0
+//! HiROM: 64K banks, header at FFC0
+//! ExHiROM: 64K banks, header at 40FFC0
+//! ```
+//!
+//! The header is at the same offsets in each layout:
+//!
+//! ```ignore
+//! 00: the title, 21 bytes padded with spaces
+//! 15: the map mode, 20 for LoROM, 21 for HiROM, 25 for ExHiROM, plus
+//!     10 for FastROM
+//! 16: the cartridge type, ROM, RAM, battery and coprocessor
+//! 17: the ROM size, 2^N kilobytes
+//! 18: the RAM size, 2^N kilobytes or zero
+//! 19: the destination code
+//! 1A: the developer ID
+//! 1B: the version
+//! 1C: the checksum complement, 16 bit little endian
+//! 1E: the checksum, 16 bit little endian
+//! 3C: the reset vector
+//! ```
+//!
+//! The layout is guessed by scoring each header location: the checksum
+//! and its complement, the map mode, the title and the reset vector.
+//!
+//! Information from:\
+//! [ROM header](https://snes.nesdev.org/wiki/ROM_header)\
+//! [Memory map](https://snes.nesdev.org/wiki/Memory_map)
+use std::fmt::{Display, Formatter, Result};
+use std::io::Write;
+
+use config::Config;
+use log::{debug, warn};
+
+use nom::bytes::complete::take;
+use nom::combinator::rest;
+use nom::number::complete::{le_u16, le_u8};
+use nom::IResult;
+
+use crate::disk_format::file_select::DiskFile;
+use crate::disk_format::format_info::{Capabilities, FormatInfo, Reference, SizeRange};
+use crate::disk_format::image::DiskImageSaver;
+use crate::disk_format::sanity_check::SanityCheck;
+use crate::error::Error;
+use crate::log_target::PARSE;
+use crate::rom_format::atari::{bank_files, rom_file, save_rom};
+
+/// An SNES cartridge dump
+pub const SFC_FORMAT_INFO: FormatInfo = FormatInfo {
+    name: "SFC",
+    description: "SNES cartridge ROM, LoROM, HiROM or ExHiROM, with or without a copier header",
+    extensions: &["sfc", "smc"],
+    sizes: &[SizeRange::at_least(0x8000)],
+    references: &[
+        Reference {
+            title: "ROM header",
+            url: Some("https://snes.nesdev.org/wiki/ROM_header"),
+        },
+        Reference {
+            title: "Memory map",
+            url: Some("https://snes.nesdev.org/wiki/Memory_map"),
+        },
+    ],
+    capabilities: Capabilities::NONE,
+};
+
+/// The size of a copier header
+pub const COPIER_HEADER_SIZE: usize = 512;
+
+/// The size of the internal header, up to the end of the vectors
+pub const SNES_HEADER_SIZE: usize = 64;
+
+/// The length of the title in the internal header
+const TITLE_SIZE: usize = 21;
+
+/// The smallest ROM with a header, one LoROM bank
+const MIN_ROM_SIZE: usize = 32 * 1024;
+
+/// Return true if the filename is an SNES cartridge image, .sfc, .smc,
+/// .swc or .fig
+/// SNES dumps have no signature, so they're only read by name.
+pub fn is_snes_filename(filename: &str) -> bool {
+    let filename = filename.to_lowercase();
+    [".sfc", ".smc", ".swc", ".fig"]
+        .iter()
+        .any(|extension| filename.ends_with(extension))
+}
+
+/// How the board maps the ROM into the address space
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SNESMapMode {
+    /// 32K banks mapped into the upper half of each bank
+    LoROM,
+    /// 64K banks mapped into whole banks
+    HiROM,
+    /// HiROM extended past 4MB
+    ExHiROM,
+}
+
+impl SNESMapMode {
+    /// All the layouts, in the order they're scored
+    const ALL: [SNESMapMode; 3] = [SNESMapMode::LoROM, SNESMapMode::HiROM, SNESMapMode::ExHiROM];
+
+    /// Return the offset of the internal header in the ROM
+    pub fn header_offset(&self) -> usize {
+        match self {
+            SNESMapMode::LoROM => 0x7FC0,
+            SNESMapMode::HiROM => 0xFFC0,
+            SNESMapMode::ExHiROM => 0x40FFC0,
+        }
+    }
+
+    /// Return the size of a bank
+    pub fn bank_size(&self) -> usize {
+        match self {
+            SNESMapMode::LoROM => 32 * 1024,
+            SNESMapMode::HiROM | SNESMapMode::ExHiROM => 64 * 1024,
+        }
+    }
+
+    /// Return true if the map mode byte of a header says this layout
+    /// The FastROM bit is ignored.
+    fn matches(&self, map_mode: u8) -> bool {
+        match self {
+            SNESMapMode::LoROM => map_mode & 0xEF == 0x20,
+            SNESMapMode::HiROM => map_mode & 0xEF == 0x21,
+            SNESMapMode::ExHiROM => map_mode & 0xEF == 0x25,
+        }
+    }
+}
+
+impl Display for SNESMapMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// The internal header of an SNES cartridge
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SNESHeader {
+    /// The cartridge title
+    pub title: String,
+    /// The map mode byte
+    pub map_mode: u8,
+    /// The cartridge type byte
+    pub cartridge_type: u8,
+    /// The ROM size in bytes
+    pub rom_size: usize,
+    /// The cartridge RAM size in bytes, zero for none
+    pub ram_size: usize,
+    /// The destination code
+    pub region: u8,
+    /// The developer ID, 33 for an extended header
+    pub developer: u8,
+    /// The version
+    pub version: u8,
+    /// The checksum complement
+    pub checksum_complement: u16,
+    /// The checksum of the ROM
+    pub checksum: u16,
+    /// The emulation mode reset vector
+    pub reset_vector: u16,
+}
+
+impl SNESHeader {
+    /// Return true if the map mode says the cartridge runs at FastROM
+    /// speed
+    pub fn fast_rom(&self) -> bool {
+        self.map_mode & 0x10 != 0
+    }
+
+    /// Return true if the cartridge has battery-backed RAM
+    pub fn battery(&self) -> bool {
+        matches!(self.cartridge_type & 0x0F, 0x02 | 0x05 | 0x06 | 0x09 | 0x0A)
+    }
+}
+
+impl Display for SNESHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "title: {}, ROM: {}KB, RAM: {}KB, version: 1.{}, checksum: {:04X}",
+            self.title,
+            self.rom_size / 1024,
+            self.ram_size / 1024,
+            self.version,
+            self.checksum
+        )?;
+        if self.fast_rom() {
+            write!(f, ", FastROM")?;
+        }
+        if self.battery() {
+            write!(f, ", battery")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for SNESHeader {
+    fn check(&self) -> bool {
+        if self.checksum ^ self.checksum_complement != 0xFFFF {
+            debug!(
+                target: PARSE,
+                "SNES checksum {:04X} and complement {:04X} don't match",
+                self.checksum,
+                self.checksum_complement
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Parse an SNES internal header
+pub fn snes_header_parser(i: &[u8]) -> IResult<&[u8], SNESHeader> {
+    let (i, title) = take(TITLE_SIZE)(i)?;
+    let (i, map_mode) = le_u8(i)?;
+    let (i, cartridge_type) = le_u8(i)?;
+    let (i, rom_size) = le_u8(i)?;
+    let (i, ram_size) = le_u8(i)?;
+    let (i, region) = le_u8(i)?;
+    let (i, developer) = le_u8(i)?;
+    let (i, version) = le_u8(i)?;
+    let (i, checksum_complement) = le_u16(i)?;
+    let (i, checksum) = le_u16(i)?;
+    // The native and emulation mode vectors
+    let (i, _vectors) = take(0x3C - 0x20_usize)(i)?;
+    let (i, reset_vector) = le_u16(i)?;
+    let (i, _irq_vector) = le_u16(i)?;
+
+    let size = |n: u8| if n == 0 || n > 16 { 0 } else { 1024 << n };
+    let title: String = title.iter().map(|c| char::from(*c)).collect();
+
+    Ok((
+        i,
+        SNESHeader {
+            title: String::from(title.trim_end()),
+            map_mode,
+            cartridge_type,
+            rom_size: size(rom_size),
+            ram_size: size(ram_size),
+            region,
+            developer,
+            version,
+            checksum_complement,
+            checksum,
+            reset_vector,
+        },
+    ))
+}
+
+/// Score how likely the header for a layout is the real header
+/// Returns None if the ROM is too small to have a header there.
+pub fn header_score(rom: &[u8], map_mode: SNESMapMode) -> Option<u32> {
+    let offset = map_mode.header_offset();
+    let (_, header) = snes_header_parser(rom.get(offset..offset + SNES_HEADER_SIZE)?).ok()?;
+
+    let mut score = 0;
+    if header.check() {
+        score += 4;
+    }
+    if map_mode.matches(header.map_mode) {
+        score += 2;
+    }
+    let title = &rom[offset..offset + TITLE_SIZE];
+    if title.iter().all(|c| (0x20..0x7F).contains(c)) {
+        score += 1;
+    }
+    // Code starts in the upper half of a bank
+    if header.reset_vector >= 0x8000 {
+        score += 1;
+    }
+    if (0x08..=0x0D).contains(&rom[offset + 0x17]) {
+        score += 1;
+    }
+    Some(score)
+}
+
+/// Guess how the board maps the ROM from the scores of each header
+/// location
+/// Returns None if no location scores at all.
+pub fn detect_map_mode(rom: &[u8]) -> Option<SNESMapMode> {
+    let mut best = None;
+    for map_mode in SNESMapMode::ALL {
+        let Some(score) = header_score(rom, map_mode) else {
+            continue;
+        };
+        debug!(target: PARSE, "SNES {} header score: {}", map_mode, score);
+        if score > 0 && !matches!(best, Some((_, best_score)) if best_score >= score) {
+            best = Some((map_mode, score));
+        }
+    }
+    best.map(|(map_mode, _)| map_mode)
+}
+
+/// Compute the checksum of a ROM
+/// The checksum is the sum of the bytes of a power of two sized ROM.
+/// The part of a ROM past the largest power of two is mirrored until it
+/// fills that size again, as the console sees it.
+pub fn rom_checksum(rom: &[u8]) -> u16 {
+    let sum = |data: &[u8]| {
+        data.iter()
+            .fold(0_u16, |sum, byte| sum.wrapping_add(u16::from(*byte)))
+    };
+    if rom.is_empty() {
+        return 0;
+    }
+    let base = 1 << rom.len().ilog2();
+    let (first, remainder) = rom.split_at(base);
+    if remainder.is_empty() {
+        return sum(first);
+    }
+    let mirrors = (base / remainder.len()) as u16;
+    sum(first).wrapping_add(sum(remainder).wrapping_mul(mirrors))
+}
+
+/// A parsed SNES cartridge image
+pub struct SNESRom<'a> {
+    /// The whole image
+    pub data: &'a [u8],
+    /// The copier header, if there is one
+    pub copier_header: Option<&'a [u8]>,
+    /// The ROM after the copier header
+    pub rom: &'a [u8],
+    /// How the board maps the ROM
+    pub map_mode: SNESMapMode,
+    /// The internal header
+    pub header: SNESHeader,
+}
+
+impl<'a> SNESRom<'a> {
+    /// Return the banks, 32K for LoROM and 64K for HiROM
+    pub fn banks(&self) -> Vec<&'a [u8]> {
+        self.rom.chunks(self.map_mode.bank_size()).collect()
+    }
+
+    /// Return true if the checksum in the header matches the ROM
+    pub fn checksum_matches(&self) -> bool {
+        rom_checksum(self.rom) == self.header.checksum
+    }
+}
+
+impl Display for SNESRom<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "SNES, {}, {}\nbanks: {}",
+            self.map_mode,
+            self.header,
+            self.banks().len()
+        )?;
+        if !self.checksum_matches() {
+            write!(f, ", bad checksum")?;
+        }
+        if self.copier_header.is_some() {
+            write!(f, ", copier header")?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheck for SNESRom<'_> {
+    fn check(&self) -> bool {
+        self.header.check()
+    }
+}
+
+impl DiskImageSaver for SNESRom<'_> {
+    /// Write the whole image, or the ROM without the copier header or
+    /// a bank if one is selected
+    fn save_to_writer(
+        &self,
+        _config: &Config,
+        selected_filename: Option<&str>,
+        writer: &mut impl Write,
+    ) -> std::result::Result<(), Error> {
+        save_rom(self.data, &self.disk_files(), selected_filename, writer)
+    }
+
+    /// The copier header as HEADER.BIN, the ROM without it as ROM.BIN
+    /// and the banks as BANK0.BIN and up
+    fn disk_files(&self) -> Vec<DiskFile> {
+        let mut files = Vec::new();
+        if let Some(copier_header) = self.copier_header {
+            files.push(rom_file("HEADER.BIN", "Copier header", copier_header));
+        }
+        files.push(rom_file("ROM.BIN", "ROM", self.rom));
+        files.extend(bank_files(&self.banks()));
+        files
+    }
+}
+
+/// Parse an SNES image, skipping a copier header and finding the
+/// internal header
+pub fn snes_rom_parser(i: &[u8]) -> IResult<&[u8], SNESRom<'_>> {
+    let data = i;
+    let (i, copier_header) = if i.len() % 1024 == COPIER_HEADER_SIZE {
+        let (i, copier_header) = take(COPIER_HEADER_SIZE)(i)?;
+        (i, Some(copier_header))
+    } else {
+        (i, None)
+    };
+    if i.len() < MIN_ROM_SIZE {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Eof,
+        )));
+    }
+    let Some(map_mode) = detect_map_mode(i) else {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Verify,
+        )));
+    };
+    let offset = map_mode.header_offset();
+    let (_, header) = snes_header_parser(&i[offset..])?;
+    let (i, rom) = rest(i)?;
+
+    let rom = SNESRom {
+        data,
+        copier_header,
+        rom,
+        map_mode,
+        header,
+    };
+    if !rom.checksum_matches() {
+        warn!(
+            target: PARSE,
+            "SNES checksum {:04X} doesn't match the ROM, {:04X}",
+            rom.header.checksum,
+            rom_checksum(rom.rom)
+        );
+    }
+
+    Ok((i, rom))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+
+    use super::{
+        detect_map_mode, header_score, is_snes_filename, rom_checksum, snes_rom_parser,
+        SNESMapMode, COPIER_HEADER_SIZE,
+    };
+    use crate::disk_format::image::DiskImageSaver;
+    use crate::disk_format::sanity_check::SanityCheck;
+    use crate::disk_format::testgen;
+
+    /// Test finding LoROM and HiROM headers and copier headers
+    #[test]
+    fn snes_rom_parser_works() {
+        assert!(is_snes_filename("Zelda.SFC"));
+        assert!(!is_snes_filename("zelda.nes"));
+
+        let data = testgen::snes("LOROM GAME", 0x20, 8, false);
+        assert_eq!(detect_map_mode(&data), Some(SNESMapMode::LoROM));
+        let (_, rom) = snes_rom_parser(&data).unwrap();
+        assert!(rom.check());
+        assert!(rom.checksum_matches());
+        assert_eq!(rom.header.title, "LOROM GAME");
+        assert_eq!(rom.header.rom_size, 256 * 1024);
+        assert_eq!(rom.banks().len(), 8);
+        assert_eq!(rom.banks()[5][0], 5);
+        assert!(rom.copier_header.is_none());
+
+        let data = testgen::snes("HIROM GAME", 0x31, 4, true);
+        let (_, rom) = snes_rom_parser(&data).unwrap();
+        assert_eq!(rom.map_mode, SNESMapMode::HiROM);
+        assert!(rom.header.fast_rom());
+        assert!(rom.checksum_matches());
+        assert_eq!(rom.banks().len(), 4);
+        assert_eq!(rom.rom.len(), data.len() - COPIER_HEADER_SIZE);
+        assert_eq!(
+            rom.to_string(),
+            format!(
+                "SNES, HiROM, title: HIROM GAME, ROM: 256KB, RAM: 0KB, version: 1.0, \
+                 checksum: {:04X}, FastROM\nbanks: 4, copier header",
+                rom.header.checksum
+            )
+        );
+        let config = Config::default();
+        assert_eq!(
+            rom.to_bytes(&config, Some("ROM.BIN")).unwrap(),
+            &data[COPIER_HEADER_SIZE..]
+        );
+        assert_eq!(rom.disk_files().len(), 6);
+
+        // The LoROM location of a HiROM image doesn't look like a header
+        let data = &data[COPIER_HEADER_SIZE..];
+        assert!(header_score(data, SNESMapMode::LoROM) < header_score(data, SNESMapMode::HiROM));
+        assert_eq!(header_score(data, SNESMapMode::ExHiROM), None);
+
+        assert!(snes_rom_parser(&[0; 1024]).is_err());
+        assert!(snes_rom_parser(&[0; 65536]).is_err());
+    }
+
+    /// Test computing checksums of ROMs that aren't a power of two
+    #[test]
+    fn rom_checksum_works() {
+        assert_eq!(rom_checksum(&[1; 4]), 4);
+        // 4 + 2 mirrored twice
+        assert_eq!(rom_checksum(&[1, 1, 1, 1, 1, 1]), 8);
+        assert_eq!(rom_checksum(&[0xFF; 257]), 0xFE00);
+    }
+}
@@ -1,11 +0,0 @@
-//! Parse a Commodore disk image
-//!
-//! This parses Commodore disk images.
-//!
-//! Currently this includes support for parsing D64 disk images.
-//!
-#[warn(missing_docs)]
-#[warn(unsafe_code)]
-
-/// Disk-level functions and data structures for D64 disks.
-pub mod d64;
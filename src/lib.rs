@@ -3,35 +3,13 @@
 //! image_rider is a library crate to parse disk and ROM images.  It
 //! primarily focuses on older 8-bit and 16-bit systems.
 //!
+//! The parsers and traits live in the
+//! [image_rider_core] crate, which this crate
+//! re-exports under the same paths.  The modules and the
+//! dependencies are the same in both crates, the command line tool's
+//! dependencies are only used by the example.
+//!
 //! The primary API for this library are a set of traits in the
 //! [image_rider::disk_format::image](crate::disk_format::image) module.
-//!
-//! The disk_format module contains everything to parse disk formats
-//!
-use log::error;
-
-pub mod disk_format;
-pub mod error;
-pub mod serialize;
 
-/// Initialize the module.
-/// This should be called before any parsing is performed.
-/// Panics on failure or if there are any incompatibilities.
-pub fn init() {
-    // If we're on a system with a usize < 32 bits then fail.  This
-    // crate is geared towards parsing file formats for 8-bit systems,
-    // but the code currently does not run on 8-bit systems.  For
-    // example, we read the entire file into a single image data array
-    // and access the data array with usize indexes for several of the
-    // file formats.
-    if usize::BITS < 32 {
-        error!(
-            "Architecture usize {} is too small for this library",
-            usize::BITS
-        );
-        panic!(
-            "Architecture usize {} is too small for this library",
-            usize::BITS
-        );
-    }
-}
+pub use image_rider_core::{disk_format, error, init, log_target, options, rom_format, serialize};
@@ -0,0 +1,190 @@
+//! Regression tests over a corpus of real images
+//!
+//! Real disk dumps can't be shipped with the crate, so the corpus is a
+//! directory supplied by whoever runs the tests:
+//!
+//! ```text
+//! IMAGE_RIDER_CORPUS=~/disks cargo test --test corpus
+//! ```
+//!
+//! Every file in the directory and its subdirectories is parsed and its
+//! JSON report, or the parse error, is compared against a snapshot in
+//! the .snapshots directory of the corpus.  Images without a snapshot
+//! get one written.  Set IMAGE_RIDER_UPDATE_SNAPSHOTS=1 to accept the
+//! current results after an intended change, and IMAGE_RIDER_SNAPSHOTS
+//! to keep the snapshots somewhere else.
+//!
+//! Without IMAGE_RIDER_CORPUS the test does nothing, so it passes in CI.
+use std::fs;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use image_rider::disk_format::image::DiskImageParser;
+use image_rider::options::Options;
+
+/// The directory holding the corpus
+const CORPUS_VAR: &str = "IMAGE_RIDER_CORPUS";
+
+/// The directory holding the snapshots, the .snapshots directory of the
+/// corpus by default
+const SNAPSHOTS_VAR: &str = "IMAGE_RIDER_SNAPSHOTS";
+
+/// Write the current results as the snapshots
+const UPDATE_VAR: &str = "IMAGE_RIDER_UPDATE_SNAPSHOTS";
+
+/// The name of the default snapshot directory
+const SNAPSHOT_DIR: &str = ".snapshots";
+
+/// Return every file under a directory, skipping the snapshots
+fn corpus_files(dir: &Path, snapshots: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path == snapshots {
+            continue;
+        }
+        if path.is_dir() {
+            corpus_files(&path, snapshots, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Parse an image and return its report, or the error, as JSON
+fn snapshot(path: &Path, config: &Options) -> Value {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => return json!({ "error": e.to_string() }),
+    };
+    let filename = path.to_string_lossy();
+
+    match data.parse_disk_image(config, &filename) {
+        Ok(image) => {
+            let mut value = serde_json::to_value(image.report())
+                .unwrap_or_else(|e| json!({ "error": e.to_string() }));
+            if !image.warnings.is_empty() {
+                value["warnings"] = image
+                    .warnings
+                    .iter()
+                    .map(|warning| Value::from(warning.to_string()))
+                    .collect();
+            }
+            value
+        }
+        Err(e) => json!({ "error": e.to_string() }),
+    }
+}
+
+/// List the JSON paths where two values differ
+fn differences(path: &str, expected: &Value, actual: &Value, found: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let mut keys: Vec<&String> = expected.keys().chain(actual.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = format!("{}/{}", path, key);
+                match (expected.get(key), actual.get(key)) {
+                    (Some(e), Some(a)) => differences(&child, e, a, found),
+                    (Some(_), None) => found.push(format!("{}: removed", child)),
+                    (None, _) => found.push(format!("{}: added", child)),
+                }
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => {
+            for (index, (e, a)) in e.iter().zip(a.iter()).enumerate() {
+                differences(&format!("{}/{}", path, index), e, a, found);
+            }
+        }
+        _ if expected != actual => found.push(format!("{}: {} -> {}", path, expected, actual)),
+        _ => (),
+    }
+}
+
+/// Parse every image in the corpus and compare the results with the
+/// snapshots
+#[test]
+fn corpus_matches_snapshots() {
+    let Some(corpus) = std::env::var_os(CORPUS_VAR).map(PathBuf::from) else {
+        eprintln!("{} isn't set, skipping the corpus tests", CORPUS_VAR);
+        return;
+    };
+    let snapshots = std::env::var_os(SNAPSHOTS_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| corpus.join(SNAPSHOT_DIR));
+    let update = std::env::var_os(UPDATE_VAR).is_some_and(|value| value != "0");
+    let config = Options::default();
+
+    let mut files = Vec::new();
+    corpus_files(&corpus, &snapshots, &mut files);
+    files.sort();
+    assert!(!files.is_empty(), "No images found in {}", corpus.display());
+
+    let mut failures = Vec::new();
+    for file in &files {
+        let relative = file.strip_prefix(&corpus).unwrap_or(file);
+        let snapshot_path = snapshots
+            .join(relative)
+            .with_extension(match relative.extension() {
+                Some(extension) => format!("{}.json", extension.to_string_lossy()),
+                None => String::from("json"),
+            });
+        // Some parsers still panic on damaged images, record the panic
+        // so the rest of the corpus is checked
+        let actual = std::panic::catch_unwind(AssertUnwindSafe(|| snapshot(file, &config)))
+            .unwrap_or_else(|_| json!({ "error": "panicked" }));
+
+        let expected: Option<Value> = fs::read_to_string(&snapshot_path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok());
+        match expected {
+            Some(expected) if !update => {
+                let mut found = Vec::new();
+                differences("", &expected, &actual, &mut found);
+                if !found.is_empty() {
+                    failures.push(format!("{}:\n  {}", relative.display(), found.join("\n  ")));
+                }
+            }
+            _ => {
+                if let Some(parent) = snapshot_path.parent() {
+                    fs::create_dir_all(parent).unwrap();
+                }
+                let json = serde_json::to_string_pretty(&actual).unwrap();
+                fs::write(&snapshot_path, json + "\n").unwrap();
+                eprintln!("Wrote snapshot {}", snapshot_path.display());
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} images differ from their snapshots:\n{}",
+        failures.len(),
+        files.len(),
+        failures.join("\n")
+    );
+}
+
+/// Test listing the differences between two snapshots
+#[test]
+fn differences_works() {
+    let expected = json!({ "format": "D64", "sane": true, "tracks": [1, 2] });
+    let actual = json!({ "format": "D64", "sane": false, "tracks": [1, 3], "volumes": [254] });
+    let mut found = Vec::new();
+    differences("", &expected, &actual, &mut found);
+    assert_eq!(
+        found,
+        [
+            "/sane: true -> false",
+            "/tracks/1: 2 -> 3",
+            "/volumes: added"
+        ]
+    );
+}